@@ -0,0 +1,420 @@
+//! Benchmarks for the code that every ray runs through.
+//!
+//! Everything here starts from fixed seeds, so each run measures the same rays
+//! against the same spheres, and numbers are comparable between runs.
+//! Run with `cargo bench`, and `cargo bench --features simd` to compare.
+//! `--features packets` also traces the render benchmark's camera rays in packets.
+
+use std::sync::Arc;
+
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use rand::prelude::*;
+
+use one_weekend::bvh::{
+    Bvh,
+    BvhBuilder,
+};
+use one_weekend::camera::{
+    Camera,
+    CameraInfo,
+    Projection,
+};
+use one_weekend::hitable::{
+    Aabb,
+    Aabb4,
+    Hitable,
+    HitableList,
+    Sphere,
+};
+use one_weekend::material::Lambertian;
+use one_weekend::packet::RayPacket4;
+use one_weekend::prelude::*;
+use one_weekend::ray::RayPrecomp;
+use one_weekend::render::{
+    self,
+    RenderSettings,
+    Scene,
+    DEFAULT_MAX_DEPTH,
+};
+use one_weekend::scenes::{
+    make_cover_scene,
+    make_green_scene,
+    make_random_scene,
+    packed_hitables,
+    random_scene_spheres,
+    CoverSceneParams,
+    RandomSceneParams,
+    SceneSeed,
+};
+use one_weekend::simd::Lanes;
+use one_weekend::sphere_set::SphereSet;
+
+/// How many rays each benchmark traces per iteration.
+const RAYS: usize = 1024;
+
+fn rng(seed: u8) -> SmallRng {
+    SmallRng::from_seed([seed; 16])
+}
+
+/// Rays from all around a box of `size` around the origin, aimed somewhere
+/// inside of it. Most of them hit something in there, but not all.
+fn rays(size: Float) -> Vec<Ray> {
+    let mut rng = rng(1);
+    let mut point = |scale: Float| {
+        scale * Float3::xyz(rng.gen::<Float>() - 0.5,
+                            rng.gen::<Float>() - 0.5,
+                            rng.gen::<Float>() - 0.5)
+    };
+    (0..RAYS)
+        .map(|_| {
+            let origin = point(4.0 * size);
+            let target = point(size);
+            Ray {
+                origin,
+                dir: target - origin,
+                t:   0.,
+            }
+        })
+        .collect()
+}
+
+/// `n` spheres scattered through a box of `size`.
+fn plain_spheres(n: usize, size: Float) -> Vec<Sphere> {
+    let mut rng = rng(2);
+    let material: Arc<dyn Material> = Arc::new(Lambertian { albedo: Float3::xxx(0.5) });
+    (0..n)
+        .map(|_| {
+            let center = size * Float3::xyz(rng.gen::<Float>() - 0.5,
+                                            rng.gen::<Float>() - 0.5,
+                                            rng.gen::<Float>() - 0.5);
+            Sphere {
+                center,
+                radius:   0.2 + 0.8 * rng.gen::<Float>(),
+                material: material.clone(),
+            }
+        })
+        .collect()
+}
+
+/// `plain_spheres()`, boxed up to go in a list or a BVH.
+fn spheres(n: usize, size: Float) -> Vec<Box<dyn Hitable>> {
+    plain_spheres(n, size).into_iter()
+                          .map(|sphere| Box::new(sphere) as Box<dyn Hitable>)
+                          .collect()
+}
+
+fn bench_hit(c: &mut Criterion, name: &str, hitable: impl Hitable + 'static, rays: Vec<Ray>) {
+    c.bench_function(name, move |b| {
+        let mut rng = seeded_rng(0);
+        b.iter(|| {
+            rays.iter()
+                .filter(|ray| hitable.hit(ray, 1e-3, FLOAT_MAX, &mut rng).is_some())
+                .count()
+        })
+    });
+}
+
+fn sphere_hit(c: &mut Criterion) {
+    let sphere = Sphere {
+        center:   Float3::new(),
+        radius:   1.,
+        material: Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+    };
+    bench_hit(c, "sphere hit", sphere, rays(2.));
+}
+
+fn aabb_hit(c: &mut Criterion) {
+    let bbox = Aabb {
+        min: Float3::xxx(-1.),
+        max: Float3::xxx(1.),
+    };
+    let rays = rays(2.);
+    c.bench_function("aabb hit", move |b| {
+        b.iter(|| rays.iter().filter(|ray| bbox.hit(ray, 1e-3, FLOAT_MAX)).count())
+    });
+}
+
+/// The same box test, with what it needs from each ray worked out ahead of
+/// time, like the BVH does once for every box along the way.
+fn aabb_hit_precomp(c: &mut Criterion) {
+    let bbox = Aabb {
+        min: Float3::xxx(-1.),
+        max: Float3::xxx(1.),
+    };
+    let rays: Vec<RayPrecomp> = rays(2.).iter().map(RayPrecomp::new).collect();
+    c.bench_function("aabb hit, precomputed", move |b| {
+        b.iter(|| rays.iter().filter(|ray| bbox.hit_precomp(ray, 1e-3, FLOAT_MAX)).count())
+    });
+}
+
+/// The same four boxes, one at a time and all at once.
+fn aabb4_hit(c: &mut Criterion) {
+    let boxes = [-1.5, -0.5, 0.5, 1.5];
+    let boxes = [0, 1, 2, 3].iter().map(|&i| {
+        Aabb {
+            min: Float3::xyz(boxes[i] - 0.4, -1., -1.),
+            max: Float3::xyz(boxes[i] + 0.4, 1., 1.),
+        }
+    });
+    let boxes: Vec<Aabb> = boxes.collect();
+    let aabb4 = Aabb4::new([boxes[0], boxes[1], boxes[2], boxes[3]]);
+    let rays = rays(4.);
+    let one_at_a_time = rays.clone();
+    c.bench_function("4 aabbs hit", move |b| {
+        b.iter(|| {
+            one_at_a_time.iter()
+                .map(|ray| boxes.iter().filter(|bbox| bbox.hit(ray, 1e-3, FLOAT_MAX)).count())
+                .sum::<usize>()
+        })
+    });
+    c.bench_function("aabb4 hit", move |b| {
+        b.iter(|| {
+            rays.iter()
+                .map(|ray| aabb4.hit(ray, 1e-3, FLOAT_MAX).iter().filter(|&&hit| hit).count())
+                .sum::<usize>()
+        })
+    });
+}
+
+fn float3_math(c: &mut Criterion) {
+    let rays = rays(2.);
+    c.bench_function("float3 sub and dot", move |b| {
+        b.iter(|| {
+            rays.iter()
+                .map(|ray| {
+                    let oc = ray.origin - black_box(Float3::xyz(0.1, 0.2, 0.3));
+                    oc.dot(&ray.dir)
+                })
+                .sum::<Float>()
+        })
+    });
+}
+
+fn list_vs_bvh(c: &mut Criterion) {
+    const SPHERES: usize = 500;
+    const SIZE: Float = 40.;
+    let list = HitableList { hitables: spheres(SPHERES, SIZE) };
+    bench_hit(c, "500 spheres, list", list, rays(SIZE));
+    let bvh = Bvh::new(spheres(SPHERES, SIZE), 0., 0.);
+    bench_hit(c, "500 spheres, bvh", bvh, rays(SIZE));
+}
+
+fn list_vs_sphere_set(c: &mut Criterion) {
+    // The same density of spheres both times.
+    for &(n, size) in [(1000, 50.), (10_000, 108.)].iter() {
+        let list = HitableList { hitables: spheres(n, size) };
+        bench_hit(c, &format!("{} spheres, list", n), list, rays(size));
+        let set = SphereSet::from_spheres(plain_spheres(n, size));
+        bench_hit(c, &format!("{} spheres, sphere set", n), set, rays(size));
+
+        let bvh = Bvh::new(spheres(n, size), 0., 0.);
+        bench_hit(c, &format!("{} spheres, bvh", n), bvh, rays(size));
+        let sets = SphereSet::from_spheres(plain_spheres(n, size))
+            .split(16)
+            .into_iter()
+            .map(|set| Box::new(set) as Box<dyn Hitable>)
+            .collect();
+        let bvh = Bvh::new(sets, 0., 0.);
+        bench_hit(c, &format!("{} spheres, bvh of sphere sets", n), bvh, rays(size));
+    }
+}
+
+/// 5000 spheres over the ground, like `--scene random --objects 5000 --extent 50`.
+fn random_scene() -> Bvh {
+    random_scene_with(BvhBuilder::Median)
+}
+
+fn random_scene_with(builder: BvhBuilder) -> Bvh {
+    Bvh::with_builder(stress_scene().hitables, 0., 0., builder)
+}
+
+/// The hitables of `random_scene()`, before they go in a BVH.
+fn stress_scene() -> HitableList {
+    let params = RandomSceneParams {
+        objects: 5000,
+        extent:  50.,
+    };
+    make_random_scene(&SceneSeed::new("7"), &CoverSceneParams::default(), &params)
+}
+
+fn cover_scene() -> HitableList {
+    make_cover_scene(&SceneSeed::new("7"), &CoverSceneParams::default())
+}
+
+fn random_scene_bvh(c: &mut Criterion) {
+    bench_hit(c, "random scene of 5000 spheres, bvh", random_scene(), rays(50.));
+    let sah = random_scene_with(BvhBuilder::Sah);
+    bench_hit(c, "random scene of 5000 spheres, sah bvh", sah, rays(50.));
+}
+
+/// The flattened BVH against the tree it's built from, traversed recursively
+/// like `Bvh` used to be, over the cover scene and the random scene's stress
+/// test.
+///
+/// To measure a change to either, run `cargo bench --bench hot_paths -- bvh`
+/// on the commit before it and then on the change, on an otherwise idle
+/// machine. Criterion compares each run with the last one it saved under
+/// target/criterion, and prints how much each benchmark's time changed.
+fn flat_vs_pointer_bvh(c: &mut Criterion) {
+    let scenes: [(&str, fn() -> HitableList, Float); 2] = [
+        ("cover scene", cover_scene, 24.),
+        ("random scene of 5000 spheres", stress_scene, 50.),
+    ];
+    for &(name, scene, size) in scenes.iter() {
+        let (tree, hitables, _) = Bvh::build_tree(scene().hitables, 0., 0., BvhBuilder::Median);
+        let tree = tree.expect("Every sphere has a bounding box");
+        let tree_rays = rays(size);
+        c.bench_function(&format!("{}, pointer bvh", name), move |b| {
+            let mut rng = seeded_rng(0);
+            b.iter(|| {
+                tree_rays.iter()
+                         .filter(|ray| {
+                             tree.hit(&hitables, ray, 1e-3, FLOAT_MAX, &mut rng).is_some()
+                         })
+                         .count()
+            })
+        });
+        let bvh = Bvh::new(scene().hitables, 0., 0.);
+        bench_hit(c, &format!("{}, flat bvh", name), bvh, rays(size));
+    }
+}
+
+/// Shadow rays from just above the random scene's ground up toward a light,
+/// which only need to know whether anything's in the way.
+fn shadow_rays(c: &mut Criterion) {
+    let mut rng = rng(3);
+    let rays: Vec<Ray> = (0..RAYS)
+        .map(|_| {
+            let origin = Float3::xyz(100. * rng.gen::<Float>() - 50.,
+                                     0.01,
+                                     100. * rng.gen::<Float>() - 50.);
+            Ray {
+                origin,
+                dir: Float3::xyz(0., 20., 0.) - origin,
+                t:   0.,
+            }
+        })
+        .collect();
+    let bvh = Arc::new(random_scene());
+    let (hit_bvh, hit_rays) = (bvh.clone(), rays.clone());
+    c.bench_function("shadow rays, hit", move |b| {
+        let mut rng = seeded_rng(0);
+        b.iter(|| {
+            hit_rays.iter()
+                    .filter(|ray| hit_bvh.hit(ray, 1e-3, 1.0, &mut rng).is_some())
+                    .count()
+        })
+    });
+    c.bench_function("shadow rays, hit_any", move |b| {
+        let mut rng = seeded_rng(0);
+        b.iter(|| {
+            rays.iter()
+                .filter(|ray| bvh.hit_any(ray, 1e-3, 1.0, &mut rng))
+                .count()
+        })
+    });
+}
+
+/// Camera rays through the middle of each pixel of a 32x32 image of the random
+/// scene, with its little spheres in sets, in 2x2 blocks of pixels. These are
+/// traced one at a time, and four at a time as packets.
+fn primary_rays(c: &mut Criterion) {
+    let params = RandomSceneParams {
+        objects: 5000,
+        extent:  50.,
+    };
+    let spheres = random_scene_spheres(&SceneSeed::new("7"), &CoverSceneParams::default(), &params);
+    let bvh = Arc::new(Bvh::new(packed_hitables(&spheres).hitables, 0., 0.));
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(13., 2., 3.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 40. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 10.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    const SIZE: u32 = 32;
+    let mut rng = seeded_rng(0x5eed);
+    let mut ray = |x: u32, y: u32| {
+        let (s, t) = ((x as Float + 0.5) / SIZE as Float, (y as Float + 0.5) / SIZE as Float);
+        Some(cam.get_ray(s, t, &mut rng))
+    };
+    let blocks: Vec<[Option<Ray>; 4]> = (0..SIZE * SIZE / 4)
+        .map(|i| {
+            let (x, y) = (2 * (i % (SIZE / 2)), 2 * (i / (SIZE / 2)));
+            [ray(x, y), ray(x + 1, y), ray(x, y + 1), ray(x + 1, y + 1)]
+        })
+        .collect();
+
+    let (scalar_bvh, scalar_blocks) = (bvh.clone(), blocks.clone());
+    c.bench_function("primary rays, one at a time", move |b| {
+        let mut rng = seeded_rng(0);
+        b.iter(|| {
+            scalar_blocks.iter()
+                         .flat_map(|rays| rays.iter().flatten())
+                         .filter(|ray| scalar_bvh.hit(ray, 1e-3, FLOAT_MAX, &mut rng).is_some())
+                         .count()
+        })
+    });
+    c.bench_function("primary rays, packets", move |b| {
+        let mut rngs = [0, 1, 2, 3].map(seeded_rng);
+        b.iter(|| {
+            blocks.iter()
+                  .map(|&rays| {
+                      let packet = RayPacket4::new(rays);
+                      let hits = bvh.hit_packet(&packet,
+                                                Lanes::splat(1e-3),
+                                                Lanes::splat(FLOAT_MAX),
+                                                &mut rngs);
+                      hits.iter().filter(|hit| hit.is_some()).count()
+                  })
+                  .sum::<usize>()
+        })
+    });
+}
+
+fn sampling(c: &mut Criterion) {
+    c.bench_function("random_in_sphere", |b| {
+        seed_thread_rng(0x5eed);
+        b.iter(|| black_box(random_in_sphere()))
+    });
+}
+
+fn render_green(c: &mut Criterion) {
+    let scene = Scene::new(make_green_scene());
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::new(),
+        lookat:     Float3::xyz(0., 0., -1.),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 90. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 1.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    let settings = RenderSettings {
+        width:             64,
+        height:            64,
+        samples_per_pixel: 4,
+        max_depth:         DEFAULT_MAX_DEPTH,
+        seed:              0x5eed,
+        scene:             "green".into(),
+    };
+    c.bench_function("render green 64x64 at 4 spp", move |b| {
+        b.iter(|| render::render(&scene, &cam, &settings, &|_| {}))
+    });
+}
+
+criterion_group!(benches, sphere_hit, aabb_hit, aabb_hit_precomp, aabb4_hit, float3_math,
+                 list_vs_bvh, list_vs_sphere_set, random_scene_bvh, flat_vs_pointer_bvh,
+                 shadow_rays, primary_rays, sampling, render_green);
+criterion_main!(benches);
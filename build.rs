@@ -0,0 +1,104 @@
+//! Collects information about the build so the binary can report what it is.
+//! Everything here must work outside of a git checkout, too. When something
+//! can't be determined, we report "unknown" instead of failing the build.
+
+use std::{
+    env,
+    process::Command,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+fn main() {
+    let git_describe = git(&["describe", "--always", "--dirty", "--tags"]);
+    let git_commit = git(&["rev-parse", "HEAD"]);
+    let build_date = build_date();
+    let profile = env::var("PROFILE").unwrap_or_else(|_| "unknown".into());
+    let target = env::var("TARGET").unwrap_or_else(|_| "unknown".into());
+    let features = features();
+
+    let version_line = format!("{} ({} {}, {}) [{}]",
+                               env::var("CARGO_PKG_VERSION").unwrap(),
+                               git_describe,
+                               build_date,
+                               profile,
+                               if features.is_empty() { "no features" }
+                               else { &features });
+
+    println!("cargo:rustc-env=BUILD_GIT_DESCRIBE={}", git_describe);
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=BUILD_DATE={}", build_date);
+    println!("cargo:rustc-env=BUILD_PROFILE={}", profile);
+    println!("cargo:rustc-env=BUILD_TARGET={}", target);
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features);
+    println!("cargo:rustc-env=BUILD_VERSION_LINE={}", version_line);
+
+    // Only rebuild this info when something it depends on changes.
+    // Without these lines, Cargo reruns us whenever any file changes.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// Runs git with `args`, returning its trimmed stdout or "unknown".
+fn git(args: &[&str]) -> String {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// The date of the build as YYYY-MM-DD, in UTC.
+/// This respects `SOURCE_DATE_EPOCH` so builds can be reproducible.
+/// See https://reproducible-builds.org/specs/source-date-epoch/
+fn build_date() -> String {
+    let secs: u64 = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => match epoch.trim().parse() {
+            Ok(secs) => secs,
+            Err(_) => return "unknown".into(),
+        },
+        Err(_) => match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => return "unknown".into(),
+        },
+    };
+
+    // Convert days since the epoch into a civil date.
+    // This is Howard Hinnant's `civil_from_days` algorithm.
+    //      See: http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// A sorted, comma separated list of the enabled cargo features.
+fn features() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            if key.starts_with("CARGO_FEATURE_") {
+                let name = &key["CARGO_FEATURE_".len()..];
+                Some(name.to_lowercase().replace('_', "-"))
+            } else {
+                None
+            }
+        })
+        .collect();
+    features.sort();
+    features.join(",")
+}
@@ -0,0 +1,537 @@
+//! Arbitrary output variables: extra images written alongside the main one.
+//!
+//! Light passes split up the light in the image by the paths it took. The
+//! surface passes (normal, depth, and albedo) describe what each pixel's
+//! primary rays hit first, averaged over the samples that hit anything, for
+//! denoisers and compositing. Where every sample missed, they're black, except
+//! for depth, which is white: as far away as it goes.
+//!
+//! AOVs settle down much sooner than the image itself, so they don't need
+//! every sample. With `--aov-stride N`, only every Nth sample of a pixel is
+//! traced for them, and each AOV is averaged over the samples it was given.
+//! Surface passes only look at the first hit, so they stop altogether after
+//! `SURFACE_SAMPLES` samples. The image is the same either way: tracing AOVs
+//! doesn't change which random numbers a sample sees.
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use crate::lpe::{
+    self,
+    LightPaths,
+};
+use crate::material::ScatterEvent;
+use crate::output;
+use crate::prelude::*;
+use crate::render::DEFAULT_MAX_DEPTH;
+
+/// Surface passes stop taking samples once they have this many.
+pub const SURFACE_SAMPLES: u32 = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    /// Light that reached the camera after at most one diffuse bounce.
+    Direct,
+    /// Light that took more than one bounce, starting with a diffuse one.
+    Indirect,
+    /// Light whose first bounce was specular.
+    Specular,
+    /// Where the first thing a path hit was glass, the fraction of samples
+    /// that refracted (red), reflected (green), and were totally internally
+    /// reflected (blue) there. Black everywhere else.
+    GlassDebug,
+    /// The surface normal, with each axis mapped from [-1, 1] to [0, 1].
+    Normal,
+    /// Distance from the camera, from black at the near end of the depth
+    /// range to white at the far end.
+    Depth,
+    /// The color of the material, without any lighting.
+    Albedo,
+    /// How many times paths bounced before they ended, on average, as a
+    /// heatmap. See `heat()`.
+    Bounces,
+}
+
+impl Aov {
+    pub const ALL: &'static [Aov] = &[
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Specular,
+        Aov::GlassDebug,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Albedo,
+        Aov::Bounces,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Direct   => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Specular => "specular",
+            Aov::GlassDebug => "glass-debug",
+            Aov::Normal   => "normal",
+            Aov::Depth    => "depth",
+            Aov::Albedo   => "albedo",
+            Aov::Bounces  => "bounces",
+        }
+    }
+
+    /// The light path expression this AOV collects, if it's a light pass.
+    pub fn light_path(self) -> Option<&'static str> {
+        match self {
+            Aov::Direct   => Some(lpe::DIRECT),
+            Aov::Indirect => Some(lpe::INDIRECT),
+            Aov::Specular => Some(lpe::SPECULAR),
+            _ => None,
+        }
+    }
+
+    /// Whether this AOV describes the surface that primary rays hit.
+    pub fn is_surface(self) -> bool {
+        match self {
+            Aov::Normal | Aov::Depth | Aov::Albedo => true,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a depth range like "0.5,20", for the depth AOV.
+pub fn parse_depth_range(s: &str) -> Result<(Float, Float), String> {
+    let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+    if parts.len() != 2 {
+        return Err(format!("Expected a range like \"near,far\", found \"{}\"", s));
+    }
+
+    let mut ends = [0.0; 2];
+    for (end, part) in ends.iter_mut().zip(parts.iter()) {
+        *end = part.parse::<Float>()
+                   .map_err(|e| format!("Bad depth \"{}\": {}", part, e))?;
+    }
+    let (near, far) = (ends[0], ends[1]);
+    if !(0.0 <= near && near < far) {
+        return Err(format!("Depth range must have 0 <= near < far, found {},{}", near, far));
+    }
+
+    Ok((near, far))
+}
+
+/// What a sample's primary ray hit first, and how its path went from there,
+/// for the AOVs that want to know.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FirstHit {
+    /// What the material reported about scattering the ray. See
+    /// `Material::scatter_traced()`.
+    pub scatter: Option<ScatterEvent>,
+    /// The surface it hit, if it hit anything.
+    pub surface: Option<Surface>,
+    /// How many times the path bounced before it ended.
+    pub bounces: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Surface {
+    /// Unit surface normal, as the object reports it.
+    pub normal:   Float3,
+    /// How far the hit is from the ray's origin.
+    pub distance: Float,
+    /// See `Material::albedo()`.
+    pub albedo:   Float3,
+}
+
+/// Sums for every requested AOV over the samples of one pixel.
+#[derive(Clone, Debug)]
+pub struct PixelAovs {
+    aovs:        Vec<Aov>,
+    // Tracks the paths of each sample, if any AOV is a light pass.
+    light_paths: Option<LightPaths>,
+    // For each AOV, its index in `light_paths.totals`, if it's a light pass.
+    light_pass:  Vec<Option<usize>>,
+    sums:        Vec<Float3>,
+    // Samples added to each AOV, which light passes are averaged over.
+    samples:     Vec<u32>,
+    // Samples added to each AOV that hit something, which the surface passes
+    // are averaged over.
+    hits:        Vec<u32>,
+    depth_range: (Float, Float),
+    // The most bounces a path can take, which is as hot as `Aov::Bounces` gets.
+    max_bounces: u32,
+    // Only every `stride`th sample of a pixel is added. See `wants_sample()`.
+    stride:      u32,
+}
+
+impl PixelAovs {
+    /// Tracks `aovs`, in that order.
+    pub fn new(aovs: &[Aov]) -> PixelAovs {
+        let mut patterns = vec![];
+        let light_pass = aovs
+            .iter()
+            .map(|aov| {
+                aov.light_path().map(|source| {
+                    patterns.push(lpe::Pattern::parse(source).unwrap());
+                    patterns.len() - 1
+                })
+            })
+            .collect();
+        let light_paths = if patterns.is_empty() {
+            None
+        } else {
+            Some(LightPaths::new(patterns))
+        };
+
+        PixelAovs {
+            aovs: aovs.to_vec(),
+            light_paths,
+            light_pass,
+            sums: vec![Float3::new(); aovs.len()],
+            samples: vec![0; aovs.len()],
+            hits: vec![0; aovs.len()],
+            depth_range: (0.0, 1.0),
+            max_bounces: DEFAULT_MAX_DEPTH,
+            stride: 1,
+        }
+    }
+
+    /// Only adds every `stride`th sample of each pixel.
+    pub fn with_stride(self, stride: u32) -> PixelAovs {
+        PixelAovs {
+            stride: stride.max(1),
+            ..self
+        }
+    }
+
+    /// Maps depths from `near` to `far` onto black to white.
+    pub fn with_depth_range(self, (near, far): (Float, Float)) -> PixelAovs {
+        PixelAovs {
+            depth_range: (near, far),
+            ..self
+        }
+    }
+
+    /// Shows `max_bounces` as the hottest color in `Aov::Bounces`.
+    pub fn with_max_bounces(self, max_bounces: u32) -> PixelAovs {
+        PixelAovs {
+            max_bounces,
+            ..self
+        }
+    }
+
+    /// The AOVs we're tracking, in the order that `value()` numbers them.
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aovs.is_empty()
+    }
+
+    /// Starts over for a new pixel.
+    pub fn clear(&mut self) {
+        for sum in self.sums.iter_mut() {
+            *sum = Float3::new();
+        }
+        for count in self.samples.iter_mut().chain(self.hits.iter_mut()) {
+            *count = 0;
+        }
+    }
+
+    /// Whether sample number `sample` of the pixel (counting from 0) should be
+    /// traced for the AOVs. Once every AOV has all the samples it needs, none
+    /// of them are.
+    pub fn wants_sample(&self, sample: u32) -> bool {
+        sample % self.stride == 0 && (0..self.aovs.len()).any(|i| !self.is_full(i))
+    }
+
+    // Whether the `i`th AOV has all the samples it needs.
+    fn is_full(&self, i: usize) -> bool {
+        self.aovs[i].is_surface() && self.samples[i] >= SURFACE_SAMPLES
+    }
+
+    /// Where the integrator should sort each sample's light into passes.
+    pub fn light_paths(&mut self) -> Option<&mut LightPaths> {
+        self.light_paths.as_mut()
+    }
+
+    /// Whether we need to know what the first thing a path hit was.
+    pub fn wants_first_hit(&self) -> bool {
+        self.aovs.iter().any(|&aov| {
+            aov == Aov::GlassDebug || aov == Aov::Bounces || aov.is_surface()
+        })
+    }
+
+    /// Adds the sample that was just traced, which first hit `first_hit`.
+    pub fn add_sample(&mut self, first_hit: &FirstHit) {
+        for i in 0..self.aovs.len() {
+            if self.is_full(i) {
+                continue;
+            }
+            let aov = self.aovs[i];
+            let surface = first_hit.surface;
+            let sample = match (aov, self.light_pass[i]) {
+                (_, Some(pass)) => self.light_paths.as_ref().unwrap().totals[pass],
+                (Aov::Normal, None) => surface.map_or(Float3::new(), |s| s.normal),
+                (Aov::Depth, None)  => surface.map_or(Float3::new(), |s| Float3::xxx(s.distance)),
+                (Aov::Albedo, None) => surface.map_or(Float3::new(), |s| s.albedo),
+                (Aov::Bounces, None) => Float3::xxx(first_hit.bounces as Float),
+                (Aov::GlassDebug, None) => match first_hit.scatter {
+                    Some(ScatterEvent::Refracted)        => Float3::xyz(1., 0., 0.),
+                    Some(ScatterEvent::Reflected)        => Float3::xyz(0., 1., 0.),
+                    Some(ScatterEvent::TotallyReflected) => Float3::xyz(0., 0., 1.),
+                    None                                 => Float3::new(),
+                },
+                (_, None) => unreachable!("{} is a light pass", aov),
+            };
+            self.sums[i] += sample;
+            self.samples[i] += 1;
+            if surface.is_some() {
+                self.hits[i] += 1;
+            }
+        }
+    }
+
+    /// The value of the `i`th AOV, over the samples it was given.
+    pub fn value(&self, i: usize) -> Float3 {
+        let sum = self.sums[i];
+        let (samples, hits) = (self.samples[i], self.hits[i]);
+        match self.aovs[i] {
+            Aov::GlassDebug => {
+                // Only samples that hit glass count.
+                let events = sum.x + sum.y + sum.z;
+                if events > 0.0 {
+                    sum / events
+                } else {
+                    Float3::new()
+                }
+            },
+            aov if aov.is_surface() => {
+                if hits > 0 {
+                    sum / hits
+                } else if aov == Aov::Depth {
+                    Float3::xxx(std::f64::INFINITY as Float)
+                } else {
+                    Float3::new()
+                }
+            },
+            _ if samples > 0 => sum / samples,
+            _ => Float3::new(),
+        }
+    }
+
+    /// The `i`th AOV as we'd write it out.
+    pub fn to_rgb8(&self, i: usize) -> image::Rgb<u8> {
+        let value = self.value(i);
+        match self.aovs[i] {
+            // These are fractions, so don't gamma correct them.
+            Aov::GlassDebug => {
+                let rgb: Float3 = value * 255.99;
+                image::Rgb([rgb.x as u8, rgb.y as u8, rgb.z as u8])
+            },
+            // Neither of these are colors, so they aren't gamma corrected either.
+            Aov::Normal => {
+                if self.hits[i] == 0 {
+                    return image::Rgb([0, 0, 0]);
+                }
+                let rgb: Float3 = 0.5 * (value + Float3::xxx(1.0)) * 255.99;
+                image::Rgb([rgb.x as u8, rgb.y as u8, rgb.z as u8])
+            },
+            Aov::Depth => {
+                let (near, far) = self.depth_range;
+                let depth = ((value.x - near) / (far - near)).max(0.0).min(1.0);
+                let gray = (depth * 255.99) as u8;
+                image::Rgb([gray, gray, gray])
+            },
+            Aov::Bounces => heat(value.x, self.max_bounces),
+            _ => output::to_rgb8(value),
+        }
+    }
+}
+
+/// Colors along the viridis color map, from cold to hot, evenly spaced.
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 44, 122],
+    [59, 81, 139],
+    [44, 113, 142],
+    [33, 144, 141],
+    [39, 173, 129],
+    [92, 200, 99],
+    [170, 220, 50],
+    [253, 231, 37],
+];
+
+/// `bounces` as a color from cold (none) to hot (`max_bounces` or more).
+///
+/// Most paths only take a few bounces, even when they're allowed 50, so this
+/// goes by the log of the count, which leaves room to tell 2 from 5.
+pub fn heat(bounces: Float, max_bounces: u32) -> image::Rgb<u8> {
+    let t = (1.0 + bounces.max(0.0)).ln() / (1.0 + max_bounces.max(1) as Float).ln();
+    viridis(t)
+}
+
+/// `t` from 0 to 1 as a color from cold to hot.
+pub fn viridis(t: Float) -> image::Rgb<u8> {
+    let x = t.max(0.0).min(1.0) * (VIRIDIS.len() - 1) as Float;
+    let i = (x as usize).min(VIRIDIS.len() - 2);
+    let f = x - i as Float;
+    let (a, b) = (VIRIDIS[i], VIRIDIS[i + 1]);
+    let channel = |c: usize| (a[c] as Float + f * (b[c] as Float - a[c] as Float)).round() as u8;
+    image::Rgb([channel(0), channel(1), channel(2)])
+}
+
+impl fmt::Display for Aov {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aov, String> {
+        Aov::ALL
+            .iter()
+            .cloned()
+            .find(|aov| aov.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Aov::ALL.iter().map(|a| a.name()).collect();
+                format!("Unknown AOV \"{}\". Expected one of: {}",
+                        s, names.join(", "))
+            })
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use crate::aov::*;
+
+    #[test]
+    fn check_names_round_trip() {
+        for &aov in Aov::ALL {
+            assert_eq!(aov.name().parse::<Aov>(), Ok(aov));
+        }
+        assert!("beauty".parse::<Aov>().is_err());
+    }
+
+    #[test]
+    fn check_glass_debug_fractions() {
+        let mut aovs = PixelAovs::new(&[Aov::Direct, Aov::GlassDebug]);
+        assert!(aovs.wants_first_hit());
+
+        let scattered = |event| FirstHit {
+            scatter: Some(event),
+            surface: None,
+            bounces: 1,
+        };
+        // Samples that miss the glass don't count against it.
+        aovs.add_sample(&scattered(ScatterEvent::Refracted));
+        aovs.add_sample(&scattered(ScatterEvent::Refracted));
+        aovs.add_sample(&scattered(ScatterEvent::Refracted));
+        aovs.add_sample(&scattered(ScatterEvent::TotallyReflected));
+        aovs.add_sample(&FirstHit::default());
+        assert_eq!(aovs.value(1), Float3::xyz(0.75, 0., 0.25));
+        assert_eq!(aovs.to_rgb8(1), image::Rgb([191, 0, 63]));
+
+        aovs.clear();
+        aovs.add_sample(&FirstHit::default());
+        assert_eq!(aovs.value(1), Float3::new());
+
+        assert!(!PixelAovs::new(&[Aov::Direct]).wants_first_hit());
+    }
+
+    #[test]
+    fn check_surface_passes() {
+        let mut aovs = PixelAovs::new(&[Aov::Normal, Aov::Depth, Aov::Albedo])
+            .with_depth_range((2., 4.));
+        assert!(aovs.wants_first_hit());
+
+        // Every sample missed.
+        aovs.add_sample(&FirstHit::default());
+        assert_eq!(aovs.to_rgb8(0), image::Rgb([0, 0, 0]));
+        assert_eq!(aovs.to_rgb8(1), image::Rgb([255, 255, 255]));
+        assert_eq!(aovs.to_rgb8(2), image::Rgb([0, 0, 0]));
+
+        // Misses don't count toward the average.
+        let hit = FirstHit {
+            scatter: None,
+            surface: Some(Surface {
+                normal:   Float3::xyz(0., 0., 1.),
+                distance: 3.,
+                albedo:   Float3::xyz(0.25, 0.5, 1.),
+            }),
+            bounces: 1,
+        };
+        aovs.add_sample(&hit);
+        aovs.add_sample(&hit);
+        assert_eq!(aovs.value(0), Float3::xyz(0., 0., 1.));
+        assert_eq!(aovs.value(1), Float3::xxx(3.));
+        assert_eq!(aovs.value(2), Float3::xyz(0.25, 0.5, 1.));
+        assert_eq!(aovs.to_rgb8(0), image::Rgb([127, 127, 255]));
+        assert_eq!(aovs.to_rgb8(1), image::Rgb([127, 127, 127]));
+    }
+
+    #[test]
+    fn check_strides_and_caps() {
+        let mut aovs = PixelAovs::new(&[Aov::Direct, Aov::Normal]).with_stride(4);
+        let wanted: Vec<u32> = (0..10).filter(|&n| aovs.wants_sample(n)).collect();
+        assert_eq!(wanted, vec![0, 4, 8]);
+
+        let hit = FirstHit {
+            scatter: None,
+            surface: Some(Surface {
+                normal:   Float3::xyz(0., 1., 0.),
+                distance: 1.,
+                albedo:   Float3::xxx(0.5),
+            }),
+            bounces: 1,
+        };
+        for _ in 0..(SURFACE_SAMPLES + 10) {
+            aovs.add_sample(&hit);
+        }
+        // The normal stopped at its cap, but the light pass kept going.
+        assert_eq!((aovs.samples[0], aovs.samples[1]), (SURFACE_SAMPLES + 10, SURFACE_SAMPLES));
+        assert_eq!(aovs.value(1), Float3::xyz(0., 1., 0.));
+        assert!(aovs.wants_sample(12));
+
+        // With only surface passes, there's nothing left to trace for.
+        let mut surfaces = PixelAovs::new(&[Aov::Depth, Aov::Albedo]);
+        for n in 0..SURFACE_SAMPLES {
+            assert!(surfaces.wants_sample(n));
+            surfaces.add_sample(&hit);
+        }
+        assert!(!surfaces.wants_sample(SURFACE_SAMPLES));
+        assert_eq!(surfaces.value(0), Float3::xxx(1.));
+        surfaces.clear();
+        assert!(surfaces.wants_sample(0));
+    }
+
+    #[test]
+    fn check_bounce_heatmap() {
+        let mut aovs = PixelAovs::new(&[Aov::Bounces]).with_max_bounces(10);
+        assert!(aovs.wants_first_hit());
+
+        // Only seeing the sky is as cold as it gets.
+        aovs.add_sample(&FirstHit::default());
+        assert_eq!(aovs.to_rgb8(0), image::Rgb(VIRIDIS[0]));
+
+        let bounced = |bounces| FirstHit { bounces, ..FirstHit::default() };
+        aovs.add_sample(&bounced(4));
+        aovs.add_sample(&bounced(5));
+        assert_eq!(aovs.value(0), Float3::xxx(3.));
+
+        // Hotter with every bounce, up to the most there can be.
+        let greens: Vec<u8> = (0..=12).map(|b| heat(b as Float, 10).data[1]).collect();
+        assert!(greens.windows(2).all(|w| w[0] <= w[1]), "{:?}", greens);
+        assert_eq!(heat(10., 10), image::Rgb(VIRIDIS[8]));
+        assert_eq!(heat(50., 10), image::Rgb(VIRIDIS[8]));
+    }
+
+    #[test]
+    fn check_depth_range_parses() {
+        assert_eq!(parse_depth_range("0.5, 20"), Ok((0.5, 20.)));
+        assert!(parse_depth_range("20,0.5").is_err());
+        assert!(parse_depth_range("-1,1").is_err());
+        assert!(parse_depth_range("1").is_err());
+    }
+}
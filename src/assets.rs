@@ -0,0 +1,270 @@
+//! Decoded images, shared between everything that loads them.
+//!
+//! Decoding an image costs far more than looking it up, and scenes often use
+//! the same one in several places (lights are even loaded twice, see
+//! `scene_file::load_str()`). The cache keeps each decoded image behind an
+//! `Arc`, keyed by its path, modification time, and size. That's much cheaper
+//! than hashing the contents, and still notices when a file is rewritten, so
+//! loading a scene again picks up images that changed and reuses the rest.
+//!
+//! The cache holds at most `--asset-cache-size` worth of decoded images, and
+//! forgets the least recently used ones first. Images that are still in use
+//! stay alive anyway; the cache only lets go of its own reference.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    mem,
+    path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time,
+};
+
+use crate::prelude::*;
+use crate::texture::ImageTexture;
+
+/// Used when nobody asks for a size, in megabytes.
+pub const DEFAULT_BUDGET_MB: u64 = 512;
+
+/// Which version of which file an image came from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    path:     path::PathBuf,
+    modified: Option<time::SystemTime>,
+    len:      u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    image:     Arc<ImageTexture>,
+    bytes:     u64,
+    last_used: u64,
+}
+
+/// How the cache has been doing.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits:    u64,
+    pub misses:  u64,
+    /// Images we decoded. Every miss decodes one, unless it fails to.
+    pub decodes: u64,
+    /// Images held right now, and their size in bytes.
+    pub images:  usize,
+    pub bytes:   u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Asset cache: {} hits, {} misses, holding {} images in {:.1} MB",
+               self.hits, self.misses, self.images, self.bytes as f64 / (1024. * 1024.))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    // Counts up on every lookup, to tell which entries were used last.
+    clock:   u64,
+    stats:   CacheStats,
+}
+
+#[derive(Debug)]
+pub struct AssetCache {
+    budget: u64,
+    inner:  Mutex<Inner>,
+}
+
+impl Default for AssetCache {
+    fn default() -> AssetCache {
+        AssetCache::new(DEFAULT_BUDGET_MB * 1024 * 1024)
+    }
+}
+
+impl AssetCache {
+    /// A cache that holds up to `budget` bytes of decoded images.
+    pub fn new(budget: u64) -> AssetCache {
+        AssetCache {
+            budget,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// The image at `path`, decoded into a texture.
+    pub fn image(&self, path: &path::Path) -> Result<Arc<ImageTexture>, String> {
+        let metadata = fs::metadata(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let key = Key {
+            path:     path.to_path_buf(),
+            modified: metadata.modified().ok(),
+            len:      metadata.len(),
+        };
+
+        {
+            let mut guard = self.inner.lock().unwrap();
+            let inner = &mut *guard;
+            inner.clock += 1;
+            let now = inner.clock;
+            if let Some(entry) = inner.entries.get_mut(&key) {
+                entry.last_used = now;
+                let image = entry.image.clone();
+                inner.stats.hits += 1;
+                return Ok(image);
+            }
+            inner.stats.misses += 1;
+        }
+
+        // Decode without holding the lock, so that other images can load at
+        // the same time. If two threads race on one image, both decode it and
+        // the second one wins, which is wasteful but harmless.
+        let image = Arc::new(decode(path)?);
+        let bytes = (image.width() as u64 * image.height() as u64)
+                    * mem::size_of::<Float3>() as u64;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.stats.decodes += 1;
+        // Older versions of the file won't be asked for again.
+        inner.entries.retain(|k, _| k.path != key.path);
+        inner.clock += 1;
+        let entry = Entry {
+            image: image.clone(),
+            bytes,
+            last_used: inner.clock,
+        };
+        inner.entries.insert(key, entry);
+        self.evict(&mut inner);
+        Ok(image)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            images: inner.entries.len(),
+            bytes:  held(&inner),
+            ..inner.stats
+        }
+    }
+
+    /// Forgets the least recently used images until we're within budget.
+    fn evict(&self, inner: &mut Inner) {
+        while held(inner) > self.budget {
+            let oldest = inner.entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => inner.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+fn held(inner: &Inner) -> u64 {
+    inner.entries.values().map(|entry| entry.bytes).sum()
+}
+
+fn decode(path: &path::Path) -> Result<ImageTexture, String> {
+    let img = image::open(path)
+        .map_err(|e| format!("Unable to load image {}: {}", path.display(), e))?;
+    Ok(ImageTexture::from_rgb8(&img.to_rgb()))
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_image(path: &path::Path, size: u32) {
+        image::RgbImage::from_pixel(size, size, image::Rgb([255, 128, 0]))
+            .save(path)
+            .unwrap();
+    }
+
+    /// Bytes that a `size` x `size` image takes up once it's decoded.
+    fn decoded_size(size: u32) -> u64 {
+        (size * size) as u64 * mem::size_of::<Float3>() as u64
+    }
+
+    #[test]
+    fn check_images_decode_once() {
+        let dir = test_dir("assets-once");
+        let path = dir.join("a.png");
+        write_image(&path, 4);
+
+        let cache = AssetCache::default();
+        let first = cache.image(&path).unwrap();
+        let second = cache.image(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.width(), 4);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.decodes), (1, 1, 1));
+        assert_eq!((stats.images, stats.bytes), (1, decoded_size(4)));
+
+        assert!(cache.image(&dir.join("missing.png")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_eviction() {
+        let dir = test_dir("assets-evict");
+        let (a, b) = (dir.join("a.png"), dir.join("b.png"));
+        write_image(&a, 4);
+        write_image(&b, 4);
+
+        // Room for one of them at a time.
+        let cache = AssetCache::new(decoded_size(4) + 1);
+        let held_a = cache.image(&a).unwrap();
+        cache.image(&b).unwrap();
+        assert_eq!(cache.stats().images, 1);
+        assert!(cache.stats().bytes <= decoded_size(4));
+
+        // `a` was forgotten, but what we're still holding onto is fine.
+        assert_eq!(held_a.width(), 4);
+        cache.image(&a).unwrap();
+        assert_eq!(cache.stats().decodes, 3);
+        // And `b` is the least recent now.
+        cache.image(&a).unwrap();
+        assert_eq!(cache.stats().decodes, 3);
+
+        // Nothing fits in no room at all, but it still loads.
+        let cache = AssetCache::new(0);
+        assert_eq!(cache.image(&a).unwrap().width(), 4);
+        assert_eq!(cache.stats().images, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_changed_files_reload() {
+        let dir = test_dir("assets-reload");
+        let (changes, stays) = (dir.join("changes.png"), dir.join("stays.png"));
+        write_image(&changes, 2);
+        write_image(&stays, 2);
+
+        let cache = AssetCache::default();
+        cache.image(&changes).unwrap();
+        cache.image(&stays).unwrap();
+
+        // Like reloading a scene after editing one of its textures.
+        write_image(&changes, 3);
+        assert_eq!(cache.image(&changes).unwrap().width(), 3);
+        assert_eq!(cache.image(&stays).unwrap().width(), 2);
+
+        let stats = cache.stats();
+        assert_eq!(stats.decodes, 3);
+        assert_eq!(stats.hits, 1);
+        // The old version of `changes` is gone.
+        assert_eq!(stats.images, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
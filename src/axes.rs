@@ -0,0 +1,208 @@
+//! `--draw-axes`: drawing the world axes over a finished image.
+//!
+//! This is for checking which way a camera is pointing, without having to
+//! recognize the scene. x is red, y is green, and z is blue.
+//!
+//! When the world origin is on screen, the axes are drawn from it, one unit
+//! long. Otherwise, they're drawn in a corner as a gnomon: just the directions
+//! they point, from a fixed spot.
+
+use crate::camera::Camera;
+use crate::prelude::*;
+
+const RED:   image::Rgb<u8> = image::Rgb { data: [255, 0, 0] };
+const GREEN: image::Rgb<u8> = image::Rgb { data: [0, 255, 0] };
+const BLUE:  image::Rgb<u8> = image::Rgb { data: [0, 0, 255] };
+
+/// Each axis, with the color we draw it in.
+fn axes() -> [(Float3, image::Rgb<u8>); 3] {
+    [
+        (Float3::xyz(1., 0., 0.), RED),
+        (Float3::xyz(0., 1., 0.), GREEN),
+        (Float3::xyz(0., 0., 1.), BLUE),
+    ]
+}
+
+/// Draws the axes, as `cam` sees them, over `img`.
+pub fn draw(img: &mut image::RgbImage, cam: &Camera) {
+    if !draw_at_origin(img, cam) {
+        draw_gnomon(img, cam);
+    }
+}
+
+/// Where a point `cam.project()`ed to `(s, t)` lands in `img`, in pixels.
+fn to_pixel(img: &image::RgbImage, (s, t): (Float, Float)) -> (Float, Float) {
+    // t = 1 is the top row.
+    (s * img.width() as Float, (1. - t) * img.height() as Float)
+}
+
+/// Draws the axes starting from the world origin, if that's on screen and
+/// they all point somewhere reasonable. Returns whether it drew anything.
+fn draw_at_origin(img: &mut image::RgbImage, cam: &Camera) -> bool {
+    let (width, height) = (img.width() as Float, img.height() as Float);
+    let origin = match cam.project(Float3::new()) {
+        Some(st) => to_pixel(img, st),
+        None => return false,
+    };
+    if !(0. <= origin.0 && origin.0 < width && 0. <= origin.1 && origin.1 < height) {
+        return false;
+    }
+
+    let mut ends = vec![];
+    for &(axis, color) in axes().iter() {
+        let end = match cam.project(axis) {
+            Some(st) => to_pixel(img, st),
+            None => return false,
+        };
+        // An axis pointing almost straight at the camera projects way off
+        // screen. The gnomon shows that better than a line across everything.
+        let reach = 4. * width.max(height);
+        if (end.0 - origin.0).abs() > reach || (end.1 - origin.1).abs() > reach {
+            return false;
+        }
+        ends.push((end, color));
+    }
+
+    for (end, color) in ends {
+        draw_line(img, origin, end, color);
+    }
+    true
+}
+
+/// Draws the directions of the axes from the bottom left corner.
+fn draw_gnomon(img: &mut image::RgbImage, cam: &Camera) {
+    let size = (img.width().min(img.height()) as Float / 8.).max(4.);
+    let center = (size + 2., img.height() as Float - size - 2.);
+
+    for &(axis, color) in axes().iter() {
+        // Only the parts of the axis across the screen show up. Screen y is down.
+        let dx = axis.dot(&cam.u);
+        let dy = -axis.dot(&cam.v);
+        let end = (center.0 + size * dx, center.1 + size * dy);
+        draw_line(img, center, end, color);
+    }
+}
+
+/// Draws a line between two points in pixels, skipping any of it that's off of `img`.
+/// See: https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm
+pub fn draw_line(img:   &mut image::RgbImage,
+                 from:  (Float, Float),
+                 to:    (Float, Float),
+                 color: image::Rgb<u8>)
+{
+    let (mut x, mut y) = (from.0.floor() as i64, from.1.floor() as i64);
+    let (x1, y1) = (to.0.floor() as i64, to.1.floor() as i64);
+
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let step_x = if x < x1 { 1 } else { -1 };
+    let step_y = if y < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+
+    loop {
+        if 0 <= x && x < img.width() as i64 && 0 <= y && y < img.height() as i64 {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+        if x == x1 && y == y1 {
+            break;
+        }
+        let err2 = 2 * err;
+        if err2 >= dy {
+            err += dy;
+            x += step_x;
+        }
+        if err2 <= dx {
+            err += dx;
+            y += step_y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::camera::{
+        CameraInfo,
+        Projection,
+    };
+
+    fn camera(lookfrom: Float3, lookat: Float3) -> Camera {
+        Camera::new(CameraInfo {
+            lookfrom,
+            lookat,
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 1.,
+            t_start:    0.,
+            t_end:      0.,
+        })
+    }
+
+    fn colors_in(img: &image::RgbImage, x0: u32, y0: u32, x1: u32, y1: u32) -> Vec<image::Rgb<u8>> {
+        let mut colors = vec![];
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let pixel = *img.get_pixel(x, y);
+                if pixel != image::Rgb([0, 0, 0]) && !colors.contains(&pixel) {
+                    colors.push(pixel);
+                }
+            }
+        }
+        colors
+    }
+
+    #[test]
+    fn check_line_endpoints_and_clipping() {
+        let mut img = image::RgbImage::new(8, 8);
+        draw_line(&mut img, (1., 1.), (6., 3.), RED);
+        assert_eq!(*img.get_pixel(1, 1), RED);
+        assert_eq!(*img.get_pixel(6, 3), RED);
+        let drawn = img.pixels().filter(|&&p| p == RED).count();
+        // One pixel for every step across.
+        assert_eq!(drawn, 6);
+
+        // Lines that leave the image are cut off, not wrapped or panicking.
+        let mut img = image::RgbImage::new(8, 8);
+        draw_line(&mut img, (-20., 4.), (20., 4.), GREEN);
+        assert_eq!(img.pixels().filter(|&&p| p == GREEN).count(), 8);
+    }
+
+    #[test]
+    fn check_gnomon_when_origin_is_behind() {
+        // Looking away from the origin.
+        let cam = camera(Float3::xyz(1., 2., 3.), Float3::xyz(2., 3., 6.));
+        assert_eq!(cam.project(Float3::new()), None);
+
+        let mut img = image::RgbImage::new(64, 64);
+        draw(&mut img, &cam);
+        // Everything is in the bottom left corner.
+        let inset = colors_in(&img, 0, 40, 24, 64);
+        for color in [RED, GREEN, BLUE].iter() {
+            assert!(inset.contains(color), "{:?} missing from {:?}", color, inset);
+        }
+        assert_eq!(colors_in(&img, 24, 0, 64, 64), vec![]);
+        assert_eq!(colors_in(&img, 0, 0, 64, 40), vec![]);
+    }
+
+    #[test]
+    fn check_axes_at_visible_origin() {
+        let cam = camera(Float3::xyz(3., 2., 4.), Float3::new());
+        let mut img = image::RgbImage::new(64, 64);
+        draw(&mut img, &cam);
+        // They meet in the middle, where the origin is.
+        let middle = colors_in(&img, 30, 30, 34, 34);
+        assert!(!middle.is_empty());
+        let all = colors_in(&img, 0, 0, 64, 64);
+        for color in [RED, GREEN, BLUE].iter() {
+            assert!(all.contains(color), "{:?} missing from {:?}", color, all);
+        }
+        // x and z point towards us, to the right and left, and y points up.
+        let (ox, oy) = to_pixel(&img, cam.project(Float3::new()).unwrap());
+        let (xx, _) = to_pixel(&img, cam.project(Float3::xyz(1., 0., 0.)).unwrap());
+        let (zx, _) = to_pixel(&img, cam.project(Float3::xyz(0., 0., 1.)).unwrap());
+        let (_, yy) = to_pixel(&img, cam.project(Float3::xyz(0., 1., 0.)).unwrap());
+        assert!(xx > ox && zx < ox && yy < oy);
+    }
+}
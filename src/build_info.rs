@@ -0,0 +1,91 @@
+//! Information about how this crate was built, collected by `build.rs`.
+//! Anything we write out should be traceable back to the build that made it.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BuildInfo {
+    /// Version from Cargo.toml
+    pub version:      &'static str,
+    /// Output of `git describe`, or "unknown" when built outside of git
+    pub git_describe: &'static str,
+    /// Full commit hash, or "unknown" when built outside of git
+    pub git_commit:   &'static str,
+    /// YYYY-MM-DD, honoring `SOURCE_DATE_EPOCH`
+    pub build_date:   &'static str,
+    /// "debug" or "release"
+    pub profile:      &'static str,
+    /// Target triple
+    pub target:       &'static str,
+    /// Comma separated list of enabled cargo features. May be empty.
+    pub features:     &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version:      env!("CARGO_PKG_VERSION"),
+    git_describe: env!("BUILD_GIT_DESCRIBE"),
+    git_commit:   env!("BUILD_GIT_COMMIT"),
+    build_date:   env!("BUILD_DATE"),
+    profile:      env!("BUILD_PROFILE"),
+    target:       env!("BUILD_TARGET"),
+    features:     env!("BUILD_FEATURES"),
+};
+
+/// The one-line summary used by `--version`.
+pub const VERSION_LINE: &str = env!("BUILD_VERSION_LINE");
+
+impl BuildInfo {
+    /// Iterate over the enabled features by name.
+    pub fn features(&self) -> impl Iterator<Item=&'static str> {
+        self.features.split(',').filter(|f| !f.is_empty())
+    }
+
+    /// Key/value pairs for embedding in other files' metadata.
+    pub fn pairs(&self) -> [(&'static str, &'static str); 7] {
+        [
+            ("version",      self.version),
+            ("git-describe", self.git_describe),
+            ("git-commit",   self.git_commit),
+            ("build-date",   self.build_date),
+            ("profile",      self.profile),
+            ("target",       self.target),
+            ("features",     self.features),
+        ]
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in self.pairs().iter() {
+            let value = if value.is_empty() { "none" } else { value };
+            writeln!(f, "{:<14}{}", format!("{}:", key), value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use crate::build_info::*;
+
+    #[test]
+    fn check_fields_are_populated() {
+        // Features are the only thing allowed to be empty.
+        for (key, value) in BUILD_INFO.pairs().iter() {
+            if *key != "features" {
+                assert!(!value.is_empty(), "{} is empty", key);
+            }
+        }
+        assert!(VERSION_LINE.starts_with(BUILD_INFO.version));
+        assert!(VERSION_LINE.contains(BUILD_INFO.git_describe));
+    }
+
+    #[test]
+    fn check_display_has_every_field() {
+        let text = BUILD_INFO.to_string();
+        for (key, _) in BUILD_INFO.pairs().iter() {
+            assert!(text.contains(key), "{} missing from:\n{}", key, text);
+        }
+        assert_eq!(text.lines().count(), BUILD_INFO.pairs().len());
+    }
+}
@@ -0,0 +1,955 @@
+//! A bounding volume hierarchy over a list of hitables.
+//!
+//! We build the tree the obvious way, with boxed children, and then flatten
+//! it into one `Vec<FlatNode>` in depth-first order. The first child of an
+//! interior node is always the node right after it, so we only need to store
+//! where the second child starts. Traversal walks that array with a small
+//! stack instead of recursing, which keeps nodes close together in memory and
+//! skips a lot of call overhead.
+//!
+//! There are two ways to build the tree. The median split halves every node
+//! along its longest axis, which is quick and keeps the tree balanced. The SAH
+//! builder picks splits by the surface area heuristic: rays go through boxes
+//! about in proportion to their surface area, so it splits where the two
+//! sides' areas, weighted by how many hitables are in each, add up the least.
+//! That takes longer to build, and usually less time to trace.
+
+use std::{
+    fmt,
+    ops::Range,
+    str::FromStr,
+};
+
+use crate::hitable::{
+    Aabb,
+    Hitable,
+};
+use crate::packet::{
+    hit_closest_packet,
+    keep_closer,
+    LaneRngs,
+    RayPacket4,
+};
+use crate::prelude::*;
+use crate::ray::RayPrecomp;
+use crate::simd::Lanes;
+
+/// Leaves hold at most this many hitables.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// How many buckets the SAH builder sorts centroids into along each axis.
+/// Splits can only go between buckets.
+const SAH_BINS: usize = 16;
+
+/// How to pick where each node of a BVH splits its hitables in two.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BvhBuilder {
+    /// Halve the hitables along the axis their centroids spread out over the
+    /// most.
+    Median,
+    /// Split where the surface area heuristic says rays will test the fewest
+    /// hitables.
+    Sah,
+}
+
+impl Default for BvhBuilder {
+    fn default() -> BvhBuilder {
+        BvhBuilder::Median
+    }
+}
+
+impl BvhBuilder {
+    pub const ALL: &'static [BvhBuilder] = &[
+        BvhBuilder::Median,
+        BvhBuilder::Sah,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BvhBuilder::Median => "median",
+            BvhBuilder::Sah    => "sah",
+        }
+    }
+}
+
+impl FromStr for BvhBuilder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BvhBuilder, String> {
+        BvhBuilder::ALL
+            .iter()
+            .cloned()
+            .find(|builder| builder.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = BvhBuilder::ALL.iter().map(|b| b.name()).collect();
+                format!("Unknown BVH builder \"{}\". Expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Depth of the traversal stack. Median splits keep trees far shallower than
+/// this, but trees that don't fit fall back to testing everything.
+const STACK_SIZE: usize = 64;
+
+/// The tree as it's built, before flattening.
+/// `Leaf`s refer to a range of the (reordered) hitables.
+#[derive(Debug)]
+pub enum BuildNode {
+    Leaf {
+        bbox:  Aabb,
+        range: Range<usize>,
+    },
+    Interior {
+        bbox:  Aabb,
+        axis:  usize,
+        left:  Box<BuildNode>,
+        right: Box<BuildNode>,
+    },
+}
+
+impl BuildNode {
+    /// Builds a tree over `bboxes` with `builder`, reordering `order` to match.
+    /// `order` maps positions in the tree back to indices into `bboxes`.
+    fn build(bboxes: &[Aabb], order: &mut [usize], start: usize, builder: BvhBuilder)
+        -> BuildNode
+    {
+        let bbox = order[1..]
+            .iter()
+            .fold(bboxes[order[0]], |acc, &i| Aabb::surrounding(&acc, &bboxes[i]));
+
+        if order.len() <= MAX_LEAF_SIZE {
+            return BuildNode::Leaf {
+                bbox,
+                range: start..(start + order.len()),
+            };
+        }
+
+        // Both splits leave at least one hitable on each side, so this always
+        // gets to the leaves.
+        let (axis, mid) = match builder {
+            BvhBuilder::Median => median_split(bboxes, order),
+            BvhBuilder::Sah => {
+                // With every centroid in one place, there's nothing to choose
+                // between, so just halve them.
+                sah_split(bboxes, order).unwrap_or_else(|| median_split(bboxes, order))
+            },
+        };
+        let (lower, upper) = order.split_at_mut(mid);
+        BuildNode::Interior {
+            bbox,
+            axis,
+            left:  Box::new(BuildNode::build(bboxes, lower, start, builder)),
+            right: Box::new(BuildNode::build(bboxes, upper, start + mid, builder)),
+        }
+    }
+
+    pub fn bbox(&self) -> &Aabb {
+        match self {
+            BuildNode::Leaf { bbox, .. }     => bbox,
+            BuildNode::Interior { bbox, .. } => bbox,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            BuildNode::Leaf { .. } => 1,
+            BuildNode::Interior { left, right, .. } => {
+                1 + left.depth().max(right.depth())
+            },
+        }
+    }
+
+    /// Recursive traversal of the unflattened tree. `Bvh` gives the same hits
+    /// without recursing; this is to check it against, and benchmark it with.
+    pub fn hit(&self,
+               hitables: &[Box<dyn Hitable>],
+               ray:      &Ray,
+               t_min:    Float,
+               t_max:    Float,
+               rng:      &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        if !self.bbox().hit(ray, t_min, t_max) {
+            return None;
+        }
+        match self {
+            BuildNode::Leaf { range, .. } => {
+                hit_closest(&hitables[range.clone()], ray, t_min, t_max, rng)
+            },
+            BuildNode::Interior { left, right, .. } => {
+                let left_hit = left.hit(hitables, ray, t_min, t_max, rng);
+                let closest = left_hit.as_ref().map_or(t_max, |record| record.t);
+                right.hit(hitables, ray, t_min, closest, rng).or(left_hit)
+            },
+        }
+    }
+
+    /// Appends this subtree to `nodes` in depth-first order.
+    fn flatten(&self, nodes: &mut Vec<FlatNode>) {
+        match self {
+            BuildNode::Leaf { bbox, range } => {
+                nodes.push(FlatNode {
+                    bbox:   *bbox,
+                    offset: range.start as u32,
+                    count:  range.len() as u16,
+                    axis:   0,
+                });
+            },
+            BuildNode::Interior { bbox, axis, left, right } => {
+                let me = nodes.len();
+                nodes.push(FlatNode {
+                    bbox:   *bbox,
+                    // Patched below, once we know where `right` starts.
+                    offset: 0,
+                    count:  0,
+                    axis:   *axis as u8,
+                });
+                left.flatten(nodes);
+                nodes[me].offset = nodes.len() as u32;
+                right.flatten(nodes);
+            },
+        }
+    }
+}
+
+/// The smallest and largest centroid of the boxes in `order`, along each axis.
+fn centroid_bounds(bboxes: &[Aabb], order: &[usize]) -> (Float3, Float3) {
+    let centroid = |i: usize| bboxes[i].centroid();
+    let mut min = centroid(order[0]);
+    let mut max = min;
+    for &i in order[1..].iter() {
+        min = min.min(&centroid(i));
+        max = max.max(&centroid(i));
+    }
+    (min, max)
+}
+
+/// Sorts `order` along whichever axis the centroids are spread out the most,
+/// to split in half there. Returns that axis, and where the half is.
+fn median_split(bboxes: &[Aabb], order: &mut [usize]) -> (usize, usize) {
+    let centroid = |i: usize| bboxes[i].centroid();
+    let (min, max) = centroid_bounds(bboxes, order);
+    let extent = max - min;
+    let axis = extent.argmax_component();
+
+    order.sort_by(|&a, &b| {
+        let a = centroid(a)[axis];
+        let b = centroid(b)[axis];
+        a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    (axis, order.len() / 2)
+}
+
+/// Finds the split with the lowest surface area heuristic cost, out of the
+/// ones between `SAH_BINS` buckets of centroids along each axis, and moves the
+/// hitables that go left of it to the front of `order`. Returns the axis, and
+/// how many went left.
+/// When the centroids are all in the same place, there's no split to find.
+fn sah_split(bboxes: &[Aabb], order: &mut [usize]) -> Option<(usize, usize)> {
+    let (min, max) = centroid_bounds(bboxes, order);
+    let extent = max - min;
+    let bucket = |i: usize, axis: usize| {
+        let offset = (bboxes[i].centroid()[axis] - min[axis]) / extent[axis];
+        ((offset * SAH_BINS as Float) as usize).min(SAH_BINS - 1)
+    };
+
+    // The cheapest split so far: its cost, axis, and the first bucket that goes
+    // right of it.
+    let mut best: Option<(Float, usize, usize)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        // How many hitables are in each bucket, and the box around them.
+        let mut buckets: [(usize, Option<Aabb>); SAH_BINS] = [(0, None); SAH_BINS];
+        for &i in order.iter() {
+            let (count, bbox) = &mut buckets[bucket(i, axis)];
+            *count += 1;
+            *bbox = Some(bbox.map_or(bboxes[i], |bbox| Aabb::surrounding(&bbox, &bboxes[i])));
+        }
+
+        // What ends up on either side of the split after each bucket but the
+        // last, sweeping in from both ends.
+        let left = sweep(buckets[..SAH_BINS - 1].iter());
+        let mut right = sweep(buckets[1..].iter().rev());
+        right.reverse();
+
+        for (split, (&(left_n, left_area), &(right_n, right_area))) in
+            left.iter().zip(right.iter()).enumerate()
+        {
+            if left_n == 0 || right_n == 0 {
+                continue;
+            }
+            let cost = left_area * left_n as Float + right_area * right_n as Float;
+            if best.map_or(true, |(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split + 1));
+            }
+        }
+    }
+
+    let (_, axis, split) = best?;
+    let (left, right): (Vec<usize>, Vec<usize>) = order.iter()
+                                                       .partition(|&&i| bucket(i, axis) < split);
+    let mid = left.len();
+    for (slot, i) in order.iter_mut().zip(left.into_iter().chain(right)) {
+        *slot = i;
+    }
+    Some((axis, mid))
+}
+
+/// How many hitables `buckets` hold, and the surface area of the box around
+/// them, up to and including each one.
+fn sweep<'a>(buckets: impl Iterator<Item=&'a (usize, Option<Aabb>)>) -> Vec<(usize, Float)> {
+    let mut count = 0;
+    let mut bbox: Option<Aabb> = None;
+    buckets.map(|&(n, b)| {
+               count += n;
+               bbox = match (bbox, b) {
+                   (Some(a), Some(b)) => Some(Aabb::surrounding(&a, &b)),
+                   (a, b) => a.or(b),
+               };
+               (count, bbox.map_or(0.0, |bbox| bbox.surface_area()))
+           })
+           .collect()
+}
+
+/// One node of a flattened tree.
+/// Leaves have a non-zero `count` of hitables starting at `offset`.
+/// Interior nodes have their second child at `offset`.
+#[derive(Copy, Clone, Debug)]
+pub struct FlatNode {
+    pub bbox:   Aabb,
+    pub offset: u32,
+    pub count:  u16,
+    pub axis:   u8,
+}
+
+impl FlatNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// How a BVH turned out, for --verbose.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BvhStats {
+    pub nodes:             usize,
+    pub leaves:            usize,
+    /// Of the deepest leaf. The root is at depth 1.
+    pub max_depth:         usize,
+    /// How many hitables are in each leaf, on average.
+    pub average_leaf_size: f64,
+    /// Of every node's box, added up. Rays visit boxes about in proportion to
+    /// their area, so trees with less of it are faster to trace.
+    pub surface_area:      Float,
+    /// Hitables without bounding boxes, which every ray tests.
+    pub unbounded:         usize,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BVH: {} nodes, {} deep, {} leaves of {:.2} hitables on average, \
+                   {:.1} total surface area",
+               self.nodes, self.max_depth, self.leaves, self.average_leaf_size,
+               self.surface_area)?;
+        if self.unbounded > 0 {
+            write!(f, ", and {} unbounded hitables", self.unbounded)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Bvh {
+    // Hitables with bounding boxes, in the order the tree refers to them.
+    hitables: Vec<Box<dyn Hitable>>,
+    // Hitables without them, which every ray tests.
+    unbounded: Vec<Box<dyn Hitable>>,
+    nodes: Vec<FlatNode>,
+    // When the tree is too deep for our traversal stack, skip the tree.
+    too_deep: bool,
+}
+
+impl Bvh {
+    /// Builds a BVH over `hitables`, bounding them over the times `t0..t1`,
+    /// with median splits.
+    pub fn new(hitables: Vec<Box<dyn Hitable>>, t0: Float, t1: Float) -> Bvh {
+        Bvh::with_builder(hitables, t0, t1, BvhBuilder::Median)
+    }
+
+    /// Like `new()`, splitting nodes the way `builder` does.
+    pub fn with_builder(hitables: Vec<Box<dyn Hitable>>,
+                        t0:       Float,
+                        t1:       Float,
+                        builder:  BvhBuilder)
+        -> Bvh
+    {
+        let (tree, hitables, unbounded) = Bvh::build_tree(hitables, t0, t1, builder);
+        let mut nodes = vec![];
+        let mut too_deep = false;
+        if let Some(tree) = tree {
+            tree.flatten(&mut nodes);
+            too_deep = tree.depth() > STACK_SIZE;
+        }
+
+        Bvh {
+            hitables,
+            unbounded,
+            nodes,
+            too_deep,
+        }
+    }
+
+    /// Builds the unflattened tree.
+    /// Returns the tree, the bounded hitables it refers to, and the rest.
+    pub fn build_tree(hitables: Vec<Box<dyn Hitable>>,
+                      t0:       Float,
+                      t1:       Float,
+                      builder:  BvhBuilder)
+        -> (Option<BuildNode>, Vec<Box<dyn Hitable>>, Vec<Box<dyn Hitable>>)
+    {
+        let mut bounded = vec![];
+        let mut bboxes = vec![];
+        let mut unbounded = vec![];
+        for hitable in hitables {
+            match hitable.bounding_box(t0, t1) {
+                Some(bbox) => {
+                    bboxes.push(bbox);
+                    bounded.push(Some(hitable));
+                },
+                None => unbounded.push(hitable),
+            }
+        }
+
+        if bounded.is_empty() {
+            return (None, vec![], unbounded);
+        }
+
+        let mut order: Vec<usize> = (0..bounded.len()).collect();
+        let tree = BuildNode::build(&bboxes, &mut order, 0, builder);
+        // Each index shows up exactly once, so every `take()` finds something.
+        let hitables = order
+            .into_iter()
+            .map(|i| bounded[i].take().unwrap())
+            .collect();
+
+        (Some(tree), hitables, unbounded)
+    }
+
+    pub fn nodes(&self) -> &[FlatNode] {
+        &self.nodes
+    }
+
+    /// How the tree turned out.
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            nodes:     self.nodes.len(),
+            unbounded: self.unbounded.len(),
+            ..BvhStats::default()
+        };
+        let mut in_leaves = 0;
+        // Nodes to visit, and how deep they are.
+        let mut stack = vec![];
+        if !self.nodes.is_empty() {
+            stack.push((0, 1));
+        }
+        while let Some((i, depth)) = stack.pop() {
+            let node = &self.nodes[i];
+            stats.max_depth = stats.max_depth.max(depth);
+            stats.surface_area += node.bbox.surface_area();
+            if node.is_leaf() {
+                stats.leaves += 1;
+                in_leaves += node.count as usize;
+            } else {
+                stack.push((i + 1, depth + 1));
+                stack.push((node.offset as usize, depth + 1));
+            }
+        }
+        if stats.leaves > 0 {
+            stats.average_leaf_size = in_leaves as f64 / stats.leaves as f64;
+        }
+        stats
+    }
+
+    fn hit_tree(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        if self.too_deep {
+            return hit_closest(&self.hitables, ray, t_min, t_max, rng);
+        }
+
+        // Every box on the way down sees the same ray.
+        let precomp = RayPrecomp::new(ray);
+        let mut o_hit_record = None;
+        let mut closest = t_max;
+
+        let mut stack = [0_u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0_u32;
+        loop {
+            let node = &self.nodes[current as usize];
+            if node.bbox.hit_precomp(&precomp, t_min, closest) {
+                if node.is_leaf() {
+                    let start = node.offset as usize;
+                    let range = start..(start + node.count as usize);
+                    let found = hit_closest(&self.hitables[range], ray, t_min, closest, rng);
+                    if let Some(record) = found {
+                        closest = record.t;
+                        o_hit_record = Some(record);
+                    }
+                } else {
+                    // Visit the child nearer to the ray first, so that hits
+                    // there can cull the farther one.
+                    let first = current + 1;
+                    let second = node.offset;
+                    if precomp.dir_is_neg[node.axis as usize] {
+                        stack[stack_len] = first;
+                        current = second;
+                    } else {
+                        stack[stack_len] = second;
+                        current = first;
+                    }
+                    stack_len += 1;
+                    continue;
+                }
+            }
+
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+
+        o_hit_record
+    }
+
+    /// Like `hit_tree()`, for each ray of a packet. Nodes are visited when any
+    /// of the rays goes through them, and only those rays look inside.
+    fn hit_tree_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        if self.nodes.is_empty() {
+            return Default::default();
+        }
+        if self.too_deep {
+            return hit_closest_packet(&self.hitables, rays, t_min, t_max, rngs);
+        }
+
+        let mut hits = Default::default();
+        let mut closest = t_max;
+
+        let mut stack = [0_u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0_u32;
+        loop {
+            let node = &self.nodes[current as usize];
+            let inside = node.bbox.hit_packet(rays, t_min, closest);
+            if let Some(lead) = inside.iter().position(|&hit| hit) {
+                if node.is_leaf() {
+                    let start = node.offset as usize;
+                    let range = start..(start + node.count as usize);
+                    let found = hit_closest_packet(&self.hitables[range],
+                                                   &rays.masked(inside),
+                                                   t_min,
+                                                   closest,
+                                                   rngs);
+                    keep_closer(&mut hits, &mut closest, found);
+                } else {
+                    // Rays from neighboring pixels nearly always agree on
+                    // which child is nearer. When they don't, the first one
+                    // inside picks.
+                    let first = current + 1;
+                    let second = node.offset;
+                    if rays.dir_is_neg[node.axis as usize][lead] {
+                        stack[stack_len] = first;
+                        current = second;
+                    } else {
+                        stack[stack_len] = second;
+                        current = first;
+                    }
+                    stack_len += 1;
+                    continue;
+                }
+            }
+
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+
+        hits
+    }
+
+    /// Like `hit_tree()`, but any hit will do, so we stop at the first one
+    /// instead of culling what's behind it.
+    fn hit_any_in_tree(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> bool
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        if self.too_deep {
+            return self.hitables.iter().any(|hitable| hitable.hit_any(ray, t_min, t_max, rng));
+        }
+
+        let precomp = RayPrecomp::new(ray);
+        let mut stack = [0_u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0_u32;
+        loop {
+            let node = &self.nodes[current as usize];
+            if node.bbox.hit_precomp(&precomp, t_min, t_max) {
+                if node.is_leaf() {
+                    let start = node.offset as usize;
+                    let range = start..(start + node.count as usize);
+                    if self.hitables[range].iter().any(|h| h.hit_any(ray, t_min, t_max, rng)) {
+                        return true;
+                    }
+                } else {
+                    let first = current + 1;
+                    let second = node.offset;
+                    if precomp.dir_is_neg[node.axis as usize] {
+                        stack[stack_len] = first;
+                        current = second;
+                    } else {
+                        stack[stack_len] = second;
+                        current = first;
+                    }
+                    stack_len += 1;
+                    continue;
+                }
+            }
+
+            if stack_len == 0 {
+                return false;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+    }
+}
+
+impl Hitable for Bvh {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let tree_hit = self.hit_tree(ray, t_min, t_max, rng);
+        let closest = tree_hit.as_ref().map_or(t_max, |record| record.t);
+        hit_closest(&self.unbounded, ray, t_min, closest, rng).or(tree_hit)
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        self.hit_any_in_tree(ray, t_min, t_max, rng) ||
+            self.unbounded.iter().any(|hitable| hitable.hit_any(ray, t_min, t_max, rng))
+    }
+
+    fn hit_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        let mut hits = self.hit_tree_packet(rays, t_min, t_max, rngs);
+        let mut closest = t_max;
+        for (lane, hit) in hits.iter().enumerate() {
+            if let Some(record) = hit {
+                closest.0[lane] = record.t;
+            }
+        }
+        let found = hit_closest_packet(&self.unbounded, rays, t_min, closest, rngs);
+        keep_closer(&mut hits, &mut closest, found);
+        hits
+    }
+
+    fn bounding_box(&self, _t0: Float, _t1: Float) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(|node| node.bbox)
+    }
+}
+
+/// The closest hit among `hitables`, just like `HitableList` finds it.
+fn hit_closest(hitables: &[Box<dyn Hitable>],
+               ray:      &Ray,
+               t_min:    Float,
+               t_max:    Float,
+               rng:      &mut dyn RngCore)
+    -> Option<HitRecord>
+{
+    let mut o_hit_record = None;
+    let mut closest = t_max;
+
+    for hitable in hitables.iter() {
+        if let Some(new_record) = hitable.hit(ray, t_min, closest, rng) {
+            closest = new_record.t;
+            o_hit_record = Some(new_record);
+        }
+    }
+
+    o_hit_record
+}
+
+#[cfg(test)]
+mod t {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::hitable::{
+        HitableList,
+        Sphere,
+    };
+    use crate::material::{
+        Cutout,
+        Lambertian,
+    };
+
+    fn random_spheres(n: usize) -> Vec<Sphere> {
+        let material: Arc<dyn Material> = Arc::new(Lambertian {
+            albedo: Float3::xxx(0.5),
+        });
+        (0..n)
+            .map(|_| Sphere {
+                center: 10.0 * Float3::xyz(random_sfloat(),
+                                           random_sfloat(),
+                                           random_sfloat()),
+                radius: 0.1 + random_float(),
+                material: material.clone(),
+            })
+            .collect()
+    }
+
+    fn boxed(spheres: &[Sphere]) -> Vec<Box<dyn Hitable>> {
+        spheres.iter()
+               .map(|s| Box::new(s.clone()) as Box<dyn Hitable>)
+               .collect()
+    }
+
+    fn random_ray() -> Ray {
+        Ray {
+            origin: 15.0 * Float3::xyz(random_sfloat(), random_sfloat(), random_sfloat()),
+            dir:    random_unit_vector(),
+            t:      0.0,
+        }
+    }
+
+    fn assert_same_hit(ours: Option<HitRecord>, theirs: Option<HitRecord>, ray: &Ray) {
+        match (ours, theirs) {
+            (None, None) => {},
+            (Some(ours), Some(theirs)) => {
+                assert_eq!(ours.t, theirs.t, "{:?}", ray);
+                assert_eq!(ours.p, theirs.p, "{:?}", ray);
+                assert_eq!(ours.normal, theirs.normal, "{:?}", ray);
+            },
+            (ours, theirs) => {
+                panic!("{:?}: BVH hit {:?}, but expected {:?}", ray, ours, theirs);
+            },
+        }
+    }
+
+    #[test]
+    fn check_aabb_hit() {
+        let bbox = Aabb {
+            min: Float3::xyz(-1., -1., -1.),
+            max: Float3::xyz(1., 1., 1.),
+        };
+        let ray = |origin: Float3, dir: Float3| Ray { origin, dir, t: 0.0 };
+
+        let toward = ray(Float3::xyz(-5., 0., 0.), Float3::xyz(1., 0., 0.));
+        let away   = ray(Float3::xyz(-5., 0., 0.), Float3::xyz(-1., 0., 0.));
+        let past   = ray(Float3::xyz(-5., 2., 0.), Float3::xyz(1., 0., 0.));
+        let inside = ray(Float3::xyz(0., 0., 0.), Float3::xyz(0., -1., 0.));
+
+        assert!(bbox.hit(&toward, 0.0, 100.0));
+        assert!(!bbox.hit(&toward, 0.0, 3.0), "The box is past t_max");
+        assert!(!bbox.hit(&away, 0.0, 100.0));
+        assert!(!bbox.hit(&past, 0.0, 100.0));
+        assert!(bbox.hit(&inside, 0.0, 100.0));
+    }
+
+    #[test]
+    fn check_aabb_interval() {
+        let unit = Aabb {
+            min: Float3::xyz(0., 0., 0.),
+            max: Float3::xyz(1., 1., 1.),
+        };
+        let interval = |origin: (Float, Float, Float), dir: (Float, Float, Float), tmax| {
+            let ray = Ray {
+                origin: Float3::xyz(origin.0, origin.1, origin.2),
+                dir:    Float3::xyz(dir.0, dir.1, dir.2),
+                t:      0.0,
+            };
+            unit.interval(&ray, 1.0e-3, tmax)
+        };
+
+        // Straight through, at either speed, and either way.
+        assert_eq!(interval((-1., 0.5, 0.5), (1., 0., 0.), 100.), Some((1., 2.)));
+        assert_eq!(interval((-1., 0.5, 0.5), (2., 0., 0.), 100.), Some((0.5, 1.)));
+        assert_eq!(interval((2., 0.5, 0.5), (-1., 0., 0.), 100.), Some((1., 2.)));
+        // Corner to corner.
+        assert_eq!(interval((-1., -1., -1.), (1., 1., 1.), 100.), Some((1., 2.)));
+        // Starting inside, we're already in at `tmin`.
+        assert_eq!(interval((0.5, 0.5, 0.5), (0., 0., -1.), 100.), Some((1.0e-3, 0.5)));
+        // Stopping inside, we leave at `tmax`.
+        assert_eq!(interval((-1., 0.5, 0.5), (1., 0., 0.), 1.5), Some((1., 1.5)));
+        assert_eq!(interval((-1., 0.5, 0.5), (1., 0., 0.), 0.5), None);
+        // Parallel to the y slabs, and outside of them.
+        assert_eq!(interval((-1., 2., 0.5), (1., 0., 0.), 100.), None);
+        // Or right along a face, which counts as inside.
+        assert_eq!(interval((-1., 0., 0.5), (1., 0., 0.), 100.), Some((1., 2.)));
+        assert_eq!(interval((-1., 1., 0.5), (1., 0., 0.), 100.), Some((1., 2.)));
+        // And behind the ray, it's never there.
+        assert_eq!(interval((2., 0.5, 0.5), (1., 0., 0.), 100.), None);
+    }
+
+    #[test]
+    fn check_bvh_matches_list() {
+        let mut rng = seeded_rng(0);
+        for &builder in BvhBuilder::ALL {
+            for &n in [1, 2, 5, 17, 300].iter() {
+                let spheres = random_spheres(n);
+                let list = HitableList {
+                    hitables: boxed(&spheres),
+                };
+                let bvh = Bvh::with_builder(boxed(&spheres), 0., 0., builder);
+                let (tree, tree_hitables, unbounded) =
+                    Bvh::build_tree(boxed(&spheres), 0., 0., builder);
+                let tree = tree.unwrap();
+                assert!(unbounded.is_empty());
+                assert_eq!(bvh.bounding_box(0., 0.), list.bounding_box(0., 0.));
+
+                for _ in 0..2000 {
+                    let ray = random_ray();
+                    let expected = list.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng);
+                    assert_same_hit(bvh.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng),
+                                    expected.clone(),
+                                    &ray);
+                    assert_same_hit(tree.hit(&tree_hitables, &ray, 1.0e-3, FLOAT_MAX, &mut rng),
+                                    expected,
+                                    &ray);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_degenerate_inputs() {
+        let mut rng = seeded_rng(0);
+        let material: Arc<dyn Material> = Arc::new(Lambertian { albedo: Float3::xxx(0.5) });
+        let at = |center: Float3| Sphere { center, radius: 1.0, material: material.clone() };
+        // Every sphere in the same place, a single sphere, and spheres spread
+        // out along only one axis.
+        let piles = [
+            vec![at(Float3::xyz(1., 2., 3.)); 100],
+            vec![at(Float3::new())],
+            (0..50).map(|i| at(Float3::xyz(i as Float, 0., 0.))).collect(),
+        ];
+        for &builder in BvhBuilder::ALL {
+            for spheres in piles.iter() {
+                let bvh = Bvh::with_builder(boxed(spheres), 0., 0., builder);
+                let stats = bvh.stats();
+                assert!(stats.max_depth <= STACK_SIZE, "{:?}: {}", builder, stats);
+                assert_eq!((stats.average_leaf_size * stats.leaves as f64).round() as usize,
+                           spheres.len());
+
+                let ray = Ray {
+                    origin: Float3::xyz(1., 2., -10.),
+                    dir:    Float3::xyz(0., 0., 1.),
+                    t:      0.0,
+                };
+                let list = HitableList { hitables: boxed(spheres) };
+                assert_same_hit(bvh.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng),
+                                list.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng),
+                                &ray);
+            }
+        }
+    }
+
+    #[test]
+    fn check_sah_has_less_area() {
+        seed_thread_rng(0x5a4);
+        let spheres = random_spheres(1000);
+        let median = Bvh::with_builder(boxed(&spheres), 0., 0., BvhBuilder::Median).stats();
+        let sah = Bvh::with_builder(boxed(&spheres), 0., 0., BvhBuilder::Sah).stats();
+        // Both trees have the whole scene at the root.
+        assert_eq!(median.leaves * 2 - 1, median.nodes);
+        assert_eq!(sah.leaves * 2 - 1, sah.nodes);
+        assert!(sah.surface_area < median.surface_area, "{} vs {}", sah, median);
+
+        assert_eq!("sah".parse::<BvhBuilder>(), Ok(BvhBuilder::Sah));
+        assert!("best".parse::<BvhBuilder>().is_err());
+    }
+
+    #[test]
+    fn check_hit_any_matches_hit() {
+        seed_thread_rng(0x5eed);
+        let mut rng = seeded_rng(0);
+        for &n in [1, 5, 300].iter() {
+            let spheres = random_spheres(n);
+            let list = HitableList {
+                hitables: boxed(&spheres),
+            };
+            let bvh = Bvh::new(boxed(&spheres), 0., 0.);
+            for _ in 0..2000 {
+                let ray = random_ray();
+                // Sometimes stopping short of things, like shadow rays do.
+                let t_max = 30.0 * random_float();
+                let expected = list.hit(&ray, 1.0e-3, t_max, &mut rng).is_some();
+                assert_eq!(list.hit_any(&ray, 1.0e-3, t_max, &mut rng), expected, "{:?}", ray);
+                assert_eq!(bvh.hit_any(&ray, 1.0e-3, t_max, &mut rng), expected, "{:?}", ray);
+            }
+        }
+
+        // Nothing stops at a hole.
+        let hole = Sphere {
+            center:   Float3::new(),
+            radius:   1.0,
+            material: Arc::new(Cutout {
+                material: Lambertian::new(Float3::xxx(0.5)),
+                alpha:    Arc::new(Float3::new()),
+            }),
+        };
+        let ray = Ray {
+            origin: Float3::xyz(0., 0., -5.),
+            dir:    Float3::xyz(0., 0., 1.),
+            t:      0.0,
+        };
+        assert!(hole.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng).is_some());
+        let bvh = Bvh::new(vec![Box::new(hole)], 0., 0.);
+        assert!(!bvh.hit_any(&ray, 1.0e-3, FLOAT_MAX, &mut rng));
+    }
+
+    #[test]
+    fn check_flat_layout() {
+        for &builder in BvhBuilder::ALL {
+            check_flat_layout_of(Bvh::with_builder(boxed(&random_spheres(100)), 0., 0., builder));
+        }
+    }
+
+    fn check_flat_layout_of(bvh: Bvh) {
+        let nodes = bvh.nodes();
+
+        // Every hitable shows up in exactly one leaf.
+        let mut seen = vec![0; 100];
+        for node in nodes.iter().filter(|node| node.is_leaf()) {
+            assert!(node.count as usize <= MAX_LEAF_SIZE);
+            for i in node.offset..(node.offset + node.count as u32) {
+                seen[i as usize] += 1;
+            }
+        }
+        assert_eq!(seen, vec![1; 100]);
+
+        // Children come after their parents, and fit inside them.
+        for (i, node) in nodes.iter().enumerate().filter(|(_, node)| !node.is_leaf()) {
+            let second = node.offset as usize;
+            assert!(i + 1 < second && second < nodes.len());
+            for child in [&nodes[i + 1], &nodes[second]].iter() {
+                assert_eq!(Aabb::surrounding(&node.bbox, &child.bbox), node.bbox);
+            }
+        }
+    }
+}
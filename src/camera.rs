@@ -1,7 +1,121 @@
-use std::f64::consts;
+use std::str::FromStr;
 
+use crate::float3::consts;
 use crate::prelude::*;
 
+/// How the camera flattens the scene into an image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Rays spread out from a point, `vfov` degrees apart from the bottom of
+    /// the image to the top.
+    Perspective { vfov: Float },
+    /// Rays all go the same way, from across a plane `height` tall, so things
+    /// are as big however far away they are. There's no lens to focus, so the
+    /// aperture doesn't matter.
+    Orthographic { height: Float },
+    /// Rays go out from a point in every direction, as a 360 degree panorama:
+    /// longitude goes across the image, all the way around, and latitude from
+    /// straight down at the bottom to straight up at the top. The middle looks
+    /// at `lookat`. Images should be twice as wide as they are tall, and like
+    /// orthographic cameras, there's no lens.
+    Equirectangular,
+    /// Rays spread out from a point, further from straight ahead the further
+    /// they are from the middle of the image, up to `fov / 2` degrees at the
+    /// edge of a circle that fits the image. Outside of it, there's nothing.
+    Fisheye { fov: Float },
+}
+
+/// Which `Projection` to use, without its size, for the command line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProjectionKind {
+    Perspective,
+    Orthographic,
+    Equirectangular,
+    Fisheye,
+}
+
+impl ProjectionKind {
+    pub const ALL: &'static [ProjectionKind] = &[
+        ProjectionKind::Perspective,
+        ProjectionKind::Orthographic,
+        ProjectionKind::Equirectangular,
+        ProjectionKind::Fisheye,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProjectionKind::Perspective     => "perspective",
+            ProjectionKind::Orthographic    => "ortho",
+            ProjectionKind::Equirectangular => "equirect",
+            ProjectionKind::Fisheye         => "fisheye",
+        }
+    }
+}
+
+impl FromStr for ProjectionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProjectionKind, String> {
+        ProjectionKind::ALL
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = ProjectionKind::ALL.iter().map(|k| k.name()).collect();
+                format!("Unknown projection \"{}\". Expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Parses an aspect ratio, as width over height: either like "16:9", or a
+/// number like "1.5".
+pub fn parse_aspect(s: &str) -> Result<Float, String> {
+    let number = |part: &str| {
+        part.trim()
+            .parse::<Float>()
+            .map_err(|e| format!("Bad aspect ratio \"{}\": {}", s, e))
+    };
+    let aspect = match s.find(':') {
+        Some(colon) => number(&s[..colon])? / number(&s[colon + 1..])?,
+        None => number(s)?,
+    };
+    if !(aspect > 0.0) || aspect.is_infinite() {
+        return Err(format!("Aspect ratios must be positive, found \"{}\"", s));
+    }
+    Ok(aspect)
+}
+
+/// The shape of the lens opening, which out of focus highlights take on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Aperture {
+    Circle,
+    /// A regular polygon with `blades` sides, like the iris of a real lens,
+    /// turned `rotation` degrees counterclockwise.
+    Polygon { blades: u32, rotation: Float },
+}
+
+impl Aperture {
+    /// Circles for 0 blades, and polygons for 3 or more.
+    pub fn from_blades(blades: u32, rotation: Float) -> Result<Aperture, String> {
+        match blades {
+            0 => Ok(Aperture::Circle),
+            1 | 2 => Err(format!("An aperture needs at least 3 blades, or 0 for a circle, \
+                                  not {}", blades)),
+            _ => Ok(Aperture::Polygon { blades, rotation }),
+        }
+    }
+
+    /// A random point on the aperture, as big as the unit disk.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> Float3 {
+        match *self {
+            Aperture::Circle => random_in_disk_with(rng),
+            Aperture::Polygon { blades, rotation } => {
+                random_in_polygon_with(rng, blades, rotation)
+            },
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
     pub w:           Float3,
@@ -14,14 +128,16 @@ pub struct Camera {
     pub lens_radius: Float,
     pub t_start:     Float,
     pub t_end:       Float,
+    pub projection:  Projection,
+    pub aperture:    Aperture,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct CameraInfo {
     pub lookfrom:   Float3,
     pub lookat:     Float3,
     pub up:         Float3,
-    pub vfov:       Float,
+    pub projection: Projection,
     pub aspect:     Float,
     pub aperature:  Float,
     pub focus_dist: Float,
@@ -29,6 +145,18 @@ pub struct CameraInfo {
     pub t_end:      Float,
 }
 
+/// Everything `Camera::new` works out, for checking it in tests.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DebugInfo {
+    pub u:           Float3,
+    pub v:           Float3,
+    pub w:           Float3,
+    pub lower_left:  Float3,
+    pub horizontal:  Float3,
+    pub vertical:    Float3,
+    pub lens_radius: Float,
+}
+
 impl Camera {
     pub fn new(info: CameraInfo) -> Camera {
         // We need a few things to create our camera.
@@ -40,8 +168,18 @@ impl Camera {
         // To keep the top and bottom lines of the plane at the same visual
         // angle, we scale its height by the distance away that it is.
         // Width then follows from `info.aspect`.
-        let theta:       Float = info.vfov * consts::PI / 180.0;
-        let half_height: Float = (theta / 2.0).tan();
+        // Orthographic cameras have a plane of the same size, wherever it is.
+        let half_height: Float = match info.projection {
+            Projection::Perspective { vfov } => {
+                let theta: Float = vfov * consts::PI / 180.0;
+                (theta / 2.0).tan()
+            },
+            Projection::Orthographic { height } => height / 2.0,
+            // Panoramas don't have a plane.
+            Projection::Equirectangular => 1.0,
+            // The image circle has a radius of 1, and fits the shorter side.
+            Projection::Fisheye { .. } => 1.0 / info.aspect.min(1.0),
+        };
         let half_width:  Float = info.aspect * half_height;
 
         // We also need to construct three directions to describe the plane:
@@ -58,8 +196,8 @@ impl Camera {
         let u: Float3 = info.up.cross(&w).unit();
         let v: Float3 = w.cross(&u); // Note: Don't need to `.unit()`
 
-        let CameraInfo { lookfrom, focus_dist, ..} = info;
-        Camera {
+        let CameraInfo { lookfrom, focus_dist, projection, ..} = info;
+        let camera = Camera {
             u,
             v,
             w,
@@ -71,18 +209,451 @@ impl Camera {
             lens_radius: info.aperature / 2.0,
             t_start:     info.t_start,
             t_end:       info.t_end,
+            projection,
+            aperture:    Aperture::Circle,
+        };
+        match projection {
+            Projection::Perspective { .. } => camera,
+            // Rays start on a plane through `lookfrom`, and go straight ahead.
+            Projection::Orthographic { .. } => Camera {
+                horizontal:  2.0 * half_width * u,
+                vertical:    2.0 * half_height * v,
+                lower_left:  lookfrom - (half_width * u + half_height * v),
+                lens_radius: 0.0,
+                ..camera
+            },
+            Projection::Equirectangular => Camera {
+                lens_radius: 0.0,
+                ..camera
+            },
+            // Fisheyes keep the focus plane, but only to say where on it
+            // (`s`, `t`) is. See `fisheye_film`.
+            Projection::Fisheye { .. } => camera,
+        }
+    }
+
+    /// The same camera, with its lens opening shaped like `aperture`.
+    pub fn with_aperture(self, aperture: Aperture) -> Camera {
+        Camera {
+            aperture,
+            ..self
+        }
+    }
+
+    /// Whether anything shows up at (`s`, `t`), which is everywhere but
+    /// outside a fisheye's image circle.
+    pub fn sees(&self, s: Float, t: Float) -> bool {
+        match self.projection {
+            Projection::Fisheye { .. } => self.fisheye_film(s, t).length_sq() <= 1.0,
+            _ => true,
+        }
+    }
+
+    /// How far the focus plane is in front of the camera.
+    fn focus_dist(&self) -> Float {
+        (self.origin - self.lower_left).dot(&self.w)
+    }
+
+    /// Where (`s`, `t`) is relative to the middle of a fisheye's image, where
+    /// the image circle has a radius of 1.
+    fn fisheye_film(&self, s: Float, t: Float) -> Float3 {
+        let focus_dist = self.focus_dist();
+        let on_plane = self.lower_left + s * self.horizontal + t * self.vertical;
+        (on_plane - self.origin + focus_dist * self.w) / focus_dist
+    }
+
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            u:           self.u,
+            v:           self.v,
+            w:           self.w,
+            lower_left:  self.lower_left,
+            horizontal:  self.horizontal,
+            vertical:    self.vertical,
+            lens_radius: self.lens_radius,
         }
     }
 
-    pub fn get_ray(&self, s: Float, t: Float) -> Ray {
-        let disk = self.lens_radius * random_in_disk();
+    /// Where `point` shows up on screen, as the `(s, t)` that `get_ray()` takes.
+    /// This ignores the lens, as if the aperture were a pinhole.
+    /// Points behind the camera don't show up, and return `None`.
+    /// Points in front but out of view are outside of [0, 1].
+    pub fn project(&self, point: Float3) -> Option<(Float, Float)> {
+        let dir = point - self.origin;
+        match self.projection {
+            Projection::Perspective { .. } => {},
+            Projection::Orthographic { .. } => {
+                if -dir.dot(&self.w) <= 0.0 {
+                    return None;
+                }
+                let on_plane = point - self.lower_left;
+                let s = on_plane.dot(&self.horizontal) / self.horizontal.length_sq();
+                let t = on_plane.dot(&self.vertical) / self.vertical.length_sq();
+                return Some((s, t));
+            },
+            // Everything shows up somewhere, except the camera itself.
+            Projection::Equirectangular => {
+                if dir.near_zero() {
+                    return None;
+                }
+                let dir = dir.unit();
+                let longitude = dir.dot(&self.u).atan2(-dir.dot(&self.w));
+                let latitude = dir.dot(&self.v).max(-1.0).min(1.0).asin();
+                return Some((0.5 + longitude / (2.0 * consts::PI), 0.5 + latitude / consts::PI));
+            },
+            // Points past the image circle don't show up, even in the corners.
+            Projection::Fisheye { fov } => {
+                if dir.near_zero() {
+                    return None;
+                }
+                let dir = dir.unit();
+                let angle = (-dir.dot(&self.w)).max(-1.0).min(1.0).acos();
+                let radius = angle / (fov.to_radians() / 2.0);
+                if radius > 1.0 {
+                    return None;
+                }
+                let (x, y) = (dir.dot(&self.u), dir.dot(&self.v));
+                let across = (x * x + y * y).sqrt();
+                let (x, y) = if across > 0.0 { (x / across, y / across) } else { (0.0, 0.0) };
+                let focus_dist = self.focus_dist();
+                let s = 0.5 + radius * x * focus_dist / self.horizontal.length();
+                let t = 0.5 + radius * y * focus_dist / self.vertical.length();
+                return Some((s, t));
+            },
+        }
+
+        // How far in front of the camera `point` is, and the plane is.
+        let depth = -dir.dot(&self.w);
+        let center: Float3 = self.lower_left + 0.5 * self.horizontal + 0.5 * self.vertical;
+        let plane_depth = -(center - self.origin).dot(&self.w);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        // Follow `dir` out to the plane, and see where on it we are.
+        let on_plane = self.origin + dir * (plane_depth / depth) - self.lower_left;
+        let s = on_plane.dot(&self.horizontal) / self.horizontal.length_sq();
+        let t = on_plane.dot(&self.vertical) / self.vertical.length_sq();
+        Some((s, t))
+    }
+
+    /// The ray through (`s`, `t`) on the film, from a random spot on the lens
+    /// and at a random time while the shutter is open, both drawn from `rng`.
+    pub fn get_ray(&self, s: Float, t: Float, rng: &mut dyn RngCore) -> Ray {
+        match self.projection {
+            Projection::Perspective { .. } => {},
+            Projection::Orthographic { .. } => {
+                return Ray {
+                    origin: self.lower_left + s * self.horizontal + t * self.vertical,
+                    dir:    -self.w,
+                    t:      random_float_in_with(rng, self.t_start, self.t_end),
+                };
+            },
+            Projection::Equirectangular => {
+                // Around from straight behind, through ahead at s = 0.5, to
+                // straight behind again, so the left and right edges meet.
+                let longitude = (s - 0.5) * 2.0 * consts::PI;
+                let latitude = (t - 0.5) * consts::PI;
+                let around = longitude.sin() * self.u - longitude.cos() * self.w;
+                return Ray {
+                    origin: self.origin,
+                    dir:    latitude.cos() * around + latitude.sin() * self.v,
+                    t:      random_float_in_with(rng, self.t_start, self.t_end),
+                };
+            },
+            Projection::Fisheye { fov } => {
+                // Equidistant: the angle from straight ahead grows evenly
+                // with the distance from the middle.
+                let film = self.fisheye_film(s, t);
+                let radius = film.length();
+                let angle = radius * fov.to_radians() / 2.0;
+                let across = if radius > 0.0 { film / radius } else { Float3::new() };
+                let dir = angle.sin() * across - angle.cos() * self.w;
+
+                // The lens moves where rays start, and they meet again at
+                // the focus distance.
+                let disk = self.lens_radius * self.aperture.sample(rng);
+                let offset = self.u * disk.x + self.v * disk.y;
+                return Ray {
+                    origin: self.origin + offset,
+                    dir:    self.focus_dist() * dir - offset,
+                    t:      random_float_in_with(rng, self.t_start, self.t_end),
+                };
+            },
+        }
+        let disk = self.lens_radius * self.aperture.sample(rng);
         let offset = self.u * disk.x + self.v * disk.y;
         let dir = (self.lower_left - self.origin) +
                   (s*self.horizontal + t*self.vertical);
         Ray {
             origin: self.origin + offset,
             dir:    dir - offset,
-            t:      random_float_in(self.t_start, self.t_end),
+            t:      random_float_in_with(rng, self.t_start, self.t_end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn info(lookfrom: Float3, lookat: Float3, up: Float3) -> CameraInfo {
+        CameraInfo {
+            lookfrom,
+            lookat,
+            up,
+            projection: Projection::Perspective { vfov: 90. },
+            aspect:     2.,
+            aperature:  0.5,
+            focus_dist: 1.,
+            t_start:    0.,
+            t_end:      0.,
+        }
+    }
+
+    fn assert_close(a: Float3, b: Float3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn check_debug_info_looking_down_z() {
+        let cam = Camera::new(info(Float3::new(),
+                                   Float3::xyz(0., 0., -1.),
+                                   Float3::xyz(0., 1., 0.)));
+        let debug = cam.debug_info();
+        assert_close(debug.u, Float3::xyz(1., 0., 0.));
+        assert_close(debug.v, Float3::xyz(0., 1., 0.));
+        assert_close(debug.w, Float3::xyz(0., 0., 1.));
+        // 90 degrees up and down is a plane 2 tall at distance 1, and twice that wide.
+        assert_close(debug.horizontal, Float3::xyz(4., 0., 0.));
+        assert_close(debug.vertical, Float3::xyz(0., 2., 0.));
+        assert_close(debug.lower_left, Float3::xyz(-2., -1., -1.));
+        assert_eq!(debug.lens_radius, 0.25);
+    }
+
+    #[test]
+    fn check_debug_info_looking_down_x() {
+        let cam = Camera::new(CameraInfo {
+            focus_dist: 2.,
+            ..info(Float3::xyz(5., 0., 0.),
+                   Float3::new(),
+                   Float3::xyz(0., 1., 0.))
+        });
+        let debug = cam.debug_info();
+        assert_close(debug.u, Float3::xyz(0., 0., -1.));
+        assert_close(debug.v, Float3::xyz(0., 1., 0.));
+        assert_close(debug.w, Float3::xyz(1., 0., 0.));
+        assert_close(debug.horizontal, Float3::xyz(0., 0., -8.));
+        assert_close(debug.vertical, Float3::xyz(0., 4., 0.));
+        assert_close(debug.lower_left, Float3::xyz(3., -2., 4.));
+    }
+
+    #[test]
+    fn check_debug_info_with_tilted_up() {
+        // `up` only needs to be in the right half-plane; the basis comes out square.
+        let cam = Camera::new(info(Float3::new(),
+                                   Float3::xyz(0., 0., -1.),
+                                   Float3::xyz(1., 1., 0.)));
+        let debug = cam.debug_info();
+        let r = (0.5 as Float).sqrt();
+        assert_close(debug.u, Float3::xyz(r, -r, 0.));
+        assert_close(debug.v, Float3::xyz(r, r, 0.));
+        assert_close(debug.w, Float3::xyz(0., 0., 1.));
+        assert!(debug.u.dot(&debug.v).abs() < 1e-9);
+    }
+
+    #[test]
+    fn check_project() {
+        let cam = Camera::new(info(Float3::xyz(0., 0., 3.),
+                                   Float3::new(),
+                                   Float3::xyz(0., 1., 0.)));
+        let (s, t) = cam.project(Float3::new()).unwrap();
+        assert!((s - 0.5).abs() < 1e-6 && (t - 0.5).abs() < 1e-6, "{} {}", s, t);
+
+        // Up and right of center.
+        let (s, t) = cam.project(Float3::xyz(1., 1., 0.)).unwrap();
+        assert!(s > 0.5 && t > 0.5, "{} {}", s, t);
+
+        // Projecting agrees with where a pinhole camera aims its rays.
+        let pinhole = Camera { lens_radius: 0., ..cam };
+        let ray = pinhole.get_ray(0.2, 0.7, &mut seeded_rng(0x5eed));
+        let (s, t) = cam.project(ray.at_t(5.)).unwrap();
+        assert!((s - 0.2).abs() < 1e-6 && (t - 0.7).abs() < 1e-6, "{} {}", s, t);
+
+        assert_eq!(cam.project(Float3::xyz(0., 0., 10.)), None);
+    }
+
+    #[test]
+    fn check_orthographic() {
+        let cam = Camera::new(CameraInfo {
+            projection: Projection::Orthographic { height: 4. },
+            ..info(Float3::xyz(0., 0., 5.), Float3::new(), Float3::xyz(0., 1., 0.))
+        });
+        let mut rng = seeded_rng(0x5eed);
+        let (mut min, mut max) = (Float3::xxx(FLOAT_MAX), Float3::xxx(-FLOAT_MAX));
+        for _ in 0..1000 {
+            let (s, t) = (random_float_with(&mut rng), random_float_with(&mut rng));
+            let ray = cam.get_ray(s, t, &mut rng);
+            // Every ray goes straight ahead, and the lens doesn't move them.
+            assert_eq!(ray.dir, Float3::xyz(0., 0., -1.));
+            let expected = Float3::xyz(8. * s - 4., 4. * t - 2., 5.);
+            assert!((ray.origin - expected).length() < 1e-5, "{:?}", ray.origin);
+            // And points along them project back to where they started.
+            let (ps, pt) = cam.project(ray.at_t(3.)).unwrap();
+            assert!((ps - s).abs() < 1e-5 && (pt - t).abs() < 1e-5, "{} {}", ps, pt);
+            min = min.min(&ray.origin);
+            max = max.max(&ray.origin);
+        }
+        // The origins cover a plane 4 tall, and twice as wide for the aspect.
+        assert!((min - Float3::xyz(-4., -2., 5.)).length() < 0.1, "{:?}", min);
+        assert!((max - Float3::xyz(4., 2., 5.)).length() < 0.1, "{:?}", max);
+        assert_eq!(cam.project(Float3::xyz(0., 0., 6.)), None);
+        assert_eq!("ortho".parse(), Ok(ProjectionKind::Orthographic));
+        assert!("cylinder".parse::<ProjectionKind>().is_err());
+    }
+
+    #[test]
+    fn check_equirectangular() {
+        // Lenses don't matter for panoramas.
+        let cam = Camera::new(CameraInfo {
+            projection: Projection::Equirectangular,
+            ..info(Float3::new(), Float3::xyz(0., 0., -1.), Float3::xyz(0., 1., 0.))
+        });
+        let near = |a: Float3, b: Float3| (a.unit() - b).length() < 1e-5;
+        let (width, height) = (64, 32);
+        let mut rng = seeded_rng(0x5eed);
+        // Ahead is in the middle, and turning right goes right.
+        for &(column, dir) in [(0, Float3::xyz(0., 0., 1.)),
+                               (16, Float3::xyz(-1., 0., 0.)),
+                               (32, Float3::xyz(0., 0., -1.)),
+                               (48, Float3::xyz(1., 0., 0.)),
+                               (64, Float3::xyz(0., 0., 1.))].iter()
+        {
+            let ray = cam.get_ray(column as Float / width as Float, 0.5, &mut rng);
+            assert_eq!(ray.origin, Float3::new());
+            assert!(near(ray.dir, dir), "{}: {:?}", column, ray.dir);
+            if column != 0 && column != 64 {
+                let (s, t) = cam.project(dir).unwrap();
+                assert!((s * width as Float - column as Float).abs() < 1e-3, "{}: {}", column, s);
+                assert!((t - 0.5).abs() < 1e-5);
+            }
         }
+
+        // The edges meet, and so does each pole.
+        for _ in 0..100 {
+            let t = random_float_with(&mut rng);
+            let left = cam.get_ray(1e-6, t, &mut rng).dir;
+            let right = cam.get_ray(1.0 - 1e-6, t, &mut rng).dir;
+            assert!((left - right).length() < 1e-4, "{:?} {:?}", left, right);
+        }
+        let top = (height as Float - 0.5) / height as Float;
+        for column in 0..width {
+            let s = (column as Float + 0.5) / width as Float;
+            let up = cam.get_ray(s, top, &mut rng).dir;
+            assert!(up.unit().y > 0.998, "{}: {:?}", column, up);
+            let down = cam.get_ray(s, 1.0 - top, &mut rng).dir;
+            assert!(down.unit().y < -0.998, "{}: {:?}", column, down);
+        }
+        assert_eq!(cam.project(Float3::new()), None);
+    }
+
+    #[test]
+    fn check_fisheye() {
+        let fisheye = |fov: Float, aperature: Float| Camera::new(CameraInfo {
+            projection: Projection::Fisheye { fov },
+            aperature,
+            ..info(Float3::xyz(0., 0., 5.), Float3::new(), Float3::xyz(0., 1., 0.))
+        });
+        let angle = |dir: Float3| (-dir.unit().z).max(-1.0).min(1.0).acos().to_degrees();
+        for &fov in [90., 180., 270.].iter() {
+            let cam = fisheye(fov, 0.);
+            let mut rng = seeded_rng(0x5eed);
+            // The middle looks straight ahead.
+            let ray = cam.get_ray(0.5, 0.5, &mut rng);
+            assert!((ray.dir.unit() - Float3::xyz(0., 0., -1.)).length() < 1e-6, "{:?}", ray.dir);
+
+            // The image circle fits the height, and its edge is fov / 2 out.
+            for &(s, t) in [(0.5, 1.), (0.5, 0.), (0.75, 0.5), (0.25, 0.5)].iter() {
+                assert!(cam.sees(s, t));
+                let dir = cam.get_ray(s, t, &mut rng).dir;
+                assert!((angle(dir) - fov / 2.).abs() < 1e-3, "{} {} {}: {:?}", fov, s, t, dir);
+            }
+            // Right goes right, and up goes up.
+            assert!(cam.get_ray(0.6, 0.5, &mut rng).dir.x > 0.);
+            assert!(cam.get_ray(0.5, 0.6, &mut rng).dir.y > 0.);
+
+            // The corners, and the sides past the circle, see nothing.
+            for &(s, t) in [(0., 0.), (1., 1.), (0.1, 0.5), (0.8, 0.9)].iter() {
+                assert!(!cam.sees(s, t), "{} {}", s, t);
+            }
+
+            // Points along rays project back to where they started.
+            for _ in 0..100 {
+                let (s, t) = (random_float_with(&mut rng), random_float_with(&mut rng));
+                if !cam.sees(s, t) {
+                    continue;
+                }
+                let (ps, pt) = cam.project(cam.get_ray(s, t, &mut rng).at_t(3.)).unwrap();
+                assert!((ps - s).abs() < 1e-4 && (pt - t).abs() < 1e-4, "{} {}", ps, pt);
+            }
+        }
+
+        // Past fov / 2, nothing shows up.
+        assert!(fisheye(90., 0.).project(Float3::xyz(10., 0., 0.)).is_none());
+
+        // The lens moves where rays start, but they still meet at the focus distance.
+        let cam = fisheye(180., 0.5);
+        let pinhole = fisheye(180., 0.);
+        let mut rng = seeded_rng(0x5eed);
+        let mut moved = false;
+        for _ in 0..100 {
+            let ray = cam.get_ray(0.6, 0.3, &mut rng);
+            let focus = pinhole.get_ray(0.6, 0.3, &mut rng).at_t(1.);
+            assert!((ray.at_t(1.) - focus).length() < 1e-6, "{:?} {:?}", ray, focus);
+            moved |= ray.origin != pinhole.origin;
+        }
+        assert!(moved);
+        assert_eq!("fisheye".parse(), Ok(ProjectionKind::Fisheye));
+    }
+
+    #[test]
+    fn check_parse_aspect() {
+        assert_eq!(parse_aspect("2:1"), Ok(2.0));
+        assert_eq!(parse_aspect("4:3"), Ok(4.0 / 3.0));
+        assert_eq!(parse_aspect(" 16 : 9 "), Ok(16.0 / 9.0));
+        assert_eq!(parse_aspect("1.5"), Ok(1.5));
+        assert!(parse_aspect("16:0").is_err());
+        assert!(parse_aspect("0").is_err());
+        assert!(parse_aspect("-4:3").is_err());
+        assert!(parse_aspect("16:9:1").is_err());
+        assert!(parse_aspect("wide").is_err());
+        assert!(parse_aspect("").is_err());
+    }
+
+    #[test]
+    fn check_aperture() {
+        // Straight down -Z from the origin, with a lens 0.25 across.
+        let cam = Camera::new(info(Float3::new(),
+                                   Float3::xyz(0., 0., -1.),
+                                   Float3::xyz(0., 1., 0.)));
+        let square = cam.with_aperture(Aperture::from_blades(4, 45.).unwrap());
+        let mut rng = seeded_rng(0x5eed);
+        let (mut min, mut max) = (Float3::xxx(FLOAT_MAX), Float3::xxx(-FLOAT_MAX));
+        for _ in 0..1000 {
+            let ray = square.get_ray(0.3, 0.6, &mut rng);
+            // The lens only moves where rays start.
+            let focus = Camera { lens_radius: 0., ..cam }.get_ray(0.3, 0.6, &mut rng).at_t(1.);
+            assert!((ray.at_t(1.) - focus).length() < 1e-6, "{:?} {:?}", ray, focus);
+            min = min.min(&ray.origin);
+            max = max.max(&ray.origin);
+        }
+        // Corners at 45 degrees make a square, lined up with the image.
+        let half_side = 0.25 * (0.5 as Float).sqrt();
+        assert!((max - Float3::xyz(half_side, half_side, 0.)).length() < 0.01, "{:?}", max);
+        assert!((min + Float3::xyz(half_side, half_side, 0.)).length() < 0.01, "{:?}", min);
+
+        assert_eq!(Aperture::from_blades(0, 30.), Ok(Aperture::Circle));
+        assert!(Aperture::from_blades(2, 0.).is_err());
+        assert_eq!(Aperture::from_blades(6, 0.), Ok(Aperture::Polygon { blades: 6, rotation: 0. }));
     }
 }
@@ -0,0 +1,240 @@
+//! Moving the camera over an animation.
+//!
+//! `--turntable N` orbits the camera once around `--lookat`, about the up
+//! axis, over N frames. The frame after the last one would be the first one
+//! again, so the animation loops without a seam.
+//!
+//! `--camera-path` reads keyframes from a file, one per line:
+//!
+//! ```text
+//! # time  lookfrom     lookat    vfov
+//! 0.0     13 2 3       0 0 0     20
+//! 0.5     0 2 13       0 0 0     30
+//! ```
+//!
+//! Keyframe times are on the same clock as `--t-start` and `--t-end`, and each
+//! frame uses the camera at the middle of its shutter. Between keyframes,
+//! `lookat`, `vfov`, and the distance to `lookfrom` are interpolated linearly,
+//! while the direction the camera looks in turns at a steady rate, so that
+//! orbits stay round. Before the first keyframe and after the last, the camera
+//! holds still.
+
+use std::{
+    fs,
+    path,
+};
+
+use crate::camera::{
+    CameraInfo,
+    Projection,
+};
+use crate::float3::consts;
+use crate::fly_camera::rotate;
+use crate::lut::{
+    lines,
+    parse_floats,
+};
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time:     Float,
+    pub lookfrom: Float3,
+    pub lookat:   Float3,
+    pub vfov:     Float,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CameraPath {
+    /// The camera stays where the flags put it.
+    Fixed,
+    /// Once around `lookat` over the whole animation.
+    Turntable,
+    /// Sorted by time, and at least one of them.
+    Keyframes(Vec<Keyframe>),
+}
+
+impl CameraPath {
+    /// The camera for `frame` of `n_frames`, whose shutter is open around `time`.
+    /// `info` is the camera that the flags describe.
+    pub fn camera(&self, info: CameraInfo, frame: u32, n_frames: u32, time: Float)
+        -> CameraInfo
+    {
+        match self {
+            CameraPath::Fixed => info,
+            CameraPath::Turntable => {
+                let angle = 2.0 * consts::PI * frame as Float / n_frames as Float;
+                let offset = rotate(info.lookfrom - info.lookat, info.up.unit(), angle);
+                CameraInfo {
+                    lookfrom: info.lookat + offset,
+                    ..info
+                }
+            },
+            CameraPath::Keyframes(keys) => {
+                let key = interpolate(keys, time);
+                // Other cameras have no field of view to change.
+                let projection = match info.projection {
+                    Projection::Perspective { .. } => Projection::Perspective { vfov: key.vfov },
+                    other => other,
+                };
+                CameraInfo {
+                    lookfrom: key.lookfrom,
+                    lookat:   key.lookat,
+                    projection,
+                    ..info
+                }
+            },
+        }
+    }
+}
+
+/// Reads the keyframes at `path`.
+pub fn load_keyframes(path: &path::Path) -> Result<Vec<Keyframe>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read camera path {}: {}", path.display(), e))?;
+    parse_keyframes(&text).map_err(|msg| format!("{}:{}", path.display(), msg))
+}
+
+/// Parses keyframes. Errors start with the line number they're about.
+pub fn parse_keyframes(text: &str) -> Result<Vec<Keyframe>, String> {
+    let mut keys: Vec<Keyframe> = vec![];
+    for (line_no, words) in lines(text) {
+        let values = parse_floats(line_no, &words, 8)?;
+        let key = Keyframe {
+            time:     values[0],
+            lookfrom: Float3::xyz(values[1], values[2], values[3]),
+            lookat:   Float3::xyz(values[4], values[5], values[6]),
+            vfov:     values[7],
+        };
+        if let Some(last) = keys.last() {
+            if key.time <= last.time {
+                return Err(format!("{}: times must increase, but {} follows {}",
+                                   line_no, key.time, last.time));
+            }
+        }
+        if key.lookfrom == key.lookat {
+            return Err(format!("{}: lookfrom and lookat are the same point", line_no));
+        }
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err("1: a camera path needs at least one keyframe".into());
+    }
+    Ok(keys)
+}
+
+/// The camera at `time`, between the keyframes on either side of it.
+fn interpolate(keys: &[Keyframe], time: Float) -> Keyframe {
+    let first = keys[0];
+    let last = keys[keys.len() - 1];
+    if time <= first.time {
+        return first;
+    }
+    if time >= last.time {
+        return last;
+    }
+    let i = keys.iter().position(|key| key.time > time).unwrap();
+    let (a, b) = (keys[i - 1], keys[i]);
+    let t = (time - a.time) / (b.time - a.time);
+
+    let lookat = Float3::lerp(t, a.lookat, b.lookat);
+    let (from_a, from_b) = (a.lookfrom - a.lookat, b.lookfrom - b.lookat);
+    let distance = (1.0 - t) * from_a.length() + t * from_b.length();
+    Keyframe {
+        time,
+        lookfrom: lookat + distance * slerp(t, from_a.unit(), from_b.unit()),
+        lookat,
+        vfov: (1.0 - t) * a.vfov + t * b.vfov,
+    }
+}
+
+/// Turns the unit vector `a` toward `b` at a steady rate, reaching it at t = 1.
+fn slerp(t: Float, a: Float3, b: Float3) -> Float3 {
+    let cos = a.dot(&b).max(-1.0).min(1.0);
+    let angle = cos.acos();
+    // Too close to tell apart, or exactly opposite, where any way around is
+    // as good as another. Going straight through is at least well defined.
+    if angle.sin().abs() < 1e-6 {
+        return Float3::lerp(t, a, b).unit();
+    }
+    (((1.0 - t) * angle).sin() * a + (t * angle).sin() * b) / angle.sin()
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn info() -> CameraInfo {
+        CameraInfo {
+            lookfrom:   Float3::xyz(3., 2., 4.),
+            lookat:     Float3::xyz(0., 1., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.5,
+            aperature:  0.1,
+            focus_dist: 5.,
+            t_start:    0.,
+            t_end:      1.,
+        }
+    }
+
+    fn assert_close(a: Float3, b: Float3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn check_turntable_orbits() {
+        let info = info();
+        let radius = (info.lookfrom - info.lookat).length();
+        const N: u32 = 12;
+        for frame in 0..N {
+            let cam = CameraPath::Turntable.camera(info, frame, N, 0.0);
+            // Same distance away, at the same height, looking at the same spot.
+            assert!(((cam.lookfrom - info.lookat).length() - radius).abs() < 1e-5);
+            assert!((cam.lookfrom.y - info.lookfrom.y).abs() < 1e-5);
+            assert_eq!(cam.lookat, info.lookat);
+            assert_eq!((cam.projection, cam.aperature, cam.focus_dist),
+                       (Projection::Perspective { vfov: 40. }, 0.1, 5.));
+        }
+        // A quarter of the way around is a quarter turn.
+        let flat = |v: Float3| Float3::xyz(v.x, 0., v.z);
+        let quarter = CameraPath::Turntable.camera(info, 3, N, 0.0);
+        let turned = flat(quarter.lookfrom - info.lookat).dot(&flat(info.lookfrom - info.lookat));
+        assert!(turned.abs() < 1e-5, "{}", turned);
+        // And all the way around is back where it started.
+        assert_close(CameraPath::Turntable.camera(info, N, N, 0.0).lookfrom, info.lookfrom);
+        assert_close(CameraPath::Turntable.camera(info, 0, N, 0.0).lookfrom, info.lookfrom);
+    }
+
+    #[test]
+    fn check_keyframes() {
+        let keys = parse_keyframes("# time  lookfrom  lookat  vfov\n\
+                                    0.0   10 0 0    0 0 0   20\n\
+                                    \n\
+                                    1.0   0 0 10    0 0 0   40  # a quarter turn\n").unwrap();
+        assert_eq!(keys.len(), 2);
+        let path = CameraPath::Keyframes(keys);
+
+        // Held still past either end.
+        assert_close(path.camera(info(), 0, 3, -1.0).lookfrom, Float3::xyz(10., 0., 0.));
+        assert_close(path.camera(info(), 0, 3, 2.0).lookfrom, Float3::xyz(0., 0., 10.));
+
+        // Halfway is halfway around the arc, not cutting across it.
+        let half = path.camera(info(), 1, 3, 0.5);
+        let r = 10.0 * (0.5 as Float).sqrt();
+        assert_close(half.lookfrom, Float3::xyz(r, 0., r));
+        assert_eq!(half.projection, Projection::Perspective { vfov: 30. });
+        // The rest comes from the flags.
+        assert_eq!(half.aspect, 1.5);
+    }
+
+    #[test]
+    fn check_bad_keyframes() {
+        assert!(parse_keyframes("").is_err());
+        assert!(parse_keyframes("0  1 2 3  0 0 0").is_err());
+        assert!(parse_keyframes("0  1 2 3  0 0 0  x").is_err());
+        let err = parse_keyframes("1  1 2 3  0 0 0  40\n1  1 2 3  0 0 0  40").unwrap_err();
+        assert!(err.starts_with("2:"), "{}", err);
+        assert!(parse_keyframes("0  1 2 3  1 2 3  40").is_err());
+    }
+}
@@ -0,0 +1,971 @@
+//! Saving a render's progress to disk, so that it can be resumed later.
+//!
+//! A checkpoint holds the settings the render was started with, and every
+//! pixel's summed radiance, sample count, and filter weight. The file format
+//! is simple:
+//!
+//! ```text
+//!     "WKNDCKPT"          8 bytes of magic
+//!     version             u32
+//!     width, height       u32, u32
+//!     seed                u64
+//!     samples per pixel   u32, what the render was aiming for
+//!     max depth           u32
+//!     scene               u32 length, then that many bytes of utf-8
+//!     tiles               u32 tiles_x, u32 tiles_y, u32 first, u32 end: the
+//!                         --tile-range rendered, or all 0 for the whole image
+//!     pixels              (u32 samples, f64 r, f64 g, f64 b, f64 weight), row-major
+//! ```
+//!
+//! Everything is little endian. Pixels are f64 even when `Float` is f32, so
+//! either build can resume the other's checkpoints. Version 1 didn't have
+//! weights, since every sample weighed 1 back then. Versions before 3 didn't
+//! have samples per pixel or a max depth, which was always `DEFAULT_MAX_DEPTH`.
+//! Versions before 4 were always of the whole image.
+//!
+//! Renders of part of an image, with `--tile-range`, are checkpointed like any
+//! other, and `merge()` puts the parts back together. Renders of the whole
+//! image with different seeds can be combined by `average()` instead.
+
+use std::{
+    fs,
+    ops::Range,
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    path,
+    sync::Mutex,
+};
+
+use crate::float3::FLOAT_EPSILON;
+use crate::output::LinearImage;
+use crate::prelude::*;
+use crate::render::{
+    RenderSettings,
+    DEFAULT_MAX_DEPTH,
+};
+
+const MAGIC: &[u8; 8] = b"WKNDCKPT";
+const VERSION: u32 = 4;
+
+/// Sum of every sample taken for a pixel so far.
+/// Samples are weighted by the reconstruction filter (see `filter`), and
+/// `radiance` is already multiplied by those weights.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PixelSum {
+    pub radiance:     Float3,
+    pub samples:      u32,
+    pub weight:       Float,
+    /// Weight of the samples that hit something, rather than the background.
+    /// Only counted with --transparent-background, which doesn't support
+    /// checkpoints, so this isn't saved in them.
+    pub covered:      Float,
+    /// Weighted sum of the squared luminance of every sample, for telling how
+    /// noisy the pixel is. Only --despeckle and --variance-map use this, and
+    /// they don't support --resume, so this isn't saved in checkpoints either.
+    pub luminance_sq: Float,
+}
+
+impl PixelSum {
+    /// Adds in one sample, with its weight from the reconstruction filter.
+    pub fn add(&mut self, rgb: Float3, weight: Float) {
+        self.radiance += weight * rgb;
+        self.weight += weight;
+        self.luminance_sq += weight * rgb.luminance() * rgb.luminance();
+        self.samples += 1;
+    }
+
+    pub fn average(&self) -> Float3 {
+        if self.weight == 0.0 {
+            Float3::new()
+        } else {
+            self.radiance / self.weight
+        }
+    }
+
+    /// How much of the pixel's samples hit something, from 0 to 1.
+    pub fn coverage(&self) -> Float {
+        if self.weight == 0.0 {
+            0.0
+        } else {
+            self.covered / self.weight
+        }
+    }
+
+    /// How uncertain the pixel's luminance is: the standard error of its
+    /// average, relative to the average itself.
+    /// None when there aren't enough samples (or light) to tell.
+    pub fn relative_error(&self) -> Option<Float> {
+        let mean = self.average().luminance();
+        if self.samples < 2 || mean <= 0.0 {
+            return None;
+        }
+        Some((self.luminance_variance() / self.samples as Float).sqrt() / mean)
+    }
+
+    /// How far the luminance of the pixel's samples is from their average,
+    /// typically. 0 when they all agree, or there's fewer than two of them.
+    pub fn std_dev(&self) -> Float {
+        if self.samples < 2 {
+            return 0.0;
+        }
+        let mean = self.average().luminance();
+        let variance = self.luminance_variance();
+        // Samples that are all the same can still leave a little rounding
+        // error behind, which isn't noise.
+        if variance <= 4.0 * FLOAT_EPSILON * self.samples as Float * mean * mean {
+            0.0
+        } else {
+            variance.sqrt()
+        }
+    }
+
+    /// Weighted variance of the luminance of the samples.
+    fn luminance_variance(&self) -> Float {
+        let mean = self.average().luminance();
+        (self.luminance_sq / self.weight - mean * mean).max(0.0)
+    }
+}
+
+/// Per-pixel sums for a whole image, shared between the threads rendering it
+/// and any thread that wants to checkpoint it.
+/// Each row has its own lock, so that tiles rarely contend.
+pub struct Accumulator {
+    width:  u32,
+    height: u32,
+    rows:   Vec<Mutex<Vec<PixelSum>>>,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Accumulator {
+        let rows = (0..height)
+            .map(|_| Mutex::new(vec![PixelSum::default(); width as usize]))
+            .collect();
+        Accumulator {
+            width,
+            height,
+            rows,
+        }
+    }
+
+    /// Picks up where `checkpoint` left off.
+    pub fn from_checkpoint(checkpoint: &Checkpoint) -> Accumulator {
+        let width = checkpoint.settings.width;
+        let height = checkpoint.settings.height;
+        let rows = checkpoint.pixels
+            .chunks(width as usize)
+            .map(|row| Mutex::new(row.to_vec()))
+            .collect();
+        Accumulator {
+            width,
+            height,
+            rows,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> PixelSum {
+        self.rows[y as usize].lock().unwrap()[x as usize]
+    }
+
+    pub fn set(&self, x: u32, y: u32, sum: PixelSum) {
+        self.rows[y as usize].lock().unwrap()[x as usize] = sum;
+    }
+
+    /// Throws away every sample, as if we'd just started.
+    pub fn reset(&self) {
+        for row in self.rows.iter() {
+            for sum in row.lock().unwrap().iter_mut() {
+                *sum = PixelSum::default();
+            }
+        }
+    }
+
+    /// The fewest and the most samples that any pixel has.
+    pub fn sample_range(&self) -> (u32, u32) {
+        self.rows.iter().fold((u32::MAX, 0), |(fewest, most), row| {
+            row.lock().unwrap().iter().fold((fewest, most), |(fewest, most), sum| {
+                (fewest.min(sum.samples), most.max(sum.samples))
+            })
+        })
+    }
+
+    /// Whether every pixel has at least `target` samples.
+    pub fn is_complete(&self, target: u32) -> bool {
+        self.rows.iter()
+                 .all(|row| row.lock().unwrap().iter().all(|sum| sum.samples >= target))
+    }
+
+    /// How far along each pixel is, as an image.
+    /// Pixels with all `target` samples are white, and pixels without any are
+    /// black. Anything in between is grey, but never fully black.
+    pub fn mask(&self, target: u32) -> image::GrayImage {
+        image::GrayImage::from_fn(self.width, self.height, |x, y| {
+            let samples = self.get(x, y).samples;
+            let level = if samples >= target {
+                255
+            } else if samples == 0 {
+                0
+            } else {
+                (255 * samples as u64 / target as u64).max(1).min(254) as u8
+            };
+            image::Luma([level])
+        })
+    }
+
+    /// How noisy each pixel is, for --variance-map: the standard deviation of
+    /// its samples, from black for none to white for the noisiest pixel.
+    /// This goes by the log of the standard deviation, so that the quieter
+    /// pixels aren't all lost in the dark.
+    pub fn noise_map(&self) -> image::GrayImage {
+        let mut std_devs = Vec::with_capacity(self.width as usize * self.height as usize);
+        for row in self.rows.iter() {
+            std_devs.extend(row.lock().unwrap().iter().map(|sum| sum.std_dev()));
+        }
+        let noisiest = std_devs.iter().cloned().fold(0.0, Float::max);
+        image::GrayImage::from_fn(self.width, self.height, |x, y| {
+            let std_dev = std_devs[(y * self.width + x) as usize];
+            let level = if std_dev > 0.0 {
+                (255.0 * std_dev.ln_1p() / noisiest.ln_1p()).round().max(1.0).min(255.0)
+            } else {
+                0.0
+            };
+            image::Luma([level as u8])
+        })
+    }
+
+    pub fn to_checkpoint(&self, settings: &RenderSettings) -> Checkpoint {
+        assert_eq!((settings.width, settings.height), (self.width, self.height));
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize);
+        for row in self.rows.iter() {
+            pixels.extend_from_slice(&row.lock().unwrap());
+        }
+        Checkpoint {
+            settings: settings.clone(),
+            pixels,
+            tiles: None,
+        }
+    }
+}
+
+/// The tiles that a `--tile-range` render covers, out of the grid it split the
+/// image into. Tile ids go left to right, and then top to bottom.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileRange {
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+    pub ids:     Range<u32>,
+}
+
+impl TileRange {
+    /// The id of the tile that has pixel (`x`, `y`) of a `width` x `height`
+    /// image in it.
+    pub fn tile_at(&self, x: u32, y: u32, width: u32, height: u32) -> u32 {
+        // Every tile is this big, except for the last ones in each direction.
+        let size_x = (width + self.tiles_x - 1) / self.tiles_x;
+        let size_y = (height + self.tiles_y - 1) / self.tiles_y;
+        (y / size_y) * self.tiles_x + x / size_x
+    }
+}
+
+/// Parses a range of tile ids like "0..32", which includes 0 but not 32.
+pub fn parse_tile_range(s: &str) -> Result<Range<u32>, String> {
+    let parts: Vec<&str> = s.split("..").map(|p| p.trim()).collect();
+    if parts.len() != 2 {
+        return Err(format!("Expected a range like \"first..end\", found \"{}\"", s));
+    }
+
+    let mut ends = [0; 2];
+    for (end, part) in ends.iter_mut().zip(parts.iter()) {
+        *end = part.parse::<u32>()
+                   .map_err(|e| format!("Bad tile id \"{}\": {}", part, e))?;
+    }
+    if ends[0] >= ends[1] {
+        return Err(format!("Tile range must have first < end, found {}..{}", ends[0], ends[1]));
+    }
+
+    Ok(ends[0]..ends[1])
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub settings: RenderSettings,
+    // Row-major, starting at the top of the image.
+    pub pixels:   Vec<PixelSum>,
+    /// Which tiles we rendered, if it wasn't all of them.
+    pub tiles:    Option<TileRange>,
+}
+
+impl Checkpoint {
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.settings.width.to_le_bytes())?;
+        w.write_all(&self.settings.height.to_le_bytes())?;
+        w.write_all(&self.settings.seed.to_le_bytes())?;
+        w.write_all(&self.settings.samples_per_pixel.to_le_bytes())?;
+        w.write_all(&self.settings.max_depth.to_le_bytes())?;
+        w.write_all(&(self.settings.scene.len() as u32).to_le_bytes())?;
+        w.write_all(self.settings.scene.as_bytes())?;
+        let tiles = self.tiles.as_ref().map_or([0; 4], |tiles| {
+            [tiles.tiles_x, tiles.tiles_y, tiles.ids.start, tiles.ids.end]
+        });
+        for n in tiles.iter() {
+            w.write_all(&n.to_le_bytes())?;
+        }
+        for px in self.pixels.iter() {
+            w.write_all(&px.samples.to_le_bytes())?;
+            for c in px.radiance.as_slice().iter() {
+                w.write_all(&(*c as f64).to_bits().to_le_bytes())?;
+            }
+            w.write_all(&(px.weight as f64).to_bits().to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Checkpoint> {
+        fn invalid(msg: String) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        }
+        fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+            let mut bytes = [0_u8; 4];
+            r.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        }
+        fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+            let mut bytes = [0_u8; 8];
+            r.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+
+        let mut magic = [0_u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a checkpoint file".into()));
+        }
+        let version = read_u32(r)?;
+        if version == 0 || version > VERSION {
+            return Err(invalid(format!("Checkpoint is version {}, but we only read up to \
+                                        version {}",
+                                       version, VERSION)));
+        }
+
+        let width = read_u32(r)?;
+        let height = read_u32(r)?;
+        let seed = read_u64(r)?;
+        let (samples_per_pixel, max_depth) = if version < 3 {
+            // We'll fill in samples per pixel from the pixels.
+            (None, DEFAULT_MAX_DEPTH)
+        } else {
+            (Some(read_u32(r)?), read_u32(r)?)
+        };
+        let scene_len = read_u32(r)?;
+        let mut scene = vec![0_u8; scene_len as usize];
+        r.read_exact(&mut scene)?;
+        let scene = String::from_utf8(scene)
+            .map_err(|e| invalid(format!("Bad scene name: {}", e)))?;
+        let tiles = if version < 4 {
+            None
+        } else {
+            let (tiles_x, tiles_y) = (read_u32(r)?, read_u32(r)?);
+            let ids = read_u32(r)?..read_u32(r)?;
+            match (tiles_x, tiles_y) {
+                (0, 0) => None,
+                (0, _) | (_, 0) => return Err(invalid("Bad tile grid".into())),
+                _ => Some(TileRange { tiles_x, tiles_y, ids }),
+            }
+        };
+
+        let len = width as usize * height as usize;
+        let mut pixels = Vec::with_capacity(len);
+        for _ in 0..len {
+            let samples = read_u32(r)?;
+            let mut radiance = Float3::new();
+            for c in radiance.as_mut_slice().iter_mut() {
+                *c = f64::from_bits(read_u64(r)?) as Float;
+            }
+            let weight = if version == 1 {
+                samples as Float
+            } else {
+                f64::from_bits(read_u64(r)?) as Float
+            };
+            pixels.push(PixelSum {
+                radiance,
+                samples,
+                weight,
+                covered: 0.0,
+                luminance_sq: 0.0,
+            });
+        }
+
+        Ok(Checkpoint {
+            settings: RenderSettings {
+                width,
+                height,
+                samples_per_pixel: samples_per_pixel.unwrap_or_else(|| {
+                    pixels.iter().map(|px| px.samples).max().unwrap_or(0)
+                }),
+                max_depth,
+                seed,
+                scene,
+            },
+            pixels,
+            tiles,
+        })
+    }
+
+    /// Writes the checkpoint next to `path` first and then moves it into place,
+    /// so that a crash while saving never loses the previous checkpoint.
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = path.with_file_name(format!(".tmp.{}", file_name));
+        {
+            let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+            self.write_to(&mut file)?;
+            file.flush()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &path::Path) -> io::Result<Checkpoint> {
+        let mut file = io::BufReader::new(fs::File::open(path)?);
+        Checkpoint::read_from(&mut file)
+    }
+
+    /// The average of every pixel's samples so far.
+    pub fn to_linear(&self) -> LinearImage {
+        let width = self.settings.width;
+        LinearImage::from_fn(width, self.settings.height, |x, y| {
+            self.pixels[(y * width + x) as usize].average()
+        })
+    }
+}
+
+/// Loads the checkpoints at `paths`, and puts them together with `combine`,
+/// which is `merge()` or `average()`.
+pub fn combine_files(paths:   &[path::PathBuf],
+                     combine: fn(&[Checkpoint]) -> Result<Checkpoint, String>)
+    -> Result<Checkpoint, String>
+{
+    let parts: Vec<Checkpoint> = paths.iter().map(|path| {
+        Checkpoint::load(path)
+            .map_err(|e| format!("Unable to load {}: {}", path.display(), e))
+    }).collect::<Result<_, String>>()?;
+    combine(&parts)
+}
+
+/// Puts renders of different `--tile-range`s of the same image back together,
+/// into a checkpoint of the whole thing. Every tile has to be finished in
+/// exactly one of `parts`.
+pub fn merge(parts: &[Checkpoint]) -> Result<Checkpoint, String> {
+    let first = match parts.first() {
+        Some(first) => first,
+        None => return Err("There's nothing to merge".into()),
+    };
+    let mut ranges: Vec<&TileRange> = vec![];
+    for (i, part) in parts.iter().enumerate() {
+        let range = part.tiles.as_ref().ok_or_else(|| {
+            format!("Part {} is of the whole image, and not of a --tile-range", i + 1)
+        })?;
+        first.settings.check_matches(&part.settings)
+            .map_err(|msg| format!("Part {} doesn't match part 1: {}", i + 1, msg))?;
+        if part.settings.samples_per_pixel != first.settings.samples_per_pixel {
+            return Err(format!("Part {} has {} samples per pixel, but part 1 has {}",
+                               i + 1, part.settings.samples_per_pixel,
+                               first.settings.samples_per_pixel));
+        }
+        let grid = |range: &TileRange| (range.tiles_x, range.tiles_y);
+        if let Some(first_range) = ranges.first() {
+            if grid(range) != grid(first_range) {
+                return Err(format!("Part {} has {}x{} tiles, but part 1 has {}x{}",
+                                   i + 1, range.tiles_x, range.tiles_y,
+                                   first_range.tiles_x, first_range.tiles_y));
+            }
+        }
+        ranges.push(range);
+    }
+
+    // Which part has each tile.
+    let n_tiles = ranges[0].tiles_x * ranges[0].tiles_y;
+    let mut owners: Vec<Option<usize>> = vec![None; n_tiles as usize];
+    for (i, range) in ranges.iter().enumerate() {
+        if range.ids.end > n_tiles {
+            return Err(format!("Part {} has tiles up to {}, but there are only {}",
+                               i + 1, range.ids.end, n_tiles));
+        }
+        for id in range.ids.clone() {
+            if let Some(other) = owners[id as usize].replace(i) {
+                return Err(format!("Tile {} is in both part {} and part {}", id, other + 1, i + 1));
+            }
+        }
+    }
+    let missing: Vec<String> = (0..n_tiles).filter(|&id| owners[id as usize].is_none())
+                                           .map(|id| id.to_string())
+                                           .collect();
+    if !missing.is_empty() {
+        return Err(format!("No part has tiles {}", missing.join(", ")));
+    }
+
+    let settings = &first.settings;
+    let mut pixels = Vec::with_capacity(first.pixels.len());
+    for y in 0..settings.height {
+        for x in 0..settings.width {
+            let id = ranges[0].tile_at(x, y, settings.width, settings.height);
+            let part = owners[id as usize].expect("Every tile has a part");
+            let px = parts[part].pixels[(y * settings.width + x) as usize];
+            if px.samples < settings.samples_per_pixel {
+                return Err(format!("Tile {} isn't finished in part {}", id, part + 1));
+            }
+            pixels.push(px);
+        }
+    }
+    Ok(Checkpoint {
+        settings: settings.clone(),
+        pixels,
+        tiles: None,
+    })
+}
+
+/// Combines renders of the same image with different seeds, as if one render
+/// had taken all of their samples. Each pixel sums every part's samples, so
+/// parts with more of them count for more.
+pub fn average(parts: &[Checkpoint]) -> Result<Checkpoint, String> {
+    let first = match parts.first() {
+        Some(first) => first,
+        None => return Err("There's nothing to average".into()),
+    };
+    let (a, mut settings) = (&first.settings, first.settings.clone());
+    for (i, part) in parts.iter().enumerate().skip(1) {
+        let b = &part.settings;
+        if (a.width, a.height) != (b.width, b.height) {
+            return Err(format!("Part {} is {}x{}, but part 1 is {}x{}",
+                               i + 1, b.width, b.height, a.width, a.height));
+        }
+        if a.scene != b.scene {
+            return Err(format!("Part {} rendered \"{}\", but part 1 rendered \"{}\"",
+                               i + 1, b.scene, a.scene));
+        }
+        if a.max_depth != b.max_depth {
+            return Err(format!("Part {} has a max depth of {}, but part 1 has {}",
+                               i + 1, b.max_depth, a.max_depth));
+        }
+        if let Some(j) = parts[..i].iter().position(|other| other.settings.seed == b.seed) {
+            // They'd have taken exactly the same samples.
+            return Err(format!("Parts {} and {} both have the seed {}", j + 1, i + 1, b.seed));
+        }
+        settings.samples_per_pixel += b.samples_per_pixel;
+    }
+    if let Some(i) = parts.iter().position(|part| part.tiles.is_some()) {
+        return Err(format!("Part {} is of a --tile-range. Merge its tiles without --average \
+                            first", i + 1));
+    }
+
+    let mut pixels = first.pixels.clone();
+    for part in parts[1..].iter() {
+        for (sum, px) in pixels.iter_mut().zip(part.pixels.iter()) {
+            sum.radiance += px.radiance;
+            sum.samples += px.samples;
+            sum.weight += px.weight;
+        }
+    }
+    Ok(Checkpoint {
+        settings,
+        pixels,
+        tiles: None,
+    })
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use crate::aov::PixelAovs;
+    use crate::camera::{
+        Camera,
+        CameraInfo,
+        Projection,
+    };
+    use crate::render::{
+        render_pixel,
+        Scene,
+    };
+    use crate::render::t::light_box_camera;
+    use crate::scenes::{
+        make_green_scene,
+        make_small_light_box,
+    };
+    use crate::tile_order::TileOrder;
+    use crate::tiles::{
+        TiledRender,
+        TilingPlan,
+    };
+
+    fn settings() -> RenderSettings {
+        RenderSettings {
+            width:             3,
+            height:            2,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              1234,
+            scene:             "cover".into(),
+        }
+    }
+
+    #[test]
+    fn check_std_dev() {
+        // Samples that take turns between two colors, with luminance 0.2 and
+        // 0.8, are 0.3 from their average either way.
+        let mut alternating = PixelSum::default();
+        for i in 0..64 {
+            alternating.add(Float3::xxx(if i % 2 == 0 { 0.2 } else { 0.8 }), 1.0);
+        }
+        assert!((alternating.std_dev() - 0.3).abs() < 1e-4, "{}", alternating.std_dev());
+
+        let mut constant = PixelSum::default();
+        for _ in 0..1000 {
+            constant.add(Float3::xyz(0.3, 0.6, 0.9), 0.7);
+        }
+        assert_eq!(constant.std_dev(), 0.0);
+
+        let mut single = PixelSum::default();
+        single.add(Float3::xxx(5.0), 1.0);
+        assert_eq!(single.std_dev(), 0.0);
+
+        let accum = Accumulator::new(3, 1);
+        accum.set(0, 0, constant);
+        accum.set(1, 0, alternating);
+        let mut quieter = PixelSum::default();
+        for i in 0..64 {
+            quieter.add(Float3::xxx(if i % 2 == 0 { 0.4 } else { 0.6 }), 1.0);
+        }
+        accum.set(2, 0, quieter);
+        let map = accum.noise_map();
+        assert_eq!(map.get_pixel(0, 0).data, [0]);
+        assert_eq!(map.get_pixel(1, 0).data, [255]);
+        let level = map.get_pixel(2, 0).data[0];
+        assert!(0 < level && level < 255, "{}", level);
+    }
+
+    #[test]
+    fn check_checkpoint_roundtrip() {
+        let accum = Accumulator::new(3, 2);
+        accum.set(0, 0, PixelSum {
+            radiance: Float3::xyz(1.5, 0.25, 1e-300),
+            samples:  7,
+            weight:   3.125,
+            ..PixelSum::default()
+        });
+        accum.set(2, 1, PixelSum {
+            radiance: Float3::xyz(0.1, 0.2, 0.3),
+            samples:  1,
+            weight:   1.0,
+            ..PixelSum::default()
+        });
+
+        let checkpoint = accum.to_checkpoint(&settings());
+        let mut bytes = vec![];
+        checkpoint.write_to(&mut bytes).unwrap();
+        let loaded = Checkpoint::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded, checkpoint);
+
+        let resumed = Accumulator::from_checkpoint(&loaded);
+        for y in 0..2 {
+            for x in 0..3 {
+                assert_eq!(resumed.get(x, y), accum.get(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn check_mask() {
+        let accum = Accumulator::new(4, 1);
+        for (x, &samples) in [0, 1, 50, 100].iter().enumerate() {
+            accum.set(x as u32, 0, PixelSum {
+                radiance: Float3::xxx(samples as Float),
+                samples,
+                ..PixelSum::default()
+            });
+        }
+
+        assert_eq!(accum.sample_range(), (0, 100));
+
+        let mask = accum.mask(100);
+        let levels: Vec<u8> = mask.pixels().map(|px| px.data[0]).collect();
+        assert_eq!(levels, [0, 2, 127, 255]);
+
+        // Even a single sample out of many is distinguishable from none.
+        let mask = accum.mask(1_000_000);
+        assert_eq!(mask.get_pixel(1, 0).data[0], 1);
+    }
+
+    #[test]
+    fn check_reset() {
+        let accum = Accumulator::new(2, 2);
+        assert!(!accum.is_complete(1));
+        for y in 0..2 {
+            for x in 0..2 {
+                accum.set(x, y, PixelSum {
+                    radiance: Float3::xxx(1.0),
+                    samples:  3,
+                    ..PixelSum::default()
+                });
+            }
+        }
+        assert!(accum.is_complete(3));
+        assert!(!accum.is_complete(4));
+
+        accum.reset();
+        assert_eq!(accum.get(1, 1), PixelSum::default());
+        assert!(accum.is_complete(0));
+        assert!(!accum.is_complete(1));
+    }
+
+    #[test]
+    fn check_checkpoint_rejects_garbage() {
+        let mut bytes = vec![];
+        Accumulator::new(3, 2).to_checkpoint(&settings()).write_to(&mut bytes).unwrap();
+
+        // Wrong magic
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(Checkpoint::read_from(&mut bad.as_slice()).is_err());
+
+        // Wrong version
+        let mut bad = bytes.clone();
+        bad[8] = 99;
+        assert!(Checkpoint::read_from(&mut bad.as_slice()).is_err());
+
+        // Truncated
+        let bad = &bytes[..bytes.len() - 1];
+        assert!(Checkpoint::read_from(&mut &bad[..]).is_err());
+    }
+
+    #[test]
+    fn check_version_1_checkpoints() {
+        // One pixel with two samples, from before we had filters.
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        for n in [1_u32, 1, 1].iter() {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(&1234_u64.to_le_bytes());
+        bytes.extend_from_slice(&0_u32.to_le_bytes());
+        bytes.extend_from_slice(&2_u32.to_le_bytes());
+        for c in [1.0_f64, 0.5, 0.25].iter() {
+            bytes.extend_from_slice(&c.to_bits().to_le_bytes());
+        }
+
+        let loaded = Checkpoint::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded.pixels[0].weight, 2.0);
+        assert_eq!(loaded.pixels[0].average(), Float3::xyz(0.5, 0.25, 0.125));
+        assert_eq!((loaded.settings.samples_per_pixel, loaded.settings.max_depth),
+                   (2, DEFAULT_MAX_DEPTH));
+    }
+
+    #[test]
+    fn check_settings_must_match() {
+        let ckpt = settings();
+        assert_eq!(ckpt.check_matches(&settings()), Ok(()));
+
+        let bigger = RenderSettings { width: 4, ..settings() };
+        assert!(ckpt.check_matches(&bigger).is_err());
+        let reseeded = RenderSettings { seed: 1, ..settings() };
+        assert!(ckpt.check_matches(&reseeded).is_err());
+        let other_scene = RenderSettings { scene: "green".into(), ..settings() };
+        assert!(ckpt.check_matches(&other_scene).is_err());
+        let deeper = RenderSettings { max_depth: 8, ..settings() };
+        assert!(ckpt.check_matches(&deeper).is_err());
+        // Resuming is how we get more samples.
+        let more_samples = RenderSettings { samples_per_pixel: 64, ..settings() };
+        assert_eq!(ckpt.check_matches(&more_samples), Ok(()));
+    }
+
+    #[test]
+    fn check_parse_tile_range() {
+        assert_eq!(parse_tile_range("0..32"), Ok(0..32));
+        assert_eq!(parse_tile_range(" 5 .. 6 "), Ok(5..6));
+        assert!(parse_tile_range("4..4").is_err());
+        assert!(parse_tile_range("0-32").is_err());
+        assert!(parse_tile_range("0..-1").is_err());
+    }
+
+    #[test]
+    fn check_merge() {
+        // Three tiles, one for each column, with the pixels in tiles outside
+        // each part left at 0 samples.
+        let part = |ids: Range<u32>| {
+            let accum = Accumulator::new(3, 2);
+            for y in 0..2 {
+                for x in ids.clone() {
+                    accum.set(x, y, PixelSum {
+                        radiance: Float3::xxx((y * 3 + x) as Float),
+                        samples:  4,
+                        weight:   1.0,
+                        ..PixelSum::default()
+                    });
+                }
+            }
+            let mut checkpoint = accum.to_checkpoint(&settings());
+            checkpoint.tiles = Some(TileRange { tiles_x: 3, tiles_y: 1, ids });
+            checkpoint
+        };
+
+        let mut bytes = vec![];
+        part(1..3).write_to(&mut bytes).unwrap();
+        assert_eq!(Checkpoint::read_from(&mut bytes.as_slice()).unwrap(), part(1..3));
+
+        let merged = merge(&[part(1..3), part(0..1)]).unwrap();
+        assert_eq!(merged.tiles, None);
+        let expected: Vec<Float3> = (0..6).map(|i| Float3::xxx(i as Float)).collect();
+        let averages: Vec<Float3> = merged.pixels.iter().map(|px| px.average()).collect();
+        assert_eq!(averages, expected);
+
+        assert_eq!(merge(&[part(0..2), part(1..3)]).err().unwrap(),
+                   "Tile 1 is in both part 1 and part 2");
+        assert_eq!(merge(&[part(0..1), part(2..3)]).err().unwrap(), "No part has tiles 1");
+        assert!(merge(&[part(0..1), Accumulator::new(3, 2).to_checkpoint(&settings())]).is_err());
+
+        let mut unfinished = part(1..3);
+        unfinished.pixels[2].samples = 3;
+        assert_eq!(merge(&[part(0..1), unfinished]).err().unwrap(),
+                   "Tile 2 isn't finished in part 2");
+    }
+
+    #[test]
+    fn check_average() {
+        let render = |seed: u64, rgb: Float3, samples: u32| {
+            let accum = Accumulator::new(3, 2);
+            let mut px = PixelSum::default();
+            for _ in 0..samples {
+                px.add(rgb, 1.0);
+            }
+            accum.set(1, 1, px);
+            accum.to_checkpoint(&RenderSettings {
+                seed,
+                samples_per_pixel: samples,
+                ..settings()
+            })
+        };
+
+        // Three times the samples count three times as much.
+        let averaged = average(&[render(1, Float3::xxx(1.0), 2),
+                                 render(2, Float3::xxx(5.0), 6)]).unwrap();
+        assert_eq!(averaged.settings.samples_per_pixel, 8);
+        assert_eq!(averaged.pixels[4].samples, 8);
+        assert_eq!(averaged.pixels[4].average(), Float3::xxx(4.0));
+        assert_eq!(averaged.pixels[0], PixelSum::default());
+
+        let same_seed = average(&[render(1, Float3::new(), 2), render(1, Float3::new(), 2)]);
+        assert_eq!(same_seed.err().unwrap(), "Parts 1 and 2 both have the seed 1");
+        let mut other_scene = render(2, Float3::new(), 2);
+        other_scene.settings.scene = "green".into();
+        assert!(average(&[render(1, Float3::new(), 2), other_scene]).is_err());
+        let mut bigger = render(2, Float3::new(), 2);
+        bigger.settings.width = 4;
+        assert!(average(&[render(1, Float3::new(), 2), bigger]).is_err());
+    }
+
+    #[test]
+    fn check_tile_ranges_merge_into_the_whole_image() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             20,
+            height:            12,
+            samples_per_pixel: 2,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let plan = TilingPlan::new(6, 1, settings.width, settings.height);
+        let render = |ids: Option<Range<u32>>| {
+            let accum = Accumulator::new(settings.width, settings.height);
+            let selected = |tile_id: u32, _: u32, _: u32| {
+                ids.as_ref().map_or(true, |ids| ids.contains(&tile_id))
+            };
+            TiledRender::new(plan, &settings, TileOrder::Scanline, &accum, PixelAovs::new(&[]),
+                             selected)
+                .render(&scene, &cam, &settings, &accum, 2, &|_| ());
+            let mut checkpoint = accum.to_checkpoint(&settings);
+            checkpoint.tiles = ids.map(|ids| {
+                TileRange { tiles_x: plan.tiles_x, tiles_y: plan.tiles_y, ids }
+            });
+            checkpoint
+        };
+
+        let whole = render(None);
+        let first = render(Some(0..3));
+        let second = render(Some(3..6));
+        assert!(first.pixels.iter().any(|px| px.samples == 0));
+        assert_eq!(merge(&[first.clone(), second.clone()]), Ok(whole.clone()));
+        assert!(merge(&[first.clone(), first.clone(), second.clone()]).is_err());
+        assert!(merge(&[second.clone()]).is_err());
+
+        // The same again from files, like `raytracer merge` does it.
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-parts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = [dir.join("first.ckpt"), dir.join("second.ckpt")];
+        first.save(&paths[0]).unwrap();
+        second.save(&paths[1]).unwrap();
+        let merged = combine_files(&paths, merge);
+        let missing = combine_files(&[paths[0].clone(), dir.join("missing.ckpt")], merge);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(merged.map(|merged| merged.to_linear()), Ok(whole.to_linear()));
+        assert!(missing.err().unwrap().starts_with("Unable to load"));
+    }
+
+    #[test]
+    fn check_averaged_renders_match_one_with_all_their_samples() {
+        // Lit by the sky, so that a few samples are already close.
+        let scene = Scene::new(make_green_scene());
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::new(),
+            lookat:     Float3::xyz(0., 0., -1.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 90. },
+            aspect:     32. / 24.,
+            aperature:  0.,
+            focus_dist: 1.,
+            t_start:    0.,
+            t_end:      0.5,
+        });
+        let render = |seed: u64, samples_per_pixel: u32| {
+            let settings = RenderSettings {
+                width: 32,
+                height: 24,
+                samples_per_pixel,
+                max_depth: DEFAULT_MAX_DEPTH,
+                seed,
+                scene: "green".into(),
+            };
+            let accum = Accumulator::new(settings.width, settings.height);
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut sum = PixelSum::default();
+                    render_pixel(&scene, &cam, &settings, (px, py), samples_per_pixel, &mut sum,
+                                 None);
+                    accum.set(px, py, sum);
+                }
+            }
+            accum.to_checkpoint(&settings)
+        };
+        let mean = |checkpoint: &Checkpoint| {
+            let total = checkpoint.pixels
+                                  .iter()
+                                  .fold(Float3::new(), |total, px| total + px.average());
+            total / checkpoint.pixels.len() as Float
+        };
+
+        let averaged = average(&[render(1, 8), render(2, 8)]).unwrap();
+        assert_eq!(averaged.settings.samples_per_pixel, 16);
+        assert!(averaged.pixels.iter().all(|px| px.samples == 16));
+        let (averaged, single) = (mean(&averaged), mean(&render(3, 16)));
+        for &(a, b) in [(averaged.x, single.x), (averaged.y, single.y), (averaged.z, single.z)]
+                           .iter()
+        {
+            assert!((a - b).abs() < 0.02 * b, "{:?} vs {:?}", averaged, single);
+        }
+    }
+}
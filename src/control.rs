@@ -0,0 +1,441 @@
+//! Pausing, resuming, and stopping a render from the keyboard.
+//!
+//! While we render in a terminal, a background thread reads keys from it:
+//! `p` pauses the render (or resumes it), `q` stops it the same way Ctrl+C
+//! does, and `s` writes a snapshot of the image right away.
+//!
+//! Render threads call `wait_while_paused()` between pixels, so pausing lets
+//! every thread finish the pixel it's on and then sleep until we resume or
+//! stop. Nothing is thrown away, so a paused render picks up where it was.
+//!
+//! Keys are read in "cbreak" mode: each key as it's pressed, without echoing
+//! it. Unlike a fully raw terminal, Ctrl+C still sends a signal and our output
+//! still looks the same. The terminal is put back on every way out, including
+//! panics. This is only implemented for unix so far.
+//!
+//! To try it by hand, render something slow, like
+//! `cargo run --release -- --width 800 --height 600 --samples-per-pixel 500`.
+//! Pressing `p` should stop the progress bar and drop CPU use to nothing, and
+//! pressing it again should carry on. `s` writes `output.partial.png`, and `q`
+//! writes out what's done and exits. Afterwards, typing in the terminal should
+//! echo again.
+//!
+//! Jobs without a terminal can get a snapshot with SIGUSR1 instead of `s`.
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+        Condvar,
+        Mutex,
+    },
+    thread,
+    time,
+};
+
+use log::{
+    error,
+    info,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    Running,
+    Paused,
+    /// Once we're stopping, nothing pauses us again.
+    Stopping,
+}
+
+/// Whether the render should be running, shared between the render threads
+/// and whatever controls them.
+pub struct RenderControl {
+    state:   Mutex<State>,
+    changed: Condvar,
+}
+
+/// The control for the render in progress.
+pub static RENDER: RenderControl = RenderControl::new();
+
+impl RenderControl {
+    pub const fn new() -> RenderControl {
+        RenderControl {
+            state:   Mutex::new(State::Running),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+
+    /// Pauses a running render, or resumes a paused one.
+    /// Returns the state we're in afterwards.
+    pub fn toggle_pause(&self) -> State {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            State::Running => State::Paused,
+            State::Paused => State::Running,
+            State::Stopping => State::Stopping,
+        };
+        self.changed.notify_all();
+        *state
+    }
+
+    /// Stops the render, waking up anything waiting on a pause.
+    pub fn stop(&self) {
+        *self.state.lock().unwrap() = State::Stopping;
+        self.changed.notify_all();
+    }
+
+    /// Blocks the calling thread for as long as we're paused.
+    pub fn wait_while_paused(&self) {
+        let mut state = self.state.lock().unwrap();
+        while *state == State::Paused {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+/// Does whatever `key` asks of `control`.
+/// `q` also calls `stop`, and `s` calls `snapshot`. Other keys do nothing.
+pub fn handle(control:  &RenderControl,
+              key:      u8,
+              stop:     impl FnOnce(),
+              snapshot: impl FnOnce())
+{
+    match key {
+        b'p' | b'P' => {
+            match control.toggle_pause() {
+                State::Paused => info!("\nPaused. Press p to resume"),
+                State::Running => info!("\nResumed"),
+                State::Stopping => {},
+            }
+        },
+        b'q' | b'Q' => {
+            control.stop();
+            stop();
+        },
+        b's' | b'S' => snapshot(),
+        _ => {},
+    }
+}
+
+/// Reads keys from the terminal on a background thread, until finished.
+pub struct Keyboard {
+    done:   Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+    // Dropped after the thread is done with the terminal.
+    _mode:  tty::Cbreak,
+}
+
+impl Keyboard {
+    /// Starts passing every key pressed to `on_key`.
+    /// Returns None when stdin isn't a terminal we can read keys from.
+    pub fn spawn<F>(mut on_key: F) -> Option<Keyboard>
+        where F: FnMut(u8) + Send + 'static
+    {
+        let mode = tty::Cbreak::enable()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let done = done.clone();
+            thread::spawn(move || {
+                // Reads time out every so often, so that we notice when we're done.
+                while !done.load(Ordering::SeqCst) {
+                    if let Some(key) = tty::read_key() {
+                        on_key(key);
+                    }
+                }
+            })
+        };
+
+        Some(Keyboard {
+            done,
+            handle,
+            _mode: mode,
+        })
+    }
+
+    /// Stops reading keys, and puts the terminal back how we found it.
+    pub fn finish(self) {
+        self.done.store(true, Ordering::SeqCst);
+        if self.handle.join().is_err() {
+            error!("Keyboard thread panicked");
+        }
+    }
+}
+
+/// Starts catching SIGUSR1, so that a `SnapshotSignal` can answer it later.
+/// Until this is called, SIGUSR1 ends the process. Returns whether it worked,
+/// which it never does on platforms without the signal.
+pub fn catch_snapshot_signal() -> bool {
+    signal::catch()
+}
+
+/// Calls a function for each SIGUSR1 on a background thread, until finished.
+/// Signals from before this starts, but after `catch_snapshot_signal()`,
+/// are answered once it does.
+pub struct SnapshotSignal {
+    done:   Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl SnapshotSignal {
+    pub fn spawn<F>(mut on_signal: F) -> SnapshotSignal
+        where F: FnMut() + Send + 'static
+    {
+        let done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let done = done.clone();
+            thread::spawn(move || {
+                // Signal handlers can't do much safely, so this one only sets
+                // a flag, which we check every so often.
+                while !done.load(Ordering::SeqCst) {
+                    if signal::take() {
+                        on_signal();
+                    }
+                    thread::sleep(time::Duration::from_millis(100));
+                }
+            })
+        };
+
+        SnapshotSignal {
+            done,
+            handle,
+        }
+    }
+
+    /// Stops answering the signal. It's still caught, but does nothing.
+    pub fn finish(self) {
+        self.done.store(true, Ordering::SeqCst);
+        if self.handle.join().is_err() {
+            error!("Snapshot signal thread panicked");
+        }
+    }
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::{
+        mem,
+        ptr,
+        sync::atomic::{
+            AtomicBool,
+            Ordering,
+        },
+    };
+
+    static RAISED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_signal(_signum: libc::c_int) {
+        RAISED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn catch() -> bool {
+        // Safety: The handler only touches an atomic, which is signal safe.
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Don't make reads and writes elsewhere fail with EINTR.
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()) == 0
+        }
+    }
+
+    /// Whether the signal came since we last asked.
+    pub fn take() -> bool {
+        RAISED.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod signal {
+    pub fn catch() -> bool {
+        false
+    }
+
+    pub fn take() -> bool {
+        false
+    }
+}
+
+#[cfg(unix)]
+mod tty {
+    use std::{
+        mem,
+        panic,
+    };
+
+    /// The terminal in cbreak mode. Dropping this restores it.
+    pub struct Cbreak {
+        original: libc::termios,
+    }
+
+    impl Cbreak {
+        pub fn enable() -> Option<Cbreak> {
+            // Safety: These only read and write the termios struct we hand them.
+            let original = unsafe {
+                if libc::isatty(libc::STDIN_FILENO) != 1 {
+                    return None;
+                }
+                let mut original: libc::termios = mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                    return None;
+                }
+
+                let mut cbreak = original;
+                // Keys as they're pressed, without echoing them. Signals and
+                // output processing are left alone.
+                cbreak.c_lflag &= !(libc::ICANON | libc::ECHO);
+                // Wait at most a tenth of a second for each key.
+                cbreak.c_cc[libc::VMIN] = 0;
+                cbreak.c_cc[libc::VTIME] = 1;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &cbreak) != 0 {
+                    return None;
+                }
+                original
+            };
+
+            // A panic on any thread can end the process before we're dropped,
+            // so make sure it leaves the terminal usable.
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                restore(&original);
+                previous(info);
+            }));
+
+            Some(Cbreak { original })
+        }
+    }
+
+    impl Drop for Cbreak {
+        fn drop(&mut self) {
+            restore(&self.original);
+        }
+    }
+
+    fn restore(original: &libc::termios) {
+        // Safety: `original` came from tcgetattr().
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+        }
+    }
+
+    /// The next key pressed, or None if nothing was pressed for a little while.
+    pub fn read_key() -> Option<u8> {
+        let mut key = 0_u8;
+        // Safety: We read at most 1 byte into `key`.
+        let read = unsafe {
+            libc::read(libc::STDIN_FILENO, &mut key as *mut u8 as *mut libc::c_void, 1)
+        };
+        if read == 1 {
+            Some(key)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod tty {
+    /// We don't know how to read single keys here yet.
+    pub struct Cbreak;
+
+    impl Cbreak {
+        pub fn enable() -> Option<Cbreak> {
+            None
+        }
+    }
+
+    pub fn read_key() -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    #[test]
+    fn check_state_transitions() {
+        let control = RenderControl::new();
+        assert_eq!(control.state(), State::Running);
+        assert_eq!(control.toggle_pause(), State::Paused);
+        assert_eq!(control.toggle_pause(), State::Running);
+        assert_eq!(control.toggle_pause(), State::Paused);
+
+        // Stopping wins over pausing, either way around.
+        control.stop();
+        assert_eq!(control.state(), State::Stopping);
+        assert_eq!(control.toggle_pause(), State::Stopping);
+        // And doesn't block anybody.
+        control.wait_while_paused();
+    }
+
+    #[test]
+    fn check_keys() {
+        let control = RenderControl::new();
+        let mut stops = 0;
+        let mut snapshots = 0;
+        let mut press = |key: u8| {
+            handle(&control, key, || stops += 1, || snapshots += 1);
+        };
+
+        press(b'x');
+        press(b'p');
+        press(b's');
+        press(b'S');
+        let paused = control.state();
+        press(b'P');
+        let resumed = control.state();
+        press(b'q');
+        press(b'p');
+
+        assert_eq!(paused, State::Paused);
+        assert_eq!(resumed, State::Running);
+        assert_eq!(control.state(), State::Stopping);
+        assert_eq!((stops, snapshots), (1, 2));
+    }
+
+    #[test]
+    fn check_pause_blocks_workers() {
+        let control = Arc::new(RenderControl::new());
+        let pixels = Arc::new(AtomicU64::new(0));
+        // Renders "pixels" the way the render threads do.
+        let worker = {
+            let control = control.clone();
+            let pixels = pixels.clone();
+            thread::spawn(move || {
+                while control.state() != State::Stopping {
+                    control.wait_while_paused();
+                    pixels.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(time::Duration::from_millis(1));
+                }
+            })
+        };
+        let wait = || thread::sleep(time::Duration::from_millis(50));
+
+        wait();
+        assert!(pixels.load(Ordering::SeqCst) > 0);
+
+        control.toggle_pause();
+        // Give it time to finish the pixel it's on.
+        wait();
+        let paused_at = pixels.load(Ordering::SeqCst);
+        wait();
+        assert_eq!(pixels.load(Ordering::SeqCst), paused_at);
+
+        control.toggle_pause();
+        wait();
+        assert!(pixels.load(Ordering::SeqCst) > paused_at);
+
+        // Stopping while paused still lets it finish.
+        control.toggle_pause();
+        control.stop();
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! Constructive solid geometry: shapes made by combining solids.
+//!
+//! A `Csg` combines two children, which have to be solids that say where rays
+//! are inside of them with `hit_all()`, like `Sphere`, `Cuboid`, or another
+//! `Csg`. Along a ray, the result is inside of either child for `Union`, both
+//! for `Intersection`, and the first but not the second for `Difference`, and
+//! its surface is wherever that changes. So a sphere with a bite taken out of
+//! it shows the inside of the second sphere in the bite, with its normals
+//! flipped to face out of what's left, and in its material.
+
+use std::sync::Arc;
+
+use crate::hitable::{
+    Aabb,
+    Hitable,
+    Span,
+};
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CsgMode {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgMode {
+    /// Whether a point is inside the result, given whether it's inside each child.
+    fn inside(self, inside_a: bool, inside_b: bool) -> bool {
+        match self {
+            CsgMode::Union => inside_a || inside_b,
+            CsgMode::Intersection => inside_a && inside_b,
+            CsgMode::Difference => inside_a && !inside_b,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Csg {
+    pub mode: CsgMode,
+    pub a:    Box<dyn Hitable>,
+    pub b:    Box<dyn Hitable>,
+}
+
+impl Csg {
+    pub fn new(mode: CsgMode, a: impl Hitable + 'static, b: impl Hitable + 'static) -> Csg {
+        Csg {
+            mode,
+            a: Box::new(a),
+            b: Box::new(b),
+        }
+    }
+}
+
+impl Hitable for Csg {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, _rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let within = |record: &HitRecord| t_min < record.t && record.t < t_max;
+        for Span { enter, exit } in self.hit_all(ray) {
+            if within(&enter) {
+                return Some(enter);
+            }
+            if within(&exit) {
+                return Some(exit);
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        let a = self.a.bounding_box(t0, t1);
+        let b = self.b.bounding_box(t0, t1);
+        match self.mode {
+            CsgMode::Union => Some(Aabb::surrounding(&a?, &b?)),
+            // Where the boxes don't overlap, this is inside out, and nothing hits it.
+            CsgMode::Intersection => match (a, b) {
+                (Some(a), Some(b)) => Some(Aabb { min: a.min.max(&b.min), max: a.max.min(&b.max) }),
+                (Some(only), None) | (None, Some(only)) => Some(only),
+                (None, None) => None,
+            },
+            CsgMode::Difference => a,
+        }
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.a.map_materials(f);
+        self.b.map_materials(f);
+    }
+
+    fn hit_all(&self, ray: &Ray) -> Vec<Span> {
+        // Every time the ray crosses either child's surface, in order, and
+        // whether that's `b`'s.
+        let mut crossings = vec![];
+        for &(child, is_b) in [(&self.a, false), (&self.b, true)].iter() {
+            for span in child.hit_all(ray) {
+                crossings.push((span.enter, is_b));
+                crossings.push((span.exit, is_b));
+            }
+        }
+        // Stable, so that a child's own crossings stay in order.
+        crossings.sort_by(|(a, _), (b, _)| {
+            a.t.partial_cmp(&b.t).unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let (mut inside_a, mut inside_b, mut inside) = (false, false, false);
+        let mut enter = None;
+        let mut spans = vec![];
+        for (mut record, is_b) in crossings {
+            if is_b {
+                inside_b = !inside_b;
+            } else {
+                inside_a = !inside_a;
+            }
+            if self.mode.inside(inside_a, inside_b) == inside {
+                continue;
+            }
+            inside = !inside;
+            // What's cut away by `b` is outside, so its normals face the other way.
+            if is_b && self.mode == CsgMode::Difference {
+                record.normal = -record.normal;
+            }
+            match enter.take() {
+                None => enter = Some(record),
+                Some(enter) => spans.push(Span { enter, exit: record }),
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::hitable::Sphere;
+    use crate::material::Lambertian;
+    use crate::rect::Cuboid;
+
+    fn sphere(center: Float3, radius: Float, albedo: Float) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material: Arc::new(Lambertian::new(Float3::xxx(albedo))),
+        }
+    }
+
+    /// Where `ray` goes in and out of `hitable`, along with the normals there.
+    fn spans(hitable: &dyn Hitable, ray: &Ray) -> Vec<(Float, Float3, Float, Float3)> {
+        hitable.hit_all(ray)
+               .into_iter()
+               .map(|span| (span.enter.t, span.enter.normal, span.exit.t, span.exit.normal))
+               .collect()
+    }
+
+    #[test]
+    fn check_notch() {
+        let mut rng = seeded_rng(0);
+        // A bite out of the +z side of a sphere.
+        let notched = Csg::new(CsgMode::Difference,
+                               sphere(Float3::new(), 1., 0.5),
+                               sphere(Float3::xyz(0., 0., 1.), 0.5, 0.9));
+        let ray = Ray { origin: Float3::xyz(0., 0., 5.), dir: Float3::xyz(0., 0., -1.), t: 0. };
+
+        // Through the bite, the first thing we hit is its far side, facing us.
+        let record = notched.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+        assert_eq!(record.t, 4.5);
+        assert_eq!(record.normal, Float3::xyz(0., 0., 1.));
+        assert_eq!(record.material.albedo(&record), Float3::xxx(0.9));
+        assert_eq!(spans(&notched, &ray),
+                   vec![(4.5, Float3::xyz(0., 0., 1.), 6., Float3::xyz(0., 0., -1.))]);
+
+        // Away from the bite, it's just the sphere.
+        let ray = Ray { origin: Float3::xyz(0., 5., 0.), dir: Float3::xyz(0., -1., 0.), t: 0. };
+        let record = notched.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+        assert_eq!((record.t, record.normal), (4., Float3::xyz(0., 1., 0.)));
+        assert_eq!(record.material.albedo(&record), Float3::xxx(0.5));
+
+        // And from inside the bite, the carved surface is the only way in.
+        let ray = Ray { origin: Float3::xyz(0., 0., 1.), dir: Float3::xyz(0., 0., -1.), t: 0. };
+        assert_eq!(notched.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap().t, 0.5);
+    }
+
+    #[test]
+    fn check_modes() {
+        let (left, right) = (Float3::xyz(-0.5, 0., 0.), Float3::xyz(0.5, 0., 0.));
+        let csg = |mode| Csg::new(mode, sphere(left, 1., 0.5), sphere(right, 1., 0.5));
+        let ray = Ray { origin: Float3::xyz(-5., 0., 0.), dir: Float3::xyz(1., 0., 0.), t: 0. };
+        let (x, minus_x) = (Float3::xyz(1., 0., 0.), Float3::xyz(-1., 0., 0.));
+
+        assert_eq!(spans(&csg(CsgMode::Union), &ray), vec![(3.5, minus_x, 6.5, x)]);
+        // A lens.
+        let lens = csg(CsgMode::Intersection);
+        assert_eq!(spans(&lens, &ray), vec![(4.5, minus_x, 5.5, x)]);
+        assert_eq!(lens.bounding_box(0., 0.),
+                   Some(Aabb { min: Float3::xyz(-0.5, -1., -1.), max: Float3::xyz(0.5, 1., 1.) }));
+        assert_eq!(spans(&csg(CsgMode::Difference), &ray), vec![(3.5, minus_x, 4.5, x)]);
+
+        // A hollow box, which rays go through twice.
+        let hollow = Csg::new(CsgMode::Difference,
+                              Cuboid {
+                                  min:      Float3::xxx(-1.),
+                                  max:      Float3::xxx(1.),
+                                  material: Arc::new(Lambertian::new(Float3::xxx(0.5))),
+                              },
+                              sphere(Float3::new(), 0.5, 0.5));
+        assert_eq!(spans(&hollow, &ray), vec![(4., minus_x, 4.5, x), (5.5, minus_x, 6., x)]);
+        assert_eq!(hollow.bounding_box(0., 0.).unwrap().max, Float3::xxx(1.));
+    }
+}
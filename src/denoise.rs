@@ -0,0 +1,175 @@
+//! Smoothing the noise out of previews.
+//!
+//! At a few samples per pixel, every pixel is off by its own bit of noise.
+//! `--denoise` averages each pixel with those around it, weighted by how alike
+//! they are, so that flat areas come out smooth while edges between different
+//! things stay sharp. This is a cross bilateral filter.
+//!
+//! How alike two pixels are goes by their color, and also by their normal and
+//! albedo when we rendered those AOVs, since they show the same edges without
+//! any of the noise. Colors are compared after a 3x3 median, or else a pixel
+//! with one lucky sample would look unlike everything around it, and would
+//! only be averaged with itself.
+//!
+//! This runs on linear radiance, before the image is tonemapped.
+
+use crate::despeckle::median_neighbor;
+use crate::output::LinearImage;
+use crate::prelude::*;
+
+/// How far away, in pixels, neighbors are averaged in.
+const RADIUS: u32 = 3;
+
+/// How quickly neighbors count for less the further away they are.
+const SIGMA_SPACE: Float = 2.0;
+
+/// How different colors can be before they stop counting, after they've been
+/// squeezed into [0, 1) by `compress()`.
+const SIGMA_COLOR: Float = 0.15;
+
+/// The same for normals, from -1 to 1 on each axis, and albedo.
+const SIGMA_NORMAL: Float = 0.3;
+const SIGMA_ALBEDO: Float = 0.1;
+
+/// Brings bright colors into [0, 1), so that a difference between two bright
+/// pixels counts for about as much as it looks like it does.
+fn compress(rgb: Float3) -> Float3 {
+    rgb / (Float3::xxx(1.0) + rgb)
+}
+
+/// How much a neighbor `diff` away counts, from 1 for the same to 0 for very
+/// different.
+fn similarity(diff: Float3, sigma: Float) -> Float {
+    (-diff.length_sq() / (2.0 * sigma * sigma)).exp()
+}
+
+/// `color` with its noise smoothed out. `normals` and `albedo` are the same
+/// size as `color`, and keep edges between surfaces sharp.
+pub fn denoise(color:   &LinearImage,
+               normals: Option<&LinearImage>,
+               albedo:  Option<&LinearImage>)
+    -> LinearImage
+{
+    let (width, height) = (color.width(), color.height());
+    let guide = LinearImage::from_fn(width, height, |x, y| {
+        let (mx, my) = median_neighbor(color, x, y, &|_, _| true);
+        compress(color.get_pixel(mx, my))
+    });
+
+    let result = LinearImage::from_fn(width, height, |x, y| {
+        let mut sum = Float3::new();
+        let mut total = 0.0;
+        for ny in y.saturating_sub(RADIUS)..=(y + RADIUS).min(height - 1) {
+            for nx in x.saturating_sub(RADIUS)..=(x + RADIUS).min(width - 1) {
+                let offset = Float3::xy(nx as Float - x as Float, ny as Float - y as Float);
+                let mut weight = similarity(offset, SIGMA_SPACE);
+                weight *= similarity(guide.get_pixel(nx, ny) - guide.get_pixel(x, y),
+                                     SIGMA_COLOR);
+                if let Some(normals) = normals {
+                    weight *= similarity(normals.get_pixel(nx, ny) - normals.get_pixel(x, y),
+                                         SIGMA_NORMAL);
+                }
+                if let Some(albedo) = albedo {
+                    weight *= similarity(albedo.get_pixel(nx, ny) - albedo.get_pixel(x, y),
+                                         SIGMA_ALBEDO);
+                }
+                sum += weight * color.get_pixel(nx, ny);
+                total += weight;
+            }
+        }
+        // The pixel itself always counts fully, so `total` is at least 1.
+        sum / total
+    });
+
+    if color.has_alpha() {
+        result.with_alpha(|x, y| color.get_alpha(x, y))
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    /// Dark on the left of `edge` and bright from it on, with about one in
+    /// twenty pixels knocked to black or white.
+    fn noisy_edge(width: u32, height: u32, edge: u32) -> (LinearImage, LinearImage) {
+        let clean = LinearImage::from_fn(width, height, |x, _| {
+            Float3::xxx(if x < edge { 0.2 } else { 0.8 })
+        });
+        seed_thread_rng(0x5eed);
+        let noisy = LinearImage::from_fn(width, height, |x, y| {
+            let roll = random_float();
+            if roll < 0.025 {
+                Float3::xxx(0.0)
+            } else if roll < 0.05 {
+                Float3::xxx(1.0)
+            } else {
+                clean.get_pixel(x, y)
+            }
+        });
+        (clean, noisy)
+    }
+
+    /// Variance of the luminance over the pixels with `x` in `xs`.
+    fn variance(img: &LinearImage, xs: std::ops::Range<u32>) -> Float {
+        let values: Vec<Float> = (0..img.height())
+            .flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .map(|(x, y)| img.get_pixel(x, y).luminance())
+            .collect();
+        let mean = values.iter().sum::<Float>() / values.len() as Float;
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<Float>() / values.len() as Float
+    }
+
+    #[test]
+    fn check_noise_is_smoothed() {
+        let (_, noisy) = noisy_edge(40, 20, 20);
+        let smooth = denoise(&noisy, None, None);
+
+        // Well away from the edge, on both sides.
+        for xs in [0..16, 24..40].iter() {
+            let (before, after) = (variance(&noisy, xs.clone()), variance(&smooth, xs.clone()));
+            assert!(after < 0.05 * before, "{:?}: {} -> {}", xs, before, after);
+        }
+    }
+
+    #[test]
+    fn check_edges_stay_put() {
+        let (_, noisy) = noisy_edge(40, 20, 20);
+        let smooth = denoise(&noisy, None, None);
+        for y in 0..20 {
+            let edge = (0..40).find(|&x| smooth.get_pixel(x, y).x > 0.5).unwrap();
+            assert!(edge == 19 || edge == 20 || edge == 21, "row {} has its edge at {}", y, edge);
+        }
+    }
+
+    #[test]
+    fn check_guides_keep_edges() {
+        // The same color on both sides, so only the albedo knows where the
+        // edge is.
+        let color = LinearImage::from_fn(8, 1, |x, _| {
+            Float3::xxx(if x == 3 { 0.6 } else { 0.5 })
+        });
+        let albedo = LinearImage::from_fn(8, 1, |x, _| {
+            Float3::xxx(if x < 4 { 0.1 } else { 0.9 })
+        });
+        let normals = LinearImage::from_fn(8, 1, |_, _| Float3::xyz(0., 0., 1.));
+
+        let unguided = denoise(&color, None, None);
+        let guided = denoise(&color, Some(&normals), Some(&albedo));
+        // The bump leaks across without the albedo, and not with it.
+        assert!(unguided.get_pixel(4, 0).x > 0.5, "{:?}", unguided.get_pixel(4, 0));
+        assert!((guided.get_pixel(4, 0).x - 0.5).abs() < 1e-4, "{:?}", guided.get_pixel(4, 0));
+        assert!(guided.get_pixel(3, 0).x > unguided.get_pixel(3, 0).x);
+    }
+
+    #[test]
+    fn check_alpha_is_kept() {
+        let color = LinearImage::from_fn(4, 4, |_, _| Float3::xxx(0.5))
+            .with_alpha(|x, _| x as Float / 4.0);
+        let smooth = denoise(&color, None, None);
+        assert_eq!(smooth.get_alpha(3, 2), 0.75);
+        assert!((smooth.get_pixel(1, 1).x - 0.5).abs() < 1e-4);
+    }
+}
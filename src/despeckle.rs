@@ -0,0 +1,247 @@
+//! Removing fireflies from a finished render.
+//!
+//! Now and then a path finds a bright light through an unlikely bounce, and
+//! that one sample is enough to leave a hot pixel that would take thousands
+//! more to average out. `--despeckle` looks for pixels far brighter than the
+//! median of their 3x3 neighborhood, and replaces them with that median.
+//!
+//! Being much brighter than the neighbors isn't enough on its own: a tiny
+//! specular highlight is too. So when we know how the pixel's samples varied,
+//! we only replace pixels whose brightness came from a few extreme samples,
+//! rather than from all of them agreeing. When we don't know (e.g. when the
+//! pixel only has one sample), a pixel has to stand out a lot more to count.
+//!
+//! This runs on the whole image after its tiles are put together, so tile
+//! boundaries are like any other pixels. Pixels without any samples (from
+//! tiles we skipped or never got to) aren't counted as anybody's neighbor,
+//! since they'd make whatever is next to them look like a firefly.
+
+use std::fmt;
+
+use crate::checkpoint::Accumulator;
+use crate::output::LinearImage;
+use crate::prelude::*;
+
+/// How many times brighter than its neighbors a pixel has to be.
+pub const DEFAULT_FACTOR: Float = 10.0;
+
+/// Without knowing the variance, pixels have to be this many times brighter
+/// still. That leaves genuine small highlights alone.
+const FALLBACK_SCALE: Float = 5.0;
+
+/// Pixels are too noisy to trust when their standard error is more than this
+/// fraction of their luminance. One sample carrying a whole pixel of `n`
+/// samples is close to 1.0, while samples that agree are close to 0.
+const EXTREME_ERROR: Float = 0.5;
+
+/// What the pass did.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Despeckled {
+    /// Pixels we replaced.
+    pub replaced: usize,
+    /// Pixels that were only checked by their brightness, because we didn't
+    /// know their variance.
+    pub without_variance: usize,
+}
+
+impl fmt::Display for Despeckled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Despeckle replaced {} pixels", self.replaced)?;
+        if self.without_variance > 0 {
+            write!(f, " ({} checked without their variance)", self.without_variance)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the fireflies in `img` with the median of their neighborhood.
+/// `sums` are the samples `img` was averaged from, when we have them.
+pub fn despeckle(img: &mut LinearImage, factor: Float, sums: Option<&Accumulator>)
+    -> Despeckled
+{
+    let rendered = |x: u32, y: u32| sums.map_or(true, |sums| sums.get(x, y).samples > 0);
+
+    let mut result = Despeckled::default();
+    // Decide everything from the original image, so that replacing one pixel
+    // doesn't change what its neighbors are compared to.
+    let mut replacements = vec![];
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            if !rendered(x, y) {
+                continue;
+            }
+            let luminance = img.get_pixel(x, y).luminance();
+            let (mx, my) = median_neighbor(img, x, y, &rendered);
+            let median = img.get_pixel(mx, my).luminance();
+
+            let relative_error = sums.and_then(|sums| sums.get(x, y).relative_error());
+            let is_speckle = match relative_error {
+                Some(error) => luminance > factor * median && error > EXTREME_ERROR,
+                None => {
+                    result.without_variance += 1;
+                    luminance > FALLBACK_SCALE * factor * median
+                },
+            };
+            if is_speckle {
+                replacements.push((x, y, img.get_pixel(mx, my)));
+            }
+        }
+    }
+
+    for &(x, y, rgb) in replacements.iter() {
+        img.put_pixel(x, y, rgb);
+    }
+    result.replaced = replacements.len();
+    result
+}
+
+/// The pixel with the median luminance of the 3x3 neighborhood around
+/// (`x`, `y`), counting only `rendered` pixels inside of the image.
+/// With an even number of them, this is the darker of the middle two, so that
+/// a firefly never picks itself.
+pub(crate) fn median_neighbor(img:      &LinearImage,
+                              x:        u32,
+                              y:        u32,
+                              rendered: &dyn Fn(u32, u32) -> bool)
+    -> (u32, u32)
+{
+    let mut neighbors = Vec::with_capacity(9);
+    for ny in y.saturating_sub(1)..=(y + 1).min(img.height() - 1) {
+        for nx in x.saturating_sub(1)..=(x + 1).min(img.width() - 1) {
+            if rendered(nx, ny) {
+                neighbors.push((img.get_pixel(nx, ny).luminance(), nx, ny));
+            }
+        }
+    }
+    neighbors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let (_, mx, my) = neighbors[(neighbors.len() - 1) / 2];
+    (mx, my)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::checkpoint::PixelSum;
+
+    const SAMPLES: u32 = 16;
+
+    /// A smooth gradient, gray enough that every channel is lit.
+    fn smooth(width: u32, height: u32) -> LinearImage {
+        LinearImage::from_fn(width, height, |x, y| {
+            Float3::xxx(0.2 + 0.02 * x as Float + 0.01 * y as Float)
+        })
+    }
+
+    /// Samples that average to `img`. Pixels where `lucky` is true got all
+    /// of their light from a single sample, and the rest got the same light
+    /// from every sample.
+    fn sums(img: &LinearImage, lucky: impl Fn(u32, u32) -> bool) -> Accumulator {
+        let accum = Accumulator::new(img.width(), img.height());
+        for y in 0..img.height() {
+            for x in 0..img.width() {
+                let rgb = img.get_pixel(x, y);
+                let n = SAMPLES as Float;
+                let lum = rgb.luminance();
+                let luminance_sq = if lucky(x, y) {
+                    (n * lum) * (n * lum)
+                } else {
+                    n * lum * lum
+                };
+                accum.set(x, y, PixelSum {
+                    radiance: n * rgb,
+                    samples:  SAMPLES,
+                    weight:   n,
+                    luminance_sq,
+                    ..PixelSum::default()
+                });
+            }
+        }
+        accum
+    }
+
+    #[test]
+    fn check_fireflies_are_removed() {
+        let clean = smooth(8, 6);
+        let mut img = clean.clone();
+        let fireflies = [(3, 2), (0, 0), (7, 5), (6, 0)];
+        for &(x, y) in fireflies.iter() {
+            img.put_pixel(x, y, Float3::xxx(50.0));
+        }
+        let accum = sums(&img, |x, y| fireflies.contains(&(x, y)));
+
+        let result = despeckle(&mut img, DEFAULT_FACTOR, Some(&accum));
+        assert_eq!(result, Despeckled { replaced: 4, without_variance: 0 });
+        for y in 0..6 {
+            for x in 0..8 {
+                let rgb = img.get_pixel(x, y);
+                if fireflies.contains(&(x, y)) {
+                    // Replaced with one of its neighbors.
+                    let near = (rgb - clean.get_pixel(x, y)).length();
+                    assert!(near < 0.1, "({}, {}) is {:?}", x, y, rgb);
+                } else {
+                    assert_eq!(rgb, clean.get_pixel(x, y), "({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_highlights_are_kept() {
+        let mut img = smooth(5, 5);
+        img.put_pixel(2, 2, Float3::xxx(50.0));
+        let before = img.clone();
+        // Every sample of the highlight saw it.
+        let accum = sums(&img, |_, _| false);
+
+        assert_eq!(despeckle(&mut img, DEFAULT_FACTOR, Some(&accum)).replaced, 0);
+        assert_eq!(img, before);
+    }
+
+    #[test]
+    fn check_clean_renders_are_untouched() {
+        let mut img = smooth(8, 8);
+        let before = img.clone();
+        let accum = sums(&img, |x, y| (x + y) % 3 == 0);
+        assert_eq!(despeckle(&mut img, DEFAULT_FACTOR, Some(&accum)).replaced, 0);
+        assert_eq!(despeckle(&mut img, DEFAULT_FACTOR, None).replaced, 0);
+        assert_eq!(img, before);
+    }
+
+    #[test]
+    fn check_without_variance() {
+        let mut img = smooth(6, 6);
+        // 25x brighter than its neighbors, which could be a real highlight,
+        // and 500x, which can't.
+        let dim = 25.0 * img.get_pixel(1, 1).luminance();
+        img.put_pixel(1, 1, Float3::xxx(dim));
+        img.put_pixel(4, 4, Float3::xxx(500.0));
+
+        let result = despeckle(&mut img, DEFAULT_FACTOR, None);
+        assert_eq!(result, Despeckled { replaced: 1, without_variance: 36 });
+        assert_eq!(img.get_pixel(1, 1), Float3::xxx(dim));
+        assert!(img.get_pixel(4, 4).x < 1.0);
+    }
+
+    #[test]
+    fn check_missing_pixels_are_not_neighbors() {
+        // Only the first column was rendered, and the rest is black. Next to
+        // all of that black, the first column would look like fireflies.
+        let mut img = LinearImage::from_fn(4, 4, |x, _| {
+            if x == 0 {
+                Float3::xxx(0.5)
+            } else {
+                Float3::new()
+            }
+        });
+        let before = img.clone();
+        let accum = sums(&img, |x, _| x == 0);
+        for y in 0..4 {
+            for x in 1..4 {
+                accum.set(x, y, PixelSum::default());
+            }
+        }
+
+        assert_eq!(despeckle(&mut img, DEFAULT_FACTOR, Some(&accum)).replaced, 0);
+        assert_eq!(img, before);
+    }
+}
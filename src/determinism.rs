@@ -0,0 +1,371 @@
+//! Checking that renders come out the same every time.
+//!
+//! Every sample is seeded by its pixel and index, so rendering with the same
+//! settings should give exactly the same image, however the work is split
+//! between threads. `--verify-determinism` renders the image a few times and
+//! compares a hash of each tile, so that anything that breaks that (like a
+//! material reaching for `thread_rng()`) shows up right away, along with which
+//! tiles it affected.
+//!
+//! Tile hashes are also written to `--stats-json`, which makes that file a
+//! manifest of the render: `raytracer compare-manifests a.json b.json` finds
+//! the tiles that differ between renders on different machines. The hash is
+//! FNV-1a over the bits of each linear pixel, so unlike `DefaultHasher`, it
+//! doesn't depend on which version of Rust we were built with.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    path,
+};
+
+use crate::output::LinearImage;
+use crate::prelude::*;
+use crate::render::{
+    needs_to_exit,
+    Frame,
+};
+use crate::stats::{
+    RenderStats,
+    TileTime,
+};
+use crate::tiles::tile_span;
+
+/// The pixels a tile covers: (x, y, width, height).
+pub type TileRect = (u32, u32, u32, u32);
+
+/// A tile that came out differently between two renders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    pub tile_id:  u32,
+    /// Where the tile is in the tile grid.
+    pub x:        u32,
+    pub y:        u32,
+    /// The biggest difference in any channel of any of its pixels, when we
+    /// have both images to look at.
+    pub max_diff: Option<Float>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tile {} ({}, {})", self.tile_id, self.x, self.y)?;
+        if let Some(diff) = self.max_diff {
+            write!(f, " differs by up to {:e}", diff)?;
+        }
+        Ok(())
+    }
+}
+
+/// A hash of every pixel of `img` within `rect`.
+pub fn hash_tile(img: &LinearImage, (x0, y0, width, height): TileRect) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = OFFSET_BASIS;
+    let mut add = |value: Float| {
+        for byte in value.to_bits().to_le_bytes().iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    };
+    for y in y0..(y0 + height) {
+        for x in x0..(x0 + width) {
+            let rgb = img.get_pixel(x, y);
+            add(rgb.x);
+            add(rgb.y);
+            add(rgb.z);
+            if img.has_alpha() {
+                add(img.get_alpha(x, y));
+            }
+        }
+    }
+    hash
+}
+
+/// The biggest difference in any channel of any pixel within `rect`.
+pub fn max_diff(a: &LinearImage, b: &LinearImage, (x0, y0, width, height): TileRect) -> Float {
+    let mut diff: Float = 0.0;
+    for y in y0..(y0 + height) {
+        for x in x0..(x0 + width) {
+            let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+            let alpha = (a.get_alpha(x, y) - b.get_alpha(x, y)).abs();
+            diff = diff.max((pa.x - pb.x).abs())
+                       .max((pa.y - pb.y).abs())
+                       .max((pa.z - pb.z).abs())
+                       .max(alpha);
+        }
+    }
+    diff
+}
+
+/// The tiles whose hashes differ between two renders, by tile id.
+/// Both have to be split up into the same tiles.
+pub fn compare_tiles(a: &[TileTime], b: &[TileTime]) -> Result<Vec<Mismatch>, String> {
+    let b_hashes: HashMap<u32, u64> = b.iter().map(|tile| (tile.tile_id, tile.hash)).collect();
+    if a.len() != b.len() || a.iter().any(|tile| !b_hashes.contains_key(&tile.tile_id)) {
+        return Err("The renders weren't split into the same tiles".into());
+    }
+
+    let mut mismatches: Vec<Mismatch> = a
+        .iter()
+        .filter(|tile| b_hashes[&tile.tile_id] != tile.hash)
+        .map(|tile| Mismatch {
+            tile_id:  tile.tile_id,
+            x:        tile.x,
+            y:        tile.y,
+            max_diff: None,
+        })
+        .collect();
+    mismatches.sort_by_key(|m| m.tile_id);
+    Ok(mismatches)
+}
+
+/// The tiles that differ between the renders that wrote the `--stats-json`
+/// files at `a` and `b`.
+pub fn compare_manifests(a: &path::Path, b: &path::Path) -> Result<Vec<Mismatch>, String> {
+    let load = |path: &path::Path| -> Result<RenderStats, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))
+    };
+    let (a, b) = (load(a)?, load(b)?);
+
+    let settings = |s: &RenderStats| (s.width, s.height, s.samples_per_pixel, s.seed);
+    if settings(&a) != settings(&b) {
+        let describe = |s: &RenderStats| {
+            format!("{}x{} at {} spp with seed {}",
+                    s.width, s.height, s.samples_per_pixel, s.seed)
+        };
+        return Err(format!("The renders have different settings: {} vs {}",
+                           describe(&a), describe(&b)));
+    }
+    if a.interrupted || b.interrupted {
+        return Err("At least one of the renders was interrupted".into());
+    }
+    compare_tiles(&a.tiles, &b.tiles)
+}
+
+/// Renders the image `runs` times with `render`, which is told which run it
+/// is (counting from 1) and starts from scratch every time. Returns the first
+/// render, and the tiles that any of the others disagreed with it on.
+/// Renders need their stats, which is where the tile hashes are.
+pub fn verify(runs: u32, mut render: impl FnMut(u32) -> Frame) -> (Frame, Vec<Mismatch>) {
+    let first = render(1);
+    let mut mismatches: Vec<Mismatch> = vec![];
+    for run in 2..=runs {
+        if needs_to_exit() {
+            break;
+        }
+        let frame = render(run);
+        if needs_to_exit() {
+            // Tiles we didn't finish would look like they changed.
+            break;
+        }
+
+        let (a, b) = (first.stats.as_ref().unwrap(), frame.stats.as_ref().unwrap());
+        let found = compare_tiles(&a.tiles, &b.tiles)
+            .expect("The same settings should give the same tiles");
+        for mut mismatch in found {
+            let (offset_x, width) = tile_span(mismatch.x, a.tiles_x, a.width);
+            let (offset_y, height) = tile_span(mismatch.y, a.tiles_y, a.height);
+            let diff = max_diff(&first.linear, &frame.linear, (offset_x, offset_y, width, height));
+            match mismatches.iter_mut().find(|m| m.tile_id == mismatch.tile_id) {
+                Some(seen) => seen.max_diff = Some(seen.max_diff.unwrap_or(0.0).max(diff)),
+                None => {
+                    mismatch.max_diff = Some(diff);
+                    mismatches.push(mismatch);
+                },
+            }
+        }
+    }
+    mismatches.sort_by_key(|m| m.tile_id);
+    (first, mismatches)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use rand::Rng;
+
+    use crate::aov::PixelAovs;
+    use crate::camera::{
+        Camera,
+        CameraInfo,
+        Projection,
+    };
+    use crate::checkpoint::Accumulator;
+    use crate::hitable::HitableList;
+    use crate::rect::XyRect;
+    use crate::render::{
+        Background,
+        RenderSettings,
+        Scene,
+        DEFAULT_MAX_DEPTH,
+    };
+    use crate::scenes::make_green_scene;
+    use crate::tile_order::TileOrder;
+    use crate::tiles::{
+        TiledRender,
+        TilingPlan,
+    };
+
+    fn gradient() -> LinearImage {
+        LinearImage::from_fn(8, 4, |x, y| Float3::xyz(x as Float, y as Float, 0.5))
+    }
+
+    fn tiles(img: &LinearImage) -> Vec<TileTime> {
+        // A 2x1 grid.
+        (0..2)
+            .map(|x| TileTime {
+                tile_id:   x,
+                x,
+                y:         0,
+                secs:      0.0,
+                hash:      hash_tile(img, (4 * x, 0, 4, 4)),
+                pixels:    16,
+                wall_secs: 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_hashes_find_changed_tiles() {
+        let img = gradient();
+        let mut changed = img.clone();
+        // The smallest change we can make.
+        let nudged = Float::from_bits((0.5 as Float).to_bits() + 1);
+        changed.put_pixel(6, 3, Float3::xyz(6.0, 3.0, nudged));
+
+        assert_eq!(hash_tile(&img, (0, 0, 4, 4)), hash_tile(&gradient(), (0, 0, 4, 4)));
+        assert_ne!(hash_tile(&img, (0, 0, 4, 4)), hash_tile(&img, (4, 0, 4, 4)));
+        assert_eq!(compare_tiles(&tiles(&img), &tiles(&gradient())), Ok(vec![]));
+
+        let mismatches = compare_tiles(&tiles(&img), &tiles(&changed)).unwrap();
+        assert_eq!(mismatches, vec![Mismatch { tile_id: 1, x: 1, y: 0, max_diff: None }]);
+        let diff = max_diff(&img, &changed, (4, 0, 4, 4));
+        assert!(diff > 0.0 && diff < 1e-6, "{}", diff);
+        assert_eq!(max_diff(&img, &changed, (0, 0, 4, 4)), 0.0);
+
+        // Alpha counts too.
+        let opaque = img.clone().with_alpha(|_, _| 1.0);
+        let clear = img.clone().with_alpha(|x, _| if x == 0 { 0.0 } else { 1.0 });
+        assert_ne!(hash_tile(&opaque, (0, 0, 4, 4)), hash_tile(&clear, (0, 0, 4, 4)));
+
+        assert!(compare_tiles(&tiles(&img), &tiles(&img)[..1]).is_err());
+    }
+
+    #[test]
+    fn check_compare_manifests() {
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-manifests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = |name: &str, img: &LinearImage, seed: u64| {
+            let path = dir.join(name);
+            RenderStats {
+                width:             8,
+                height:            4,
+                samples_per_pixel: 1,
+                tiles_x:           2,
+                tiles_y:           1,
+                threads:           1,
+                seed,
+                render_secs:       0.0,
+                tiles:             tiles(img),
+                counts:            Default::default(),
+                interrupted:       false,
+                build:             String::new(),
+            }.save(&path).unwrap();
+            path
+        };
+        let mut changed = gradient();
+        changed.put_pixel(0, 0, Float3::xxx(1.0));
+
+        let a = manifest("a.json", &gradient(), 1);
+        let same = manifest("same.json", &gradient(), 1);
+        let different = manifest("different.json", &changed, 1);
+        let other_seed = manifest("other-seed.json", &gradient(), 2);
+
+        assert_eq!(compare_manifests(&a, &same), Ok(vec![]));
+        let mismatches = compare_manifests(&a, &different).unwrap();
+        assert_eq!(mismatches.iter().map(|m| m.tile_id).collect::<Vec<_>>(), vec![0]);
+        assert!(compare_manifests(&a, &other_seed).is_err());
+        assert!(compare_manifests(&a, &dir.join("missing.json")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Glows a different brightness every time it's hit, from an RNG that
+    /// isn't seeded by the sample.
+    #[derive(Debug)]
+    struct Flicker;
+
+    impl Material for Flicker {
+        fn scatter(&self, _ray_in: &Ray, _record: &HitRecord, _rng: &mut dyn RngCore) -> Scatter {
+            Scatter::Absorb(Float3::new())
+        }
+
+        fn emitted(&self, _ray_in: &Ray, _record: &HitRecord) -> Float3 {
+            Float3::xxx(rand::thread_rng().gen::<Float>())
+        }
+    }
+
+    #[test]
+    fn check_verify_finds_flicker() {
+        let settings = RenderSettings {
+            width:             16,
+            height:            8,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "determinism".into(),
+        };
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::new(),
+            lookat:     Float3::xyz(0., 0., -1.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 90. },
+            aspect:     2.,
+            aperature:  0.,
+            focus_dist: 1.,
+            t_start:    0.,
+            t_end:      0.5,
+        });
+        let verify = |scene: &Scene| {
+            verify(3, |_| {
+                // Left and right halves of the image.
+                let plan = TilingPlan::new(2, 1, settings.width, settings.height);
+                assert_eq!((plan.tiles_x, plan.tiles_y), (2, 1));
+                let accum = Accumulator::new(settings.width, settings.height);
+                TiledRender::new(plan, &settings, TileOrder::Scanline, &accum,
+                                 PixelAovs::new(&[]), |_, _, _| true)
+                    .render(scene, &cam, &settings, &accum, 4, &|_| ())
+            })
+        };
+
+        let (frame, mismatches) = verify(&Scene::new(make_green_scene()));
+        assert_eq!(mismatches, vec![]);
+        let tiles = &frame.stats.as_ref().unwrap().tiles;
+        assert_eq!(tiles.len(), 2);
+        assert_ne!(tiles[0].hash, tiles[1].hash);
+
+        // Flickering across the left half of the view only.
+        let flicker = Scene {
+            background: Background::Black,
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(XyRect {
+                    a0: -3., a1: 0.,
+                    b0: -2., b1: 2.,
+                    k:  -1.,
+                    material: Arc::new(Flicker),
+                })],
+            })
+        };
+        let (_, mismatches) = verify(&flicker);
+        assert_eq!(mismatches.iter().map(|m| (m.x, m.y)).collect::<Vec<_>>(), vec![(0, 0)]);
+        assert!(mismatches[0].max_diff.unwrap() > 0.0, "{}", mismatches[0]);
+    }
+}
@@ -0,0 +1,201 @@
+//! Reconstruction filters, for turning samples into pixels.
+//!
+//! Each sample of a pixel lands somewhere within `radius()` of the pixel's
+//! center, and counts toward it by `weight(dx, dy)` of its offset. The pixel
+//! is the weighted average of its samples. A box filter weighs every sample
+//! the same, which is what we've always done. Wider filters blend in a bit of
+//! each neighboring pixel, which smooths out jagged edges.
+//!
+//! Pixels take their own samples over the whole footprint of their filter,
+//! instead of splatting every sample into each pixel that it's near. That
+//! costs a few more samples at the same quality, but it keeps every pixel
+//! independent of the others: tiles don't need to overlap or share anything,
+//! and stopping and resuming a pixel still gives exactly the same result.
+
+use std::str::FromStr;
+
+use crate::prelude::*;
+
+pub trait Filter: std::fmt::Debug + Send + Sync {
+    /// How far from the center of a pixel (in pixels) samples count toward
+    /// it, along each axis.
+    fn radius(&self) -> Float;
+
+    /// How much a sample (`dx`, `dy`) pixels from the center counts.
+    /// Over the square within `radius()`, this integrates to 1.
+    fn weight(&self, dx: Float, dy: Float) -> Float;
+}
+
+/// Every sample counts the same, and only within the pixel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BoxFilter;
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> Float {
+        0.5
+    }
+
+    fn weight(&self, _dx: Float, _dy: Float) -> Float {
+        1.0
+    }
+}
+
+/// Falls off linearly to nothing at the centers of the neighboring pixels.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TentFilter;
+
+impl Filter for TentFilter {
+    fn radius(&self) -> Float {
+        1.0
+    }
+
+    fn weight(&self, dx: Float, dy: Float) -> Float {
+        let tent = |d: Float| (1.0 - d.abs()).max(0.0);
+        tent(dx) * tent(dy)
+    }
+}
+
+/// A Gaussian, shifted down so that it reaches 0 at `radius`.
+#[derive(Copy, Clone, Debug)]
+pub struct GaussianFilter {
+    radius: Float,
+    sigma:  Float,
+    // What one axis of the Gaussian integrates to, so that we can make both
+    // of them integrate to 1.
+    area:   Float,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: Float, sigma: Float) -> GaussianFilter {
+        let mut filter = GaussianFilter {
+            radius,
+            sigma,
+            area: 1.0,
+        };
+        // Midpoint rule. It's smooth enough that this is plenty.
+        const STEPS: u32 = 10_000;
+        let dx = 2.0 * radius / STEPS as Float;
+        filter.area = (0..STEPS)
+            .map(|i| filter.gaussian(-radius + (i as Float + 0.5) * dx) * dx)
+            .sum();
+        filter
+    }
+
+    fn gaussian(&self, d: Float) -> Float {
+        let g = |d: Float| (-d * d / (2.0 * self.sigma * self.sigma)).exp();
+        (g(d) - g(self.radius)).max(0.0)
+    }
+}
+
+impl Default for GaussianFilter {
+    fn default() -> GaussianFilter {
+        GaussianFilter::new(1.5, 0.5)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> Float {
+        self.radius
+    }
+
+    fn weight(&self, dx: Float, dy: Float) -> Float {
+        self.gaussian(dx) * self.gaussian(dy) / (self.area * self.area)
+    }
+}
+
+/// The filters we can pick from the command line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+}
+
+impl FilterKind {
+    pub const ALL: &'static [FilterKind] = &[
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterKind::Box      => "box",
+            FilterKind::Tent     => "tent",
+            FilterKind::Gaussian => "gaussian",
+        }
+    }
+
+    pub fn filter(self) -> Box<dyn Filter> {
+        match self {
+            FilterKind::Box => Box::new(BoxFilter),
+            FilterKind::Tent => Box::new(TentFilter),
+            FilterKind::Gaussian => Box::new(GaussianFilter::default()),
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FilterKind, String> {
+        FilterKind::ALL
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = FilterKind::ALL.iter().map(|k| k.name()).collect();
+                format!("Unknown filter \"{}\". Expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_weights_integrate_to_one() {
+        for &kind in FilterKind::ALL {
+            let filter = kind.filter();
+            let r = filter.radius();
+            const STEPS: u32 = 400;
+            let d = 2.0 * r / STEPS as Float;
+            let mut total = 0.0;
+            for i in 0..STEPS {
+                for j in 0..STEPS {
+                    let dx = -r + (i as Float + 0.5) * d;
+                    let dy = -r + (j as Float + 0.5) * d;
+                    total += filter.weight(dx, dy) * d * d;
+                }
+            }
+            assert!((total - 1.0).abs() < 1e-3, "{:?} integrates to {}", kind, total);
+        }
+    }
+
+    #[test]
+    fn check_weights_fall_off() {
+        for &kind in FilterKind::ALL {
+            let filter = kind.filter();
+            let r = filter.radius();
+            let center = filter.weight(0.0, 0.0);
+            assert!(center > 0.0, "{:?}", kind);
+            // Never negative, and never more than the center.
+            for &(dx, dy) in [(0.3, 0.0), (0.0, -0.45), (r, r), (-r, 0.2)].iter() {
+                let w = filter.weight(dx, dy);
+                assert!(0.0 <= w && w <= center, "{:?} at ({}, {}): {}", kind, dx, dy, w);
+            }
+        }
+        // Gaussians and tents reach 0 at their edge.
+        assert!(TentFilter.weight(1.0, 0.0).abs() < 1e-12);
+        assert!(GaussianFilter::default().weight(0.0, 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn check_names_round_trip() {
+        for &kind in FilterKind::ALL {
+            assert_eq!(kind.name().parse::<FilterKind>(), Ok(kind));
+        }
+        assert!("mitchell".parse::<FilterKind>().is_err());
+    }
+}
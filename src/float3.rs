@@ -1,11 +1,37 @@
 
 use std::{
-    convert::Into,
     mem,
     ops,
+    str::FromStr,
 };
 
+#[cfg(feature = "simd")]
+use crate::simd::Lanes;
+
+/// The type of every number in the renderer. This is f64, unless the
+/// `float32` feature asks for f32.
+#[cfg(not(feature = "float32"))]
 pub type Float = f64;
+#[cfg(feature = "float32")]
+pub type Float = f32;
+
+/// Constants like PI, as `Float`s.
+#[cfg(not(feature = "float32"))]
+pub use std::f64::consts;
+#[cfg(feature = "float32")]
+pub use std::f32::consts;
+
+/// The largest `Float`, for rays that go on forever.
+#[cfg(not(feature = "float32"))]
+pub const FLOAT_MAX: Float = std::f64::MAX;
+#[cfg(feature = "float32")]
+pub const FLOAT_MAX: Float = std::f32::MAX;
+
+/// The gap between 1 and the next `Float` after it.
+#[cfg(not(feature = "float32"))]
+pub const FLOAT_EPSILON: Float = std::f64::EPSILON;
+#[cfg(feature = "float32")]
+pub const FLOAT_EPSILON: Float = std::f32::EPSILON;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
@@ -27,28 +53,15 @@ impl Float3 {
     }
 
     pub fn xyz(x: Float, y: Float, z: Float) -> Float3 {
-        Float3 {
-            x: x.into(),
-            y: y.into(),
-            z: z.into(),
-        }
+        Float3 { x, y, z }
     }
 
     pub fn xy(x: Float, y: Float) -> Float3 {
-        Float3 {
-            x: x.into(),
-            y: y.into(),
-            z: 0.0,
-        }
+        Float3 { x, y, z: 0.0 }
     }
 
     pub fn xxx(x: Float) -> Float3 {
-        let x = x.into();
-        Float3 {
-            x: x,
-            y: x,
-            z: x,
-        }
+        Float3 { x, y: x, z: x }
     }
 
     // ---- Access/Translations ----------
@@ -94,12 +107,26 @@ impl Float3 {
         (1.0 - t) * a + t * b
     }
 
+    /// Linear luminance, with Rec. 709 weights.
+    pub fn luminance(&self) -> Float {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    #[cfg(not(feature = "simd"))]
     pub fn dot(&self, other: &Float3) -> Float {
         (self.x * other.x) +
         (self.y * other.y) +
         (self.z * other.z)
     }
 
+    #[cfg(feature = "simd")]
+    #[inline]
+    pub fn dot(&self, other: &Float3) -> Float {
+        let products = (Lanes::from(*self) * Lanes::from(*other)).0;
+        // In the same order as above, so that we round the same way.
+        products[0] + products[1] + products[2]
+    }
+
     pub fn cross(&self, other: &Float3) -> Float3 {
         let v1 = self;
         let v2 = &other;
@@ -118,6 +145,13 @@ impl Float3 {
         self.dot(self)
     }
 
+    /// Whether every component is so small that the vector has no direction
+    /// worth speaking of. `unit()` of one of these is garbage, or NaN.
+    pub fn near_zero(&self) -> bool {
+        const EPSILON: Float = 1.0e-8;
+        self.x.abs() < EPSILON && self.y.abs() < EPSILON && self.z.abs() < EPSILON
+    }
+
     pub fn unit(&self) -> Float3 {
         let length = self.length();
         assert_ne!(length, 0.);
@@ -140,12 +174,12 @@ impl Float3 {
 
     /// Return the minimum of each component in a new Float3.
     /// ```rust
-    /// # use crate::float3::Float3;
-    /// let a = Float3::xyz(-1, 2, -3);
-    /// let b = Float3::xyz(1, -2, 3);
+    /// # use one_weekend::float3::Float3;
+    /// let a = Float3::xyz(-1., 2., -3.);
+    /// let b = Float3::xyz(1., -2., 3.);
     ///
-    /// assert_eq!(a.min(c), c.min(a));
-    /// assert_eq!(a.min(c), Float3::xyz(-1, -2, -3))
+    /// assert_eq!(a.min(&b), b.min(&a));
+    /// assert_eq!(a.min(&b), Float3::xyz(-1., -2., -3.))
     /// ```
     pub fn min(&self, other: &Float3) -> Float3 {
         Float3 {
@@ -157,13 +191,12 @@ impl Float3 {
 
     /// Return the maximum of each component in a new Float3.
     /// ```rust
-    /// # use crate::float3::Float3;
-    /// let a = Float3::xyz(-1, 2, -3);
-    /// let b = Float3::xyz(1, -2, 3);
-    /// let c = a.max(c);
+    /// # use one_weekend::float3::Float3;
+    /// let a = Float3::xyz(-1., 2., -3.);
+    /// let b = Float3::xyz(1., -2., 3.);
     ///
-    /// assert_eq!(a.max(c), c.max(a));
-    /// assert_eq!(a.max(c), Float3::xyz(1, 2, 3))
+    /// assert_eq!(a.max(&b), b.max(&a));
+    /// assert_eq!(a.max(&b), Float3::xyz(1., 2., 3.))
     /// ```
     pub fn max(&self, other: &Float3) -> Float3 {
         Float3 {
@@ -172,10 +205,78 @@ impl Float3 {
             z: self.z.max(other.z),
         }
     }
+
+    /// Each component, limited to [`lo`, `hi`]. NaN components become `lo`,
+    /// like colors are clamped everywhere else.
+    /// ```rust
+    /// # use one_weekend::float3::Float3;
+    /// assert_eq!(Float3::xyz(-1., 0.5, 2.).clamp(0., 1.), Float3::xyz(0., 0.5, 1.));
+    /// ```
+    pub fn clamp(&self, lo: Float, hi: Float) -> Float3 {
+        Float3 {
+            x: self.x.max(lo).min(hi),
+            y: self.y.max(lo).min(hi),
+            z: self.z.max(lo).min(hi),
+        }
+    }
+
+    /// The largest component. NaN components are skipped, unless they're all NaN.
+    pub fn max_component(&self) -> Float {
+        self.x.max(self.y).max(self.z)
+    }
+
+    /// The smallest component. NaN components are skipped, unless they're all NaN.
+    pub fn min_component(&self) -> Float {
+        self.x.min(self.y).min(self.z)
+    }
+
+    /// Which axis has the largest component, the first one when some tie.
+    /// NaN components are skipped, unless they're all NaN, and then it's 0.
+    /// ```rust
+    /// # use one_weekend::float3::Float3;
+    /// assert_eq!(Float3::xyz(1., 3., 2.).argmax_component(), 1);
+    /// assert_eq!(Float3::xyz(3., 1., 3.).argmax_component(), 0);
+    /// ```
+    pub fn argmax_component(&self) -> usize {
+        let mut axis = 0;
+        for i in 1..3 {
+            if self[i] > self[axis] || (self[axis].is_nan() && !self[i].is_nan()) {
+                axis = i;
+            }
+        }
+        axis
+    }
+}
+
+/// `v[0]`, `v[1]`, and `v[2]` are `v.x`, `v.y`, and `v.z`. Any other axis panics.
+impl ops::Index<usize> for Float3 {
+    type Output = Float;
+    #[inline]
+    fn index(&self, axis: usize) -> &Float {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Float3 has no axis {}", axis),
+        }
+    }
+}
+
+impl ops::IndexMut<usize> for Float3 {
+    #[inline]
+    fn index_mut(&mut self, axis: usize) -> &mut Float {
+        match axis {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Float3 has no axis {}", axis),
+        }
+    }
 }
 
 impl ops::Add<Float3> for Float3 {
     type Output = Self;
+    #[cfg(not(feature = "simd"))]
     fn add(self, rhs: Float3) -> Float3 {
         Float3 {
             x: self.x + rhs.x,
@@ -183,6 +284,12 @@ impl ops::Add<Float3> for Float3 {
             z: self.z + rhs.z,
         }
     }
+
+    #[cfg(feature = "simd")]
+    #[inline]
+    fn add(self, rhs: Float3) -> Float3 {
+        (Lanes::from(self) + Lanes::from(rhs)).into()
+    }
 }
 
 impl ops::AddAssign<Float3> for Float3 {
@@ -195,6 +302,7 @@ impl ops::AddAssign<Float3> for Float3 {
 
 impl ops::Sub for Float3 {
     type Output = Self;
+    #[cfg(not(feature = "simd"))]
     fn sub(self, rhs: Float3) -> Float3 {
         Float3 {
             x: self.x - rhs.x,
@@ -202,6 +310,12 @@ impl ops::Sub for Float3 {
             z: self.z - rhs.z,
         }
     }
+
+    #[cfg(feature = "simd")]
+    #[inline]
+    fn sub(self, rhs: Float3) -> Float3 {
+        (Lanes::from(self) - Lanes::from(rhs)).into()
+    }
 }
 
 impl ops::SubAssign<Float3> for Float3 {
@@ -225,6 +339,7 @@ impl ops::Neg for Float3 {
 
 impl ops::Mul<Float3> for Float3 {
     type Output = Self;
+    #[cfg(not(feature = "simd"))]
     fn mul(self, rhs: Float3) -> Float3 {
         Float3 {
             x: self.x * rhs.x,
@@ -232,6 +347,12 @@ impl ops::Mul<Float3> for Float3 {
             z: self.z * rhs.z,
         }
     }
+
+    #[cfg(feature = "simd")]
+    #[inline]
+    fn mul(self, rhs: Float3) -> Float3 {
+        (Lanes::from(self) * Lanes::from(rhs)).into()
+    }
 }
 
 impl ops::MulAssign<Float3> for Float3 {
@@ -242,6 +363,27 @@ impl ops::MulAssign<Float3> for Float3 {
     }
 }
 
+/// Divides each component by the one in `rhs`. Like dividing `Float`s, a 0
+/// there gives inf, or NaN for 0 / 0.
+impl ops::Div<Float3> for Float3 {
+    type Output = Self;
+    fn div(self, rhs: Float3) -> Float3 {
+        Float3 {
+            x: self.x / rhs.x,
+            y: self.y / rhs.y,
+            z: self.z / rhs.z,
+        }
+    }
+}
+
+impl ops::DivAssign<Float3> for Float3 {
+    fn div_assign(&mut self, rhs: Float3) {
+        self.x /= rhs.x;
+        self.y /= rhs.y;
+        self.z /= rhs.z;
+    }
+}
+
 macro_rules! impl_scalar_add_for {
     ($prim:ty) => {
         // $prim + Float3
@@ -404,6 +546,25 @@ impl_scalar_div_for!(i64);
 impl_scalar_div_for!(usize);
 impl_scalar_div_for!(isize);
 
+/// Parses "x,y,z", like we take on the command line.
+impl FromStr for Float3 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Float3, String> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+        if parts.len() != 3 {
+            return Err(format!("Expected a vector like \"x,y,z\", found \"{}\"", s));
+        }
+
+        let mut v = Float3::new();
+        for (c, part) in v.as_mut_slice().iter_mut().zip(parts.iter()) {
+            *c = part.parse()
+                     .map_err(|e| format!("Bad component \"{}\" in \"{}\": {}", part, s, e))?;
+        }
+        Ok(v)
+    }
+}
+
 #[cfg(test)]
 mod t {
     use std::mem;
@@ -424,6 +585,18 @@ mod t {
         assert_eq!(slice, &[1.0, 2.0, 3.0]);
     }
 
+    #[test]
+    fn check_layout() {
+        // `as_slice()` reads the fields as an array, so they have to be laid
+        // out like one: in order, one right after the other.
+        let a = Float3::xyz(1., 2., 3.);
+        let offset = |field: &Float| field as *const Float as usize - &a as *const Float3 as usize;
+        let size = mem::size_of::<Float>();
+        assert_eq!((offset(&a.x), offset(&a.y), offset(&a.z)), (0, size, 2 * size));
+        assert_eq!(mem::size_of::<Float3>(), mem::size_of::<[Float; 3]>());
+        assert_eq!(mem::align_of::<Float3>(), mem::align_of::<[Float; 3]>());
+    }
+
     #[test]
     fn check_as_mut_slice() {
         let mut a = Float3::xyz(1., 2., 3.);
@@ -472,6 +645,22 @@ mod t {
         assert_eq!(a - Float3::xxx(10.), Float3::xyz(1., 2., 3.));
         a -= Float3::xxx(10.);
         assert_eq!(a, Float3::xyz(1., 2., 3.));
+
+        // Vector Mul and Div, component by component
+        let b = Float3::xyz(2., 4., -1.);
+        assert_eq!(a * b, Float3::xyz(2., 8., -3.));
+        assert_eq!(a * Float3::xxx(1.), a);
+        assert_eq!(a * (b + Float3::xxx(3.)), a * b + a * Float3::xxx(3.));
+        assert_eq!((a * b) / b, a);
+        a *= b;
+        assert_eq!(a, Float3::xyz(2., 8., -3.));
+        a /= b;
+        assert_eq!(a, Float3::xyz(1., 2., 3.));
+
+        // Dividing by 0 goes the way it does for a Float.
+        let q = Float3::xyz(1., -1., 0.) / Float3::new();
+        assert_eq!((q.x, q.y), (Float::INFINITY, -Float::INFINITY));
+        assert!(q.z.is_nan());
     }
 
     #[test]
@@ -503,5 +692,54 @@ mod t {
         // Solutions from Paul's Notes.
         assert_eq!(a.cross(&b), Float3::xyz(5., 1., 11.));
         assert_eq!(b.cross(&a), Float3::xyz(-5., -1., -11.));
+
+        assert!(Float3::new().near_zero());
+        assert!(Float3::xyz(1.0e-9, -1.0e-9, 0.).near_zero());
+        assert!(!Float3::xyz(0., 0., 1.0e-6).near_zero());
+        assert!(!(i - Float3::xyz(0.999, 0., 0.)).near_zero());
+    }
+
+    #[test]
+    fn check_index() {
+        let mut a = Float3::xyz(1., 2., 3.);
+        assert_eq!((a[0], a[1], a[2]), (1., 2., 3.));
+        a[1] = -2.;
+        assert_eq!(a, Float3::xyz(1., -2., 3.));
+    }
+
+    #[test]
+    #[should_panic(expected = "Float3 has no axis 3")]
+    fn check_index_past_z() {
+        let _ = Float3::new()[3];
+    }
+
+    #[test]
+    fn check_components() {
+        let nan = Float::NAN;
+        let a = Float3::xyz(2., -1., 5.);
+        assert_eq!((a.min_component(), a.max_component(), a.argmax_component()), (-1., 5., 2));
+        assert_eq!(a.clamp(0., 3.), Float3::xyz(2., 0., 3.));
+
+        // NaNs are skipped over...
+        let a = Float3::xyz(nan, -1., 5.);
+        assert_eq!((a.min_component(), a.max_component(), a.argmax_component()), (-1., 5., 2));
+        assert_eq!(Float3::xyz(3., nan, 1.).argmax_component(), 0);
+        assert_eq!(Float3::xyz(nan, nan, 1.).argmax_component(), 2);
+        // ...unless there's nothing else.
+        let a = Float3::xxx(nan);
+        assert!(a.min_component().is_nan() && a.max_component().is_nan());
+        assert_eq!(a.argmax_component(), 0);
+        // And clamping takes them to the bottom.
+        assert_eq!(Float3::xyz(nan, 0.5, 9.).clamp(0., 1.), Float3::xyz(0., 0.5, 1.));
+    }
+
+    #[test]
+    fn check_from_str() {
+        assert_eq!("1,2,3".parse(), Ok(Float3::xyz(1., 2., 3.)));
+        assert_eq!(" -1.5, 0 ,1e3".parse(), Ok(Float3::xyz(-1.5, 0., 1000.)));
+
+        assert!("1,2".parse::<Float3>().is_err());
+        assert!("1,2,3,4".parse::<Float3>().is_err());
+        assert!("1,two,3".parse::<Float3>().is_err());
     }
 }
@@ -0,0 +1,301 @@
+//! Flying the camera around a scene from the preview window.
+//!
+//! The window turns key presses and mouse movement into `Input`s, and applies
+//! them to a `View` that it shares with the renderer. Every change bumps the
+//! view's generation, which is how the renderer notices that its samples are
+//! for an old camera and starts over.
+
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+    Mutex,
+};
+
+use log::info;
+
+use crate::camera::{
+    CameraInfo,
+    Projection,
+};
+use crate::prelude::*;
+
+/// Each step moves this fraction of the distance to `lookat`.
+const MOVE_STEP: Float = 0.05;
+
+/// How far the view turns as the mouse is dragged.
+const DEGREES_PER_PIXEL: Float = 0.2;
+
+/// How much one click of the mouse wheel changes the field of view.
+const DEGREES_PER_CLICK: Float = 2.0;
+
+/// How much one click of the mouse wheel shrinks an orthographic view.
+const ORTHO_ZOOM_PER_CLICK: Float = 0.9;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Input {
+    /// Steps along the camera's (right, up, forward) axes.
+    Move(Float3),
+    /// The mouse was dragged by (x, y) pixels.
+    Rotate(Float, Float),
+    /// Clicks of the mouse wheel. Positive zooms in.
+    Zoom(Float),
+    /// Print the camera as command line flags.
+    Print,
+}
+
+/// Returns the camera after `input`, or `None` when it doesn't change.
+pub fn apply(info: &CameraInfo, input: Input) -> Option<CameraInfo> {
+    let forward = info.lookat - info.lookfrom;
+    let distance = forward.length();
+    let forward = forward.unit();
+    let right = forward.cross(&info.up).unit();
+    let up = right.cross(&forward);
+
+    match input {
+        Input::Move(steps) => {
+            if steps == Float3::new() {
+                return None;
+            }
+            let delta = MOVE_STEP * distance * (steps.x * right +
+                                                steps.y * up +
+                                                steps.z * forward);
+            Some(CameraInfo {
+                lookfrom: info.lookfrom + delta,
+                lookat:   info.lookat + delta,
+                ..*info
+            })
+        },
+        Input::Rotate(dx, dy) => {
+            if dx == 0.0 && dy == 0.0 {
+                return None;
+            }
+            // Dragging right turns us right, and dragging down looks down.
+            let yaw = (-dx * DEGREES_PER_PIXEL).to_radians();
+            let pitch = (-dy * DEGREES_PER_PIXEL).to_radians();
+
+            let turned = rotate(forward, info.up.unit(), yaw);
+            let tilted = rotate(turned, turned.cross(&info.up).unit(), pitch);
+            // Don't let the view flip over the top or bottom.
+            let forward = if tilted.dot(&info.up.unit()).abs() < 0.99 {
+                tilted
+            } else {
+                turned
+            };
+            Some(CameraInfo {
+                lookat: info.lookfrom + distance * forward,
+                ..*info
+            })
+        },
+        Input::Zoom(clicks) => {
+            let projection = match info.projection {
+                Projection::Perspective { vfov } => {
+                    let vfov = (vfov - clicks * DEGREES_PER_CLICK).max(1.0).min(150.0);
+                    Projection::Perspective { vfov }
+                },
+                Projection::Orthographic { height } => {
+                    let height = height * ORTHO_ZOOM_PER_CLICK.powf(clicks);
+                    Projection::Orthographic { height }
+                },
+                Projection::Fisheye { fov } => {
+                    let fov = (fov - clicks * DEGREES_PER_CLICK).max(1.0).min(360.0);
+                    Projection::Fisheye { fov }
+                },
+                // Panoramas already see everything.
+                Projection::Equirectangular => return None,
+            };
+            if projection == info.projection {
+                return None;
+            }
+            Some(CameraInfo {
+                projection,
+                ..*info
+            })
+        },
+        Input::Print => None,
+    }
+}
+
+/// Rotates `v` by `angle` radians around the unit vector `axis`.
+/// See: Rodrigues' rotation formula
+pub fn rotate(v: Float3, axis: Float3, angle: Float) -> Float3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + axis.cross(&v) * sin + axis * axis.dot(&v) * (1.0 - cos)
+}
+
+/// The command line flags that reproduce this camera.
+pub fn cli_flags(info: &CameraInfo) -> String {
+    let vector = |v: Float3| format!("{},{},{}", v.x, v.y, v.z);
+    let projection = match info.projection {
+        Projection::Perspective { vfov } => format!("--vfov {}", vfov),
+        Projection::Orthographic { height } => {
+            format!("--projection ortho --ortho-height {}", height)
+        },
+        Projection::Equirectangular => "--projection equirect".into(),
+        Projection::Fisheye { fov } => format!("--projection fisheye --fov {}", fov),
+    };
+    format!("--lookfrom {} --lookat {} {} --aperature {} --focus-dist {} \
+             --t-start {} --t-end {}",
+            vector(info.lookfrom),
+            vector(info.lookat),
+            projection,
+            info.aperature,
+            info.focus_dist,
+            info.t_start,
+            info.t_end)
+}
+
+/// The camera, shared between the window and the renderer.
+pub struct View {
+    info:       Mutex<CameraInfo>,
+    // Bumped every time `info` changes.
+    generation: AtomicU64,
+    // Set once nobody is looking anymore.
+    closed:     AtomicBool,
+}
+
+impl View {
+    pub fn new(info: CameraInfo) -> View {
+        View {
+            info:       Mutex::new(info),
+            generation: AtomicU64::new(0),
+            closed:     AtomicBool::new(false),
+        }
+    }
+
+    /// The current camera, and its generation.
+    pub fn camera(&self) -> (u64, CameraInfo) {
+        let info = self.info.lock().unwrap();
+        (self.generation(), *info)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn apply(&self, input: Input) {
+        let mut info = self.info.lock().unwrap();
+        if input == Input::Print {
+            info!("{}", cli_flags(&info));
+        }
+        if let Some(new_info) = apply(&info, input) {
+            *info = new_info;
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn info() -> CameraInfo {
+        CameraInfo {
+            lookfrom:   Float3::xyz(13., 2., 3.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 20. },
+            aspect:     1.5,
+            aperature:  0.1,
+            focus_dist: 10.,
+            t_start:    0.,
+            t_end:      0.5,
+        }
+    }
+
+    #[test]
+    fn check_move() {
+        let before = info();
+        let after = apply(&before, Input::Move(Float3::xyz(0., 0., 1.))).unwrap();
+
+        // Moving forward gets us closer, and keeps us looking the same way.
+        let gap = |info: &CameraInfo| info.lookat - info.lookfrom;
+        assert!((after.lookfrom - before.lookat).length() <
+                (before.lookfrom - before.lookat).length());
+        assert!((gap(&after) - gap(&before)).length() < 1e-12);
+
+        // Left and right cancel out.
+        let left = apply(&before, Input::Move(Float3::xyz(-1., 0., 0.))).unwrap();
+        let back = apply(&left, Input::Move(Float3::xyz(1., 0., 0.))).unwrap();
+        assert!((back.lookfrom - before.lookfrom).length() < 1e-12);
+
+        assert!(apply(&before, Input::Move(Float3::new())).is_none());
+    }
+
+    #[test]
+    fn check_rotate() {
+        let before = info();
+        let distance = (before.lookat - before.lookfrom).length();
+        for &(dx, dy) in [(10., 0.), (0., 10.), (-35., 12.), (0., -1000.)].iter() {
+            let after = apply(&before, Input::Rotate(dx, dy)).unwrap();
+            // We turn in place.
+            assert_eq!(after.lookfrom, before.lookfrom);
+            let new_distance = (after.lookat - after.lookfrom).length();
+            assert!((new_distance - distance).abs() < 1e-5);
+            assert!(after.lookat != before.lookat);
+        }
+        assert!(apply(&before, Input::Rotate(0., 0.)).is_none());
+    }
+
+    #[test]
+    fn check_zoom() {
+        let before = info();
+        let vfov = |clicks| apply(&before, Input::Zoom(clicks)).unwrap().projection;
+        assert_eq!(vfov(1.), Projection::Perspective { vfov: 18. });
+        assert_eq!(vfov(-1.), Projection::Perspective { vfov: 22. });
+        assert_eq!(vfov(100.), Projection::Perspective { vfov: 1. });
+
+        let ortho = CameraInfo { projection: Projection::Orthographic { height: 10. }, ..before };
+        match apply(&ortho, Input::Zoom(2.)).unwrap().projection {
+            Projection::Orthographic { height } => assert!((height - 8.1).abs() < 1e-5),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_cli_flags_roundtrip() {
+        let mut info = info();
+        // Make sure odd values survive exactly.
+        info.lookfrom = Float3::xyz(0.1 + 0.2, -1.0 / 3.0, 1e-7);
+        info.projection = Projection::Perspective { vfov: 33.333333333333336 };
+
+        let flags = cli_flags(&info);
+        let words: Vec<&str> = flags.split_whitespace().collect();
+        let value = |flag: &str| {
+            let i = words.iter().position(|w| *w == flag).unwrap();
+            words[i + 1]
+        };
+        assert_eq!(value("--lookfrom").parse::<Float3>(), Ok(info.lookfrom));
+        assert_eq!(value("--lookat").parse::<Float3>(), Ok(info.lookat));
+        assert_eq!(value("--vfov").parse::<Float>(), Ok(33.333333333333336));
+        assert_eq!(value("--aperature").parse::<Float>(), Ok(info.aperature));
+        assert_eq!(value("--focus-dist").parse::<Float>(), Ok(info.focus_dist));
+        assert_eq!(value("--t-start").parse::<Float>(), Ok(info.t_start));
+        assert_eq!(value("--t-end").parse::<Float>(), Ok(info.t_end));
+    }
+
+    #[test]
+    fn check_view_generations() {
+        let view = View::new(info());
+        assert_eq!(view.generation(), 0);
+
+        view.apply(Input::Print);
+        view.apply(Input::Rotate(0., 0.));
+        assert_eq!(view.generation(), 0);
+
+        view.apply(Input::Zoom(1.));
+        let (generation, info) = view.camera();
+        assert_eq!(generation, 1);
+        assert_eq!(info.projection, Projection::Perspective { vfov: 18. });
+    }
+}
@@ -0,0 +1,230 @@
+//! Merging renders focused at different distances into one image that's
+//! in focus everywhere.
+//!
+//! For every pixel, we pick the layer that looks sharpest there. "Sharp" is
+//! measured with the magnitude of a 3x3 Laplacian over linear luminance:
+//! in-focus detail has large second derivatives, and blur smooths them away.
+
+use crate::output::LinearImage;
+use crate::prelude::*;
+
+/// One end of a focus range: a distance from the camera, like "2.5", or an
+/// offset from the camera's focus distance, like "-2" or "+3".
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FocusEnd {
+    Distance(Float),
+    Offset(Float),
+}
+
+impl FocusEnd {
+    /// Where this end is, for a camera focused at `focus_dist`.
+    pub fn distance(self, focus_dist: Float) -> Float {
+        match self {
+            FocusEnd::Distance(distance) => distance,
+            FocusEnd::Offset(offset) => focus_dist + offset,
+        }
+    }
+}
+
+/// Parses a focus range like "2.5,12", or "-2,+3" around the camera's focus
+/// distance. Distances must be positive, but the ends may come in either
+/// order - we sweep from the first to the second.
+pub fn parse_focus_range(s: &str) -> Result<(FocusEnd, FocusEnd), String> {
+    let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+    if parts.len() != 2 {
+        return Err(format!("Expected a range like \"d0,d1\", found \"{}\"", s));
+    }
+
+    let mut ends = [FocusEnd::Distance(0.0); 2];
+    for (end, part) in ends.iter_mut().zip(parts.iter()) {
+        let value = part.parse::<Float>()
+                        .map_err(|e| format!("Bad focus distance \"{}\": {}", part, e))?;
+        *end = if part.starts_with('+') || part.starts_with('-') {
+            FocusEnd::Offset(value)
+        } else if value > 0.0 {
+            FocusEnd::Distance(value)
+        } else {
+            return Err(format!("Focus distances must be positive, found {}", value));
+        };
+    }
+
+    Ok((ends[0], ends[1]))
+}
+
+/// The distances that `range` spans for a camera focused at `focus_dist`.
+/// Offsets can't reach back past the camera.
+pub fn resolve_focus_range(range: (FocusEnd, FocusEnd), focus_dist: Float)
+    -> Result<(Float, Float), String>
+{
+    let ends = (range.0.distance(focus_dist), range.1.distance(focus_dist));
+    for &end in [ends.0, ends.1].iter() {
+        if !(end > 0.0) {
+            return Err(format!("Focus distances must be positive, but the focus range \
+                                reaches {} from a focus distance of {}",
+                               end, focus_dist));
+        }
+    }
+    Ok(ends)
+}
+
+/// The focus distance of each of `n` layers, evenly spanning `range`.
+pub fn focus_distances(n: u32, range: (Float, Float)) -> Vec<Float> {
+    let (d0, d1) = range;
+    match n {
+        0 => vec![],
+        1 => vec![0.5 * (d0 + d1)],
+        _ => (0..n).map(|i| {
+                 let t = i as Float / (n - 1) as Float;
+                 d0 + t * (d1 - d0)
+             })
+             .collect(),
+    }
+}
+
+/// Local contrast of each pixel: |Laplacian| over its 3x3 neighborhood.
+/// Neighbors past the edge of the image reuse the nearest edge pixel.
+pub fn contrast(lum: &[Float], width: u32, height: u32) -> Vec<Float> {
+    assert_eq!(lum.len(), (width * height) as usize);
+
+    let (w, h) = (width as i64, height as i64);
+    let at = |x: i64, y: i64| {
+        let x = x.max(0).min(w - 1);
+        let y = y.max(0).min(h - 1);
+        lum[(y * w + x) as usize]
+    };
+
+    let mut out = Vec::with_capacity(lum.len());
+    for y in 0..h {
+        for x in 0..w {
+            let mut neighbors = 0.0;
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if dx != 0 || dy != 0 {
+                        neighbors += at(x + dx, y + dy);
+                    }
+                }
+            }
+            out.push((8.0 * at(x, y) - neighbors).abs());
+        }
+    }
+    out
+}
+
+/// Merge `layers` by taking each pixel from the layer with the highest local
+/// contrast there. Ties go to the earlier layer.
+/// All layers must have the same dimensions.
+pub fn merge(layers: &[LinearImage]) -> LinearImage {
+    assert!(!layers.is_empty(), "Cannot merge zero layers");
+    let (width, height) = (layers[0].width(), layers[0].height());
+    for layer in layers {
+        assert_eq!((layer.width(), layer.height()), (width, height),
+                   "Every layer must be the same size");
+    }
+
+    let contrasts: Vec<Vec<Float>> = layers
+        .iter()
+        .map(|layer| {
+            let lum: Vec<Float> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| layer.get_pixel(x, y).luminance()))
+                .collect();
+            contrast(&lum, width, height)
+        })
+        .collect();
+
+    LinearImage::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        let mut best = 0;
+        for (layer, c) in contrasts.iter().enumerate() {
+            if c[i] > contrasts[best][i] {
+                best = layer;
+            }
+        }
+        layers[best].get_pixel(x, y)
+    })
+}
+
+#[cfg(test)]
+mod t {
+    use crate::focus_stack::*;
+
+    const W: u32 = 16;
+    const H: u32 = 8;
+
+    // A fine checkerboard stands in for "sharp", flat gray for "blurry".
+    fn sharp(x: u32, y: u32) -> Float3 {
+        if (x + y) % 2 == 0 { Float3::xxx(1.0) } else { Float3::new() }
+    }
+
+    fn blurry() -> Float3 {
+        Float3::xxx(0.5)
+    }
+
+    #[test]
+    fn check_merge_picks_sharp_halves() {
+        let left_sharp = LinearImage::from_fn(W, H, |x, y| {
+            if x < W / 2 { sharp(x, y) } else { blurry() }
+        });
+        let right_sharp = LinearImage::from_fn(W, H, |x, y| {
+            if x < W / 2 { blurry() } else { sharp(x, y) }
+        });
+
+        let merged = merge(&[left_sharp, right_sharp]);
+        for y in 0..H {
+            for x in 0..W {
+                assert_eq!(merged.get_pixel(x, y), sharp(x, y), "at ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn check_merge_keeps_highlights() {
+        // Brighter than 8 bits can hold, which the merged image still is.
+        let bright = |x: u32, y: u32| 4.0 * sharp(x, y);
+        let sharp_layer = LinearImage::from_fn(W, H, bright);
+        let blurry_layer = LinearImage::from_fn(W, H, |_, _| Float3::xxx(2.0));
+
+        let merged = merge(&[blurry_layer, sharp_layer]);
+        assert_eq!(merged.get_pixel(0, 0), Float3::xxx(4.0));
+        assert_eq!(merged.get_pixel(1, 0), Float3::new());
+    }
+
+    #[test]
+    fn check_contrast_of_flat_image_is_zero() {
+        let lum = vec![0.5; (W * H) as usize];
+        assert!(contrast(&lum, W, H).iter().all(|&c| c == 0.0));
+    }
+
+    #[test]
+    fn check_focus_distances() {
+        assert_eq!(focus_distances(3, (1.0, 3.0)), vec![1.0, 2.0, 3.0]);
+        assert_eq!(focus_distances(2, (10.0, 5.0)), vec![10.0, 5.0]);
+        assert_eq!(focus_distances(1, (2.0, 4.0)), vec![3.0]);
+    }
+
+    #[test]
+    fn check_parse_focus_range() {
+        use self::FocusEnd::*;
+
+        assert_eq!(parse_focus_range("2,12"), Ok((Distance(2.0), Distance(12.0))));
+        assert_eq!(parse_focus_range(" 12.5 , 0.5 "), Ok((Distance(12.5), Distance(0.5))));
+        assert_eq!(parse_focus_range("-2,+3"), Ok((Offset(-2.0), Offset(3.0))));
+        assert_eq!(parse_focus_range("-1, 12"), Ok((Offset(-1.0), Distance(12.0))));
+        assert!(parse_focus_range("2").is_err());
+        assert!(parse_focus_range("2,3,4").is_err());
+        assert!(parse_focus_range("0,3").is_err());
+        assert!(parse_focus_range("a,3").is_err());
+        assert!(parse_focus_range("+a,3").is_err());
+    }
+
+    #[test]
+    fn check_resolve_focus_range() {
+        let range = |s: &str| parse_focus_range(s).unwrap();
+        assert_eq!(resolve_focus_range(range("-2,+3"), 10.0), Ok((8.0, 13.0)));
+        assert_eq!(resolve_focus_range(range("+1,-1"), 4.0), Ok((5.0, 3.0)));
+        assert_eq!(resolve_focus_range(range("2,+3"), 10.0), Ok((2.0, 13.0)));
+        assert_eq!(resolve_focus_range(range("2,12"), 5.0), Ok((2.0, 12.0)));
+        // Offsets can't reach the camera, or behind it.
+        assert!(resolve_focus_range(range("-10,+3"), 10.0).is_err());
+        assert!(resolve_focus_range(range("-12,+3"), 10.0).is_err());
+    }
+}
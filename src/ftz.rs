@@ -0,0 +1,102 @@
+//! Flushing subnormal floats to zero.
+//!
+//! Subnormals are the floats too small to keep full precision. On many x86
+//! CPUs, any math that produces or reads one takes a slow path through
+//! microcode, which can be 100x slower than usual. Deep paths grind their
+//! throughput down to exactly these sizes.
+//!
+//! Stopping paths at `--throughput-cutoff` avoids most of that. With the `ftz`
+//! feature, we also set the FTZ ("flush to zero") and DAZ ("denormals are
+//! zero") bits on the render threads, so that the CPU treats every subnormal
+//! as 0 and never takes the slow path.
+
+/// Has the CPU treat subnormals as zero on the calling thread, if we were built
+/// with the `ftz` feature for x86. Returns whether it did.
+///
+/// This only changes math on the calling thread, and only affects values so
+/// small that they can't show up in an image. It isn't something to turn on
+/// for threads that run code we don't own, which might rely on subnormals.
+pub fn enable_on_this_thread() -> bool {
+    #[cfg(all(feature = "ftz", any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        #[cfg(target_arch = "x86")]
+        #[allow(deprecated)]
+        use std::arch::x86::{_mm_getcsr, _mm_setcsr};
+        #[cfg(target_arch = "x86_64")]
+        #[allow(deprecated)]
+        use std::arch::x86_64::{_mm_getcsr, _mm_setcsr};
+
+        // Bits of MXCSR, the SSE control register.
+        const FLUSH_TO_ZERO: u32 = 1 << 15;
+        const DENORMALS_ARE_ZERO: u32 = 1 << 6;
+
+        // Safety: SSE is part of every x86_64 CPU and every x86 target Rust
+        // supports by default. Setting these bits changes the results of float
+        // math on this thread, but not in a way that breaks memory safety.
+        #[allow(deprecated)]
+        unsafe {
+            _mm_setcsr(_mm_getcsr() | FLUSH_TO_ZERO | DENORMALS_ARE_ZERO);
+        }
+        true
+    }
+
+    #[cfg(not(all(feature = "ftz", any(target_arch = "x86", target_arch = "x86_64"))))]
+    {
+        false
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use std::time::Instant;
+
+    /// Keeps the optimizer from doing our math for us at compile time.
+    fn black_box(x: f64) -> f64 {
+        unsafe { std::ptr::read_volatile(&x) }
+    }
+
+    /// Multiplies `x` by a number near 1 many times, and returns how long it took.
+    fn time_multiplies(x: f64) -> (f64, f64) {
+        let start = Instant::now();
+        let mut acc = black_box(x);
+        for _ in 0..10_000_000 {
+            acc = black_box(acc * black_box(0.999_999_9) + black_box(0.0));
+        }
+        (start.elapsed().as_secs_f64(), acc)
+    }
+
+    #[test]
+    fn check_ftz() {
+        let smallest_normal = std::f64::MIN_POSITIVE;
+        // Enabling only touches this thread, and tests each get their own.
+        let flushed = std::thread::spawn(move || {
+            let enabled = enable_on_this_thread();
+            (enabled, black_box(smallest_normal) / black_box(4.0))
+        }).join().unwrap();
+
+        match flushed {
+            (true, x) => assert_eq!(x, 0.0),
+            (false, x) => assert!(x > 0.0),
+        }
+        // We're still unaffected.
+        assert!(black_box(smallest_normal) / black_box(4.0) > 0.0);
+    }
+
+    /// Run with `cargo test --release --features ftz -- --ignored --nocapture`
+    /// to see the difference subnormals make.
+    #[test]
+    #[ignore]
+    fn bench_subnormals() {
+        let subnormal = std::f64::MIN_POSITIVE / 4.0;
+        let (normal_secs, _) = time_multiplies(1.0);
+        let (subnormal_secs, _) = time_multiplies(subnormal);
+        let (flushed_secs, _) = std::thread::spawn(move || {
+            assert!(enable_on_this_thread(), "Build with --features ftz on x86");
+            time_multiplies(subnormal)
+        }).join().unwrap();
+        eprintln!("normal:    {:.3}s", normal_secs);
+        eprintln!("subnormal: {:.3}s ({:.1}x)", subnormal_secs, subnormal_secs / normal_secs);
+        eprintln!("flushed:   {:.3}s ({:.1}x)", flushed_secs, flushed_secs / normal_secs);
+    }
+}
@@ -1,9 +1,21 @@
-use std::{
-    mem,
-    sync::Arc,
-};
+use std::sync::Arc;
 
+use crate::float3::{
+    consts,
+    FLOAT_EPSILON,
+};
+use crate::packet::{
+    hit_closest_packet,
+    LaneRngs,
+    RayPacket4,
+};
 use crate::prelude::*;
+use crate::ray::RayPrecomp;
+use crate::simd::Lanes;
+
+/// A ray passes through at most this many cutouts before we give up and
+/// stop it at the next one.
+pub const MAX_CUTOUT_SKIPS: u32 = 32;
 
 #[derive(Clone, Debug)]
 pub struct HitRecord {
@@ -13,16 +25,93 @@ pub struct HitRecord {
     pub p: Float3,
     // Normal value at point of hit.
     pub normal: Float3,
+    // Surface coordinates of the hit, for textures. Both are in [0, 1].
+    pub u: Float,
+    pub v: Float,
+    // Which way `u` increases along the surface, for normal maps. It's unit
+    // length, and perpendicular to `normal`.
+    pub tangent: Float3,
     // Material of hit.
     pub material: Arc<dyn Material>,
 }
 
 pub trait Hitable: std::fmt::Debug + Send + Sync {
     /// Compute whether and where a ray intersections this object.
-    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>;
+
+    /// Whether a ray hits anything at all between `t_min` and `t_max`, for
+    /// shadow rays, which don't care what or where. Cutouts stop it as often
+    /// as they're opaque, like they do in the renderer.
+    /// Collections of hitables can stop at the first hit, rather than find
+    /// the closest one.
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        hit_any_through_cutouts(self, ray, t_min, t_max, rng)
+    }
+
+    /// Like `hit()`, for each ray of a packet, between its own lane of `t_min`
+    /// and `t_max`. Lanes that are switched off don't hit anything.
+    /// Whatever the packet finds has to be exactly what `hit()` finds for each
+    /// ray alone, which by default is how we find it.
+    fn hit_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        rays.map(|lane, ray| self.hit(ray, t_min.0[lane], t_max.0[lane], &mut rngs[lane]))
+    }
 
     /// Compute the bounding box for this object.
     fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb>;
+
+    /// The probability density of `random_toward(origin)` picking `dir`.
+    /// Objects that can't be sampled as lights leave this as 0.0.
+    fn pdf_value(&self, _origin: &Float3, _dir: &Float3) -> Float {
+        0.0
+    }
+
+    /// Pick a random direction from `origin` toward this object, with random
+    /// numbers from `rng`.
+    fn random_toward(&self, _origin: &Float3, _rng: &mut dyn RngCore) -> Float3 {
+        Float3::xyz(1., 0., 0.)
+    }
+
+    /// Replace every material this object uses with `f(material)`.
+    fn map_materials(&mut self, _f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+    }
+
+    /// Every stretch of the whole line through `ray` that's inside this
+    /// object, in order, with normals facing out. `Csg` builds shapes out of
+    /// these. Only solids have an inside, so by default there's none.
+    fn hit_all(&self, _ray: &Ray) -> Vec<Span> {
+        vec![]
+    }
+}
+
+/// `Hitable::hit_any()` for any hitable, from its `hit()`s.
+pub fn hit_any_through_cutouts(hitable: &(impl Hitable + ?Sized),
+                               ray:     &Ray,
+                               t_min:   Float,
+                               t_max:   Float,
+                               rng:     &mut dyn RngCore)
+    -> bool
+{
+    let mut t_min = t_min;
+    let mut skips = 0;
+    while let Some(record) = hitable.hit(ray, t_min, t_max, rng) {
+        let opacity = record.material.opacity(&record);
+        if opacity >= 1.0 || skips == MAX_CUTOUT_SKIPS || random_float_with(rng) < opacity {
+            return true;
+        }
+        t_min = record.t;
+        skips += 1;
+    }
+    false
+}
+
+/// Where a ray goes into a solid, and where it comes back out.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub enter: HitRecord,
+    pub exit:  HitRecord,
 }
 
 #[derive(Clone, Debug)]
@@ -32,42 +121,116 @@ pub struct Sphere {
     pub material: Arc<dyn Material>,
 }
 
+/// Points closer than this to a sphere's surface, relative to its radius,
+/// are treated as on it when sampling the sphere as a light.
+const SPHERE_SURFACE_EPSILON: Float = 1e-6;
+
+/// The narrowest cone we'll sample a sphere light through, as a cosine.
+/// Far enough away, the cone rounds to nothing (especially in f32), and the
+/// pdf over it to infinity. A few ulps is still far narrower than a pixel.
+const MAX_COS_THETA: Float = 1.0 - 16.0 * FLOAT_EPSILON;
+
+impl Sphere {
+    /// The hit at `t` along `ray`, which has to be on the sphere.
+    fn record(&self, ray: &Ray, t: Float) -> HitRecord {
+        sphere_record(self.center, self.radius, &self.material, ray, t)
+    }
+
+    /// The directions that `random_toward(origin)` picks from, as the cosine
+    /// of the widest angle they make with the direction to the center.
+    ///
+    /// From outside, that's the cone of directions that see the sphere. From
+    /// (nearly) on the surface, that cone opens up into the hemisphere facing
+    /// inward, and from inside, every direction hits it.
+    fn cos_theta_max(&self, origin: &Float3) -> Float {
+        let radius = self.radius.abs();
+        let distance = (self.center - *origin).length();
+        if distance < radius * (1.0 - SPHERE_SURFACE_EPSILON) {
+            -1.0
+        } else if distance <= radius * (1.0 + SPHERE_SURFACE_EPSILON) {
+            0.0
+        } else {
+            // Just outside the epsilon, this can round past 1.
+            let sin_theta_max = (radius / distance).min(1.0);
+            (1.0 - sin_theta_max * sin_theta_max).sqrt().min(MAX_COS_THETA)
+        }
+    }
+}
+
+impl Sphere {
+    /// How far along `ray` it first hits the sphere, between `t_min` and
+    /// `t_max`.
+    fn hit_t(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<Float> {
+        crate::stats::count(|c| c.sphere_tests += 1);
+        sphere_hit_t(self.center, self.radius, ray, t_min, t_max)
+    }
+}
+
+/// How far along `ray` it first hits the sphere at `center`, between `t_min`
+/// and `t_max`. `SphereSet`s test their spheres with this too, so that they're
+/// hit in exactly the same places as `Sphere`s.
+#[inline]
+pub fn sphere_hit_t(center: Float3, radius: Float, ray: &Ray, t_min: Float, t_max: Float)
+    -> Option<Float>
+{
+    let oc = ray.origin - center;
+    let a = ray.dir.length_sq();
+    let b = oc.dot(&ray.dir);
+    let c = oc.length_sq() - radius * radius;
+    let discriminant = b * b - a * c;
+
+    // There are three cases to consider here:
+    //      1. discriminant < 0  => There are zero real solutions, no hit.
+    //      2. discriminant == 0 => There is exactly one real solutioin,
+    //          and the ray just barely grazes the sphere.
+    //      3. discriminant > 0  => There are two real solutions, so the ray
+    //          intersects the sphere and we need to hande the coloring.
+    if discriminant >= 0.0 {
+        // Check that the first hit is within bounds.
+        let t = (-b - discriminant.sqrt()) / a;
+        if t_min < t && t < t_max {
+            return Some(t);
+        }
+        // It wasn't - check if the second one is.
+        let t = (-b + discriminant.sqrt()) / a;
+        if t_min < t && t < t_max {
+            return Some(t);
+        }
+    }
+    // Nothing worked - no hit.
+    None
+}
+
+/// The hit at `t` along `ray` on the sphere at `center`, made of `material`.
+pub fn sphere_record(center:   Float3,
+                     radius:   Float,
+                     material: &Arc<dyn Material>,
+                     ray:      &Ray,
+                     t:        Float)
+    -> HitRecord
+{
+    let p = ray.at_t(t);
+    // Make sure `normal` stays normal.
+    let normal = (p - center) / radius;
+    let (u, v) = sphere_uv(&normal);
+    let tangent = sphere_tangent(&normal, radius);
+    let material = material.clone();
+    HitRecord { t, p, normal, u, v, tangent, material }
+}
+
 impl Hitable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        let oc = ray.origin - self.center;
-        let a = ray.dir.length_sq();
-        let b = oc.dot(&ray.dir);
-        let c = oc.length_sq() - self.radius * self.radius;
-        let discriminant = b * b - a * c;
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, _rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        self.hit_t(ray, t_min, t_max).map(|t| self.record(ray, t))
+    }
 
-        // There are three cases to consider here:
-        //      1. discriminant < 0  => There are zero real solutions, no hit.
-        //      2. discriminant == 0 => There is exactly one real solutioin,
-        //          and the ray just barely grazes the sphere.
-        //      3. discriminant > 0  => There are two real solutions, so the ray
-        //          intersects the sphere and we need to hande the coloring.
-        if discriminant >= 0.0 {
-            // Check that the first hit is within bounds.
-            let t = (-b - discriminant.sqrt()) / a;
-            if t_min < t && t < t_max {
-                let p = ray.at_t(t);
-                // Make sure `normal` stays normal.
-                let normal = (p - self.center) / self.radius;
-                let material = self.material.clone();
-                return Some(HitRecord { t, p, normal, material });
-            }
-            // It wasn't - check if the second one is.
-            let t = (-b + discriminant.sqrt()) / a;
-            if t_min < t && t < t_max {
-                let p = ray.at_t(t);
-                // Make sure `normal` stays normal.
-                let normal = (p - self.center) / self.radius;
-                let material = self.material.clone();
-                return Some(HitRecord { t, p, normal, material });
-            }
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        if self.material.is_opaque() {
+            self.hit_t(ray, t_min, t_max).is_some()
+        } else {
+            hit_any_through_cutouts(self, ray, t_min, t_max, rng)
         }
-        // Nothing worked - no hit.
-        None
     }
 
     // This object does not move wrt time, so we ignore the time inputs.
@@ -78,6 +241,89 @@ impl Hitable for Sphere {
             max: self.center + r,
         })
     }
+
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        // We sample uniformly over a cone of directions (see `cos_theta_max()`),
+        // so the pdf is 1 / (the cone's solid angle) inside of it, and 0 outside.
+        // This has to agree with `random_toward()` exactly, or lights sampled
+        // both ways won't add up to the right amount.
+        let cos_theta_max = self.cos_theta_max(origin);
+        let solid_angle = 2.0 * consts::PI * (1.0 - cos_theta_max);
+        if cos_theta_max <= -1.0 {
+            // Every direction counts, including ones with nothing to compare to.
+            return 1.0 / solid_angle;
+        }
+
+        let cos_theta = dir.unit().dot(&(self.center - *origin).unit());
+        if cos_theta >= cos_theta_max {
+            1.0 / solid_angle
+        } else {
+            0.0
+        }
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        let cos_theta_max = self.cos_theta_max(origin);
+        if cos_theta_max <= -1.0 {
+            // Every direction is as good as any other. We might be at the
+            // center, where there's no direction to build a basis around.
+            return random_in_cone_with(rng, cos_theta_max);
+        }
+        let onb = Onb::build_from_w(self.center - *origin);
+        onb.local(random_in_cone_with(rng, cos_theta_max))
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.material = f(&self.material);
+    }
+
+    fn hit_all(&self, ray: &Ray) -> Vec<Span> {
+        let oc = ray.origin - self.center;
+        let a = ray.dir.length_sq();
+        let b = oc.dot(&ray.dir);
+        let c = oc.length_sq() - self.radius * self.radius;
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            return vec![];
+        }
+        vec![Span {
+            enter: self.record(ray, (-b - discriminant.sqrt()) / a),
+            exit:  self.record(ray, (-b + discriminant.sqrt()) / a),
+        }]
+    }
+}
+
+/// Texture coordinates of a point on the unit sphere.
+/// `u` goes around the y axis, and `v` goes from the bottom to the top.
+pub fn sphere_uv(p: &Float3) -> (Float, Float) {
+    let phi = p.z.atan2(p.x);
+    // Clamp, in case `p` is ever so slightly off of the sphere.
+    let theta = p.y.max(-1.0).min(1.0).asin();
+    let u = 1.0 - (phi + consts::PI) / (2.0 * consts::PI);
+    let v = (theta + consts::FRAC_PI_2) / consts::PI;
+    (u, v)
+}
+
+/// Which way `u` goes on a sphere of `radius`, where `normal` is `(p - center)
+/// / radius`. `u` goes the other way around the y axis from `normal`, and at
+/// the poles, where it goes every way at once, we pick one.
+pub fn sphere_tangent(normal: &Float3, radius: Float) -> Float3 {
+    let tangent = Float3::xyz(normal.z, 0.0, -normal.x) * radius.signum();
+    if tangent.length_sq() > 0.0 {
+        tangent.unit()
+    } else {
+        Float3::xyz(1.0, 0.0, 0.0)
+    }
+}
+
+/// The point on the unit sphere with texture coordinates (`u`, `v`).
+/// This undoes `sphere_uv()`.
+pub fn sphere_point(u: Float, v: Float) -> Float3 {
+    let phi = (1.0 - u) * 2.0 * consts::PI - consts::PI;
+    let theta = v * consts::PI - consts::FRAC_PI_2;
+    Float3::xyz(theta.cos() * phi.cos(),
+                theta.sin(),
+                theta.cos() * phi.sin())
 }
 
 #[derive(Clone, Debug)]
@@ -89,7 +335,9 @@ pub struct MovingSphere {
 }
 
 impl Hitable for MovingSphere {
-    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
         // There's no easy way to
         //      1) reuse the sphere code, and
         //      2) not clone the material
@@ -97,7 +345,7 @@ impl Hitable for MovingSphere {
         // When we switch to Arcs, the ref count increment becomes atomic.
         let mut sphere = self.sphere.clone();
         sphere.center += ray.t * self.motion;
-        sphere.hit(ray, t_min, t_max)
+        sphere.hit(ray, t_min, t_max, rng)
     }
 
     fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
@@ -113,6 +361,69 @@ impl Hitable for MovingSphere {
         Some(Aabb::surrounding(&sphere_t0.bounding_box(t0, t1).unwrap(),
                                &sphere_t1.bounding_box(t0, t1).unwrap()))
     }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.sphere.map_materials(f);
+    }
+}
+
+/// A sphere that moves along a path, instead of in a straight line like a
+/// `MovingSphere`. Between keyframes it moves at a constant speed, and before
+/// the first or after the last, it stays put.
+#[derive(Clone, Debug)]
+pub struct KeyframedSphere {
+    // Geometry and material. Its center is ignored in favor of the keyframes.
+    pub sphere:    Sphere,
+    // (time, center) pairs, sorted by time. There has to be at least one.
+    pub keyframes: Vec<(Float, Float3)>,
+}
+
+impl KeyframedSphere {
+    /// Where the center is at time `t`.
+    pub fn center_at(&self, t: Float) -> Float3 {
+        // The first keyframe after `t`.
+        let next = self.keyframes.iter().position(|&(time, _)| time > t);
+        match next {
+            Some(0) => self.keyframes[0].1,
+            None => self.keyframes[self.keyframes.len() - 1].1,
+            Some(i) => {
+                let (t0, c0) = self.keyframes[i - 1];
+                let (t1, c1) = self.keyframes[i];
+                let s = (t - t0) / (t1 - t0);
+                c0 + s * (c1 - c0)
+            },
+        }
+    }
+
+    fn sphere_at(&self, t: Float) -> Sphere {
+        // Like `MovingSphere`, this clones the material's Arc.
+        let mut sphere = self.sphere.clone();
+        sphere.center = self.center_at(t);
+        sphere
+    }
+}
+
+impl Hitable for KeyframedSphere {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        self.sphere_at(ray.t).hit(ray, t_min, t_max, rng)
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        // The path is straight between keyframes, so it's enough to bound the
+        // ends of the interval and every keyframe within it.
+        let mut bbox = self.sphere_at(t0).bounding_box(t0, t1)?;
+        bbox = Aabb::surrounding(&bbox, &self.sphere_at(t1).bounding_box(t0, t1)?);
+        for &(time, _) in self.keyframes.iter().filter(|&&(time, _)| t0 < time && time < t1) {
+            bbox = Aabb::surrounding(&bbox, &self.sphere_at(time).bounding_box(t0, t1)?);
+        }
+        Some(bbox)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.sphere.map_materials(f);
+    }
 }
 
 #[derive(Debug, Default)]
@@ -121,12 +432,14 @@ pub struct HitableList {
 }
 
 impl Hitable for HitableList {
-    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
         let mut o_hit_record = None;
         let mut closest = t_max;
 
         for hitable in self.hitables.iter() {
-            if let Some(new_record) = hitable.hit(ray, t_min, closest) {
+            if let Some(new_record) = hitable.hit(ray, t_min, closest, rng) {
                 closest = new_record.t;
                 o_hit_record = Some(new_record);
             }
@@ -135,6 +448,16 @@ impl Hitable for HitableList {
         o_hit_record
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        self.hitables.iter().any(|hitable| hitable.hit_any(ray, t_min, t_max, rng))
+    }
+
+    fn hit_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        hit_closest_packet(&self.hitables, rays, t_min, t_max, rngs)
+    }
+
     fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
         // Iterate over the bounding boxes of `self.hitables`.
         let mut iter = self.hitables
@@ -159,8 +482,111 @@ impl Hitable for HitableList {
 
         Some(running_aabb)
     }
+
+    // A list is sampled by picking one of its members uniformly, so its pdf is
+    // the average of theirs.
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        if self.hitables.is_empty() {
+            return 0.0;
+        }
+
+        let sum: Float = self.hitables
+            .iter()
+            .map(|h| h.pdf_value(origin, dir))
+            .sum();
+        sum / self.hitables.len() as Float
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        let len = self.hitables.len();
+        let i = ((random_float_with(rng) * len as Float) as usize).min(len - 1);
+        self.hitables[i].random_toward(origin, rng)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        for hitable in self.hitables.iter_mut() {
+            hitable.map_materials(f);
+        }
+    }
 }
 
+/// Flips the normals of another hitable. Useful for surfaces that we look at
+/// from "behind", like the inside walls of a box.
+#[derive(Debug)]
+pub struct FlipNormals<H: Hitable> {
+    pub hitable: H,
+}
+
+impl<H: Hitable> Hitable for FlipNormals<H> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let mut record = self.hitable.hit(ray, t_min, t_max, rng)?;
+        record.normal = -record.normal;
+        Some(record)
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        self.hitable.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        self.hitable.pdf_value(origin, dir)
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        self.hitable.random_toward(origin, rng)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.hitable.map_materials(f);
+    }
+}
+
+/// A hitable that's shared, like the object behind many `transform::Instance`s.
+impl<H: Hitable + ?Sized> Hitable for Arc<H> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        (**self).hit(ray, t_min, t_max, rng)
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        (**self).hit_any(ray, t_min, t_max, rng)
+    }
+
+    fn hit_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        (**self).hit_packet(rays, t_min, t_max, rngs)
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        (**self).bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        (**self).pdf_value(origin, dir)
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        (**self).random_toward(origin, rng)
+    }
+
+    // Changing the materials of something shared would change them for
+    // everything sharing it, so only a hitable that isn't shared is changed.
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        if let Some(hitable) = Arc::get_mut(self) {
+            hitable.map_materials(f);
+        }
+    }
+
+    fn hit_all(&self, ray: &Ray) -> Vec<Span> {
+        (**self).hit_all(ray)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Aabb {
     pub min: Float3,
     pub max: Float3,
@@ -174,20 +600,440 @@ impl Aabb {
         }
     }
 
+    pub fn centroid(&self) -> Float3 {
+        0.5 * (self.min + self.max)
+    }
+
+    /// The area of the box's six sides.
+    pub fn surface_area(&self) -> Float {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
     pub fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> bool {
+        self.interval(ray, tmin, tmax).is_some()
+    }
+
+    /// The part of [`tmin`, `tmax`] that `ray` spends inside the box, if any.
+    pub fn interval(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<(Float, Float)> {
+        self.interval_precomp(&RayPrecomp::new(ray), tmin, tmax)
+    }
+
+    /// Like `hit()`, for a ray that's tested against many boxes.
+    #[inline]
+    pub fn hit_precomp(&self, ray: &RayPrecomp, tmin: Float, tmax: Float) -> bool {
+        self.interval_precomp(ray, tmin, tmax).is_some()
+    }
+
+    /// Like `interval()`, for a ray that's tested against many boxes.
+    #[inline]
+    pub fn interval_precomp(&self, ray: &RayPrecomp, tmin: Float, tmax: Float)
+        -> Option<(Float, Float)>
+    {
+        // Rays going "backwards" along an axis meet `max` first there.
+        let [neg_x, neg_y, neg_z] = ray.dir_is_neg;
+        let near = Float3::xyz(if neg_x { self.max.x } else { self.min.x },
+                               if neg_y { self.max.y } else { self.min.y },
+                               if neg_z { self.max.z } else { self.min.z });
+        let far = Float3::xyz(if neg_x { self.min.x } else { self.max.x },
+                              if neg_y { self.min.y } else { self.max.y },
+                              if neg_z { self.min.z } else { self.max.z });
+        let t0 = (near - ray.origin) * ray.inv_dir;
+        let t1 = (far - ray.origin) * ray.inv_dir;
+
+        // The ray is inside the box when it's between *every* pair of slabs,
+        // so we want the latest entry and the earliest exit.
+        // Rays parallel to a pair of slabs enter and leave at -inf and inf
+        // when they're between them, so those slabs don't matter, and the
+        // other way around when they're outside. Rays right along a slab get
+        // a NaN there (0 * inf), which `max()` and `min()` skip over, so
+        // they count as inside.
+        let enter = t0.x.max(t0.y).max(t0.z).max(tmin);
+        let exit  = t1.x.min(t1.y).min(t1.z).min(tmax);
+
+        if enter <= exit {
+            Some((enter, exit))
+        } else {
+            None
+        }
+    }
+
+    /// Which rays of `rays` go through the box, each between its own lane of
+    /// `tmin` and `tmax`. This agrees with `hit_precomp()` on each of them.
+    #[inline]
+    pub fn hit_packet(&self, rays: &RayPacket4, tmin: Lanes, tmax: Lanes) -> [bool; 4] {
+        let mut enter = tmin;
+        let mut exit = tmax;
+        for axis in 0..3 {
+            let (min, max) = (Lanes::splat(self.min[axis]), Lanes::splat(self.max[axis]));
+            let neg = rays.dir_is_neg[axis];
+            let t0 = (Lanes::select(neg, max, min) - rays.origin[axis]) * rays.inv_dir[axis];
+            let t1 = (Lanes::select(neg, min, max) - rays.origin[axis]) * rays.inv_dir[axis];
+            // Lanes' `max()` and `min()` give back their argument when either
+            // is NaN, so this skips NaNs just like `interval_precomp()` does.
+            enter = t0.max(enter);
+            exit = t1.min(exit);
+        }
+
+        let (enter, exit) = (enter.0, exit.0);
+        [0, 1, 2, 3].map(|i| rays.active[i] && enter[i] <= exit[i])
+    }
+}
+
+/// Four bounding boxes, laid out to test a ray against all of them at once,
+/// for BVH nodes with four children. Nodes with fewer can repeat a child.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb4 {
+    // The x, y, and z of each box's corners, a box to a lane.
+    min: [Lanes; 3],
+    max: [Lanes; 3],
+}
+
+impl Aabb4 {
+    pub fn new(boxes: [Aabb; 4]) -> Aabb4 {
+        let mut aabb4 = Aabb4 {
+            min: [Lanes::default(); 3],
+            max: [Lanes::default(); 3],
+        };
+        for (lane, bbox) in boxes.iter().enumerate() {
+            for axis in 0..3 {
+                aabb4.min[axis].0[lane] = bbox.min[axis];
+                aabb4.max[axis].0[lane] = bbox.max[axis];
+            }
+        }
+        aabb4
+    }
+
+    /// Which of the boxes `ray` goes through between `tmin` and `tmax`.
+    /// This agrees with `Aabb::hit()` on each of them.
+    #[inline]
+    pub fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> [bool; 4] {
         let inv_dir: Float3 = 1.0 / ray.dir;
 
-        let mut t0 = (self.min - ray.origin) * inv_dir;
-        let mut t1 = (self.max - ray.origin) * inv_dir;
+        let mut enter = Lanes::splat(tmin);
+        let mut exit = Lanes::splat(tmax);
+        for axis in 0..3 {
+            let origin = Lanes::splat(ray.origin[axis]);
+            let inv_dir = Lanes::splat(inv_dir[axis]);
+            let t0 = (self.min[axis] - origin) * inv_dir;
+            let t1 = (self.max[axis] - origin) * inv_dir;
+            // Rays going "backwards" along this axis meet `max` first.
+            enter = t0.min(t1).max(enter);
+            exit = t0.max(t1).min(exit);
+        }
+
+        let (enter, exit) = (enter.0, exit.0);
+        [enter[0] <= exit[0], enter[1] <= exit[1], enter[2] <= exit[2], enter[3] <= exit[3]]
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::material::DiffuseLight;
+
+    fn light(radius: Float) -> Sphere {
+        Sphere {
+            center:   Float3::new(),
+            radius,
+            material: Arc::new(DiffuseLight { emit: Float3::xxx(1.) }),
+        }
+    }
+
+    /// Points inside, on, just outside, and far from a sphere of radius 2.
+    fn origins() -> Vec<Float3> {
+        vec![
+            Float3::new(),
+            Float3::xyz(0.5, -1., 0.25),
+            Float3::xyz(0., 2., 0.),
+            Float3::xyz(2. * (1. + 1e-9), 0., 0.),
+            Float3::xyz(0., 0., -2.001),
+            Float3::xyz(6., 0., 0.),
+            Float3::xyz(1e4, 2e4, -3e4),
+        ]
+    }
+
+    #[test]
+    fn check_sphere_sampling_has_no_nans() {
+        let mut rng = seeded_rng(0x5eed);
+        let sphere = light(2.);
+        for origin in origins() {
+            for _ in 0..1000 {
+                let dir = sphere.random_toward(&origin, &mut rng);
+                let pdf = sphere.pdf_value(&origin, &dir);
+                assert!(dir.as_slice().iter().all(|c| c.is_finite()),
+                        "{:?} => {:?}", origin, dir);
+                assert!((dir.length() - 1.0).abs() < 1e-6, "{:?} => {:?}", origin, dir);
+                // Whatever we pick, we have to agree that we could have picked it.
+                assert!(pdf.is_finite() && pdf > 0.0, "{:?} => {:?}: {}", origin, dir, pdf);
+            }
+        }
+    }
+
+    #[test]
+    fn check_sphere_pdf_integrates_to_one() {
+        seed_thread_rng(0x5eed);
+        let sphere = light(2.);
+        // The far point sees too little of the sphere to hit it uniformly.
+        for origin in origins().into_iter().take(6) {
+            let n = 100_000;
+            let total: Float = (0..n)
+                .map(|_| sphere.pdf_value(&origin, &random_unit_vector()))
+                .sum();
+            let integral = 4.0 * consts::PI * total / n as Float;
+            assert!((integral - 1.0).abs() < 0.08, "{:?}: {}", origin, integral);
+        }
+    }
+
+    #[test]
+    fn check_sphere_pdf_matches_its_samples() {
+        let mut rng = seeded_rng(0x5eed);
+        let sphere = light(2.);
+        let origin = Float3::xyz(0., 0., 3.);
+        // Directions that miss the sphere have no density.
+        assert_eq!(sphere.pdf_value(&origin, &Float3::xyz(0., 0., 1.)), 0.0);
+        assert_eq!(sphere.pdf_value(&origin, &Float3::xyz(1., 0., 0.)), 0.0);
 
-        // Reverse the interval along the axis when the ray is going "backwards"
-        if inv_dir.x < 0.0 { mem::swap(&mut t0.x, &mut t1.x); }
-        if inv_dir.y < 0.0 { mem::swap(&mut t0.y, &mut t1.y); }
-        if inv_dir.z < 0.0 { mem::swap(&mut t0.z, &mut t1.z); }
+        // And ones that hit it all have the same density.
+        let expected = sphere.pdf_value(&origin, &Float3::xyz(0., 0., -1.));
+        for _ in 0..1000 {
+            let dir = sphere.random_toward(&origin, &mut rng);
+            let ray = Ray { origin, dir, t: 0. };
+            assert!(sphere.hit(&ray, 1e-3, 1e9, &mut rng).is_some(), "{:?}", dir);
+            assert_eq!(sphere.pdf_value(&origin, &dir), expected);
+        }
+    }
 
-        t0 = t0.min(&Float3::xxx(tmin));
-        t1 = t1.max(&Float3::xxx(tmax));
+    #[test]
+    fn check_aabb4_matches_aabb() {
+        seed_thread_rng(0x5eed);
+        let point = || -> Float3 {
+            Float3::xyz(random_float() - 0.5, random_float() - 0.5, random_float() - 0.5) * 10
+        };
+        let bbox = || {
+            let (a, b) = (point(), point());
+            Aabb { min: a.min(&b), max: a.max(&b) }
+        };
+        let mut hits = 0;
+        for i in 0..1000 {
+            let boxes = [bbox(), bbox(), bbox(), bbox()];
+            let aabb4 = Aabb4::new(boxes);
+            let mut dir = point();
+            // Some rays run parallel to an axis, and never cross its slabs.
+            if i % 10 == 0 {
+                dir[i % 3] = 0.0;
+            }
+            let ray = Ray { origin: point(), dir, t: 0. };
+            // And some stop short of boxes that they'd otherwise hit.
+            let (tmin, tmax) = (1e-3, if i % 2 == 0 { FLOAT_MAX } else { random_float() });
+            let expected: Vec<bool> = boxes.iter().map(|b| b.hit(&ray, tmin, tmax)).collect();
+            assert_eq!(aabb4.hit(&ray, tmin, tmax).to_vec(), expected, "{:?} {:?}", boxes, ray);
+            hits += expected.iter().filter(|&&hit| hit).count();
+        }
+        // Both outcomes get tested plenty.
+        assert!(200 < hits && hits < 3800, "{}", hits);
+    }
 
-        (t0 < t1)
+    #[test]
+    fn check_precomputed_box_test_matches() {
+        // Working out 1 / dir for each box, and swapping the slabs after.
+        fn interval(bbox: &Aabb, ray: &Ray, tmin: Float, tmax: Float) -> Option<(Float, Float)> {
+            let inv_dir: Float3 = 1.0 / ray.dir;
+            let mut t0 = (bbox.min - ray.origin) * inv_dir;
+            let mut t1 = (bbox.max - ray.origin) * inv_dir;
+            for axis in 0..3 {
+                if inv_dir[axis] < 0.0 {
+                    std::mem::swap(&mut t0[axis], &mut t1[axis]);
+                }
+            }
+            let enter = t0.x.max(t0.y).max(t0.z).max(tmin);
+            let exit  = t1.x.min(t1.y).min(t1.z).min(tmax);
+            if enter <= exit { Some((enter, exit)) } else { None }
+        }
+
+        seed_thread_rng(0x5eed);
+        let point = || -> Float3 {
+            Float3::xyz(random_float() - 0.5, random_float() - 0.5, random_float() - 0.5) * 10
+        };
+        let mut hits = 0;
+        for i in 0..1000 {
+            let (a, b) = (point(), point());
+            let bbox = Aabb { min: a.min(&b), max: a.max(&b) };
+            let mut dir = point();
+            // Directions that are 0 along an axis, of either sign, give
+            // infinities, and rays that start on a slab there give NaNs.
+            let mut origin = point();
+            if i % 5 == 0 {
+                dir[i % 3] = if i % 10 == 0 { 0.0 } else { -0.0 };
+            }
+            if i % 20 == 0 {
+                origin[i % 3] = bbox.min[i % 3];
+            }
+            let ray = Ray { origin, dir, t: 0. };
+            let (tmin, tmax) = (1e-3, if i % 2 == 0 { FLOAT_MAX } else { random_float() });
+
+            let expected = interval(&bbox, &ray, tmin, tmax);
+            let precomp = RayPrecomp::new(&ray);
+            let got = bbox.interval_precomp(&precomp, tmin, tmax);
+            // Down to the bit, so compare them as bits.
+            let bits = |i: Option<(Float, Float)>| i.map(|(a, b)| (a.to_bits(), b.to_bits()));
+            assert_eq!(bits(got), bits(expected), "{:?} {:?}", bbox, ray);
+            assert_eq!(bbox.hit_precomp(&precomp, tmin, tmax), expected.is_some());
+            hits += expected.is_some() as usize;
+        }
+        // Both outcomes get tested plenty.
+        assert!(50 < hits && hits < 950, "{}", hits);
+    }
+
+    #[test]
+    fn check_big_spheres_dont_hit_themselves() {
+        seed_thread_rng(0x5eed);
+        let mut rng = seeded_rng(0);
+        // Like the ground in the cover scene. Hits on it are a long way from
+        // the origin, where f32 in particular doesn't have many digits left.
+        let ground = Sphere {
+            center:   Float3::xyz(0., -1000., 0.),
+            radius:   1000.,
+            material: Arc::new(DiffuseLight { emit: Float3::xxx(1.) }),
+        };
+        for _ in 0..1000 {
+            let target = Float3::xyz(20. * random_float() - 10., 0., 20. * random_float() - 10.);
+            let from = Ray {
+                origin: Float3::xyz(13., 2., 3.),
+                dir:    target - Float3::xyz(13., 2., 3.),
+                t:      0.,
+            };
+            let hit = ground.hit(&from, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+
+            // Rays leaving the surface shouldn't find it again right away.
+            // That's shadow acne. Grazing ones can, but they're rare.
+            let normal = hit.normal.unit();
+            let dir = (normal + random_unit_vector()).unit();
+            if dir.dot(&normal) < 0.1 {
+                continue;
+            }
+            let bounce = Ray { origin: hit.p, dir, t: 0. };
+            assert!(ground.hit(&bounce, 1e-3, FLOAT_MAX, &mut rng).is_none(), "{:?}", bounce);
+        }
+    }
+
+    #[test]
+    fn check_keyframed_sphere_follows_its_path() {
+        let mut rng = seeded_rng(0);
+        // Right for the first half of the shutter, then up.
+        let sphere = KeyframedSphere {
+            sphere:    light(0.5),
+            keyframes: vec![(0.0, Float3::xyz(0., 0., 0.)),
+                            (0.5, Float3::xyz(2., 0., 0.)),
+                            (1.0, Float3::xyz(2., 2., 0.))],
+        };
+        let expected = [(-1.0, Float3::xyz(0., 0., 0.)),
+                        (0.0, Float3::xyz(0., 0., 0.)),
+                        (0.25, Float3::xyz(1., 0., 0.)),
+                        (0.5, Float3::xyz(2., 0., 0.)),
+                        (0.75, Float3::xyz(2., 1., 0.)),
+                        (1.0, Float3::xyz(2., 2., 0.)),
+                        (2.0, Float3::xyz(2., 2., 0.))];
+        for &(t, center) in expected.iter() {
+            assert_eq!(sphere.center_at(t), center, "t = {}", t);
+
+            // Straight down -z at the center, it hits the front of the sphere.
+            let origin = center + Float3::xyz(0., 0., 10.);
+            let ray = Ray { origin, dir: Float3::xyz(0., 0., -1.), t };
+            let hit = sphere.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+            assert_eq!(hit.p, center + Float3::xyz(0., 0., 0.5), "t = {}", t);
+            assert_eq!(hit.normal, Float3::xyz(0., 0., 1.), "t = {}", t);
+
+            // And one where the sphere was at t = 0 misses, once it's moved on.
+            let ray = Ray { origin: Float3::xyz(0., 0., 10.), dir: Float3::xyz(0., 0., -1.), t };
+            let hit = sphere.hit(&ray, 1e-3, FLOAT_MAX, &mut rng);
+            assert_eq!(hit.is_some(), t <= 0.0, "t = {}", t);
+        }
+
+        let bbox = sphere.bounding_box(0.0, 1.0).unwrap();
+        assert_eq!(bbox.min, Float3::xyz(-0.5, -0.5, -0.5));
+        assert_eq!(bbox.max, Float3::xyz(2.5, 2.5, 0.5));
+        for &(_, center) in sphere.keyframes.iter() {
+            let r = Float3::xxx(0.5);
+            let keyframe = Aabb { min: center - r, max: center + r };
+            assert_eq!(Aabb::surrounding(&bbox, &keyframe), bbox, "{:?}", center);
+        }
+
+        // Only part of the path, which doesn't reach the last keyframe.
+        let bbox = sphere.bounding_box(0.25, 0.75).unwrap();
+        assert_eq!(bbox.min, Float3::xyz(0.5, -0.5, -0.5));
+        assert_eq!(bbox.max, Float3::xyz(2.5, 1.5, 0.5));
+    }
+
+    #[test]
+    fn check_tangent_frames() {
+        use crate::rect::{
+            Cuboid,
+            XyRect,
+        };
+        use crate::transform::{
+            Mat3,
+            Transform,
+        };
+
+        seed_thread_rng(0x7a9);
+        let mut rng = seeded_rng(0);
+        let material: Arc<dyn Material> = Arc::new(DiffuseLight { emit: Float3::xxx(1.) });
+        let square = |k| XyRect { a0: -1., a1: 1., b0: -1., b1: 1., k, material: material.clone() };
+        let hitables: Vec<Box<dyn Hitable>> = vec![
+            Box::new(light(2.)),
+            // Hollow, with normals facing in.
+            Box::new(Sphere { radius: -2., ..light(2.) }),
+            Box::new(square(0.)),
+            Box::new(FlipNormals { hitable: square(0.5) }),
+            Box::new(Cuboid {
+                min:      Float3::xxx(-1.),
+                max:      Float3::xxx(1.),
+                material: material.clone(),
+            }),
+            Box::new(Transform::new(light(2.)).scaled(0.5)
+                                              .rotated(Mat3::from_euler(30., 45., 60.))
+                                              .translated(Float3::xyz(0.1, 0.2, 0.3))),
+        ];
+        for (i, hitable) in hitables.iter().enumerate() {
+            let mut hits = 0;
+            for _ in 0..1000 {
+                let origin = 5. * random_unit_vector();
+                let ray = Ray { origin, dir: 0.5 * random_unit_vector() - origin, t: 0. };
+                let record = match hitable.hit(&ray, 1e-3, FLOAT_MAX, &mut rng) {
+                    Some(record) => record,
+                    None => continue,
+                };
+                hits += 1;
+                // Tangents and normals make an orthonormal frame with their
+                // cross product.
+                let (n, t) = (record.normal, record.tangent);
+                let b = n.cross(&t);
+                assert!((n.length() - 1.).abs() < 1e-5, "{}: {:?}", i, n);
+                assert!((t.length() - 1.).abs() < 1e-5, "{}: {:?}", i, t);
+                assert!((b.length() - 1.).abs() < 1e-5, "{}: {:?}", i, b);
+                assert!(n.dot(&t).abs() < 1e-5, "{}: {:?} and {:?}", i, n, t);
+            }
+            assert!(hits > 100, "{}: {}", i, hits);
+        }
+
+        // On spheres, `u` really does increase along the tangent, away from
+        // the seam where it wraps around.
+        for &radius in [2., -2.].iter() {
+            let sphere = Sphere { radius, ..light(2.) };
+            for _ in 0..1000 {
+                let p = 2. * random_unit_vector();
+                let ray = Ray { origin: Float3::new(), dir: p, t: 0. };
+                let record = match sphere.hit(&ray, 0.5, 1.5, &mut rng) {
+                    Some(record) => record,
+                    None => continue,
+                };
+                let step: Float3 = 1e-3 * record.tangent;
+                let (u, _) = sphere_uv(&((record.p + step - sphere.center) / radius).unit());
+                if (u - record.u).abs() < 0.5 {
+                    assert!(u > record.u, "{}: {} to {} at {:?}", radius, record.u, u, record.p);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,151 @@
+//! Comparing two renders, for `raytracer diff`.
+//!
+//! Raw images from `--raw-output` are compared by their linear radiance, so
+//! differences far too small to change an 8-bit pixel still show up. As soon
+//! as one of the images is a PNG, both are compared the way they'd look: raw
+//! images are gamma corrected and quantized like we'd write them, and every
+//! channel goes from 0 to 1 (255).
+
+use std::path;
+
+use crate::output::LinearImage;
+use crate::prelude::*;
+
+/// How much brighter differences are drawn in `Diff::image`, so that small
+/// ones are easy to see.
+pub const AMPLIFY: Float = 8.0;
+
+/// A render to compare, as we read it.
+pub enum Image {
+    Rgb8(image::RgbImage),
+    Linear(LinearImage),
+}
+
+impl Image {
+    /// Reads a PNG, or a raw image from `--raw-output`.
+    pub fn load(path: &path::Path) -> Result<Image, String> {
+        let ext = path.extension()
+                      .map(|ext| ext.to_string_lossy().to_lowercase())
+                      .unwrap_or_default();
+        if ext == "png" {
+            image::open(path)
+                .map(|img| Image::Rgb8(img.to_rgb()))
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+        } else {
+            LinearImage::load_raw(path).map(Image::Linear)
+        }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Image::Rgb8(img) => img.dimensions(),
+            Image::Linear(img) => (img.width(), img.height()),
+        }
+    }
+
+    /// The image as 8-bit color, with channels from 0 to 1.
+    fn to_rgb8_values(&self) -> LinearImage {
+        let from_rgb8 = |img: &image::RgbImage| {
+            LinearImage::from_fn(img.width(), img.height(), |x, y| {
+                let [r, g, b] = img.get_pixel(x, y).data;
+                Float3::xyz(r as Float, g as Float, b as Float) / 255.0
+            })
+        };
+        match self {
+            Image::Rgb8(img) => from_rgb8(img),
+            Image::Linear(img) => from_rgb8(&img.to_rgb8()),
+        }
+    }
+}
+
+/// How two images differ, over every channel of every pixel.
+#[derive(Clone, Debug)]
+pub struct Diff {
+    /// The biggest difference in any one channel.
+    pub max:   Float,
+    /// The mean absolute error.
+    pub mean:  Float,
+    /// The root mean square error.
+    pub rmse:  Float,
+    /// How far apart each pixel is, times `AMPLIFY`, in 8-bit color.
+    pub image: image::RgbImage,
+}
+
+/// Compares `a` and `b`, which have to be the same size.
+pub fn diff(a: &Image, b: &Image) -> Result<Diff, String> {
+    if a.dimensions() != b.dimensions() {
+        let (aw, ah) = a.dimensions();
+        let (bw, bh) = b.dimensions();
+        return Err(format!("The images are different sizes: {}x{} vs {}x{}", aw, ah, bw, bh));
+    }
+    let (a, b) = match (a, b) {
+        (Image::Linear(a), Image::Linear(b)) => (a.clone(), b.clone()),
+        _ => (a.to_rgb8_values(), b.to_rgb8_values()),
+    };
+
+    let (width, height) = (a.width(), a.height());
+    let (mut max, mut total, mut total_sq): (Float, Float, Float) = (0.0, 0.0, 0.0);
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let d = a.get_pixel(x, y) - b.get_pixel(x, y);
+        let d = Float3::xyz(d.x.abs(), d.y.abs(), d.z.abs());
+        max = max.max(d.x).max(d.y).max(d.z);
+        total += d.x + d.y + d.z;
+        total_sq += d.length_sq();
+        let channel = |c: Float| (c * AMPLIFY * 255.0).min(255.0).round() as u8;
+        image::Rgb([channel(d.x), channel(d.y), channel(d.z)])
+    });
+    let channels = (3 * width as u64 * height as u64).max(1) as Float;
+    Ok(Diff {
+        max,
+        mean: total / channels,
+        rmse: (total_sq / channels).sqrt(),
+        image,
+    })
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn gradient() -> LinearImage {
+        LinearImage::from_fn(8, 4, |x, y| Float3::xyz(x as Float / 8.0, y as Float / 4.0, 0.5))
+    }
+
+    #[test]
+    fn check_same_image_has_no_difference() {
+        let img = Image::Linear(gradient());
+        let diff = diff(&img, &img).unwrap();
+        assert_eq!((diff.max, diff.mean, diff.rmse), (0.0, 0.0, 0.0));
+        assert!(diff.image.pixels().all(|px| px.data == [0, 0, 0]));
+
+        let rgb8 = Image::Rgb8(gradient().to_rgb8());
+        let diff = super::diff(&rgb8, &img).unwrap();
+        assert_eq!((diff.max, diff.mean, diff.rmse), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn check_one_pixel_off() {
+        let mut perturbed = gradient();
+        perturbed.put_pixel(3, 2, gradient().get_pixel(3, 2) + Float3::xyz(0.0, 0.01, 0.0));
+        let diff = diff(&Image::Linear(gradient()), &Image::Linear(perturbed)).unwrap();
+
+        let channels = (3 * 8 * 4) as Float;
+        assert!((diff.max - 0.01).abs() < 1e-6, "{:?}", diff.max);
+        assert!((diff.mean - 0.01 / channels).abs() < 1e-6, "{:?}", diff.mean);
+        assert!((diff.rmse - 0.01 / channels.sqrt()).abs() < 1e-6, "{:?}", diff.rmse);
+        for (x, y, px) in diff.image.enumerate_pixels() {
+            if (x, y) == (3, 2) {
+                assert_eq!(px.data, [0, 20, 0]);
+            } else {
+                assert_eq!(px.data, [0, 0, 0], "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn check_sizes_have_to_match() {
+        let small = Image::Linear(LinearImage::from_fn(4, 4, |_, _| Float3::new()));
+        let msg = diff(&Image::Linear(gradient()), &small).err().unwrap();
+        assert_eq!(msg, "The images are different sizes: 8x4 vs 4x4");
+    }
+}
@@ -0,0 +1,53 @@
+//! A path tracer, following "Ray Tracing in One Weekend" and the books after it.
+//!
+//! Build a `render::Scene` out of `hitable`s and `material`s, point a
+//! `camera::Camera` at it, and call `render::render()` for the image, or
+//! render it in `tiles` to hear about each pixel as it's finished.
+//! The `raytracer` binary is a command line around this, which adds progress
+//! bars, checkpoints, and a window to watch the render in.
+
+#![allow(dead_code)]
+
+pub mod aov;
+pub mod assets;
+pub mod axes;
+pub mod build_info;
+pub mod bvh;
+pub mod camera;
+pub mod camera_path;
+pub mod checkpoint;
+pub mod csg;
+pub mod denoise;
+pub mod despeckle;
+pub mod determinism;
+pub mod float3;
+pub mod filter;
+pub mod fly_camera;
+pub mod focus_stack;
+pub mod ftz;
+pub mod hitable;
+pub mod image_diff;
+pub mod lpe;
+pub mod lut;
+pub mod material;
+pub mod math;
+pub mod microfacet;
+pub mod output;
+pub mod packet;
+pub mod profile;
+pub mod progressive;
+pub mod ray;
+pub mod rect;
+pub mod render;
+pub mod scene_file;
+pub mod scenes;
+pub mod simd;
+pub mod sphere_set;
+pub mod stats;
+pub mod texture;
+pub mod tile_order;
+pub mod tiles;
+pub mod transform;
+pub mod volume;
+
+pub mod prelude;
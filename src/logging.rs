@@ -0,0 +1,60 @@
+//! Where our messages go.
+//!
+//! Everything we have to say goes through `log`, to stderr. Errors and
+//! warnings start with "error: " and "warning: ", and everything else is
+//! printed as is. `--quiet` leaves only the errors, and `--verbose` adds debug
+//! messages, like counts of the rays traced.
+
+use log::{
+    Level,
+    LevelFilter,
+    Log,
+    Metadata,
+    Record,
+};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Only our own messages, and not those of our dependencies.
+        metadata.level() <= log::max_level() && metadata.target().starts_with("one_weekend")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let prefix = match record.level() {
+            Level::Error => "error: ",
+            Level::Warn => "warning: ",
+            _ => "",
+        };
+        // Blank lines to get out from under a progress bar go before it.
+        let message = record.args().to_string();
+        let body = message.trim_start_matches('\n');
+        eprintln!("{}{}{}", &message[..message.len() - body.len()], prefix, body);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// How much to say, for `--quiet` and `--verbose`.
+pub fn level(quiet: bool, verbose: bool) -> LevelFilter {
+    if quiet {
+        LevelFilter::Error
+    } else if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/// Sends messages up to `level` to stderr. Only the first call sets where
+/// they go, but every call sets the level.
+pub fn init(level: LevelFilter) {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -0,0 +1,266 @@
+//! Light path expressions, just enough of them to split an image into passes.
+//!
+//! Every path from the camera is a sequence of events:
+//!     C   The camera, where every path starts
+//!     D   A diffuse bounce (anything the integrator can sample lights from)
+//!     S   A specular bounce (mirrors, glass)
+//!     L   Light entering the path: an emitter, the background, or anything
+//!         else that ends the path with a color
+//! Each time light is added to a path, the events so far (ending in L) are
+//! checked against a pattern to decide which pass the light belongs to.
+//!
+//! Patterns are written like "C D .+ L". Each term is one of C, D, S, L, or
+//! `.` (any event), optionally followed by `?`, `*`, or `+`.
+//! Patterns must match the entire sequence.
+
+use std::fmt;
+
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PathEvent {
+    Camera,
+    Diffuse,
+    Specular,
+    Light,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Atom {
+    Event(PathEvent),
+    Any,
+}
+
+impl Atom {
+    fn matches(self, event: PathEvent) -> bool {
+        match self {
+            Atom::Event(e) => e == event,
+            Atom::Any => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Repeat {
+    One,
+    Optional,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Term {
+    atom:   Atom,
+    repeat: Repeat,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    source: String,
+    terms:  Vec<Term>,
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Pattern, String> {
+        let mut terms: Vec<Term> = vec![];
+
+        for (i, c) in source.chars().enumerate() {
+            let atom = match c {
+                'C' => Atom::Event(PathEvent::Camera),
+                'D' => Atom::Event(PathEvent::Diffuse),
+                'S' => Atom::Event(PathEvent::Specular),
+                'L' => Atom::Event(PathEvent::Light),
+                '.' => Atom::Any,
+                '?' | '*' | '+' => {
+                    let repeat = match c {
+                        '?' => Repeat::Optional,
+                        '*' => Repeat::ZeroOrMore,
+                        _   => Repeat::OneOrMore,
+                    };
+                    let last = terms.last_mut()
+                                    .filter(|term| term.repeat == Repeat::One);
+                    match last {
+                        Some(term) => term.repeat = repeat,
+                        None => {
+                            return Err(format!("\"{}\": '{}' at {} has nothing to repeat",
+                                               source, c, i));
+                        },
+                    }
+                    continue;
+                },
+                c if c.is_whitespace() => continue,
+                _ => {
+                    return Err(format!("\"{}\": unexpected '{}' at {}",
+                                       source, c, i));
+                },
+            };
+            terms.push(Term { atom, repeat: Repeat::One });
+        }
+
+        if terms.is_empty() {
+            return Err("Empty light path expression".into());
+        }
+
+        Ok(Pattern {
+            source: source.into(),
+            terms,
+        })
+    }
+
+    pub fn matches(&self, events: &[PathEvent]) -> bool {
+        matches_terms(&self.terms, events)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// A plain backtracking matcher. Our patterns and paths are both short.
+fn matches_terms(terms: &[Term], events: &[PathEvent]) -> bool {
+    let (term, rest) = match terms.split_first() {
+        Some(split) => split,
+        None => return events.is_empty(),
+    };
+
+    // How many events could this term consume?
+    let mut max_run = 0;
+    while max_run < events.len() && term.atom.matches(events[max_run]) {
+        max_run += 1;
+    }
+    let (min, max) = match term.repeat {
+        Repeat::One        => (1, 1),
+        Repeat::Optional   => (0, 1),
+        Repeat::ZeroOrMore => (0, max_run),
+        Repeat::OneOrMore  => (1, max_run),
+    };
+    let max = max.min(max_run);
+
+    // Prefer consuming more, like most regex engines.
+    (min..=max).rev().any(|n| matches_terms(rest, &events[n..]))
+}
+
+/// Built-in passes. Together they cover every path exactly once, so they
+/// always sum to the full image.
+pub const DIRECT:   &str = "C D? L";
+pub const INDIRECT: &str = "C D .+ L";
+pub const SPECULAR: &str = "C S .* L";
+
+/// Tracks the events along one path at a time, and sorts the light added to
+/// it into passes.
+#[derive(Clone, Debug)]
+pub struct LightPaths {
+    patterns: Vec<Pattern>,
+    events:   Vec<PathEvent>,
+    /// Light gathered into each pass, in the same order as the patterns.
+    pub totals: Vec<Float3>,
+}
+
+impl LightPaths {
+    pub fn new(patterns: Vec<Pattern>) -> LightPaths {
+        let totals = vec![Float3::new(); patterns.len()];
+        LightPaths {
+            patterns,
+            events: vec![],
+            totals,
+        }
+    }
+
+    /// Start a new path. This clears `totals`.
+    pub fn begin(&mut self) {
+        self.events.clear();
+        self.events.push(PathEvent::Camera);
+        for total in self.totals.iter_mut() {
+            *total = Float3::new();
+        }
+    }
+
+    /// Record a bounce along the current path.
+    pub fn push(&mut self, event: PathEvent) {
+        self.events.push(event);
+    }
+
+    /// Add `light` to every pass that matches the path so far, followed by
+    /// `extra` (if any), and then `L`.
+    pub fn contribute(&mut self, extra: Option<PathEvent>, light: Float3) {
+        let len = self.events.len();
+        self.events.extend(extra);
+        self.events.push(PathEvent::Light);
+
+        for (pattern, total) in self.patterns.iter().zip(self.totals.iter_mut()) {
+            if pattern.matches(&self.events) {
+                *total += light;
+            }
+        }
+
+        self.events.truncate(len);
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use crate::lpe::*;
+    use crate::lpe::PathEvent::*;
+
+    #[test]
+    fn check_parse() {
+        assert!(Pattern::parse("C D L").is_ok());
+        assert!(Pattern::parse("CDL").is_ok());
+        assert!(Pattern::parse("C .* L").is_ok());
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::parse("C X L").is_err());
+        assert!(Pattern::parse("* C").is_err());
+        assert!(Pattern::parse("C D+* L").is_err());
+    }
+
+    #[test]
+    fn check_matches() {
+        let direct = Pattern::parse(DIRECT).unwrap();
+        let indirect = Pattern::parse(INDIRECT).unwrap();
+        let specular = Pattern::parse(SPECULAR).unwrap();
+        let strict_indirect = Pattern::parse("C D D+ L").unwrap();
+
+        assert!(direct.matches(&[Camera, Light]));
+        assert!(direct.matches(&[Camera, Diffuse, Light]));
+        assert!(!direct.matches(&[Camera, Diffuse, Diffuse, Light]));
+        assert!(!direct.matches(&[Camera, Specular, Light]));
+
+        assert!(indirect.matches(&[Camera, Diffuse, Diffuse, Light]));
+        assert!(indirect.matches(&[Camera, Diffuse, Specular, Light]));
+        assert!(!indirect.matches(&[Camera, Diffuse, Light]));
+
+        assert!(specular.matches(&[Camera, Specular, Light]));
+        assert!(specular.matches(&[Camera, Specular, Diffuse, Specular, Light]));
+        assert!(!specular.matches(&[Camera, Diffuse, Specular, Light]));
+
+        assert!(strict_indirect.matches(&[Camera, Diffuse, Diffuse, Diffuse, Light]));
+        assert!(!strict_indirect.matches(&[Camera, Diffuse, Specular, Light]));
+
+        // Patterns must match the whole path.
+        assert!(!direct.matches(&[Camera, Diffuse]));
+        assert!(!direct.matches(&[Diffuse, Light]));
+    }
+
+    #[test]
+    fn check_builtins_partition_paths() {
+        let builtins: Vec<Pattern> = [DIRECT, INDIRECT, SPECULAR]
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect();
+
+        // Every path up to 4 bounces should match exactly one built-in.
+        let kinds = [Diffuse, Specular];
+        for len in 0..=4 {
+            for bits in 0..(1 << len) {
+                let mut path = vec![Camera];
+                path.extend((0..len).map(|i| kinds[(bits >> i) & 1]));
+                path.push(Light);
+
+                let count = builtins.iter().filter(|p| p.matches(&path)).count();
+                assert_eq!(count, 1, "{:?}", path);
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Lookup tables for the look of the final image, like film emulation.
+//!
+//! LUTs apply to display values: after gamma correction and clipping to
+//! [0, 1], but before we quantize them. There are two kinds:
+//!
+//! - `.cube` files with a 3D table (LUT_3D_SIZE), sampled trilinearly.
+//! - Anything else is read as a 1D curve: one "input output" pair per line,
+//!   inputs increasing, interpolated linearly and applied to each channel.
+//!
+//! In both, `#` starts a comment.
+
+use std::{
+    fs,
+    path,
+};
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lut {
+    /// (input, output) pairs, sorted by input.
+    Curve(Vec<(Float, Float)>),
+    Cube {
+        size:       usize,
+        domain_min: Float3,
+        domain_max: Float3,
+        // Red changes fastest, then green, then blue.
+        table:      Vec<Float3>,
+    },
+}
+
+impl Lut {
+    /// Reads the LUT at `path`. `.cube` files are 3D, everything else is a curve.
+    pub fn load(path: &path::Path) -> Result<Lut, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read LUT {}: {}", path.display(), e))?;
+        let is_cube = path.extension()
+                          .map_or(false, |ext| ext.to_string_lossy().to_lowercase() == "cube");
+        let lut = if is_cube {
+            Lut::parse_cube(&text)
+        } else {
+            Lut::parse_curve(&text)
+        };
+        lut.map_err(|msg| format!("{}:{}", path.display(), msg))
+    }
+
+    /// Parses a 1D curve. Errors start with the line number they're about.
+    pub fn parse_curve(text: &str) -> Result<Lut, String> {
+        let mut points: Vec<(Float, Float)> = vec![];
+        for (line_no, words) in lines(text) {
+            let values = parse_floats(line_no, &words, 2)?;
+            let point = (values[0], values[1]);
+            if let Some(&(last, _)) = points.last() {
+                if point.0 <= last {
+                    return Err(format!("{}: inputs must increase, but {} follows {}",
+                                       line_no, point.0, last));
+                }
+            }
+            points.push(point);
+        }
+        if points.len() < 2 {
+            return Err("1: a curve needs at least two points".into());
+        }
+        Ok(Lut::Curve(points))
+    }
+
+    /// Parses a `.cube` file. Errors start with the line number they're about.
+    pub fn parse_cube(text: &str) -> Result<Lut, String> {
+        let mut size = None;
+        let mut domain_min = Float3::xxx(0.0);
+        let mut domain_max = Float3::xxx(1.0);
+        let mut table = vec![];
+        let mut last_line = 0;
+
+        for (line_no, words) in lines(text) {
+            last_line = line_no;
+            match words[0] {
+                // Titles are quoted, and can have spaces. We don't need them.
+                "TITLE" => {},
+                "LUT_3D_SIZE" => {
+                    if words.len() != 2 {
+                        return Err(format!("{}: expected one number after LUT_3D_SIZE",
+                                           line_no));
+                    }
+                    let n: usize = words[1].parse().map_err(|_| {
+                        format!("{}: \"{}\" is not a LUT size", line_no, words[1])
+                    })?;
+                    if n < 2 || n > 256 {
+                        return Err(format!("{}: LUT_3D_SIZE must be from 2 to 256, not {}",
+                                           line_no, n));
+                    }
+                    size = Some(n);
+                },
+                "LUT_1D_SIZE" => {
+                    return Err(format!("{}: 1D .cube files aren't supported. \
+                                        Use a curve file instead", line_no));
+                },
+                "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                    let values = parse_floats(line_no, &words[1..], 3)?;
+                    let domain = Float3::xyz(values[0], values[1], values[2]);
+                    if words[0] == "DOMAIN_MIN" {
+                        domain_min = domain;
+                    } else {
+                        domain_max = domain;
+                    }
+                },
+                _ => {
+                    if size.is_none() {
+                        return Err(format!("{}: LUT data before LUT_3D_SIZE", line_no));
+                    }
+                    let values = parse_floats(line_no, &words, 3)?;
+                    table.push(Float3::xyz(values[0], values[1], values[2]));
+                },
+            }
+        }
+
+        let size = size.ok_or_else(|| format!("{}: missing LUT_3D_SIZE", last_line))?;
+        if table.len() != size * size * size {
+            return Err(format!("{}: expected {} entries for LUT_3D_SIZE {}, found {}",
+                               last_line, size * size * size, size, table.len()));
+        }
+        for i in 0..3 {
+            if domain_min[i] >= domain_max[i] {
+                return Err(format!("{}: DOMAIN_MIN must be below DOMAIN_MAX", last_line));
+            }
+        }
+
+        Ok(Lut::Cube {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    /// Looks up a display color, with each channel in [0, 1].
+    pub fn apply(&self, rgb: Float3) -> Float3 {
+        match self {
+            Lut::Curve(points) => {
+                Float3::xyz(curve(points, rgb.x), curve(points, rgb.y), curve(points, rgb.z))
+            },
+            Lut::Cube { size, domain_min, domain_max, table } => {
+                // Where `rgb` lands on the lattice, per channel.
+                let n = *size;
+                let mut cell = [0_usize; 3];
+                let mut frac = [0.0; 3];
+                for i in 0..3 {
+                    let (min, max) = (domain_min[i], domain_max[i]);
+                    let t = ((rgb[i] - min) / (max - min)).max(0.0).min(1.0);
+                    let t = t * (n - 1) as Float;
+                    cell[i] = (t.floor() as usize).min(n - 2);
+                    frac[i] = t - cell[i] as Float;
+                }
+
+                let at = |r: usize, g: usize, b: usize| table[r + n * (g + n * b)];
+                let [r, g, b] = cell;
+                let lerp_r = |g: usize, b: usize| Float3::lerp(frac[0], at(r, g, b), at(r + 1, g, b));
+                let lerp_g = |b: usize| Float3::lerp(frac[1], lerp_r(g, b), lerp_r(g + 1, b));
+                Float3::lerp(frac[2], lerp_g(b), lerp_g(b + 1))
+            },
+        }
+    }
+}
+
+/// Non-empty lines of `text` split into words, with comments removed, along
+/// with their line numbers.
+pub fn lines(text: &str) -> impl Iterator<Item=(usize, Vec<&str>)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line.split('#').next().unwrap_or("");
+            (i + 1, line.split_whitespace().collect::<Vec<_>>())
+        })
+        .filter(|(_, words)| !words.is_empty())
+}
+
+pub fn parse_floats(line_no: usize, words: &[&str], count: usize) -> Result<Vec<Float>, String> {
+    if words.len() != count {
+        return Err(format!("{}: expected {} numbers, found \"{}\"",
+                           line_no, count, words.join(" ")));
+    }
+    words.iter()
+         .map(|word| {
+             word.parse::<Float>()
+                 .map_err(|_| format!("{}: \"{}\" is not a number", line_no, word))
+         })
+         .collect()
+}
+
+/// Piecewise linear interpolation through `points`, flat past either end.
+fn curve(points: &[(Float, Float)], x: Float) -> Float {
+    let first = points[0];
+    let last = points[points.len() - 1];
+    if x <= first.0 {
+        return first.1;
+    }
+    if x >= last.0 {
+        return last.1;
+    }
+    let i = points.iter().position(|&(input, _)| input > x).unwrap();
+    let (x0, y0) = points[i - 1];
+    let (x1, y1) = points[i];
+    y0 + (x - x0) / (x1 - x0) * (y1 - y0)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    const IDENTITY: &str = include_str!("../luts/identity.cube");
+    const FILMIC: &str = include_str!("../luts/filmic.cube");
+
+    fn test_colors() -> Vec<Float3> {
+        let mut colors = vec![];
+        for i in 0..=10 {
+            for j in 0..=10 {
+                colors.push(Float3::xyz(i as Float / 10., j as Float / 10., 0.37 * (i + j) as Float / 20.));
+            }
+        }
+        colors
+    }
+
+    #[test]
+    fn check_identity_cube() {
+        let lut = Lut::parse_cube(IDENTITY).unwrap();
+        for rgb in test_colors() {
+            assert!((lut.apply(rgb) - rgb).length() < 1e-6, "{:?} => {:?}", rgb, lut.apply(rgb));
+        }
+    }
+
+    #[test]
+    fn check_simple_cube() {
+        // Swaps red and blue, and halves green.
+        let lut = Lut::parse_cube("
+            TITLE \"swap\"
+            # comments are fine
+            LUT_3D_SIZE 2
+            0 0 0
+            0 0 1
+            0 0.5 0
+            0 0.5 1
+            1 0 0
+            1 0 1
+            1 0.5 0
+            1 0.5 1
+        ").unwrap();
+        assert_eq!(lut.apply(Float3::xyz(1., 0., 0.)), Float3::xyz(0., 0., 1.));
+        assert_eq!(lut.apply(Float3::xyz(0., 1., 0.)), Float3::xyz(0., 0.5, 0.));
+        assert_eq!(lut.apply(Float3::xyz(0.25, 0.5, 0.75)), Float3::xyz(0.75, 0.25, 0.25));
+        // Out of range values are clamped to the domain.
+        assert_eq!(lut.apply(Float3::xyz(2., -1., 0.)), Float3::xyz(0., 0., 1.));
+
+        // The domain can be moved around.
+        let lut = Lut::parse_cube("
+            LUT_3D_SIZE 2
+            DOMAIN_MIN 0 0 0
+            DOMAIN_MAX 2 2 2
+            0 0 0
+            1 0 0
+            0 1 0
+            1 1 0
+            0 0 1
+            1 0 1
+            0 1 1
+            1 1 1
+        ").unwrap();
+        assert_eq!(lut.apply(Float3::xyz(1., 0.5, 0.)), Float3::xyz(0.5, 0.25, 0.));
+    }
+
+    #[test]
+    fn check_curve() {
+        let lut = Lut::parse_curve("
+            0.0 0.0
+            0.5 0.25   # darker mids
+            1.0 1.0
+        ").unwrap();
+        assert_eq!(lut.apply(Float3::xyz(0.25, 0.5, 0.75)), Float3::xyz(0.125, 0.25, 0.625));
+        assert_eq!(lut.apply(Float3::xyz(-1., 0., 2.)), Float3::xyz(0., 0., 1.));
+    }
+
+    #[test]
+    fn check_filmic_cube() {
+        let lut = Lut::parse_cube(FILMIC).unwrap();
+        // Black and white stay put, but the mids change.
+        assert!(lut.apply(Float3::xxx(0.0)).length() < 1e-3);
+        assert!((lut.apply(Float3::xxx(1.0)) - Float3::xxx(1.0)).length() < 1e-3);
+        let mid = lut.apply(Float3::xxx(0.25));
+        assert!((mid - Float3::xxx(0.25)).length() > 0.02, "{:?}", mid);
+    }
+
+    #[test]
+    fn check_errors_have_positions() {
+        let err = |result: Result<Lut, String>| result.unwrap_err();
+
+        assert_eq!(err(Lut::parse_cube("LUT_3D_SIZE 2\n0 0 0\n0 zero 0\n")),
+                   "3: \"zero\" is not a number");
+        assert_eq!(err(Lut::parse_cube("TITLE \"x\"\n\n1 2 3\n")),
+                   "3: LUT data before LUT_3D_SIZE");
+        assert_eq!(err(Lut::parse_cube("LUT_3D_SIZE 2\n0 0 0\n1 1\n")),
+                   "3: expected 3 numbers, found \"1 1\"");
+        assert_eq!(err(Lut::parse_cube("LUT_3D_SIZE 2\n0 0 0\n1 1 1\n")),
+                   "3: expected 8 entries for LUT_3D_SIZE 2, found 2");
+        assert_eq!(err(Lut::parse_cube("LUT_3D_SIZE 1\n")),
+                   "1: LUT_3D_SIZE must be from 2 to 256, not 1");
+        assert_eq!(err(Lut::parse_cube("# nothing here\nTITLE \"x\"\n")),
+                   "2: missing LUT_3D_SIZE");
+        assert!(err(Lut::parse_cube("LUT_1D_SIZE 16\n")).starts_with("1: "));
+
+        assert_eq!(err(Lut::parse_curve("0 0\n0.5 0.2\n0.4 0.3\n")),
+                   "3: inputs must increase, but 0.4 follows 0.5");
+        assert_eq!(err(Lut::parse_curve("0 0\n")),
+                   "1: a curve needs at least two points");
+    }
+}
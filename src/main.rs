@@ -1,51 +1,131 @@
 #![allow(dead_code)]
 
 use std::{
-    collections::hash_map,
-    hash::{
-        self,
-        Hasher,
-    },
-    mem,
     path,
-    sync::Arc,
-    sync::atomic,
+    sync::{
+        atomic,
+        Arc,
+        Mutex,
+    },
     time,
 };
 
 use ctrlc;
-use image::{
-    GenericImage,
+use log::{
+    debug,
+    error,
+    info,
+    warn,
 };
 use pbr;
+#[cfg(feature = "window")]
 use sdl2;
 
-use rand::prelude::*;
-use rayon::prelude::*;
 use structopt::*;
 
-mod camera;
-mod float3;
-mod hitable;
-mod material;
-mod math;
-mod ray;
-
-pub mod prelude;
-
-use self::prelude::*;
-use self::hitable::*;
-use self::material::*;
-use self::camera::*;
-
-const MAX_RAY_RECURSION: u32 = 50;
+mod control;
+mod logging;
+mod progress;
+mod shutdown;
+mod snapshot;
+mod watch;
+
+use one_weekend::{
+    aov,
+    assets,
+    build_info,
+    bvh,
+    camera_path,
+    checkpoint,
+    denoise,
+    despeckle,
+    determinism,
+    filter,
+    fly_camera,
+    focus_stack,
+    ftz,
+    image_diff,
+    lut,
+    output,
+    profile,
+    progressive,
+    scene_file,
+    stats,
+    tile_order,
+};
+use one_weekend::prelude::*;
+use self::progress::ProgressStyle;
+use one_weekend::aov::{
+    Aov,
+    PixelAovs,
+};
+use one_weekend::hitable::*;
+use one_weekend::output::LinearImage;
+use one_weekend::camera::*;
+use one_weekend::camera_path::CameraPath;
+use one_weekend::checkpoint::Accumulator;
+use one_weekend::render::{
+    needs_to_exit,
+    needs_to_stop,
+    Background,
+    DebugView,
+    Frame,
+    RenderSettings,
+    Scene,
+    NEED_TO_EXIT,
+    NEGLIGIBLE_PATHS,
+    OUT_OF_TIME,
+};
+use one_weekend::scenes::{
+    packed_hitables,
+    CoverSceneParams,
+    RandomSceneParams,
+    SceneKind,
+    SceneSeed,
+};
+use one_weekend::tiles::{
+    tile_time_map,
+    TileProgress,
+    TiledRender,
+    TilingPlan,
+};
 
-#[derive(Debug, StructOpt)]
+/// The whole command line. With no subcommand, we render, like `render`.
+#[derive(Clone, Debug, StructOpt)]
 #[structopt(name="raytracer",
             about="Traces rays",
             raw(
-                setting="clap::AppSettings::DeriveDisplayOrder",
-                setting="clap::AppSettings::DisableVersion"))]
+                version="build_info::VERSION_LINE",
+                global_setting="clap::AppSettings::DeriveDisplayOrder",
+                global_setting="clap::AppSettings::VersionlessSubcommands"))]
+struct Cli {
+    // ===== Shared by every subcommand ==========
+
+    /// Number of threads used in thread pool.
+    /// 0 uses system default
+    #[structopt(default_value="0", short, long, raw(global="true"))]
+    jobs: u8, // Like we're going to run on 256-thread machines.
+
+    /// Enable more detailed output, including counts of the rays traced
+    #[structopt(short, long, raw(global="true"))]
+    verbose: bool,
+
+    /// Only print errors: no progress bars, and nothing about how the render
+    /// is going or which files it wrote
+    #[structopt(short, long, raw(global="true", conflicts_with="\"verbose\""))]
+    quiet: bool,
+
+    #[structopt(flatten)]
+    render: Opt,
+
+    // ===== Subcommands ==========
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+/// Everything about a render.
+#[derive(Clone, Debug, StructOpt)]
 struct Opt {
     // ===== Options ==========
 
@@ -53,38 +133,97 @@ struct Opt {
     #[structopt(default_value="1200", short, long)]
     width: u32,
 
-    /// Height of image in pixels
-    #[structopt(default_value="800", short, long)]
-    height: u32,
+    /// Height of image in pixels. Defaults to 800, or to --width over
+    /// --aspect
+    #[structopt(short, long)]
+    height: Option<u32>,
+
+    /// Width over height, like "16:9" or "1.5", for working out --height
+    #[structopt(long, parse(try_from_str="parse_aspect"))]
+    aspect: Option<Float>,
 
     /// Number of rays cast per pixel
     #[structopt(default_value="10", short, long="samples")]
     samples_per_pixel: u32,
 
-    /// Number of tiles to subdivide the image into
-    // TODO: Pick this automatically and default to "0"
-    #[structopt(default_value="16", short, long)]
-    tiles: u32,
-
-    /// Number of threads used in thread pool.
-    /// 0 uses system default
+    /// Number of tiles to subdivide the image into.
+    /// 0 picks a number based on the thread count and image size
     #[structopt(default_value="0", short, long)]
-    jobs: u8, // Like we're going to run on 256-thread machines.
+    tiles: u32,
 
-    /// File to write image data into
-    // It will be created if it does not exist, and overwriten if it does
-    // Note that this extension is how `image` determines encoding
-    #[structopt(default_value="output.png", parse(from_os_str), short, long)]
-    output: path::PathBuf,
+    /// How to show progress: total (one bar for the whole image, with an
+    /// ETA) or per-tile
+    #[structopt(default_value="total", long)]
+    progress: ProgressStyle,
+
+    /// Seconds between lines about progress when stderr isn't a terminal,
+    /// like in a log file or CI, where progress bars don't work
+    #[structopt(default_value="10", long="progress-interval")]
+    progress_interval: u64,
+
+    /// Order to start rendering tiles in: scanline, spiral (from the center
+    /// out), random, or hilbert
+    #[structopt(default_value="scanline", long="tile-order")]
+    tile_order: tile_order::TileOrder,
+
+    /// How samples are filtered into pixels: box (each pixel averages the
+    /// samples inside of it), tent, or gaussian. Tent and gaussian reach
+    /// into the neighboring pixels, which smooths out edges
+    #[structopt(default_value="box", long)]
+    filter: filter::FilterKind,
+
+    /// Files to write image data into. May be given more than once.
+    /// The extension picks the format: png, ppm, or pfm (floating point)
+    // They will be created if they do not exist, and overwriten if they do.
+    // Extra images (AOVs, masks, ...) are named after the first one.
+    #[structopt(default_value="output.png",
+                parse(from_os_str),
+                short,
+                long,
+                raw(number_of_values="1", use_delimiter="true"))]
+    output: Vec<path::PathBuf>,
+
+    /// Where the camera sits, as "x,y,z"
+    #[structopt(default_value="13,2,3", long)]
+    lookfrom: Float3,
+
+    /// What the camera looks at, as "x,y,z"
+    #[structopt(default_value="0,0,0", long)]
+    lookat: Float3,
 
     /// Vertical field of view
     #[structopt(default_value="20.0", long)]
     vfov: Float,
 
+    /// How the camera sees the scene: perspective, ortho, where every ray goes
+    /// the same way, for diagrams, equirect, a 360 degree panorama for VR
+    /// viewers, which should be twice as wide as it is tall, or fisheye, which
+    /// sees --fov degrees across a circle in the middle. Ortho and equirect
+    /// ignore --vfov and --aperature
+    #[structopt(default_value="perspective", long)]
+    projection: ProjectionKind,
+
+    /// How much of the scene an ortho camera sees, top to bottom
+    #[structopt(default_value="4.0", long="ortho-height")]
+    ortho_height: Float,
+
+    /// How much of the scene a fisheye camera sees, across its image circle
+    #[structopt(default_value="180.0", long)]
+    fov: Float,
+
     /// Camera aperature
     #[structopt(default_value="0.1", short, long)]
     aperature: Float,
 
+    /// How many sides the aperture has, which out of focus highlights take
+    /// the shape of. 0 is a circle
+    #[structopt(default_value="0", long="aperture-blades")]
+    aperture_blades: u32,
+
+    /// How far to turn a --aperture-blades polygon, in degrees counterclockwise
+    #[structopt(default_value="0.0", long="aperture-rotation")]
+    aperture_rotation: Float,
+
     /// Camera focus point
     #[structopt(default_value="10.0", short, long)]
     focus_dist: Float,
@@ -97,140 +236,1770 @@ struct Opt {
     #[structopt(default_value="0.5", long="t-end")]
     t_end: Float,
 
-    /// Select a scene to render.
-    /// NOT IMPLEMENTED
+    /// Render an animation of this many frames across --t-start to --t-end,
+    /// each written to a numbered copy of the outputs, like output_0001.png.
+    /// Ctrl+C stops once the frame in progress is written
+    #[structopt(long="frames")]
+    frames: Option<u32>,
+
+    /// Render an animation of this many frames, with the camera orbiting once
+    /// around --lookat. Written out like --frames
+    #[structopt(long="turntable")]
+    turntable: Option<u32>,
+
+    /// Move the camera through the keyframes in this file during --frames.
+    /// Each line is "time lookfrom lookat vfov", like "0.5 13 2 3 0 0 0 20"
+    #[structopt(long="camera-path")]
+    camera_path: Option<path::PathBuf>,
+
+    /// Render this many images with their focus swept across --focus-range,
+    /// and merge them into one that is in focus everywhere
+    #[structopt(long="focus-stack")]
+    focus_stack: Option<u32>,
+
+    /// Range of focus distances to sweep with --focus-stack, as "first,last".
+    /// Signed ends, like "-2,+3", are offsets from --focus-dist
+    #[structopt(long="focus-range",
+                parse(try_from_str="focus_stack::parse_focus_range"))]
+    focus_range: Option<(focus_stack::FocusEnd, focus_stack::FocusEnd)>,
+
+    /// Also write the image each time it reaches one of these sample counts,
+    /// as a comma separated list like "1,4,16,64". Each one is written next
+    /// to the outputs, like output.spp0016.png, and looks exactly like a
+    /// render with that many samples per pixel would
+    #[structopt(long="spp-ladder", raw(use_delimiter="true"))]
+    spp_ladder: Vec<u32>,
+
+    /// Stop adding samples after this many seconds, however far short of
+    /// --samples that leaves us. Samples are taken over the whole image a few
+    /// at a time, so every pixel ends up with about as many. Pair it with a
+    /// big --samples
+    #[structopt(long="max-seconds")]
+    max_seconds: Option<u64>,
+
+    /// Extra images to write next to the output, as a comma separated list.
+    /// Light passes: direct, indirect, specular.
+    /// Surfaces: normal, depth, albedo.
+    /// Debugging: glass-debug
+    #[structopt(long="aov", raw(use_delimiter="true"))]
+    aov: Vec<Aov>,
+
+    /// Only trace every this many samples of a pixel for --aov. Surface AOVs
+    /// also stop after their first 16 samples. The image itself is unchanged
+    #[structopt(default_value="1", long="aov-stride")]
+    aov_stride: u32,
+
+    /// Write a heatmap of how many times paths bounced to this file, from cold
+    /// where they bounced the least to hot at --max-depth. This is the
+    /// bounces AOV, written where you'd like
+    #[structopt(long, parse(from_os_str))]
+    heatmap: Option<path::PathBuf>,
+
+    /// Write how noisy each pixel is to this file, as the standard deviation
+    /// of its samples' brightness: black where they all agreed, up to white
+    /// for the noisiest pixel
+    #[structopt(long="variance-map", parse(from_os_str))]
+    variance_map: Option<path::PathBuf>,
+
+    /// Also write the linear radiance of every pixel to this file, before
+    /// --despeckle, --denoise, and gamma, with every bit we rendered it with.
+    /// A .pfm is written as PFM, and anything else, like a .bin, in a raw
+    /// layout described in src/output.rs
+    #[structopt(long="raw-output", parse(from_os_str))]
+    raw_output: Option<path::PathBuf>,
+
+    /// Distances that the depth AOV maps to black and white, as "near,far".
+    /// Defaults to from the camera out to twice the distance to --lookat
+    #[structopt(long="depth-range", parse(try_from_str="aov::parse_depth_range"))]
+    depth_range: Option<(Float, Float)>,
+
+    /// Every this many seconds, write the image rendered so far next to the
+    /// output as "<name>.partial.<ext>". Sending the process SIGUSR1 writes
+    /// one right away, with or without this
+    #[structopt(long="snapshot-interval")]
+    snapshot_interval: Option<u64>,
+
+    /// When stopped early, by Ctrl+C or SIGTERM, only take this many seconds
+    /// to write everything out. After that, only the image and --checkpoint
+    /// are written, and other outputs are skipped
+    #[structopt(long="grace-seconds")]
+    grace_seconds: Option<u64>,
+
+    /// Seed for every random number used while rendering.
+    /// Picked randomly if not given
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Save progress to this file as we render, so that it can be picked up
+    /// again with --resume
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<path::PathBuf>,
+
+    /// Seconds between saves to --checkpoint
+    #[structopt(default_value="60", long="checkpoint-interval")]
+    checkpoint_interval: u64,
+
+    /// Continue the render saved in this checkpoint until every pixel has
+    /// --samples samples. Progress is saved back to it unless --checkpoint
+    /// names another file
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<path::PathBuf>,
+
+    /// Give the image a look with this LUT, like a film emulation.
+    /// Either a .cube 3D LUT, or a text file of "input output" pairs in [0, 1]
+    /// that's applied to each channel. Only changes 8-bit outputs
+    #[structopt(long, parse(from_os_str))]
+    lut: Option<path::PathBuf>,
+
+    /// Stop paths once every channel of their throughput is below this.
+    /// 0 never stops them early
+    #[structopt(default_value="1e-30", long="throughput-cutoff")]
+    throughput_cutoff: Float,
+
+    /// Most times a path bounces before we stop following it
+    #[structopt(default_value="50", long="max-depth")]
+    max_depth: u32,
+
+    /// With --despeckle, how many times brighter than the median of its
+    /// neighbors a pixel has to be to count as a firefly
+    #[structopt(default_value="10", long="despeckle-factor")]
+    despeckle_factor: Float,
+
+    /// Which scene to render: cover, green, cornell, final, or random. See
+    /// --list-scenes. Each comes with a camera and background of its own,
+    /// which --lookfrom, --lookat, --vfov, --aperature, --focus-dist, and
+    /// --background change
     #[structopt(default_value="cover", long)]
-    scene: String,
+    scene: SceneKind,
+
+    /// Print the scenes --scene can render, and what's in them, and exit.
+    /// The same as the list-scenes subcommand
+    #[structopt(long="list-scenes")]
+    list_scenes: bool,
+
+    /// Render the scene described in this file, instead of the cover scene.
+    /// See src/scene_file.rs for the format
+    #[structopt(long="scene-file", parse(from_os_str))]
+    scene_file: Option<path::PathBuf>,
+
+    /// Scatter the cover or random scene's little spheres differently. Any
+    /// number or text will do, and the same one always gives the same scene
+    #[structopt(long="scene-seed")]
+    scene_seed: Option<String>,
+
+    /// Change how the cover or random scene's little spheres are laid out,
+    /// as name=value. grid_half_extent is how far out they go in the cover
+    /// scene (10), sphere_radius how big they are (0.2), and diffuse_prob and
+    /// metal_prob how likely they are to be diffuse (0.7) or metal (0.2),
+    /// rather than glass
+    #[structopt(long="scene-param", raw(number_of_values="1", use_delimiter="true"))]
+    scene_params: Vec<String>,
+
+    /// Write the cover or random scene to this file, as --scene-file reads
+    /// them, to render that exact scene again, or to edit it
+    #[structopt(long="dump-scene", parse(from_os_str))]
+    dump_scene: Option<path::PathBuf>,
+
+    /// Keep the cover or random scene's little spheres that don't move in
+    /// arrays of up to 16 each, rather than each on its own. It renders
+    /// the same, and it's often faster with thousands of them
+    #[structopt(long="pack-spheres")]
+    pack_spheres: bool,
+
+    /// How to build the BVH over the scene: median (halve every node along
+    /// its longest axis) or sah (split where the surface area heuristic says
+    /// is cheapest). sah takes longer to build, and is usually faster to
+    /// render. --verbose reports how the tree turned out
+    #[structopt(default_value="median", long="bvh-builder")]
+    bvh_builder: bvh::BvhBuilder,
+
+    /// How many spheres the random scene scatters, if there's room for them.
+    /// Defaults to 500
+    #[structopt(long)]
+    objects: Option<u32>,
+
+    /// How far out the random scene's spheres go, from -this to this in x and
+    /// z. Defaults to 10
+    #[structopt(long)]
+    extent: Option<Float>,
+
+    /// Keep up to this many megabytes of decoded images around, so that
+    /// images used more than once are only decoded once
+    #[structopt(default_value="512", long="asset-cache-size")]
+    asset_cache_size: u64,
+
+    /// Write statistics about the render to this file as JSON: settings,
+    /// timings for the whole image and each tile, ray counts, and a hash of
+    /// each tile. It's written even if the render is interrupted
+    #[structopt(long="stats-json", parse(from_os_str))]
+    stats_json: Option<path::PathBuf>,
+
+    /// Write an image of how long each tile took to this file, shaded from
+    /// cold for the fastest tile to hot for the slowest
+    #[structopt(long="tile-time-map", parse(from_os_str))]
+    tile_time_map: Option<path::PathBuf>,
+
+    /// Render the image this many times (2 if not given), and fail if any
+    /// tile comes out differently between them
+    #[structopt(long="verify-determinism")]
+    verify_determinism: Option<Option<u32>>,
 
     // ===== Flags ==========
 
-    /// Enable more detailed output
-    #[structopt(short, long)]
-    verbose: bool,
+    /// Print how long each tile took once the render is done, to see which
+    /// parts of the image are slow. --verbose prints this too
+    #[structopt(long="tile-times")]
+    tile_times: bool,
 
     /// Start the renderer in interactive mode.
-    /// NOT IMPLEMENTED
+    /// The image is shown in a window as it renders, starting at a low
+    /// resolution and sharpening as it goes.
+    /// WASD (and Q/E) move the camera, dragging the mouse turns it, the
+    /// mouse wheel zooms, and P prints flags that reproduce the view
     #[structopt(short, long)]
     interactive: bool,
 
+    /// After rendering, keep watching the --scene-file, and render it again
+    /// whenever it changes, over the same outputs. Ctrl+C stops. Pair it with
+    /// a low --samples to see each edit quickly
+    #[structopt(long)]
+    watch: bool,
+
+    /// Count intersection tests per object and scatters per material, and
+    /// report the busiest ones when the render finishes
+    #[structopt(long="profile-objects")]
+    profile_objects: bool,
+
+    /// Draw the world axes over the 8-bit outputs: x in red, y in green, and
+    /// z in blue. They start at the origin when it's in view, and are drawn
+    /// in the bottom left corner otherwise
+    #[structopt(long="draw-axes")]
+    draw_axes: bool,
+
+    /// Leave the background out of the image, so that it can be composited
+    /// over something else. Outputs get an alpha channel for how much of each
+    /// pixel is covered by the scene, which needs a format like png.
+    /// The background still lights the scene
+    #[structopt(long="transparent-background")]
+    transparent_background: bool,
+
+    /// Instead of lighting the scene, show what camera rays hit first:
+    /// normals, depth (over --depth-range), albedo, or uv. For quickly
+    /// checking geometry and texture coordinates
+    #[structopt(long)]
+    debug: Option<DebugView>,
+
+    /// What rays see when they leave the scene: sky, or black (or none), for
+    /// enclosed scenes that should only be lit by their own lights.
+    /// Defaults to the scene's own
+    #[structopt(long)]
+    background: Option<Background>,
+
+    /// Replace fireflies (pixels much brighter than their neighbors, from a
+    /// few unlucky samples) with the median of their neighbors, and report
+    /// how many there were. See --despeckle-factor
+    #[structopt(long)]
+    despeckle: bool,
+
+    /// Smooth out the noise of a render with few samples, keeping edges
+    /// sharp. With --aov normal and --aov albedo, edges between surfaces are
+    /// kept sharper still
+    #[structopt(long)]
+    denoise: bool,
+
     /// Skip some tiles in a checkerboard fashion. Useful for debugging tiles
     #[structopt(long="checkerboard-tiles")]
     checkerboard_tiles: bool,
+
+    /// Only render the tiles with these ids, like "0..32" (which doesn't
+    /// include 32), out of the --tiles the image is split into. Ids go left to
+    /// right, then top to bottom. The tiles are saved to --checkpoint, for
+    /// the merge subcommand to put together with the other parts
+    #[structopt(long="tile-range", parse(try_from_str="checkpoint::parse_tile_range"))]
+    tile_range: Option<std::ops::Range<u32>>,
+
+    /// Also save each layer of a --focus-stack next to the output
+    #[structopt(long="save-focus-layers")]
+    save_focus_layers: bool,
 }
 
-/// A subset of our final image.
-/// Tiles do not know about other tiles, but they do know their x offsets.
-struct Tile {
-    // Unique id for each tile
-    pub tile_id: u32,
-    // x coordinate of tile, in the tile grid
-    pub tile_x: u32,
-    // y coordinate of tile, in the tile grid
-    pub tile_y: u32,
-    // x offset into the parent image
-    pub offset_x: u32,
-    // y-offset into the parent image
-    pub offset_y: u32,
-    // Pixel data for the sub image.
-    // This is owned by the tile, and copied out to the parent image later.
-    pub pixels: image::RgbImage,
-    // A visual indicator of progress on rendering its sub image.
-    pub progress: pbr::ProgressBar<pbr::Pipe>,
+impl Opt {
+    /// Extra images we write are named after this one.
+    fn primary_output(&self) -> &path::Path {
+        &self.output[0]
+    }
+
+    /// Height of the image, from --height, or --width and --aspect.
+    fn height(&self) -> u32 {
+        image_height(self.width, self.height, self.aspect)
+            .expect("--height and --aspect are checked on startup")
+    }
+
+    /// How many times --verify-determinism renders the image, if it does.
+    fn verify_runs(&self) -> Option<u32> {
+        self.verify_determinism.map(|runs| runs.unwrap_or(2))
+    }
+
+    /// How many frames of animation we're rendering, if we are.
+    fn n_frames(&self) -> Option<u32> {
+        self.frames.or(self.turntable)
+    }
 }
 
-// Tasks use this to exit early
-static NEED_TO_EXIT: atomic::AtomicBool = atomic::AtomicBool::new(false);
+#[derive(Clone, Debug, StructOpt)]
+enum Command {
+    /// Render an image. This is what we do without a subcommand, too
+    #[structopt(name="render")]
+    Render(Opt),
+
+    /// Show the render in a window as it goes, and fly the camera around it.
+    /// The same as render --interactive
+    #[structopt(name="preview")]
+    Preview(Opt),
+
+    /// Print the scenes --scene can render, and what's in them
+    #[structopt(name="list-scenes")]
+    ListScenes,
+
+    /// Render the image a few times, without writing it, and report how long
+    /// it took
+    #[structopt(name="bench")]
+    Bench {
+        /// How many times to render it
+        #[structopt(default_value="3", long)]
+        runs: u32,
+
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+
+    /// Print detailed information about this build and exit
+    #[structopt(name="info")]
+    Info,
+
+    /// Compare the tiles of two renders, from the files their --stats-json
+    /// wrote, and fail if any of them differ
+    #[structopt(name="compare-manifests")]
+    CompareManifests {
+        #[structopt(parse(from_os_str))]
+        a: path::PathBuf,
+        #[structopt(parse(from_os_str))]
+        b: path::PathBuf,
+    },
+
+    /// Compare two images, PNGs or from --raw-output, and fail if they're
+    /// further apart than --threshold. Raw images are compared by their linear
+    /// radiance, and anything else by 8-bit color from 0 to 1
+    #[structopt(name="diff")]
+    Diff {
+        #[structopt(parse(from_os_str))]
+        a: path::PathBuf,
+        #[structopt(parse(from_os_str))]
+        b: path::PathBuf,
+
+        /// Write how far apart each pixel is to this PNG, 8 times brighter
+        /// than it is, so that small differences show up
+        #[structopt(long, parse(from_os_str))]
+        out: Option<path::PathBuf>,
+
+        /// Fail if the root mean square error is more than this. Defaults to
+        /// 0, so any difference at all fails
+        #[structopt(default_value="0", long)]
+        threshold: Float,
+    },
 
-// Things can poll this method to know if they should exit early
-// e.g. we received a CtrlC.
-fn needs_to_exit() -> bool {
-    NEED_TO_EXIT.load(atomic::Ordering::SeqCst)
+    /// Put together the parts of an image rendered with --tile-range, from
+    /// their checkpoints. Every tile has to be finished in exactly one part
+    #[structopt(name="merge")]
+    Merge {
+        #[structopt(parse(from_os_str), raw(required="true"))]
+        parts: Vec<path::PathBuf>,
+
+        /// The parts are each of the whole image, rendered with different
+        /// seeds. Average them, as if one render had taken all of their
+        /// samples
+        #[structopt(long)]
+        average: bool,
+
+        /// Files to write the image into. May be given more than once
+        #[structopt(default_value="output.png",
+                    parse(from_os_str),
+                    short,
+                    long,
+                    raw(number_of_values="1", use_delimiter="true"))]
+        output: Vec<path::PathBuf>,
+    },
 }
 
 // Things can call this method to signal that the application should exit
 // Calling this multiple times is fine but redundant.
 fn signal_exit() {
+    shutdown::note_stop();
     NEED_TO_EXIT.store(true, atomic::Ordering::SeqCst);
+    // Paused threads need to wake up to notice.
+    control::RENDER.stop();
+}
+
+// Animations stop between frames, so that the last one isn't half done.
+static STOP_AFTER_FRAME: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+// Stops an animation once the frame in progress is written.
+// Asking a second time stops right away, like `signal_exit()`.
+fn stop_after_frame() {
+    if STOP_AFTER_FRAME.swap(true, atomic::Ordering::SeqCst) {
+        signal_exit();
+    } else {
+        info!("\nStopping after this frame. Press Ctrl+C again to stop now");
+    }
+}
+
+/// The normal and albedo AOVs, when we rendered them, to guide --denoise.
+fn denoise_guides(aovs: &[(Aov, image::RgbImage)]) -> (Option<LinearImage>, Option<LinearImage>) {
+    let find = |which: Aov| aovs.iter().find(|(aov, _)| *aov == which).map(|(_, img)| img);
+    // Back from [0, 1] to [-1, 1] on each axis.
+    let normals = find(Aov::Normal).map(|img| {
+        LinearImage::from_fn(img.width(), img.height(), |x, y| {
+            let [r, g, b] = img.get_pixel(x, y).data;
+            Float3::xyz(r as Float, g as Float, b as Float) / 127.5 - Float3::xxx(1.0)
+        })
+    });
+    let albedo = find(Aov::Albedo).map(LinearImage::from_rgb8);
+    (normals, albedo)
+}
+
+/// Builds `<stem>.<tag>.<ext>` next to `path`.
+/// e.g. ("renders/out.png", "focus03") => "renders/out.focus03.png"
+fn output_sibling(path: &path::Path, tag: &str) -> path::PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, tag, ext.to_string_lossy()),
+        None      => format!("{}.{}", stem, tag),
+    };
+    path.with_file_name(name)
+}
+
+/// The path for `frame` (counting from 0) of an animation written to `path`.
+/// e.g. frame 2 of "output.png" is "output_0003.png".
+fn frame_path(path: &path::Path, frame: u32) -> path::PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:04}.{}", stem, frame + 1, ext.to_string_lossy()),
+        None      => format!("{}_{:04}", stem, frame + 1),
+    };
+    path.with_file_name(name)
+}
+
+/// When the shutter is open for `frame` of `n_frames` across [t_start, t_end].
+/// Frames are centered on evenly spaced times starting at `t_start`, and each
+/// one is open for half of the time between frames, like a film camera's
+/// 180 degree shutter. That never reaches outside of [t_start, t_end], which
+/// is all that the BVH was built to cover.
+fn frame_shutter(t_start: Float, t_end: Float, frame: u32, n_frames: u32) -> (Float, Float) {
+    let step = (t_end - t_start) / n_frames as Float;
+    let center = frame_time(t_start, t_end, frame, n_frames);
+    ((center - 0.25 * step).max(t_start), (center + 0.25 * step).min(t_end))
+}
+
+/// The time in the middle of `frame`'s shutter. See `frame_shutter()`.
+fn frame_time(t_start: Float, t_end: Float, frame: u32, n_frames: u32) -> Float {
+    t_start + frame as Float * (t_end - t_start) / n_frames as Float
+}
+
+/// Checks that --frames (or --turntable) is something we can render.
+fn check_frames(opt: &Opt) -> Result<(), String> {
+    if opt.camera_path.is_some() {
+        if opt.turntable.is_some() {
+            return Err("--camera-path is not supported with --turntable".into());
+        }
+        if opt.frames.is_none() {
+            return Err("--camera-path requires --frames".into());
+        }
+    }
+    if opt.frames.is_some() && opt.turntable.is_some() {
+        return Err("--turntable sets the number of frames, so it can't be used with --frames"
+                   .into());
+    }
+    if opt.n_frames().is_none() {
+        return Ok(());
+    }
+    if opt.n_frames() == Some(0) {
+        return Err("--frames and --turntable need at least 1 frame".into());
+    }
+    if opt.interactive {
+        return Err("--frames is not supported with --interactive".into());
+    }
+    if opt.focus_stack.is_some() {
+        return Err("--frames is not supported with --focus-stack".into());
+    }
+    if !opt.spp_ladder.is_empty() {
+        return Err("--frames is not supported with --spp-ladder".into());
+    }
+    if opt.checkpoint.is_some() || opt.resume.is_some() {
+        // A checkpoint only holds one frame.
+        return Err("--checkpoint and --resume are not supported with --frames".into());
+    }
+    Ok(())
+}
+
+/// Checks the focus stacking flags for consistency.
+/// Returns the layer count and focus range when we're stacking.
+fn focus_stack_settings(opt: &Opt) -> Result<Option<(u32, (Float, Float))>, String> {
+    match (opt.focus_stack, opt.focus_range) {
+        (Some(n), Some(range)) => {
+            if n < 2 {
+                Err(format!("--focus-stack needs at least 2 layers, found {}", n))
+            } else {
+                Ok(Some((n, focus_stack::resolve_focus_range(range, opt.focus_dist)?)))
+            }
+        },
+        (Some(_), None) => Err("--focus-stack requires --focus-range".into()),
+        (None, Some(_)) => Err("--focus-range requires --focus-stack".into()),
+        (None, None) => {
+            if opt.save_focus_layers {
+                Err("--save-focus-layers requires --focus-stack".into())
+            } else {
+                Ok(None)
+            }
+        },
+    }
+    .and_then(|settings| {
+        if settings.is_some() && !opt.aov.is_empty() {
+            Err("--aov is not supported with --focus-stack".into())
+        } else if settings.is_some() && opt.interactive {
+            Err("--interactive is not supported with --focus-stack".into())
+        } else if settings.is_some() && opt.transparent_background {
+            Err("--transparent-background is not supported with --focus-stack".into())
+        } else {
+            Ok(settings)
+        }
+    })
+}
+
+/// Checks that --verify-determinism has something to compare.
+fn check_verify_determinism(opt: &Opt) -> Result<(), String> {
+    let runs = match opt.verify_runs() {
+        Some(runs) => runs,
+        None => return Ok(()),
+    };
+    if runs < 2 {
+        return Err(format!("--verify-determinism needs at least 2 renders, found {}", runs));
+    }
+    let unsupported = [
+        ("--interactive", opt.interactive),
+        ("--focus-stack", opt.focus_stack.is_some()),
+        ("--frames and --turntable", opt.n_frames().is_some()),
+        ("--spp-ladder", !opt.spp_ladder.is_empty()),
+        // Every render has to start from nothing.
+        ("--checkpoint and --resume", opt.checkpoint.is_some() || opt.resume.is_some()),
+    ];
+    for &(flags, used) in unsupported.iter() {
+        if used {
+            return Err(format!("{} {} not supported with --verify-determinism",
+                               flags, if flags.contains(" and ") { "are" } else { "is" }));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every rung of `--spp-ladder` is one we'll reach.
+fn check_spp_ladder(opt: &Opt) -> Result<(), String> {
+    if opt.spp_ladder.is_empty() {
+        return Ok(());
+    }
+    if opt.interactive {
+        return Err("--spp-ladder is not supported with --interactive".into());
+    }
+    if opt.focus_stack.is_some() {
+        return Err("--spp-ladder is not supported with --focus-stack".into());
+    }
+    if !opt.aov.is_empty() {
+        // AOVs are only summed over the samples of one call to write_image().
+        return Err("--spp-ladder is not supported with --aov".into());
+    }
+    if opt.resume.is_some() {
+        // The checkpoint could already be past some of the rungs.
+        return Err("--spp-ladder is not supported with --resume".into());
+    }
+    for &spp in opt.spp_ladder.iter() {
+        if spp == 0 || spp > opt.samples_per_pixel {
+            return Err(format!("--spp-ladder counts need to be between 1 and \
+                                --samples-per-pixel ({}), found {}",
+                               opt.samples_per_pixel, spp));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that --max-seconds has time to render in, and something to stop.
+fn check_max_seconds(opt: &Opt) -> Result<(), String> {
+    match opt.max_seconds {
+        None => return Ok(()),
+        Some(0) => return Err("--max-seconds must be at least 1 second".into()),
+        Some(_) => {},
+    }
+    let unsupported = [
+        ("--interactive", opt.interactive),
+        ("--focus-stack", opt.focus_stack.is_some()),
+        ("--spp-ladder", !opt.spp_ladder.is_empty()),
+        // AOVs are only summed over the samples of one call to write_image().
+        ("--aov", !opt.aov.is_empty()),
+        ("--verify-determinism", opt.verify_determinism.is_some()),
+    ];
+    for &(flag, used) in unsupported.iter() {
+        if used {
+            return Err(format!("{} is not supported with --max-seconds", flag));
+        }
+    }
+    Ok(())
 }
 
-fn hash_it(thing: &impl hash::Hash) -> u64 {
-    let mut hasher = hash_map::DefaultHasher::new();
-    thing.hash(&mut hasher);
-    hasher.finish()
-}
-
-fn pick_tiling_dimensions(n_tiles: u32, nx: u32, ny: u32) -> (u32, u32) {
-    let aspect: Float = (nx as Float) / (ny as Float);
-
-    // We want to create roughly square tiles, but they need to divide the
-    // image's width exactly.
-    // In the case of a square image (W == H), we could just call `.sqrt()`.
-    // More generally, we need to scale the number of tiles along one side
-    // by the aspect ratio (W/H).
-    // Here's the problem described in formula.
-    //          x := # of tiles along thex axis (width)
-    //          y := # of tiles along they axis (height)
-    //      x     == ASPECT * y;
-    //      x * y == n_tiles;
-    // Since we know `ASPECT` and `n_tiles`, we re-arrange the above as:
-    //      x    = ASPECT * y
-    //      y**2 = n_tiles / ASPECT
-    // This is enough to compute the value and round it to an integer.
-    let raw_y: f64 = (n_tiles as Float / aspect).sqrt().round() as f64;
-
-    // At this point `raw_y` is a float and might not divide the requested
-    // tile count easily. We need to decide wether to opt for more square
-    // tiles by disregarding the requested tile count, or opt for hitting
-    // the tile count but with less square tiles.
-    // We opt for respecting the requested tile count.
-    // We do this by rounding the previous raw_y value to the closest factor
-    // of the tile count.
-    let mut best_factor = 1;                // First factor
-    let mut best_error = n_tiles as f64;  // Worst possible error
-    for factor in math::factors(n_tiles) {
-        // We want to minimize "error". Here, error is defined as the
-        // ratio from our raw, ideal y with the factor in question.
-        // If the factor is *smaller*, we flip the ratio to allow this
-        // process to shorten the height of tiles, if need be.
-        let mut next_error = raw_y / factor as f64;
-        if next_error < 1.0 {
-            next_error = 1.0 / next_error;
-        }
-
-        if next_error < best_error {
-            best_factor = factor;
-            best_error = next_error;
-        }
-    }
-    let y = best_factor;
-    let x = n_tiles / y;
-    assert_eq!(n_tiles as f64 / y as f64, x as f64,
-               "Tile size calculation should be exact, integer math!");
-    (x, y)
+/// Checks that --watch has a file to watch, and renders one image at a time.
+fn check_watch(opt: &Opt) -> Result<(), String> {
+    if !opt.watch {
+        return Ok(());
+    }
+    if opt.scene_file.is_none() {
+        return Err("--watch needs a --scene-file to watch".into());
+    }
+    let unsupported = [
+        ("--interactive", opt.interactive),
+        ("--frames and --turntable", opt.n_frames().is_some()),
+        ("--focus-stack", opt.focus_stack.is_some()),
+        ("--verify-determinism", opt.verify_determinism.is_some()),
+        // Every render starts over, so there's nothing to carry on from.
+        ("--checkpoint and --resume", opt.checkpoint.is_some() || opt.resume.is_some()),
+        // The counts would be for the first scene, and not the one we stop on.
+        ("--profile-objects", opt.profile_objects),
+    ];
+    for &(flag, used) in unsupported.iter() {
+        if used {
+            return Err(format!("{} is not supported with --watch", flag));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that --tile-range picks tiles out of a grid that every part agrees
+/// on, and has somewhere to save them.
+fn check_tile_range(opt: &Opt) -> Result<(), String> {
+    let range = match opt.tile_range {
+        Some(ref range) => range,
+        None => return Ok(()),
+    };
+    if opt.tiles == 0 {
+        // The tiles we'd pick depend on how many threads there are.
+        return Err("--tile-range needs --tiles, so that every part splits up the image \
+                    the same way".into());
+    }
+    if opt.checkpoint.is_none() && opt.resume.is_none() {
+        return Err("--tile-range needs a --checkpoint to save the tiles to".into());
+    }
+    let n_tiles = tile_part(opt).map_or(0, |part| part.tiles_x * part.tiles_y);
+    if range.end > n_tiles {
+        return Err(format!("--tile-range goes up to {}, but the image only has {} tiles",
+                           range.end, n_tiles));
+    }
+    let unsupported = [
+        ("--interactive", opt.interactive),
+        ("--frames and --turntable", opt.n_frames().is_some()),
+        ("--focus-stack", opt.focus_stack.is_some()),
+        ("--watch", opt.watch),
+        // Parts have to finish every one of their tiles.
+        ("--max-seconds", opt.max_seconds.is_some()),
+        ("--checkerboard-tiles", opt.checkerboard_tiles),
+    ];
+    for &(flag, used) in unsupported.iter() {
+        if used {
+            return Err(format!("{} is not supported with --tile-range", flag));
+        }
+    }
+    Ok(())
+}
+
+/// The tiles we're rendering, as checkpoints remember them, when it's only
+/// some of them.
+fn tile_part(opt: &Opt) -> Option<checkpoint::TileRange> {
+    opt.tile_range.as_ref().map(|ids| {
+        let plan = TilingPlan::new(opt.tiles, 1, opt.width, opt.height());
+        checkpoint::TileRange {
+            tiles_x: plan.tiles_x,
+            tiles_y: plan.tiles_y,
+            ids:     ids.clone(),
+        }
+    })
+}
+
+/// Whether we render the tile at (`x`, `y`) in the grid, which is `tile_id`.
+fn tile_selected(opt: &Opt, tile_id: u32, x: u32, y: u32) -> bool {
+    let on_checkerboard = !opt.checkerboard_tiles || x % 2 == y % 2;
+    let in_range = opt.tile_range.as_ref().map_or(true, |ids| ids.contains(&tile_id));
+    on_checkerboard && in_range
+}
+
+/// What we're rendering, as checkpoints remember it. The cover and random
+/// scenes' seed and params are part of it, so that we don't resume a render of
+/// a different scene.
+fn scene_name(opt: &Opt) -> String {
+    let mut name = opt.scene.name().to_string();
+    if let Some(ref seed) = opt.scene_seed {
+        name += &format!(" --scene-seed {}", seed);
+    }
+    for param in opt.scene_params.iter() {
+        name += &format!(" --scene-param {}", param);
+    }
+    if let Some(objects) = opt.objects {
+        name += &format!(" --objects {}", objects);
+    }
+    if let Some(extent) = opt.extent {
+        name += &format!(" --extent {}", extent);
+    }
+    name
+}
+
+/// The random scene's params, from --objects and --extent.
+fn random_scene_params(opt: &Opt) -> RandomSceneParams {
+    let default = RandomSceneParams::default();
+    RandomSceneParams {
+        objects: opt.objects.unwrap_or(default.objects),
+        extent:  opt.extent.unwrap_or(default.extent),
+    }
+}
+
+/// Where our render starts: from scratch, or from a --resume checkpoint.
+fn render_progress(opt: &Opt) -> Result<(RenderSettings, Accumulator), String> {
+    if opt.checkpoint.is_some() || opt.resume.is_some() {
+        if opt.focus_stack.is_some() {
+            return Err("--checkpoint and --resume are not supported with --focus-stack".into());
+        }
+        if !opt.aov.is_empty() {
+            return Err("--checkpoint and --resume are not supported with --aov".into());
+        }
+        if opt.transparent_background {
+            return Err("--checkpoint and --resume are not supported with \
+                        --transparent-background".into());
+        }
+        if opt.checkpoint_interval == 0 {
+            return Err("--checkpoint-interval must be at least 1 second".into());
+        }
+    }
+
+    if opt.resume.is_some() && opt.despeckle {
+        // Checkpoints don't keep the variance that it needs.
+        return Err("--resume is not supported with --despeckle".into());
+    }
+    if opt.resume.is_some() && opt.variance_map.is_some() {
+        return Err("--resume is not supported with --variance-map".into());
+    }
+
+    let mut settings = RenderSettings {
+        width:             opt.width,
+        height:            opt.height(),
+        samples_per_pixel: opt.samples_per_pixel,
+        max_depth:         opt.max_depth,
+        seed:              opt.seed.unwrap_or_else(rand::random),
+        scene:             scene_name(opt),
+    };
+
+    match opt.resume {
+        Some(ref path) => {
+            let checkpoint = checkpoint::Checkpoint::load(path)
+                .map_err(|e| format!("Unable to load {}: {}", path.display(), e))?;
+            if opt.seed.is_none() {
+                settings.seed = checkpoint.settings.seed;
+            }
+            checkpoint.settings.check_matches(&settings)?;
+            if checkpoint.tiles != tile_part(opt) {
+                return Err(format!("{} has different tiles than --tiles and --tile-range \
+                                    ask for", path.display()));
+            }
+            Ok((settings, Accumulator::from_checkpoint(&checkpoint)))
+        },
+        None => {
+            let accum = Accumulator::new(settings.width, settings.height);
+            Ok((settings, accum))
+        },
+    }
+}
+
+/// Points the camera the way the --scene would like, except where the command
+/// line says otherwise.
+fn use_scene_view(opt: &mut Opt, matches: &clap::ArgMatches) {
+    let view = opt.scene.view();
+    let given = |name: &str| matches.occurrences_of(name) > 0;
+    if !given("lookfrom") {
+        opt.lookfrom = view.lookfrom;
+    }
+    if !given("lookat") {
+        opt.lookat = view.lookat;
+    }
+    if !given("vfov") {
+        opt.vfov = view.vfov;
+    }
+    if !given("aperature") {
+        opt.aperature = view.aperature;
+    }
+    if !given("focus_dist") {
+        opt.focus_dist = view.focus_dist;
+    }
 }
 
 fn main() {
     // Parse CLI
-    let opt = Opt::from_args();
+    let matches = Cli::clap().get_matches();
+    let cli = Cli::from_clap(&matches);
+    logging::init(logging::level(cli.quiet, cli.verbose));
+    let jobs = cli.jobs;
+
+    // Without a subcommand, the render options are on the top level.
+    let mut bench_runs = None;
+    let (mut opt, render_matches) = match cli.cmd {
+        None => (cli.render, &matches),
+        Some(cmd) => {
+            // With one, they go after it.
+            let shared = ["jobs", "verbose", "quiet"];
+            let given = |name: &str| matches.occurrences_of(name) > 0 && !shared.contains(&name);
+            if matches.args.keys().any(|name| given(name)) {
+                error!("Options for rendering go after the subcommand, like \
+                        `raytracer render --width 400`");
+                std::process::exit(1);
+            }
+            let sub_matches = matches.subcommand().1.expect("We just parsed a subcommand");
+            match cmd {
+                Command::Render(opt) => (opt, sub_matches),
+                Command::Preview(opt) => (Opt { interactive: true, ..opt }, sub_matches),
+                Command::Bench { runs, opt } => {
+                    if runs == 0 {
+                        error!("--runs must be at least 1");
+                        std::process::exit(1);
+                    }
+                    bench_runs = Some(runs);
+                    (opt, sub_matches)
+                },
+                Command::ListScenes => {
+                    list_scenes();
+                    return;
+                },
+                Command::Info => {
+                    print!("{}", build_info::BUILD_INFO);
+                    return;
+                },
+                Command::CompareManifests { ref a, ref b } => {
+                    match determinism::compare_manifests(a, b) {
+                        Ok(ref mismatches) if mismatches.is_empty() => {
+                            info!("Every tile matches");
+                            return;
+                        },
+                        Ok(mismatches) => {
+                            error!("{} tiles differ:{}", mismatches.len(), list(&mismatches));
+                        },
+                        Err(msg) => error!("{}", msg),
+                    }
+                    std::process::exit(1);
+                },
+                Command::Diff { ref a, ref b, ref out, threshold } => {
+                    let diff = image_diff::Image::load(a)
+                        .and_then(|a| Ok((a, image_diff::Image::load(b)?)))
+                        .and_then(|(a, b)| image_diff::diff(&a, &b));
+                    let diff = match diff {
+                        Ok(diff) => diff,
+                        Err(msg) => {
+                            error!("{}", msg);
+                            std::process::exit(1);
+                        },
+                    };
+                    println!("Max difference:      {}", diff.max);
+                    println!("Mean absolute error: {}", diff.mean);
+                    println!("RMSE:                {}", diff.rmse);
+                    if let Some(out) = out {
+                        if let Err(err) = diff.image.save(out) {
+                            error!("Unable to write {}: {}", out.display(), err);
+                            std::process::exit(1);
+                        }
+                        report_written(out);
+                    }
+                    if diff.rmse > threshold {
+                        error!("The images are further apart than the --threshold of {}",
+                               threshold);
+                        std::process::exit(1);
+                    }
+                    return;
+                },
+                Command::Merge { ref parts, average, ref output } => {
+                    if !merge_parts(parts, average, output) {
+                        std::process::exit(1);
+                    }
+                    return;
+                },
+            }
+        },
+    };
+    use_scene_view(&mut opt, render_matches);
+    if opt.heatmap.is_some() && !opt.aov.contains(&Aov::Bounces) {
+        opt.aov.push(Aov::Bounces);
+    }
+    if opt.list_scenes {
+        list_scenes();
+        return;
+    }
+
+    if let Err(msg) = image_height(opt.width, opt.height, opt.aspect) {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+
+    let focus_stack = match focus_stack_settings(&opt) {
+        Ok(focus_stack) => focus_stack,
+        Err(msg) => {
+            error!("{}", msg);
+            std::process::exit(1);
+        },
+    };
+
+    for path in opt.output.iter() {
+        match output::Format::from_path(path) {
+            Ok(format) => {
+                if opt.transparent_background && !format.has_alpha() {
+                    error!("--transparent-background needs outputs that can hold \
+                               alpha, like png. {} can't",
+                              path.display());
+                    std::process::exit(1);
+                }
+            },
+            Err(msg) => {
+                error!("{}", msg);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    if opt.projection == ProjectionKind::Equirectangular && opt.width != 2 * opt.height() {
+        warn!("Panoramas are meant to be twice as wide as they are tall, \
+                   so {}x{} will look stretched in a 360 viewer\n",
+                  opt.width, opt.height());
+    }
+
+    let lut = match opt.lut.as_ref().map(|path| lut::Lut::load(path)).transpose() {
+        Ok(lut) => lut,
+        Err(msg) => {
+            error!("{}", msg);
+            std::process::exit(1);
+        },
+    };
+
+    let checked = check_spp_ladder(&opt)
+        .and_then(|()| check_frames(&opt))
+        .and_then(|()| check_verify_determinism(&opt))
+        .and_then(|()| check_max_seconds(&opt))
+        .and_then(|()| check_watch(&opt))
+        .and_then(|()| check_tile_range(&opt));
+    if let Err(msg) = checked {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    let camera_path = match (opt.turntable, opt.camera_path.as_ref()) {
+        (Some(_), _) => CameraPath::Turntable,
+        (None, Some(path)) => match camera_path::load_keyframes(path) {
+            Ok(keys) => CameraPath::Keyframes(keys),
+            Err(msg) => {
+                error!("{}", msg);
+                std::process::exit(1);
+            },
+        },
+        (None, None) => CameraPath::Fixed,
+    };
+
+    let (settings, accum) = match render_progress(&opt) {
+        Ok(progress) => progress,
+        Err(msg) => {
+            error!("{}", msg);
+            std::process::exit(1);
+        },
+    };
+    info!("Rendering with seed {}", settings.seed);
+
+    if opt.snapshot_interval == Some(0) {
+        error!("--snapshot-interval must be at least 1 second");
+        std::process::exit(1);
+    }
+    if opt.progress_interval == 0 {
+        error!("--progress-interval must be at least 1 second");
+        std::process::exit(1);
+    }
+    if let Err(msg) = Aperture::from_blades(opt.aperture_blades, opt.aperture_rotation) {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    if opt.aov_stride == 0 {
+        error!("--aov-stride must be at least 1");
+        std::process::exit(1);
+    }
+    if !(opt.despeckle_factor > 1.0) {
+        error!("--despeckle-factor must be more than 1, found {}",
+                  opt.despeckle_factor);
+        std::process::exit(1);
+    }
+    let spheres = &[SceneKind::Cover, SceneKind::Random][..];
+    let random = &[SceneKind::Random][..];
+    let scene_flags = [
+        ("--scene-seed", opt.scene_seed.is_some(), spheres),
+        ("--scene-param", !opt.scene_params.is_empty(), spheres),
+        ("--objects", opt.objects.is_some(), random),
+        ("--extent", opt.extent.is_some(), random),
+        ("--dump-scene", opt.dump_scene.is_some(), spheres),
+        ("--pack-spheres", opt.pack_spheres, spheres),
+    ];
+    for &(flag, used, scenes) in scene_flags.iter() {
+        if used && (opt.scene_file.is_some() || !scenes.contains(&opt.scene)) {
+            let names: Vec<&str> = scenes.iter().map(|kind| kind.name()).collect();
+            error!("{} only works with --scene {}", flag, names.join(" or "));
+            std::process::exit(1);
+        }
+    }
+    if let Err(msg) = random_scene_params(&opt).check() {
+        error!("{}", msg);
+        std::process::exit(1);
+    }
+    if opt.scene_file.is_some() && opt.scene != SceneKind::Cover {
+        error!("--scene is not supported with --scene-file");
+        std::process::exit(1);
+    }
+    if opt.variance_map.is_some() && opt.focus_stack.is_some() {
+        // Each layer has samples of its own, and none of them are the image's.
+        error!("--variance-map is not supported with --focus-stack");
+        std::process::exit(1);
+    }
+    if opt.raw_output.is_some() && opt.focus_stack.is_some() {
+        // The layers are merged in 8-bit color.
+        error!("--raw-output is not supported with --focus-stack");
+        std::process::exit(1);
+    }
+    if opt.stats_json.is_some() && opt.interactive {
+        error!("--stats-json is not supported with --interactive");
+        std::process::exit(1);
+    }
+    if (opt.tile_times || opt.tile_time_map.is_some()) && opt.interactive {
+        error!("--tile-times and --tile-time-map are not supported with --interactive");
+        std::process::exit(1);
+    }
 
     // If the user uses Ctrl+C to quit early, we want to handle that.
     // Specifically, we write what image data has been generated to disk.
-    if ctrlc::set_handler(signal_exit).is_err() {
-        eprintln!("Unable to set Ctrl+C handler. Ctrl+C will abort the program.");
+    // SIGTERM (and closing the console on Windows) does the same.
+    let interrupt: fn() = if opt.n_frames().is_some() {
+        stop_after_frame
+    } else {
+        signal_exit
+    };
+    if ctrlc::set_handler(interrupt).is_err() {
+        warn!("Unable to set Ctrl+C handler. Ctrl+C will abort the program.");
     }
+    // Before anything slow, so that an early SIGUSR1 doesn't kill us.
+    control::catch_snapshot_signal();
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs as usize)
+        .start_handler(|_| {
+            ftz::enable_on_this_thread();
+        })
+        .build_global()
+        .expect("Unexpected failure with rayon::ThreadPoolBuilder");
+    info!("Rendering on {} threads\n", rayon::current_num_threads());
+
+    let plan = TilingPlan::new(opt.tiles, rayon::current_num_threads() as u32,
+                               opt.width, opt.height());
+    if let Some(warning) = plan.warning(opt.width, opt.height()) {
+        warn!("{}\n", warning);
+    }
+
+    // Load the scene
+    let assets = assets::AssetCache::new(opt.asset_cache_size * 1024 * 1024);
+    let (mut hitables, lights, background) = match (opt.scene_file.as_ref(), opt.scene) {
+        (Some(path), _) => {
+            match scene_file::load(path, &assets, &scene_file::MaterialRegistry::default()) {
+                Ok(scene) => {
+                    debug!("{}", assets.stats());
+                    (scene.world.hitables, scene.lights, scene.background)
+                },
+                Err(msg) => {
+                    error!("{}", msg);
+                    std::process::exit(1);
+                },
+            }
+        },
+        (None, kind) => {
+            let seed = opt.scene_seed.as_ref().map_or_else(SceneSeed::default, |seed| {
+                SceneSeed::new(seed)
+            });
+            let mut params = CoverSceneParams::default();
+            let checked = opt.scene_params
+                             .iter()
+                             .map(|param| params.set(param))
+                             .collect::<Result<(), String>>()
+                             .and_then(|()| params.check());
+            if let Err(msg) = checked {
+                error!("--scene-param: {}", msg);
+                std::process::exit(1);
+            }
+            let random = random_scene_params(&opt);
+            let scene = kind.make(&seed, &params, &random);
+            if let Some(ref path) = opt.dump_scene {
+                // Only the scenes made of spheres get here with --dump-scene.
+                let spheres = kind.spheres(&seed, &params, &random).unwrap_or_default();
+                let comment = format!("--scene {}, from --dump-scene\n\
+                                       Render with: --scene-file {}",
+                                      scene_name(&opt), path.display());
+                let text = scene_file::write_spheres(&comment, scene.background, &spheres);
+                if let Err(err) = std::fs::write(path, text) {
+                    error!("Unable to write {}: {}", path.display(), err);
+                    std::process::exit(1);
+                }
+                report_written(path);
+            }
+            // Everything but the ground.
+            let placed = scene.world.hitables.len() - 1;
+            if kind == SceneKind::Random && placed < random.objects as usize {
+                warn!("Only found room for {} of the {} --objects in an --extent of {}",
+                      placed, random.objects, random.extent);
+            }
+            let world = match kind.spheres(&seed, &params, &random) {
+                Some(spheres) if opt.pack_spheres => packed_hitables(&spheres),
+                _ => scene.world,
+            };
+            (world.hitables, scene.lights, scene.background)
+        },
+    };
+    let mut profile = None;
+    if opt.profile_objects {
+        let (instrumented, counts) = profile::instrument(hitables, opt.t_start, opt.t_end);
+        hitables = instrumented;
+        profile = Some(counts);
+    }
+    let world = Arc::new(render_scene(&opt, hitables, lights, background));
+    if let Some(runs) = bench_runs {
+        bench(&opt, &world, &settings, runs);
+        return;
+    }
+
+    // Tiles draw into this as they go, and the window shows it.
+    let framebuffer = Arc::new(snapshot::Framebuffer::new(opt.width, opt.height()));
+    // The window moves the camera around in interactive mode.
+    let view = Arc::new(fly_camera::View::new(camera_info(&opt)));
+
+    // Keys pressed in the terminal pause, stop, and snapshot the render.
+    let snapshot_path = output_sibling(opt.primary_output(), "partial");
+    let keyboard = {
+        let framebuffer = framebuffer.clone();
+        let snapshot_path = snapshot_path.clone();
+        control::Keyboard::spawn(move |key| {
+            control::handle(&control::RENDER, key, interrupt, || {
+                save_snapshot(&framebuffer, &snapshot_path);
+            });
+        })
+    };
+    if keyboard.is_some() {
+        info!("Press p to pause or resume, s to save a snapshot, or q to stop");
+    }
+    // And SIGUSR1 snapshots it too, without a terminal.
+    let snapshot_signal = {
+        let framebuffer = framebuffer.clone();
+        control::SnapshotSignal::spawn(move || save_snapshot(&framebuffer, &snapshot_path))
+    };
 
     // Bulk of the work
-    let imgbuf = write_image(&opt);
+    // This happens off of the main thread, so that the window stays responsive.
+    let render = {
+        let framebuffer = framebuffer.clone();
+        let view = view.clone();
+        move || {
+            match (opt.n_frames(), opt.scene_file.as_ref().filter(|_| opt.watch)) {
+                (Some(n_frames), _) => {
+                    render_animation(&opt, n_frames, &camera_path, &world, settings, &framebuffer,
+                                     &view, lut.as_ref())
+                },
+                (None, Some(path)) => {
+                    render_watched(&opt, path, world, settings, &framebuffer, &view, lut.as_ref())
+                },
+                (None, None) => {
+                    render_and_save(&opt,
+                                    &world,
+                                    focus_stack,
+                                    settings,
+                                    accum,
+                                    &framebuffer,
+                                    &view,
+                                    lut.as_ref())
+                },
+            }
+        }
+    };
+    let saved = with_window(&framebuffer, &view, render);
+    // Put the terminal back before anything else can exit.
+    if let Some(keyboard) = keyboard {
+        keyboard.finish();
+    }
+    snapshot_signal.finish();
+
+    if let Some(profile) = profile {
+        info!("\n{}", profile.report(10).trim_end());
+    }
+    let negligible_paths = NEGLIGIBLE_PATHS.load(atomic::Ordering::Relaxed);
+    if negligible_paths > 0 {
+        info!("Paths stopped early with negligible throughput: {}", negligible_paths);
+    }
+
+    if !saved {
+        std::process::exit(1);
+    }
+}
+
+/// The scene to render: `hitables` in a BVH, and everything else about it from
+/// `opt`, unless the scene has its own `background`.
+fn render_scene(opt:        &Opt,
+                hitables:   Vec<Box<dyn Hitable>>,
+                lights:     HitableList,
+                background: Background)
+    -> Scene
+{
+    let bvh = bvh::Bvh::with_builder(hitables, opt.t_start, opt.t_end, opt.bvh_builder);
+    debug!("{} ({} splits)", bvh.stats(), opt.bvh_builder.name());
+    Scene {
+        world: HitableList {
+            hitables: vec![Box::new(bvh)],
+        },
+        lights,
+        background: opt.background.unwrap_or(background),
+        throughput_cutoff: opt.throughput_cutoff,
+        transparent_background: opt.transparent_background,
+        filter: opt.filter.filter(),
+        debug: opt.debug,
+        depth_range: depth_range(opt),
+    }
+}
+
+/// Writes what `framebuffer` has so far to `path`, while we keep rendering.
+/// Renders `world` `runs` times, for `bench`, and reports the fastest.
+fn bench(opt: &Opt, world: &Scene, settings: &RenderSettings, runs: u32) {
+    let cam = camera(opt, camera_info(opt));
+    let samples = settings.width as f64 * settings.height as f64
+                  * settings.samples_per_pixel as f64;
+    let mut best: Option<f64> = None;
+    for run in 0..runs {
+        let start = time::Instant::now();
+        one_weekend::render::render(world, &cam, settings, &|_| {});
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_micros() as f64 / 1e6;
+        info!("Run {}/{}: {:.3}s", run + 1, runs, secs);
+        best = Some(best.map_or(secs, |best| best.min(secs)));
+        if needs_to_exit() {
+            break;
+        }
+    }
+    if let Some(best) = best {
+        println!("Fastest: {:.3}s, {:.2}M samples per second", best, samples / best / 1e6);
+    }
+}
+
+/// Prints every scene --scene can render, and what's in it.
+fn list_scenes() {
+    for &kind in SceneKind::ALL {
+        println!("{:<8} {}", kind.name(), kind.description());
+    }
+}
+
+/// Says that we wrote `path`, on stdout, for scripts to pick up.
+/// `--quiet` leaves it out.
+fn report_written(path: &path::Path) {
+    if log::log_enabled!(log::Level::Info) {
+        println!("Wrote {}", path.display());
+    }
+}
+
+/// Every item of `items` on its own line, indented, to follow a message.
+fn list<T: std::fmt::Display>(items: &[T]) -> String {
+    items.iter().map(|item| format!("\n    {}", item)).collect()
+}
+
+fn save_snapshot(framebuffer: &snapshot::Framebuffer, path: &path::Path) {
+    match snapshot::save_atomically(&framebuffer.to_image(), path) {
+        Ok(()) => info!("\nWrote {}", path.display()),
+        Err(err) => error!("\nFailed to write snapshot to {}: {}", path.display(), err),
+    }
+}
+
+/// Runs `job` on another thread, while we show `framebuffer` in a window on
+/// this one. Closing the window before `job` is done stops the render.
+/// Input from the window is applied to `view`, which is closed once nobody
+/// can see it anymore. Without a window, we just wait.
+fn with_window<R>(framebuffer: &snapshot::Framebuffer,
+                  view:        &fly_camera::View,
+                  job:         impl FnOnce() -> R + Send + 'static)
+    -> R
+    where R: Send + 'static
+{
+    let done = Arc::new(atomic::AtomicBool::new(false));
+    let worker = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let result = job();
+            done.store(true, atomic::Ordering::SeqCst);
+            result
+        })
+    };
+
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let shown = show_window(width,
+                            height,
+                            || Some(framebuffer.to_image()),
+                            |input| view.apply(input));
+    view.close();
+    match shown {
+        Ok(()) => {
+            if !done.load(atomic::Ordering::SeqCst) {
+                info!("Window closed, stopping early");
+                signal_exit();
+            }
+        },
+        Err(err) => {
+            // If we can't open SDL (e.g. no video device), fail elegantly
+            warn!("Failed to open SDL window: {:#?}", err);
+        },
+    }
+
+    worker.join().expect("Render thread panicked")
+}
+
+/// Renders `n_frames` frames across the shutter interval, writing each one
+/// out like `render_and_save()` does, to numbered copies of the outputs.
+/// Returns whether every output of every frame was written.
+fn render_animation(opt:         &Opt,
+                    n_frames:    u32,
+                    camera_path: &CameraPath,
+                    world:       &Scene,
+                    settings:    RenderSettings,
+                    framebuffer: &Arc<snapshot::Framebuffer>,
+                    view:        &fly_camera::View,
+                    lut:         Option<&lut::Lut>)
+    -> bool
+{
+    let start = time::Instant::now();
+    let mut saved = true;
+    for frame in 0..n_frames {
+        if needs_to_exit() || STOP_AFTER_FRAME.load(atomic::Ordering::SeqCst) {
+            info!("Stopped after {} of {} frames", frame, n_frames);
+            break;
+        }
+        if frame == 0 {
+            info!("Frame 1/{}", n_frames);
+        } else {
+            let elapsed = start.elapsed();
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_millis() as f64 / 1e3;
+            let left = secs / frame as f64 * (n_frames - frame) as f64;
+            info!("Frame {}/{}, about {:.0}s left", frame + 1, n_frames, left);
+        }
+
+        let frame_opt = animation_frame_opt(opt, camera_path, frame, n_frames);
+        let accum = Accumulator::new(opt.width, opt.height());
+        saved &= render_and_save(&frame_opt, world, None, settings.clone(), accum,
+                                 framebuffer, view, lut);
+    }
+    saved
+}
+
+/// Renders `world`, and then renders the --scene-file at `path` again each time
+/// it changes, until we're asked to stop. A file that doesn't load is reported,
+/// and we wait for the next change. Returns whether the last render's outputs
+/// were all written.
+fn render_watched(opt:         &Opt,
+                  path:        &path::Path,
+                  world:       Arc<Scene>,
+                  settings:    RenderSettings,
+                  framebuffer: &Arc<snapshot::Framebuffer>,
+                  view:        &fly_camera::View,
+                  lut:         Option<&lut::Lut>)
+    -> bool
+{
+    let mut watcher = watch::Watcher::new(path);
+    let mut world = world;
+    loop {
+        let accum = Accumulator::new(opt.width, opt.height());
+        let saved = render_and_save(opt, &world, None, settings.clone(), accum, framebuffer,
+                                    view, lut);
+        loop {
+            if needs_to_exit() {
+                return saved;
+            }
+            info!("\nWatching {} for changes. Press Ctrl+C to stop", path.display());
+            if !watcher.wait(needs_to_exit) {
+                return saved;
+            }
+            // Images used by the scene may have changed too.
+            let assets = assets::AssetCache::new(opt.asset_cache_size * 1024 * 1024);
+            match scene_file::load(path, &assets, &scene_file::MaterialRegistry::default()) {
+                Ok(scene) => {
+                    info!("{} changed, rendering it again", path.display());
+                    world = Arc::new(render_scene(opt, scene.world.hitables, scene.lights,
+                                                  scene.background));
+                    break;
+                },
+                Err(msg) => error!("{}", msg),
+            }
+        }
+    }
+}
+
+/// `opt` for rendering one frame of an animation on its own, with the camera
+/// wherever `camera_path` puts it.
+fn animation_frame_opt(opt: &Opt, camera_path: &CameraPath, frame: u32, n_frames: u32) -> Opt {
+    let (t_start, t_end) = frame_shutter(opt.t_start, opt.t_end, frame, n_frames);
+    let time = frame_time(opt.t_start, opt.t_end, frame, n_frames);
+    let cam = camera_path.camera(camera_info(opt), frame, n_frames, time);
+    Opt {
+        t_start,
+        t_end,
+        lookfrom:      cam.lookfrom,
+        lookat:        cam.lookat,
+        vfov:          match cam.projection {
+            Projection::Perspective { vfov } => vfov,
+            _ => opt.vfov,
+        },
+        output:        opt.output.iter().map(|path| frame_path(path, frame)).collect(),
+        stats_json:    opt.stats_json.as_ref().map(|path| frame_path(path, frame)),
+        variance_map:  opt.variance_map.as_ref().map(|path| frame_path(path, frame)),
+        raw_output:    opt.raw_output.as_ref().map(|path| frame_path(path, frame)),
+        tile_time_map: opt.tile_time_map.as_ref().map(|path| frame_path(path, frame)),
+        frames:        None,
+        turntable:     None,
+        ..opt.clone()
+    }
+}
+
+/// Renders the scene and writes out everything we were asked for.
+/// Returns whether every output was written.
+fn render_and_save(opt:         &Opt,
+                   world:       &Scene,
+                   focus_stack: Option<(u32, (Float, Float))>,
+                   settings:    RenderSettings,
+                   accum:       Accumulator,
+                   framebuffer: &Arc<snapshot::Framebuffer>,
+                   view:        &fly_camera::View,
+                   lut:         Option<&lut::Lut>)
+    -> bool
+{
+    // Interactive renders finish wherever the camera was flown to.
+    let axes_from = || {
+        if opt.draw_axes {
+            Some(camera(opt, view.camera().1))
+        } else {
+            None
+        }
+    };
 
-    imgbuf.save(&opt.output).unwrap();
+    let mut saved = true;
+    let mut render_stats = None;
+    let mut aovs = vec![];
+    let mut mask = None;
+    let accum = Arc::new(accum);
+    let mut linear = match focus_stack {
+        None => {
+            let frame = if opt.interactive {
+                let show = |image: &image::RgbImage| {
+                    for (x, y, pixel) in image.enumerate_pixels() {
+                        framebuffer.put_pixel(x, y, *pixel);
+                    }
+                };
+                let frame = progressive::render_interactive(world,
+                                                            &settings,
+                                                            &accum,
+                                                            view,
+                                                            |info| camera(opt, info),
+                                                            &|| control::RENDER.wait_while_paused(),
+                                                            &show);
+                if let Some(path) = opt.checkpoint.as_ref().or(opt.resume.as_ref()) {
+                    if view.generation() == 0 {
+                        save_checkpoint(&accum, &settings, None, path);
+                    } else {
+                        // Resuming would use the camera from the command line instead.
+                        info!("The camera was moved, so not saving a checkpoint to {}",
+                              path.display());
+                    }
+                }
+                frame
+            } else if let Some(runs) = opt.verify_runs() {
+                let cam = camera(opt, camera_info(opt));
+                let ns = opt.samples_per_pixel;
+                let (frame, mismatches) = determinism::verify(runs, |run| {
+                    if run == 1 {
+                        info!("Render 1/{}", runs);
+                        write_image(opt, world, &cam, &settings, &accum, framebuffer, ns)
+                    } else {
+                        info!("Render {}/{}, to compare with the first", run, runs);
+                        let accum = Arc::new(Accumulator::new(opt.width, opt.height()));
+                        write_image(opt, world, &cam, &settings, &accum, framebuffer, ns)
+                    }
+                });
+                if mismatches.is_empty() {
+                    info!("Every tile matched over {} renders", runs);
+                } else {
+                    error!("{} tiles came out differently between renders:{}",
+                           mismatches.len(), list(&mismatches));
+                    saved = false;
+                }
+                frame
+            } else {
+                let cam = camera(opt, camera_info(opt));
+                let on_rung = |spp: u32, frame: &Frame| {
+                    let mut linear = frame.linear.clone();
+                    if opt.despeckle {
+                        // `accum` has exactly this rung's samples so far.
+                        despeckle::despeckle(&mut linear, opt.despeckle_factor, Some(&*accum));
+                    }
+                    if opt.denoise {
+                        let (normals, albedo) = denoise_guides(&frame.aovs);
+                        linear = denoise::denoise(&linear, normals.as_ref(), albedo.as_ref());
+                    }
+                    let tag = format!("spp{:04}", spp);
+                    let paths: Vec<_> = opt.output
+                                           .iter()
+                                           .map(|path| output_sibling(path, &tag))
+                                           .collect();
+                    let axes_from = axes_from();
+                    for (path, result) in output::write_all(&linear, &paths, lut,
+                                                            axes_from.as_ref())
+                    {
+                        match result {
+                            Ok(()) => report_written(path),
+                            Err(msg) => {
+                                error!("{}", msg);
+                                saved = false;
+                            },
+                        }
+                    }
+                };
+                match opt.max_seconds {
+                    Some(secs) => {
+                        let budget = time::Duration::from_secs(secs);
+                        write_image_budgeted(opt, world, &cam, &settings, &accum, framebuffer,
+                                             budget)
+                    },
+                    None => {
+                        write_image_ladder(opt, world, &cam, &settings, &accum, framebuffer,
+                                           on_rung)
+                    },
+                }
+            };
+            aovs = frame.aovs;
+            mask = frame.mask;
+            render_stats = frame.stats;
+            frame.linear
+        },
+        Some((n_layers, range)) => {
+            let mut layers = vec![];
+            for (i, focus_dist) in focus_stack::focus_distances(n_layers, range)
+                                       .into_iter()
+                                       .enumerate()
+            {
+                info!("Focus layer {}/{} at distance {}",
+                          i + 1, n_layers, focus_dist);
+                let cam = camera(opt, CameraInfo {
+                    focus_dist,
+                    ..camera_info(opt)
+                });
+                let layer_accum = Arc::new(Accumulator::new(opt.width, opt.height()));
+                let frame = write_image(opt, world, &cam, &settings, &layer_accum, framebuffer,
+                                        opt.samples_per_pixel);
+                if let Some(layer) = frame.stats {
+                    match render_stats {
+                        Some(ref mut all) => all.add_layer(&layer),
+                        None => render_stats = Some(layer),
+                    }
+                }
+                if opt.save_focus_layers {
+                    let tag = format!("focus{:02}", i);
+                    frame.image.save(output_sibling(opt.primary_output(), &tag)).unwrap();
+                }
+                layers.push(frame.linear);
+
+                if needs_to_exit() {
+                    break;
+                }
+            }
+            let merged = focus_stack::merge(&layers);
+            for (x, y, pixel) in merged.to_rgb8().enumerate_pixels() {
+                framebuffer.put_pixel(x, y, *pixel);
+            }
+            merged
+        },
+    };
+
+    if let Some(render_stats) = render_stats.as_ref() {
+        if opt.tile_times {
+            info!("{}", render_stats.tile_report());
+        } else {
+            debug!("{}", render_stats.tile_report());
+        }
+    }
+
+    // Before anything else touches it.
+    let raw = opt.raw_output.as_ref().map(|path| (path, linear.clone()));
+    if opt.despeckle {
+        // Focus stacks don't have one set of samples behind the image.
+        let sums = if focus_stack.is_none() {
+            Some(&*accum)
+        } else {
+            None
+        };
+        info!("{}", despeckle::despeckle(&mut linear, opt.despeckle_factor, sums));
+    }
+    if opt.denoise {
+        let (normals, albedo) = denoise_guides(&aovs);
+        linear = denoise::denoise(&linear, normals.as_ref(), albedo.as_ref());
+    }
+
+    // When we've been stopped, there may not be long before we're killed, so
+    // the outputs we can't do without go first. See `shutdown`.
+    let axes_from = axes_from();
+    let write_images = |paths: &[path::PathBuf]| {
+        let mut ok = true;
+        for (path, result) in output::write_all(&linear, paths, lut, axes_from.as_ref()) {
+            match result {
+                Ok(()) => report_written(path),
+                Err(msg) => {
+                    error!("{}", msg);
+                    ok = false;
+                },
+            }
+        }
+        ok
+    };
+    let mut steps: Vec<shutdown::Step> = vec![
+        (shutdown::Output::Image, Box::new(|| write_images(&opt.output[..1]))),
+        (shutdown::Output::OtherImages, Box::new(|| write_images(&opt.output[1..]))),
+    ];
+    if let Some((path, raw)) = raw {
+        steps.push((shutdown::Output::OtherImages, Box::new(move || {
+            match output::write_raw(&raw, path) {
+                Ok(()) => {
+                    report_written(path);
+                    true
+                },
+                Err(msg) => {
+                    error!("{}", msg);
+                    false
+                },
+            }
+        })));
+    }
+    // Interactive renders save their own, if the camera didn't move.
+    let checkpoint_path = opt.checkpoint.as_ref().or(opt.resume.as_ref());
+    if let (Some(path), false) = (checkpoint_path, opt.interactive) {
+        let (accum, settings, tiles) = (&accum, &settings, tile_part(opt));
+        steps.push((shutdown::Output::ResumeState,
+                    Box::new(move || save_checkpoint(accum, settings, tiles.as_ref(), path))));
+    }
+    if let Some(mask) = mask {
+        steps.push((shutdown::Output::Mask, Box::new(move || {
+            let mask_path = output_sibling(opt.primary_output(), "mask");
+            match mask.save(&mask_path) {
+                Ok(()) => {
+                    info!("Render was interrupted. Pixels that were rendered are marked in {}",
+                              mask_path.display());
+                    true
+                },
+                Err(err) => {
+                    error!("Unable to write {}: {}", mask_path.display(), err);
+                    false
+                },
+            }
+        })));
+    }
+    if let (Some(path), Some(render_stats)) = (opt.tile_time_map.as_ref(), render_stats.as_ref()) {
+        let map = tile_time_map(render_stats);
+        steps.push((shutdown::Output::Manifest, Box::new(move || {
+            match map.save(path) {
+                Ok(()) => {
+                    report_written(path);
+                    true
+                },
+                Err(err) => {
+                    error!("Unable to write {}: {}", path.display(), err);
+                    false
+                },
+            }
+        })));
+    }
+    if let (Some(path), Some(render_stats)) = (opt.stats_json.as_ref(), render_stats) {
+        steps.push((shutdown::Output::Manifest, Box::new(move || {
+            match render_stats.save(path) {
+                Ok(()) => {
+                    report_written(path);
+                    true
+                },
+                Err(err) => {
+                    error!("Unable to write {}: {}", path.display(), err);
+                    false
+                },
+            }
+        })));
+    }
+    if let Some(path) = opt.variance_map.as_ref() {
+        let map = accum.noise_map();
+        steps.push((shutdown::Output::Aovs, Box::new(move || {
+            match map.save(path) {
+                Ok(()) => {
+                    report_written(path);
+                    true
+                },
+                Err(err) => {
+                    error!("Unable to write {}: {}", path.display(), err);
+                    false
+                },
+            }
+        })));
+    }
+    if !aovs.is_empty() {
+        steps.push((shutdown::Output::Aovs, Box::new(move || {
+            let mut ok = true;
+            for (aov, img) in aovs.iter() {
+                let path = match (aov, &opt.heatmap) {
+                    (Aov::Bounces, Some(heatmap)) => heatmap.clone(),
+                    _ => output_sibling(opt.primary_output(), aov.name()),
+                };
+                if let Err(err) = img.save(&path) {
+                    error!("Unable to write {}: {}", path.display(), err);
+                    ok = false;
+                }
+            }
+            ok
+        })));
+    }
 
-    // If we can't open SDL (e.g. no video device), fail elegantly
-    if let Err(err) = show_window(&imgbuf) {
-        eprintln!("Failed to open SDL window: {:#?}", err);
+    let grace = opt.grace_seconds.map(time::Duration::from_secs);
+    for (output, outcome) in shutdown::write_outputs(steps, || shutdown::past_deadline(grace)) {
+        match outcome {
+            shutdown::Outcome::Written => {},
+            shutdown::Outcome::Failed => saved = false,
+            shutdown::Outcome::Skipped => {
+                info!("Out of time after being stopped, so skipped the {}", output.name());
+            },
+        }
     }
+    // The real thing is here, so any snapshot (from --snapshot-interval, or
+    // pressing s) isn't useful anymore.
+    let _ = std::fs::remove_file(output_sibling(opt.primary_output(), "partial"));
+
+    saved
 }
 
-fn show_window(image: &image::RgbImage) -> Result<(), Box<dyn std::error::Error>> {
+/// Opens a window and keeps it open until it's closed.
+/// `next_frame` is polled regularly, and whatever it returns is displayed.
+/// Camera controls are passed along to `on_input`.
+#[cfg(feature = "window")]
+fn show_window(width:          u32,
+               height:         u32,
+               mut next_frame: impl FnMut() -> Option<image::RgbImage>,
+               mut on_input:   impl FnMut(fly_camera::Input))
+    -> Result<(), Box<dyn std::error::Error>>
+{
     use sdl2::{
         pixels::PixelFormatEnum,
         render::TextureAccess,
@@ -238,13 +2007,14 @@ fn show_window(image: &image::RgbImage) -> Result<(), Box<dyn std::error::Error>
         keyboard::Keycode,
         rect::Rect,
     };
+    use self::fly_camera::Input;
 
     let sdl_ctx = sdl2::init()?;
     let video = sdl_ctx.video()?;
 
     let mut canvas;
     {
-        let window = video.window("yo", image.width(), image.height())
+        let window = video.window("yo", width, height)
             .position_centered()
             .build()?;
         canvas = window.into_canvas().build()?;
@@ -256,34 +2026,58 @@ fn show_window(image: &image::RgbImage) -> Result<(), Box<dyn std::error::Error>
     let mut buffer = tc.create_texture(
         PixelFormatEnum::RGBX8888,
         TextureAccess::Streaming,
-        image.width(),
-        image.height()
+        width,
+        height
     )?;
-
-    // Write and flush the image buffer into it
-    let full_image = Rect::new(0, 0, image.width(), image.height());
-    buffer.with_lock(full_image, |bytes: &mut [u8], _pitch_in_bytes: usize| {
-        // Note: "bytes" here is **write only**.
-        //       Values read have no guaranetees.
-        assert_eq!(bytes.len() % 4, 0, "bytes should fit 32-bit values exactly");
-        for (dst, src) in bytes.chunks_exact_mut(4).zip(image.pixels()) {
-            let (r, g, b, x) = (src.data[0], src.data[1], src.data[2], 0);
-            dst[0] = x;
-            dst[1] = b;
-            dst[2] = g;
-            dst[3] = r;
-        }
-    })?;
+    let full_image = Rect::new(0, 0, width, height);
 
     let mut event_pump = sdl_ctx.event_pump()?;
     'running:
     loop {
+        // Write and flush the image buffer into it
+        if let Some(image) = next_frame() {
+            buffer.with_lock(full_image, |bytes: &mut [u8], _pitch_in_bytes: usize| {
+                // Note: "bytes" here is **write only**.
+                //       Values read have no guaranetees.
+                assert_eq!(bytes.len() % 4, 0, "bytes should fit 32-bit values exactly");
+                for (dst, src) in bytes.chunks_exact_mut(4).zip(image.pixels()) {
+                    let (r, g, b, x) = (src.data[0], src.data[1], src.data[2], 0);
+                    dst[0] = x;
+                    dst[1] = b;
+                    dst[2] = g;
+                    dst[3] = r;
+                }
+            })?;
+        }
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
+                Event::KeyDown { keycode: Some(key), .. } => {
+                    let steps = match key {
+                        Keycode::W => Float3::xyz( 0.,  0.,  1.),
+                        Keycode::S => Float3::xyz( 0.,  0., -1.),
+                        Keycode::A => Float3::xyz(-1.,  0.,  0.),
+                        Keycode::D => Float3::xyz( 1.,  0.,  0.),
+                        Keycode::E => Float3::xyz( 0.,  1.,  0.),
+                        Keycode::Q => Float3::xyz( 0., -1.,  0.),
+                        Keycode::P => {
+                            on_input(Input::Print);
+                            continue;
+                        },
+                        _ => continue,
+                    };
+                    on_input(Input::Move(steps));
+                },
+                Event::MouseMotion { mousestate, xrel, yrel, .. } if mousestate.left() => {
+                    on_input(Input::Rotate(xrel as Float, yrel as Float));
+                },
+                Event::MouseWheel { y, .. } => {
+                    on_input(Input::Zoom(y as Float));
+                },
                 _ => {
                 }
             }
@@ -295,401 +2089,778 @@ fn show_window(image: &image::RgbImage) -> Result<(), Box<dyn std::error::Error>
         }
         canvas.present();
 
-        const DELAY: time::Duration = time::Duration::from_millis(1000);
+        // Short enough to keep up with previews as they come in.
+        const DELAY: time::Duration = time::Duration::from_millis(100);
         std::thread::sleep(DELAY);
     };
 
     Ok(())
 }
 
-fn write_image(opt: &Opt) -> image::RgbImage {
-    let ns: u32 = opt.samples_per_pixel;
-    let nx: u32 = opt.width;
-    let ny: u32 = opt.height;
-
-    let (tiles_x, tiles_y) = pick_tiling_dimensions(opt.tiles, nx, ny);
-
-    assert_eq!(nx % tiles_x, 0, "I'll solve this later");
-    assert_eq!(ny % tiles_y, 0, "I'll solve this later");
-
-    // Width of each tile in pixels.
-    let tile_nx = nx / tiles_x;
-    // Height of each tile in pixels.
-    let tile_ny = ny / tiles_y;
+#[cfg(not(feature = "window"))]
+fn show_window(_width:      u32,
+               _height:     u32,
+               _next_frame: impl FnMut() -> Option<image::RgbImage>,
+               _on_input:   impl FnMut(fly_camera::Input))
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    Err("This build does not support windows. Rebuild with --features=window".into())
+}
 
-    let cam = Camera::new(CameraInfo {
-        lookfrom:   Float3::xyz(13., 2., 3.),
-        lookat:     Float3::xyz(0., 0., 0.),
+/// The camera described by the CLI options.
+fn camera_info(opt: &Opt) -> CameraInfo {
+    CameraInfo {
+        lookfrom:   opt.lookfrom,
+        lookat:     opt.lookat,
         up:         Float3::xyz(0., 1., 0.),
-        vfov:       opt.vfov,
-        aspect:     nx as Float / ny as Float,
+        projection: match opt.projection {
+            ProjectionKind::Perspective => Projection::Perspective { vfov: opt.vfov },
+            ProjectionKind::Orthographic => Projection::Orthographic { height: opt.ortho_height },
+            ProjectionKind::Equirectangular => Projection::Equirectangular,
+            ProjectionKind::Fisheye => Projection::Fisheye { fov: opt.fov },
+        },
+        aspect:     opt.width as Float / opt.height() as Float,
         aperature:  opt.aperature,
         focus_dist: opt.focus_dist,
         t_start:    opt.t_start,
         t_end:      opt.t_end,
+    }
+}
+
+/// How tall an image `width` wide should be. Without a `height`, `aspect`
+/// works it out, rounded to an even number of pixels.
+fn image_height(width: u32, height: Option<u32>, aspect: Option<Float>) -> Result<u32, String> {
+    let from_aspect = aspect.map(|aspect| {
+        (2.0 * (width as Float / aspect / 2.0).round()).max(2.0) as u32
     });
+    match (height, from_aspect) {
+        (Some(height), Some(from_aspect)) if height != from_aspect => {
+            Err(format!("--width {} and --height {} don't match --aspect, which would \
+                         make it {} tall. Leave one of them out",
+                        width, height, from_aspect))
+        },
+        (Some(height), _) => Ok(height),
+        (None, Some(from_aspect)) => Ok(from_aspect),
+        (None, None) => Ok(800),
+    }
+}
 
-    let mut multi_progress = pbr::MultiBar::new();
+/// A camera for `info`, with the lens that the CLI options describe.
+fn camera(opt: &Opt, info: CameraInfo) -> Camera {
+    let aperture = Aperture::from_blades(opt.aperture_blades, opt.aperture_rotation)
+                       .expect("--aperture-blades is checked on startup");
+    Camera::new(info).with_aperture(aperture)
+}
 
-    // Each tile represents a subimage of (tile_nx, tile_ny) pixels.
-    // They are combined after ray tracing.
-    let mut tiles: Vec<Tile> = vec![];
-    for tile_id in 0..(tiles_x * tiles_y) {
-        // Tile coordinates. Must be translated into pixels with tile_n{x,y}.
-        let x = tile_id % tiles_x;
-        let y = tile_id / tiles_x;
+/// See `Opt::depth_range`.
+fn depth_range(opt: &Opt) -> (Float, Float) {
+    opt.depth_range.unwrap_or_else(|| {
+        (0.0, 2.0 * (opt.lookat - opt.lookfrom).length())
+    })
+}
 
-        // When we're set to skip tiles, don't even enqueue it.
-        if opt.checkerboard_tiles {
-            if x % 2 != y % 2 {
-                continue;
-            }
+/// Puts the checkpoints of a render split up with --tile-range back together,
+/// or averages them if they're each of the whole image, and writes the image
+/// to `outputs`. Returns whether they were all written.
+fn merge_parts(parts: &[path::PathBuf], average: bool, outputs: &[path::PathBuf]) -> bool {
+    let combine = if average { checkpoint::average } else { checkpoint::merge };
+    let linear = match checkpoint::combine_files(parts, combine) {
+        Ok(merged) => merged.to_linear(),
+        Err(msg) => {
+            error!("{}", msg);
+            return false;
+        },
+    };
+    let mut ok = true;
+    for (path, result) in output::write_all(&linear, outputs, None, None) {
+        match result {
+            Ok(()) => report_written(path),
+            Err(msg) => {
+                error!("{}", msg);
+                ok = false;
+            },
         }
+    }
+    ok
+}
 
-        let pixels = image::RgbImage::new(tile_nx, tile_ny);
-        let pixel_total = pixels.width() as u64 * pixels.height() as u64;
+/// Saves `accum` to `path`, complaining (but carrying on) if we can't.
+/// Returns whether it was saved.
+fn save_checkpoint(accum:    &Accumulator,
+                   settings: &RenderSettings,
+                   tiles:    Option<&checkpoint::TileRange>,
+                   path:     &path::Path)
+    -> bool
+{
+    let mut checkpoint = accum.to_checkpoint(settings);
+    checkpoint.tiles = tiles.cloned();
+    match checkpoint.save(path) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("Failed to write checkpoint to {}: {}", path.display(), err);
+            false
+        },
+    }
+}
 
-        let mut progress = multi_progress.create_bar(pixel_total);
-        progress.message(&format!("Tile {:>2} ({}, {}): ", tile_id, x, y));
-        progress.format("[=> ]");
-        progress.set_max_refresh_rate(Some(time::Duration::from_millis(700)));
+/// Renders the image like `write_image()`, stopping at each rung of
+/// `--spp-ladder` on the way to hand `on_rung` the image so far.
+/// Samples are seeded by their index, so carrying on from one rung to the next
+/// gives the same image as rendering with that many samples from scratch.
+fn write_image_ladder(opt:         &Opt,
+                      world:       &Scene,
+                      cam:         &Camera,
+                      settings:    &RenderSettings,
+                      accum:       &Arc<Accumulator>,
+                      framebuffer: &Arc<snapshot::Framebuffer>,
+                      mut on_rung: impl FnMut(u32, &Frame))
+    -> Frame
+{
+    let mut rungs = opt.spp_ladder.clone();
+    rungs.sort();
+    rungs.dedup();
+
+    let mut rung_stats = vec![];
+    for &spp in rungs.iter().filter(|&&spp| spp < opt.samples_per_pixel) {
+        info!("Rendering up to {} of {} samples per pixel", spp, opt.samples_per_pixel);
+        let frame = write_image(opt, world, cam, settings, accum, framebuffer, spp);
+        if needs_to_exit() {
+            return frame;
+        }
+        on_rung(spp, &frame);
+        rung_stats.extend(frame.stats);
+    }
 
-        tiles.push(Tile {
-            tile_id,
-            tile_x: x,
-            tile_y: y,
-            offset_x: x * tile_nx,
-            offset_y: y * tile_ny,
-            pixels,
-            progress,
-        });
+    let mut frame = write_image(opt, world, cam, settings, accum, framebuffer,
+                                opt.samples_per_pixel);
+    if let Some(ref mut stats) = frame.stats {
+        for rung in rung_stats.iter() {
+            stats.add_layer(rung);
+        }
     }
+    if rungs.contains(&opt.samples_per_pixel) && !needs_to_exit() {
+        on_rung(opt.samples_per_pixel, &frame);
+    }
+    frame
+}
 
-    // Load the scene
-    let world = make_cover_scene();
+/// Renders the image in passes over every pixel, each taking a few more
+/// samples than the last, until every pixel has `opt.samples_per_pixel` of
+/// them or `budget` runs out. Running out partway through a pass leaves some
+/// pixels with more samples than others, but every pixel is the average of
+/// however many it has.
+fn write_image_budgeted(opt:         &Opt,
+                        world:       &Scene,
+                        cam:         &Camera,
+                        settings:    &RenderSettings,
+                        accum:       &Arc<Accumulator>,
+                        framebuffer: &Arc<snapshot::Framebuffer>,
+                        budget:      time::Duration)
+    -> Frame
+{
+    let deadline = snapshot::Periodic::spawn(budget, || {
+        OUT_OF_TIME.store(true, atomic::Ordering::SeqCst);
+    });
 
-    // Sanity check the progress bars.
-    // If we're doing checkboarded tiles, we don't care since it would
-    // fail anyway.
-    if !opt.checkerboard_tiles {
-        let pb_count: u64 = tiles.iter().map(|t| t.progress.total).sum();
-        let px_count: u64 = (nx * ny) as u64;
-        assert_eq!(pb_count, px_count,
-                "The progress bars don't agree on how many pixels there are!");
+    // Passes grow with the samples we already have, so that there aren't too
+    // many of them, but never by more than a quarter, so that a pass cut
+    // short doesn't leave some pixels much better off than the rest.
+    let mut pass_stats = vec![];
+    let (mut spp, _) = accum.sample_range();
+    let mut frame = loop {
+        spp = (spp + (spp / 4).max(1)).min(opt.samples_per_pixel);
+        let frame = write_image(opt, world, cam, settings, accum, framebuffer, spp);
+        if needs_to_stop() || spp >= opt.samples_per_pixel {
+            break frame;
+        }
+        pass_stats.extend(frame.stats);
+    };
+    deadline.finish();
+    let out_of_time = OUT_OF_TIME.swap(false, atomic::Ordering::SeqCst);
+
+    let (fewest, most) = accum.sample_range();
+    if let Some(ref mut stats) = frame.stats {
+        for pass in pass_stats.iter() {
+            stats.add_layer(pass);
+        }
+        stats.samples_per_pixel = fewest;
+        let spp = if fewest == most {
+            fewest.to_string()
+        } else {
+            format!("{} to {}", fewest, most)
+        };
+        info!("Rendered {} samples per pixel in {:.3}s{}",
+                  spp,
+                  stats.render_secs,
+                  if out_of_time { ", when time ran out" } else { "" });
     }
+    frame
+}
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(opt.jobs as usize)
-        .build_global()
-        .expect("Unexpected failure with rayon::ThreadPoolBuilder");
-    eprintln!("Rendering on {} threads\n", rayon::current_num_threads());
+/// Renders the image, adding samples to `accum` until every pixel has
+/// `samples_per_pixel` of them.
+fn write_image(opt:               &Opt,
+               world:             &Scene,
+               cam:               &Camera,
+               settings:          &RenderSettings,
+               accum:             &Arc<Accumulator>,
+               framebuffer:       &Arc<snapshot::Framebuffer>,
+               samples_per_pixel: u32)
+    -> Frame
+{
+    let nx: u32 = opt.width;
+    let ny: u32 = opt.height();
+
+    let plan = TilingPlan::new(opt.tiles, rayon::current_num_threads() as u32, nx, ny);
+    let pixel_aovs = PixelAovs::new(&opt.aov)
+        .with_depth_range(depth_range(opt))
+        .with_max_bounces(opt.max_depth)
+        .with_stride(opt.aov_stride);
+    let render = TiledRender::new(plan, settings, opt.tile_order, accum, pixel_aovs,
+                                  |tile_id, x, y| tile_selected(opt, tile_id, x, y));
+
+    // Quiet renders don't show any bars, or have threads waiting on them.
+    let quiet = !log::log_enabled!(log::Level::Info);
+    let mut multi_progress = match opt.progress {
+        _ if quiet => None,
+        // Lines about the total are all that work without a terminal.
+        _ if !progress::stderr_is_terminal() => None,
+        ProgressStyle::Total => None,
+        ProgressStyle::PerTile => Some(pbr::MultiBar::new()),
+    };
 
-    let h_listener = std::thread::spawn(move || {
+    // A visual indicator of progress on rendering each tile's sub image.
+    // Only with `--progress per-tile`.
+    let bars: Vec<Mutex<Option<pbr::ProgressBar<pbr::Pipe>>>> = render.tiles.iter().map(|tile| {
+        Mutex::new(multi_progress.as_mut().map(|multi_progress| {
+            let pixel_total = tile.pixels.width() as u64 * tile.pixels.height() as u64;
+            let mut progress = multi_progress.create_bar(pixel_total);
+            progress.message(&format!("Tile {:>2} ({}, {}): ",
+                                      tile.tile_id, tile.tile_x, tile.tile_y));
+            progress.format("[=> ]");
+            progress.set_max_refresh_rate(Some(time::Duration::from_millis(700)));
+            progress
+        }))
+    }).collect();
+
+    // Every tile adds the pixels it finishes to this. Skipped tiles never
+    // will, so they don't count toward the total.
+    let pixels_done = Arc::new(progress::PixelCounter::new(render.pixels()));
+
+    // Sanity check the tiles.
+    // If we're skipping tiles, we don't care since it would fail anyway.
+    if !opt.checkerboard_tiles && opt.tile_range.is_none() {
+        let px_count: u64 = (nx * ny) as u64;
+        assert_eq!(pixels_done.total(), px_count,
+                "The tiles don't agree on how many pixels there are!");
+    }
+
+    let total_bar = match multi_progress {
+        None if !quiet => {
+            let interval = time::Duration::from_secs(opt.progress_interval);
+            Some(progress::TotalBar::spawn(pixels_done.clone(), interval))
+        },
+        _ => None,
+    };
+    let h_listener = multi_progress.map(|mut multi_progress| {
         // This blocks, so we run it on a separate thread.
-        multi_progress.listen();
+        std::thread::spawn(move || multi_progress.listen())
     });
 
-    let before_render = time::Instant::now();
-    tiles.par_iter_mut().for_each(|tile: &mut Tile| {
-        'per_pixel:
-        for (x, y, pixel) in tile.pixels.enumerate_pixels_mut() {
-            // Adjust the (x, y) coordinates wrt our tile.
-            let x = x + tile.offset_x;
-            // Go through `y` "backwards"
-            let y = ny - (y + tile.offset_y) + 1;
-
-            let mut rgb = Float3::default();
-
-            // AA through many samples.
-            // We divide by `sample`, so it must not start at zero.
-            for sample in 1..(ns+1) {
-                let u = (x as Float + random_sfloat()) / nx as Float;
-                let v = (y as Float + random_sfloat()) / ny as Float;
-                let ray = cam.get_ray(u, v);
-
-                rgb += color(&ray, &world, 0);
-
-                // Sanity checks - no pixels are allowed outside of the range [0, 1]
-                // Since we accumulate `ns` samples, each within that range,
-                // the valid range at any point in the process is [0, sample].
-                debug_assert!(0.0 <= rgb.x && rgb.x <= sample as Float,
-                              "({}, {}) #{} rgb = {:?}",
-                              x, y, sample, rgb / sample);
-                debug_assert!(0.0 <= rgb.y && rgb.y <= sample as Float,
-                              "({}, {}) #{} rgb = {:?}",
-                              x, y, sample, rgb / sample);
-                debug_assert!(0.0 <= rgb.z && rgb.z <= sample as Float,
-                              "({}, {}) #{} rgb = {:?}",
-                              x, y, sample, rgb / sample);
-            }
-            // Average samples
-            rgb /= ns;
-            // Gamma correct
-            rgb = rgb.sqrt();
-            // Scale into u8 range
-            rgb *= 255.99;
-            *pixel = image::Rgb([
-                rgb.x as u8,
-                rgb.y as u8,
-                rgb.z as u8,
-            ]);
-
-            tile.progress.inc();
-
-            if needs_to_exit() {
-                break 'per_pixel;
-            }
+    // Tiles also copy their pixels out to `framebuffer` as they go, so that
+    // we can show and save snapshots of the whole image while it renders.
+    for tile in render.tiles.iter() {
+        for (tile_x, tile_y, pixel) in tile.pixels.enumerate_pixels() {
+            framebuffer.put_pixel(tile.offset_x + tile_x, tile.offset_y + tile_y, *pixel);
         }
-        tile.progress.finish();
+    }
+    let snapshots = opt.snapshot_interval.map(|secs| {
+        snapshot::spawn_snapshots(framebuffer.clone(),
+                                  time::Duration::from_secs(secs),
+                                  output_sibling(opt.primary_output(), "partial"))
     });
-    let render_time = before_render.elapsed();
 
-    match h_listener.join() {
-        Ok(()) => {},
-        Err(ref err) => {
-            eprintln!("Error joining progress bar listener thread: {:#?}", err);
-            // We ignore this error because... what else are we going to do?
-        },
+    // Progress goes back into the checkpoint we resumed from, unless we're
+    // told otherwise.
+    let checkpoint_path = opt.checkpoint.as_ref().or(opt.resume.as_ref()).cloned();
+    let checkpoints = checkpoint_path.map(|path| {
+        let accum = accum.clone();
+        let settings = settings.clone();
+        let tiles = tile_part(opt);
+        snapshot::Periodic::spawn(time::Duration::from_secs(opt.checkpoint_interval), move || {
+            save_checkpoint(&accum, &settings, tiles.as_ref(), &path);
+        })
+    });
+
+    if log::log_enabled!(log::Level::Debug) || opt.stats_json.is_some() {
+        stats::enable(true);
+        // Counts from an earlier render (e.g. another focus distance) don't
+        // belong to this one.
+        stats::take_totals();
     }
-    // Tasteful empty space.
-    println!("");
-
-    let secs = render_time.as_secs() as f64
-               + render_time.subsec_millis() as f64 / 1e3;
-    eprintln!("Full scene render time: {:.3}s", secs);
-
-    // Combine the tiles into the final image, which we write to disk.
-    let mut imgbuf = image::RgbImage::new(nx, ny);
-    for tile in tiles {
-        let ok = imgbuf.copy_from(&tile.pixels, tile.offset_x, tile.offset_y);
-        assert_eq!(ok, true,
-                  concat!("imgbuf::copy_from() failed. ",
-                          "Is ({}, {}) out of bounds? Bounds are ({}, {})."),
-                  tile.offset_x + tile.pixels.width(),
-                  tile.offset_y + tile.pixels.height(),
-                  imgbuf.width(),
-                  imgbuf.height());
-    }
-
-    imgbuf
-}
-
-fn color(ray: &Ray, world: &dyn Hitable, depth: u32) -> Float3 {
-    if let Some(hit_record) = world.hit(ray, 1.0e-3, std::f64::MAX as Float) {
-        let mut scattered = Ray::default();
-        let mut attenuation = Float3::new();
-        if depth < MAX_RAY_RECURSION &&
-           hit_record.material.scatter(ray,
-                                       &hit_record,
-                                       &mut attenuation,
-                                       &mut scattered)
-        {
-            attenuation * color(&scattered, world, depth + 1)
-        } else if depth == MAX_RAY_RECURSION {
-            Float3::xyz(1., 0., 1.)
-        } else {
-            // If scatter hit something, but doesn't produce more rays,
-            // just return the attenuation.
-            attenuation.abs()
+
+    let on_progress = |progress: TileProgress| match progress {
+        TileProgress::Pixel { x, y, pixel } => {
+            framebuffer.put_pixel(x, y, pixel);
+            control::RENDER.wait_while_paused();
+        },
+        TileProgress::Row { tile, pixels, rows_left } => {
+            pixels_done.add(pixels as u64);
+            let mut bar = bars[tile].lock().unwrap();
+            if let Some(progress) = bar.as_mut() {
+                progress.add(pixels as u64);
+                if rows_left == 0 {
+                    progress.finish();
+                }
+            }
+            if rows_left == 0 {
+                // Finished bars shouldn't be finished again below.
+                bar.take();
+            }
+        },
+    };
+    let frame = render.render(world, cam, settings, accum, samples_per_pixel, &on_progress);
+
+    // Tiles we never got to because we were interrupted.
+    for bar in bars {
+        if let Some(mut progress) = bar.into_inner().unwrap() {
+            progress.finish();
         }
-    } else {
-        // Linearly blend white and blue, depending on the "up" or
-        // "downn"ness of the y coordinate.
-        let white = Float3::xyz(1., 1., 1.);
-        let blue = Float3::xyz(0.5, 0.7, 1.0);
-
-        let t = 0.5 * (1.0 + ray.dir.unit().y);
-        Float3::lerp(t, white, blue)
-    }
-}
-
-#[allow(dead_code)]
-fn make_green_scene() -> HitableList {
-    HitableList {
-        hitables: vec![
-            Box::new(Sphere {
-                center: Float3::xyz(0., 0., -1.),
-                radius: 0.5,
-                material: Arc::new(Lambertian {
-                    albedo: Float3::xyz(0.1, 0.2, 0.5),
-                }),
-            }),
-            Box::new(Sphere {
-                center: Float3::xyz(0.0, -100.5, -1.0),
-                radius: 100.0,
-                material: Arc::new(Lambertian {
-                    albedo: Float3::xyz(0.8, 0.8, 0.0),
-                }),
-            }),
-            Box::new(Sphere {
-                center: Float3::xyz(1., 0., -1.),
-                radius: 0.5,
-                material: Arc::new(Metal {
-                    albedo: Float3::xyz(0.8, 0.6, 0.2),
-                    fuzz:   0.0,
-                }),
-            }),
-            Box::new(Sphere {
-                center: Float3::xyz(-1., 0., -1.),
-                radius: 0.5,
-                material: Arc::new(Dielectric {
-                    refraction_index: 1.5,
-                }),
-            }),
-            Box::new(Sphere {
-                center: Float3::xyz(-1., 0., -1.),
-                radius: -0.45, // Negative radius makes the above sphere hollow.
-                material: Arc::new(Dielectric {
-                    refraction_index: 1.5,
-                }),
-            }),
-        ],
-    }
-}
-
-fn make_cover_scene() -> HitableList {
-    // Sigh... All of this to hash two strings into 128-bits. ._.
-    //
-    // `mem::transmute` is unsafe in general because many types don't appreciate
-    // arbitary bit patterns being operated on like they're that type.
-    // We're transmuting from two primitives types, so this is fine.
-    // All possible 128-bit patterns for [u8; 16] are valid here.
-    let hash_bytes: [u8; 16] = unsafe {
-        // TODO: When `u64::to_be_bytes` and friends stabilize, we can use those.
-        //       See https://github.com/rust-lang/rust/issues/52963
-        // The advantage of that will be consistency across endian platforms.
-        mem::transmute([
-            hash_it(b"Katy's Penguin"),
-            hash_it(b"Alyssa's Panda"),
-        ])
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.finish();
+    }
+    // Whether we finished or were interrupted, `render_and_save()` saves
+    // where we got to, once the image is written.
+    if let Some(checkpoints) = checkpoints {
+        checkpoints.finish();
+    }
+
+    if !needs_to_stop() {
+        assert_eq!(pixels_done.done(), pixels_done.total(),
+                   "Every pixel we set out to render should have been counted");
+    }
+    if let Some(total_bar) = total_bar {
+        total_bar.finish();
+    }
+    if let Some(Err(ref err)) = h_listener.map(|h| h.join()) {
+        error!("Joining the progress bar listener thread failed: {:#?}", err);
+        // We ignore this error because... what else are we going to do?
+    }
+
+    if let Some(ref render_stats) = frame.stats {
+        let secs = render_stats.render_secs;
+        // With tasteful empty space after the progress bar.
+        info!("\nFull scene render time: {:.3}s", secs);
+        debug!("{}", render_stats.counts.report(secs));
+    }
+    frame
+}
+
+#[cfg(test)]
+mod t {
+    use crate::*;
+
+    use one_weekend::material::*;
+    use one_weekend::render::{
+        DEFAULT_MAX_DEPTH,
+        DEFAULT_THROUGHPUT_CUTOFF,
     };
-    let mut rng = SmallRng::from_seed(hash_bytes);
+    use one_weekend::scenes::make_small_light_box;
+
+    #[test]
+    fn check_subcommands() {
+        let parse = |args: &[&str]| Cli::from_iter_safe(["one-weekend"].iter().chain(args));
+
+        // Rendering needs no subcommand, like it always has.
+        let cli = parse(&["-w", "400", "-q", "--scene", "cornell"]).unwrap();
+        assert!(cli.cmd.is_none());
+        assert_eq!((cli.render.width, cli.render.scene), (400, SceneKind::Cornell));
+        assert!(cli.quiet);
+
+        // Shared options go before or after the subcommand.
+        let orders = [&["-j", "2", "render", "-w", "300"], &["render", "-w", "300", "-j", "2"]];
+        for args in orders.iter() {
+            match parse(&args[..]).unwrap() {
+                Cli { jobs: 2, cmd: Some(Command::Render(opt)), .. } => {
+                    assert_eq!(opt.width, 300)
+                },
+                other => panic!("{:?}: {:?}", args, other),
+            }
+        }
+        match parse(&["bench", "--runs", "5", "-s", "2"]).unwrap().cmd {
+            Some(Command::Bench { runs: 5, opt }) => assert_eq!(opt.samples_per_pixel, 2),
+            other => panic!("{:?}", other),
+        }
+        match parse(&["diff", "a.png", "b.bin", "--threshold", "0.5"]).unwrap().cmd {
+            Some(Command::Diff { a, b, out: None, threshold }) => {
+                assert_eq!((a.to_str(), b.to_str()), (Some("a.png"), Some("b.bin")));
+                assert_eq!(threshold, 0.5);
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(parse(&["diff", "a.png"]).is_err());
+        assert!(parse(&["list-scenes", "-w", "400"]).is_err());
+    }
 
-    // Our accelaration structure is a list of spheres.
-    let mut spheres: Vec<Box<dyn Hitable>> = vec![];
+    #[test]
+    fn check_image_height() {
+        let parse = |args: &[&str]| {
+            let opt = Opt::from_iter_safe(["one-weekend"].iter().chain(args)).unwrap();
+            image_height(opt.width, opt.height, opt.aspect)
+        };
+        assert_eq!(parse(&["--width", "800", "--aspect", "2:1"]), Ok(400));
+        assert_eq!(parse(&["--width", "1920", "--aspect", "16:9"]), Ok(1080));
+        assert_eq!(parse(&["--width", "1000", "--aspect", "1.5"]), Ok(666));
+        // Always an even number, and never nothing.
+        assert_eq!(parse(&["--width", "101", "--aspect", "1"]), Ok(102));
+        assert_eq!(parse(&["--width", "1", "--aspect", "16:9"]), Ok(2));
+        assert_eq!(parse(&["--height", "300"]), Ok(300));
+        assert_eq!(parse(&[]), Ok(800));
+
+        // All three are fine when they agree, and an error when they don't.
+        assert_eq!(parse(&["--width", "800", "--height", "400", "--aspect", "2:1"]), Ok(400));
+        assert!(parse(&["--width", "800", "--height", "600", "--aspect", "2:1"]).is_err());
+        assert!(parse(&["--height", "800", "--aspect", "16:9"]).is_err());
+        assert!(Opt::from_iter_safe(&["one-weekend", "--aspect", "wide"]).is_err());
+    }
 
-    // A giant, darkish colored sphere to act as the floor.
-    let ground = Box::new(Sphere {
-        center: Float3::xyz(0., -1000., 0.),
-        radius: 1000.0,
-        material: Arc::new(Lambertian {
-            albedo: Float3::xxx(0.5),
-        })
-    });
-    spheres.push(ground);
+    /// Options as if they came from the command line defaults.
+    fn test_opt(width: u32, height: u32, samples_per_pixel: u32) -> Opt {
+        Opt {
+            width,
+            height:                 Some(height),
+            aspect:                 None,
+            samples_per_pixel,
+            tiles:                  0,
+            tile_order:             tile_order::TileOrder::Scanline,
+            filter:                 filter::FilterKind::Box,
+            progress:               ProgressStyle::Total,
+            output:                 vec!["output.png".into()],
+            lookfrom:               Float3::xyz(13., 2., 3.),
+            lookat:                 Float3::xyz(0., 0., 0.),
+            vfov:                   20.,
+            projection:             ProjectionKind::Perspective,
+            ortho_height:           4.,
+            fov:                    180.,
+            aperature:              0.1,
+            aperture_blades:        0,
+            aperture_rotation:      0.,
+            focus_dist:             10.,
+            t_start:                0.,
+            t_end:                  0.5,
+            focus_stack:            None,
+            focus_range:            None,
+            frames:                 None,
+            verify_determinism:     None,
+            turntable:              None,
+            camera_path:            None,
+            spp_ladder:             vec![],
+            max_seconds:            None,
+            aov:                    vec![],
+            aov_stride:             1,
+            heatmap:                None,
+            variance_map:           None,
+            raw_output:             None,
+            depth_range:            None,
+            snapshot_interval:      None,
+            grace_seconds:          None,
+            seed:                   None,
+            checkpoint:             None,
+            checkpoint_interval:    60,
+            progress_interval:      10,
+            resume:                 None,
+            lut:                    None,
+            throughput_cutoff:      DEFAULT_THROUGHPUT_CUTOFF,
+            max_depth:              DEFAULT_MAX_DEPTH,
+            despeckle_factor:       despeckle::DEFAULT_FACTOR,
+            scene:                  SceneKind::Cover,
+            list_scenes:            false,
+            dump_scene:             None,
+            pack_spheres:           false,
+            bvh_builder:            bvh::BvhBuilder::Median,
+            objects:                None,
+            extent:                 None,
+            scene_file:             None,
+            scene_seed:             None,
+            scene_params:           vec![],
+            asset_cache_size:       assets::DEFAULT_BUDGET_MB,
+            stats_json:             None,
+            tile_time_map:          None,
+            tile_times:             false,
+            interactive:            false,
+            watch:                  false,
+            profile_objects:        false,
+            draw_axes:              false,
+            transparent_background: false,
+            background:             None,
+            debug:                  None,
+            despeckle:              false,
+            denoise:                false,
+            checkerboard_tiles:     false,
+            tile_range:             None,
+            save_focus_layers:      false,
+        }
+    }
 
-    // This material can be reused, since its parameters don't change
-    // between spheres.
-    let dielectric = Arc::new(Dielectric {
-        refraction_index: 1.5,
-    });
+    #[test]
+    fn check_tile_range_conflicts() {
+        let part = Opt {
+            tiles:      6,
+            tile_range: Some(2..6),
+            checkpoint: Some("part.ckpt".into()),
+            ..test_opt(20, 12, 2)
+        };
+        assert_eq!(check_tile_range(&test_opt(20, 12, 2)), Ok(()));
+        assert_eq!(check_tile_range(&part), Ok(()));
+        assert!(check_tile_range(&Opt { tiles: 0, ..part.clone() }).is_err());
+        assert!(check_tile_range(&Opt { checkpoint: None, ..part.clone() }).is_err());
+        assert!(check_tile_range(&Opt { tile_range: Some(2..7), ..part.clone() }).is_err());
+        assert!(check_tile_range(&Opt { watch: true, ..part }).is_err());
+    }
+
+    #[test]
+    fn check_animation_frames_move() {
+        // A glowing ball rolling left to right across the view.
+        let scene = Scene {
+            world: HitableList {
+                hitables: vec![
+                    Box::new(MovingSphere {
+                        sphere: Sphere {
+                            center:   Float3::xyz(-1.5, 0., 0.),
+                            radius:   0.4,
+                            material: Arc::new(DiffuseLight { emit: Float3::xxx(1.) }),
+                        },
+                        motion: Float3::xyz(3., 0., 0.),
+                    }),
+                ],
+            },
+            background: Background::Black,
+            ..Scene::default()
+        };
+        let settings = RenderSettings {
+            width:             24,
+            height:            12,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "moving-sphere".into(),
+        };
+        let opt = Opt {
+            lookfrom:   Float3::xyz(0., 0., 5.),
+            lookat:     Float3::new(),
+            vfov:       60.,
+            aperature:  0.,
+            focus_dist: 5.,
+            t_start:    0.,
+            t_end:      1.,
+            frames:     Some(5),
+            output:     vec!["anim.png".into()],
+            ..test_opt(settings.width, settings.height, 4)
+        };
+        assert_eq!(check_frames(&opt), Ok(()));
+
+        let mut centroids = vec![];
+        for frame in 0..5 {
+            let frame_opt = animation_frame_opt(&opt, &CameraPath::Fixed, frame, 5);
+            assert_eq!(frame_opt.output, vec![path::PathBuf::from(format!("anim_{:04}.png",
+                                                                          frame + 1))]);
+            assert!(opt.t_start <= frame_opt.t_start && frame_opt.t_end <= opt.t_end);
+
+            let cam = Camera::new(camera_info(&frame_opt));
+            let accum = Arc::new(Accumulator::new(settings.width, settings.height));
+            let framebuffer = Arc::new(snapshot::Framebuffer::new(settings.width,
+                                                                  settings.height));
+            let linear = write_image(&frame_opt, &scene, &cam, &settings, &accum, &framebuffer,
+                                     frame_opt.samples_per_pixel).linear;
+            let (mut total, mut weighted_x) = (0.0, 0.0);
+            for y in 0..settings.height {
+                for x in 0..settings.width {
+                    let luminance = linear.get_pixel(x, y).luminance();
+                    total += luminance;
+                    weighted_x += luminance * x as Float;
+                }
+            }
+            assert!(total > 0.0, "frame {} is black", frame);
+            centroids.push(weighted_x / total);
+        }
+        for pair in centroids.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", centroids);
+        }
 
-    // This material is colored by its surface normal and nothing else.
-    // It does not refract, reflect, or change within its environment.
-    let _normal_map_material = Arc::new(NormalToRgb {});
-
-    let point = Float3::xyz(4.0, 0.2, 0.0);
-    let radius = 0.2;
-    const GRID: Float = 1.0;
-
-    // Many, many little spheres.
-    for a in -10..10 {
-        let a = a as Float;
-        for b in -10..10 {
-            let b = b as Float;
-
-            // These are positive random floats to avoid collisions.
-            let center = Float3 {
-                x: a + (GRID - radius) * rng.gen::<Float>(),
-                y: radius,
-                z: b + (GRID - radius) * rng.gen::<Float>(),
+        assert_eq!(frame_shutter(0., 1., 0, 4), (0., 0.0625));
+        assert_eq!(frame_shutter(0., 1., 2, 4), (0.4375, 0.5625));
+        assert_eq!(frame_path(path::Path::new("out/a.b.pfm"), 11),
+                   path::PathBuf::from("out/a.b_0012.pfm"));
+        for &frames in [0, 1].iter() {
+            let opt = Opt {
+                frames:  Some(frames),
+                resume:  Some("x.ckpt".into()),
+                ..test_opt(settings.width, settings.height, 4)
             };
+            assert!(check_frames(&opt).is_err());
+        }
+    }
 
-            if (center - point).length_sq() > (0.9*0.9) {
-                let sphere: Box<dyn Hitable>;
-                sphere = match rng.gen::<Float>() {
-                    // Diffuse
-                    prob if prob < 0.7 => {
-                        Box::new(MovingSphere {
-                            sphere: Sphere {
-                                center,
-                                radius,
-                                material: Arc::new(Lambertian {
-                                    albedo: Float3 {
-                                        x: rng.gen::<Float>() * rng.gen::<Float>(),
-                                        y: rng.gen::<Float>() * rng.gen::<Float>(),
-                                        z: rng.gen::<Float>() * rng.gen::<Float>(),
-                                    },
-                                }),
-                            },
-                            // Only Lambertian spheres bounce
-                            motion: Float3 {
-                                x: 0.0,
-                                y: 0.5 * rng.gen::<Float>(),
-                                z: 0.0,
-                            },
-                        })
-                    }
-                    // Metal
-                    prob if prob < 0.90 => {
-                        Box::new(MovingSphere {
-                            sphere: Sphere {
-                                center,
-                                radius,
-                                material: Arc::new(Metal {
-                                    albedo: Float3 {
-                                        x: rng.gen::<Float>(),
-                                        y: rng.gen::<Float>(),
-                                        z: rng.gen::<Float>(),
-                                    },
-                                    fuzz: 0.5 * rng.gen::<Float>(),
-                                }),
-                            },
-                            // Stationary
-                            motion: Float3::new(),
-                        })
-                    }
-                    // Glass
-                    _ => {
-                        Box::new(MovingSphere {
-                            sphere: Sphere {
-                                center,
-                                radius,
-                                material: dielectric.clone(),
-                            },
-                            // Stationary - the glass would break!
-                            motion: Float3::new(),
-                        })
-                    }
-                };
-                spheres.push(sphere);
-            }
+    #[test]
+    fn check_turntable_frames() {
+        let opt = Opt {
+            lookfrom:  Float3::xyz(0., 2., 10.),
+            lookat:    Float3::xyz(0., 1., 0.),
+            turntable: Some(8),
+            ..test_opt(16, 16, 1)
+        };
+        assert_eq!(check_frames(&opt), Ok(()));
+        assert_eq!(opt.n_frames(), Some(8));
+
+        let radius = (opt.lookfrom - opt.lookat).length();
+        let mut seen = vec![];
+        for frame in 0..8 {
+            let frame_opt = animation_frame_opt(&opt, &CameraPath::Turntable, frame, 8);
+            let offset = frame_opt.lookfrom - frame_opt.lookat;
+            assert!((offset.length() - radius).abs() < 1e-9, "{:?}", frame_opt.lookfrom);
+            assert_eq!((frame_opt.lookat, frame_opt.vfov, frame_opt.focus_dist),
+                       (opt.lookat, opt.vfov, opt.focus_dist));
+            // Every frame is somewhere new.
+            assert!(seen.iter().all(|&p: &Float3| (p - frame_opt.lookfrom).length() > 1.0));
+            seen.push(frame_opt.lookfrom);
+        }
+        // The frame after the last is the first again.
+        let first = animation_frame_opt(&opt, &CameraPath::Turntable, 0, 8);
+        let wrapped = animation_frame_opt(&opt, &CameraPath::Turntable, 8, 8);
+        assert!((first.lookfrom - wrapped.lookfrom).length() < 1e-5);
+        assert_eq!(first.lookfrom, opt.lookfrom);
+
+        let conflicting = [
+            Opt { frames: Some(8), ..opt.clone() },
+            Opt { camera_path: Some("path.txt".into()), ..opt.clone() },
+            Opt { turntable: None, camera_path: Some("path.txt".into()), ..opt.clone() },
+            Opt { turntable: Some(0), ..opt.clone() },
+        ];
+        for opt in conflicting.iter() {
+            assert!(check_frames(opt).is_err(), "{:?}", opt);
         }
     }
 
-    // Three big spheres
-    spheres.push(Box::new(Sphere {
-        center:   Float3::xyz(0., 1., 0.),
-        radius:   1.,
-        material: dielectric.clone(),
-    }));
+    #[test]
+    fn check_verify_determinism_conflicts() {
+        let opt = Opt {
+            // Left and right halves of the image.
+            tiles:              2,
+            verify_determinism: Some(None),
+            ..test_opt(16, 8, 4)
+        };
+        assert_eq!(check_verify_determinism(&opt), Ok(()));
+        assert_eq!(opt.verify_runs(), Some(2));
+
+        let unsupported = [
+            Opt { verify_determinism: Some(Some(1)), ..opt.clone() },
+            Opt { interactive: true, ..opt.clone() },
+            Opt { frames: Some(4), ..opt.clone() },
+            Opt { spp_ladder: vec![1, 2], ..opt.clone() },
+            Opt { resume: Some("x.ckpt".into()), ..opt.clone() },
+        ];
+        for opt in unsupported.iter() {
+            assert!(check_verify_determinism(opt).is_err(), "{:?}", opt);
+        }
+    }
 
-    spheres.push(Box::new(Sphere {
-        center:   Float3::xyz(-4., 1., 0.),
-        radius:   1.,
-        material: Arc::new(Lambertian {
-            albedo: Float3::xyz(0.4, 0.2, 0.1),
-        }),
-    }));
+    #[test]
+    fn check_max_seconds_conflicts() {
+        let opt = |max_seconds: Option<u64>| Opt {
+            max_seconds,
+            ..test_opt(8, 8, 4)
+        };
+        assert_eq!(check_max_seconds(&opt(None)), Ok(()));
+        assert_eq!(check_max_seconds(&opt(Some(5))), Ok(()));
+        assert!(check_max_seconds(&opt(Some(0))).is_err());
+        let with_ladder = Opt { spp_ladder: vec![1], ..opt(Some(5)) };
+        assert!(check_max_seconds(&with_ladder).is_err());
+        let with_aov = Opt { aov: vec![Aov::Normal], ..opt(Some(5)) };
+        assert!(check_max_seconds(&with_aov).is_err());
+    }
 
-    spheres.push(Box::new(Sphere {
-        center:   Float3::xyz(4., 1., 0.),
-        radius:   1.,
-        material: Arc::new(Metal {
-            albedo: Float3::xyz(0.7, 0.6, 0.5),
-            fuzz:   0.,
-        }),
-    }));
+    #[test]
+    fn check_watch_conflicts() {
+        let watching = Opt {
+            watch:      true,
+            scene_file: Some("scene.ron".into()),
+            ..test_opt(8, 8, 4)
+        };
+        assert_eq!(check_watch(&test_opt(8, 8, 4)), Ok(()));
+        assert_eq!(check_watch(&watching), Ok(()));
+        let without_file = Opt { scene_file: None, ..watching.clone() };
+        assert!(check_watch(&without_file).is_err());
+        let animated = Opt { frames: Some(4), ..watching.clone() };
+        assert!(check_watch(&animated).is_err());
+        let resumed = Opt { resume: Some("render.ckpt".into()), ..watching.clone() };
+        assert!(check_watch(&resumed).is_err());
+    }
+
+    #[test]
+    fn check_spp_ladder_matches_standalone_renders() {
+        // Looking into the light box.
+        let cam = Camera::new(camera_info(&Opt {
+            lookfrom:   Float3::xyz(278., 278., -800.),
+            lookat:     Float3::xyz(278., 278., 0.),
+            vfov:       40.,
+            aperature:  0.,
+            focus_dist: 10.,
+            t_end:      0.,
+            ..test_opt(10, 8, 5)
+        }));
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             10,
+            height:            8,
+            samples_per_pixel: 5,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let render = |opt: &Opt, on_rung: &mut dyn FnMut(u32, &Frame)| {
+            let accum = Arc::new(Accumulator::new(settings.width, settings.height));
+            let framebuffer = Arc::new(snapshot::Framebuffer::new(settings.width,
+                                                                  settings.height));
+            write_image_ladder(opt, &scene, &cam, &settings, &accum, &framebuffer,
+                               |spp, frame| on_rung(spp, frame))
+        };
+
+        let opt = Opt {
+            tiles:      3,
+            spp_ladder: vec![3, 1, 5, 3],
+            ..test_opt(settings.width, settings.height, 5)
+        };
+        assert_eq!(check_spp_ladder(&opt), Ok(()));
+        let mut rungs = vec![];
+        let last = render(&opt, &mut |spp: u32, frame: &Frame| {
+            rungs.push((spp, frame.linear.clone()))
+        });
+        // In order, once each, and the last one is the full render.
+        let counts: Vec<u32> = rungs.iter().map(|&(spp, _)| spp).collect();
+        assert_eq!(counts, vec![1, 3, 5]);
+        assert_eq!(rungs[2].1, last.linear);
+
+        for (spp, linear) in rungs {
+            let standalone = render(&test_opt(settings.width, settings.height, spp),
+                                    &mut |_, _| panic!("no rungs were asked for"));
+            assert_eq!(linear, standalone.linear, "{} spp", spp);
+        }
+
+        for rungs in [vec![0], vec![6], vec![2, 9]].iter() {
+            let opt = Opt {
+                spp_ladder: rungs.clone(),
+                ..test_opt(settings.width, settings.height, 5)
+            };
+            assert!(check_spp_ladder(&opt).is_err(), "{:?}", rungs);
+        }
+        let opt = Opt {
+            spp_ladder: vec![1],
+            aov:        vec![Aov::Normal],
+            ..test_opt(settings.width, settings.height, 5)
+        };
+        assert!(check_spp_ladder(&opt).is_err());
+    }
 
-    HitableList { hitables: spheres }
 }
+
@@ -1,17 +1,151 @@
+use std::sync::Arc;
+
+use crate::float3::consts;
+use crate::microfacet;
 use crate::prelude::*;
+use crate::texture::Texture;
 
 pub trait Material: std::fmt::Debug + Send + Sync {
-    fn scatter(&self,
-               ray_in:      &Ray,
-               record:      &HitRecord,
-               attenuation: &mut Float3,
-               scattered:   &mut Ray)
-        -> bool;
+    /// What happens to `ray_in` at `record`. Any random numbers this needs
+    /// come from `rng`, so that the renderer can replay samples exactly.
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter;
+
+    /// Like `scatter()`, but also reports which way the ray went in `event`.
+    /// Only materials with more than one way to go (like glass) report
+    /// anything. The integrator only asks for this when someone's listening.
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      _event: &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        self.scatter(ray_in, record, rng)
+    }
+
+    /// What kind of material this is, for reports. Defaults to the type's name.
+    fn kind(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        // "one_weekend::material::Lambertian<...>" => "Lambertian"
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// How likely a ray is to stop at this hit, rather than pass through as
+    /// if nothing was there. Most materials are fully opaque.
+    /// See `Cutout`.
+    fn opacity(&self, _record: &HitRecord) -> Float {
+        1.0
+    }
+
+    /// Whether `opacity()` is 1 everywhere, so that shadow rays only need to
+    /// know that they hit, and not where. Anything overriding `opacity()` has
+    /// to override this too.
+    fn is_opaque(&self) -> bool {
+        true
+    }
+
+    /// Light given off by the material itself. Most materials don't glow.
+    fn emitted(&self, _ray_in: &Ray, _record: &HitRecord) -> Float3 {
+        Float3::new()
+    }
+
+    /// The material's own color at `record`, without any lighting, for the
+    /// albedo AOV. Defaults to black, for materials that don't reflect anything.
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        Float3::new()
+    }
+
+    /// The probability density that `scatter()` picks `scattered`'s direction.
+    ///
+    /// Materials that can't express this (like a perfect mirror) return 0.0,
+    /// and are only ever sampled through `scatter()`.
+    fn scattering_pdf(&self,
+                      _ray_in:    &Ray,
+                      _record:    &HitRecord,
+                      _scattered: &Ray)
+        -> Float
+    {
+        0.0
+    }
+
+    /// The material's BSDF times the cosine term, toward `scattered`, so
+    /// that the integrator can evaluate it in directions it picked itself
+    /// (e.g. toward a light). `attenuation` is what `scatter()` gave us.
+    ///
+    /// By default, that's `attenuation * scattering_pdf(..)`, which is right
+    /// when the attenuation doesn't depend on the direction, like `Lambertian`'s.
+    fn bsdf_cos(&self,
+                ray_in:      &Ray,
+                record:      &HitRecord,
+                scattered:   &Ray,
+                attenuation: &Float3)
+        -> Float3
+    {
+        self.scattering_pdf(ray_in, record, scattered) * *attenuation
+    }
 }
 
+/// A ray bouncing off of a material.
+#[derive(Copy, Clone, Debug)]
+pub struct ScatterRecord {
+    /// How much of the light coming back along `scattered` carries on back
+    /// along the ray that hit the material.
+    pub attenuation: Float3,
+    pub scattered:   Ray,
+}
+
+/// What a material does with a ray that hits it.
+#[derive(Copy, Clone, Debug)]
+pub enum Scatter {
+    /// The ray bounces off, and the path carries on.
+    Bounce(ScatterRecord),
+    /// The path stops here, and this is the last color it sees. That's black
+    /// for most materials, but `NormalToRgb` shows the normal this way.
+    Absorb(Float3),
+}
+
+impl Scatter {
+    /// The bounce, if the ray bounced.
+    pub fn bounce(self) -> Option<ScatterRecord> {
+        match self {
+            Scatter::Bounce(bounce) => Some(bounce),
+            Scatter::Absorb(_) => None,
+        }
+    }
+}
+
+/// How a dielectric handled a ray.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScatterEvent {
+    /// Went through the surface.
+    Refracted,
+    /// Could have gone through, but `schlick()` picked reflection.
+    Reflected,
+    /// Couldn't go through at all: total internal reflection.
+    TotallyReflected,
+}
+
+/// `albedo`, clamped to [0, 1].
+/// Surfaces can't reflect more light than reaches them, or less than none, so
+/// anything outside of that is a mistake, which debug builds reject.
+pub fn checked_albedo(albedo: Float3) -> Float3 {
+    debug_assert!(albedo.as_slice().iter().all(|c| (0.0..=1.0).contains(c)),
+                  "Albedo should be between 0 and 1, not {:?}", albedo);
+    albedo.max(&Float3::new()).min(&Float3::xxx(1.0))
+}
+
+/// A diffuse material. The albedo is a plain color unless it's textured.
 #[derive(Copy, Clone, Debug, Default)]
-pub struct Lambertian {
-    pub albedo: Float3,
+pub struct Lambertian<T: Texture = Float3> {
+    pub albedo: T,
+}
+
+impl Lambertian {
+    /// A plain colored one. See `checked_albedo()`.
+    pub fn new(albedo: Float3) -> Lambertian {
+        Lambertian { albedo: checked_albedo(albedo) }
+    }
 }
 
 
@@ -19,35 +153,169 @@ pub struct Lambertian {
 pub struct NormalToRgb {}
 
 impl Material for NormalToRgb {
-    fn scatter(&self,
-               _ray_in:     &Ray,
-               record:      &HitRecord,
-               attenuation: &mut Float3,
-               _scattered:  &mut Ray)
-        -> bool
-    {
-        *attenuation = record.normal.unit();
+    fn scatter(&self, _ray_in: &Ray, record: &HitRecord, _rng: &mut dyn RngCore) -> Scatter {
         // No scattered ray.
-        false
+        Scatter::Absorb(record.normal.unit())
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        record.normal.unit()
     }
 }
 
-impl Material for Lambertian {
-    fn scatter(&self,
-               ray_in:      &Ray,
-               record:      &HitRecord,
-               attenuation: &mut Float3,
-               scattered:   &mut Ray)
-        -> bool
+/// Where a diffuse bounce off of `record` goes, given a random point
+/// `on_sphere` on the unit sphere.
+fn diffuse_direction(record: &HitRecord, on_sphere: Float3) -> Float3 {
+    // Offsetting the normal by a point *on* the unit sphere gives us
+    // a cosine-weighted direction. See `scattering_pdf()`.
+    let target = record.p + record.normal + on_sphere;
+    let dir = target - record.p;
+    // When the point is right across from the normal, they cancel out, and
+    // there's no direction left. Going straight out is as good as any.
+    if dir.near_zero() {
+        record.normal
+    } else {
+        dir
+    }
+}
+
+impl<T: Texture> Material for Lambertian<T> {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        Scatter::Bounce(ScatterRecord {
+            attenuation: self.albedo.value(record.u, record.v, &record.p),
+            scattered:   Ray {
+                origin: record.p,
+                dir:    diffuse_direction(record, random_unit_vector_with(rng)),
+                t:      ray_in.t,
+            },
+        })
+    }
+
+    fn scattering_pdf(&self,
+                      _ray_in:   &Ray,
+                      record:    &HitRecord,
+                      scattered: &Ray)
+        -> Float
+    {
+        let cosine = record.normal.unit().dot(&scattered.dir.unit());
+        if cosine > 0.0 {
+            cosine / consts::PI
+        } else {
+            0.0
+        }
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.albedo.value(record.u, record.v, &record.p)
+    }
+}
+
+/// A rough diffuse material, like clay or concrete, with the Oren-Nayar model.
+///
+/// Rough surfaces are made of tiny facets that are each Lambertian, but face
+/// every which way. Facets facing the light, and the viewer, make it look
+/// flatter than `Lambertian`, and brighter toward the edges when lit from
+/// behind the camera. `sigma` is how rough: the standard deviation of the
+/// facets' slopes, in radians. At 0, it's exactly `Lambertian`.
+/// See: "Generalization of Lambert's Reflectance Model", Oren and Nayar (1994)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OrenNayar {
+    pub albedo: Float3,
+    pub sigma:  Float,
+}
+
+impl OrenNayar {
+    /// See `checked_albedo()`. A negative `sigma` is the same as 0.
+    pub fn new(albedo: Float3, sigma: Float) -> OrenNayar {
+        OrenNayar {
+            albedo: checked_albedo(albedo),
+            sigma:  sigma.max(0.0),
+        }
+    }
+
+    /// How much brighter (or darker) than `Lambertian` this is for light from
+    /// `wi` leaving along `wo`. Both are unit vectors in the normal's frame,
+    /// which is z up. This is the usual "A + B" approximation.
+    pub fn factor(&self, wi: Float3, wo: Float3) -> Float {
+        let sigma2 = self.sigma * self.sigma;
+        let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        if b == 0.0 {
+            return a;
+        }
+
+        let sin_theta = |w: Float3| (1.0 - w.z * w.z).max(0.0).sqrt();
+        let (sin_i, sin_o) = (sin_theta(wi), sin_theta(wo));
+        // cos(phi_i - phi_o), from the parts of each in the surface's plane.
+        // Straight up or down, there's no azimuth, and this doesn't matter.
+        let cos_dphi = if sin_i > 1.0e-4 && sin_o > 1.0e-4 {
+            ((wi.x * wo.x + wi.y * wo.y) / (sin_i * sin_o)).max(0.0)
+        } else {
+            0.0
+        };
+        // alpha is the larger of the two angles from the normal, and beta the smaller.
+        let (sin_alpha, tan_beta) = if wi.z.abs() > wo.z.abs() {
+            (sin_o, sin_i / wi.z.abs())
+        } else {
+            (sin_i, sin_o / wo.z.abs())
+        };
+        a + b * cos_dphi * sin_alpha * tan_beta
+    }
+
+    /// `ray_in` and `scattered`, in the frame of `record`'s normal.
+    fn local(ray_in: &Ray, record: &HitRecord, scattered: &Ray) -> (Float3, Float3) {
+        let onb = Onb::build_from_w(record.normal);
+        (onb.to_local(-ray_in.dir.unit()), onb.to_local(scattered.dir.unit()))
+    }
+}
+
+impl Material for OrenNayar {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
     {
-        let target = record.p + record.normal + random_in_sphere();
-        *attenuation = self.albedo;
-        *scattered = Ray {
+        // Sampled just like `Lambertian`, so all that's left to weigh each
+        // direction by is how it differs from it.
+        let scattered = Ray {
             origin: record.p,
-            dir:    target - record.p,
+            dir:    diffuse_direction(record, random_unit_vector_with(rng)),
             t:      ray_in.t,
         };
-        true
+        let (wi, wo) = OrenNayar::local(ray_in, record, &scattered);
+        Scatter::Bounce(ScatterRecord {
+            attenuation: self.factor(wi, wo) * self.albedo,
+            scattered,
+        })
+    }
+
+    fn scattering_pdf(&self,
+                      _ray_in:   &Ray,
+                      record:    &HitRecord,
+                      scattered: &Ray)
+        -> Float
+    {
+        let cosine = record.normal.unit().dot(&scattered.dir.unit());
+        if cosine > 0.0 {
+            cosine / consts::PI
+        } else {
+            0.0
+        }
+    }
+
+    fn bsdf_cos(&self,
+                ray_in:       &Ray,
+                record:       &HitRecord,
+                scattered:    &Ray,
+                _attenuation: &Float3)
+        -> Float3
+    {
+        // The attenuation was for wherever `scatter()` went, not here.
+        let (wi, wo) = OrenNayar::local(ray_in, record, scattered);
+        let pdf = self.scattering_pdf(ray_in, record, scattered);
+        (self.factor(wi, wo) * pdf) * self.albedo
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        self.albedo
     }
 }
 
@@ -57,42 +325,159 @@ pub struct Metal {
     pub fuzz:   Float,
 }
 
+impl Metal {
+    /// `fuzz` is clamped to [0, 1], like in the book. Any fuzzier, and most
+    /// reflections would go into the surface, which renders nearly black.
+    /// See `checked_albedo()` for `albedo`.
+    pub fn new(albedo: Float3, fuzz: Float) -> Metal {
+        Metal {
+            albedo: checked_albedo(albedo),
+            fuzz:   fuzz.max(0.0).min(1.0),
+        }
+    }
+}
+
 impl Material for Metal {
-    fn scatter(&self,
-               ray_in:      &Ray,
-               record:      &HitRecord,
-               attenuation: &mut Float3,
-               scattered:   &mut Ray)
-        -> bool
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
     {
         let reflected = ray_in.dir.unit().reflect(record.normal);
-        *attenuation = self.albedo;
-        let dir = reflected + self.fuzz * random_in_sphere();
-        *scattered = Ray {
-            origin: record.p,
-            dir,
-            t: ray_in.t,
-        };
-        (scattered.dir.dot(&record.normal) > 0.0)
+        // This can cancel out to nothing, like `diffuse_direction()` can, but
+        // then it doesn't point out of the surface below, so it's absorbed.
+        let dir = reflected + self.fuzz * random_in_sphere_with(rng);
+        if dir.dot(&record.normal) > 0.0 {
+            Scatter::Bounce(ScatterRecord {
+                attenuation: self.albedo,
+                scattered:   Ray {
+                    origin: record.p,
+                    dir,
+                    t: ray_in.t,
+                },
+            })
+        } else {
+            // Fuzz sent it into the surface. It leaves the metal's color.
+            Scatter::Absorb(self.albedo)
+        }
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        self.albedo
     }
 }
 
+/// A rough metal, which reflects off of GGX microfacets. See `microfacet`.
+///
+/// Unlike `Metal`'s fuzz, `roughness` spreads reflections out the way real
+/// surfaces do: highlights stretch out and get sharper toward grazing angles,
+/// where everything reflects more, too. 0 is a mirror, and 1 is very rough.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GgxMetal {
+    pub albedo:    Float3,
+    pub roughness: Float,
+}
+
+impl Material for GgxMetal {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        let onb = Onb::build_from_w(record.normal);
+        let wi = onb.to_local(-ray_in.dir.unit());
+        let alpha = microfacet::alpha(self.roughness);
+        let (u1, u2) = (random_float_with(rng), random_float_with(rng));
+        match microfacet::sample(alpha, self.albedo, wi, u1, u2) {
+            Some((wo, weight)) => {
+                Scatter::Bounce(ScatterRecord {
+                    attenuation: weight,
+                    scattered:   Ray {
+                        origin: record.p,
+                        dir:    onb.local(wo),
+                        t:      ray_in.t,
+                    },
+                })
+            },
+            // Rays that go into the surface are absorbed, and don't leave any
+            // color behind.
+            None => Scatter::Absorb(Float3::new()),
+        }
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        self.albedo
+    }
+
+    // This leaves `scattering_pdf()` at 0, like `Metal`, so we're never asked
+    // to evaluate a direction toward a light. Near-mirror lobes are better
+    // sampled by `scatter()` than by the light anyway, and the weights from it
+    // are unbiased on their own.
+}
+
 // Glass ball
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Dielectric {
     pub refraction_index: Float,
+    /// How much of each channel is absorbed per unit traveled inside, which
+    /// tints the glass. Light makes it through a distance d with
+    /// exp(-absorption * d) of itself left, so more absorption of red leaves
+    /// the glass looking green-blue. 0 is perfectly clear.
+    pub absorption:       Float3,
+}
+
+impl Dielectric {
+    /// Clear glass. `refraction_index` has to be more than 0. Air is 1, and
+    /// glass is about 1.5.
+    pub fn new(refraction_index: Float) -> Dielectric {
+        debug_assert!(refraction_index > 0.0,
+                      "Refraction index should be more than 0, not {}", refraction_index);
+        Dielectric {
+            refraction_index,
+            absorption: Float3::new(),
+        }
+    }
+
+    /// Tinted glass, which absorbs `absorption` per unit. None of it can be negative.
+    pub fn with_absorption(self, absorption: Float3) -> Dielectric {
+        debug_assert!(absorption.as_slice().iter().all(|&c| c >= 0.0),
+                      "Absorption can't be negative, but it's {:?}", absorption);
+        Dielectric {
+            absorption,
+            ..self
+        }
+    }
+
+    /// How much of each channel is left after traveling `distance` inside.
+    pub fn transmittance(&self, distance: Float) -> Float3 {
+        let mut left = Float3::xxx(1.0);
+        for (l, a) in left.as_mut_slice().iter_mut().zip(self.absorption.as_slice().iter()) {
+            // Clear channels are left exactly alone.
+            if *a != 0.0 {
+                *l = (-a * distance).exp();
+            }
+        }
+        left
+    }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self,
-               ray_in:      &Ray,
-               record:      &HitRecord,
-               attenuation: &mut Float3,
-               scattered:   &mut Ray)
-        -> bool
-    {
-        // Our material doesn't attenuate anything.
-        *attenuation = Float3::xyz(1., 1., 1.);
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        self.scatter_traced(ray_in, record, rng, &mut None)
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        // Glass doesn't tint anything.
+        Float3::xxx(1.0)
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        // Reflecting and refracting both keep the length of `ray_in.dir`, so
+        // unlike the other materials, we can't end up without a direction.
+
+        // Nothing is lost at the surface itself.
+        let mut attenuation = Float3::xyz(1., 1., 1.);
         let reflected = ray_in.dir.reflect(record.normal);
 
         // We handle refraction differently depending on whether the ray
@@ -101,6 +486,9 @@ impl Material for Dielectric {
         let refraction_index: Float;
         let cosine:           Float;
         if ray_in.dir.dot(&record.normal) > 0.0 {
+            // Rays inside start where they got in, or last bounced, so the
+            // whole ray was through the glass, and lost some on the way.
+            attenuation = self.transmittance(record.t * ray_in.dir.length());
             outward_normal = -record.normal;
             refraction_index = self.refraction_index;
             cosine = refraction_index * ray_in.dir.unit().dot(&record.normal);
@@ -122,22 +510,723 @@ impl Material for Dielectric {
             // Yes, and we usually will if we can.
             // But first, we check a random number against the `schlick`
             // function. This represents the odds of *reflecting* instead.
-            if random_float() >= schlick(cosine, refraction_index) {
+            if random_float_with(rng) >= schlick(cosine, refraction_index) {
                 scattered_dir = refracted;
+                *event = Some(ScatterEvent::Refracted);
             } else {
                 // Probability test failed: just reflect.
                 scattered_dir = reflected;
+                *event = Some(ScatterEvent::Reflected);
             }
         } else {
             // We can't refract: just reflect.
             scattered_dir = reflected;
+            *event = Some(ScatterEvent::TotallyReflected);
         }
 
-        *scattered = Ray {
-            origin: record.p,
-            dir:    scattered_dir,
-            t:      ray_in.t,
+        Scatter::Bounce(ScatterRecord {
+            attenuation,
+            scattered: Ray {
+                origin: record.p,
+                dir:    scattered_dir,
+                t:      ray_in.t,
+            },
+        })
+    }
+}
+
+/// A material that gives off light, and nothing else.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DiffuseLight {
+    pub emit: Float3,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray_in: &Ray, _record: &HitRecord, _rng: &mut dyn RngCore) -> Scatter {
+        // Lights absorb everything that hits them.
+        Scatter::Absorb(Float3::new())
+    }
+
+    fn emitted(&self, _ray_in: &Ray, _record: &HitRecord) -> Float3 {
+        self.emit
+    }
+}
+
+/// Scatters light equally in every direction. This is for the insides of
+/// smoke and fog (see `volume`), where hits have no surface to face.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Isotropic {
+    pub albedo: Float3,
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        Scatter::Bounce(ScatterRecord {
+            attenuation: self.albedo,
+            scattered:   Ray {
+                origin: record.p,
+                dir:    random_unit_vector_with(rng),
+                t:      ray_in.t,
+            },
+        })
+    }
+
+    /// Every direction is as likely as any other. There's no cosine term,
+    /// since there's no surface.
+    fn scattering_pdf(&self,
+                      _ray_in:    &Ray,
+                      _record:    &HitRecord,
+                      _scattered: &Ray)
+        -> Float
+    {
+        1.0 / (4.0 * consts::PI)
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Float3 {
+        self.albedo
+    }
+}
+
+/// Blends two materials, like a mostly diffuse surface with a bit of shine.
+/// Each hit scatters like `a` with probability `factor`, and like `b`
+/// otherwise, so the blend averages out over samples.
+///
+/// The integrator can't tell which one was picked, so unless `factor` is 0 or
+/// 1, this has no pdf, and is only sampled through `scatter()`, like a mirror.
+#[derive(Clone, Debug)]
+pub struct Mix {
+    pub a:      Arc<dyn Material>,
+    pub b:      Arc<dyn Material>,
+    pub factor: Float,
+}
+
+impl Mix {
+    /// `factor` is clamped to [0, 1].
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, factor: Float) -> Mix {
+        Mix {
+            a,
+            b,
+            factor: factor.max(0.0).min(1.0),
+        }
+    }
+
+    /// The material every hit uses, if it's always the same one.
+    /// Then we're just that material, random numbers and all.
+    fn only(&self) -> Option<&dyn Material> {
+        if self.factor >= 1.0 {
+            Some(&*self.a)
+        } else if self.factor <= 0.0 {
+            Some(&*self.b)
+        } else {
+            None
+        }
+    }
+
+    fn pick(&self, rng: &mut dyn RngCore) -> &dyn Material {
+        match self.only() {
+            Some(material) => material,
+            None if random_float_with(rng) < self.factor => &*self.a,
+            None => &*self.b,
+        }
+    }
+
+    /// `factor` of what `a` says, and the rest of what `b` says.
+    fn blend(&self, a: Float3, b: Float3) -> Float3 {
+        self.factor * a + (1.0 - self.factor) * b
+    }
+}
+
+impl Material for Mix {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        self.pick(rng).scatter(ray_in, record, rng)
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        self.pick(rng).scatter_traced(ray_in, record, rng, event)
+    }
+
+    fn opacity(&self, record: &HitRecord) -> Float {
+        let (a, b) = (self.a.opacity(record), self.b.opacity(record));
+        self.factor * a + (1.0 - self.factor) * b
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.a.is_opaque() && self.b.is_opaque()
+    }
+
+    fn emitted(&self, ray_in: &Ray, record: &HitRecord) -> Float3 {
+        self.blend(self.a.emitted(ray_in, record), self.b.emitted(ray_in, record))
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.blend(self.a.albedo(record), self.b.albedo(record))
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, record: &HitRecord, scattered: &Ray) -> Float {
+        self.only().map_or(0.0, |material| material.scattering_pdf(ray_in, record, scattered))
+    }
+
+    fn bsdf_cos(&self,
+                ray_in:      &Ray,
+                record:      &HitRecord,
+                scattered:   &Ray,
+                attenuation: &Float3)
+        -> Float3
+    {
+        match self.only() {
+            Some(material) => material.bsdf_cos(ray_in, record, scattered, attenuation),
+            None => Float3::new(),
+        }
+    }
+}
+
+/// `a` over `b`: hits scatter off of `a`, unless it absorbs the ray (like a
+/// fuzzy `Metal` scattering into the surface), and then off of `b` instead.
+///
+/// Like `Mix`, this has no pdf, since either layer could have scattered.
+#[derive(Clone, Debug)]
+pub struct Layered {
+    pub a: Arc<dyn Material>,
+    pub b: Arc<dyn Material>,
+}
+
+impl Material for Layered {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        match self.a.scatter(ray_in, record, rng) {
+            Scatter::Absorb(_) => self.b.scatter(ray_in, record, rng),
+            bounce => bounce,
+        }
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        match self.a.scatter_traced(ray_in, record, rng, event) {
+            Scatter::Absorb(_) => self.b.scatter_traced(ray_in, record, rng, event),
+            bounce => bounce,
+        }
+    }
+
+    // Rays get through where neither layer is.
+    fn opacity(&self, record: &HitRecord) -> Float {
+        let (a, b) = (self.a.opacity(record), self.b.opacity(record));
+        1.0 - (1.0 - a) * (1.0 - b)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.a.is_opaque() || self.b.is_opaque()
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.a.albedo(record)
+    }
+}
+
+/// Cuts holes in another material wherever `alpha` is below 1, for leaves,
+/// fences, and the like. Alpha is the average of the texture's channels.
+///
+/// Rays stop with probability alpha, and otherwise carry on past the hit, so
+/// partial alpha averages out to partial coverage.
+#[derive(Clone, Debug)]
+pub struct Cutout<M: Material> {
+    pub material: M,
+    pub alpha:    Arc<dyn Texture>,
+}
+
+impl<M: Material> Material for Cutout<M> {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        self.material.scatter(ray_in, record, rng)
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        self.material.scatter_traced(ray_in, record, rng, event)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.material.kind()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> Float {
+        let alpha = self.alpha.value(record.u, record.v, &record.p);
+        (alpha.x + alpha.y + alpha.z) / 3.0 * self.material.opacity(record)
+    }
+
+    fn is_opaque(&self) -> bool {
+        false
+    }
+
+    fn emitted(&self, ray_in: &Ray, record: &HitRecord) -> Float3 {
+        self.material.emitted(ray_in, record)
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.material.albedo(record)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, record: &HitRecord, scattered: &Ray) -> Float {
+        self.material.scattering_pdf(ray_in, record, scattered)
+    }
+
+    fn bsdf_cos(&self,
+                ray_in:      &Ray,
+                record:      &HitRecord,
+                scattered:   &Ray,
+                attenuation: &Float3)
+        -> Float3
+    {
+        self.material.bsdf_cos(ray_in, record, scattered, attenuation)
+    }
+}
+
+/// Bends the normals of another material's hits by a tangent-space normal
+/// map, for bumps and grooves too small to model.
+///
+/// The map's colors are directions: red goes along the hit's tangent (which
+/// way `u` goes), green along the bitangent (which way `v` goes, roughly),
+/// and blue straight out, each from 0 for -1 to 1 for +1. So a flat map is
+/// (0.5, 0.5, 1) everywhere, and leaves normals alone.
+#[derive(Clone, Debug)]
+pub struct NormalMapped<M: Material> {
+    pub material:   M,
+    pub normal_map: Arc<dyn Texture>,
+}
+
+/// The least a mapped normal can point out of the surface, as a cosine.
+/// Maps can say to tip normals past the surface, but then light would come
+/// from behind it, so we keep them just above it.
+const MIN_MAPPED_COS: Float = 0.05;
+
+impl<M: Material> NormalMapped<M> {
+    /// The normal at `record`, according to the map. It's always unit
+    /// length, and on the same side of the surface as `record.normal`.
+    pub fn mapped_normal(&self, record: &HitRecord) -> Float3 {
+        let rgb = self.normal_map.value(record.u, record.v, &record.p);
+        let local: Float3 = 2.0 * rgb - Float3::xxx(1.0);
+
+        let normal = record.normal.unit();
+        // Tangents are perpendicular already, unless something bent the
+        // normal without them.
+        let tangent = (record.tangent - record.tangent.dot(&normal) * normal).unit();
+        let bitangent = normal.cross(&tangent);
+        let z = local.z.max(MIN_MAPPED_COS);
+        (local.x * tangent + local.y * bitangent + z * normal).unit()
+    }
+
+    /// `record`, with its normal from the map.
+    fn mapped(&self, record: &HitRecord) -> HitRecord {
+        HitRecord {
+            normal: self.mapped_normal(record),
+            ..record.clone()
+        }
+    }
+}
+
+impl<M: Material> Material for NormalMapped<M> {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter
+    {
+        self.material.scatter(ray_in, &self.mapped(record), rng)
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<ScatterEvent>)
+        -> Scatter
+    {
+        self.material.scatter_traced(ray_in, &self.mapped(record), rng, event)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.material.kind()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> Float {
+        self.material.opacity(record)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.material.is_opaque()
+    }
+
+    fn emitted(&self, ray_in: &Ray, record: &HitRecord) -> Float3 {
+        self.material.emitted(ray_in, record)
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.material.albedo(record)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, record: &HitRecord, scattered: &Ray) -> Float {
+        self.material.scattering_pdf(ray_in, &self.mapped(record), scattered)
+    }
+
+    fn bsdf_cos(&self,
+                ray_in:      &Ray,
+                record:      &HitRecord,
+                scattered:   &Ray,
+                attenuation: &Float3)
+        -> Float3
+    {
+        self.material.bsdf_cos(ray_in, &self.mapped(record), scattered, attenuation)
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_fuzz_is_clamped() {
+        let albedo = Float3::xyz(0.8, 0.6, 0.2);
+        assert_eq!(Metal::new(albedo, 5.0).fuzz, 1.0);
+        assert_eq!(Metal::new(albedo, -1.0).fuzz, 0.0);
+        assert_eq!(Metal::new(albedo, 0.3).fuzz, 0.3);
+
+        // And scatters exactly like a fuzz of 1 would.
+        let record = HitRecord {
+            t:        1.,
+            p:        Float3::new(),
+            normal:   Float3::xyz(0., 1., 0.),
+            u:        0.,
+            v:        0.,
+            tangent:  Float3::xyz(1., 0., 0.),
+            material: Arc::new(NormalToRgb {}),
         };
-        true
+        let ray_in = Ray {
+            origin: Float3::xyz(-1., 1., 0.),
+            dir:    Float3::xyz(1., -1., 0.),
+            t:      0.,
+        };
+        let scatter = |metal: &Metal| {
+            let mut rng = seeded_rng(7);
+            (0..100)
+                .map(|_| {
+                    metal.scatter(&ray_in, &record, &mut rng).bounce().map(|b| b.scattered.dir)
+                })
+                .collect::<Vec<_>>()
+        };
+        let clamped = scatter(&Metal::new(albedo, 5.0));
+        assert_eq!(clamped, scatter(&Metal { albedo, fuzz: 1.0 }));
+        // Which keeps most rays out of the surface, unlike a fuzz of 5.
+        let out = |rays: &[Option<Float3>]| rays.iter().filter(|r| r.is_some()).count();
+        assert!(out(&clamped) > out(&scatter(&Metal { albedo, fuzz: 5.0 })));
+    }
+
+    #[test]
+    fn check_degenerate_diffuse_direction() {
+        let normal = Float3::xyz(0., 1., 0.);
+        let record = HitRecord {
+            t:        1.,
+            p:        Float3::xyz(278., 0., 278.),
+            normal,
+            u:        0.,
+            v:        0.,
+            tangent:  normal.cross(&Float3::xyz(0., 0., 1.)).unit(),
+            material: Arc::new(NormalToRgb {}),
+        };
+        // Right across from the normal, and only nearly.
+        for &on_sphere in [-normal, Float3::xyz(1.0e-10, -1., 0.)].iter() {
+            let dir = diffuse_direction(&record, on_sphere);
+            assert!(!dir.near_zero(), "{:?}", dir);
+            let unit = dir.unit();
+            assert!(unit.as_slice().iter().all(|c| c.is_finite()), "{:?}", unit);
+            assert!(unit.dot(&normal) > 0.0, "{:?}", unit);
+        }
+        // Everything else is left alone.
+        let on_sphere = Float3::xyz(0.6, 0., 0.8);
+        assert_eq!(diffuse_direction(&record, on_sphere),
+                   (record.p + normal + on_sphere) - record.p);
+    }
+
+    /// A hit on a surface facing +z, at the origin.
+    fn facing_up(material: Arc<dyn Material>) -> HitRecord {
+        HitRecord {
+            t:        1.,
+            p:        Float3::new(),
+            normal:   Float3::xyz(0., 0., 1.),
+            u:        0.,
+            v:        0.,
+            tangent:  Float3::xyz(1., 0., 0.),
+            material,
+        }
+    }
+
+    /// A unit vector `theta` degrees from +z, and `phi` degrees around it.
+    fn direction(theta: Float, phi: Float) -> Float3 {
+        let (theta, phi) = (theta.to_radians(), phi.to_radians());
+        Float3::xyz(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+    }
+
+    /// A ray coming in toward the origin from `from`, and one leaving toward `to`.
+    fn rays(from: Float3, to: Float3) -> (Ray, Ray) {
+        (Ray { origin: from, dir: -from, t: 0. }, Ray { origin: Float3::new(), dir: to, t: 0. })
+    }
+
+    #[test]
+    fn check_smooth_oren_nayar_is_lambertian() {
+        let albedo = Float3::xyz(0.7, 0.5, 0.3);
+        let clay = OrenNayar::new(albedo, 0.0);
+        let lambertian = Lambertian::new(albedo);
+        let record = facing_up(Arc::new(clay));
+        for theta_i in (0..9).map(|i| 10.0 * i as Float) {
+            for theta_o in (0..9).map(|i| 10.0 * i as Float) {
+                for phi in (0..8).map(|i| 45.0 * i as Float) {
+                    let (ray_in, scattered) = rays(direction(theta_i, 0.), direction(theta_o, phi));
+                    let expected = lambertian.bsdf_cos(&ray_in, &record, &scattered, &albedo);
+                    let found = clay.bsdf_cos(&ray_in, &record, &scattered, &Float3::new());
+                    for (f, e) in found.as_slice().iter().zip(expected.as_slice().iter()) {
+                        assert!((f - e).abs() < 1.0e-6, "({}, {}, {}): {:?} vs {:?}",
+                                theta_i, theta_o, phi, found, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_rough_oren_nayar() {
+        let clay = OrenNayar::new(Float3::xxx(0.5), 0.5);
+        let (wi, back, away) = (direction(60., 0.), direction(60., 0.), direction(60., 180.));
+        // Brighter than Lambertian back toward the light, and darker away from it.
+        assert!(clay.factor(wi, back) > 1.0, "{}", clay.factor(wi, back));
+        assert!(clay.factor(wi, away) < 1.0, "{}", clay.factor(wi, away));
+        // Either way around is the same.
+        let other = direction(30., 70.);
+        assert!((clay.factor(wi, other) - clay.factor(other, wi)).abs() < 1.0e-6);
+
+        // `scatter()` and `bsdf_cos()` agree on the directions it picks.
+        let mut rng = seeded_rng(3);
+        let record = facing_up(Arc::new(clay));
+        let (ray_in, _) = rays(direction(50., 20.), Float3::new());
+        for _ in 0..100 {
+            let ScatterRecord { attenuation, scattered } =
+                clay.scatter(&ray_in, &record, &mut rng).bounce().unwrap();
+            let pdf = clay.scattering_pdf(&ray_in, &record, &scattered);
+            let bsdf_cos = clay.bsdf_cos(&ray_in, &record, &scattered, &Float3::new());
+            assert!((pdf * attenuation - bsdf_cos).length() < 1.0e-6,
+                    "{:?} vs {:?}", pdf * attenuation, bsdf_cos);
+        }
+    }
+
+    #[test]
+    fn check_albedo_in_range() {
+        assert_eq!(Lambertian::new(Float3::xyz(0., 0.5, 1.)).albedo, Float3::xyz(0., 0.5, 1.));
+        assert_eq!(Metal::new(Float3::xxx(0.25), 0.).albedo, Float3::xxx(0.25));
+    }
+
+    #[test]
+    fn check_absorption_over_distance() {
+        let glass = Dielectric::new(1.5).with_absorption(Float3::xyz(1.0, 0.0, 0.5));
+        let record = |t: Float| HitRecord {
+            t,
+            p:        Float3::xyz(1., 0., 0.),
+            normal:   Float3::xyz(1., 0., 0.),
+            u:        0.,
+            v:        0.,
+            tangent:  Float3::xyz(0., 1., 0.),
+            material: Arc::new(glass),
+        };
+        let scatter = |ray_in: Ray, record: &HitRecord| {
+            glass.scatter(&ray_in, record, &mut seeded_rng(0)).bounce().unwrap().attenuation
+        };
+
+        // Leaving after 1 unit inside: half of a ray twice as long.
+        let inside = Ray {
+            origin: Float3::new(),
+            dir:    Float3::xyz(2., 0., 0.),
+            t:      0.,
+        };
+        let left = scatter(inside, &record(0.5));
+        let expected = Float3::xyz((-1.0 as Float).exp(), 1.0, (-0.5 as Float).exp());
+        for (l, e) in left.as_slice().iter().zip(expected.as_slice().iter()) {
+            assert!((l - e).abs() < 1.0e-6, "{:?} vs {:?}", left, expected);
+        }
+        // Twice as far loses twice as much.
+        let further = scatter(inside, &record(1.0));
+        assert!((further.x - left.x * left.x).abs() < 1.0e-6, "{:?}", further);
+
+        // Coming in from outside, nothing has been lost yet.
+        let outside = Ray {
+            origin: Float3::xyz(3., 0., 0.),
+            dir:    Float3::xyz(-1., 0., 0.),
+            t:      0.,
+        };
+        assert_eq!(scatter(outside, &record(2.0)), Float3::xxx(1.0));
+        // And clear glass never loses anything.
+        assert_eq!(Dielectric::new(1.5).transmittance(100.0), Float3::xxx(1.0));
+    }
+
+    /// What `material` does with `n` rays coming in `theta` degrees from
+    /// straight down onto `record`, with random numbers from `seed`.
+    fn scatter_many(material: &dyn Material, record: &HitRecord, theta: Float, n: usize, seed: u64)
+        -> Vec<(bool, Float3, Float3)>
+    {
+        let mut rng = seeded_rng(seed);
+        let (ray_in, _) = rays(direction(theta, 0.), Float3::new());
+        (0..n)
+            .map(|_| {
+                match material.scatter(&ray_in, record, &mut rng) {
+                    Scatter::Bounce(bounce) => (true, bounce.attenuation, bounce.scattered.dir),
+                    Scatter::Absorb(color) => (false, color, Float3::new()),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_mix_extremes_are_pure() {
+        let a: Arc<dyn Material> = Arc::new(Lambertian::new(Float3::xyz(0.8, 0.2, 0.2)));
+        let b: Arc<dyn Material> = Arc::new(Metal::new(Float3::xyz(0.2, 0.2, 0.8), 0.3));
+        let record = facing_up(a.clone());
+        for &(factor, pure) in [(1.0, &a), (0.0, &b), (7.0, &a), (-2.0, &b)].iter() {
+            let mix = Mix::new(a.clone(), b.clone(), factor);
+            let mixed = scatter_many(&mix, &record, 30., 100, 3);
+            assert_eq!(mixed, scatter_many(&**pure, &record, 30., 100, 3), "factor {}", factor);
+
+            let (ray_in, scattered) = rays(direction(20., 0.), direction(40., 90.));
+            assert_eq!(mix.scattering_pdf(&ray_in, &record, &scattered),
+                       pure.scattering_pdf(&ray_in, &record, &scattered));
+        }
+    }
+
+    #[test]
+    fn check_mix_picks_both() {
+        let red = Float3::xyz(0.8, 0.1, 0.1);
+        let blue = Float3::xyz(0.1, 0.1, 0.8);
+        let mix = Mix::new(Arc::new(Lambertian::new(red)), Arc::new(Lambertian::new(blue)), 0.5);
+        let record = facing_up(Arc::new(NormalToRgb {}));
+        const N: usize = 10_000;
+        let scattered = scatter_many(&mix, &record, 0., N, 4);
+        assert!(scattered.iter().all(|&(hit, color, _)| hit && (color == red || color == blue)));
+        let reds = scattered.iter().filter(|&&(_, color, _)| color == red).count();
+        let fraction = reds as Float / N as Float;
+        assert!((fraction - 0.5).abs() < 0.02, "{}", fraction);
+        // Neither one alone can say how likely a direction is.
+        let (ray_in, to) = rays(direction(20., 0.), direction(40., 90.));
+        assert_eq!(mix.scattering_pdf(&ray_in, &record, &to), 0.0);
+        let albedo = mix.albedo(&record);
+        assert!((albedo - Float3::xyz(0.45, 0.1, 0.45)).length() < 1e-6, "{:?}", albedo);
+
+        // Layered only falls back to its base when the top absorbs the ray.
+        let layered = Layered {
+            a: Arc::new(Metal::new(red, 1.0)),
+            b: Arc::new(Lambertian::new(blue)),
+        };
+        // Coming in nearly flat, fuzz sends plenty of reflections into the surface.
+        let scattered = scatter_many(&layered, &record, 80., N, 5);
+        assert!(scattered.iter().all(|&(hit, _, dir)| hit && dir.z > 0.0));
+        let blues = scattered.iter().filter(|&&(_, color, _)| color == blue).count();
+        assert!(blues > 0 && blues < N / 2, "{}", blues);
+    }
+
+    #[test]
+    fn check_cutouts_in_mixes_let_shadows_through() {
+        use crate::hitable::{
+            Hitable,
+            Sphere,
+        };
+
+        let hole = || -> Arc<dyn Material> {
+            Arc::new(Cutout {
+                material: Lambertian::new(Float3::xxx(0.5)),
+                alpha:    Arc::new(Float3::new()),
+            })
+        };
+        let solid = || -> Arc<dyn Material> { Arc::new(Lambertian::new(Float3::xxx(0.5))) };
+        let ray = Ray {
+            origin: Float3::xyz(0., 0., -5.),
+            dir:    Float3::xyz(0., 0., 1.),
+            t:      0.0,
+        };
+        let mut rng = seeded_rng(5);
+        // How many of `n` shadow rays a ball of `material` stops.
+        let mut stopped = |material: Arc<dyn Material>, n: usize| {
+            let ball = Sphere { center: Float3::new(), radius: 1.0, material };
+            (0..n).filter(|_| ball.hit_any(&ray, 1.0e-3, FLOAT_MAX, &mut rng)).count()
+        };
+
+        // Seen through `dyn Material`, a mix of holes is a hole.
+        let holes: Arc<dyn Material> = Arc::new(Mix::new(hole(), hole(), 0.5));
+        assert!(!holes.is_opaque());
+        assert_eq!(stopped(holes, 100), 0);
+        // Half a hole lets through half of them at each side of the ball,
+        // so a quarter get all the way through.
+        let half = stopped(Arc::new(Mix::new(hole(), solid(), 0.5)), 1000);
+        assert!(690 < half && half < 810, "{}", half);
+        assert!(Arc::new(Mix::new(solid(), solid(), 0.5)).is_opaque());
+
+        // Layers are only see-through where they both are.
+        let layers: Arc<dyn Material> = Arc::new(Layered { a: hole(), b: hole() });
+        assert!(!layers.is_opaque());
+        assert_eq!(stopped(layers, 100), 0);
+        let covered: Arc<dyn Material> = Arc::new(Layered { a: hole(), b: solid() });
+        assert!(covered.is_opaque());
+        assert_eq!(stopped(covered, 100), 100);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Albedo should be between 0 and 1")]
+    fn check_negative_albedo_is_rejected() {
+        Lambertian::new(Float3::xyz(0.5, -0.1, 0.5));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Refraction index should be more than 0")]
+    fn check_zero_refraction_index_is_rejected() {
+        Dielectric::new(0.0);
+    }
+
+    #[test]
+    fn check_normal_maps() {
+        seed_thread_rng(0x5eed);
+        let mapped = |rgb: Float3| NormalMapped {
+            material:   Lambertian::new(Float3::xxx(0.5)),
+            normal_map: Arc::new(rgb),
+        };
+        for _ in 0..1000 {
+            let normal = random_unit_vector();
+            let tangent = normal.cross(&random_unit_vector()).unit();
+            let record = HitRecord { normal, tangent, ..facing_up(Arc::new(NormalToRgb {})) };
+
+            // A flat map leaves normals where they were.
+            let flat = mapped(Float3::xyz(0.5, 0.5, 1.)).mapped_normal(&record);
+            assert!((flat - normal).length() < 1e-6, "{:?} vs {:?}", flat, normal);
+
+            // Anything else tips them, but never through the surface.
+            let rgb = Float3::xyz(random_float(), random_float(), random_float());
+            let bent = mapped(rgb).mapped_normal(&record);
+            assert!((bent.length() - 1.).abs() < 1e-6, "{:?}", bent);
+            assert!(bent.dot(&normal) > 0.0, "{:?} from {:?}", bent, rgb);
+        }
+
+        // Red leans toward the tangent, and green toward the bitangent.
+        let record = facing_up(Arc::new(NormalToRgb {}));
+        let red = mapped(Float3::xyz(1., 0.5, 0.5)).mapped_normal(&record);
+        assert!(red.x > 0.9 && red.z > 0.0, "{:?}", red);
+        let green = mapped(Float3::xyz(0.5, 1., 0.5)).mapped_normal(&record);
+        assert!(green.y > 0.9 && green.z > 0.0, "{:?}", green);
     }
 }
@@ -1,5 +1,67 @@
+use std::cell::RefCell;
+
+use rand::prelude::*;
+
+use crate::float3::consts;
 use crate::prelude::*;
 
+thread_local! {
+    // Random numbers for anything that isn't handed a generator of its own,
+    // like tests making up random rays. Nothing a render traces draws from
+    // it, so samples replay exactly from their own generators.
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+/// A generator that draws everything from `seed` alone.
+/// It draws the same numbers that this thread's generator does after
+/// `seed_thread_rng()` with the same seed.
+pub fn seeded_rng(seed: u64) -> SmallRng {
+    // Mix the seed up, so that nearby seeds don't produce related streams.
+    let a = splitmix64(seed);
+    let b = splitmix64(a);
+    let mut bytes = [0_u8; 16];
+    bytes[..8].copy_from_slice(&a.to_le_bytes());
+    bytes[8..].copy_from_slice(&b.to_le_bytes());
+    SmallRng::from_seed(bytes)
+}
+
+/// Reseeds this thread's random number generator.
+/// Everything drawn afterwards on this thread follows from `seed` alone.
+pub fn seed_thread_rng(seed: u64) {
+    let seeded = seeded_rng(seed);
+    RNG.with(|rng| *rng.borrow_mut() = seeded);
+}
+
+/// Calls `f` with this thread's random number generator, for code that takes
+/// its generator explicitly. `f` can't use the `random_*()` functions that
+/// draw from the thread's generator; they'd find it already borrowed.
+pub fn with_thread_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    RNG.with(|rng| f(&mut *rng.borrow_mut()))
+}
+
+/// Where this thread's random number generator is in its stream, to pick up
+/// from later with `restore_thread_rng()`.
+#[derive(Clone, Debug)]
+pub struct ThreadRngState(SmallRng);
+
+pub fn save_thread_rng() -> ThreadRngState {
+    RNG.with(|rng| ThreadRngState(rng.borrow().clone()))
+}
+
+/// Puts this thread's random number generator back where it was when `state`
+/// was saved, so that it draws the same numbers it would have then.
+pub fn restore_thread_rng(state: ThreadRngState) {
+    RNG.with(|rng| *rng.borrow_mut() = state.0);
+}
+
+/// A quick, well mixed 64-bit hash. Good for deriving seeds from indices.
+pub fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 /// When you look at a window at a steep angle, it becomes a mirror.
 /// This is a simple approximation to that by Christophe Schlick.
 pub fn schlick(cosine: Float, refraction_index: Float) -> Float {
@@ -11,13 +73,18 @@ pub fn schlick(cosine: Float, refraction_index: Float) -> Float {
 /// Returns a random point uniformly from the unit sphere,
 /// centered at the origin.
 pub fn random_in_sphere() -> Float3 {
+    with_thread_rng(random_in_sphere_with)
+}
+
+/// `random_in_sphere()`, drawing from `rng`.
+pub fn random_in_sphere_with(rng: &mut dyn RngCore) -> Float3 {
     // This is a bad way to do this. With our 200x100 image, we reliably
     // run this loop 18 times without finding a point.
     // ಠ_ಠ
     loop {
-        let x: Float = random_sfloat();
-        let y: Float = random_sfloat();
-        let z: Float = random_sfloat();
+        let x: Float = random_sfloat_with(rng);
+        let y: Float = random_sfloat_with(rng);
+        let z: Float = random_sfloat_with(rng);
         let p = Float3 { x, y, z };
         if p.length_sq() < 1.0 {
             return p;
@@ -25,13 +92,63 @@ pub fn random_in_sphere() -> Float3 {
     }
 }
 
+/// Returns a random point uniformly from the *surface* of the unit sphere.
+pub fn random_unit_vector() -> Float3 {
+    with_thread_rng(random_unit_vector_with)
+}
+
+/// `random_unit_vector()`, drawing from `rng`.
+pub fn random_unit_vector_with(rng: &mut dyn RngCore) -> Float3 {
+    random_in_sphere_with(rng).unit()
+}
+
+/// Returns a random direction within `acos(cos_theta_max)` of +Z, uniformly
+/// over that cone. A cosine of 0 gives the hemisphere around +Z, and -1 gives
+/// every direction.
+pub fn random_in_cone(cos_theta_max: Float) -> Float3 {
+    with_thread_rng(|rng| random_in_cone_with(rng, cos_theta_max))
+}
+
+/// `random_in_cone()`, drawing from `rng`.
+pub fn random_in_cone_with(rng: &mut dyn RngCore, cos_theta_max: Float) -> Float3 {
+    let r1 = random_float_with(rng);
+    let r2 = random_float_with(rng);
+    let z = 1.0 + r2 * (cos_theta_max - 1.0);
+
+    let phi = 2.0 * consts::PI * r1;
+    // Rounding can leave `z` a hair past 1.
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+    let x = phi.cos() * sin_theta;
+    let y = phi.sin() * sin_theta;
+
+    Float3::xyz(x, y, z)
+}
+
+/// The power heuristic (with beta = 2) for weighting one sample from each of
+/// two strategies. `pdf` is the density of the strategy that was actually used.
+/// See Veach's thesis, section 9.2.4.
+pub fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
+}
+
 /// Returns a random point uniformly from the unit disk.
 /// Disks are 2D, so the Z component is always zero.
 pub fn random_in_disk() -> Float3 {
+    with_thread_rng(random_in_disk_with)
+}
+
+/// `random_in_disk()`, drawing from `rng`.
+pub fn random_in_disk_with(rng: &mut dyn RngCore) -> Float3 {
     // Oh good, more of this.
     loop {
-        let x = random_sfloat();
-        let y = random_sfloat();
+        let x = random_sfloat_with(rng);
+        let y = random_sfloat_with(rng);
         let p = Float3::xyz(x, y, 0.0);
         if p.length_sq() < 1.0 {
             return p;
@@ -39,16 +156,112 @@ pub fn random_in_disk() -> Float3 {
     }
 }
 
+/// Returns a random point uniformly from a regular polygon with `sides`
+/// corners on the unit circle, the first `rotation` degrees counterclockwise
+/// from +X. The Z component is always zero.
+pub fn random_in_polygon(sides: u32, rotation: Float) -> Float3 {
+    with_thread_rng(|rng| random_in_polygon_with(rng, sides, rotation))
+}
+
+/// `random_in_polygon()`, drawing from `rng`.
+pub fn random_in_polygon_with(rng: &mut dyn RngCore, sides: u32, rotation: Float) -> Float3 {
+    debug_assert!(sides >= 3, "A polygon needs at least 3 sides, not {}", sides);
+
+    // Split the polygon into a fan of triangles around its center, and pick
+    // one by its area. They're all the same, so that's just picking evenly.
+    let wedge = 2.0 * consts::PI / sides as Float;
+    let i = ((random_float_with(rng) * sides as Float) as u32).min(sides - 1);
+    let start = rotation.to_radians() + i as Float * wedge;
+    let a = Float3::xyz(start.cos(), start.sin(), 0.0);
+    let b = Float3::xyz((start + wedge).cos(), (start + wedge).sin(), 0.0);
+
+    // Then pick a point in the triangle, folding the half of the
+    // parallelogram past the a-b edge back over it.
+    let (mut r1, mut r2) = (random_float_with(rng), random_float_with(rng));
+    if r1 + r2 > 1.0 {
+        r1 = 1.0 - r1;
+        r2 = 1.0 - r2;
+    }
+    r1 * a + r2 * b
+}
+
 pub fn random_float() -> Float {
-    rand::random()
+    with_thread_rng(random_float_with)
+}
+
+/// `random_float()`, drawing from `rng`.
+pub fn random_float_with(rng: &mut dyn RngCore) -> Float {
+    rng.gen()
 }
 
 pub fn random_float_in(start: Float, end: Float) -> Float {
-    (end - start) * rand::random::<Float>() + end
+    with_thread_rng(|rng| random_float_in_with(rng, start, end))
+}
+
+/// `random_float_in()`, drawing from `rng`.
+pub fn random_float_in_with(rng: &mut dyn RngCore, start: Float, end: Float) -> Float {
+    (end - start) * random_float_with(rng) + end
 }
 
 pub fn random_sfloat() -> Float {
-    2.0 * rand::random::<Float>() - 1.0
+    with_thread_rng(random_sfloat_with)
+}
+
+/// `random_sfloat()`, drawing from `rng`.
+pub fn random_sfloat_with(rng: &mut dyn RngCore) -> Float {
+    2.0 * random_float_with(rng) - 1.0
+}
+
+/// An orthonormal basis built around a single direction.
+/// `w` is the direction we were given, and `u` and `v` fill out the other two
+/// axes. This lets us generate directions in a "local" frame where +Z is `w`,
+/// and then move them into world space.
+#[derive(Copy, Clone, Debug)]
+pub struct Onb {
+    axes: [Float3; 3],
+}
+
+impl Onb {
+    /// Builds a basis whose `w` axis points along `n`.
+    /// `n` does not need to be normalized, but it must not be zero.
+    pub fn build_from_w(n: Float3) -> Onb {
+        let w = n.unit();
+        // We need *some* vector that isn't parallel to `w` to cross against.
+        // Y is the usual choice, but when `w` is (nearly) Y itself the cross
+        // product degenerates, so we switch to X.
+        let a = if w.y.abs() > 0.9 {
+            Float3::xyz(1., 0., 0.)
+        } else {
+            Float3::xyz(0., 1., 0.)
+        };
+        let v = w.cross(&a).unit();
+        // Keep the basis right-handed: u x v == w.
+        let u = v.cross(&w);
+        Onb { axes: [u, v, w] }
+    }
+
+    pub fn u(&self) -> Float3 {
+        self.axes[0]
+    }
+
+    pub fn v(&self) -> Float3 {
+        self.axes[1]
+    }
+
+    pub fn w(&self) -> Float3 {
+        self.axes[2]
+    }
+
+    /// Transforms `a` from the local frame into world space.
+    pub fn local(&self, a: Float3) -> Float3 {
+        a.x * self.u() + a.y * self.v() + a.z * self.w()
+    }
+
+    /// Transforms `a` from world space into the local frame.
+    /// The inverse of `local()`.
+    pub fn to_local(&self, a: Float3) -> Float3 {
+        Float3::xyz(a.dot(&self.u()), a.dot(&self.v()), a.dot(&self.w()))
+    }
 }
 
 pub fn factors(num: u32) -> impl Iterator<Item=u32> {
@@ -88,6 +301,127 @@ pub fn factors(num: u32) -> impl Iterator<Item=u32> {
 
 #[cfg(test)]
 mod t {
+    use crate::float3::consts;
+    use crate::prelude::*;
+
+    #[test]
+    fn check_onb() {
+        let normals = [
+            Float3::xyz(0., 0., 1.),
+            Float3::xyz(1., 0., 0.),
+            Float3::xyz(0., 1., 0.),
+            Float3::xyz(0., -1., 0.),
+            Float3::xyz(1., 2., 3.),
+            Float3::xyz(-4., 0.5, -0.25),
+            // Nearly parallel to our reference axis.
+            Float3::xyz(1e-6, 1., 0.),
+            Float3::xyz(0., -1., 1e-9),
+            Float3::xyz(1e-12, 1e-3, -1e-12),
+        ];
+
+        const EPS: Float = 1e-6;
+        for &n in normals.iter() {
+            let onb = Onb::build_from_w(n);
+            let (u, v, w) = (onb.u(), onb.v(), onb.w());
+
+            // All three axes are unit length.
+            assert!((u.length() - 1.0).abs() < EPS, "n = {:?}, u = {:?}", n, u);
+            assert!((v.length() - 1.0).abs() < EPS, "n = {:?}, v = {:?}", n, v);
+            assert!((w.length() - 1.0).abs() < EPS, "n = {:?}, w = {:?}", n, w);
+
+            // And mutually orthogonal.
+            assert!(u.dot(&v).abs() < EPS, "n = {:?}", n);
+            assert!(v.dot(&w).abs() < EPS, "n = {:?}", n);
+            assert!(w.dot(&u).abs() < EPS, "n = {:?}", n);
+            assert!((u.cross(&v) - w).length() < EPS, "n = {:?}", n);
+
+            // `w` follows the normal, and local +Z maps right back onto it.
+            assert!((w - n.unit()).length() < EPS, "n = {:?}", n);
+            assert_eq!(onb.local(Float3::xyz(0., 0., 1.)), w);
+
+            // And `to_local()` undoes `local()`.
+            let a = Float3::xyz(0.25, -2., 0.5);
+            assert!((onb.to_local(onb.local(a)) - a).length() < EPS, "n = {:?}", n);
+        }
+    }
+
+    #[test]
+    fn check_polygon_is_uniform() {
+        // Whether `p` is inside the polygon, without going through the sampler.
+        let inside = |sides: u32, rotation: Float, p: Float3| {
+            (0..sides).all(|i| {
+                let angle = |i: u32| {
+                    rotation.to_radians() + 2. * consts::PI * i as Float / sides as Float
+                };
+                let a = Float3::xyz(angle(i).cos(), angle(i).sin(), 0.);
+                let b = Float3::xyz(angle(i + 1).cos(), angle(i + 1).sin(), 0.);
+                (b - a).cross(&(p - a)).z >= 0.
+            })
+        };
+
+        const BINS: usize = 8;
+        const FINE: usize = 100;
+        const SAMPLES: usize = 100_000;
+        let bin_size = 2. / BINS as Float;
+        let at = |bin: usize, fine: usize| {
+            -1. + bin_size * (bin as Float + (fine as Float + 0.5) / FINE as Float)
+        };
+        for &(sides, rotation) in [(3, 0.), (6, 15.), (5, 90.), (8, 0.)].iter() {
+            // How much of the polygon is in each bin, on a fine grid.
+            let mut area = [[0.; BINS]; BINS];
+            let mut total = 0.;
+            for (by, row) in area.iter_mut().enumerate() {
+                for (bx, bin) in row.iter_mut().enumerate() {
+                    for fy in 0..FINE {
+                        for fx in 0..FINE {
+                            if inside(sides, rotation, Float3::xyz(at(bx, fx), at(by, fy), 0.)) {
+                                *bin += 1.;
+                                total += 1.;
+                            }
+                        }
+                    }
+                }
+            }
+
+            seed_thread_rng(0x5eed + sides as u64);
+            let mut counts = [[0; BINS]; BINS];
+            for _ in 0..SAMPLES {
+                let p = random_in_polygon(sides, rotation);
+                assert_eq!(p.z, 0.);
+                assert!(p.length() <= 1. + 1e-5, "{:?}", p);
+                let bin = |c: Float| (((c + 1.) / bin_size) as usize).min(BINS - 1);
+                counts[bin(p.y)][bin(p.x)] += 1;
+            }
+
+            // Chi-square, with the bins that barely touch the polygon lumped
+            // together, so that every bin expects enough samples for it to work.
+            let (mut chi_sq, mut dof) = (0., 0);
+            let (mut rest_expected, mut rest_found) = (0., 0.);
+            let mut add = |expected: Float, found: Float| {
+                chi_sq += (found - expected) * (found - expected) / expected;
+                dof += 1;
+            };
+            for by in 0..BINS {
+                for bx in 0..BINS {
+                    let expected = SAMPLES as Float * area[by][bx] / total;
+                    let found = counts[by][bx] as Float;
+                    if expected < 20. {
+                        rest_expected += expected;
+                        rest_found += found;
+                    } else {
+                        add(expected, found);
+                    }
+                }
+            }
+            if rest_expected > 0. {
+                add(rest_expected, rest_found);
+            }
+            // Well past the 99.9th percentile for this many bins.
+            let limit = dof as Float + 5. * (2. * dof as Float).sqrt();
+            assert!(chi_sq < limit, "{} sides: chi-square {} over {} bins", sides, chi_sq, dof);
+        }
+    }
+
     #[test]
     fn check_factors() {
         let known_factors: [ &[u32]; 33 ] = [
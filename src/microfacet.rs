@@ -0,0 +1,385 @@
+//! Reflection off of rough surfaces, for `GgxMetal`.
+//!
+//! A rough surface is modeled as lots of tiny mirrors ("microfacets"), whose
+//! normals are spread out around the surface normal following the GGX (or
+//! Trowbridge-Reitz) distribution. Light reflects off of whichever facet it
+//! hits, so it leaves in the mirror direction about that facet's normal, which
+//! is the half-vector between where it came from and where it goes.
+//!
+//! Everything here works in a local frame where the surface normal is +Z, and
+//! both directions point away from the surface. `alpha` is how spread out the
+//! facets are: near 0 is a mirror, and 1 is very rough.
+//!
+//! Half-vectors are sampled from the facets that are visible from the incoming
+//! direction (Heitz 2018, "Sampling the GGX Distribution of Visible Normals").
+//! That never picks a facet that faces away from the ray, which keeps noise
+//! down at grazing angles, and it cancels most of the BRDF: each sample is
+//! weighted by just F * G2 / G1.
+
+use crate::float3::consts;
+use crate::prelude::*;
+
+/// Below this, `ndf()` is too sharp a spike to work with.
+const MIN_ALPHA: Float = 1e-4;
+
+/// The GGX `alpha` for a `roughness` between 0 and 1.
+/// Squaring it makes equal steps in roughness look about equally blurrier.
+pub fn alpha(roughness: Float) -> Float {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+/// The GGX normal distribution: the density of facets facing along `h`,
+/// per unit of solid angle and of surface area.
+pub fn ndf(alpha: Float, h: Float3) -> Float {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (consts::PI * d * d)
+}
+
+/// Smith's Λ for GGX: how much of the surface seen from `w` is hidden behind
+/// other facets, relative to what's visible.
+fn lambda(alpha: Float, w: Float3) -> Float {
+    let cos2 = w.z * w.z;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    0.5 * ((1.0 + alpha * alpha * tan2).sqrt() - 1.0)
+}
+
+/// The fraction of facets that are visible from `w`.
+pub fn smith_g1(alpha: Float, w: Float3) -> Float {
+    if w.z <= 0.0 {
+        return 0.0;
+    }
+    1.0 / (1.0 + lambda(alpha, w))
+}
+
+/// The fraction of facets that are visible from both `wi` and `wo`.
+/// This is the height-correlated form, which accounts for facets that are
+/// high up being more likely to be visible from both.
+pub fn smith_g2(alpha: Float, wi: Float3, wo: Float3) -> Float {
+    if wi.z <= 0.0 || wo.z <= 0.0 {
+        return 0.0;
+    }
+    1.0 / (1.0 + lambda(alpha, wi) + lambda(alpha, wo))
+}
+
+/// Schlick's approximation to Fresnel reflectance, for metals whose color
+/// head-on is `f0`. Everything reflects fully at grazing angles.
+pub fn fresnel(f0: Float3, cos: Float) -> Float3 {
+    let m = (1.0 - cos).max(0.0).min(1.0);
+    f0 + (m * m * m * m * m) * (Float3::xxx(1.0) - f0)
+}
+
+/// Picks a facet normal that is visible from `wi`, with the density given by
+/// `visible_normal_pdf()`. `u1` and `u2` are uniform in [0, 1).
+pub fn sample_visible_normal(alpha: Float, wi: Float3, u1: Float, u2: Float) -> Float3 {
+    // Stretch the view so that the facets make up a hemisphere.
+    let vh = Float3::xyz(alpha * wi.x, alpha * wi.y, wi.z).unit();
+
+    // Looking straight down, any tangent will do.
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len_sq > 0.0 {
+        Float3::xyz(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+    } else {
+        Float3::xyz(1.0, 0.0, 0.0)
+    };
+    let t2 = vh.cross(&t1);
+
+    // Pick a point on the disk facing `vh`, squashed into the part of it that
+    // the hemisphere covers.
+    let r = u1.sqrt();
+    let phi = 2.0 * consts::PI * u2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+
+    // Lift it up onto the hemisphere, and unstretch.
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    Float3::xyz(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).unit()
+}
+
+/// The density that `sample_visible_normal()` picks `h`, per solid angle.
+pub fn visible_normal_pdf(alpha: Float, wi: Float3, h: Float3) -> Float {
+    if wi.z <= 0.0 {
+        return 0.0;
+    }
+    smith_g1(alpha, wi) * wi.dot(&h).max(0.0) * ndf(alpha, h) / wi.z
+}
+
+/// The density that `sample()` reflects `wi` into `wo`, per solid angle.
+pub fn pdf(alpha: Float, wi: Float3, wo: Float3) -> Float {
+    let h = (wi + wo).unit();
+    let cos = wi.dot(&h);
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    // Reflecting squeezes solid angle around `h` into a quarter of it.
+    visible_normal_pdf(alpha, wi, h) / (4.0 * cos)
+}
+
+/// The BRDF for light from `wo` reflecting into `wi`, times the cosine at `wo`.
+pub fn eval(alpha: Float, f0: Float3, wi: Float3, wo: Float3) -> Float3 {
+    if wi.z <= 0.0 || wo.z <= 0.0 {
+        return Float3::new();
+    }
+    let h = (wi + wo).unit();
+    let d = ndf(alpha, h) * smith_g2(alpha, wi, wo) / (4.0 * wi.z);
+    d * fresnel(f0, wi.dot(&h))
+}
+
+/// Picks the direction that `wi` reflects into, and its weight:
+/// `eval() / pdf()`. Gives `None` when the ray went into the surface, since
+/// it's absorbed, as far as one bounce between facets can tell.
+pub fn sample(alpha: Float, f0: Float3, wi: Float3, u1: Float, u2: Float)
+    -> Option<(Float3, Float3)>
+{
+    if wi.z <= 0.0 {
+        return None;
+    }
+    let h = sample_visible_normal(alpha, wi, u1, u2);
+    let cos = wi.dot(&h);
+    let wo = 2.0 * cos * h - wi;
+    if wo.z <= 0.0 {
+        return None;
+    }
+    let weight = smith_g2(alpha, wi, wo) / smith_g1(alpha, wi);
+    Some((wo, weight * fresnel(f0, cos)))
+}
+
+#[cfg(test)]
+mod t {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::material::{
+        GgxMetal,
+        Metal,
+    };
+
+    const ROUGHNESSES: [Float; 6] = [0.05, 0.1, 0.25, 0.5, 0.75, 1.0];
+
+    /// Directions coming in from straight above, down to nearly grazing.
+    fn incoming() -> Vec<Float3> {
+        [0.0, 30.0, 60.0, 80.0 as Float]
+            .iter()
+            .map(|deg| {
+                let theta = deg.to_radians();
+                Float3::xyz(theta.sin(), 0.0, theta.cos())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_half_vectors_follow_ndf() {
+        seed_thread_rng(1);
+        const N: usize = 40_000;
+        let straight_down = Float3::xyz(0.0, 0.0, 1.0);
+        for &roughness in ROUGHNESSES.iter() {
+            let alpha = alpha(roughness);
+            let a2 = alpha * alpha;
+            let tan2: Vec<Float> = (0..N)
+                .map(|_| {
+                    let h = sample_visible_normal(alpha, straight_down,
+                                                  random_float(), random_float());
+                    assert!(h.z > 0.0 && (h.length() - 1.0).abs() < 1e-6, "{:?}", h);
+                    (1.0 - h.z * h.z) / (h.z * h.z)
+                })
+                .collect();
+
+            // Seen from straight above, every facet is visible, so half-vectors
+            // should follow D(h) cos(h) itself, whose CDF over tan^2 is
+            // x / (alpha^2 + x). So a quarter should land below alpha^2 / 3,
+            // half below alpha^2, and three quarters below 3 alpha^2.
+            for &(x, expected) in [(a2 / 3.0, 0.25), (a2, 0.5), (3.0 * a2, 0.75)].iter() {
+                let below = tan2.iter().filter(|&&t| t < x).count() as Float / N as Float;
+                assert!((below - expected).abs() < 0.015,
+                        "roughness {}: {} below {}, expected {}", roughness, below, x, expected);
+            }
+        }
+    }
+
+    /// Reflects `wi` off of a facet picked from the plain NDF, rather than
+    /// just the visible ones, for checking against. Seen from straight above,
+    /// every facet is visible, so that's what we sample there.
+    /// Gives the direction and the density of picking it.
+    fn sample_ndf(alpha: Float, wi: Float3) -> Option<(Float3, Float)> {
+        let up = Float3::xyz(0.0, 0.0, 1.0);
+        let h = sample_visible_normal(alpha, up, random_float(), random_float());
+        let cos = wi.dot(&h);
+        if cos <= 0.0 {
+            return None;
+        }
+        Some((2.0 * cos * h - wi, ndf(alpha, h) * h.z / (4.0 * cos)))
+    }
+
+    #[test]
+    fn check_pdf_matches_samples() {
+        seed_thread_rng(2);
+        const N: usize = 100_000;
+        for &roughness in ROUGHNESSES.iter() {
+            let alpha = alpha(roughness);
+            for &wi in incoming().iter() {
+                // Averages over `pdf()`...
+                let (mut total, mut x, mut z) = (0.0, 0.0, 0.0);
+                for _ in 0..N {
+                    if let Some((wo, ndf_pdf)) = sample_ndf(alpha, wi) {
+                        let p = pdf(alpha, wi, wo) / ndf_pdf;
+                        total += p;
+                        x += p * wo.x;
+                        z += p * wo.z;
+                    }
+                }
+                let (total, x, z) = (total / N as Float, x / N as Float, z / N as Float);
+                assert!((total - 1.0).abs() < 0.03, "roughness {}, {:?}: {}", roughness, wi, total);
+
+                // ...match the averages of what `sample_visible_normal()` picks.
+                let (mut sx, mut sz) = (0.0, 0.0);
+                for _ in 0..N {
+                    let h = sample_visible_normal(alpha, wi, random_float(), random_float());
+                    let wo = 2.0 * wi.dot(&h) * h - wi;
+                    sx += wo.x;
+                    sz += wo.z;
+                }
+                let (sx, sz) = (sx / N as Float, sz / N as Float);
+                assert!((x - sx).abs() < 0.03 && (z - sz).abs() < 0.03,
+                        "roughness {}, {:?}: ({}, {}) vs ({}, {})", roughness, wi, x, z, sx, sz);
+            }
+        }
+    }
+
+    /// The fraction of light that `sample` reflects back out from `wi`,
+    /// averaged over `n` samples.
+    fn furnace(n: usize, mut sample: impl FnMut(Float3) -> Option<Float3>, wi: Float3) -> Float {
+        let total: Float = (0..n)
+            .map(|_| sample(wi).map_or(0.0, |weight| weight.luminance()))
+            .sum();
+        total / n as Float
+    }
+
+    #[test]
+    fn check_white_furnace() {
+        seed_thread_rng(3);
+        let mut rng = seeded_rng(3);
+        const N: usize = 40_000;
+        let white = Float3::xxx(1.0);
+        let mut head_on = 1.0 + 1e-6;
+        for &roughness in ROUGHNESSES.iter() {
+            let alpha = alpha(roughness);
+            let material = GgxMetal { albedo: white, roughness };
+            for &wi in incoming().iter() {
+                let sampled = furnace(N, |wi| {
+                    sample(alpha, white, wi, random_float(), random_float())
+                        .map(|(_, weight)| weight)
+                }, wi);
+                // A white metal can't reflect more than comes in. Light that
+                // bounces between facets more than once is lost, though, which
+                // is hardly any of it for smooth surfaces, but most of it for
+                // the roughest.
+                assert!(sampled <= 1.0 + 1e-6, "roughness {}, {:?}: {}", roughness, wi, sampled);
+                if roughness <= 0.1 {
+                    assert!(sampled > 0.99, "roughness {}, {:?}: {}", roughness, wi, sampled);
+                }
+                if wi.z == 1.0 {
+                    assert!(sampled < head_on, "roughness {}: {}", roughness, sampled);
+                    head_on = sampled;
+                }
+
+                // The material does the same, through the integrator's interface.
+                let through_material = furnace(N, |wi| {
+                    let (ray, record) = hit(Arc::new(material), wi);
+                    match material.scatter(&ray, &record, &mut rng) {
+                        Scatter::Bounce(ScatterRecord { attenuation, scattered }) => {
+                            assert!(scattered.dir.z > 0.0);
+                            Some(attenuation)
+                        },
+                        Scatter::Absorb(color) => {
+                            // Absorbed rays don't leave anything behind.
+                            assert_eq!(color, Float3::new());
+                            None
+                        },
+                    }
+                }, wi);
+                assert!((through_material - sampled).abs() < 0.02,
+                        "roughness {}, {:?}: {} vs {}", roughness, wi, through_material, sampled);
+
+                // And the weights are unbiased: integrating `eval()` with
+                // other directions comes out the same.
+                let plain = furnace(N, |wi| {
+                    sample_ndf(alpha, wi).map(|(wo, pdf)| eval(alpha, white, wi, wo) / pdf)
+                }, wi);
+                assert!((plain - sampled).abs() < 0.03,
+                        "roughness {}, {:?}: {} vs {}", roughness, wi, plain, sampled);
+            }
+        }
+    }
+
+    /// A ray coming from `wi` that hits the floor at the origin.
+    fn hit(material: Arc<dyn Material>, wi: Float3) -> (Ray, HitRecord) {
+        let ray = Ray {
+            origin: wi,
+            dir:    -wi,
+            t:      0.0,
+        };
+        let record = HitRecord {
+            t:        1.0,
+            p:        Float3::new(),
+            normal:   Float3::xyz(0.0, 0.0, 1.0),
+            u:        0.0,
+            v:        0.0,
+            tangent:  Float3::xyz(1.0, 0.0, 0.0),
+            material,
+        };
+        (ray, record)
+    }
+
+    #[test]
+    fn check_smooth_is_a_mirror() {
+        let mut rng = seeded_rng(4);
+        const N: usize = 1000;
+        let albedo = Float3::xyz(0.9, 0.6, 0.3);
+        let mirror = Metal { albedo, fuzz: 0.0 };
+        for &wi in incoming().iter() {
+            // GGX has long tails, so a few rays always stray, but on average
+            // they close in on the mirror direction as the surface gets smoother.
+            let mut last_error = 2.0;
+            for &roughness in [0.1, 0.03, 0.01].iter() {
+                let ggx = GgxMetal { albedo, roughness };
+                let (mut error, mut missed) = (0.0, 0);
+                for _ in 0..N {
+                    let (ray, record) = hit(Arc::new(ggx), wi);
+                    let bounce = ggx.scatter(&ray, &record, &mut rng).bounce();
+                    let (ggx_color, ggx_ray) = match bounce {
+                        Some(bounce) => (bounce.attenuation, bounce.scattered),
+                        None => {
+                            // Facets steep enough to send rays into the surface
+                            // get rarer, too.
+                            missed += 1;
+                            continue;
+                        },
+                    };
+                    let reflected = mirror.scatter(&ray, &record, &mut rng).bounce().unwrap();
+                    let (mirror_color, mirror_ray) = (reflected.attenuation, reflected.scattered);
+                    assert_eq!(ggx_ray.origin, mirror_ray.origin);
+                    error += (ggx_ray.dir.unit() - mirror_ray.dir.unit()).length();
+
+                    // The color is the albedo head on, and whitens toward grazing.
+                    if roughness == 0.01 {
+                        let expected = fresnel(albedo, wi.z);
+                        assert!((ggx_color - expected).length() < 1e-2,
+                                "{:?}: {:?} vs {:?}", wi, ggx_color, expected);
+                        if wi.z == 1.0 {
+                            assert!((ggx_color - mirror_color).length() < 1e-3);
+                        }
+                    }
+                }
+                error /= (N - missed) as Float;
+                assert!(roughness > 0.01 || missed == 0, "{:?}: {}", wi, missed);
+                assert!(error < last_error, "{:?}: {} then {}", wi, last_error, error);
+                last_error = error;
+            }
+            assert!(last_error < 1e-3, "{:?}: {}", wi, last_error);
+        }
+    }
+}
@@ -0,0 +1,721 @@
+//! Writing finished renders to disk.
+//!
+//! Every output starts from the same linear framebuffer. Formats that store
+//! 8-bit color (PNG and PPM) are gamma corrected, run through the LUT (if
+//! there is one), and quantized on the way out, while PFM keeps the raw
+//! floating point values. Debug overlays like `--draw-axes` are drawn over
+//! the 8-bit outputs after that.
+//!
+//! Images rendered with `--transparent-background` also have an alpha channel,
+//! which only PNG can hold. Their linear colors are premultiplied by alpha (the
+//! background adds nothing to them), but PNGs are written with straight alpha,
+//! as the format expects: we divide it back out before tonemapping.
+//!
+//! PNGs also say which build wrote them, in a "Software" text chunk holding
+//! `build_info::VERSION_LINE`. `png_text()` reads it back.
+//!
+//! `--raw-output` writes the linear framebuffer with nothing lost at all, not
+//! even the precision PFM's 32-bit floats would cost us. Its layout is
+//! described on `LinearImage::load_raw()`, which reads it back.
+
+use std::{
+    convert::TryInto,
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    mem,
+    path,
+};
+
+use crate::axes;
+use crate::build_info;
+use crate::camera::Camera;
+use crate::lut::Lut;
+use crate::prelude::*;
+
+/// Linear radiance for every pixel, row-major from the top of the image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearImage {
+    width:  u32,
+    height: u32,
+    pixels: Vec<Float3>,
+    // Coverage of every pixel, from 0 to 1, when we have it.
+    alpha:  Option<Vec<Float>>,
+}
+
+impl LinearImage {
+    pub fn from_fn(width: u32, height: u32, mut f: impl FnMut(u32, u32) -> Float3)
+        -> LinearImage
+    {
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(f(x, y));
+            }
+        }
+        LinearImage {
+            width,
+            height,
+            pixels,
+            alpha: None,
+        }
+    }
+
+    /// Adds an alpha channel to the image.
+    /// The colors should already be premultiplied by it.
+    pub fn with_alpha(mut self, mut f: impl FnMut(u32, u32) -> Float) -> LinearImage {
+        let mut alpha = Vec::with_capacity(self.pixels.len());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                alpha.push(f(x, y));
+            }
+        }
+        self.alpha = Some(alpha);
+        self
+    }
+
+    /// Undoes the gamma correction of an 8-bit image.
+    /// Anything brighter than 1.0 was lost when it was quantized, of course.
+    pub fn from_rgb8(img: &image::RgbImage) -> LinearImage {
+        LinearImage::from_fn(img.width(), img.height(), |x, y| {
+            let [r, g, b] = img.get_pixel(x, y).data;
+            let linear = |c: u8| {
+                let c = c as Float / 255.0;
+                c * c
+            };
+            Float3::xyz(linear(r), linear(g), linear(b))
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Float3 {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, rgb: Float3) {
+        self.pixels[y as usize * self.width as usize + x as usize] = rgb;
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.alpha.is_some()
+    }
+
+    /// How much of the pixel is covered. Images without alpha are opaque.
+    pub fn get_alpha(&self, x: u32, y: u32) -> Float {
+        match self.alpha {
+            Some(ref alpha) => alpha[y as usize * self.width as usize + x as usize],
+            None => 1.0,
+        }
+    }
+
+    /// Reads an image written by `write_raw()`. Raw files are all little
+    /// endian, and start with a 20 byte header:
+    ///
+    /// - b"LRAD"
+    /// - the width and height, as u32s
+    /// - how many channels each pixel has, as a u32: 3 for RGB, or 4 for RGB
+    ///   and alpha, with the colors premultiplied by it
+    /// - how many bytes each channel takes, as a u32: 4 for f32, or 8 for f64
+    ///
+    /// After that, every channel of every pixel, row-major from the top of the
+    /// image.
+    ///
+    /// `write_raw()` writes .pfm paths as PFMs, so this reads those too.
+    pub fn load_raw(path: &path::Path) -> Result<LinearImage, String> {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let img = if bytes.starts_with(b"PF\n") {
+            LinearImage::from_pfm_bytes(&bytes)
+        } else {
+            LinearImage::from_raw_bytes(&bytes)
+        };
+        img.map_err(|msg| format!("{}: {}", path.display(), msg))
+    }
+
+    /// Reads a color PFM, like `write_pfm()` writes.
+    fn from_pfm_bytes(bytes: &[u8]) -> Result<LinearImage, String> {
+        // The header is three lines of text.
+        let header_end = bytes.iter()
+                              .enumerate()
+                              .filter(|(_, &b)| b == b'\n')
+                              .nth(2)
+                              .map(|(i, _)| i + 1)
+                              .ok_or("The PFM header is cut short")?;
+        let header = String::from_utf8_lossy(&bytes[..header_end]);
+        let header: Vec<&str> = header.split_whitespace().collect();
+        let field = |i: usize| header.get(i).cloned().unwrap_or("");
+        let (width, height, scale) = match (field(1).parse::<u32>(),
+                                            field(2).parse::<u32>(),
+                                            field(3).parse::<f32>()) {
+            (Ok(width), Ok(height), Ok(scale)) => (width, height, scale),
+            _ => return Err("The PFM header is broken".into()),
+        };
+        let floats = &bytes[header_end..];
+        if floats.len() != 4 * 3 * width as usize * height as usize {
+            return Err(format!("Expected {}x{} pixels, but the file is the wrong size",
+                               width, height));
+        }
+
+        // A negative scale means little endian.
+        let read = |i: usize| {
+            let b = [floats[4 * i], floats[4 * i + 1], floats[4 * i + 2], floats[4 * i + 3]];
+            let bits = if scale < 0.0 { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) };
+            f32::from_bits(bits) as Float
+        };
+        // PFM goes from the bottom of the image up.
+        Ok(LinearImage::from_fn(width, height, |x, y| {
+            let i = 3 * ((height - 1 - y) as usize * width as usize + x as usize);
+            Float3::xyz(read(i), read(i + 1), read(i + 2))
+        }))
+    }
+
+    fn from_raw_bytes(bytes: &[u8]) -> Result<LinearImage, String> {
+        if bytes.len() < RAW_HEADER_SIZE || &bytes[..4] != RAW_MAGIC {
+            return Err("This isn't a raw image".into());
+        }
+        let header_u32 = |i: usize| {
+            u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap())
+        };
+        let (width, height, channels, size) = (header_u32(1), header_u32(2),
+                                               header_u32(3), header_u32(4));
+        if channels != 3 && channels != 4 {
+            return Err(format!("Raw images have 3 or 4 channels, not {}", channels));
+        }
+        let read: fn(&[u8]) -> Float = match size {
+            4 => |b| f32::from_bits(u32::from_le_bytes(b.try_into().unwrap())) as Float,
+            8 => |b| f64::from_bits(u64::from_le_bytes(b.try_into().unwrap())) as Float,
+            _ => return Err(format!("Raw images have 4 or 8 byte floats, not {}", size)),
+        };
+        let expected = width as usize * height as usize * channels as usize;
+        if bytes.len() != RAW_HEADER_SIZE + expected * size as usize {
+            return Err(format!("A {}x{} raw image with {} channels should have {} floats, \
+                                but this one has {} bytes of them",
+                               width, height, channels, expected,
+                               bytes.len() - RAW_HEADER_SIZE));
+        }
+        let floats: Vec<Float> = bytes[RAW_HEADER_SIZE..].chunks(size as usize).map(read).collect();
+
+        let channel = |x: u32, y: u32, c: u32| {
+            floats[((y * width + x) * channels + c) as usize]
+        };
+        let img = LinearImage::from_fn(width, height, |x, y| {
+            Float3::xyz(channel(x, y, 0), channel(x, y, 1), channel(x, y, 2))
+        });
+        if channels == 4 {
+            Ok(img.with_alpha(|x, y| channel(x, y, 3)))
+        } else {
+            Ok(img)
+        }
+    }
+
+    /// The image as we'd display it.
+    pub fn to_rgb8(&self) -> image::RgbImage {
+        self.to_rgb8_with(None)
+    }
+
+    /// The image as we'd display it, with the look from `lut`.
+    pub fn to_rgb8_with(&self, lut: Option<&Lut>) -> image::RgbImage {
+        image::RgbImage::from_fn(self.width, self.height, |x, y| {
+            let display = to_display(self.get_pixel(x, y));
+            quantize(lut.map_or(display, |lut| lut.apply(display)))
+        })
+    }
+
+    /// Like `to_rgb8_with()`, with straight alpha.
+    pub fn to_rgba8_with(&self, lut: Option<&Lut>) -> image::RgbaImage {
+        image::RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let alpha = self.get_alpha(x, y).max(0.0).min(1.0);
+            // Nothing covers the pixel, so it has no color to speak of.
+            let straight = if alpha > 0.0 {
+                self.get_pixel(x, y) / alpha
+            } else {
+                Float3::new()
+            };
+            let display = to_display(straight);
+            let [r, g, b] = quantize(lut.map_or(display, |lut| lut.apply(display))).data;
+            image::Rgba([r, g, b, (alpha * 255.99) as u8])
+        })
+    }
+}
+
+/// Raw images start with this. See `LinearImage::load_raw()`.
+const RAW_MAGIC: &[u8] = b"LRAD";
+const RAW_HEADER_SIZE: usize = 20;
+
+/// Averaged linear color => gamma corrected 8-bit color.
+/// Anything brighter than 1.0 is clipped.
+pub fn to_rgb8(rgb: Float3) -> image::Rgb<u8> {
+    quantize(to_display(rgb))
+}
+
+/// Averaged linear color => gamma corrected color in [0, 1].
+fn to_display(rgb: Float3) -> Float3 {
+    // Gamma correct
+    let rgb = rgb.sqrt();
+    rgb.max(&Float3::new()).min(&Float3::xxx(1.0))
+}
+
+fn quantize(rgb: Float3) -> image::Rgb<u8> {
+    // Scale into u8 range
+    let rgb: Float3 = rgb * 255.99;
+    image::Rgb([
+        rgb.x as u8,
+        rgb.y as u8,
+        rgb.z as u8,
+    ])
+}
+
+/// The kinds of files we know how to write.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// 8-bit PNG
+    Png,
+    /// 8-bit binary PPM ("P6")
+    Ppm,
+    /// 32-bit float PFM, for keeping everything we rendered
+    Pfm,
+}
+
+impl Format {
+    /// Picks a format from the extension of `path`.
+    pub fn from_path(path: &path::Path) -> Result<Format, String> {
+        let ext = path.extension()
+                      .map(|ext| ext.to_string_lossy().to_lowercase())
+                      .unwrap_or_default();
+        match ext.as_str() {
+            "png" => Ok(Format::Png),
+            "ppm" => Ok(Format::Ppm),
+            "pfm" => Ok(Format::Pfm),
+            _ => Err(format!("Unsupported output format \"{}\" for {}. Try png, ppm, or pfm",
+                             ext, path.display())),
+        }
+    }
+
+    /// Whether the format can hold an alpha channel.
+    pub fn has_alpha(self) -> bool {
+        match self {
+            Format::Png => true,
+            Format::Ppm | Format::Pfm => false,
+        }
+    }
+}
+
+/// Writes `img` to `path`, in the format its extension asks for.
+/// `lut` only applies to the 8-bit formats, and so do the axes drawn for
+/// `axes_from`, if that's given.
+pub fn write(img:       &LinearImage,
+             path:      &path::Path,
+             lut:       Option<&Lut>,
+             axes_from: Option<&Camera>)
+    -> Result<(), String>
+{
+    let to_rgb8 = || {
+        let mut rgb8 = img.to_rgb8_with(lut);
+        if let Some(cam) = axes_from {
+            axes::draw(&mut rgb8, cam);
+        }
+        rgb8
+    };
+    let to_rgba8 = || {
+        let mut rgba8 = img.to_rgba8_with(lut);
+        if let Some(cam) = axes_from {
+            draw_axes_rgba(&mut rgba8, cam);
+        }
+        rgba8
+    };
+    let format = Format::from_path(path)?;
+    if img.has_alpha() && !format.has_alpha() {
+        return Err(format!("{} can't hold the alpha channel from --transparent-background. \
+                            Try png",
+                           path.display()));
+    }
+    let result = match format {
+        Format::Png if img.has_alpha() => {
+            let rgba8 = to_rgba8();
+            write_png(&rgba8, rgba8.dimensions(), image::ColorType::RGBA(8), path)
+        },
+        Format::Png => {
+            let rgb8 = to_rgb8();
+            write_png(&rgb8, rgb8.dimensions(), image::ColorType::RGB(8), path)
+        },
+        Format::Ppm => write_ppm(&to_rgb8(), path),
+        Format::Pfm => write_pfm(img, path),
+    };
+    result.map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Writes `img` to every path in `paths`.
+/// A failure on one path doesn't stop us from trying the rest, so we return
+/// the error (if any) for each of them.
+pub fn write_all<'a>(img:       &LinearImage,
+                     paths:     &'a [path::PathBuf],
+                     lut:       Option<&Lut>,
+                     axes_from: Option<&Camera>)
+    -> Vec<(&'a path::Path, Result<(), String>)>
+{
+    paths.iter()
+         .map(|path| (path.as_path(), write(img, path, lut, axes_from)))
+         .collect()
+}
+
+/// Writes `img` to `path` exactly as it is, for `--raw-output`. A .pfm path
+/// gets a PFM, and anything else our own raw layout, which
+/// `LinearImage::load_raw()` reads.
+pub fn write_raw(img: &LinearImage, path: &path::Path) -> Result<(), String> {
+    if let Ok(Format::Pfm) = Format::from_path(path) {
+        return write(img, path, None, None);
+    }
+    write_raw_floats(img, path).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Draws the axes from `cam` over `img`, making them opaque.
+fn draw_axes_rgba(img: &mut image::RgbaImage, cam: &Camera) {
+    let mut rgb8 = image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, _] = img.get_pixel(x, y).data;
+        image::Rgb([r, g, b])
+    });
+    axes::draw(&mut rgb8, cam);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let [r, g, b] = rgb8.get_pixel(x, y).data;
+        // Whatever the axes drew over.
+        if pixel.data[..3] != [r, g, b] {
+            pixel.data = [r, g, b, 255];
+        }
+    }
+}
+
+/// Every PNG starts with these 8 bytes.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Writes `pixels` as a PNG, with a text chunk naming the build that wrote it.
+fn write_png(pixels:          &[u8],
+             (width, height): (u32, u32),
+             color:           image::ColorType,
+             path:            &path::Path)
+    -> io::Result<()>
+{
+    let mut png = vec![];
+    image::png::PNGEncoder::new(&mut png).encode(pixels, width, height, color)?;
+
+    // Text chunks can go anywhere after IHDR, which always comes first.
+    // It's 13 bytes, plus 12 for its length, type, and CRC.
+    let after_ihdr = PNG_SIGNATURE.len() + 12 + 13;
+    let mut text = b"Software\0".to_vec();
+    text.extend_from_slice(build_info::VERSION_LINE.as_bytes());
+    let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"tEXt");
+    chunk.extend_from_slice(&text);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    png.splice(after_ihdr..after_ihdr, chunk);
+
+    fs::write(path, png)
+}
+
+/// The key and value of every tEXt chunk in the PNG at `path`.
+pub fn png_text(path: &path::Path) -> Result<Vec<(String, String)>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err(format!("{} isn't a PNG", path.display()));
+    }
+    let mut text = vec![];
+    let mut rest = &bytes[PNG_SIGNATURE.len()..];
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        if rest.len() < 12 + len {
+            return Err(format!("{} has a chunk that runs past the end", path.display()));
+        }
+        if &rest[4..8] == b"tEXt" {
+            // Latin-1, which is the same as UTF-8 for the ASCII we write.
+            let data: String = rest[8..8 + len].iter().map(|&b| b as char).collect();
+            let mut parts = data.splitn(2, '\0');
+            let key = parts.next().unwrap_or_default().to_string();
+            text.push((key, parts.next().unwrap_or_default().to_string()));
+        }
+        rest = &rest[12 + len..];
+    }
+    Ok(text)
+}
+
+/// The CRC that PNG chunks end with, over their type and data.
+/// This goes a bit at a time, which is plenty for the one chunk we write.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn write_ppm(img: &image::RgbImage, path: &path::Path) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", img.width(), img.height())?;
+    file.write_all(img)?;
+    file.flush()
+}
+
+fn write_pfm(img: &LinearImage, path: &path::Path) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    // A negative scale means little endian.
+    write!(file, "PF\n{} {}\n-1.0\n", img.width, img.height)?;
+    // PFM goes from the bottom of the image up.
+    for y in (0..img.height).rev() {
+        for x in 0..img.width {
+            for c in img.get_pixel(x, y).as_slice().iter() {
+                file.write_all(&(*c as f32).to_bits().to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+fn write_raw_floats(img: &LinearImage, path: &path::Path) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    let channels = if img.has_alpha() { 4 } else { 3 };
+    file.write_all(RAW_MAGIC)?;
+    for n in [img.width, img.height, channels, mem::size_of::<Float>() as u32].iter() {
+        file.write_all(&n.to_le_bytes())?;
+    }
+    for y in 0..img.height {
+        for x in 0..img.width {
+            for c in img.get_pixel(x, y).as_slice().iter() {
+                file.write_all(&c.to_le_bytes())?;
+            }
+            if img.has_alpha() {
+                file.write_all(&img.get_alpha(x, y).to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    /// Reads back a PFM written by `write_pfm()`.
+    fn read_pfm(path: &path::Path) -> LinearImage {
+        let bytes = fs::read(path).unwrap();
+        assert!(bytes.starts_with(b"PF\n5 3\n-1.0\n"));
+        LinearImage::load_raw(path).unwrap()
+    }
+
+    /// Reads back a PPM written by `write_ppm()`.
+    fn read_ppm(path: &path::Path) -> image::RgbImage {
+        let bytes = fs::read(path).unwrap();
+        let header = b"P6\n5 3\n255\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        image::RgbImage::from_raw(5, 3, bytes[header.len()..].to_vec()).unwrap()
+    }
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_image() -> LinearImage {
+        // Include some values past 1.0, which only the PFM keeps.
+        LinearImage::from_fn(5, 3, |x, y| {
+            Float3::xyz(x as Float / 4.0, y as Float / 2.0, 0.75 * (x + y) as Float)
+        })
+    }
+
+    #[test]
+    fn check_outputs_agree() {
+        let dir = test_dir("outputs-agree");
+        let paths = vec![
+            dir.join("out.png"),
+            dir.join("out.ppm"),
+            dir.join("out.pfm"),
+        ];
+        let img = test_image();
+
+        for (path, result) in write_all(&img, &paths, None, None) {
+            assert_eq!(result, Ok(()), "{}", path.display());
+        }
+
+        let png = image::open(&paths[0]).unwrap().to_rgb();
+        let ppm = read_ppm(&paths[1]);
+        let pfm = read_pfm(&paths[2]);
+        assert_eq!(*png, *ppm);
+        assert_eq!((pfm.width(), pfm.height()), (5, 3));
+
+        for y in 0..3 {
+            for x in 0..5 {
+                // PFM holds f32s, so it's close but not exact.
+                let expected = img.get_pixel(x, y);
+                let found = pfm.get_pixel(x, y);
+                assert!((expected - found).length() < 1e-6,
+                        "({}, {}): {:?} vs {:?}", x, y, expected, found);
+
+                // And once we tonemap it, it matches the 8-bit outputs to
+                // within quantization error.
+                let tonemapped = to_rgb8(found).data;
+                let quantized = png.get_pixel(x, y).data;
+                for (a, b) in tonemapped.iter().zip(quantized.iter()) {
+                    assert!((*a as i32 - *b as i32).abs() <= 1,
+                            "({}, {}): {:?} vs {:?}", x, y, tonemapped, quantized);
+                }
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_pngs_name_their_build() {
+        let dir = test_dir("png-text");
+        let opaque = dir.join("opaque.png");
+        let clear = dir.join("clear.png");
+        let img = test_image();
+        assert_eq!(write(&img, &opaque, None, None), Ok(()));
+        assert_eq!(write(&img.clone().with_alpha(|_, _| 0.5), &clear, None, None), Ok(()));
+
+        let software = ("Software".to_string(), build_info::VERSION_LINE.to_string());
+        for path in [&opaque, &clear].iter() {
+            assert_eq!(png_text(path), Ok(vec![software.clone()]), "{}", path.display());
+            // The chunk doesn't get in the way of reading the image.
+            assert_eq!(image::open(path).unwrap().to_rgba().dimensions(), (5, 3));
+        }
+        // Every PNG ends with this exact chunk.
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert!(png_text(&dir.join("missing.png")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_one_bad_output_does_not_stop_the_rest() {
+        let dir = test_dir("bad-output");
+        let paths = vec![
+            dir.join("missing-dir").join("out.png"),
+            dir.join("out.ppm"),
+            dir.join("out.exr"),
+            dir.join("out.pfm"),
+        ];
+
+        let results = write_all(&test_image(), &paths, None, None);
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].1, Ok(()));
+        assert!(results[2].1.is_err());
+        assert_eq!(results[3].1, Ok(()));
+        assert!(paths[1].exists());
+        assert!(paths[3].exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_lut_only_changes_8bit_outputs() {
+        let dir = test_dir("lut");
+        let paths = vec![
+            dir.join("out.ppm"),
+            dir.join("out.pfm"),
+        ];
+        // Inverts everything.
+        let lut = Lut::parse_curve("0 1\n1 0\n").unwrap();
+        let img = test_image();
+        for (path, result) in write_all(&img, &paths, Some(&lut), None) {
+            assert_eq!(result, Ok(()), "{}", path.display());
+        }
+
+        let ppm = read_ppm(&paths[0]);
+        let pfm = read_pfm(&paths[1]);
+        for y in 0..3 {
+            for x in 0..5 {
+                let plain = to_rgb8(img.get_pixel(x, y)).data;
+                let inverted = ppm.get_pixel(x, y).data;
+                for (a, b) in plain.iter().zip(inverted.iter()) {
+                    assert!((255 - *a as i32 - *b as i32).abs() <= 1,
+                            "({}, {}): {:?} vs {:?}", x, y, plain, inverted);
+                }
+                assert!((pfm.get_pixel(x, y) - img.get_pixel(x, y)).length() < 1e-6);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_alpha_outputs() {
+        let dir = test_dir("alpha");
+        let paths = vec![
+            dir.join("out.png"),
+            dir.join("out.ppm"),
+            dir.join("out.pfm"),
+        ];
+        // Half of a pixel covered by a quarter gray is premultiplied to an
+        // eighth.
+        let img = LinearImage::from_fn(3, 1, |x, _| Float3::xxx([0.0, 0.125, 0.25][x as usize]))
+            .with_alpha(|x, _| [0.0, 0.5, 1.0][x as usize]);
+
+        let results = write_all(&img, &paths, None, None);
+        assert_eq!(results[0].1, Ok(()));
+        assert!(results[1].1.is_err());
+        assert!(results[2].1.is_err());
+        assert!(!paths[1].exists());
+        assert!(!paths[2].exists());
+
+        let png = image::open(&paths[0]).unwrap().to_rgba();
+        let pixels: Vec<[u8; 4]> = png.pixels().map(|px| px.data).collect();
+        // Straight alpha, so both covered pixels are the same gray.
+        assert_eq!(pixels, [[0, 0, 0, 0], [127, 127, 127, 127], [127, 127, 127, 255]]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_raw_round_trips() {
+        let dir = test_dir("raw");
+        let path = dir.join("out.bin");
+        // Values that 32-bit floats can't hold exactly, in f64 builds.
+        let img = LinearImage::from_fn(5, 3, |x, y| test_image().get_pixel(x, y) / 3.0);
+        assert_eq!(write_raw(&img, &path), Ok(()));
+        assert_eq!(LinearImage::load_raw(&path), Ok(img.clone()));
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 20 + 5 * 3 * 3 * mem::size_of::<Float>());
+
+        let with_alpha = img.clone().with_alpha(|x, _| x as Float / 4.0);
+        assert_eq!(write_raw(&with_alpha, &path), Ok(()));
+        assert_eq!(LinearImage::load_raw(&path), Ok(with_alpha));
+
+        // The other float size reads too.
+        let mut f32_bytes = b"LRAD".to_vec();
+        for n in [2u32, 1, 3, 4].iter() {
+            f32_bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        for c in [0.5f32, 1.0, 2.0, 0.25, 0.125, 4.0].iter() {
+            f32_bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        assert_eq!(LinearImage::from_raw_bytes(&f32_bytes),
+                   Ok(LinearImage::from_fn(2, 1, |x, _| {
+                       [Float3::xyz(0.5, 1.0, 2.0), Float3::xyz(0.25, 0.125, 4.0)][x as usize]
+                   })));
+        assert!(LinearImage::from_raw_bytes(&f32_bytes[..f32_bytes.len() - 1]).is_err());
+        assert!(LinearImage::from_raw_bytes(&bytes[..19]).is_err());
+        assert!(LinearImage::from_raw_bytes(b"PF\n5 3\n-1.0\n0000000000").is_err());
+
+        // A .pfm is a PFM, which reads back too.
+        let pfm = dir.join("out.pfm");
+        assert_eq!(write_raw(&img, &pfm), Ok(()));
+        let diff = read_pfm(&pfm).get_pixel(4, 2) - img.get_pixel(4, 2);
+        assert!(diff.length() < 1e-6, "{:?}", diff);
+        assert!(LinearImage::from_pfm_bytes(b"PF\n5 3\n-1.0\n0000000000").is_err());
+        assert!(LinearImage::from_pfm_bytes(b"PF\n5 3\n").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
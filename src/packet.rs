@@ -0,0 +1,314 @@
+//! Four camera rays at a time.
+//!
+//! Neighboring pixels' camera rays leave from about the same place, headed in
+//! about the same direction, so they tend to visit the same BVH nodes and test
+//! the same spheres. A `RayPacket4` holds four of them, a ray to a lane, so
+//! that each box or sphere is loaded once for all of them, and the math for
+//! all four happens side by side in `Lanes`.
+//!
+//! Each lane goes through the same operations, in the same order, as testing
+//! its ray alone, so packets find exactly the same hits. Lanes without a ray,
+//! or whose ray missed a box on the way down, are switched off, and never hit
+//! anything. Scattered rays go every which way, so only camera rays are
+//! traced like this; see `render::render_block()`.
+
+use rand::rngs::SmallRng;
+
+use crate::hitable::Hitable;
+use crate::prelude::*;
+use crate::simd::Lanes;
+
+/// A generator for each lane of a packet, so that whatever a lane's hit draws
+/// comes from its own ray's generator, just like testing that ray alone.
+pub type LaneRngs = [SmallRng; 4];
+
+#[derive(Copy, Clone, Debug)]
+pub struct RayPacket4 {
+    rays:           [Ray; 4],
+    /// Which lanes hold a ray. The rest are ignored.
+    pub active:     [bool; 4],
+    // The rays' origins and directions, an axis at a time.
+    pub origin:     [Lanes; 3],
+    pub dir:        [Lanes; 3],
+    /// `1 / dir`, for box tests.
+    pub inv_dir:    [Lanes; 3],
+    /// Whether `inv_dir` is negative, like `RayPrecomp::dir_is_neg`.
+    pub dir_is_neg: [[bool; 4]; 3],
+}
+
+impl RayPacket4 {
+    /// A packet of `rays`, with the lanes that don't have one switched off.
+    pub fn new(rays: [Option<Ray>; 4]) -> RayPacket4 {
+        let mut packet = RayPacket4 {
+            rays:       [Ray::default(); 4],
+            active:     [false; 4],
+            origin:     [Lanes::default(); 3],
+            dir:        [Lanes::default(); 3],
+            inv_dir:    [Lanes::default(); 3],
+            dir_is_neg: [[false; 4]; 3],
+        };
+        for (lane, ray) in rays.iter().enumerate() {
+            if let Some(ray) = ray {
+                packet.rays[lane] = *ray;
+                packet.active[lane] = true;
+                for axis in 0..3 {
+                    packet.origin[axis].0[lane] = ray.origin[axis];
+                    packet.dir[axis].0[lane] = ray.dir[axis];
+                }
+            }
+        }
+        for axis in 0..3 {
+            packet.inv_dir[axis] = Lanes::splat(1.0) / packet.dir[axis];
+            packet.dir_is_neg[axis] = packet.inv_dir[axis].lt(Lanes::splat(0.0));
+        }
+        packet
+    }
+
+    /// The ray in `lane`, if it's switched on.
+    pub fn ray(&self, lane: usize) -> Option<&Ray> {
+        if self.active[lane] {
+            Some(&self.rays[lane])
+        } else {
+            None
+        }
+    }
+
+    pub fn any_active(&self) -> bool {
+        self.active.iter().any(|&on| on)
+    }
+
+    /// The same packet, with only the lanes that are on in `mask` left on.
+    pub fn masked(&self, mask: [bool; 4]) -> RayPacket4 {
+        let mut packet = *self;
+        for (active, &on) in packet.active.iter_mut().zip(mask.iter()) {
+            *active &= on;
+        }
+        packet
+    }
+
+    /// `f(lane, ray)` for each lane that's switched on, and `None` for the rest.
+    pub fn map<T>(&self, mut f: impl FnMut(usize, &Ray) -> Option<T>) -> [Option<T>; 4] {
+        let mut lane = |i: usize| self.ray(i).and_then(|ray| f(i, ray));
+        [lane(0), lane(1), lane(2), lane(3)]
+    }
+}
+
+/// Which rays of `rays` hit the sphere at `center` between their lane of
+/// `t_min` and `t_max`, and how far along them they first do.
+/// `dir_length_sq` is each ray's `dir.length_sq()`, which every sphere shares.
+/// Each lane agrees exactly with `hitable::sphere_hit_t()`.
+#[inline]
+pub fn sphere_hit_t4(center:        Float3,
+                     radius:        Float,
+                     rays:          &RayPacket4,
+                     dir_length_sq: Lanes,
+                     t_min:         Lanes,
+                     t_max:         Lanes)
+    -> ([bool; 4], Lanes)
+{
+    let [dx, dy, dz] = rays.dir;
+    let ocx = rays.origin[0] - Lanes::splat(center.x);
+    let ocy = rays.origin[1] - Lanes::splat(center.y);
+    let ocz = rays.origin[2] - Lanes::splat(center.z);
+    let a = dir_length_sq;
+    let b = ocx * dx + ocy * dy + ocz * dz;
+    let c = (ocx * ocx + ocy * ocy + ocz * ocz) - Lanes::splat(radius * radius);
+    let discriminant = b * b - a * c;
+
+    // Lanes that miss have a negative discriminant, which makes both of these
+    // NaN, and NaNs are never in range.
+    let root = discriminant.sqrt();
+    let near = (-b - root) / a;
+    let far = (-b + root) / a;
+    let in_range = |t: Lanes| {
+        let (above, below) = (t_min.lt(t), t.lt(t_max));
+        [0, 1, 2, 3].map(|i| rays.active[i] && above[i] && below[i])
+    };
+    let (near_hit, far_hit) = (in_range(near), in_range(far));
+    let hit = [0, 1, 2, 3].map(|i| near_hit[i] || far_hit[i]);
+    (hit, Lanes::select(near_hit, near, far))
+}
+
+/// The closest hit for each ray of `rays` among `hitables`, just like
+/// `HitableList` finds them.
+pub fn hit_closest_packet(hitables: &[Box<dyn Hitable>],
+                          rays:     &RayPacket4,
+                          t_min:    Lanes,
+                          t_max:    Lanes,
+                          rngs:     &mut LaneRngs)
+    -> [Option<HitRecord>; 4]
+{
+    let mut hits: [Option<HitRecord>; 4] = Default::default();
+    let mut closest = t_max;
+    for hitable in hitables.iter() {
+        let found = hitable.hit_packet(rays, t_min, closest, rngs);
+        keep_closer(&mut hits, &mut closest, found);
+    }
+    hits
+}
+
+/// Takes each of `found` over from `hits`, and its t into `closest`.
+/// These were all found closer than `closest`, so they're always closer.
+pub fn keep_closer(hits:      &mut [Option<HitRecord>; 4],
+                   closest:   &mut Lanes,
+                   mut found: [Option<HitRecord>; 4])
+{
+    for (lane, record) in found.iter_mut().enumerate() {
+        if let Some(record) = record.take() {
+            closest.0[lane] = record.t;
+            hits[lane] = Some(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::hitable::{
+        sphere_hit_t,
+        Aabb,
+        HitableList,
+        Sphere,
+    };
+    use crate::material::Lambertian;
+    use crate::sphere_set::SphereSet;
+
+    fn random_point(scale: Float) -> Float3 {
+        scale * Float3::xyz(random_sfloat(), random_sfloat(), random_sfloat())
+    }
+
+    /// Four rays from around the same place, like a block of camera rays, or
+    /// from anywhere at all. Now and then a lane is left empty.
+    fn random_packet(coherent: bool) -> [Option<Ray>; 4] {
+        let origin = random_point(15.0);
+        let target = random_point(5.0);
+        let ray = |_| {
+            if random_float() < 0.1 {
+                return None;
+            }
+            let (origin, target) = if coherent {
+                (origin, target + random_point(0.5))
+            } else {
+                (random_point(15.0), random_point(5.0))
+            };
+            Some(Ray { origin, dir: target - origin, t: 0.0 })
+        };
+        [0, 1, 2, 3].map(ray)
+    }
+
+    /// Different ranges of t in every lane.
+    fn random_range() -> (Lanes, Lanes) {
+        let mut t_min = Lanes::splat(1e-3);
+        let mut t_max = Lanes::splat(FLOAT_MAX);
+        for lane in 0..4 {
+            if random_float() < 0.5 {
+                t_min.0[lane] = random_float();
+                t_max.0[lane] = t_min.0[lane] + 3.0 * random_float();
+            }
+        }
+        (t_min, t_max)
+    }
+
+    fn random_spheres(n: usize) -> Vec<Sphere> {
+        let material: Arc<dyn Material> = Arc::new(Lambertian { albedo: Float3::xxx(0.5) });
+        (0..n)
+            .map(|_| Sphere {
+                center:   random_point(10.0),
+                radius:   0.1 + random_float(),
+                material: material.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_sphere_matches_scalar() {
+        seed_thread_rng(0x5eed);
+        for i in 0..5000 {
+            let rays = random_packet(i % 2 == 0);
+            let packet = RayPacket4::new(rays);
+            let (t_min, t_max) = random_range();
+            let (center, radius) = (random_point(2.0), 0.1 + 2.0 * random_float());
+            let [dx, dy, dz] = packet.dir;
+            let (hit, t) = sphere_hit_t4(center, radius, &packet,
+                                         dx * dx + dy * dy + dz * dz, t_min, t_max);
+            for (lane, ray) in rays.iter().enumerate() {
+                let expected = ray.as_ref().and_then(|ray| {
+                    sphere_hit_t(center, radius, ray, t_min.0[lane], t_max.0[lane])
+                });
+                let ours = if hit[lane] { Some(t.0[lane]) } else { None };
+                assert_eq!(ours, expected, "lane {} of {:?}", lane, rays);
+            }
+        }
+    }
+
+    #[test]
+    fn check_aabb_matches_scalar() {
+        seed_thread_rng(0xb0c5);
+        for i in 0..5000 {
+            let mut rays = random_packet(i % 2 == 0);
+            // Some rays run parallel to an axis, and never cross its slabs.
+            if i % 10 == 0 {
+                if let Some(ray) = rays[i % 4].as_mut() {
+                    ray.dir.y = 0.0;
+                }
+            }
+            let packet = RayPacket4::new(rays);
+            let (t_min, t_max) = random_range();
+            let (a, b) = (random_point(4.0), random_point(4.0));
+            let bbox = Aabb { min: a.min(&b), max: a.max(&b) };
+            let hits = bbox.hit_packet(&packet, t_min, t_max);
+            for (lane, ray) in rays.iter().enumerate() {
+                let expected = ray.as_ref().map_or(false, |ray| {
+                    bbox.hit(ray, t_min.0[lane], t_max.0[lane])
+                });
+                assert_eq!(hits[lane], expected, "lane {} of {:?} vs {:?}", lane, rays, bbox);
+            }
+        }
+    }
+
+    #[test]
+    fn check_scenes_match_scalar() {
+        seed_thread_rng(0xface);
+        let spheres = random_spheres(400);
+        let boxed = || {
+            spheres.iter()
+                   .map(|s| Box::new(s.clone()) as Box<dyn Hitable>)
+                   .collect::<Vec<_>>()
+        };
+        let set = SphereSet::from_spheres(spheres.clone());
+        let sets = set.split(16)
+                      .into_iter()
+                      .map(|set| Box::new(set) as Box<dyn Hitable>)
+                      .collect();
+        let scenes: Vec<(&str, Box<dyn Hitable>)> = vec![
+            ("list", Box::new(HitableList { hitables: boxed() })),
+            ("bvh", Box::new(Bvh::new(boxed(), 0.0, 0.0))),
+            ("sphere set", Box::new(set)),
+            ("bvh of sphere sets", Box::new(Bvh::new(sets, 0.0, 0.0))),
+        ];
+
+        let mut rngs = [0, 1, 2, 3].map(seeded_rng);
+        for i in 0..2000 {
+            let rays = random_packet(i % 2 == 0);
+            let packet = RayPacket4::new(rays);
+            let (t_min, t_max) = random_range();
+            for (name, scene) in scenes.iter() {
+                let hits = scene.hit_packet(&packet, t_min, t_max, &mut rngs);
+                for (lane, ray) in rays.iter().enumerate() {
+                    let expected = ray.as_ref().and_then(|ray| {
+                        scene.hit(ray, t_min.0[lane], t_max.0[lane], &mut rngs[lane])
+                    });
+                    let t = |hit: &Option<HitRecord>| hit.as_ref().map(|record| record.t);
+                    let p = |hit: &Option<HitRecord>| hit.as_ref().map(|record| record.p);
+                    assert_eq!(t(&hits[lane]), t(&expected), "{}: lane {} of {:?}",
+                               name, lane, rays);
+                    assert_eq!(p(&hits[lane]), p(&expected), "{}: lane {} of {:?}",
+                               name, lane, rays);
+                }
+            }
+        }
+    }
+}
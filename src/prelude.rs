@@ -4,8 +4,14 @@
 pub use crate::float3::{
     Float,
     Float3,
+    FLOAT_MAX,
 };
 pub use crate::hitable::HitRecord;
-pub use crate::material::Material;
+pub use crate::material::{
+    Material,
+    Scatter,
+    ScatterRecord,
+};
 pub use crate::math::*;
+pub use rand::RngCore;
 pub use crate::ray::Ray;
\ No newline at end of file
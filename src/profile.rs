@@ -0,0 +1,338 @@
+//! Counting which objects and materials a render spends its time on.
+//!
+//! With `--profile-objects`, every top-level object in the scene is wrapped in
+//! a `Profiled` hitable, and every material in a `Counted` one. These count
+//! intersection tests, hits, and scatters into per-thread counters, which are
+//! summed up for the report at the end. Without it nothing is wrapped, so
+//! rendering doesn't pay anything for this.
+//!
+//! The BVH only tests the objects in leaves that a ray reaches. So an object's
+//! tests are the times it was tested from its leaf (misses included), and its
+//! hits are the candidate hits it reported, whether or not something closer
+//! turned up later. Tests against the tree's own bounding boxes aren't
+//! attributed to any object.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+};
+
+use crate::hitable::{
+    Aabb,
+    Hitable,
+};
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ObjectCounts {
+    pub tests: u64,
+    pub hits:  u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Counts {
+    /// Indexed by object id.
+    pub objects:  Vec<ObjectCounts>,
+    /// By `Material::kind()`.
+    pub scatters: HashMap<&'static str, u64>,
+}
+
+/// Everything counted for one scene.
+#[derive(Debug)]
+pub struct Profile {
+    // Describes each object, by id.
+    names:  Vec<String>,
+    // One set of counts per thread in the pool, plus one for everyone else.
+    shards: Vec<Mutex<Counts>>,
+}
+
+impl Profile {
+    fn new(names: Vec<String>) -> Profile {
+        let empty = Counts {
+            objects:  vec![ObjectCounts::default(); names.len()],
+            scatters: HashMap::new(),
+        };
+        let shards = (0..rayon::current_num_threads() + 1)
+            .map(|_| Mutex::new(empty.clone()))
+            .collect();
+        Profile {
+            names,
+            shards,
+        }
+    }
+
+    /// The calling thread's counts. Nobody else touches these (unless we're
+    /// outside of the pool), so locking them is cheap.
+    fn shard(&self) -> MutexGuard<'_, Counts> {
+        let others = self.shards.len() - 1;
+        let i = rayon::current_thread_index().map_or(others, |i| i.min(others - 1));
+        self.shards[i].lock().unwrap()
+    }
+
+    /// Counts from every thread, added up.
+    pub fn totals(&self) -> Counts {
+        let mut totals = Counts {
+            objects:  vec![ObjectCounts::default(); self.names.len()],
+            scatters: HashMap::new(),
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            for (total, counts) in totals.objects.iter_mut().zip(shard.objects.iter()) {
+                total.tests += counts.tests;
+                total.hits += counts.hits;
+            }
+            for (kind, scatters) in shard.scatters.iter() {
+                *totals.scatters.entry(kind).or_insert(0) += scatters;
+            }
+        }
+        totals
+    }
+
+    /// The `top` objects by intersection tests, and the `top` materials by
+    /// scatters, as text.
+    pub fn report(&self, top: usize) -> String {
+        let totals = self.totals();
+        let percent = |n: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                100.0 * n as f64 / total as f64
+            }
+        };
+
+        let mut report = String::new();
+        let all_tests: u64 = totals.objects.iter().map(|c| c.tests).sum();
+        let mut objects: Vec<(usize, ObjectCounts)> =
+            totals.objects.iter().cloned().enumerate().collect();
+        // Objects in the same leaf are tested together, so break ties by hits.
+        objects.sort_by(|a, b| {
+            b.1.tests.cmp(&a.1.tests)
+                     .then(b.1.hits.cmp(&a.1.hits))
+                     .then(a.0.cmp(&b.0))
+        });
+        writeln!(report, "Intersection tests by object ({} total):", all_tests).unwrap();
+        for (id, counts) in objects.iter().take(top) {
+            writeln!(report, "    {:<40} {:>12} tests {:>5.1}% {:>12} hits",
+                     self.names[*id],
+                     counts.tests,
+                     percent(counts.tests, all_tests),
+                     counts.hits).unwrap();
+        }
+
+        let all_scatters: u64 = totals.scatters.values().sum();
+        let mut materials: Vec<(&'static str, u64)> = totals.scatters.into_iter().collect();
+        materials.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        writeln!(report, "Scatters by material ({} total):", all_scatters).unwrap();
+        for (kind, scatters) in materials.iter().take(top) {
+            writeln!(report, "    {:<40} {:>12} {:>5.1}%",
+                     kind, scatters, percent(*scatters, all_scatters)).unwrap();
+        }
+
+        report
+    }
+}
+
+/// Wraps every object in `hitables`, and all of their materials, so that we
+/// count what they do. Objects are numbered by their position in `hitables`.
+pub fn instrument(hitables: Vec<Box<dyn Hitable>>, t0: Float, t1: Float)
+    -> (Vec<Box<dyn Hitable>>, Arc<Profile>)
+{
+    let names = hitables
+        .iter()
+        .enumerate()
+        .map(|(id, hitable)| describe(id, hitable.bounding_box(t0, t1)))
+        .collect();
+    let profile = Arc::new(Profile::new(names));
+
+    let hitables = hitables
+        .into_iter()
+        .enumerate()
+        .map(|(id, mut hitable)| {
+            hitable.map_materials(&mut |material| {
+                Arc::new(Counted {
+                    material: material.clone(),
+                    kind:     material.kind(),
+                    profile:  profile.clone(),
+                })
+            });
+            Box::new(Profiled {
+                id,
+                hitable,
+                profile: profile.clone(),
+            }) as Box<dyn Hitable>
+        })
+        .collect();
+
+    (hitables, profile)
+}
+
+fn describe(id: usize, bbox: Option<Aabb>) -> String {
+    match bbox {
+        Some(bbox) => {
+            let c = bbox.centroid();
+            format!("#{} at ({:.2}, {:.2}, {:.2})", id, c.x, c.y, c.z)
+        },
+        None => format!("#{} (unbounded)", id),
+    }
+}
+
+/// Counts tests and hits of another hitable.
+#[derive(Debug)]
+struct Profiled {
+    id:      usize,
+    hitable: Box<dyn Hitable>,
+    profile: Arc<Profile>,
+}
+
+impl Hitable for Profiled {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let record = self.hitable.hit(ray, t_min, t_max, rng);
+        let mut counts = self.profile.shard();
+        counts.objects[self.id].tests += 1;
+        if record.is_some() {
+            counts.objects[self.id].hits += 1;
+        }
+        record
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        self.hitable.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        self.hitable.pdf_value(origin, dir)
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        self.hitable.random_toward(origin, rng)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.hitable.map_materials(f);
+    }
+}
+
+/// Counts scatters off of another material.
+#[derive(Debug)]
+struct Counted {
+    material: Arc<dyn Material>,
+    kind:     &'static str,
+    profile:  Arc<Profile>,
+}
+
+impl Counted {
+    fn count(&self) {
+        *self.profile.shard().scatters.entry(self.kind).or_insert(0) += 1;
+    }
+}
+
+impl Material for Counted {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, rng: &mut dyn RngCore) -> Scatter {
+        self.count();
+        self.material.scatter(ray_in, record, rng)
+    }
+
+    fn scatter_traced(&self,
+                      ray_in: &Ray,
+                      record: &HitRecord,
+                      rng:    &mut dyn RngCore,
+                      event:  &mut Option<crate::material::ScatterEvent>)
+        -> Scatter
+    {
+        self.count();
+        self.material.scatter_traced(ray_in, record, rng, event)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    fn opacity(&self, record: &HitRecord) -> Float {
+        self.material.opacity(record)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.material.is_opaque()
+    }
+
+    fn emitted(&self, ray_in: &Ray, record: &HitRecord) -> Float3 {
+        self.material.emitted(ray_in, record)
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Float3 {
+        self.material.albedo(record)
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, record: &HitRecord, scattered: &Ray) -> Float {
+        self.material.scattering_pdf(ray_in, record, scattered)
+    }
+
+    fn bsdf_cos(&self,
+                ray_in:      &Ray,
+                record:      &HitRecord,
+                scattered:   &Ray,
+                attenuation: &Float3)
+        -> Float3
+    {
+        self.material.bsdf_cos(ray_in, record, scattered, attenuation)
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::hitable::Sphere;
+    use crate::material::{
+        Dielectric,
+        Lambertian,
+    };
+
+    #[test]
+    fn check_counts_and_report() {
+        let mut rng = seeded_rng(0);
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
+        let hitables: Vec<Box<dyn Hitable>> = vec![
+            Box::new(Sphere {
+                center:   Float3::xyz(0., 0., -5.),
+                radius:   1.,
+                material: glass,
+            }),
+            Box::new(Sphere {
+                center:   Float3::xyz(0., 10., -5.),
+                radius:   1.,
+                material: Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+            }),
+        ];
+        let (hitables, profile) = instrument(hitables, 0., 0.);
+
+        let ray = Ray {
+            origin: Float3::new(),
+            dir:    Float3::xyz(0., 0., -1.),
+            t:      0.,
+        };
+        for hitable in hitables.iter() {
+            if let Some(record) = hitable.hit(&ray, 1e-3, 1e9, &mut rng) {
+                assert_eq!(record.material.kind(), "Dielectric");
+                record.material.scatter(&ray, &record, &mut seeded_rng(0));
+            }
+        }
+
+        let totals = profile.totals();
+        assert_eq!(totals.objects, [ObjectCounts { tests: 1, hits: 1 },
+                                    ObjectCounts { tests: 1, hits: 0 }]);
+        assert_eq!(totals.scatters.get("Dielectric"), Some(&1));
+        assert_eq!(totals.scatters.get("Lambertian"), None);
+
+        let report = profile.report(1);
+        assert!(report.contains("#0 at (0.00, 0.00, -5.00)"), "{}", report);
+        assert!(!report.contains("#1 at"), "{}", report);
+        assert!(report.contains("Dielectric"), "{}", report);
+    }
+}
@@ -0,0 +1,225 @@
+//! Showing how far along a render is.
+//!
+//! Render threads add to a shared count of finished pixels as they go. By
+//! default, a background thread draws that as one bar for the whole image,
+//! with its speed and time left. `--progress per-tile` shows a bar for every
+//! tile instead, which is mostly useful for debugging tiles.
+//!
+//! Bars redraw themselves with carriage returns, which only make sense to a
+//! terminal. When stderr is a log file or CI output instead, we print a plain
+//! line now and then, every `--progress-interval` seconds.
+
+use std::{
+    io::{
+        self,
+        IsTerminal,
+    },
+    str::FromStr,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time,
+};
+
+use log::info;
+
+use crate::snapshot::Periodic;
+
+/// How often the bar for the whole image is redrawn.
+const REFRESH_INTERVAL: time::Duration = time::Duration::from_millis(250);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProgressStyle {
+    /// One bar for the whole image.
+    Total,
+    /// One bar for every tile.
+    PerTile,
+}
+
+impl FromStr for ProgressStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProgressStyle, String> {
+        match s {
+            "total" => Ok(ProgressStyle::Total),
+            "per-tile" => Ok(ProgressStyle::PerTile),
+            _ => Err(format!("Unknown progress style \"{}\". Expected total or per-tile", s)),
+        }
+    }
+}
+
+/// Pixels rendered so far, out of how many we set out to render.
+#[derive(Debug)]
+pub struct PixelCounter {
+    done:  AtomicU64,
+    total: u64,
+}
+
+impl PixelCounter {
+    pub fn new(total: u64) -> PixelCounter {
+        PixelCounter {
+            done: AtomicU64::new(0),
+            total,
+        }
+    }
+
+    pub fn add(&self, pixels: u64) {
+        self.done.fetch_add(pixels, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// Whether stderr can show progress bars.
+pub fn stderr_is_terminal() -> bool {
+    io::stderr().is_terminal()
+}
+
+/// One line about how far along we are, for when we can't draw a bar.
+pub fn progress_line(done: u64, total: u64, elapsed: time::Duration) -> String {
+    let percent = if total == 0 {
+        100.0
+    } else {
+        100.0 * done as f64 / total as f64
+    };
+    let eta = if done >= total {
+        String::new()
+    } else if done == 0 {
+        ", eta unknown".to_string()
+    } else {
+        let left = elapsed.as_secs_f64() * (total - done) as f64 / done as f64;
+        format!(", eta {:.0}s", left)
+    };
+    format!("Rendered {:.1}% ({}/{} px), elapsed {:.0}s{}",
+            percent, done, total, elapsed.as_secs_f64(), eta)
+}
+
+/// How we show the total.
+enum Display {
+    Bar(Arc<Mutex<pbr::ProgressBar<io::Stderr>>>),
+    /// Plain lines, from when we started.
+    Lines(time::Instant),
+}
+
+/// Draws `counter` as a single bar on stderr, until finished. Or as a line
+/// every `line_interval`, when stderr isn't a terminal.
+pub struct TotalBar {
+    display:  Display,
+    counter:  Arc<PixelCounter>,
+    periodic: Periodic,
+}
+
+impl TotalBar {
+    pub fn spawn(counter: Arc<PixelCounter>, line_interval: time::Duration) -> TotalBar {
+        if !stderr_is_terminal() {
+            let started = time::Instant::now();
+            let periodic = {
+                let counter = counter.clone();
+                Periodic::spawn(line_interval, move || {
+                    info!("{}", progress_line(counter.done(), counter.total(), started.elapsed()));
+                })
+            };
+            return TotalBar {
+                display: Display::Lines(started),
+                counter,
+                periodic,
+            };
+        }
+
+        let mut bar = pbr::ProgressBar::on(io::stderr(), counter.total());
+        bar.message("Pixels: ");
+        bar.format("[=> ]");
+        // The bar works out speed and time left from when it was created, and
+        // only ever sees the total, so it doesn't matter which tiles finish
+        // first or which ones we skip.
+        let bar = Arc::new(Mutex::new(bar));
+
+        let periodic = {
+            let bar = bar.clone();
+            let counter = counter.clone();
+            Periodic::spawn(REFRESH_INTERVAL, move || {
+                bar.lock().unwrap().set(counter.done());
+            })
+        };
+
+        TotalBar {
+            display: Display::Bar(bar),
+            counter,
+            periodic,
+        }
+    }
+
+    /// Stops redrawing, after one last update. That's where we got to, whether
+    /// we finished or were stopped.
+    pub fn finish(self) {
+        self.periodic.finish();
+        match self.display {
+            Display::Bar(bar) => {
+                let mut bar = bar.lock().unwrap();
+                bar.set(self.counter.done());
+                bar.finish();
+            },
+            Display::Lines(started) => {
+                info!("{}", progress_line(self.counter.done(),
+                                          self.counter.total(),
+                                          started.elapsed()));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_styles_parse() {
+        assert_eq!("total".parse::<ProgressStyle>(), Ok(ProgressStyle::Total));
+        assert_eq!("per-tile".parse::<ProgressStyle>(), Ok(ProgressStyle::PerTile));
+        assert!("tiles".parse::<ProgressStyle>().is_err());
+    }
+
+    #[test]
+    fn check_progress_lines() {
+        let secs = time::Duration::from_secs;
+        assert_eq!(progress_line(120000, 480000, secs(32)),
+                   "Rendered 25.0% (120000/480000 px), elapsed 32s, eta 96s");
+        assert_eq!(progress_line(0, 480000, secs(1)),
+                   "Rendered 0.0% (0/480000 px), elapsed 1s, eta unknown");
+        assert_eq!(progress_line(1, 3, time::Duration::from_millis(2400)),
+                   "Rendered 33.3% (1/3 px), elapsed 2s, eta 5s");
+        assert_eq!(progress_line(480000, 480000, secs(128)),
+                   "Rendered 100.0% (480000/480000 px), elapsed 128s");
+        assert_eq!(progress_line(0, 0, secs(0)), "Rendered 100.0% (0/0 px), elapsed 0s");
+    }
+
+    #[test]
+    fn check_counter_from_many_threads() {
+        let counter = Arc::new(PixelCounter::new(8 * 1000));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        counter.add(10);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(counter.done(), counter.total());
+    }
+}
@@ -0,0 +1,285 @@
+//! Coarse-to-fine rendering, so that previews show up right away.
+//!
+//! We render the image in passes. The first pass only renders every 8th pixel
+//! in each direction, the next fills in every 4th, then every 2nd, and the
+//! last pass fills in the rest. Each pixel is rendered in exactly one pass -
+//! the coarsest one whose grid it sits on - so the samples from early passes
+//! aren't thrown away, and the final image is the same as if we'd never done
+//! the coarse passes at all.
+//!
+//! While we wait on later passes, previews fill the gaps by stretching each
+//! rendered pixel into a block.
+//!
+//! `render_interactive()` renders this way for the preview window, starting
+//! over whenever the camera is flown somewhere else.
+
+use std::time;
+
+use image::{
+    Rgb,
+    RgbImage,
+};
+use log::{
+    debug,
+    info,
+};
+use rayon::prelude::*;
+
+use crate::camera::{
+    Camera,
+    CameraInfo,
+};
+use crate::checkpoint::Accumulator;
+use crate::fly_camera::View;
+use crate::output::to_rgb8;
+use crate::render::{
+    linear_image,
+    needs_to_exit,
+    render_pixel,
+    Frame,
+    RenderSettings,
+    Scene,
+};
+
+/// Distance between rendered pixels in each pass, from coarse to fine.
+pub const STRIDES: [u32; 4] = [8, 4, 2, 1];
+
+/// The stride of the pass that renders (`x`, `y`).
+pub fn pass_stride(x: u32, y: u32) -> u32 {
+    // Every pixel is on the grid of the last pass, so this always finds one.
+    STRIDES.iter()
+           .cloned()
+           .find(|&stride| x % stride == 0 && y % stride == 0)
+           .unwrap()
+}
+
+/// Pixels rendered by the pass with `stride`, in row-major order.
+pub fn pass_pixels(stride: u32, width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut pixels = vec![];
+    for y in (0..height).step_by(stride as usize) {
+        for x in (0..width).step_by(stride as usize) {
+            if pass_stride(x, y) == stride {
+                pixels.push((x, y));
+            }
+        }
+    }
+    pixels
+}
+
+/// What to show after the pass with `stride` finishes.
+/// Each pixel on that pass's grid is stretched over the `stride` x `stride`
+/// block to its lower right.
+pub fn preview(width: u32, height: u32, stride: u32, rendered: impl Fn(u32, u32) -> Rgb<u8>)
+    -> RgbImage
+{
+    RgbImage::from_fn(width, height, |x, y| {
+        rendered(x - x % stride, y - y % stride)
+    })
+}
+
+/// Renders every pixel up to `target` samples, one pass of `STRIDES` at a
+/// time, calling `on_pass` after each one.
+/// The result is exactly what rendering every pixel in order would give.
+/// `stop` is asked before every pixel, and once it returns true, no more
+/// pixels are started. Until it returns, neither is that one, so it's also
+/// where to wait while the render is paused.
+pub fn render(world:       &Scene,
+              cam:         &Camera,
+              settings:    &RenderSettings,
+              target:      u32,
+              accum:       &Accumulator,
+              stop:        &(dyn Fn() -> bool + Sync),
+              mut on_pass: impl FnMut(u32))
+{
+    for &stride in STRIDES.iter() {
+        let pixels = pass_pixels(stride, settings.width, settings.height);
+        pixels.par_iter().for_each(|&(px, py)| {
+            if stop() || needs_to_exit() {
+                return;
+            }
+            let mut sum = accum.get(px, py);
+            render_pixel(world, cam, settings, (px, py), target, &mut sum, None);
+            accum.set(px, py, sum);
+        });
+
+        if needs_to_exit() || stop() {
+            break;
+        }
+        on_pass(stride);
+    }
+}
+
+/// Renders the image from wherever `view` is looking, through `camera`, and
+/// hands each new look at it to `show`.
+/// The first sample of each pixel is rendered in coarse-to-fine passes, and
+/// every sample after that refines the whole image. When the camera moves, we
+/// throw away what we have and start over. `pause` is called before every
+/// pixel, and holds the render up for as long as it doesn't return.
+pub fn render_interactive(world:    &Scene,
+                          settings: &RenderSettings,
+                          accum:    &Accumulator,
+                          view:     &View,
+                          camera:   impl Fn(CameraInfo) -> Camera,
+                          pause:    &(dyn Fn() + Sync),
+                          show:     &dyn Fn(&RgbImage))
+    -> Frame
+{
+    let (nx, ny) = (settings.width, settings.height);
+    let ns = settings.samples_per_pixel;
+    let image = || RgbImage::from_fn(nx, ny, |x, y| to_rgb8(accum.get(x, y).average()));
+
+    'render:
+    loop {
+        let (generation, info) = view.camera();
+        let cam = camera(info);
+        let moved = || view.generation() != generation;
+        let stop = || {
+            pause();
+            moved()
+        };
+
+        let before_render = time::Instant::now();
+        for spp in 1..=ns {
+            render(world, &cam, settings, spp, accum, &stop, |stride| {
+                if spp != 1 {
+                    return;
+                }
+                show(&preview(nx, ny, stride, |x, y| to_rgb8(accum.get(x, y).average())));
+                debug!("Finished 1/{} resolution pass after {:.3}s",
+                       stride, before_render.elapsed().as_millis() as f64 / 1e3);
+            });
+            if needs_to_exit() {
+                break 'render;
+            }
+            if moved() {
+                break;
+            }
+            show(&image());
+        }
+
+        if !moved() {
+            info!("Finished {} samples per pixel after {:.3}s",
+                  ns, before_render.elapsed().as_millis() as f64 / 1e3);
+            // Nothing left to do until the camera moves again.
+            while !moved() {
+                if needs_to_exit() || view.is_closed() {
+                    break 'render;
+                }
+                std::thread::sleep(time::Duration::from_millis(50));
+            }
+        }
+
+        // Those samples were for the old camera.
+        accum.reset();
+    }
+
+    let linear = linear_image(world, accum, nx, ny);
+    let mask = if needs_to_exit() && !accum.is_complete(ns) {
+        Some(accum.mask(ns))
+    } else {
+        None
+    };
+    Frame {
+        image:  linear.to_rgb8(),
+        linear,
+        aovs:   vec![],
+        mask,
+        stats:  None,
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use crate::render::t::light_box_camera;
+    use crate::render::DEFAULT_MAX_DEPTH;
+    use crate::scenes::make_small_light_box;
+
+    #[test]
+    fn check_passes_cover_every_pixel_once() {
+        // Include sizes that don't divide evenly into the coarse grid.
+        for &(width, height) in [(16, 16), (13, 7), (1, 1), (9, 17)].iter() {
+            let mut visits = vec![0; (width * height) as usize];
+            for &stride in STRIDES.iter() {
+                for (x, y) in pass_pixels(stride, width, height) {
+                    assert_eq!((x % stride, y % stride), (0, 0));
+                    visits[(y * width + x) as usize] += 1;
+                }
+            }
+            assert_eq!(visits, vec![1; (width * height) as usize],
+                       "{}x{}", width, height);
+        }
+
+        // The first pass is every 8th pixel
+        assert_eq!(pass_pixels(8, 17, 9), [(0, 0), (8, 0), (16, 0),
+                                          (0, 8), (8, 8), (16, 8)]);
+    }
+
+    #[test]
+    fn check_preview_blocks() {
+        let (width, height) = (20, 12);
+        let color_of = |x: u32, y: u32| Rgb([x as u8, y as u8, 0]);
+
+        for &stride in STRIDES.iter() {
+            let img = preview(width, height, stride, color_of);
+            for (x, y, px) in img.enumerate_pixels() {
+                // Every pixel shows the pixel at the corner of its block...
+                let (bx, by) = (x / stride * stride, y / stride * stride);
+                assert_eq!(*px, color_of(bx, by), "stride {} at ({}, {})", stride, x, y);
+                // ... which has already been rendered by now.
+                assert!(pass_stride(bx, by) >= stride);
+            }
+        }
+
+        // At full resolution, the preview is just the image.
+        let img = preview(width, height, 1, color_of);
+        assert!(img.enumerate_pixels().all(|(x, y, px)| *px == color_of(x, y)));
+    }
+
+    #[test]
+    fn check_progressive_matches_straight_render() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             20,
+            height:            12,
+            samples_per_pixel: 3,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+
+        let progressive = Accumulator::new(settings.width, settings.height);
+        let mut strides = vec![];
+        render(&scene, &cam, &settings, 3, &progressive, &|| false, |stride| {
+            // Everything on this pass's grid is done, and nothing off it is.
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let samples = progressive.get(px, py).samples;
+                    let on_grid = px % stride == 0 && py % stride == 0;
+                    assert_eq!(samples, if on_grid { 3 } else { 0 },
+                               "({}, {}) after the 1/{} pass", px, py, stride);
+                }
+            }
+            strides.push(stride);
+        });
+        assert_eq!(strides, STRIDES);
+
+        let straight = Accumulator::new(settings.width, settings.height);
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let mut sum = straight.get(px, py);
+                render_pixel(&scene, &cam, &settings, (px, py), 3, &mut sum, None);
+                straight.set(px, py, sum);
+            }
+        }
+
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                assert_eq!(progressive.get(px, py), straight.get(px, py),
+                           "({}, {})", px, py);
+            }
+        }
+    }
+}
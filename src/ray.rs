@@ -12,3 +12,26 @@ impl Ray {
         self.origin + t * self.dir
     }
 }
+
+/// What box tests need to know about a ray, worked out once for every box it
+/// visits, rather than once per box.
+#[derive(Copy, Clone, Debug)]
+pub struct RayPrecomp {
+    pub origin:     Float3,
+    /// `1 / dir`. Where `dir` is 0 this is inf, or -inf for -0.
+    pub inv_dir:    Float3,
+    /// Whether `inv_dir` is negative along each axis, so that the ray meets a
+    /// box's `max` before its `min` there.
+    pub dir_is_neg: [bool; 3],
+}
+
+impl RayPrecomp {
+    pub fn new(ray: &Ray) -> RayPrecomp {
+        let inv_dir: Float3 = 1.0 / ray.dir;
+        RayPrecomp {
+            origin: ray.origin,
+            inv_dir,
+            dir_is_neg: [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0],
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Axis-aligned rectangles, and boxes.
+//! Each rectangle lies in a plane perpendicular to one axis, at `k` along that
+//! axis. The normals of these face the positive direction of that axis. Wrap
+//! them in `FlipNormals` to face the other way.
+
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::hitable::{
+    Aabb,
+    Hitable,
+    Span,
+};
+
+// Rectangles have no thickness, but bounding boxes need some.
+const PADDING: Float = 1.0e-4;
+
+macro_rules! impl_rect {
+    ($name:ident, $a:ident, $b:ident, $k:ident) => {
+        #[derive(Clone, Debug)]
+        pub struct $name {
+            // The rectangle spans [a0, a1] x [b0, b1] in its plane.
+            pub a0: Float,
+            pub a1: Float,
+            pub b0: Float,
+            pub b1: Float,
+            // Position along the perpendicular axis.
+            pub k:  Float,
+            pub material: Arc<dyn Material>,
+        }
+
+        impl $name {
+            fn normal() -> Float3 {
+                let mut normal = Float3::new();
+                normal.$k = 1.0;
+                normal
+            }
+
+            pub fn area(&self) -> Float {
+                (self.a1 - self.a0) * (self.b1 - self.b0)
+            }
+        }
+
+        impl Hitable for $name {
+            fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, _rng: &mut dyn RngCore)
+                -> Option<HitRecord>
+            {
+                // Where does the ray cross our plane?
+                let t = (self.k - ray.origin.$k) / ray.dir.$k;
+                if !(t_min < t && t < t_max) {
+                    // This also catches rays parallel to the plane, since
+                    // `t` is then +/- infinity or NaN.
+                    return None;
+                }
+
+                // Is that crossing inside the rectangle?
+                let p = ray.at_t(t);
+                if p.$a < self.a0 || self.a1 < p.$a ||
+                   p.$b < self.b0 || self.b1 < p.$b
+                {
+                    return None;
+                }
+
+                let mut tangent = Float3::new();
+                tangent.$a = 1.0;
+                Some(HitRecord {
+                    t,
+                    p,
+                    normal: Self::normal(),
+                    u: (p.$a - self.a0) / (self.a1 - self.a0),
+                    v: (p.$b - self.b0) / (self.b1 - self.b0),
+                    tangent,
+                    material: self.material.clone(),
+                })
+            }
+
+            fn bounding_box(&self, _t0: Float, _t1: Float) -> Option<Aabb> {
+                let mut min = Float3::new();
+                let mut max = Float3::new();
+                min.$a = self.a0;
+                max.$a = self.a1;
+                min.$b = self.b0;
+                max.$b = self.b1;
+                min.$k = self.k - PADDING;
+                max.$k = self.k + PADDING;
+                Some(Aabb { min, max })
+            }
+
+            fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+                let ray = Ray {
+                    origin: *origin,
+                    dir:    *dir,
+                    t:      0.0,
+                };
+                // Rects don't draw any random numbers to hit, so any
+                // generator will do.
+                if let Some(record) = self.hit(&ray, 1.0e-3, FLOAT_MAX, &mut seeded_rng(0)) {
+                    // Convert the uniform-by-area pdf into a solid angle pdf.
+                    let distance_sq = record.t * record.t * dir.length_sq();
+                    let cosine = (dir.dot(&record.normal) / dir.length()).abs();
+                    distance_sq / (cosine * self.area())
+                } else {
+                    0.0
+                }
+            }
+
+            fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+                let mut point = Float3::new();
+                point.$a = self.a0 + random_float_with(rng) * (self.a1 - self.a0);
+                point.$b = self.b0 + random_float_with(rng) * (self.b1 - self.b0);
+                point.$k = self.k;
+                point - *origin
+            }
+
+            fn map_materials(&mut self,
+                             f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>)
+            {
+                self.material = f(&self.material);
+            }
+        }
+    };
+}
+
+impl_rect!(XyRect, x, y, z);
+impl_rect!(XzRect, x, z, y);
+impl_rect!(YzRect, y, z, x);
+
+/// A solid axis-aligned box, from `min` to `max`, with normals facing out.
+/// Unlike a box of rectangles, this has an inside, so `Csg` can carve it.
+#[derive(Clone, Debug)]
+pub struct Cuboid {
+    pub min:      Float3,
+    pub max:      Float3,
+    pub material: Arc<dyn Material>,
+}
+
+impl Cuboid {
+    /// The hit at `t` along `ray`, on the face across `axis`, facing `sign`.
+    fn record(&self, ray: &Ray, t: Float, axis: usize, sign: Float) -> HitRecord {
+        let p = ray.at_t(t);
+        let mut normal = Float3::new();
+        normal[axis] = sign;
+        // Texture coordinates run along the other two axes.
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let along = |i: usize| {
+            let (min, max) = (self.min[i], self.max[i]);
+            ((p[i] - min) / (max - min)).max(0.0).min(1.0)
+        };
+        let mut tangent = Float3::new();
+        tangent[a] = 1.0;
+        HitRecord {
+            t,
+            p,
+            normal,
+            u: along(a),
+            v: along(b),
+            tangent,
+            material: self.material.clone(),
+        }
+    }
+
+    /// Where the line through `ray` goes into the box and out of it, if it does.
+    fn span(&self, ray: &Ray) -> Option<Span> {
+        let (mut enter, mut exit) = (-Float::INFINITY, Float::INFINITY);
+        let (mut enter_axis, mut exit_axis) = (0, 0);
+        for axis in 0..3 {
+            let (origin, dir) = (ray.origin[axis], ray.dir[axis]);
+            let t0 = (self.min[axis] - origin) / dir;
+            let t1 = (self.max[axis] - origin) / dir;
+            // Parallel rays get infinities (or NaNs, right on a face), and the
+            // comparisons leave those slabs out.
+            let (near, far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+            if near > enter {
+                enter = near;
+                enter_axis = axis;
+            }
+            if far < exit {
+                exit = far;
+                exit_axis = axis;
+            }
+        }
+        if !(enter <= exit) || enter == -Float::INFINITY {
+            return None;
+        }
+        // Normals face out: against the ray where it comes in, and along it
+        // where it leaves.
+        let sign = |axis: usize| if ray.dir[axis] < 0.0 { -1.0 } else { 1.0 };
+        Some(Span {
+            enter: self.record(ray, enter, enter_axis, -sign(enter_axis)),
+            exit:  self.record(ray, exit, exit_axis, sign(exit_axis)),
+        })
+    }
+}
+
+impl Hitable for Cuboid {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, _rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let Span { enter, exit } = self.span(ray)?;
+        if t_min < enter.t && enter.t < t_max {
+            Some(enter)
+        } else if t_min < exit.t && exit.t < t_max {
+            // From inside, the first hit is on the way out.
+            Some(exit)
+        } else {
+            None
+        }
+    }
+
+    fn bounding_box(&self, _t0: Float, _t1: Float) -> Option<Aabb> {
+        Some(Aabb { min: self.min, max: self.max })
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.material = f(&self.material);
+    }
+
+    fn hit_all(&self, ray: &Ray) -> Vec<Span> {
+        self.span(ray).into_iter().collect()
+    }
+}
@@ -0,0 +1,2074 @@
+//! Rendering images, for anything that wants to.
+//!
+//! The integrator lives here: `render_pixel()` takes samples of one pixel of
+//! a `Scene`, each seeded by the pixel and its index, and `trace_path()`
+//! follows each of them around the scene. `render()` puts those together into
+//! a whole image, for programs (and tests) that just want one. The command line
+//! renders the same pixels, but splits them into `tiles` to show progress,
+//! checkpoint them, and so on.
+//!
+//! `render_block()` takes the same samples of a 2x2 block of pixels at once,
+//! tracing their camera rays together as a `packet::RayPacket4`. With the
+//! `packets` feature, `render()` renders that way.
+
+use std::{
+    str::FromStr,
+    sync::atomic,
+};
+
+use rand::rngs::SmallRng;
+use rayon::prelude::*;
+
+use crate::aov::{
+    self,
+    FirstHit,
+    PixelAovs,
+};
+use crate::camera::Camera;
+use crate::checkpoint::{
+    Accumulator,
+    PixelSum,
+};
+use crate::filter::{
+    BoxFilter,
+    Filter,
+};
+use crate::hitable::{
+    Hitable,
+    HitableList,
+    MAX_CUTOUT_SKIPS,
+};
+use crate::lpe::{
+    LightPaths,
+    PathEvent,
+};
+use crate::output::LinearImage;
+use crate::packet::{
+    LaneRngs,
+    RayPacket4,
+};
+use crate::prelude::*;
+use crate::simd::Lanes;
+use crate::stats;
+
+/// How many times paths bounce before we give up on them.
+pub const DEFAULT_MAX_DEPTH: u32 = 50;
+
+/// Paths stop once every channel of their throughput drops below this.
+/// Nothing they could pick up after that would show in the image, and math on
+/// numbers this small soon turns subnormal, which is very slow on some CPUs.
+pub const DEFAULT_THROUGHPUT_CUTOFF: Float = 1e-30;
+
+/// Renders stop early once this is set, e.g. when we receive a Ctrl+C.
+/// Pixels keep the samples they have so far.
+pub static NEED_TO_EXIT: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// Renders with a time limit stop once this is set, like they do for
+/// `NEED_TO_EXIT`. Unlike that, what they have so far is the finished image.
+pub static OUT_OF_TIME: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// Paths that we stopped early, because nothing they'd find could matter.
+/// See `DEFAULT_THROUGHPUT_CUTOFF`.
+pub static NEGLIGIBLE_PATHS: atomic::AtomicU64 = atomic::AtomicU64::new(0);
+
+/// Things can poll this method to know if they should exit early.
+pub fn needs_to_exit() -> bool {
+    NEED_TO_EXIT.load(atomic::Ordering::SeqCst)
+}
+
+/// Whether to stop adding samples: because we need to exit, or because we're
+/// out of time.
+pub fn needs_to_stop() -> bool {
+    needs_to_exit() || OUT_OF_TIME.load(atomic::Ordering::SeqCst)
+}
+
+/// Everything about how to render an image, besides what's in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub width:             u32,
+    pub height:            u32,
+    /// How many samples `render()` takes of each pixel. Resuming a checkpoint
+    /// takes more of them, so this is the one setting it doesn't have to match.
+    pub samples_per_pixel: u32,
+    /// The most times a path bounces. See `DEFAULT_MAX_DEPTH`.
+    pub max_depth:         u32,
+    pub seed:              u64,
+    /// Which scene we're rendering, so that checkpoints of one scene aren't
+    /// resumed with another. Only for reports; it doesn't pick the scene.
+    pub scene:             String,
+}
+
+impl RenderSettings {
+    /// Explains how `self` differs from `other`, if it does in any way that
+    /// matters for resuming a checkpoint.
+    pub fn check_matches(&self, other: &RenderSettings) -> Result<(), String> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(format!("Image size is {}x{}, but the checkpoint is {}x{}",
+                               other.width, other.height, self.width, self.height));
+        }
+        if self.seed != other.seed {
+            return Err(format!("Seed is {}, but the checkpoint used {}",
+                               other.seed, self.seed));
+        }
+        if self.max_depth != other.max_depth {
+            return Err(format!("Max depth is {}, but the checkpoint used {}",
+                               other.max_depth, self.max_depth));
+        }
+        if self.scene != other.scene {
+            return Err(format!("Scene is \"{}\", but the checkpoint rendered \"{}\"",
+                               other.scene, self.scene));
+        }
+        Ok(())
+    }
+}
+
+/// How far along a `render()` is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub rows_done: u32,
+    pub rows:      u32,
+}
+
+/// The result of a render.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub image:  image::RgbImage,
+    /// What `image` was made from, before we tonemapped it.
+    pub linear: LinearImage,
+    pub aovs:   Vec<(aov::Aov, image::RgbImage)>,
+    /// When we're interrupted, this tells real pixels apart from missing ones.
+    /// See `Accumulator::mask()`.
+    pub mask:   Option<image::GrayImage>,
+    /// For --stats-json. Interactive renders don't have these.
+    pub stats:  Option<stats::RenderStats>,
+}
+
+/// Renders every pixel of the image that `camera` sees of `scene`, up to
+/// `settings.samples_per_pixel` samples each. Rows render in parallel, and
+/// `on_progress` hears about each one as it finishes, from whichever thread
+/// rendered it.
+///
+/// If `NEED_TO_EXIT` or `OUT_OF_TIME` is set partway through, pixels keep
+/// whatever samples they had, and pixels that weren't started are black.
+///
+/// With the `packets` feature, this is `render_packets()`.
+pub fn render(scene:       &Scene,
+              camera:      &Camera,
+              settings:    &RenderSettings,
+              on_progress: &(dyn Fn(Progress) + Sync))
+    -> LinearImage
+{
+    if cfg!(feature = "packets") {
+        return render_packets(scene, camera, settings, on_progress);
+    }
+    let (nx, ny) = (settings.width, settings.height);
+    let accum = Accumulator::new(nx, ny);
+    let rows_done = atomic::AtomicU32::new(0);
+    (0..ny).into_par_iter().for_each(|py| {
+        if needs_to_stop() {
+            return;
+        }
+        for px in 0..nx {
+            let mut sum = PixelSum::default();
+            render_pixel(scene,
+                         camera,
+                         settings,
+                         (px, py),
+                         settings.samples_per_pixel,
+                         &mut sum,
+                         None);
+            accum.set(px, py, sum);
+        }
+        let done = rows_done.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        on_progress(Progress { rows_done: done, rows: ny });
+    });
+    linear_image(scene, &accum, nx, ny)
+}
+
+/// Like `render()`, but tracing camera rays four at a time, from 2x2 blocks of
+/// pixels (see `render_block()`). Pairs of rows render in parallel. The image
+/// is exactly the same as `render()`'s.
+pub fn render_packets(scene:       &Scene,
+                      camera:      &Camera,
+                      settings:    &RenderSettings,
+                      on_progress: &(dyn Fn(Progress) + Sync))
+    -> LinearImage
+{
+    let (nx, ny) = (settings.width, settings.height);
+    let accum = Accumulator::new(nx, ny);
+    let rows_done = atomic::AtomicU32::new(0);
+    (0..(ny + 1) / 2).into_par_iter().for_each(|block_y| {
+        if needs_to_stop() {
+            return;
+        }
+        let py = 2 * block_y;
+        for px in (0..nx).step_by(2) {
+            let mut sums = [PixelSum::default(); 4];
+            render_block(scene, camera, settings, (px, py), settings.samples_per_pixel, &mut sums);
+            for (&(x, y), &sum) in block_pixels((px, py)).iter().zip(sums.iter()) {
+                if x < nx && y < ny {
+                    accum.set(x, y, sum);
+                }
+            }
+        }
+        for _ in py..(py + 2).min(ny) {
+            let done = rows_done.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            on_progress(Progress { rows_done: done, rows: ny });
+        }
+    });
+    linear_image(scene, &accum, nx, ny)
+}
+
+/// The average of every pixel in `accum`.
+/// With a transparent background, that comes with coverage as alpha.
+pub fn linear_image(world: &Scene, accum: &Accumulator, nx: u32, ny: u32) -> LinearImage {
+    let linear = LinearImage::from_fn(nx, ny, |x, y| accum.get(x, y).average());
+    if world.transparent_background {
+        linear.with_alpha(|x, y| accum.get(x, y).coverage())
+    } else {
+        linear
+    }
+}
+
+/// A seed for one sample of one pixel.
+pub fn sample_seed(seed: u64, (px, py): (u32, u32), sample: u32) -> u64 {
+    let pixel = (py as u64) << 32 | px as u64;
+    splitmix64(splitmix64(splitmix64(seed) ^ pixel) ^ sample as u64)
+}
+
+/// The generator that one sample's path draws from, from `sample_seed()`.
+/// Everything the sample draws, down to where it stops in a volume, comes from
+/// it alone.
+pub fn sample_rng(seed: u64) -> SmallRng {
+    seeded_rng(seed)
+}
+
+/// Takes samples of the pixel at (`px`, `py`) until `sum` has `target` of them.
+/// Every sample is seeded by its pixel and index, so stopping and picking up
+/// again later gives exactly the same result as never stopping.
+/// Each sample is also added to `aovs`, if we're tracking any and it wants it.
+pub fn render_pixel(world:    &Scene,
+                    cam:      &Camera,
+                    settings: &RenderSettings,
+                    (px, py): (u32, u32),
+                    target:   u32,
+                    sum:      &mut PixelSum,
+                    aovs:     Option<&mut PixelAovs>)
+{
+    // Don't bother tracing the extra information if nobody wants it.
+    let mut aovs = aovs.filter(|aovs| !aovs.is_empty());
+
+    // AA through many samples.
+    while sum.samples < target {
+        // High sample counts take a while, so don't wait for the whole pixel.
+        // The samples we have so far are still good.
+        if needs_to_stop() {
+            break;
+        }
+
+        let mut rng = sample_rng(sample_seed(settings.seed, (px, py), sum.samples));
+        let CameraSample { u, v, weight, ray } =
+            camera_sample(world, cam, settings, (px, py), &mut rng);
+
+        // With --aov-stride, most samples skip the AOVs.
+        let samples = sum.samples;
+        let mut sample_aovs = aovs.as_mut()
+                                  .map(|aovs| &mut **aovs)
+                                  .filter(|aovs| aovs.wants_sample(samples));
+        let rgb = if !cam.sees(u, v) {
+            // Outside of a fisheye's image circle, there's nothing.
+            Float3::new()
+        } else if let Some(view) = world.debug {
+            let hit = hit_surface(&world.world, &ray, 1.0e-3, FLOAT_MAX, &mut rng);
+            if world.transparent_background && hit.is_some() {
+                sum.covered += weight;
+            }
+            view.color(&ray, hit.as_ref(), world)
+        } else if sample_aovs.is_none() && !world.transparent_background {
+            color(&ray, world, settings.max_depth, &mut rng)
+        } else {
+            // With a transparent background, we need to know whether the
+            // sample hit anything.
+            let wants_first_hit = world.transparent_background
+                                  || sample_aovs.as_ref()
+                                                .map_or(false, |aovs| aovs.wants_first_hit());
+            let mut first_hit = FirstHit::default();
+            let rgb = trace_path(&ray,
+                                 world,
+                                 settings.max_depth,
+                                 &mut rng,
+                                 sample_aovs.as_mut().and_then(|aovs| aovs.light_paths()),
+                                 Some(&mut first_hit).filter(|_| wants_first_hit));
+            if let Some(aovs) = sample_aovs.as_mut() {
+                stats::count(|c| c.aov_samples += 1);
+                aovs.add_sample(&first_hit);
+            }
+            if world.transparent_background && first_hit.surface.is_some() {
+                sum.covered += weight;
+            }
+            rgb
+        };
+
+        debug_check_sample(rgb, (px, py), sum.samples);
+        sum.add(rgb, weight);
+    }
+}
+
+/// The pixels of the 2x2 block with (`px`, `py`) at its top left, in the order
+/// that `render_block()` takes their sums.
+pub fn block_pixels((px, py): (u32, u32)) -> [(u32, u32); 4] {
+    [(px, py), (px + 1, py), (px, py + 1), (px + 1, py + 1)]
+}
+
+/// Takes samples of the 2x2 block of pixels with (`px`, `py`) at its top left,
+/// until each of `sums` has `target` of them, just like `render_pixel()` does
+/// for each pixel alone. `sums` go with `block_pixels()`, and pixels past the
+/// edge of the image are left alone.
+///
+/// The camera rays of each pixel's next sample are traced together in a
+/// `RayPacket4`, and the rest of each path alone, with the same generator
+/// that `render_pixel()` would give it, so the pixels come out exactly the
+/// same either way.
+pub fn render_block(world:    &Scene,
+                    cam:      &Camera,
+                    settings: &RenderSettings,
+                    (px, py): (u32, u32),
+                    target:   u32,
+                    sums:     &mut [PixelSum; 4])
+{
+    let pixels = block_pixels((px, py));
+    let in_image = |(x, y): (u32, u32)| x < settings.width && y < settings.height;
+    if world.debug.is_some() || world.transparent_background {
+        // These want more from camera rays than their first hit, which
+        // `render_pixel()` knows how to get.
+        for (&pixel, sum) in pixels.iter().zip(sums.iter_mut()) {
+            if in_image(pixel) {
+                render_pixel(world, cam, settings, pixel, target, sum, None);
+            }
+        }
+        return;
+    }
+
+    loop {
+        if needs_to_stop() {
+            break;
+        }
+
+        // Each pixel that still wants samples starts its next one, up to where
+        // its camera ray needs tracing.
+        let mut rays = [None; 4];
+        // Lanes that don't start a sample never draw from theirs.
+        let mut rngs: LaneRngs = [0, 1, 2, 3].map(seeded_rng);
+        let mut weights = [0.0; 4];
+        let mut any_left = false;
+        for (lane, (&pixel, sum)) in pixels.iter().zip(sums.iter_mut()).enumerate() {
+            if !in_image(pixel) || sum.samples >= target {
+                continue;
+            }
+            any_left = true;
+            rngs[lane] = sample_rng(sample_seed(settings.seed, pixel, sum.samples));
+            let CameraSample { u, v, weight, ray } =
+                camera_sample(world, cam, settings, pixel, &mut rngs[lane]);
+            if cam.sees(u, v) {
+                rays[lane] = Some(ray);
+                weights[lane] = weight;
+            } else {
+                // Outside of a fisheye's image circle, there's nothing.
+                sum.add(Float3::new(), weight);
+            }
+        }
+        if !any_left {
+            break;
+        }
+
+        let packet = RayPacket4::new(rays);
+        let before_hits = rngs.clone();
+        let mut hits = world.world.hit_packet(&packet,
+                                              Lanes::splat(1.0e-3),
+                                              Lanes::splat(FLOAT_MAX),
+                                              &mut rngs);
+        for (lane, hit) in hits.iter_mut().enumerate() {
+            let ray = match packet.ray(lane) {
+                Some(ray) => ray,
+                None => continue,
+            };
+            // Rays roll the dice on whether to pass through cutouts, so we
+            // leave those to `hit_surface()`, which draws whatever the packet
+            // drew for this lane all over again.
+            let camera_hit = match hit.take() {
+                Some(ref record) if record.material.opacity(record) < 1.0 => {
+                    rngs[lane] = before_hits[lane].clone();
+                    None
+                },
+                hit => Some(hit),
+            };
+            let rgb = trace_path_from(ray,
+                                      world,
+                                      settings.max_depth,
+                                      &mut rngs[lane],
+                                      None,
+                                      None,
+                                      camera_hit);
+            debug_check_sample(rgb, pixels[lane], sums[lane].samples);
+            sums[lane].add(rgb, weights[lane]);
+        }
+    }
+}
+
+/// Where a sample of a pixel looks from, and how much it counts.
+struct CameraSample {
+    /// Where on the film the sample is.
+    u:      Float,
+    v:      Float,
+    /// How much the pixel's filter weighs it.
+    weight: Float,
+    ray:    Ray,
+}
+
+/// Picks the spot on the film that the next sample of pixel (`px`, `py`)
+/// looks through, with random numbers from `rng`.
+fn camera_sample(world:    &Scene,
+                 cam:      &Camera,
+                 settings: &RenderSettings,
+                 (px, py): (u32, u32),
+                 rng:      &mut dyn RngCore)
+    -> CameraSample
+{
+    stats::count(|c| c.primary_rays += 1);
+
+    let nx = settings.width;
+    let ny = settings.height;
+    // Go through `y` "backwards": the top row of the image is at v = 1.
+    let y = ny - 1 - py;
+
+    // Jitter around the middle of the pixel, as far out as the filter
+    // reaches. With a box filter, that stays inside of the pixel.
+    let radius = world.filter.radius();
+    let dx = radius * random_sfloat_with(rng);
+    let dy = radius * random_sfloat_with(rng);
+    let weight = world.filter.weight(dx, dy);
+    let u = (px as Float + 0.5 + dx) / nx as Float;
+    let v = (y as Float + 0.5 + dy) / ny as Float;
+    // Pixels on the edges sample as far past the image as the filter
+    // reaches past them.
+    let reach = radius - 0.5;
+    debug_assert!(-reach / nx as Float <= u && u <= 1.0 + reach / nx as Float, "u = {}", u);
+    debug_assert!(-reach / ny as Float <= v && v <= 1.0 + reach / ny as Float, "v = {}", v);
+    let ray = cam.get_ray(u, v, rng);
+    CameraSample { u, v, weight, ray }
+}
+
+/// Sanity checks - no samples are allowed to be negative or NaN.
+/// (Emissive materials can push them well past 1.0, though.)
+fn debug_check_sample(rgb: Float3, (px, py): (u32, u32), sample: u32) {
+    debug_assert!(0.0 <= rgb.x, "({}, {}) #{} rgb = {:?}", px, py, sample, rgb);
+    debug_assert!(0.0 <= rgb.y, "({}, {}) #{} rgb = {:?}", px, py, sample, rgb);
+    debug_assert!(0.0 <= rgb.z, "({}, {}) #{} rgb = {:?}", px, py, sample, rgb);
+}
+
+/// Everything the integrator needs to know about what it's rendering.
+#[derive(Debug)]
+pub struct Scene {
+    /// Every object in the scene, including the lights.
+    pub world: HitableList,
+    /// Objects that are worth sampling directly, because they give off light.
+    /// These should also appear in `world`. May be empty.
+    pub lights: HitableList,
+    /// What rays see when they escape the scene.
+    pub background: Background,
+    /// Paths stop when their throughput drops below this. 0 never stops them.
+    pub throughput_cutoff: Float,
+    /// Camera rays that miss everything see nothing, instead of the
+    /// background. Rays that bounce off of something still see it.
+    pub transparent_background: bool,
+    /// How samples are weighted into pixels.
+    pub filter: Box<dyn Filter>,
+    /// Shade what camera rays hit first plainly, instead of tracing light.
+    pub debug: Option<DebugView>,
+    /// Distances that `DebugView::Depth` shows as black and white.
+    pub depth_range: (Float, Float),
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene {
+            world:                  HitableList::default(),
+            lights:                 HitableList::default(),
+            background:             Background::default(),
+            throughput_cutoff:      DEFAULT_THROUGHPUT_CUTOFF,
+            transparent_background: false,
+            filter:                 Box::new(BoxFilter),
+            debug:                  None,
+            depth_range:            (0.0, 1.0),
+        }
+    }
+}
+
+impl Scene {
+    /// A scene lit only by the sky.
+    pub fn new(world: HitableList) -> Scene {
+        Scene {
+            world,
+            ..Scene::default()
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Background {
+    /// A white to blue gradient, brighter toward the horizon.
+    Sky,
+    /// No light from outside the scene at all.
+    Black,
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Sky
+    }
+}
+
+impl Background {
+    pub fn color(&self, ray: &Ray) -> Float3 {
+        match self {
+            Background::Sky => {
+                // Linearly blend white and blue, depending on the "up" or
+                // "downn"ness of the y coordinate.
+                let white = Float3::xyz(1., 1., 1.);
+                let blue = Float3::xyz(0.5, 0.7, 1.0);
+
+                let t = 0.5 * (1.0 + ray.dir.unit().y);
+                Float3::lerp(t, white, blue)
+            },
+            Background::Black => Float3::new(),
+        }
+    }
+}
+
+/// Quick looks at a scene's geometry, from only the first thing each camera
+/// ray hits, without any lighting. Rays that miss are a neutral gray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugView {
+    /// The surface normal, with each axis mapped from [-1, 1] to [0, 1].
+    Normals,
+    /// Distance from the camera, from black to white over `Scene::depth_range`.
+    Depth,
+    /// The color of the material. See `Material::albedo()`.
+    Albedo,
+    /// Texture coordinates, u in red and v in green.
+    Uv,
+}
+
+impl DebugView {
+    pub const ALL: &'static [DebugView] = &[
+        DebugView::Normals,
+        DebugView::Depth,
+        DebugView::Albedo,
+        DebugView::Uv,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugView::Normals => "normals",
+            DebugView::Depth   => "depth",
+            DebugView::Albedo  => "albedo",
+            DebugView::Uv      => "uv",
+        }
+    }
+
+    /// What a camera `ray` that first hits `record`, if anything, looks like.
+    ///
+    /// Only albedo is a color. The rest are numbers, which should come out in
+    /// the image just as they are, so they're squared here to undo the gamma
+    /// correction they'll get.
+    pub fn color(self, ray: &Ray, record: Option<&HitRecord>, scene: &Scene) -> Float3 {
+        let record = match record {
+            Some(record) => record,
+            None => return Float3::xxx(0.25),
+        };
+        let display = match self {
+            DebugView::Normals => 0.5 * (record.normal.unit() + Float3::xxx(1.0)),
+            DebugView::Depth => {
+                let (near, far) = scene.depth_range;
+                let distance = record.t * ray.dir.length();
+                Float3::xxx(((distance - near) / (far - near)).max(0.0).min(1.0))
+            },
+            DebugView::Albedo => return record.material.albedo(record),
+            DebugView::Uv => Float3::xyz(record.u, record.v, 0.0),
+        };
+        display * display
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DebugView, String> {
+        DebugView::ALL
+            .iter()
+            .cloned()
+            .find(|view| view.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = DebugView::ALL.iter().map(|v| v.name()).collect();
+                format!("Unknown debug view \"{}\". Expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// For --background: "sky", or "black", which is also spelled "none".
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Background, String> {
+        match s {
+            "sky" => Ok(Background::Sky),
+            "black" | "none" => Ok(Background::Black),
+            _ => Err(format!("Unknown background \"{}\". Expected one of: sky, black, none", s)),
+        }
+    }
+}
+
+/// The light `ray` carries back, from a path of at most `max_depth` bounces.
+/// The path draws all of its random numbers from `rng`.
+pub fn color(ray: &Ray, scene: &Scene, max_depth: u32, rng: &mut dyn RngCore) -> Float3 {
+    trace_path(ray, scene, max_depth, rng, None, None)
+}
+
+/// The closest hit along `ray` that it doesn't pass through.
+/// Rays pass through cutouts (see `Material::opacity()`) at random, in which
+/// case we look again from just past that hit.
+fn hit_surface(world: &dyn Hitable,
+               ray:   &Ray,
+               t_min: Float,
+               t_max: Float,
+               rng:   &mut dyn RngCore)
+    -> Option<HitRecord>
+{
+    let mut t_min = t_min;
+    let mut skips = 0;
+    loop {
+        let record = world.hit(ray, t_min, t_max, rng)?;
+        let opacity = record.material.opacity(&record);
+        // Opaque hits don't roll the dice, so scenes without cutouts see
+        // the same random numbers as ever.
+        if opacity >= 1.0 || skips == MAX_CUTOUT_SKIPS || random_float_with(rng) < opacity {
+            return Some(record);
+        }
+        // Hits only count when they're strictly past `t_min`, so we won't
+        // find this one again.
+        t_min = record.t;
+        skips += 1;
+    }
+}
+
+/// Trace a path of at most `max_depth` bounces starting with `ray`, returning
+/// the light it carries back. Its random numbers come from `rng`.
+/// When `paths` is provided, that light is also sorted into its passes.
+/// When `first_hit` is provided, it's filled in with the first surface the
+/// path hit, what its material reported about scattering it (see
+/// `Material::scatter_traced()`), and how many times the path bounced.
+pub fn trace_path(ray:           &Ray,
+                  scene:         &Scene,
+                  max_depth:     u32,
+                  rng:           &mut dyn RngCore,
+                  paths:         Option<&mut LightPaths>,
+                  first_hit:     Option<&mut FirstHit>)
+    -> Float3
+{
+    trace_path_from(ray, scene, max_depth, rng, paths, first_hit, None)
+}
+
+/// `trace_path()`, for a `ray` that we might already know the first hit of.
+/// `camera_hit` is that hit, when it's what `hit_surface()` would find.
+fn trace_path_from(ray:            &Ray,
+                   scene:          &Scene,
+                   max_depth:      u32,
+                   rng:            &mut dyn RngCore,
+                   mut paths:      Option<&mut LightPaths>,
+                   mut first_hit:  Option<&mut FirstHit>,
+                   mut camera_hit: Option<Option<HitRecord>>)
+    -> Float3
+{
+    if let Some(paths) = paths.as_mut() {
+        paths.begin();
+    }
+    // Every bit of light added to the path goes through here.
+    macro_rules! contribute {
+        ($event:expr, $light:expr) => {{
+            let light: Float3 = $light;
+            if let Some(paths) = paths.as_mut() {
+                paths.contribute($event, light);
+            }
+            light
+        }}
+    }
+
+    let world: &dyn Hitable = &scene.world;
+    let lights: &dyn Hitable = &scene.lights;
+    let sample_lights = !scene.lights.hitables.is_empty();
+
+    // Light gathered so far
+    let mut radiance = Float3::new();
+    // How much of the light at the current vertex makes it back to the camera.
+    let mut throughput = Float3::xxx(1.0);
+    // The density that the material at the previous vertex picked `ray` with.
+    // When this is `None`, the previous vertex didn't sample any lights (e.g.
+    // it was the camera or a mirror), so anything we hit is counted fully.
+    let mut prev_bsdf_pdf: Option<Float> = None;
+
+    let mut ray = *ray;
+    for depth in 0..=max_depth {
+        let hit = match camera_hit.take() {
+            Some(hit) => hit,
+            None => hit_surface(world, &ray, 1.0e-3, FLOAT_MAX, rng),
+        };
+        let hit_record = match hit {
+            Some(hit_record) => hit_record,
+            None if depth == 0 && scene.transparent_background => return radiance,
+            None => {
+                return radiance + contribute!(None, throughput * scene.background.color(&ray));
+            },
+        };
+        let material = &hit_record.material;
+        if depth == 0 {
+            if let Some(first_hit) = first_hit.as_mut() {
+                first_hit.surface = Some(aov::Surface {
+                    normal:   hit_record.normal.unit(),
+                    distance: hit_record.t * ray.dir.length(),
+                    albedo:   material.albedo(&hit_record),
+                });
+            }
+        }
+
+        // If we found a light by following the material's BSDF, we may have
+        // also found it by sampling the light at the previous vertex.
+        // Weight the two so that together they count it once.
+        let emitted = material.emitted(&ray, &hit_record);
+        if emitted != Float3::new() {
+            let weight = match prev_bsdf_pdf {
+                Some(bsdf_pdf) => {
+                    let light_pdf = lights.pdf_value(&ray.origin, &ray.dir);
+                    power_heuristic(bsdf_pdf, light_pdf)
+                },
+                None => 1.0,
+            };
+            radiance += contribute!(None, weight * throughput * emitted);
+        }
+
+        if depth == max_depth {
+            // We stop following the path here, so it brings back no more
+            // light. --verbose reports how often this happens.
+            stats::count(|c| c.capped_paths += 1);
+            return radiance;
+        }
+        let scatter = match first_hit.as_mut() {
+            Some(first_hit) if depth == 0 => {
+                material.scatter_traced(&ray, &hit_record, rng, &mut first_hit.scatter)
+            },
+            _ => material.scatter(&ray, &hit_record, rng),
+        };
+        let ScatterRecord { attenuation, scattered } = match scatter {
+            Scatter::Bounce(bounce) => bounce,
+            // If scatter hit something, but doesn't produce more rays,
+            // just return the color it left.
+            Scatter::Absorb(color) => {
+                return radiance + contribute!(None, throughput * color.abs());
+            },
+        };
+
+        if let Some(first_hit) = first_hit.as_mut() {
+            first_hit.bounces = depth + 1;
+        }
+        let bsdf_pdf = material.scattering_pdf(&ray, &hit_record, &scattered);
+        prev_bsdf_pdf = None;
+
+        // Materials without a pdf (specular ones) can't be evaluated in
+        // arbitrary directions, so they're only sampled through their BSDF.
+        let event = if bsdf_pdf > 0.0 {
+            PathEvent::Diffuse
+        } else {
+            PathEvent::Specular
+        };
+        if sample_lights && bsdf_pdf > 0.0 {
+            let direct = sample_one_light(&ray, &hit_record, &attenuation, scene, rng);
+            radiance += contribute!(Some(event), throughput * direct);
+            prev_bsdf_pdf = Some(bsdf_pdf);
+        }
+        if let Some(paths) = paths.as_mut() {
+            paths.push(event);
+        }
+
+        throughput = throughput * attenuation;
+        ray = scattered;
+        stats::count(|c| c.scattered_rays += 1);
+
+        let largest = throughput.abs().as_slice().iter().cloned().fold(0.0, Float::max);
+        if largest < scene.throughput_cutoff {
+            NEGLIGIBLE_PATHS.fetch_add(1, atomic::Ordering::Relaxed);
+            return radiance;
+        }
+    }
+
+    unreachable!("The last iteration always returns");
+}
+
+/// Estimate the light arriving at `hit_record` directly from `scene.lights`,
+/// weighted for combination with the material's own sampling.
+/// `attenuation` is what the material returned from `scatter()`.
+pub fn sample_one_light(ray_in:      &Ray,
+                        hit_record:  &HitRecord,
+                        attenuation: &Float3,
+                        scene:       &Scene,
+                        rng:         &mut dyn RngCore)
+    -> Float3
+{
+    let lights: &dyn Hitable = &scene.lights;
+    let origin = hit_record.p;
+
+    let to_light = Ray {
+        origin,
+        dir: lights.random_toward(&origin, rng),
+        t:   ray_in.t,
+    };
+    let light_pdf = lights.pdf_value(&to_light.origin, &to_light.dir);
+    if light_pdf <= 0.0 {
+        return Float3::new();
+    }
+
+    // This is zero when the light is behind the surface.
+    let bsdf_pdf = hit_record.material.scattering_pdf(ray_in,
+                                                      hit_record,
+                                                      &to_light);
+    if bsdf_pdf <= 0.0 {
+        return Float3::new();
+    }
+
+    // Find where the ray reaches the light, and then whether anything else
+    // is in the way. The light is part of the world too, so we stop just
+    // short of it.
+    let light_record = match scene.lights.hit(&to_light, 1.0e-3, FLOAT_MAX, rng) {
+        Some(light_record) => light_record,
+        None => return Float3::new(),
+    };
+    stats::count(|c| c.shadow_rays += 1);
+    if scene.world.hit_any(&to_light, 1.0e-3, light_record.t * (1.0 - 1.0e-4), rng) {
+        return Float3::new();
+    }
+    let emitted = light_record.material.emitted(&to_light, &light_record);
+
+    let weight = power_heuristic(light_pdf, bsdf_pdf);
+    let bsdf_cos = hit_record.material.bsdf_cos(ray_in, hit_record, &to_light, attenuation);
+    (weight / light_pdf) * bsdf_cos * emitted
+}
+
+#[cfg(test)]
+pub(crate) mod t {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::aov::Aov;
+    use crate::bvh;
+    use crate::camera::{
+        CameraInfo,
+        Projection,
+    };
+    use crate::checkpoint;
+    use crate::filter;
+    use crate::hitable::{
+        FlipNormals,
+        Sphere,
+    };
+    use crate::lpe::{
+        self,
+        LightPaths,
+    };
+    use crate::lut;
+    use crate::material::*;
+    use crate::output;
+    use crate::profile;
+    use crate::rect::*;
+    use crate::scenes::{
+        make_cornell_box,
+        make_cover_scene,
+        make_small_light_box,
+        CoverSceneParams,
+        SceneSeed,
+    };
+    use crate::texture;
+
+    /// Looking into the light box from `make_small_light_box()`.
+    pub(crate) fn light_box_camera() -> Camera {
+        Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(278., 278., -800.),
+            lookat:     Float3::xyz(278., 278., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 10.,
+            t_start:    0.,
+            t_end:      0.,
+        })
+    }
+
+    /// Mean and variance of the average channel value of `n` samples.
+    fn pixel_stats(scene: &Scene,
+                   cam:   &Camera,
+                   u:     Float,
+                   v:     Float,
+                   n:     u32,
+                   rng:   &mut dyn RngCore)
+        -> (Float, Float)
+    {
+        let samples: Vec<Float> = (0..n)
+            .map(|_| {
+                let rgb = color(&cam.get_ray(u, v, rng), scene, DEFAULT_MAX_DEPTH, rng);
+                assert!(!rgb.x.is_nan() && !rgb.y.is_nan() && !rgb.z.is_nan());
+                (rgb.x + rgb.y + rgb.z) / 3.0
+            })
+            .collect();
+        let mean = samples.iter().sum::<Float>() / n as Float;
+        let var = samples.iter()
+            .map(|s| (s - mean) * (s - mean))
+            .sum::<Float>() / (n - 1) as Float;
+        (mean, var)
+    }
+
+    #[test]
+    fn check_mis_reduces_variance() {
+        let mut rng = seeded_rng(0x5eed);
+        let cam = light_box_camera();
+
+        let mis = make_small_light_box();
+        // Same room, but the integrator doesn't know where the light is.
+        let bsdf_only = Scene {
+            lights: HitableList::default(),
+            ..make_small_light_box()
+        };
+
+        // A patch of pixels covering the floor and the back wall.
+        // The light is small and lights up both of its sides, so now and then
+        // a path finds the gap above it and makes a firefly. It takes plenty
+        // of samples for those to even out.
+        let mut mis_var = 0.0;
+        let mut bsdf_var = 0.0;
+        for j in 0..8 {
+            for i in 0..8 {
+                let u = 0.3 + 0.05 * i as Float;
+                let v = 0.1 + 0.05 * j as Float;
+                mis_var  += pixel_stats(&mis, &cam, u, v, 1024, &mut rng).1;
+                bsdf_var += pixel_stats(&bsdf_only, &cam, u, v, 1024, &mut rng).1;
+            }
+        }
+
+        assert!(mis_var < 0.25 * bsdf_var,
+                "MIS variance: {}, BSDF only variance: {}", mis_var, bsdf_var);
+    }
+
+    #[test]
+    fn check_light_sampling_cleans_up_the_cornell_box() {
+        let cam = light_box_camera();
+        let sampled = make_cornell_box();
+        let unsampled = Scene {
+            lights: HitableList::default(),
+            ..make_cornell_box()
+        };
+
+        // 16 samples in each of a grid of pixels over the whole box, the same
+        // samples every time.
+        let variance = |scene: &Scene| {
+            let mut rng = seeded_rng(0x5eed);
+            (0..64).map(|i| {
+                let (u, v) = (0.1 + 0.1 * (i % 8) as Float, 0.1 + 0.1 * (i / 8) as Float);
+                pixel_stats(scene, &cam, u, v, 16, &mut rng).1
+            })
+            .sum::<Float>()
+        };
+        let (sampled, unsampled) = (variance(&sampled), variance(&unsampled));
+        assert!(sampled < 0.1 * unsampled,
+                "With light sampling: {}, without: {}", sampled, unsampled);
+    }
+
+    #[test]
+    fn check_mis_inside_an_emissive_dome() {
+        // A gray ball in the middle of a glowing dome, with the camera inside
+        // it too. The ball reflects half of the dome's light, since it never
+        // sees itself.
+        let dome = Sphere {
+            center:   Float3::new(),
+            radius:   20.,
+            material: Arc::new(DiffuseLight { emit: Float3::xxx(1.) }),
+        };
+        let world = || HitableList {
+            hitables: vec![
+                Box::new(Sphere {
+                    center:   Float3::new(),
+                    radius:   1.,
+                    material: Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+                }),
+                Box::new(dome.clone()),
+            ],
+        };
+        let mis = Scene {
+            world: world(),
+            lights: HitableList {
+                hitables: vec![Box::new(dome.clone())],
+            },
+            background: Background::Black,
+            ..Scene::default()
+        };
+        // The naive integrator only ever finds the dome by bouncing into it.
+        let naive = Scene {
+            world: world(),
+            background: Background::Black,
+            ..Scene::default()
+        };
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 5.),
+            lookat:     Float3::new(),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 5.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+
+        let mut rng = seeded_rng(0x5eed);
+        // The ball, then the dome behind it.
+        for &(u, v, expected) in [(0.5, 0.5, 0.5), (0.05, 0.95, 1.0)].iter() {
+            let (mis_mean, _) = pixel_stats(&mis, &cam, u, v, 256, &mut rng);
+            let (naive_mean, _) = pixel_stats(&naive, &cam, u, v, 256, &mut rng);
+            assert!((mis_mean - naive_mean).abs() < 0.05,
+                    "({}, {}): MIS {} vs naive {}", u, v, mis_mean, naive_mean);
+            assert!((mis_mean - expected).abs() < 0.05,
+                    "({}, {}): MIS {} vs {}", u, v, mis_mean, expected);
+        }
+    }
+
+    #[test]
+    fn check_light_passes_sum_to_beauty() {
+        let cam = light_box_camera();
+
+        // Add something shiny and something glassy so every pass gets light.
+        let mut scene = make_small_light_box();
+        scene.world.hitables.push(Box::new(Sphere {
+            center: Float3::xyz(190., 90., 190.),
+            radius: 90.,
+            material: Arc::new(Metal {
+                albedo: Float3::xxx(0.8),
+                fuzz:   0.1,
+            }),
+        }));
+        scene.world.hitables.push(Box::new(Sphere {
+            center: Float3::xyz(370., 90., 350.),
+            radius: 90.,
+            material: Arc::new(Dielectric::new(1.5)),
+        }));
+
+        let patterns = Aov::ALL
+            .iter()
+            .filter_map(|aov| aov.light_path())
+            .map(|source| lpe::Pattern::parse(source).unwrap())
+            .collect();
+        let mut paths = LightPaths::new(patterns);
+
+        let mut rng = seeded_rng(0x5eed);
+        let mut pass_has_light = [false; 3];
+        for j in 0..16 {
+            for i in 0..16 {
+                let u = (i as Float + 0.5) / 16.;
+                let v = (j as Float + 0.5) / 16.;
+                for _ in 0..4 {
+                    let rgb = trace_path(&cam.get_ray(u, v, &mut rng),
+                                         &scene,
+                                         DEFAULT_MAX_DEPTH,
+                                         &mut rng,
+                                         Some(&mut paths),
+                                         None);
+                    let sum = paths.totals
+                        .iter()
+                        .fold(Float3::new(), |acc, total| acc + *total);
+                    assert!((sum - rgb).length() <= 1e-5 * (1.0 + rgb.length()),
+                            "({}, {}): passes sum to {:?}, but the path carried {:?}",
+                            u, v, sum, rgb);
+
+                    for (has_light, total) in pass_has_light.iter_mut().zip(paths.totals.iter()) {
+                        *has_light |= total.length_sq() > 0.0;
+                    }
+                }
+            }
+        }
+        assert_eq!(pass_has_light, [true; 3]);
+    }
+
+    #[test]
+    fn check_glass_debug_aov() {
+        let lookfrom = Float3::xyz(0., 0., 4.);
+        let cam = Camera::new(CameraInfo {
+            lookfrom,
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             16,
+            height:            16,
+            samples_per_pixel: 64,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "glass-ball".into(),
+        };
+        let scene = Scene::new(HitableList {
+            hitables: vec![Box::new(Sphere {
+                center:   Float3::new(),
+                radius:   1.,
+                material: Arc::new(Dielectric::new(1.5)),
+            })],
+        });
+
+        let mut covered = 0;
+        let mut empty = 0;
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let mut aovs = PixelAovs::new(&[Aov::Direct, Aov::GlassDebug]);
+                let mut sum = PixelSum::default();
+                render_pixel(&scene, &cam, &settings, (px, py), 64, &mut sum, Some(&mut aovs));
+                let glass = aovs.value(1);
+                let total = glass.x + glass.y + glass.z;
+
+                // How close does the middle of this pixel pass by the sphere?
+                let u = (px as Float + 0.5) / settings.width as Float;
+                let v = ((settings.height - 1 - py) as Float + 0.5) / settings.height as Float;
+                let dir = cam.get_ray(u, v, &mut seeded_rng(0)).dir.unit();
+                let miss_by = (-lookfrom - dir * (-lookfrom).dot(&dir)).length();
+
+                if miss_by < 0.7 {
+                    covered += 1;
+                    assert!((total - 1.0).abs() < 1e-9, "({}, {}): {:?}", px, py, glass);
+                } else if miss_by > 1.3 {
+                    empty += 1;
+                    assert_eq!(glass, Float3::new(), "({}, {})", px, py);
+                }
+                // Straight on, glass mostly lets light through.
+                if miss_by < 0.2 {
+                    assert!(glass.x > 0.9, "({}, {}): {:?}", px, py, glass);
+                }
+            }
+        }
+        assert!(covered > 0 && empty > 0);
+    }
+
+    #[test]
+    fn check_surface_aovs() {
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 4.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             31,
+            height:            31,
+            samples_per_pixel: 16,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "diffuse-ball".into(),
+        };
+        let albedo = Float3::xyz(0.2, 0.4, 0.6);
+        let scene = Scene::new(HitableList {
+            hitables: vec![Box::new(Sphere {
+                center:   Float3::new(),
+                radius:   1.,
+                material: Arc::new(Lambertian { albedo }),
+            })],
+        });
+        let render = |px, py| {
+            let mut aovs = PixelAovs::new(&[Aov::Normal, Aov::Depth, Aov::Albedo])
+                .with_depth_range((2., 4.));
+            let mut sum = PixelSum::default();
+            render_pixel(&scene, &cam, &settings, (px, py), 16, &mut sum, Some(&mut aovs));
+            aovs
+        };
+
+        // The middle pixel looks straight at the front of the ball, 3 away.
+        // Its samples spread across the pixel, 0.07 wide here, so their
+        // normals only average out to about +Z, and their depths to about 3.
+        let aovs = render(15, 15);
+        let normal = aovs.value(0);
+        assert!((normal - Float3::xyz(0., 0., 1.)).length() < 1e-2, "{:?}", normal);
+        let depth = aovs.value(1);
+        assert!((depth.x - 3.).abs() < 1e-3, "{:?}", depth);
+        // Adding up the same albedo 16 times and dividing only rounds it.
+        let average = aovs.value(2);
+        assert!((average - albedo).length() < 1e-6, "{:?}", average);
+
+        // The corners miss it entirely.
+        let aovs = render(0, 0);
+        assert_eq!(aovs.to_rgb8(0), image::Rgb([0, 0, 0]));
+        assert_eq!(aovs.to_rgb8(1), image::Rgb([255, 255, 255]));
+        assert_eq!(aovs.to_rgb8(2), image::Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn check_bounces_aov() {
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0.5, 0., 0.),
+            lookat:     Float3::xyz(0.5, 0., -1.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 90. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 1.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             8,
+            height:            8,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "mirror-tunnel".into(),
+        };
+        // Two long mirrors facing each other, which rays bounce between all
+        // the way down.
+        let mirror = || Arc::new(Metal::new(Float3::xxx(0.9), 0.));
+        let wall = |k| YzRect { a0: -100., a1: 100., b0: -100., b1: 100., k, material: mirror() };
+        let tunnel = Scene::new(HitableList {
+            hitables: vec![Box::new(wall(0.)), Box::new(FlipNormals { hitable: wall(1.) })],
+        });
+        let empty = Scene::new(HitableList::default());
+
+        let average = |scene: &Scene| {
+            let mut total = 0.0;
+            for py in 0..8 {
+                for px in 0..8 {
+                    let mut aovs = PixelAovs::new(&[Aov::Bounces]);
+                    let mut sum = PixelSum::default();
+                    render_pixel(scene, &cam, &settings, (px, py), 4, &mut sum, Some(&mut aovs));
+                    total += aovs.value(0).x;
+                    if scene.world.hitables.is_empty() {
+                        assert_eq!(aovs.to_rgb8(0), aov::heat(0., DEFAULT_MAX_DEPTH));
+                    }
+                }
+            }
+            total / 64.
+        };
+        assert_eq!(average(&empty), 0.);
+        let bounces = average(&tunnel);
+        assert!(bounces > 10., "{}", bounces);
+    }
+
+    #[test]
+    fn check_aov_stride() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             8,
+            height:            8,
+            samples_per_pixel: 256,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let five = [Aov::Direct, Aov::Indirect, Aov::Specular, Aov::Normal, Aov::Albedo];
+        const SAMPLES: u32 = 256;
+        // Every pixel's AOVs and samples, with AOVs traced every `stride`th sample,
+        // and how many samples were traced for AOVs.
+        let render = |stride| {
+            stats::enable(true);
+            stats::take_local();
+            let mut pixels = vec![];
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut aovs = PixelAovs::new(&five).with_stride(stride);
+                    let mut sum = PixelSum::default();
+                    render_pixel(&scene, &cam, &settings, (px, py), SAMPLES, &mut sum,
+                                 Some(&mut aovs));
+                    pixels.push((aovs, sum));
+                }
+            }
+            (pixels, stats::take_local().aov_samples)
+        };
+
+        let (every, every_count) = render(1);
+        let (fourth, fourth_count) = render(4);
+        let n_pixels = (settings.width * settings.height) as u64;
+        assert_eq!(every_count, n_pixels * SAMPLES as u64);
+        assert_eq!(fourth_count, n_pixels * (SAMPLES / 4) as u64);
+
+        // Direct and indirect light add up to the beauty pass. Everything in the
+        // box is diffuse, so none of it is specular.
+        let light = |aovs: &PixelAovs| aovs.value(0) + aovs.value(1);
+        // Traced for every sample, they add up exactly, pixel by pixel.
+        for (aovs, sum) in every.iter() {
+            assert_eq!(aovs.value(2), Float3::new());
+            let beauty = sum.average();
+            assert!((light(aovs) - beauty).length() <= 1e-5 * (1.0 + beauty.length()),
+                    "{:?} vs {:?}", light(aovs), beauty);
+        }
+        // Traced for every fourth, they add up to the beauty of just those
+        // samples. Each sample is seeded by its pixel and index, so we can
+        // take those again on their own.
+        for (i, (aovs, _)) in fourth.iter().enumerate() {
+            let pixel = (i as u32 % settings.width, i as u32 / settings.width);
+            let mut strided = PixelSum::default();
+            for sample in (0..SAMPLES).step_by(4) {
+                let mut one = PixelSum { samples: sample, ..PixelSum::default() };
+                render_pixel(&scene, &cam, &settings, pixel, sample + 1, &mut one, None);
+                strided.radiance += one.radiance;
+                strided.weight += one.weight;
+            }
+            let beauty = strided.average();
+            assert!((light(aovs) - beauty).length() <= 1e-5 * (1.0 + beauty.length()),
+                    "{:?}: {:?} vs {:?}", pixel, light(aovs), beauty);
+        }
+        // Surface passes take their 16 samples either way, just not the same
+        // ones, so they only differ by noise.
+        let totals = |pixels: &[(PixelAovs, PixelSum)], i: usize| {
+            pixels.iter()
+                  .map(|(aovs, _)| aovs.value(i))
+                  .fold(0.0, |total, v| total + v.x.abs() + v.y.abs() + v.z.abs())
+        };
+        for i in 3..five.len() {
+            let (a, b) = (totals(&every, i), totals(&fourth, i));
+            assert!((a - b).abs() <= 0.05 * a, "{}: {} vs {}", five[i], a, b);
+        }
+        // And the image doesn't care.
+        for ((_, every_sum), (_, fourth_sum)) in every.iter().zip(fourth.iter()) {
+            assert_eq!(every_sum, fourth_sum);
+        }
+
+        // Once the surface passes are full, only light passes cost anything.
+        let surfaces_only = {
+            stats::take_local();
+            let mut aovs = PixelAovs::new(&[Aov::Normal, Aov::Depth]);
+            render_pixel(&scene, &cam, &settings, (4, 4), SAMPLES, &mut PixelSum::default(),
+                         Some(&mut aovs));
+            stats::take_local().aov_samples
+        };
+        assert_eq!(surfaces_only, aov::SURFACE_SAMPLES as u64);
+    }
+
+    #[test]
+    fn check_framing_golden() {
+        // A glowing ball in the dark, so each pixel is just how much of it
+        // the ball covers.
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 4.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.5,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             12,
+            height:            8,
+            samples_per_pixel: 64,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "framing".into(),
+        };
+        let scene = Scene {
+            background: Background::Black,
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(Sphere {
+                    // Up and to the right, so that flipping the image
+                    // either way shows.
+                    center:   Float3::xyz(0.3, 0.2, 0.),
+                    radius:   0.8,
+                    material: Arc::new(DiffuseLight {
+                        emit: Float3::xxx(1.0),
+                    }),
+                })],
+            })
+        };
+
+        let mut rendered = String::new();
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let mut sum = PixelSum::default();
+                render_pixel(&scene, &cam, &settings, (px, py), 64, &mut sum, None);
+                let coverage = (sum.average().x * 9.0).round() as u8;
+                rendered.push((b'0' + coverage) as char);
+            }
+            rendered.push('\n');
+        }
+
+        // The ball's center is 0.8 pixels right of and 0.55 pixels above the
+        // middle of the image, which is inside the pixel at column 6, row 3.
+        let golden = "\
+000000000000
+000002672000
+000019998000
+000039999100
+000028997000
+000002651000
+000000000000
+000000000000
+";
+        // Samples land in slightly different places with f32, which can
+        // tip a pixel over to the next digit, but no further.
+        let slack = if cfg!(feature = "float32") { 1 } else { 0 };
+        let off = |(a, b): (u8, u8)| (a as i32 - b as i32).abs();
+        assert!(rendered.len() == golden.len() &&
+                rendered.bytes().zip(golden.bytes()).all(|pair| off(pair) <= slack),
+                "\n{}", rendered);
+    }
+
+    #[test]
+    fn check_lut_renders() {
+        let (nx, ny) = (36, 24);
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(13., 2., 3.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 20. },
+            aspect:     1.5,
+            aperature:  0.,
+            focus_dist: 10.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             nx,
+            height:            ny,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x107,
+            scene:             "cover".into(),
+        };
+        let hitables = make_cover_scene(&SceneSeed::default(), &CoverSceneParams::default())
+            .hitables;
+        let scene = Scene::new(HitableList {
+            hitables: vec![Box::new(bvh::Bvh::new(hitables, 0., 0.))],
+        });
+        let linear = LinearImage::from_fn(nx, ny, |px, py| {
+            let mut sum = PixelSum::default();
+            render_pixel(&scene, &cam, &settings, (px, py), 4, &mut sum, None);
+            sum.average()
+        });
+
+        // Mean absolute difference per channel, out of 255.
+        let diff = |a: &image::RgbImage, b: &image::RgbImage| {
+            let total: i64 = a.iter()
+                              .zip(b.iter())
+                              .map(|(a, b)| (*a as i64 - *b as i64).abs())
+                              .sum();
+            total as f64 / a.len() as f64
+        };
+        let plain = linear.to_rgb8();
+
+        let identity = lut::Lut::parse_cube(include_str!("../luts/identity.cube")).unwrap();
+        let with_identity = linear.to_rgb8_with(Some(&identity));
+        for (a, b) in plain.iter().zip(with_identity.iter()) {
+            assert!((*a as i32 - *b as i32).abs() <= 1, "{} vs {}", a, b);
+        }
+
+        let filmic = lut::Lut::parse_cube(include_str!("../luts/filmic.cube")).unwrap();
+        let with_filmic = linear.to_rgb8_with(Some(&filmic));
+        assert!(diff(&plain, &with_filmic) > 5.0, "{}", diff(&plain, &with_filmic));
+    }
+
+    #[test]
+    fn check_filters_soften_edges() {
+        let (nx, ny) = (8, 8);
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 4.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             nx,
+            height:            ny,
+            samples_per_pixel: 32,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "edge".into(),
+        };
+        // Light to the left of x = 0, which is right between two columns of
+        // pixels.
+        let scene = |filter: filter::FilterKind| Scene {
+            background: Background::Black,
+            filter:     filter.filter(),
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(XyRect {
+                    a0: -10., a1: 0.,
+                    b0: -10., b1: 10.,
+                    k:  0.,
+                    material: Arc::new(DiffuseLight { emit: Float3::xxx(1.0) }),
+                })],
+            })
+        };
+        let row = |filter| -> Vec<Float> {
+            let scene = scene(filter);
+            (0..nx).map(|px| {
+                let mut sum = PixelSum::default();
+                render_pixel(&scene, &cam, &settings, (px, ny / 2), 256, &mut sum, None);
+                sum.average().x
+            }).collect()
+        };
+
+        // The box filter keeps the edge as sharp as a pixel can be.
+        let sharp = row(filter::FilterKind::Box);
+        assert_eq!(&sharp[..4], &[1.0; 4]);
+        assert_eq!(&sharp[4..], &[0.0; 4]);
+
+        // Wider filters blur it across the pixels next to it, and no further
+        // than their radius.
+        for &kind in [filter::FilterKind::Tent, filter::FilterKind::Gaussian].iter() {
+            let soft = row(kind);
+            assert!(0.6 < soft[3] && soft[3] < 0.98, "{:?}: {:?}", kind, soft);
+            assert!(0.02 < soft[4] && soft[4] < 0.4, "{:?}: {:?}", kind, soft);
+            assert_eq!((soft[1], soft[6]), (1.0, 0.0), "{:?}: {:?}", kind, soft);
+        }
+    }
+
+    #[test]
+    fn check_transparent_background() {
+        let (nx, ny) = (16, 16);
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 4.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             nx,
+            height:            ny,
+            samples_per_pixel: 32,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "red-ball".into(),
+        };
+        // A red ball against the sky. Nothing else has any red in it.
+        let scene = |transparent_background| Scene {
+            transparent_background,
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(Sphere {
+                    center:   Float3::new(),
+                    radius:   0.8,
+                    material: Arc::new(DiffuseLight { emit: Float3::xyz(1., 0., 0.) }),
+                })],
+            })
+        };
+        let render = |scene: &Scene| {
+            let accum = Accumulator::new(nx, ny);
+            for py in 0..ny {
+                for px in 0..nx {
+                    let mut sum = PixelSum::default();
+                    render_pixel(scene, &cam, &settings, (px, py), 32, &mut sum, None);
+                    accum.set(px, py, sum);
+                }
+            }
+            linear_image(scene, &accum, nx, ny)
+        };
+
+        let opaque = render(&scene(false));
+        assert!(!opaque.has_alpha());
+        assert!(opaque.get_pixel(0, 0).z > 0.5);
+
+        let transparent = render(&scene(true));
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-transparent-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.png");
+        output::write(&transparent, &path, None, None).unwrap();
+        let png = image::open(&path).unwrap().to_rgba();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let alpha = |x, y| png.get_pixel(x, y).data[3];
+        assert_eq!(alpha(nx / 2, ny / 2), 255);
+        for &(x, y) in [(0, 0), (nx - 1, 0), (0, ny - 1), (nx - 1, ny - 1)].iter() {
+            assert_eq!(alpha(x, y), 0, "({}, {})", x, y);
+        }
+        let edges = png.pixels().filter(|px| 0 < px.data[3] && px.data[3] < 255).count();
+        assert!(edges > 0, "The silhouette should be partly covered");
+
+        for (x, y, px) in png.enumerate_pixels() {
+            // None of the sky was mixed in, and what's covered is all red.
+            assert_eq!(&px.data[1..3], &[0, 0], "({}, {})", x, y);
+            if px.data[3] > 0 {
+                assert_eq!(px.data[0], 255, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn check_profile_finds_the_big_object() {
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 10.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 10.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             12,
+            height:            12,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "big-ball".into(),
+        };
+        let make_scene = || {
+            // A row of little balls, and a huge one behind them that fills
+            // the rest of the view.
+            let mut hitables: Vec<Box<dyn Hitable>> = (0..8)
+                .map(|i| {
+                    Box::new(Sphere {
+                        center:   Float3::xyz(-3.5 + i as Float, -2., 0.),
+                        radius:   0.3,
+                        material: Arc::new(Metal {
+                            albedo: Float3::xxx(0.8),
+                            fuzz:   0.2,
+                        }),
+                    }) as Box<dyn Hitable>
+                })
+                .collect();
+            hitables.insert(5, Box::new(Sphere {
+                center:   Float3::xyz(0., 0., -100.),
+                radius:   90.,
+                material: Arc::new(Lambertian {
+                    albedo: Float3::xyz(0.2, 0.4, 0.6),
+                }),
+            }));
+            hitables
+        };
+        let render = |hitables| {
+            let scene = Scene::new(HitableList {
+                hitables: vec![Box::new(bvh::Bvh::new(hitables, 0., 0.))],
+            });
+            let mut pixels = vec![];
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut sum = PixelSum::default();
+                    render_pixel(&scene, &cam, &settings, (px, py), 4, &mut sum, None);
+                    pixels.push(sum);
+                }
+            }
+            pixels
+        };
+
+        let plain = render(make_scene());
+        let (instrumented, profile) = profile::instrument(make_scene(), 0., 0.);
+        let profiled = render(instrumented);
+        assert_eq!(plain, profiled);
+
+        // Everything in the big ball's leaf is tested as often as it is, but
+        // it's the one that gets hit.
+        let totals = profile.totals();
+        for (id, counts) in totals.objects.iter().enumerate() {
+            assert!(counts.tests <= totals.objects[5].tests, "{:?}", totals);
+            if id != 5 {
+                assert!(10 * counts.hits < totals.objects[5].hits, "{:?}", totals);
+            }
+        }
+
+        let lambertian = totals.scatters["Lambertian"];
+        assert!(lambertian > totals.scatters["Metal"], "{:?}", totals);
+
+        let report = profile.report(3);
+        let first = report.lines().nth(1).unwrap();
+        assert!(first.contains("#5 at (0.00, 0.00, -100.00)"), "{}", report);
+    }
+
+    /// Alpha for a 4x4 checkerboard, with holes in the "even" squares.
+    fn holes() -> Arc<dyn texture::Texture> {
+        Arc::new(texture::CheckerTexture {
+            even:   Float3::xxx(0.0),
+            odd:    Float3::xxx(1.0),
+            checks: 4,
+        })
+    }
+
+    #[test]
+    fn check_cutout_holes() {
+        // A glowing grey board spanning [-2, 2] in x and y, over nothing.
+        let scene = Scene {
+            background: Background::Black,
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(XyRect {
+                    a0: -2., a1: 2.,
+                    b0: -2., b1: 2.,
+                    k:  0.,
+                    material: Arc::new(Cutout {
+                        material: DiffuseLight { emit: Float3::xxx(0.5) },
+                        alpha:    holes(),
+                    }),
+                })],
+            })
+        };
+
+        // Each square is 1x1, and the one at the bottom left is a hole.
+        let mut rng = seeded_rng(0x5eed);
+        for j in 0..4 {
+            for i in 0..4 {
+                let target = Float3::xyz(-1.5 + i as Float, -1.5 + j as Float, 0.);
+                let origin = Float3::xyz(0.3, 0.2, 5.);
+                let ray = Ray {
+                    origin,
+                    dir: target - origin,
+                    t:   0.,
+                };
+                let expected = if (i + j) % 2 == 0 { 0.0 } else { 0.5 };
+                for _ in 0..8 {
+                    assert_eq!(color(&ray, &scene, DEFAULT_MAX_DEPTH, &mut rng),
+                               Float3::xxx(expected),
+                               "({}, {})", i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_cutout_shadows() {
+        // A small light high over the floor, with the board in between.
+        let light = XzRect {
+            a0: -0.05, a1: 0.05,
+            b0: -0.05, b1: 0.05,
+            k:  5.,
+            material: Arc::new(DiffuseLight { emit: Float3::xxx(10.) }),
+        };
+        let floor = XzRect {
+            a0: -10., a1: 10.,
+            b0: -10., b1: 10.,
+            k:  0.,
+            material: Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+        };
+        let scene = Scene {
+            world: HitableList {
+                hitables: vec![
+                    Box::new(floor.clone()),
+                    Box::new(XzRect {
+                        a0: -2., a1: 2.,
+                        b0: -2., b1: 2.,
+                        k:  1.,
+                        material: Arc::new(Cutout {
+                            material: Lambertian { albedo: Float3::xxx(0.5) },
+                            alpha:    holes(),
+                        }),
+                    }),
+                    Box::new(light.clone()),
+                ],
+            },
+            lights: HitableList {
+                hitables: vec![Box::new(light)],
+            },
+            background: Background::Black,
+            ..Scene::default()
+        };
+
+        // Squares on the board project 1.25x bigger onto the floor.
+        let mut rng = seeded_rng(0x5eed);
+        let mut light_at = |x: Float, z: Float| {
+            let ray_in = Ray {
+                origin: Float3::xyz(x, 0.5, z),
+                dir:    Float3::xyz(0., -1., 0.),
+                t:      0.,
+            };
+            let record = floor.hit(&ray_in, 1e-3, 1e9, &mut rng).unwrap();
+            (0..16)
+                .map(|_| {
+                    sample_one_light(&ray_in, &record, &Float3::xxx(0.5), &scene, &mut rng)
+                })
+                .fold(Float3::new(), |acc, light| acc + light)
+        };
+        for j in 0..4 {
+            for i in 0..4 {
+                // Under the middle of each square
+                let x = 1.25 * (-1.5 + i as Float);
+                let z = 1.25 * (-1.5 + j as Float);
+                let light = light_at(x, z);
+                if (i + j) % 2 == 0 {
+                    assert!(light.x > 0.0, "({}, {}) should be lit", i, j);
+                } else {
+                    assert_eq!(light, Float3::new(), "({}, {}) should be in shadow", i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_opaque_cutout_changes_nothing() {
+        let cam = light_box_camera();
+        let settings = RenderSettings {
+            width:             8,
+            height:            8,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let render = |scene: &Scene| {
+            let mut pixels = vec![];
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut sum = PixelSum::default();
+                    render_pixel(scene, &cam, &settings, (px, py), 4, &mut sum, None);
+                    pixels.push(sum);
+                }
+            }
+            pixels
+        };
+
+        let ball = |material: Arc<dyn Material>| {
+            let mut scene = make_small_light_box();
+            scene.world.hitables.push(Box::new(Sphere {
+                center: Float3::xyz(278., 200., 278.),
+                radius: 120.,
+                material,
+            }));
+            scene
+        };
+        let albedo = Float3::xyz(0.3, 0.6, 0.2);
+        let plain = render(&ball(Arc::new(Lambertian { albedo })));
+        let opaque = render(&ball(Arc::new(Cutout {
+            material: Lambertian { albedo },
+            alpha:    Arc::new(Float3::xxx(1.0)),
+        })));
+        assert_eq!(plain, opaque);
+    }
+
+    #[test]
+    fn check_stats_count_primary_rays() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             6,
+            height:            4,
+            samples_per_pixel: 5,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let ns = 5;
+
+        stats::enable(true);
+        // Counts are kept per thread, so other tests can't add to ours.
+        stats::take_local();
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let mut sum = PixelSum::default();
+                render_pixel(&scene, &cam, &settings, (px, py), ns, &mut sum, None);
+            }
+        }
+        let counts = stats::take_local();
+
+        assert_eq!(counts.primary_rays, (settings.width * settings.height * ns) as u64);
+        assert!(counts.scattered_rays > 0, "{:?}", counts);
+        assert!(counts.total_rays() > counts.primary_rays, "{:?}", counts);
+    }
+
+    #[test]
+    fn check_throughput_cutoff() {
+        // Inside of a dim mirror ball, paths bounce until there's nothing left
+        // of them.
+        let scene = |throughput_cutoff| Scene {
+            world: HitableList {
+                hitables: vec![
+                    Box::new(FlipNormals {
+                        hitable: Sphere {
+                            center:   Float3::new(),
+                            radius:   10.,
+                            material: Arc::new(Metal {
+                                albedo: Float3::xxx(0.1),
+                                fuzz:   0.,
+                            }),
+                        },
+                    }),
+                    Box::new(Sphere {
+                        center:   Float3::xyz(0., 3., 0.),
+                        radius:   1.,
+                        material: Arc::new(DiffuseLight { emit: Float3::xxx(4.) }),
+                    }),
+                ],
+            },
+            background: Background::Black,
+            throughput_cutoff,
+            ..Scene::default()
+        };
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 8.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 90. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 8.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             8,
+            height:            8,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "mirror-ball".into(),
+        };
+        let render = |scene: &Scene| {
+            LinearImage::from_fn(settings.width, settings.height, |px, py| {
+                let mut sum = PixelSum::default();
+                render_pixel(scene, &cam, &settings, (px, py), 4, &mut sum, None);
+                sum.average()
+            })
+        };
+
+        let before = NEGLIGIBLE_PATHS.load(atomic::Ordering::SeqCst);
+        let cut = render(&scene(DEFAULT_THROUGHPUT_CUTOFF));
+        assert!(NEGLIGIBLE_PATHS.load(atomic::Ordering::SeqCst) > before);
+        let uncut = render(&scene(0.));
+
+        let mut error = 0.0;
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                error += (cut.get_pixel(px, py) - uncut.get_pixel(px, py)).length();
+            }
+        }
+        let mae = error / (settings.width * settings.height) as Float;
+        assert!(mae < 1e-20, "MAE: {}", mae);
+        // The light shows up somewhere in the mirror.
+        let lit = |py| (0..settings.width).any(|px| uncut.get_pixel(px, py) != Float3::new());
+        assert!((0..settings.height).any(lit));
+    }
+
+    #[test]
+    fn check_resume_matches_uninterrupted() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             8,
+            height:            8,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let render = |accum: &Accumulator, target: u32| {
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut sum = accum.get(px, py);
+                    render_pixel(&scene, &cam, &settings, (px, py), target, &mut sum, None);
+                    accum.set(px, py, sum);
+                }
+            }
+        };
+
+        // Render some samples, and stash them in a checkpoint.
+        let interrupted = Accumulator::new(settings.width, settings.height);
+        render(&interrupted, 3);
+        let mut bytes = vec![];
+        interrupted.to_checkpoint(&settings).write_to(&mut bytes).unwrap();
+
+        // Pick back up and finish.
+        let checkpoint = checkpoint::Checkpoint::read_from(&mut bytes.as_slice()).unwrap();
+        let resumed = Accumulator::from_checkpoint(&checkpoint);
+        render(&resumed, 7);
+
+        // Do it all in one go.
+        let uninterrupted = Accumulator::new(settings.width, settings.height);
+        render(&uninterrupted, 7);
+
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let (a, b) = (resumed.get(px, py), uninterrupted.get(px, py));
+                assert_eq!(a.samples, 7);
+                // Checkpoints don't keep `luminance_sq`, since only --despeckle
+                // and --variance-map read it.
+                assert_eq!((a.radiance, a.samples, a.weight), (b.radiance, b.samples, b.weight),
+                           "({}, {})", px, py);
+            }
+        }
+    }
+
+    #[test]
+    fn check_baked_texture_renders_the_same() {
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 4.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 4.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        let settings = RenderSettings {
+            width:             24,
+            height:            24,
+            samples_per_pixel: 4,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "checker-ball".into(),
+        };
+        let checker: Arc<dyn texture::Texture> = Arc::new(texture::CheckerTexture {
+            even:   Float3::xxx(0.9),
+            odd:    Float3::xyz(0.2, 0.3, 0.1),
+            checks: 8,
+        });
+        let render = |bake_with| {
+            let center = Float3::new();
+            let domain = texture::UvDomain::Sphere { center, radius: 1. };
+            let albedo = texture::bind(checker.clone(), domain, bake_with).unwrap();
+            let scene = Scene::new(HitableList {
+                hitables: vec![Box::new(Sphere {
+                    center,
+                    radius:   1.,
+                    material: Arc::new(Lambertian { albedo }),
+                })],
+            });
+            let mut pixels = vec![];
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    let mut sum = PixelSum::default();
+                    render_pixel(&scene, &cam, &settings, (px, py), 4, &mut sum, None);
+                    pixels.push(sum.average());
+                }
+            }
+            pixels
+        };
+
+        let unbaked = render(None);
+        let baked = render(Some(texture::Bake { resolution: 1024 }));
+        let error: Float = unbaked.iter()
+            .zip(baked.iter())
+            .map(|(a, b)| (*a - *b).abs().as_slice().iter().sum::<Float>() / 3.0)
+            .sum();
+        let mae = error / unbaked.len() as Float;
+        assert!(mae < 0.01, "MAE: {}", mae);
+    }
+}
@@ -0,0 +1,1588 @@
+//! Loading scenes from text files.
+//!
+//! Scene files look like RON: structs are `Name(field: value, ...)`, vectors
+//! are `(x, y, z)`, lists are `[a, b, c]`, and `//` starts a comment.
+//!
+//! ```text
+//! (
+//!     version: 1,
+//!     background: Sky,
+//!     objects: [
+//!         Sphere(center: (0, -1000, 0), radius: 1000,
+//!                material: Lambertian(albedo: (0.5, 0.5, 0.5))),
+//!         Sphere(center: (0, 1, 0), radius: 1,
+//!                material: Dielectric(refraction_index: 1.5)),
+//!         Sphere(center: (-4, 1, 0), radius: 1,
+//!                material: Lambertian(albedo: Image(path: "earth.png"))),
+//!     ],
+//! )
+//! ```
+//!
+//! Objects that are in a scene many times over can be defined once, by name,
+//! and placed with `Instance`s, which share the one object between them:
+//!
+//! ```text
+//! (
+//!     version: 1,
+//!     defines: (
+//!         marble: Sphere(center: (0, 0, 0), radius: 0.2,
+//!                        material: Dielectric(refraction_index: 1.5)),
+//!     ),
+//!     objects: [
+//!         Instance(of: marble, translate: (1, 0.2, 0)),
+//!         Instance(of: marble, scale: 2, rotate: (45, 0, 0), translate: (2, 0.4, 0)),
+//!     ],
+//! )
+//! ```
+//!
+//! Every file says which version of the format it was written for. When the
+//! format changes, the version goes up and `SCHEMA` gets a note about what
+//! changed, so that we can tell people how to update older files. Fields we
+//! don't know are errors, with a suggestion if it looks like a typo.
+//!
+//! Image paths are relative to the scene file. Images are decoded through an
+//! `AssetCache`, so using one in several places only decodes it once.
+//!
+//! Textures that are slow to look up, like many octaves of `Noise`, can be
+//! baked into an image when the scene loads, by giving the object they're on
+//! `bake: (resolution: 512)`. Every texture of its material is baked over the
+//! object's (u, v). Textures that depend on position can only be baked onto a
+//! `Sphere`, where we know the position of every (u, v).
+//!
+//! Materials are looked up by name in a `MaterialRegistry`. Programs using
+//! the library can register their own materials there, and use them in scene
+//! files just like the built-in ones.
+
+use std::{
+    fmt,
+    fs,
+    path,
+    sync::Arc,
+};
+
+use crate::assets::AssetCache;
+use crate::hitable::{
+    Aabb,
+    FlipNormals,
+    Hitable,
+    HitableList,
+    KeyframedSphere,
+    MovingSphere,
+    Sphere,
+};
+use crate::material::{
+    Dielectric,
+    DiffuseLight,
+    GgxMetal,
+    Isotropic,
+    Lambertian,
+    Metal,
+    OrenNayar,
+};
+use crate::prelude::*;
+use crate::rect::{
+    XyRect,
+    XzRect,
+    YzRect,
+};
+use crate::render::{
+    Background,
+    Scene,
+};
+use crate::texture::{
+    self,
+    Bake,
+    CheckerTexture,
+    ImageTexture,
+    NoiseTexture,
+    Texture,
+    UvDomain,
+};
+use crate::transform::{
+    Instance,
+    Mat3,
+};
+use crate::volume::NoiseVolume;
+
+/// Versions of the format we can read, and what changed between them.
+pub struct Schema {
+    /// The version that new files should use.
+    pub current: u32,
+    /// The oldest version we can still read.
+    pub oldest:  u32,
+    pub notes:   &'static [MigrationNote],
+}
+
+pub const SCHEMA: Schema = Schema {
+    current: 1,
+    oldest:  1,
+    notes:   &[],
+};
+
+/// Something about the format that changed in `version`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MigrationNote {
+    pub version: u32,
+    /// The kind of object the change is to, like "Metal".
+    pub object:  &'static str,
+    pub field:   &'static str,
+    pub change:  Change,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The field has a new name.
+    Renamed(&'static str),
+    /// The field is gone, for this reason.
+    Removed(&'static str),
+}
+
+impl fmt::Display for MigrationNote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.change {
+            Change::Renamed(to) => {
+                write!(f, "`{}` was renamed to `{}` in version {}",
+                       self.field, to, self.version)
+            },
+            Change::Removed(why) => {
+                write!(f, "`{}` was removed in version {}: {}", self.field, self.version, why)
+            },
+        }
+    }
+}
+
+// ===== Syntax ================================================================
+
+/// Where something is in a scene file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pos {
+    pub line: usize,
+    pub col:  usize,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A value from a scene file, before we know what it's for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneValue {
+    pub kind: ValueKind,
+    pub pos:  Pos,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueKind {
+    Number(Float),
+    Bool(bool),
+    Str(String),
+    /// A bare name, like `Sky`.
+    Ident(String),
+    /// `(a, b, c)`
+    Tuple(Vec<SceneValue>),
+    /// `[a, b, c]`
+    List(Vec<SceneValue>),
+    /// `Name(field: value, ...)`, or `(field: value, ...)` without a name.
+    Struct {
+        name:   Option<String>,
+        fields: Vec<Field>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name:  String,
+    pub pos:   Pos,
+    pub value: SceneValue,
+}
+
+impl ValueKind {
+    /// What to call this in error messages.
+    fn describe(&self) -> String {
+        match self {
+            ValueKind::Number(_) => "a number".into(),
+            ValueKind::Bool(_) => "true or false".into(),
+            ValueKind::Str(_) => "a string".into(),
+            ValueKind::Ident(name) => format!("`{}`", name),
+            ValueKind::Tuple(_) => "a tuple".into(),
+            ValueKind::List(_) => "a list".into(),
+            ValueKind::Struct { name: Some(name), .. } => format!("a {}", name),
+            ValueKind::Struct { name: None, .. } => "a struct".into(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Parser {
+    chars: Vec<char>,
+    i:     usize,
+    pos:   Pos,
+}
+
+/// Parses the text of a scene file, without checking what's in it.
+pub fn parse(text: &str) -> Result<SceneValue, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        i:     0,
+        pos:   Pos { line: 1, col: 1 },
+    };
+    let value = parser.value()?;
+    parser.skip_space();
+    if let Some(c) = parser.peek() {
+        return Err(format!("{}: expected the end of the file, found `{}`", parser.pos, c));
+    }
+    Ok(value)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.i += 1;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                },
+                Some('/') if self.chars.get(self.i + 1) == Some(&'/') => {
+                    while self.peek().map_or(false, |c| c != '\n') {
+                        self.bump();
+                    }
+                },
+                _ => break,
+            }
+        }
+    }
+
+    /// Skips whitespace, and takes `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("{}: expected `{}`, found {}", self.pos, c, self.found()))
+        }
+    }
+
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("`{}`", c),
+            None => "the end of the file".into(),
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        self.skip_space();
+        match self.peek() {
+            Some(c) if c.is_alphabetic() || c == '_' => {},
+            _ => return None,
+        }
+        let mut ident = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+            ident.push(c);
+            self.bump();
+        }
+        Some(ident)
+    }
+
+    fn value(&mut self) -> Result<SceneValue, String> {
+        self.skip_space();
+        let pos = self.pos;
+        let kind = match self.peek() {
+            Some('(') => self.parens(None)?,
+            Some('[') => {
+                self.bump();
+                ValueKind::List(self.values(']')?)
+            },
+            Some('"') => {
+                self.bump();
+                let mut s = String::new();
+                loop {
+                    match self.bump() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err(format!("{}: this string never ends", pos)),
+                    }
+                }
+                ValueKind::Str(s)
+            },
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = self.peek().filter(|c| {
+                    c.is_ascii_alphanumeric() || "+-.".contains(*c)
+                }) {
+                    number.push(c);
+                    self.bump();
+                }
+                let n = number.parse::<Float>()
+                              .map_err(|_| format!("{}: \"{}\" is not a number", pos, number))?;
+                ValueKind::Number(n)
+            },
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let ident = self.ident().unwrap();
+                self.skip_space();
+                match ident.as_str() {
+                    "true" => ValueKind::Bool(true),
+                    "false" => ValueKind::Bool(false),
+                    _ if self.peek() == Some('(') => self.parens(Some(ident))?,
+                    _ => ValueKind::Ident(ident),
+                }
+            },
+            _ => return Err(format!("{}: expected a value, found {}", pos, self.found())),
+        };
+        Ok(SceneValue { kind, pos })
+    }
+
+    /// Parses `(...)`: a struct if it starts with `field:`, and a tuple otherwise.
+    fn parens(&mut self, name: Option<String>) -> Result<ValueKind, String> {
+        self.expect('(')?;
+
+        let mut lookahead = self.clone();
+        let is_struct = lookahead.ident().is_some() && lookahead.eat(':');
+        if !is_struct {
+            let values = self.values(')')?;
+            return match name {
+                // `Name()` is a struct with no fields.
+                Some(name) if values.is_empty() => {
+                    Ok(ValueKind::Struct { name: Some(name), fields: vec![] })
+                },
+                Some(name) => Err(format!("{}: {} needs field names, like `{}(field: value)`",
+                                          self.pos, name, name)),
+                None => Ok(ValueKind::Tuple(values)),
+            };
+        }
+
+        let mut fields: Vec<Field> = vec![];
+        loop {
+            if self.eat(')') {
+                break;
+            }
+            self.skip_space();
+            let pos = self.pos;
+            let field = self.ident()
+                .ok_or_else(|| format!("{}: expected a field name, found {}", pos, self.found()))?;
+            if fields.iter().any(|f| f.name == field) {
+                return Err(format!("{}: `{}` is given more than once", pos, field));
+            }
+            self.expect(':')?;
+            let value = self.value()?;
+            fields.push(Field {
+                name: field,
+                pos,
+                value,
+            });
+            if !self.eat(',') {
+                self.expect(')')?;
+                break;
+            }
+        }
+        Ok(ValueKind::Struct { name, fields })
+    }
+
+    /// Comma separated values, up to `end`.
+    fn values(&mut self, end: char) -> Result<Vec<SceneValue>, String> {
+        let mut values = vec![];
+        loop {
+            if self.eat(end) {
+                break;
+            }
+            values.push(self.value()?);
+            if !self.eat(',') {
+                self.expect(end)?;
+                break;
+            }
+        }
+        Ok(values)
+    }
+}
+
+// ===== Materials =============================================================
+
+/// Builds a material from its struct in a scene file, like `Metal(...)`.
+/// By the time it's called, the struct's fields have been checked against the
+/// ones the material was registered with.
+pub type MaterialFactory = fn(&Object) -> Result<Arc<dyn Material>, String>;
+
+/// The materials that scene files can use, by name.
+/// The default registry has the built-in materials. Add to it with
+/// `register_material()`, and pass it to `load_with()`.
+#[derive(Clone)]
+pub struct MaterialRegistry {
+    kinds: Vec<MaterialKind>,
+}
+
+#[derive(Clone)]
+struct MaterialKind {
+    name:    String,
+    fields:  &'static [&'static str],
+    factory: MaterialFactory,
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> MaterialRegistry {
+        let mut registry = MaterialRegistry { kinds: vec![] };
+        registry.register_material("Lambertian", &["albedo"], lambertian);
+        registry.register_material("Metal", &["albedo", "fuzz"], metal);
+        registry.register_material("GgxMetal", &["albedo", "roughness"], ggx_metal);
+        registry.register_material("Dielectric", &["refraction_index", "absorption"], dielectric);
+        registry.register_material("DiffuseLight", &["emit"], diffuse_light);
+        registry.register_material("Isotropic", &["albedo"], isotropic);
+        registry.register_material("OrenNayar", &["albedo", "sigma"], oren_nayar);
+        registry
+    }
+}
+
+impl MaterialRegistry {
+    /// Lets scene files use `name(...)` as a material, with any of `fields`.
+    /// Registering a name again replaces what was there, built-ins included.
+    pub fn register_material(&mut self,
+                             name:    &str,
+                             fields:  &'static [&'static str],
+                             factory: MaterialFactory)
+    {
+        let kind = MaterialKind {
+            name: name.into(),
+            fields,
+            factory,
+        };
+        match self.kinds.iter_mut().find(|k| k.name == name) {
+            Some(existing) => *existing = kind,
+            None => self.kinds.push(kind),
+        }
+    }
+
+    /// Every name we know, in the order they were registered.
+    pub fn names(&self) -> Vec<&str> {
+        self.kinds.iter().map(|k| k.name.as_str()).collect()
+    }
+
+    fn get(&self, name: &str) -> Option<&MaterialKind> {
+        self.kinds.iter().find(|k| k.name == name)
+    }
+}
+
+fn lambertian(object: &Object) -> Result<Arc<dyn Material>, String> {
+    // Either a plain color, or a texture.
+    match object.required("albedo")?.kind {
+        ValueKind::Tuple(_) => Ok(Arc::new(Lambertian::new(object.albedo("albedo")?))),
+        _ => Ok(Arc::new(Lambertian { albedo: object.texture("albedo")? })),
+    }
+}
+
+fn oren_nayar(object: &Object) -> Result<Arc<dyn Material>, String> {
+    let sigma = object.number("sigma")?;
+    if sigma < 0.0 {
+        return Err(format!("{}: `sigma` can't be negative, but it's {}",
+                           object.required("sigma")?.pos, sigma));
+    }
+    Ok(Arc::new(OrenNayar::new(object.albedo("albedo")?, sigma)))
+}
+
+fn metal(object: &Object) -> Result<Arc<dyn Material>, String> {
+    // Fuzz past 1 is clamped, like in the book.
+    Ok(Arc::new(Metal::new(object.albedo("albedo")?, object.number("fuzz")?)))
+}
+
+fn ggx_metal(object: &Object) -> Result<Arc<dyn Material>, String> {
+    Ok(Arc::new(GgxMetal {
+        albedo:    object.albedo("albedo")?,
+        roughness: object.number("roughness")?,
+    }))
+}
+
+fn dielectric(object: &Object) -> Result<Arc<dyn Material>, String> {
+    let refraction_index = object.number("refraction_index")?;
+    if !(refraction_index > 0.0) {
+        return Err(format!("{}: `refraction_index` should be more than 0, not {}",
+                           object.required("refraction_index")?.pos, refraction_index));
+    }
+    // Clear, unless it says otherwise.
+    let absorption = object.vector_or("absorption", Float3::new())?;
+    if absorption.as_slice().iter().any(|&c| c < 0.0) {
+        return Err(format!("{}: `absorption` can't be negative, but it's ({}, {}, {})",
+                           object.required("absorption")?.pos,
+                           absorption.x, absorption.y, absorption.z));
+    }
+    Ok(Arc::new(Dielectric::new(refraction_index).with_absorption(absorption)))
+}
+
+fn diffuse_light(object: &Object) -> Result<Arc<dyn Material>, String> {
+    Ok(Arc::new(DiffuseLight { emit: object.vector("emit")? }))
+}
+
+fn isotropic(object: &Object) -> Result<Arc<dyn Material>, String> {
+    Ok(Arc::new(Isotropic { albedo: object.albedo("albedo")? }))
+}
+
+// ===== Meaning ===============================================================
+
+/// Reads the scene file at `path`, with images from `assets`, and materials
+/// from `materials`.
+pub fn load(path: &path::Path, assets: &AssetCache, materials: &MaterialRegistry)
+    -> Result<Scene, String>
+{
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read scene {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or_else(|| path::Path::new(""));
+    load_from(&text, &SCHEMA, dir, assets, materials)
+        .map_err(|msg| format!("{}:{}", path.display(), msg))
+}
+
+/// Reads a scene from the text of a scene file, with the built-in materials.
+/// Errors start with the line and column they're about.
+pub fn load_str(text: &str, schema: &Schema) -> Result<Scene, String> {
+    load_with(text, schema, &MaterialRegistry::default())
+}
+
+/// Like `load_str()`, with the materials in `materials`.
+pub fn load_with(text: &str, schema: &Schema, materials: &MaterialRegistry)
+    -> Result<Scene, String>
+{
+    load_from(text, schema, path::Path::new(""), &AssetCache::default(), materials)
+}
+
+/// Like `load_with()`, with image paths relative to `dir`.
+fn load_from(text:      &str,
+             schema:    &Schema,
+             dir:       &path::Path,
+             assets:    &AssetCache,
+             materials: &MaterialRegistry)
+    -> Result<Scene, String>
+{
+    let root = parse(text)?;
+    let version = check_version(&root, schema)?;
+    let loader = Loader {
+        schema,
+        version,
+        dir,
+        assets,
+        materials,
+    };
+
+    let scene = loader.object("the scene", &root,
+                              &["version", "background", "defines", "objects"])?;
+    let background = match scene.get("background") {
+        None => Background::Sky,
+        Some(value) => {
+            match value.kind {
+                ValueKind::Ident(ref name) if name == "Sky" => Background::Sky,
+                ValueKind::Ident(ref name) if name == "Black" => Background::Black,
+                _ => {
+                    return Err(format!("{}: background should be Sky or Black, not {}",
+                                       value.pos, value.kind.describe()));
+                },
+            }
+        },
+    };
+
+    // Each definition can use the ones before it.
+    let mut defined = vec![];
+    if let Some(defines) = scene.get("defines") {
+        let fields = match defines.kind {
+            ValueKind::Struct { name: None, ref fields } => fields,
+            ref other => {
+                return Err(format!("{}: defines should be a struct like `(name: Sphere(...))`, \
+                                    not {}",
+                                   defines.pos, other.describe()));
+            },
+        };
+        for field in fields {
+            let (hitable, is_light) = loader.hitable(&field.value, &defined)?;
+            defined.push(Definition {
+                name: field.name.clone(),
+                hitable: Arc::from(hitable),
+                is_light,
+            });
+        }
+    }
+
+    let mut world = vec![];
+    let mut lights = vec![];
+    if let Some(objects) = scene.get("objects") {
+        for value in loader.list(objects, "objects")? {
+            let (object, is_light) = loader.hitable(value, &defined)?;
+            world.push(object);
+            // Lights are also sampled directly, which needs its own copy.
+            if is_light {
+                lights.push(loader.hitable(value, &defined)?.0);
+            }
+        }
+    }
+
+    Ok(Scene {
+        world: HitableList { hitables: world },
+        lights: HitableList { hitables: lights },
+        background,
+        ..Scene::default()
+    })
+}
+
+/// The version of the file, if we can read it.
+fn check_version(root: &SceneValue, schema: &Schema) -> Result<u32, String> {
+    let fields = match root.kind {
+        ValueKind::Struct { ref fields, .. } => fields,
+        _ => {
+            return Err(format!("{}: a scene file should be a struct like `(version: {}, ...)`, \
+                                not {}",
+                               root.pos, schema.current, root.kind.describe()));
+        },
+    };
+    let field = fields.iter().find(|f| f.name == "version").ok_or_else(|| {
+        format!("{}: the scene doesn't say which version of the format it's for. \
+                 Add `version: {},` at the top, and check it against the changes listed \
+                 in scene_file.rs",
+                root.pos, schema.current)
+    })?;
+    let version = match field.value.kind {
+        ValueKind::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as u32,
+        ref other => {
+            return Err(format!("{}: version should be a whole number, not {}",
+                               field.value.pos, other.describe()));
+        },
+    };
+
+    if version > schema.current {
+        return Err(format!("{}: this scene is for version {} of the format, but we only \
+                            know up to version {}. It needs a newer build of the renderer",
+                           field.value.pos, version, schema.current));
+    }
+    if version < schema.oldest {
+        let mut msg = format!("{}: version {} scenes aren't supported anymore. \
+                               Update it to version {}",
+                              field.value.pos, version, schema.current);
+        let notes: Vec<_> = schema.notes.iter().filter(|n| n.version > version).collect();
+        if !notes.is_empty() {
+            msg += ". What changed since then:";
+            for note in notes {
+                msg += &format!("\n    {}: {}", note.object, note);
+            }
+        }
+        return Err(msg);
+    }
+    Ok(version)
+}
+
+struct Loader<'a> {
+    schema:    &'a Schema,
+    version:   u32,
+    /// Where the scene file is, which image paths are relative to.
+    dir:       &'a path::Path,
+    assets:    &'a AssetCache,
+    materials: &'a MaterialRegistry,
+}
+
+/// An object from `defines`, for `Instance`s to place.
+struct Definition {
+    name:     String,
+    hitable:  Arc<dyn Hitable>,
+    is_light: bool,
+}
+
+/// The fields of one struct, which can only be the `known` ones.
+pub struct Object<'a> {
+    kind:    &'a str,
+    pos:     Pos,
+    fields:  &'a [Field],
+    known:   &'static [&'static str],
+    loader:  &'a Loader<'a>,
+    /// What to bake textures over, for the material of an object with `bake`.
+    baking:  Option<(UvDomain, Bake)>,
+}
+
+impl<'a> Loader<'a> {
+    /// The struct in `value`, as long as it only has `known` fields.
+    fn object(&'a self, kind: &'a str, value: &'a SceneValue, known: &'static [&'static str])
+        -> Result<Object<'a>, String>
+    {
+        match value.kind {
+            ValueKind::Struct { ref fields, .. } => {
+                let object = Object {
+                    kind,
+                    pos: value.pos,
+                    fields,
+                    known,
+                    loader: self,
+                    baking: None,
+                };
+                object.check_unknown()?;
+                Ok(object)
+            },
+            ref other => Err(format!("{}: expected {}, found {}", value.pos, kind, other.describe())),
+        }
+    }
+
+    fn list(&self, value: &'a SceneValue, what: &str) -> Result<&'a [SceneValue], String> {
+        match value.kind {
+            ValueKind::List(ref values) => Ok(values),
+            ref other => {
+                Err(format!("{}: {} should be a list, not {}", value.pos, what, other.describe()))
+            },
+        }
+    }
+
+    /// The name of a struct like `Sphere(...)`, checked against `known`.
+    fn struct_name(&self, value: &'a SceneValue, what: &str, known: &[&str])
+        -> Result<&'a str, String>
+    {
+        let name = match value.kind {
+            ValueKind::Struct { name: Some(ref name), .. } => name.as_str(),
+            ValueKind::Ident(ref name) => name.as_str(),
+            ref other => {
+                return Err(format!("{}: expected {}, like {}(...), found {}",
+                                   value.pos, what, known[0], other.describe()));
+            },
+        };
+        if known.contains(&name) {
+            return Ok(name);
+        }
+        let mut msg = format!("{}: unknown {} `{}`", value.pos, what, name);
+        match closest(name, known) {
+            Some(suggestion) => msg += &format!(". Did you mean `{}`?", suggestion),
+            None => msg += &format!(". Expected one of: {}", known.join(", ")),
+        }
+        Err(msg)
+    }
+
+    /// Returns the object, and whether it gives off light. `Instance`s can
+    /// place any of `defined`.
+    fn hitable(&'a self, value: &'a SceneValue, defined: &[Definition])
+        -> Result<(Box<dyn Hitable>, bool), String>
+    {
+        const KINDS: &[&str] = &["Sphere", "MovingSphere", "KeyframedSphere", "XyRect",
+                                 "XzRect", "YzRect", "NoiseVolume", "Instance"];
+        let kind = self.struct_name(value, "object", KINDS)?;
+        if kind == "Instance" {
+            return self.instance(value, defined);
+        }
+        let known: &'static [&'static str] = match kind {
+            "Sphere" => &["center", "radius", "material", "bake"],
+            "MovingSphere" => &["center", "motion", "radius", "material", "bake"],
+            "KeyframedSphere" => &["keyframes", "radius", "material", "bake"],
+            "NoiseVolume" => &["min", "max", "density", "scale", "octaves", "material"],
+            _ => &["a0", "a1", "b0", "b1", "k", "flip", "material", "bake"],
+        };
+        let object = self.object(kind, value, known)?;
+        let bake = match kind {
+            // Volumes don't have a surface to bake over.
+            "NoiseVolume" => None,
+            // Moving spheres aren't anywhere in particular, so only still
+            // ones know the position of their (u, v).
+            "Sphere" => object.bake("bake", UvDomain::Sphere {
+                center: object.vector("center")?,
+                radius: object.number("radius")?,
+            })?,
+            _ => object.bake("bake", UvDomain::Surface)?,
+        };
+        let (material, is_light) = object.material("material", bake)?;
+
+        let hitable: Box<dyn Hitable> = match kind {
+            "Sphere" | "MovingSphere" => {
+                let sphere = Sphere {
+                    center: object.vector("center")?,
+                    radius: object.number("radius")?,
+                    material,
+                };
+                if kind == "Sphere" {
+                    Box::new(sphere)
+                } else {
+                    Box::new(MovingSphere {
+                        sphere,
+                        motion: object.vector("motion")?,
+                    })
+                }
+            },
+            "KeyframedSphere" => {
+                let keyframes = object.keyframes("keyframes")?;
+                Box::new(KeyframedSphere {
+                    sphere: Sphere {
+                        center: keyframes[0].1,
+                        radius: object.number("radius")?,
+                        material,
+                    },
+                    keyframes,
+                })
+            },
+            "NoiseVolume" => {
+                Box::new(NoiseVolume {
+                    bounds:  Aabb {
+                        min: object.vector("min")?,
+                        max: object.vector("max")?,
+                    },
+                    density: object.number("density")?,
+                    scale:   object.number("scale")?,
+                    octaves: object.number("octaves")? as u32,
+                    phase:   material,
+                })
+            },
+            _ => {
+                let a0 = object.number("a0")?;
+                let a1 = object.number("a1")?;
+                let b0 = object.number("b0")?;
+                let b1 = object.number("b1")?;
+                let k = object.number("k")?;
+                // Rects face +axis, unless they're flipped.
+                let flip = object.boolean_or("flip", false)?;
+                macro_rules! rect {
+                    ($rect:ident) => {{
+                        let rect = $rect { a0, a1, b0, b1, k, material };
+                        if flip {
+                            Box::new(FlipNormals { hitable: rect }) as Box<dyn Hitable>
+                        } else {
+                            Box::new(rect)
+                        }
+                    }}
+                }
+                match kind {
+                    "XyRect" => rect!(XyRect),
+                    "XzRect" => rect!(XzRect),
+                    _ => rect!(YzRect),
+                }
+            },
+        };
+        Ok((hitable, is_light))
+    }
+
+    /// One of `defined`, scaled, then rotated by yaw, pitch, and roll in
+    /// degrees, then moved, like `Instance(of: marble, translate: (1, 0, 0))`.
+    fn instance(&'a self, value: &'a SceneValue, defined: &[Definition])
+        -> Result<(Box<dyn Hitable>, bool), String>
+    {
+        let object = self.object("Instance", value, &["of", "scale", "rotate", "translate"])?;
+        let of = object.required("of")?;
+        let name = match of.kind {
+            ValueKind::Ident(ref name) => name.as_str(),
+            ref other => {
+                return Err(format!("{}: `of` should be the name of something in defines, \
+                                    not {}",
+                                   of.pos, other.describe()));
+            },
+        };
+        let definition = match defined.iter().find(|d| d.name == name) {
+            Some(definition) => definition,
+            None => {
+                let mut msg = format!("{}: nothing is defined as `{}`", of.pos, name);
+                let names: Vec<&str> = defined.iter().map(|d| d.name.as_str()).collect();
+                if let Some(suggestion) = closest(name, &names) {
+                    msg += &format!(". Did you mean `{}`?", suggestion);
+                }
+                return Err(msg);
+            },
+        };
+
+        let scale = match object.get("scale") {
+            Some(_) => object.number("scale")?,
+            None => 1.0,
+        };
+        if scale == 0.0 {
+            return Err(format!("{}: `scale` can't be 0", object.required("scale")?.pos));
+        }
+        let rotate = object.vector_or("rotate", Float3::new())?;
+        let instance = Instance::new(definition.hitable.clone())
+            .scaled(scale)
+            .rotated(Mat3::from_euler(rotate.x, rotate.y, rotate.z))
+            .translated(object.vector_or("translate", Float3::new())?);
+        Ok((Box::new(instance), definition.is_light))
+    }
+}
+
+impl<'a> Object<'a> {
+    /// The field called `name`, if there is one.
+    pub fn get(&self, name: &'static str) -> Option<&'a SceneValue> {
+        debug_assert!(self.known.contains(&name), "{} doesn't list `{}`", self.kind, name);
+        self.fields.iter().find(|f| f.name == name).map(|f| &f.value)
+    }
+
+    pub fn required(&self, name: &'static str) -> Result<&'a SceneValue, String> {
+        let (kind, pos) = (self.kind, self.pos);
+        self.get(name).ok_or_else(|| format!("{}: {} is missing `{}`", pos, kind, name))
+    }
+
+    pub fn number(&self, name: &'static str) -> Result<Float, String> {
+        let value = self.required(name)?;
+        as_number(value, name)
+    }
+
+    pub fn vector(&self, name: &'static str) -> Result<Float3, String> {
+        let value = self.required(name)?;
+        as_vector(value, name)
+    }
+
+    pub fn vector_or(&self, name: &'static str, default: Float3) -> Result<Float3, String> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(_) => self.vector(name),
+        }
+    }
+
+    /// A path like `[(0, (0, 1, 0)), (0.5, (1, 1, 0))]`, of centers at times.
+    /// There has to be at least one, and they have to be in order of time.
+    pub fn keyframes(&self, name: &'static str) -> Result<Vec<(Float, Float3)>, String> {
+        let value = self.required(name)?;
+        let values = self.loader.list(value, &format!("`{}`", name))?;
+        if values.is_empty() {
+            return Err(format!("{}: `{}` needs at least one keyframe", value.pos, name));
+        }
+        let mut keyframes: Vec<(Float, Float3)> = vec![];
+        for keyframe in values {
+            let (time, center) = match keyframe.kind {
+                ValueKind::Tuple(ref parts) if parts.len() == 2 => {
+                    (as_number(&parts[0], name)?, as_vector(&parts[1], name)?)
+                },
+                ref other => {
+                    return Err(format!("{}: keyframes should look like (time, (x, y, z)), \
+                                        not {}",
+                                       keyframe.pos, other.describe()));
+                },
+            };
+            if let Some(&(last, _)) = keyframes.last() {
+                if !(time >= last) {
+                    return Err(format!("{}: keyframes should be in order of time, but {} \
+                                        comes after {}",
+                                       keyframe.pos, time, last));
+                }
+            }
+            keyframes.push((time, center));
+        }
+        Ok(keyframes)
+    }
+
+    /// A color that a surface could reflect, with each part within [0, 1].
+    pub fn albedo(&self, name: &'static str) -> Result<Float3, String> {
+        let albedo = self.vector(name)?;
+        if albedo.as_slice().iter().all(|c| (0.0..=1.0).contains(c)) {
+            Ok(albedo)
+        } else {
+            Err(format!("{}: `{}` should be between 0 and 1, not ({}, {}, {})",
+                        self.required(name)?.pos, name, albedo.x, albedo.y, albedo.z))
+        }
+    }
+
+    pub fn boolean_or(&self, name: &'static str, default: bool) -> Result<bool, String> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(&SceneValue { kind: ValueKind::Bool(b), .. }) => Ok(b),
+            Some(value) => {
+                Err(format!("{}: `{}` should be true or false, not {}",
+                            value.pos, name, value.kind.describe()))
+            },
+        }
+    }
+
+    /// A texture loaded from a file, like `Image(path: "earth.png")`.
+    pub fn image(&self, name: &'static str) -> Result<Arc<ImageTexture>, String> {
+        let value = self.required(name)?;
+        let loader = self.loader;
+        loader.struct_name(value, "texture", &["Image"])?;
+        let object = loader.object("Image", value, &["path"])?;
+        let path = object.required("path")?;
+        let path = match path.kind {
+            ValueKind::Str(ref s) => loader.dir.join(s),
+            ref other => {
+                return Err(format!("{}: `path` should be a string, not {}",
+                                   path.pos, other.describe()));
+            },
+        };
+        loader.assets.image(&path).map_err(|msg| format!("{}: {}", value.pos, msg))
+    }
+
+    /// A texture, like `Image(path: "earth.png")`,
+    /// `Checker(even: (1, 1, 1), odd: (0, 0, 0), checks: 8)`, or
+    /// `Noise(color: (1, 1, 1), scale: 4, octaves: 7)`.
+    /// When the object this is on asked for it, the texture is baked.
+    pub fn texture(&self, name: &'static str) -> Result<Arc<dyn Texture>, String> {
+        let value = self.required(name)?;
+        let loader = self.loader;
+        let texture: Arc<dyn Texture> =
+            match loader.struct_name(value, "texture", &["Image", "Checker", "Noise"])? {
+                "Image" => self.image(name)?,
+                "Checker" => {
+                    let object = loader.object("Checker", value, &["even", "odd", "checks"])?;
+                    Arc::new(CheckerTexture {
+                        even:   object.vector("even")?,
+                        odd:    object.vector("odd")?,
+                        checks: object.number("checks")? as u32,
+                    })
+                },
+                _ => {
+                    let object = loader.object("Noise", value, &["color", "scale", "octaves"])?;
+                    Arc::new(NoiseTexture {
+                        color:   object.vector("color")?,
+                        scale:   object.number("scale")?,
+                        octaves: object.number("octaves")? as u32,
+                    })
+                },
+            };
+        match self.baking {
+            None => Ok(texture),
+            Some((domain, bake)) => {
+                texture::bind(texture, domain, Some(bake))
+                    .map_err(|msg| format!("{}: {}", value.pos, msg))
+            },
+        }
+    }
+
+    /// How to bake the textures of this object's material, like
+    /// `bake: (resolution: 512)`, over `domain`. Most objects don't.
+    fn bake(&self, name: &'static str, domain: UvDomain)
+        -> Result<Option<(UvDomain, Bake)>, String>
+    {
+        let value = match self.get(name) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let object = self.loader.object("bake", value, &["resolution"])?;
+        let resolution = object.number("resolution")?;
+        if !(resolution >= 1.0) || resolution.fract() != 0.0 {
+            return Err(format!("{}: `resolution` should be a whole number of texels, at least 1, \
+                                not {}",
+                               object.required("resolution")?.pos, resolution));
+        }
+        Ok(Some((domain, Bake { resolution: resolution as u32 })))
+    }
+
+    /// The material, and whether it gives off light. Its textures are baked
+    /// with `bake`, if there is one.
+    fn material(&self, name: &'static str, bake: Option<(UvDomain, Bake)>)
+        -> Result<(Arc<dyn Material>, bool), String>
+    {
+        let value = self.required(name)?;
+        let loader = self.loader;
+        let kind = loader.struct_name(value, "material", &loader.materials.names())?;
+        let registered = loader.materials.get(kind).unwrap();
+        let mut object = loader.object(kind, value, registered.fields)?;
+        object.baking = bake;
+        let material = (registered.factory)(&object)?;
+        Ok((material, kind == "DiffuseLight"))
+    }
+
+    /// Complains about the first field we don't know.
+    fn check_unknown(&self) -> Result<(), String> {
+        let unknown = match self.fields.iter().find(|f| !self.known.contains(&f.name.as_str())) {
+            Some(field) => field,
+            None => return Ok(()),
+        };
+        let name = unknown.name.as_str();
+        let schema = self.loader.schema;
+
+        // Maybe it used to be a field, and the file is from before it changed.
+        if let Some(note) = schema.notes.iter().find(|n| n.object == self.kind && n.field == name) {
+            let mut msg = format!("{}: {} has no field `{}`. {}",
+                                  unknown.pos, self.kind, name, note);
+            if self.loader.version < note.version {
+                msg += &format!(", and this file is for version {}", self.loader.version);
+            }
+            return Err(msg);
+        }
+
+        let mut msg = format!("{}: {} has no field `{}`", unknown.pos, self.kind, name);
+        match closest(name, self.known) {
+            Some(suggestion) => msg += &format!(". Did you mean `{}`?", suggestion),
+            None => msg += &format!(". Expected one of: {}", self.known.join(", ")),
+        }
+        Err(msg)
+    }
+}
+
+fn as_number(value: &SceneValue, name: &str) -> Result<Float, String> {
+    match value.kind {
+        ValueKind::Number(n) => Ok(n),
+        ref other => {
+            Err(format!("{}: `{}` should be a number, not {}", value.pos, name, other.describe()))
+        },
+    }
+}
+
+fn as_vector(value: &SceneValue, name: &str) -> Result<Float3, String> {
+    match value.kind {
+        ValueKind::Tuple(ref values) if values.len() == 3 => {
+            Ok(Float3::xyz(as_number(&values[0], name)?,
+                           as_number(&values[1], name)?,
+                           as_number(&values[2], name)?))
+        },
+        ref other => {
+            Err(format!("{}: `{}` should be a vector like (x, y, z), not {}",
+                        value.pos, name, other.describe()))
+        },
+    }
+}
+
+/// The name in `known` that `name` is most likely a typo of, if any are close.
+fn closest<'k>(name: &str, known: &[&'k str]) -> Option<&'k str> {
+    let (distance, best) = known
+        .iter()
+        .map(|k| (edit_distance(&name.to_lowercase(), &k.to_lowercase()), *k))
+        .min()?;
+    // Allow about one mistake for every three letters.
+    if distance <= (name.chars().count() / 3).max(1) {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+/// How many letters have to be inserted, removed, or changed to turn `a` into `b`.
+/// See: Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the part of `a` so far to every prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// ===== Writing ===============================================================
+
+/// One of a few materials, as plain data, so that scenes built in code can be
+/// written out as scene files, as well as rendered.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaterialDesc {
+    Lambertian { albedo: Float3 },
+    Metal { albedo: Float3, fuzz: Float },
+    Dielectric { refraction_index: Float },
+}
+
+impl MaterialDesc {
+    pub fn material(&self) -> Arc<dyn Material> {
+        match *self {
+            MaterialDesc::Lambertian { albedo } => Arc::new(Lambertian::new(albedo)),
+            MaterialDesc::Metal { albedo, fuzz } => Arc::new(Metal::new(albedo, fuzz)),
+            MaterialDesc::Dielectric { refraction_index } => {
+                Arc::new(Dielectric::new(refraction_index))
+            },
+        }
+    }
+}
+
+impl fmt::Display for MaterialDesc {
+    /// As it's written in scene files.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MaterialDesc::Lambertian { albedo } => {
+                write!(f, "Lambertian(albedo: {})", Tuple(albedo))
+            },
+            MaterialDesc::Metal { albedo, fuzz } => {
+                write!(f, "Metal(albedo: {}, fuzz: {})", Tuple(albedo), fuzz)
+            },
+            MaterialDesc::Dielectric { refraction_index } => {
+                write!(f, "Dielectric(refraction_index: {})", refraction_index)
+            },
+        }
+    }
+}
+
+/// A sphere as plain data, like `MaterialDesc`. It's a `MovingSphere` if it
+/// has a `motion`, even one of zero.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SphereDesc {
+    pub center:   Float3,
+    pub radius:   Float,
+    pub motion:   Option<Float3>,
+    pub material: MaterialDesc,
+}
+
+impl SphereDesc {
+    pub fn hitable(&self) -> Box<dyn Hitable> {
+        let sphere = Sphere {
+            center:   self.center,
+            radius:   self.radius,
+            material: self.material.material(),
+        };
+        match self.motion {
+            Some(motion) => Box::new(MovingSphere { sphere, motion }),
+            None => Box::new(sphere),
+        }
+    }
+}
+
+impl fmt::Display for SphereDesc {
+    /// As it's written in scene files.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.motion {
+            Some(motion) => write!(f, "MovingSphere(center: {}, motion: {}, ",
+                                   Tuple(self.center), Tuple(motion))?,
+            None => write!(f, "Sphere(center: {}, ", Tuple(self.center))?,
+        }
+        write!(f, "radius: {}, material: {})", self.radius, self.material)
+    }
+}
+
+/// A vector as a scene file tuple. Numbers are written with as many digits as
+/// it takes to read back exactly the same ones.
+struct Tuple(Float3);
+
+impl fmt::Display for Tuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.0.x, self.0.y, self.0.z)
+    }
+}
+
+/// The text of a scene file of `spheres`, which loads as the same scene they
+/// make. `comment` goes at the top, to say where it came from.
+pub fn write_spheres(comment: &str, background: Background, spheres: &[SphereDesc]) -> String {
+    let background = match background {
+        Background::Sky => "Sky",
+        Background::Black => "Black",
+    };
+    let mut text = String::new();
+    for line in comment.lines() {
+        text += &format!("// {}\n", line);
+    }
+    text += &format!("(\n    version: {},\n    background: {},\n    objects: [\n",
+                     SCHEMA.current, background);
+    for sphere in spheres {
+        text += &format!("        {},\n", sphere);
+    }
+    text += "    ],\n)\n";
+    text
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    /// Pretend the format has a history, so that we can check how we handle it.
+    const TEST_SCHEMA: Schema = Schema {
+        current: 3,
+        oldest:  2,
+        notes:   &[
+            MigrationNote {
+                version: 2,
+                object:  "Sphere",
+                field:   "size",
+                change:  Change::Renamed("radius"),
+            },
+            MigrationNote {
+                version: 3,
+                object:  "Metal",
+                field:   "fuzziness",
+                change:  Change::Renamed("fuzz"),
+            },
+        ],
+    };
+
+    fn error(text: &str) -> String {
+        match load_str(text, &TEST_SCHEMA) {
+            Ok(_) => panic!("This should have failed to load:\n{}", text),
+            Err(msg) => msg,
+        }
+    }
+
+    #[test]
+    fn check_load() {
+        let scene = load_str("
+            // Comments are fine
+            (
+                version: 3,
+                background: Black,
+                objects: [
+                    Sphere(center: (0, -1000, 0), radius: 1000,
+                           material: Lambertian(albedo: (0.5, 0.5, 0.5))),
+                    MovingSphere(center: (1, 2, 3), motion: (0, 0.5, 0), radius: 0.2,
+                                 material: Metal(albedo: (0.7, 0.6, 0.5), fuzz: 0.0)),
+                    KeyframedSphere(keyframes: [(0, (0, 1, 0)), (0.5, (1, 1, 0)), (1, (1, 2, 0))],
+                                    radius: 0.5, material: Lambertian(albedo: (0.2, 0.4, 0.8))),
+                    XzRect(a0: -1, a1: 1, b0: -1, b1: 1, k: 5, flip: true,
+                           material: DiffuseLight(emit: (4, 4, 4))),
+                    Sphere(center: (0, 1, 0), radius: 1,
+                           material: GgxMetal(albedo: (0.9, 0.6, 0.3), roughness: 0.25)),
+                    XzRect(a0: -5, a1: 5, b0: -5, b1: 5, k: 0,
+                           material: Lambertian(albedo: Checker(even: (1, 1, 1),
+                                                                odd: (0, 0, 0), checks: 8))),
+                    NoiseVolume(min: (-1, 0, -1), max: (1, 3, 1), density: 2, scale: 1.5,
+                                octaves: 4, material: Isotropic(albedo: (0.8, 0.8, 0.8))),
+                ],
+            )
+        ", &TEST_SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 7);
+        assert_eq!(scene.lights.hitables.len(), 1);
+        match scene.background {
+            Background::Black => {},
+            other => panic!("Wrong background: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_instances() {
+        let mut rng = seeded_rng(0);
+        let scene = load_str("(
+            version: 3,
+            defines: (
+                marble: Sphere(center: (0, 0, 0), radius: 1,
+                               material: Dielectric(refraction_index: 1.5)),
+                lamp: Sphere(center: (0, 10, 0), radius: 1,
+                             material: DiffuseLight(emit: (4, 4, 4))),
+                // Definitions can place the ones before them.
+                big_marble: Instance(of: marble, scale: 2),
+            ),
+            objects: [
+                Instance(of: marble, translate: (5, 0, 0)),
+                Instance(of: big_marble, rotate: (90, 0, 0), translate: (-5, 0, 0)),
+                Instance(of: lamp),
+            ],
+        )", &TEST_SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 3);
+        assert_eq!(scene.lights.hitables.len(), 1);
+
+        // Both marbles are where they were put, and the right size.
+        let mut t = |x: Float| {
+            let ray = Ray {
+                origin: Float3::xyz(x, 0., 10.),
+                dir:    Float3::xyz(0., 0., -1.),
+                t:      0.,
+            };
+            scene.world.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).map(|record| record.t)
+        };
+        assert!((t(5.).unwrap() - 9.).abs() < 1e-5);
+        assert!((t(-5.).unwrap() - 8.).abs() < 1e-5);
+        assert!(t(0.).is_none());
+    }
+
+    #[test]
+    fn check_edit_distance() {
+        assert_eq!(edit_distance("fuzz", "fuzz"), 0);
+        assert_eq!(edit_distance("fuz", "fuzz"), 1);
+        assert_eq!(edit_distance("raduis", "radius"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(closest("albdeo", &["albedo", "fuzz"]), Some("albedo"));
+        assert_eq!(closest("color", &["albedo", "fuzz"]), None);
+    }
+
+    #[test]
+    fn check_typos_get_suggestions() {
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), raduis: 1,
+                                     material: Dielectric(refraction_index: 1.5)),
+                          ])"),
+                   "2:57: Sphere has no field `raduis`. Did you mean `radius`?");
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1,
+                                     material: Lambertain(albedo: (1, 1, 1))),
+                          ])"),
+                   "3:48: unknown material `Lambertain`. Did you mean `Lambertian`?");
+        assert_eq!(error("(version: 3, defines: (marble: Sphere(center: (0, 0, 0), radius: 1,
+                                   material: Dielectric(refraction_index: 1.5))),
+                          objects: [Instance(of: marbel)])"),
+                   "3:50: nothing is defined as `marbel`. Did you mean `marble`?");
+        assert_eq!(error("(version: 3, backgorund: Sky)"),
+                   "1:14: the scene has no field `backgorund`. Did you mean `background`?");
+        // Nothing close, so we list what it could be.
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1, color: (1, 0, 0),
+                                     material: Dielectric(refraction_index: 1.5)),
+                          ])"),
+                   "2:68: Sphere has no field `color`. \
+                    Expected one of: center, radius, material, bake");
+    }
+
+    #[test]
+    fn check_versions() {
+        assert_eq!(error("(objects: [])"),
+                   "1:1: the scene doesn't say which version of the format it's for. \
+                    Add `version: 3,` at the top, and check it against the changes listed \
+                    in scene_file.rs");
+        assert_eq!(error("(version: 4)"),
+                   "1:11: this scene is for version 4 of the format, but we only know up to \
+                    version 3. It needs a newer build of the renderer");
+        assert_eq!(error("(version: 1)"),
+                   "1:11: version 1 scenes aren't supported anymore. Update it to version 3. \
+                    What changed since then:\
+                    \n    Sphere: `size` was renamed to `radius` in version 2\
+                    \n    Metal: `fuzziness` was renamed to `fuzz` in version 3");
+        assert_eq!(error("(version: \"two\")"), "1:11: version should be a whole number, not a string");
+    }
+
+    #[test]
+    fn check_old_fields_get_migration_notes() {
+        // Version 2 is still supported, but Metal changed after it.
+        assert_eq!(error("(version: 2, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1,
+                                     material: Metal(albedo: (1, 1, 1), fuzziness: 0.5)),
+                          ])"),
+                   "3:73: Metal has no field `fuzziness`. \
+                    `fuzziness` was renamed to `fuzz` in version 3, and this file is for version 2");
+    }
+
+    #[test]
+    fn check_material_values() {
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1,
+                                     material: Lambertian(albedo: (0.5, -0.1, 0.5))),
+                          ])"),
+                   "3:67: `albedo` should be between 0 and 1, not (0.5, -0.1, 0.5)");
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1,
+                                     material: Dielectric(refraction_index: 0)),
+                          ])"),
+                   "3:77: `refraction_index` should be more than 0, not 0");
+        assert_eq!(error("(version: 3, objects: [
+                              Sphere(center: (0, 0, 0), radius: 1,
+                                     material: Dielectric(refraction_index: 1.5,
+                                                          absorption: (0, -1, 0))),
+                          ])"),
+                   "4:71: `absorption` can't be negative, but it's (0, -1, 0)");
+    }
+
+    #[test]
+    fn check_keyframes() {
+        assert_eq!(error("(version: 3, objects: [
+                              KeyframedSphere(keyframes: [], radius: 1,
+                                              material: Dielectric(refraction_index: 1.5)),
+                          ])"),
+                   "2:58: `keyframes` needs at least one keyframe");
+        assert_eq!(error("(version: 3, objects: [
+                              KeyframedSphere(keyframes: [(0, (0, 0, 0)), (1, 0, 0)],
+                                              radius: 1,
+                                              material: Dielectric(refraction_index: 1.5)),
+                          ])"),
+                   "2:75: keyframes should look like (time, (x, y, z)), not a tuple");
+        assert_eq!(error("(version: 3, objects: [
+                              KeyframedSphere(keyframes: [(1, (0, 0, 0)), (0, (1, 0, 0))],
+                                              radius: 1,
+                                              material: Dielectric(refraction_index: 1.5)),
+                          ])"),
+                   "2:75: keyframes should be in order of time, but 0 comes after 1");
+    }
+
+    #[test]
+    fn check_baking() {
+        let scene = |object: &str| {
+            format!("(version: 3, objects: [{}])", object)
+        };
+        let checker = "Lambertian(albedo: Checker(even: (1, 1, 1), odd: (0, 0, 0), checks: 4))";
+        let sphere = |bake: &str| {
+            let text = scene(&format!("Sphere(center: (0, 0, 0), radius: 1, material: {}{})",
+                                      checker, bake));
+            let scene = load_str(&text, &TEST_SCHEMA).unwrap();
+            format!("{:?}", scene.world.hitables[0])
+        };
+        assert!(sphere("").contains("CheckerTexture"));
+        let baked = sphere(", bake: (resolution: 8)");
+        assert!(baked.contains("ImageTexture { width: 8, height: 8"), "{}", baked);
+        assert!(!baked.contains("CheckerTexture"), "{}", baked);
+
+        // Noise depends on position, which only spheres have for every (u, v).
+        let noise = "Lambertian(albedo: Noise(color: (1, 1, 1), scale: 4, octaves: 7))";
+        let text = scene(&format!("Sphere(center: (0, 0, 0), radius: 1, material: {}, \
+                                   bake: (resolution: 8))",
+                                  noise));
+        assert!(load_str(&text, &TEST_SCHEMA).is_ok());
+        let text = scene(&format!("XzRect(a0: -1, a1: 1, b0: -1, b1: 1, k: 0, material: {}, \
+                                   bake: (resolution: 8))",
+                                  noise));
+        assert!(error(&text).contains("can only be baked onto a sphere"), "{}", error(&text));
+
+        let text = scene(&format!("Sphere(center: (0, 0, 0), radius: 1, material: {}, \
+                                   bake: (resolution: 0.5))",
+                                  checker));
+        assert!(error(&text).contains("`resolution` should be a whole number"), "{}", error(&text));
+        let text = scene("NoiseVolume(min: (-1, 0, -1), max: (1, 3, 1), density: 2, scale: 1.5, \
+                          octaves: 4, material: Isotropic(albedo: (0.8, 0.8, 0.8)), \
+                          bake: (resolution: 8))");
+        assert!(error(&text).contains("has no field `bake`"), "{}", error(&text));
+    }
+
+    #[test]
+    fn check_syntax_errors() {
+        assert_eq!(parse("(version: 1,, )").unwrap_err(), "1:13: expected a field name, found `,`");
+        assert_eq!(parse("[1, 2").unwrap_err(), "1:6: expected `]`, found the end of the file");
+        assert_eq!(parse("(a: 1, a: 2)").unwrap_err(), "1:8: `a` is given more than once");
+        assert_eq!(parse("(a: 1.2.3)").unwrap_err(), "1:5: \"1.2.3\" is not a number");
+        assert_eq!(parse("(a: 1) x").unwrap_err(), "1:8: expected the end of the file, found `x`");
+    }
+
+    #[test]
+    fn check_example_scene() {
+        let scene = load_str(include_str!("../scenes/three-spheres.ron"), &SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 4);
+        assert!(scene.lights.hitables.is_empty());
+
+        let scene = load_str(include_str!("../scenes/roughness-ramp.ron"), &SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 11);
+
+        let scene = load_str(include_str!("../scenes/smoke-plume.ron"), &SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 4);
+
+        let scene = load_str(include_str!("../scenes/clay.ron"), &SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 5);
+
+        let scene = load_str(include_str!("../scenes/red-glass.ron"), &SCHEMA).unwrap();
+        assert_eq!(scene.world.hitables.len(), 3);
+        assert_eq!(scene.lights.hitables.len(), 1);
+    }
+
+    #[test]
+    fn check_real_schema() {
+        assert!(SCHEMA.oldest <= SCHEMA.current);
+        for note in SCHEMA.notes {
+            assert!(note.version <= SCHEMA.current, "{:?}", note);
+        }
+        assert!(load_str(&format!("(version: {})", SCHEMA.current), &SCHEMA).is_ok());
+    }
+
+    #[test]
+    fn check_shared_images_decode_once() {
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-scene-images-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 255]))
+            .save(dir.join("blue.png"))
+            .unwrap();
+        let text = "(
+            version: 3,
+            objects: [
+                Sphere(center: (0, 0, 0), radius: 1,
+                       material: Lambertian(albedo: Image(path: \"blue.png\"))),
+                Sphere(center: (2, 0, 0), radius: 1,
+                       material: Lambertian(albedo: Image(path: \"blue.png\"))),
+            ],
+        )";
+
+        let (assets, materials) = (AssetCache::default(), MaterialRegistry::default());
+        let scene = load_from(text, &TEST_SCHEMA, &dir, &assets, &materials).unwrap();
+        assert_eq!(scene.world.hitables.len(), 2);
+        // Loading the scene again doesn't decode anything either.
+        load_from(text, &TEST_SCHEMA, &dir, &assets, &materials).unwrap();
+        let stats = assets.stats();
+        assert_eq!((stats.decodes, stats.hits), (1, 3));
+
+        let missing = text.replace("blue.png", "red.png");
+        let msg = load_from(&missing, &TEST_SCHEMA, &dir, &assets, &materials).err().unwrap();
+        assert!(msg.starts_with("5:") && msg.contains("Unable to read"), "{}", msg);
+        let typo = text.replace("Image(", "Imag(");
+        let msg = load_from(&typo, &TEST_SCHEMA, &dir, &assets, &materials).err().unwrap();
+        assert!(msg.contains("Did you mean `Image`?"), "{}", msg);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
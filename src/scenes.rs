@@ -0,0 +1,1191 @@
+//! Scenes that are built into the renderer, rather than loaded from a file.
+
+use std::{
+    collections::{
+        hash_map,
+        HashMap,
+    },
+    hash::{
+        self,
+        Hasher,
+    },
+    str::FromStr,
+    sync::Arc,
+};
+
+use rand::prelude::*;
+
+use crate::bvh::Bvh;
+use crate::hitable::{
+    FlipNormals,
+    Hitable,
+    HitableList,
+    MovingSphere,
+    Sphere,
+};
+use crate::material::{
+    Dielectric,
+    DiffuseLight,
+    Isotropic,
+    Lambertian,
+    Metal,
+    NormalMapped,
+};
+use crate::prelude::*;
+use crate::rect::{
+    Cuboid,
+    XyRect,
+    XzRect,
+    YzRect,
+};
+use crate::render::{
+    Background,
+    Scene,
+};
+use crate::scene_file::{
+    MaterialDesc,
+    SphereDesc,
+};
+use crate::sphere_set::SphereSet;
+use crate::texture::{
+    CheckerTexture,
+    NoiseTexture,
+};
+use crate::transform::{
+    Mat3,
+    Transform,
+};
+use crate::volume::ConstantMedium;
+
+/// The scenes we can pick from the command line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneKind {
+    Cover,
+    Green,
+    Cornell,
+    Final,
+    Random,
+    Bumps,
+}
+
+impl SceneKind {
+    pub const ALL: &'static [SceneKind] = &[
+        SceneKind::Cover,
+        SceneKind::Green,
+        SceneKind::Cornell,
+        SceneKind::Final,
+        SceneKind::Random,
+        SceneKind::Bumps,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SceneKind::Cover   => "cover",
+            SceneKind::Green   => "green",
+            SceneKind::Cornell => "cornell",
+            SceneKind::Final   => "final",
+            SceneKind::Random  => "random",
+            SceneKind::Bumps   => "bumps",
+        }
+    }
+
+    /// A line about what's in the scene, for --list-scenes.
+    pub fn description(self) -> &'static str {
+        match self {
+            SceneKind::Cover   => "The first book's cover: little spheres around three big ones",
+            SceneKind::Green   => "A few spheres of different materials, on a big yellow one",
+            SceneKind::Cornell => "The Cornell box, lit by a light in its ceiling",
+            SceneKind::Final   => "The end of The Next Week: boxes, smoke, glass, metal, a light",
+            SceneKind::Random  => "--objects little spheres over --extent, as a stress test",
+            SceneKind::Bumps   => "A flat panel, made bumpy by a checkered normal map",
+        }
+    }
+
+    /// Builds the scene, with its own background. Only the cover and random
+    /// scenes use `seed` and `cover`, and only the random one `random`.
+    pub fn make(self,
+                seed:   &SceneSeed,
+                cover:  &CoverSceneParams,
+                random: &RandomSceneParams)
+        -> Scene
+    {
+        match self {
+            SceneKind::Cover => Scene {
+                world:      make_cover_scene(seed, cover),
+                background: Background::Sky,
+                ..Scene::default()
+            },
+            SceneKind::Random => Scene {
+                world:      make_random_scene(seed, cover, random),
+                background: Background::Sky,
+                ..Scene::default()
+            },
+            SceneKind::Green => Scene {
+                world:      make_green_scene(),
+                background: Background::Sky,
+                ..Scene::default()
+            },
+            SceneKind::Bumps => Scene {
+                world:      make_bumps_scene(),
+                background: Background::Sky,
+                ..Scene::default()
+            },
+            SceneKind::Cornell => make_cornell_box(),
+            SceneKind::Final => make_final_scene(),
+        }
+    }
+
+    /// The scene as plain data, for the scenes that are only spheres.
+    pub fn spheres(self,
+                   seed:   &SceneSeed,
+                   cover:  &CoverSceneParams,
+                   random: &RandomSceneParams)
+        -> Option<Vec<SphereDesc>>
+    {
+        match self {
+            SceneKind::Cover => Some(cover_scene_spheres(seed, cover)),
+            SceneKind::Random => Some(random_scene_spheres(seed, cover, random)),
+            _ => None,
+        }
+    }
+
+    /// Where the camera goes to see the scene, unless it's told otherwise.
+    pub fn view(self) -> SceneView {
+        match self {
+            SceneKind::Cover => SceneView {
+                lookfrom:   Float3::xyz(13., 2., 3.),
+                lookat:     Float3::xyz(0., 0., 0.),
+                vfov:       20.,
+                aperature:  0.1,
+                focus_dist: 10.,
+            },
+            SceneKind::Green => SceneView {
+                lookfrom:   Float3::xyz(0., 0., 0.),
+                lookat:     Float3::xyz(0., 0., -1.),
+                vfov:       90.,
+                aperature:  0.,
+                focus_dist: 1.,
+            },
+            SceneKind::Cornell => SceneView {
+                lookfrom:   Float3::xyz(278., 278., -800.),
+                lookat:     Float3::xyz(278., 278., 0.),
+                vfov:       40.,
+                aperature:  0.,
+                focus_dist: 10.,
+            },
+            SceneKind::Final => SceneView {
+                lookfrom:   Float3::xyz(478., 278., -600.),
+                lookat:     Float3::xyz(278., 278., 0.),
+                vfov:       40.,
+                aperature:  0.,
+                focus_dist: 10.,
+            },
+            // Straight on, with the panel filling most of the view.
+            SceneKind::Bumps => SceneView {
+                lookfrom:   Float3::xyz(0., 0., 4.),
+                lookat:     Float3::xyz(0., 0., 0.),
+                vfov:       35.,
+                aperature:  0.,
+                focus_dist: 4.,
+            },
+            // From higher up than the cover, to see more of the spheres.
+            SceneKind::Random => SceneView {
+                lookfrom:   Float3::xyz(26., 10., 6.),
+                lookat:     Float3::xyz(0., 0., 0.),
+                vfov:       40.,
+                aperature:  0.,
+                focus_dist: 10.,
+            },
+        }
+    }
+}
+
+impl FromStr for SceneKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SceneKind, String> {
+        SceneKind::ALL
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = SceneKind::ALL.iter().map(|k| k.name()).collect();
+                format!("Unknown scene \"{}\". Expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// A scene's own camera. See `SceneKind::view()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SceneView {
+    pub lookfrom:   Float3,
+    pub lookat:     Float3,
+    pub vfov:       Float,
+    pub aperature:  Float,
+    pub focus_dist: Float,
+}
+
+fn hash_it(thing: &impl hash::Hash) -> u64 {
+    let mut hasher = hash_map::DefaultHasher::new();
+    thing.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What the cover scene's little spheres are drawn from, for --scene-seed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SceneSeed {
+    bytes: [u8; 16],
+}
+
+impl SceneSeed {
+    /// A number, or any text. The same one always makes the same scene.
+    pub fn new(seed: &str) -> SceneSeed {
+        match seed.parse::<u64>() {
+            Ok(n) => SceneSeed::from_halves(hash_it(&n), hash_it(&!n)),
+            Err(_) => SceneSeed::from_halves(hash_it(&seed), hash_it(&(seed, 1))),
+        }
+    }
+
+    fn from_halves(a: u64, b: u64) -> SceneSeed {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&a.to_le_bytes());
+        bytes[8..].copy_from_slice(&b.to_le_bytes());
+        SceneSeed {
+            bytes,
+        }
+    }
+}
+
+/// The scene everyone has always gotten.
+impl Default for SceneSeed {
+    fn default() -> SceneSeed {
+        SceneSeed::from_halves(hash_it(b"Katy's Penguin"), hash_it(b"Alyssa's Panda"))
+    }
+}
+
+/// How the cover scene's little spheres are laid out, for --scene-param.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CoverSceneParams {
+    /// The spheres are on a grid from -this to this, in x and z, with one
+    /// sphere to a square (unless it'd be in the way of a big sphere).
+    pub grid_half_extent: i32,
+    pub sphere_radius:    Float,
+    /// The chance of each sphere being diffuse, or metal. The rest are glass.
+    pub diffuse_prob:     Float,
+    pub metal_prob:       Float,
+}
+
+impl CoverSceneParams {
+    pub const NAMES: &'static [&'static str] = &[
+        "grid_half_extent",
+        "sphere_radius",
+        "diffuse_prob",
+        "metal_prob",
+    ];
+
+    /// Sets one of the params from "name=value". Setting one can leave them
+    /// not making sense until another is set too, so `check()` them after.
+    pub fn set(&mut self, param: &str) -> Result<(), String> {
+        let mut parts = param.splitn(2, '=');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => {
+                return Err(format!("Expected a scene param like name=value, found \"{}\"",
+                                   param));
+            },
+        };
+        let bad_value = || format!("Unable to parse \"{}\" as a value for {}", value, name);
+        match name {
+            "grid_half_extent" => self.grid_half_extent = value.parse().map_err(|_| bad_value())?,
+            "sphere_radius" => self.sphere_radius = value.parse().map_err(|_| bad_value())?,
+            "diffuse_prob" => self.diffuse_prob = value.parse().map_err(|_| bad_value())?,
+            "metal_prob" => self.metal_prob = value.parse().map_err(|_| bad_value())?,
+            _ => {
+                return Err(format!("Unknown scene param \"{}\". Expected one of: {}",
+                                   name, CoverSceneParams::NAMES.join(", ")));
+            },
+        }
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.grid_half_extent < 0 {
+            return Err(format!("grid_half_extent can't be negative, found {}",
+                               self.grid_half_extent));
+        }
+        if !(0.0 < self.sphere_radius && self.sphere_radius < 1.0) {
+            return Err(format!("sphere_radius must be between 0 and 1, found {}",
+                               self.sphere_radius));
+        }
+        for &(name, prob) in [("diffuse_prob", self.diffuse_prob),
+                              ("metal_prob", self.metal_prob)].iter()
+        {
+            if !(0.0..=1.0).contains(&prob) {
+                return Err(format!("{} must be between 0 and 1, found {}", name, prob));
+            }
+        }
+        if self.diffuse_prob + self.metal_prob > 1.0 {
+            return Err(format!("diffuse_prob and metal_prob can't add up to more than 1, \
+                                found {} + {}", self.diffuse_prob, self.metal_prob));
+        }
+        Ok(())
+    }
+}
+
+/// The cover of the book.
+impl Default for CoverSceneParams {
+    fn default() -> CoverSceneParams {
+        CoverSceneParams {
+            grid_half_extent: 10,
+            sphere_radius:    0.2,
+            diffuse_prob:     0.7,
+            metal_prob:       0.2,
+        }
+    }
+}
+
+/// A few spheres of different materials, on a big yellow one.
+/// Meant to be seen from the origin, looking toward -Z.
+pub fn make_green_scene() -> HitableList {
+    HitableList {
+        hitables: vec![
+            Box::new(Sphere {
+                center: Float3::xyz(0., 0., -1.),
+                radius: 0.5,
+                material: Arc::new(Lambertian::new(Float3::xyz(0.1, 0.2, 0.5))),
+            }),
+            Box::new(Sphere {
+                center: Float3::xyz(0.0, -100.5, -1.0),
+                radius: 100.0,
+                material: Arc::new(Lambertian::new(Float3::xyz(0.8, 0.8, 0.0))),
+            }),
+            Box::new(Sphere {
+                center: Float3::xyz(1., 0., -1.),
+                radius: 0.5,
+                material: Arc::new(Metal::new(Float3::xyz(0.8, 0.6, 0.2), 0.0)),
+            }),
+            Box::new(Sphere {
+                center: Float3::xyz(-1., 0., -1.),
+                radius: 0.5,
+                material: Arc::new(Dielectric::new(1.5)),
+            }),
+            Box::new(Sphere {
+                center: Float3::xyz(-1., 0., -1.),
+                radius: -0.45, // Negative radius makes the above sphere hollow.
+                material: Arc::new(Dielectric::new(1.5)),
+            }),
+        ],
+    }
+}
+
+/// A flat panel facing +Z, whose normal map tips its checks up and down by
+/// turns. Under the sky, checks tipped up see more blue, and the rest more
+/// white, though the panel itself is flat.
+pub fn make_bumps_scene() -> HitableList {
+    let bumps = CheckerTexture {
+        even:   Float3::xyz(0.5, 0.85, 0.6),
+        odd:    Float3::xyz(0.5, 0.15, 0.6),
+        checks: 8,
+    };
+    HitableList {
+        hitables: vec![
+            Box::new(XyRect {
+                a0: -1.,
+                a1: 1.,
+                b0: -1.,
+                b1: 1.,
+                k: 0.,
+                material: Arc::new(NormalMapped {
+                    material:   Lambertian::new(Float3::xxx(0.7)),
+                    normal_map: Arc::new(bumps),
+                }),
+            }),
+        ],
+    }
+}
+
+/// A Cornell box style room, lit by a single small light in the ceiling.
+/// The front wall is missing, so we can look in from -Z.
+/// The walls are 555 units on a side.
+pub fn make_small_light_box() -> Scene {
+    let red = Arc::new(Lambertian::new(Float3::xyz(0.65, 0.05, 0.05)));
+    let white = Arc::new(Lambertian::new(Float3::xxx(0.73)));
+    let green = Arc::new(Lambertian::new(Float3::xyz(0.12, 0.45, 0.15)));
+    let light = Arc::new(DiffuseLight {
+        emit: Float3::xxx(100.),
+    });
+
+    // The light is a small square in the middle of the ceiling, facing down.
+    let light_rect = XzRect {
+        a0: 263., a1: 293.,
+        b0: 264., b1: 294.,
+        k:  554.,
+        material: light,
+    };
+
+    let world = HitableList {
+        hitables: vec![
+            // Green wall on the right
+            Box::new(FlipNormals {
+                hitable: YzRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: green,
+                },
+            }),
+            // Red wall on the left
+            Box::new(YzRect {
+                a0: 0., a1: 555.,
+                b0: 0., b1: 555.,
+                k:  0.,
+                material: red,
+            }),
+            Box::new(FlipNormals {
+                hitable: light_rect.clone(),
+            }),
+            // Ceiling
+            Box::new(FlipNormals {
+                hitable: XzRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: white.clone(),
+                },
+            }),
+            // Floor
+            Box::new(XzRect {
+                a0: 0., a1: 555.,
+                b0: 0., b1: 555.,
+                k:  0.,
+                material: white.clone(),
+            }),
+            // Back wall
+            Box::new(FlipNormals {
+                hitable: XyRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: white.clone(),
+                },
+            }),
+            // Something to cast a shadow
+            Box::new(Sphere {
+                center:   Float3::xyz(190., 90., 190.),
+                radius:   90.,
+                material: white.clone(),
+            }),
+        ],
+    };
+
+    Scene {
+        world,
+        lights: HitableList {
+            hitables: vec![Box::new(light_rect)],
+        },
+        background: Background::Black,
+        ..Scene::default()
+    }
+}
+
+/// The Cornell box: a room with a red wall on the left, a green one on the
+/// right, and white everywhere else, lit by a light in the ceiling, with two
+/// boxes turned a little on the floor. The front wall is missing, so we can
+/// look in from -Z. The walls are 555 units on a side.
+pub fn make_cornell_box() -> Scene {
+    let red = Arc::new(Lambertian::new(Float3::xyz(0.65, 0.05, 0.05)));
+    let white = Arc::new(Lambertian::new(Float3::xxx(0.73)));
+    let green = Arc::new(Lambertian::new(Float3::xyz(0.12, 0.45, 0.15)));
+    let light = Arc::new(DiffuseLight {
+        emit: Float3::xxx(15.),
+    });
+
+    let light_rect = XzRect {
+        a0: 213., a1: 343.,
+        b0: 227., b1: 332.,
+        k:  554.,
+        material: light,
+    };
+
+    // A box from the origin to `size`, turned `degrees` around its corner
+    // and moved to `offset`.
+    let turned_box = |size: Float3, degrees: Float, offset: Float3| {
+        Transform::new(Cuboid {
+            min:      Float3::new(),
+            max:      size,
+            material: white.clone(),
+        })
+        .rotated(Mat3::from_axis_angle(Float3::xyz(0., 1., 0.), degrees))
+        .translated(offset)
+    };
+
+    let world = HitableList {
+        hitables: vec![
+            // Green wall on the right
+            Box::new(FlipNormals {
+                hitable: YzRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: green,
+                },
+            }),
+            // Red wall on the left
+            Box::new(YzRect {
+                a0: 0., a1: 555.,
+                b0: 0., b1: 555.,
+                k:  0.,
+                material: red,
+            }),
+            Box::new(FlipNormals {
+                hitable: light_rect.clone(),
+            }),
+            // Ceiling
+            Box::new(FlipNormals {
+                hitable: XzRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: white.clone(),
+                },
+            }),
+            // Floor
+            Box::new(XzRect {
+                a0: 0., a1: 555.,
+                b0: 0., b1: 555.,
+                k:  0.,
+                material: white.clone(),
+            }),
+            // Back wall
+            Box::new(FlipNormals {
+                hitable: XyRect {
+                    a0: 0., a1: 555.,
+                    b0: 0., b1: 555.,
+                    k:  555.,
+                    material: white.clone(),
+                },
+            }),
+            // The tall box at the back, and the short one at the front.
+            Box::new(turned_box(Float3::xyz(165., 330., 165.), 15., Float3::xyz(265., 0., 295.))),
+            Box::new(turned_box(Float3::xxx(165.), -18., Float3::xyz(130., 0., 65.))),
+        ],
+    };
+
+    Scene {
+        world,
+        lights: HitableList {
+            hitables: vec![Box::new(light_rect)],
+        },
+        background: Background::Black,
+        ..Scene::default()
+    }
+}
+
+/// The last scene of the second book, The Next Week: a floor of boxes of
+/// random heights, lit by a light in the ceiling, with a sphere moving across
+/// the shutter, spheres of glass and metal, a glass ball full of blue smoke,
+/// two textured spheres, and a rotated cluster of little white spheres, all in
+/// a thin fog. It's meant to be seen from -Z.
+pub fn make_final_scene() -> Scene {
+    let mut rng = SmallRng::from_seed(SceneSeed::new("final").bytes);
+
+    // Boxes 100 units on a side, 20 by 20 of them.
+    let ground = Arc::new(Lambertian::new(Float3::xyz(0.48, 0.83, 0.53)));
+    let mut boxes: Vec<Box<dyn Hitable>> = vec![];
+    for i in 0..20 {
+        for j in 0..20 {
+            let min = Float3::xyz(-1000. + 100. * i as Float, 0., -1000. + 100. * j as Float);
+            let height = 1. + 100. * rng.gen::<Float>();
+            boxes.push(Box::new(Cuboid {
+                min,
+                max:      min + Float3::xyz(100., height, 100.),
+                material: ground.clone(),
+            }));
+        }
+    }
+
+    let light_rect = XzRect {
+        a0: 123., a1: 423.,
+        b0: 147., b1: 412.,
+        k:  554.,
+        material: Arc::new(DiffuseLight {
+            emit: Float3::xxx(7.),
+        }),
+    };
+
+    let glass = Arc::new(Dielectric::new(1.5));
+
+    // A cube of little spheres, turned a bit and moved up to the top left.
+    let white = Arc::new(Lambertian::new(Float3::xxx(0.73)));
+    let spheres: Vec<Box<dyn Hitable>> = (0..1000)
+        .map(|_| -> Box<dyn Hitable> {
+            Box::new(Sphere {
+                center: 165. * Float3::xyz(rng.gen(), rng.gen(), rng.gen()),
+                radius: 10.,
+                material: white.clone(),
+            })
+        })
+        .collect();
+    let cluster = Transform::new(Bvh::new(spheres, 0., 1.))
+        .rotated(Mat3::from_axis_angle(Float3::xyz(0., 1., 0.), 15.))
+        .translated(Float3::xyz(-100., 270., 395.));
+
+    let world = HitableList {
+        hitables: vec![
+            Box::new(Bvh::new(boxes, 0., 1.)),
+            Box::new(FlipNormals {
+                hitable: light_rect.clone(),
+            }),
+            Box::new(MovingSphere {
+                sphere: Sphere {
+                    center:   Float3::xyz(400., 400., 200.),
+                    radius:   50.,
+                    material: Arc::new(Lambertian::new(Float3::xyz(0.7, 0.3, 0.1))),
+                },
+                motion: Float3::xyz(30., 0., 0.),
+            }),
+            Box::new(Sphere {
+                center:   Float3::xyz(260., 150., 45.),
+                radius:   50.,
+                material: glass.clone(),
+            }),
+            Box::new(Sphere {
+                center:   Float3::xyz(0., 150., 145.),
+                radius:   50.,
+                material: Arc::new(Metal::new(Float3::xyz(0.8, 0.8, 0.9), 1.0)),
+            }),
+            // Glass, and smoke just inside of it.
+            Box::new(Sphere {
+                center:   Float3::xyz(360., 150., 145.),
+                radius:   70.,
+                material: glass.clone(),
+            }),
+            Box::new(ConstantMedium {
+                boundary: Sphere {
+                    center:   Float3::xyz(360., 150., 145.),
+                    radius:   70.,
+                    material: glass.clone(),
+                },
+                density:  0.2,
+                phase:    Arc::new(Isotropic {
+                    albedo: Float3::xyz(0.2, 0.4, 0.9),
+                }),
+            }),
+            // The book has a picture of the Earth here, which we don't have.
+            Box::new(Sphere {
+                center:   Float3::xyz(400., 200., 400.),
+                radius:   100.,
+                material: Arc::new(Lambertian {
+                    albedo: CheckerTexture {
+                        even:   Float3::xyz(0.1, 0.2, 0.5),
+                        odd:    Float3::xyz(0.9, 0.9, 0.8),
+                        checks: 16,
+                    },
+                }),
+            }),
+            Box::new(Sphere {
+                center:   Float3::xyz(220., 280., 300.),
+                radius:   80.,
+                material: Arc::new(Lambertian {
+                    albedo: NoiseTexture {
+                        color:   Float3::xxx(1.),
+                        scale:   0.1,
+                        octaves: 7,
+                    },
+                }),
+            }),
+            Box::new(cluster),
+            // Fog over everything.
+            Box::new(ConstantMedium {
+                boundary: Sphere {
+                    center:   Float3::new(),
+                    radius:   5000.,
+                    material: glass,
+                },
+                density:  0.0001,
+                phase:    Arc::new(Isotropic {
+                    albedo: Float3::xxx(1.),
+                }),
+            }),
+        ],
+    };
+
+    Scene {
+        world,
+        lights: HitableList {
+            hitables: vec![Box::new(light_rect)],
+        },
+        background: Background::Black,
+        ..Scene::default()
+    }
+}
+
+/// One of the cover scene's little spheres at `center`, of a material drawn
+/// from `rng` with the odds in `params`.
+fn little_sphere(rng: &mut SmallRng, center: Float3, params: &CoverSceneParams) -> SphereDesc {
+    let (material, motion) = match rng.gen::<Float>() {
+        // Diffuse
+        prob if prob < params.diffuse_prob => {
+            let albedo = Float3 {
+                x: rng.gen::<Float>() * rng.gen::<Float>(),
+                y: rng.gen::<Float>() * rng.gen::<Float>(),
+                z: rng.gen::<Float>() * rng.gen::<Float>(),
+            };
+            // Only Lambertian spheres bounce
+            let motion = Float3 {
+                x: 0.0,
+                y: 0.5 * rng.gen::<Float>(),
+                z: 0.0,
+            };
+            (MaterialDesc::Lambertian { albedo }, motion)
+        }
+        // Metal
+        prob if prob < params.diffuse_prob + params.metal_prob => {
+            // The albedo is drawn before the fuzz.
+            let albedo = Float3 {
+                x: rng.gen::<Float>(),
+                y: rng.gen::<Float>(),
+                z: rng.gen::<Float>(),
+            };
+            let fuzz = 0.5 * rng.gen::<Float>();
+            // Stationary
+            (MaterialDesc::Metal { albedo, fuzz }, Float3::new())
+        }
+        // Glass
+        _ => {
+            // Stationary - the glass would break!
+            (MaterialDesc::Dielectric { refraction_index: 1.5 }, Float3::new())
+        }
+    };
+    SphereDesc {
+        center,
+        radius: params.sphere_radius,
+        motion: Some(motion),
+        material,
+    }
+}
+
+/// The scene from the cover of the book: a field of small random spheres
+/// around three big ones. It's the same every time for the same `seed` and
+/// `params`.
+pub fn make_cover_scene(seed: &SceneSeed, params: &CoverSceneParams) -> HitableList {
+    hitables(&cover_scene_spheres(seed, params))
+}
+
+/// The spheres of `make_cover_scene()`, as plain data.
+pub fn cover_scene_spheres(seed: &SceneSeed, params: &CoverSceneParams) -> Vec<SphereDesc> {
+    let mut rng = SmallRng::from_seed(seed.bytes);
+
+    // Our accelaration structure is a list of spheres.
+    let mut spheres = vec![];
+
+    // A giant, darkish colored sphere to act as the floor.
+    spheres.push(SphereDesc {
+        center:   Float3::xyz(0., -1000., 0.),
+        radius:   1000.0,
+        motion:   None,
+        material: MaterialDesc::Lambertian { albedo: Float3::xxx(0.5) },
+    });
+
+    let radius = params.sphere_radius;
+    let point = Float3::xyz(4.0, radius, 0.0);
+    const GRID: Float = 1.0;
+
+    // Many, many little spheres.
+    let extent = params.grid_half_extent;
+    for a in -extent..extent {
+        let a = a as Float;
+        for b in -extent..extent {
+            let b = b as Float;
+
+            // These are positive random floats to avoid collisions.
+            let center = Float3 {
+                x: a + (GRID - radius) * rng.gen::<Float>(),
+                y: radius,
+                z: b + (GRID - radius) * rng.gen::<Float>(),
+            };
+
+            if (center - point).length_sq() > (0.9*0.9) {
+                spheres.push(little_sphere(&mut rng, center, params));
+            }
+        }
+    }
+
+    // Three big spheres
+    spheres.push(SphereDesc {
+        center:   Float3::xyz(0., 1., 0.),
+        radius:   1.,
+        motion:   None,
+        material: MaterialDesc::Dielectric { refraction_index: 1.5 },
+    });
+
+    spheres.push(SphereDesc {
+        center:   Float3::xyz(-4., 1., 0.),
+        radius:   1.,
+        motion:   None,
+        material: MaterialDesc::Lambertian { albedo: Float3::xyz(0.4, 0.2, 0.1) },
+    });
+
+    spheres.push(SphereDesc {
+        center:   Float3::xyz(4., 1., 0.),
+        radius:   1.,
+        motion:   None,
+        material: MaterialDesc::Metal { albedo: Float3::xyz(0.7, 0.6, 0.5), fuzz: 0. },
+    });
+
+    spheres
+}
+
+/// The real things, to render.
+fn hitables(spheres: &[SphereDesc]) -> HitableList {
+    HitableList {
+        hitables: spheres.iter().map(SphereDesc::hitable).collect(),
+    }
+}
+
+/// Spheres bigger than this don't go in `SphereSet`s, since most rays would
+/// hit them anyway, and they'd make the BVH's boxes around the sets big.
+const PACKED_MAX_RADIUS: Float = 0.5;
+
+/// How many spheres go in each `SphereSet`, for the BVH to sort out.
+const SPHERES_PER_SET: usize = 16;
+
+/// The real things, to render, like `hitables()`, but with the little spheres
+/// that don't move packed into `SphereSet`s, for --pack-spheres. The ground,
+/// the big spheres, and the ones that move are still hitables of their own.
+pub fn packed_hitables(spheres: &[SphereDesc]) -> HitableList {
+    let packs = |sphere: &SphereDesc| {
+        sphere.motion.map_or(true, |motion| motion == Float3::new()) &&
+            sphere.radius.abs() <= PACKED_MAX_RADIUS
+    };
+    let set = SphereSet::from_spheres(spheres.iter().filter(|sphere| packs(sphere)).map(|sphere| {
+        Sphere {
+            center:   sphere.center,
+            radius:   sphere.radius,
+            material: sphere.material.material(),
+        }
+    }));
+    let mut hitables: Vec<Box<dyn Hitable>> = spheres.iter()
+                                                     .filter(|sphere| !packs(sphere))
+                                                     .map(SphereDesc::hitable)
+                                                     .collect();
+    hitables.extend(set.split(SPHERES_PER_SET)
+                       .into_iter()
+                       .map(|set| Box::new(set) as Box<dyn Hitable>));
+    HitableList { hitables }
+}
+
+/// How many of the cover scene's little spheres the random scene scatters,
+/// and how far, for --objects and --extent.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RandomSceneParams {
+    pub objects: u32,
+    /// The spheres are somewhere from -this to this, in x and z.
+    pub extent:  Float,
+}
+
+impl RandomSceneParams {
+    pub fn check(&self) -> Result<(), String> {
+        // Any further out, and the ground curves too far away from under them.
+        if !(0.0 < self.extent && self.extent <= 500.0) {
+            return Err(format!("--extent must be more than 0 and at most 500, found {}",
+                               self.extent));
+        }
+        Ok(())
+    }
+}
+
+impl Default for RandomSceneParams {
+    fn default() -> RandomSceneParams {
+        RandomSceneParams {
+            objects: 500,
+            extent:  10.0,
+        }
+    }
+}
+
+/// How many times in a row the random scene tries to find room for another
+/// sphere, before deciding there isn't any.
+const PLACEMENT_TRIES: u32 = 1000;
+
+/// A stress test: `params.objects` of the cover scene's little spheres,
+/// scattered over the ground without touching each other, with materials
+/// drawn like `cover` says. If that many won't fit, there are as many as did.
+/// It's the same every time for the same `seed` and params.
+pub fn make_random_scene(seed:   &SceneSeed,
+                         cover:  &CoverSceneParams,
+                         params: &RandomSceneParams)
+    -> HitableList
+{
+    hitables(&random_scene_spheres(seed, cover, params))
+}
+
+/// The spheres of `make_random_scene()`, as plain data.
+pub fn random_scene_spheres(seed:   &SceneSeed,
+                            cover:  &CoverSceneParams,
+                            params: &RandomSceneParams)
+    -> Vec<SphereDesc>
+{
+    let mut rng = SmallRng::from_seed(seed.bytes);
+
+    const GROUND_RADIUS: Float = 1000.0;
+    let mut spheres = vec![
+        SphereDesc {
+            center:   Float3::xyz(0., -GROUND_RADIUS, 0.),
+            radius:   GROUND_RADIUS,
+            motion:   None,
+            material: MaterialDesc::Lambertian { albedo: Float3::xxx(0.5) },
+        },
+    ];
+
+    // Spheres touch when their centers are closer than this. Any sphere close
+    // enough to touch another one is in the same cell of `placed` as it, or
+    // one of the eight around it.
+    let radius = cover.sphere_radius;
+    let apart = 2.0 * radius;
+    let cell = |x: Float, z: Float| ((x / apart).floor() as i64, (z / apart).floor() as i64);
+    let mut placed: HashMap<(i64, i64), Vec<(Float, Float)>> = HashMap::new();
+
+    'placing: for _ in 0..params.objects {
+        for _ in 0..PLACEMENT_TRIES {
+            let x = params.extent * (2.0 * rng.gen::<Float>() - 1.0);
+            let z = params.extent * (2.0 * rng.gen::<Float>() - 1.0);
+            let (i, j) = cell(x, z);
+            let touching = (i - 1..=i + 1)
+                .flat_map(|i| (j - 1..=j + 1).map(move |j| (i, j)))
+                .filter_map(|key| placed.get(&key))
+                .flatten()
+                .any(|&(px, pz)| (px - x) * (px - x) + (pz - z) * (pz - z) < apart * apart);
+            if touching {
+                continue;
+            }
+            placed.entry((i, j)).or_default().push((x, z));
+
+            // Sitting on the ground under (x, z). That moves it out from the
+            // middle a little, which only takes it further from the others.
+            let y = (GROUND_RADIUS * GROUND_RADIUS - x * x - z * z).sqrt();
+            let up = Float3::xyz(x, y, z) / GROUND_RADIUS;
+            let center = Float3::xyz(0., -GROUND_RADIUS, 0.) + (GROUND_RADIUS + radius) * up;
+            spheres.push(little_sphere(&mut rng, center, cover));
+            continue 'placing;
+        }
+        // No room for any more.
+        break;
+    }
+
+    spheres
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::hitable::Aabb;
+    use crate::scene_file;
+
+    /// Where every sphere in the cover scene is.
+    fn boxes(seed: &SceneSeed) -> Vec<Aabb> {
+        make_cover_scene(seed, &CoverSceneParams::default()).hitables
+                              .iter()
+                              .map(|hitable| hitable.bounding_box(0., 0.).unwrap())
+                              .collect()
+    }
+
+    #[test]
+    fn check_scene_kinds() {
+        for &kind in SceneKind::ALL {
+            assert_eq!(kind.name().parse(), Ok(kind));
+        }
+        assert!("cornell-box".parse::<SceneKind>().is_err());
+
+        // Looking in through the missing front wall, at the middle of the room.
+        let view = SceneKind::Cornell.view();
+        assert!(view.lookfrom.z < 0.0);
+        assert_eq!(view.lookat, Float3::xyz(278., 278., 0.));
+        let scene = make_cornell_box();
+        assert_eq!(scene.lights.hitables.len(), 1);
+        // Five walls, the light, and two boxes.
+        assert_eq!(scene.world.hitables.len(), 8);
+    }
+
+    #[test]
+    fn check_every_scene_has_bounds() {
+        let finite = |v: Float3| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        for &kind in SceneKind::ALL {
+            let scene = kind.make(&SceneSeed::default(),
+                                  &CoverSceneParams::default(),
+                                  &RandomSceneParams::default());
+            let bounds = scene.world.bounding_box(0., 1.);
+            assert!(bounds.is_some(), "{}", kind.name());
+            let bounds = bounds.unwrap();
+            assert!(finite(bounds.min) && finite(bounds.max), "{}: {:?}", kind.name(), bounds);
+            assert!(!kind.description().is_empty());
+
+            let view = kind.view();
+            assert_ne!(view.lookfrom, view.lookat, "{}", kind.name());
+        }
+    }
+
+    /// The centers and radii of the spheres on the random scene's ground.
+    fn random_spheres(params: &RandomSceneParams) -> Vec<(Float3, Float)> {
+        make_random_scene(&SceneSeed::new("7"), &CoverSceneParams::default(), params)
+            .hitables[1..]
+            .iter()
+            .map(|hitable| {
+                let bounds = hitable.bounding_box(0., 0.).unwrap();
+                (bounds.centroid(), 0.5 * (bounds.max.x - bounds.min.x))
+            })
+            .collect()
+    }
+
+    fn any_touching(spheres: &[(Float3, Float)]) -> bool {
+        spheres.iter().enumerate().any(|(i, &(a, ra))| {
+            spheres[i + 1..].iter().any(|&(b, rb)| (a - b).length() < ra + rb - 1e-3)
+        })
+    }
+
+    #[test]
+    fn check_random_scene() {
+        let params = RandomSceneParams {
+            objects: 2000,
+            extent:  20.0,
+        };
+        let spheres = random_spheres(&params);
+        assert_eq!(spheres.len(), 2000);
+        assert!(!any_touching(&spheres));
+        for &(center, _) in spheres.iter() {
+            assert!(center.x.abs() < 20.1 && center.z.abs() < 20.1, "{:?}", center);
+        }
+        assert_eq!(spheres, random_spheres(&params));
+
+        // There's only room for a few this close together.
+        let crowded = random_spheres(&RandomSceneParams {
+            objects: 1000,
+            extent:  1.0,
+        });
+        assert!(!crowded.is_empty() && crowded.len() < 100, "{}", crowded.len());
+        assert!(!any_touching(&crowded));
+
+        assert_eq!(params.check(), Ok(()));
+        for &extent in [0.0, -1.0, 1000.0].iter() {
+            assert!(RandomSceneParams { extent, ..params }.check().is_err(), "{}", extent);
+        }
+    }
+
+    #[test]
+    fn check_dumped_scenes_load_the_same() {
+        let seed = SceneSeed::new("7");
+        let cover = CoverSceneParams::default();
+        let random = RandomSceneParams {
+            objects: 200,
+            extent:  5.0,
+        };
+        for &kind in [SceneKind::Cover, SceneKind::Random].iter() {
+            let spheres = kind.spheres(&seed, &cover, &random).unwrap();
+            let text = scene_file::write_spheres("A comment\nover two lines",
+                                                 Background::Sky,
+                                                 &spheres);
+            let loaded = scene_file::load_str(&text, &scene_file::SCHEMA)
+                .unwrap_or_else(|err| panic!("{}\n{}", err, text));
+            let made = kind.make(&seed, &cover, &random);
+            assert_eq!(loaded.world.hitables.len(), made.world.hitables.len());
+            assert_eq!(format!("{:?}", loaded.world.hitables),
+                       format!("{:?}", made.world.hitables),
+                       "{}", kind.name());
+            match loaded.background {
+                Background::Sky => {},
+                other => panic!("Wrong background: {:?}", other),
+            }
+        }
+        assert!(SceneKind::Cornell.spheres(&seed, &cover, &random).is_none());
+    }
+
+    #[test]
+    fn check_packed_spheres() {
+        let mut rng = seeded_rng(0);
+        let spheres = cover_scene_spheres(&SceneSeed::default(), &CoverSceneParams::default());
+        let packed = packed_hitables(&spheres);
+        let moving = spheres.iter()
+                            .filter(|sphere| sphere.motion.map_or(false, |m| m != Float3::new()))
+                            .count();
+        // Only the little spheres that sit still are packed, 16 to a set.
+        let still = spheres.len() - moving - 4;
+        assert!(still > 50, "{}", still);
+        assert_eq!(packed.hitables.len(), 4 + moving + (still + 15) / 16);
+
+        // They're in the same places as ever, moving or not.
+        let plain = Bvh::new(make_cover_scene(&SceneSeed::default(),
+                                              &CoverSceneParams::default()).hitables,
+                             0.,
+                             1.);
+        let packed = Bvh::new(packed.hitables, 0., 1.);
+        for _ in 0..2000 {
+            let ray = Ray {
+                origin: Float3::xyz(13., 2., 3.) + Float3::xyz(random_sfloat(), 0., 0.),
+                dir:    Float3::xyz(-13., -2., -3.) + 4.0 * random_in_sphere(),
+                t:      random_float(),
+            };
+            let mut hit = |bvh: &Bvh| {
+                bvh.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).map(|r| (r.t, r.p, r.normal))
+            };
+            assert_eq!(hit(&packed), hit(&plain), "{:?}", ray);
+        }
+    }
+
+    #[test]
+    fn check_scene_seed() {
+        let default = boxes(&SceneSeed::default());
+        assert_eq!(default, boxes(&SceneSeed::default()));
+        for seed in ["7", "Katy's Penguin", "another scene"].iter() {
+            let seeded = boxes(&SceneSeed::new(seed));
+            assert_eq!(seeded, boxes(&SceneSeed::new(seed)), "{}", seed);
+            assert_ne!(seeded, default, "{}", seed);
+        }
+        assert_ne!(boxes(&SceneSeed::new("7")), boxes(&SceneSeed::new("8")));
+    }
+
+    #[test]
+    fn check_cover_scene_params() {
+        let count = |grid_half_extent| {
+            let params = CoverSceneParams {
+                grid_half_extent,
+                ..CoverSceneParams::default()
+            };
+            make_cover_scene(&SceneSeed::default(), &params).hitables.len()
+        };
+        // The ground and three big spheres are always there.
+        assert_eq!(count(0), 4);
+        // Nothing this close in is in the way of the big metal sphere.
+        assert_eq!(count(2), 4 + 4 * 4);
+        // Out to 5, a few spheres by the metal one may be left out.
+        let n = count(5);
+        assert!((4 + 10 * 10 - 4..=4 + 10 * 10).contains(&n), "{}", n);
+
+        let mut params = CoverSceneParams::default();
+        assert_eq!(params.set("grid_half_extent=3"), Ok(()));
+        assert_eq!(params.set(" sphere_radius = 0.3"), Ok(()));
+        assert_eq!(params.grid_half_extent, 3);
+        assert_eq!(params.sphere_radius, 0.3);
+        assert!(params.set("glass_prob=0.1").is_err());
+        assert!(params.set("diffuse_prob").is_err());
+        assert!(params.set("diffuse_prob=lots").is_err());
+        assert_eq!(params.diffuse_prob, 0.7);
+
+        // Too much of a good thing.
+        assert_eq!(params.set("diffuse_prob=0.9"), Ok(()));
+        assert!(params.check().is_err());
+        assert_eq!(params.set("metal_prob=0.1"), Ok(()));
+        assert_eq!(params.check(), Ok(()));
+        for &bad in ["sphere_radius=0", "grid_half_extent=-1", "metal_prob=-0.1"].iter() {
+            let mut bad_params = params;
+            assert_eq!(bad_params.set(bad), Ok(()));
+            assert!(bad_params.check().is_err(), "{}", bad);
+        }
+
+        // All glass.
+        params.diffuse_prob = 0.0;
+        params.metal_prob = 0.0;
+        assert_eq!(params.check(), Ok(()));
+        let scene = make_cover_scene(&SceneSeed::default(), &params);
+        assert_eq!(scene.hitables.len(), 4 + 6 * 6);
+    }
+}
@@ -0,0 +1,187 @@
+//! Writing out what we have within a time limit, once we're asked to stop.
+//!
+//! Batch schedulers stop a job with SIGTERM, and then kill it outright a
+//! little later. With `--grace-seconds`, we keep track of how much of that
+//! time is left while writing outputs, and once it's gone, we skip the ones
+//! we can do without. The image itself, and the checkpoint to resume from,
+//! are always written, and the image comes first, since it's what the job was
+//! for. Renders that weren't stopped early have all the time they need.
+
+use std::{
+    sync::Mutex,
+    time,
+};
+
+/// Something we write once the render is done, most important first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Output {
+    /// The first --output.
+    Image,
+    /// The --checkpoint to carry on from with --resume.
+    ResumeState,
+    /// Which pixels are real, when the render was stopped early.
+    Mask,
+    /// Every --output after the first.
+    OtherImages,
+    /// --stats-json
+    Manifest,
+    /// --aov images, and the --variance-map.
+    Aovs,
+}
+
+impl Output {
+    /// Whether to write this even after we're out of time.
+    pub fn is_required(self) -> bool {
+        match self {
+            Output::Image | Output::ResumeState => true,
+            _ => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Output::Image => "image",
+            Output::ResumeState => "checkpoint",
+            Output::Mask => "mask",
+            Output::OtherImages => "other outputs",
+            Output::Manifest => "stats",
+            Output::Aovs => "AOVs",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Written,
+    Failed,
+    /// We ran out of time first.
+    Skipped,
+}
+
+/// Writes one output, returning whether that worked.
+pub type Step<'a> = (Output, Box<dyn FnOnce() -> bool + 'a>);
+
+/// Runs every step, most important first, and returns how each one went.
+/// Once `out_of_time()` says so, the optional ones are skipped.
+pub fn write_outputs(mut steps: Vec<Step>, mut out_of_time: impl FnMut() -> bool)
+    -> Vec<(Output, Outcome)>
+{
+    // Stable, so that steps for the same output stay in order.
+    steps.sort_by_key(|&(output, _)| output);
+    steps.into_iter()
+         .map(|(output, write)| {
+             let outcome = if !output.is_required() && out_of_time() {
+                 Outcome::Skipped
+             } else if write() {
+                 Outcome::Written
+             } else {
+                 Outcome::Failed
+             };
+             (output, outcome)
+         })
+         .collect()
+}
+
+// When we were first asked to stop, if we were.
+static STOPPED_AT: Mutex<Option<time::Instant>> = Mutex::new(None);
+
+/// Starts the clock on the grace period. Only the first call counts.
+pub fn note_stop() {
+    let mut stopped_at = STOPPED_AT.lock().unwrap();
+    if stopped_at.is_none() {
+        *stopped_at = Some(time::Instant::now());
+    }
+}
+
+/// Whether more than `grace` has gone by since we were asked to stop.
+/// Without a grace period, or without being stopped, there's always time.
+pub fn past_deadline(grace: Option<time::Duration>) -> bool {
+    match (*STOPPED_AT.lock().unwrap(), grace) {
+        (Some(stopped_at), Some(grace)) => stopped_at.elapsed() > grace,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use std::cell::{
+        Cell,
+        RefCell,
+    };
+
+    /// Steps for `outputs` that record when they run in `log`.
+    fn steps<'a>(outputs: &[Output], log: &'a RefCell<Vec<Output>>) -> Vec<Step<'a>> {
+        outputs.iter()
+               .map(|&output| {
+                   let write: Box<dyn FnOnce() -> bool> = Box::new(move || {
+                       log.borrow_mut().push(output);
+                       true
+                   });
+                   (output, write)
+               })
+               .collect()
+    }
+
+    const SHUFFLED: [Output; 6] = [
+        Output::Aovs,
+        Output::Manifest,
+        Output::ResumeState,
+        Output::OtherImages,
+        Output::Image,
+        Output::Mask,
+    ];
+
+    #[test]
+    fn check_priority_order() {
+        let log = RefCell::new(vec![]);
+        let outcomes = write_outputs(steps(&SHUFFLED, &log), || false);
+        let order = vec![
+            Output::Image,
+            Output::ResumeState,
+            Output::Mask,
+            Output::OtherImages,
+            Output::Manifest,
+            Output::Aovs,
+        ];
+        assert_eq!(*log.borrow(), order);
+        assert!(outcomes.iter().all(|&(_, outcome)| outcome == Outcome::Written));
+    }
+
+    #[test]
+    fn check_deadline_skips_optional_outputs() {
+        // Out of time after a given number of checks.
+        for checks in 0..4 {
+            let log = RefCell::new(vec![]);
+            let checked = Cell::new(0);
+            let outcomes = write_outputs(steps(&SHUFFLED, &log), || {
+                checked.set(checked.get() + 1);
+                checked.get() > checks
+            });
+
+            // The image is always attempted first, and the checkpoint after it.
+            assert_eq!(log.borrow()[..2], [Output::Image, Output::ResumeState]);
+            // Then optional ones, in order, until the time runs out.
+            let written = log.borrow().len() - 2;
+            assert_eq!(written, checks, "{:?}", log);
+            for (i, &(output, outcome)) in outcomes.iter().enumerate() {
+                let expected = if i < 2 + checks {
+                    Outcome::Written
+                } else {
+                    Outcome::Skipped
+                };
+                assert_eq!(outcome, expected, "{:?}", output);
+            }
+        }
+    }
+
+    #[test]
+    fn check_failures_dont_stop_the_rest() {
+        let failing: Vec<Step> = vec![
+            (Output::Mask, Box::new(|| true)),
+            (Output::Image, Box::new(|| false)),
+        ];
+        assert_eq!(write_outputs(failing, || false),
+                   vec![(Output::Image, Outcome::Failed), (Output::Mask, Outcome::Written)]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Four `Float`s at a time.
+//!
+//! With the `simd` feature on x86_64, `Lanes` does its arithmetic with SSE2,
+//! which every x86_64 CPU has. Everywhere else, and by default, it's a plain
+//! loop over the lanes. Either way, each lane goes through the same IEEE
+//! operation, so the two give the same answers down to the bit.
+//!
+//! `Float3` uses these for its arithmetic when the feature is on,
+//! `hitable::Aabb4` uses them to test a ray against four boxes at once, and
+//! `packet::RayPacket4` to test four rays against one sphere or box.
+
+use std::ops;
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+use crate::prelude::*;
+
+// f64s fit two to an SSE register, so we use two of them.
+#[cfg(all(feature = "simd", target_arch = "x86_64", not(feature = "float32")))]
+macro_rules! lanewise {
+    ($a:expr, $b:expr, $pd:ident, $ps:ident, $scalar:expr) => {{
+        let (a, b): (Lanes, Lanes) = ($a, $b);
+        let mut out = Lanes::default();
+        // Safe: SSE2 is always there on x86_64, and unaligned loads and stores
+        // only need the 4 lanes to be there.
+        unsafe {
+            let (a, b, out) = (a.0.as_ptr(), b.0.as_ptr(), out.0.as_mut_ptr());
+            _mm_storeu_pd(out, $pd(_mm_loadu_pd(a), _mm_loadu_pd(b)));
+            _mm_storeu_pd(out.add(2), $pd(_mm_loadu_pd(a.add(2)), _mm_loadu_pd(b.add(2))));
+        }
+        out
+    }};
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", feature = "float32"))]
+macro_rules! lanewise {
+    ($a:expr, $b:expr, $pd:ident, $ps:ident, $scalar:expr) => {{
+        let (a, b): (Lanes, Lanes) = ($a, $b);
+        let mut out = Lanes::default();
+        // Safe: SSE is always there on x86_64, and unaligned loads and stores
+        // only need the 4 lanes to be there.
+        unsafe {
+            _mm_storeu_ps(out.0.as_mut_ptr(),
+                          $ps(_mm_loadu_ps(a.0.as_ptr()), _mm_loadu_ps(b.0.as_ptr())));
+        }
+        out
+    }};
+}
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+macro_rules! lanewise {
+    ($a:expr, $b:expr, $pd:ident, $ps:ident, $scalar:expr) => {{
+        let (a, b): (Lanes, Lanes) = ($a, $b);
+        let f = $scalar;
+        Lanes([f(a.0[0], b.0[0]), f(a.0[1], b.0[1]), f(a.0[2], b.0[2]), f(a.0[3], b.0[3])])
+    }};
+}
+
+/// Four `Float`s, which arithmetic works on side by side.
+#[repr(C, align(32))]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Lanes(pub [Float; 4]);
+
+impl Lanes {
+    #[inline]
+    pub fn splat(x: Float) -> Lanes {
+        Lanes([x; 4])
+    }
+
+    /// The smaller of each pair of lanes. When either is NaN, that's `other`,
+    /// like the SSE instruction.
+    #[inline]
+    pub fn min(self, other: Lanes) -> Lanes {
+        lanewise!(self, other, _mm_min_pd, _mm_min_ps, |a: Float, b: Float| {
+            if a < b { a } else { b }
+        })
+    }
+
+    /// The larger of each pair of lanes. When either is NaN, that's `other`,
+    /// like the SSE instruction.
+    #[inline]
+    pub fn max(self, other: Lanes) -> Lanes {
+        lanewise!(self, other, _mm_max_pd, _mm_max_ps, |a: Float, b: Float| {
+            if a > b { a } else { b }
+        })
+    }
+
+    #[inline]
+    pub fn sqrt(self) -> Lanes {
+        let [a, b, c, d] = self.0;
+        Lanes([a.sqrt(), b.sqrt(), c.sqrt(), d.sqrt()])
+    }
+
+    /// Which lanes are less than `other`'s. NaNs aren't less than anything.
+    #[inline]
+    pub fn lt(self, other: Lanes) -> [bool; 4] {
+        let (a, b) = (self.0, other.0);
+        [a[0] < b[0], a[1] < b[1], a[2] < b[2], a[3] < b[3]]
+    }
+
+    /// `if_true`'s lanes where `mask` is set, and `if_false`'s elsewhere.
+    #[inline]
+    pub fn select(mask: [bool; 4], if_true: Lanes, if_false: Lanes) -> Lanes {
+        let lane = |i: usize| if mask[i] { if_true.0[i] } else { if_false.0[i] };
+        Lanes([lane(0), lane(1), lane(2), lane(3)])
+    }
+}
+
+impl ops::Add for Lanes {
+    type Output = Lanes;
+    #[inline]
+    fn add(self, rhs: Lanes) -> Lanes {
+        lanewise!(self, rhs, _mm_add_pd, _mm_add_ps, |a: Float, b: Float| a + b)
+    }
+}
+
+impl ops::Sub for Lanes {
+    type Output = Lanes;
+    #[inline]
+    fn sub(self, rhs: Lanes) -> Lanes {
+        lanewise!(self, rhs, _mm_sub_pd, _mm_sub_ps, |a: Float, b: Float| a - b)
+    }
+}
+
+impl ops::Mul for Lanes {
+    type Output = Lanes;
+    #[inline]
+    fn mul(self, rhs: Lanes) -> Lanes {
+        lanewise!(self, rhs, _mm_mul_pd, _mm_mul_ps, |a: Float, b: Float| a * b)
+    }
+}
+
+impl ops::Div for Lanes {
+    type Output = Lanes;
+    #[inline]
+    fn div(self, rhs: Lanes) -> Lanes {
+        lanewise!(self, rhs, _mm_div_pd, _mm_div_ps, |a: Float, b: Float| a / b)
+    }
+}
+
+impl ops::Neg for Lanes {
+    type Output = Lanes;
+    #[inline]
+    fn neg(self) -> Lanes {
+        let [a, b, c, d] = self.0;
+        Lanes([-a, -b, -c, -d])
+    }
+}
+
+/// The fourth lane is 0.
+impl From<Float3> for Lanes {
+    #[inline]
+    fn from(v: Float3) -> Lanes {
+        Lanes([v.x, v.y, v.z, 0.0])
+    }
+}
+
+/// Drops the fourth lane.
+impl From<Lanes> for Float3 {
+    #[inline]
+    fn from(lanes: Lanes) -> Float3 {
+        Float3::xyz(lanes.0[0], lanes.0[1], lanes.0[2])
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    /// How many representable `Float`s apart `a` and `b` are.
+    fn ulps(a: Float, b: Float) -> u64 {
+        let bits = |x: Float| {
+            // Order negative numbers below positive ones, like the numbers.
+            let bits = x.to_bits() as i64;
+            if bits < 0 { !bits } else { bits }
+        };
+        (bits(a) as i128 - bits(b) as i128).abs() as u64
+    }
+
+    fn random_lanes() -> Lanes {
+        let mut lanes = Lanes::default();
+        for lane in lanes.0.iter_mut() {
+            // Across a few orders of magnitude, and both signs.
+            *lane = (random_float() - 0.5) * (10.0 as Float).powi((random_float() * 8.0) as i32);
+        }
+        lanes
+    }
+
+    #[test]
+    fn check_lanes_match_scalars() {
+        seed_thread_rng(0x5eed);
+        type Op = (&'static str, fn(Lanes, Lanes) -> Lanes, fn(Float, Float) -> Float);
+        let ops: [Op; 6] = [
+            ("add", |a, b| a + b, |a, b| a + b),
+            ("sub", |a, b| a - b, |a, b| a - b),
+            ("mul", |a, b| a * b, |a, b| a * b),
+            ("div", |a, b| a / b, |a, b| a / b),
+            ("min", Lanes::min, Float::min),
+            ("max", Lanes::max, Float::max),
+        ];
+        for _ in 0..1000 {
+            let (a, b) = (random_lanes(), random_lanes());
+            for &(name, lanes, scalar) in ops.iter() {
+                let out = lanes(a, b);
+                for i in 0..4 {
+                    let expected = scalar(a.0[i], b.0[i]);
+                    assert!(ulps(out.0[i], expected) <= 1,
+                            "{}({}, {}): {} vs {}", name, a.0[i], b.0[i], out.0[i], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_float3_arithmetic() {
+        seed_thread_rng(1);
+        for _ in 0..1000 {
+            let (a, b) = (Float3::from(random_lanes()), Float3::from(random_lanes()));
+            let dot = a.x * b.x + a.y * b.y + a.z * b.z;
+            assert!(ulps(a.dot(&b), dot) <= 1, "{:?} . {:?}", a, b);
+            for &(out, expected) in [(a + b, Float3::xyz(a.x + b.x, a.y + b.y, a.z + b.z)),
+                                     (a - b, Float3::xyz(a.x - b.x, a.y - b.y, a.z - b.z)),
+                                     (a * b, Float3::xyz(a.x * b.x, a.y * b.y, a.z * b.z))]
+                .iter()
+            {
+                for (o, e) in out.as_slice().iter().zip(expected.as_slice().iter()) {
+                    assert!(ulps(*o, *e) <= 1, "{:?} vs {:?}", out, expected);
+                }
+            }
+        }
+    }
+}
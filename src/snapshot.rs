@@ -0,0 +1,194 @@
+//! Periodic snapshots of an image while it's still rendering.
+//!
+//! Tiles copy each finished pixel into a `Framebuffer` shared with a
+//! background thread, which assembles it into an image every so often and
+//! writes that to disk. Pixels are packed into a single `AtomicU32`, so a
+//! snapshot only ever sees whole pixels - never half of an old one and half
+//! of a new one.
+
+use std::{
+    fs,
+    io,
+    path,
+    sync::{
+        atomic::{
+            AtomicU32,
+            Ordering,
+        },
+        mpsc,
+        Arc,
+    },
+    thread,
+    time,
+};
+
+use image::{
+    Rgb,
+    RgbImage,
+};
+use log::error;
+
+/// An image that many threads can write to, and read from, at once.
+pub struct Framebuffer {
+    width:  u32,
+    height: u32,
+    pixels: Vec<AtomicU32>,
+}
+
+impl Framebuffer {
+    /// A black `width` x `height` framebuffer.
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        let len = width as usize * height as usize;
+        Framebuffer {
+            width,
+            height,
+            pixels: (0..len).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn put_pixel(&self, x: u32, y: u32, pixel: Rgb<u8>) {
+        let [r, g, b] = pixel.data;
+        let packed = (r as u32) << 16 | (g as u32) << 8 | (b as u32);
+        self.pixels[self.index(x, y)].store(packed, Ordering::Relaxed);
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Rgb<u8> {
+        let packed = self.pixels[self.index(x, y)].load(Ordering::Relaxed);
+        Rgb([
+            (packed >> 16) as u8,
+            (packed >> 8) as u8,
+            packed as u8,
+        ])
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Copies out whatever has been written so far.
+    pub fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| self.get_pixel(x, y))
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(x < self.width && y < self.height,
+                "({}, {}) is outside of a {}x{} framebuffer",
+                x, y, self.width, self.height);
+        y as usize * self.width as usize + x as usize
+    }
+}
+
+/// Saves `img` to `path` without ever leaving a partially written file there.
+/// The image is written next to `path` first, and then moved into place.
+pub fn save_atomically(img: &RgbImage, path: &path::Path) -> io::Result<()> {
+    // `image` picks the encoding from the extension, so keep it last.
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".tmp.{}", file_name));
+    img.save(&tmp_path)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Runs a task on a background thread every `interval`, until finished.
+/// We use this to write snapshots and checkpoints while we render.
+pub struct Periodic {
+    stop:   mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Periodic {
+    pub fn spawn<F>(interval: time::Duration, mut task: F) -> Periodic
+        where F: FnMut() + Send + 'static
+    {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            // Either a message or a hang up means we're done.
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                task();
+            }
+        });
+
+        Periodic {
+            stop,
+            handle,
+        }
+    }
+
+    /// Stops running the task, and waits for any run in flight to finish.
+    pub fn finish(self) {
+        // If the thread is already gone, there's nothing to stop.
+        let _ = self.stop.send(());
+        self.handle.join().expect("Periodic task panicked");
+    }
+}
+
+/// Writes `framebuffer` to `path` every `interval`.
+pub fn spawn_snapshots(framebuffer: Arc<Framebuffer>,
+                       interval:    time::Duration,
+                       path:        path::PathBuf)
+    -> Periodic
+{
+    Periodic::spawn(interval, move || {
+        if let Err(err) = save_atomically(&framebuffer.to_image(), &path) {
+            error!("Failed to write snapshot to {}: {}", path.display(), err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_framebuffer_roundtrip() {
+        let fb = Framebuffer::new(3, 2);
+        fb.put_pixel(0, 0, Rgb([255, 0, 0]));
+        fb.put_pixel(2, 1, Rgb([1, 2, 3]));
+        fb.put_pixel(1, 1, Rgb([0, 128, 255]));
+
+        let img = fb.to_image();
+        assert_eq!((img.width(), img.height()), (3, 2));
+        assert_eq!(*img.get_pixel(0, 0), Rgb([255, 0, 0]));
+        assert_eq!(*img.get_pixel(2, 1), Rgb([1, 2, 3]));
+        assert_eq!(*img.get_pixel(1, 1), Rgb([0, 128, 255]));
+        // Untouched pixels stay black.
+        assert_eq!(*img.get_pixel(1, 0), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn check_snapshots_never_tear() {
+        let fb = Arc::new(Framebuffer::new(16, 16));
+
+        // Writers only ever write grey pixels, so any pixel that isn't grey
+        // was torn between two writes.
+        let writers: Vec<_> = (0..4)
+            .map(|id| {
+                let fb = fb.clone();
+                thread::spawn(move || {
+                    for i in 0..200_u32 {
+                        let c = (i * 4 + id) as u8;
+                        for y in 0..16 {
+                            for x in 0..16 {
+                                fb.put_pixel(x, y, Rgb([c, c, c]));
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..200 {
+            for px in fb.to_image().pixels() {
+                let [r, g, b] = px.data;
+                assert!(r == g && g == b, "Torn pixel: {:?}", px);
+            }
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Lots of spheres that don't move, for scenes like `--scene random` with
+//! thousands of them.
+//!
+//! A `HitableList` of boxed `Sphere`s chases a pointer and makes a virtual call
+//! for every sphere a ray is tested against. A `SphereSet` keeps its spheres'
+//! centers and radii in arrays of their own instead, and tests a ray against
+//! all of them in one tight loop. Materials are looked up by index once we
+//! know which sphere is closest, out of one palette that spheres share.
+//!
+//! A set tests every one of its spheres, so big sets still want a `Bvh` to
+//! pick out the few a ray might hit. `split()` makes sets of spheres near each
+//! other for that, and the BVH takes them like any other hitable.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+};
+
+use crate::hitable::{
+    hit_any_through_cutouts,
+    sphere_hit_t,
+    sphere_record,
+    Aabb,
+    HitRecord,
+    Hitable,
+    Sphere,
+};
+use crate::packet::{
+    sphere_hit_t4,
+    LaneRngs,
+    RayPacket4,
+};
+use crate::prelude::*;
+use crate::simd::Lanes;
+
+#[derive(Clone, Debug, Default)]
+pub struct SphereSet {
+    centers:   Vec<Float3>,
+    radii:     Vec<Float>,
+    /// Each sphere's material, in `palette`.
+    materials: Vec<u32>,
+    palette:   Vec<Arc<dyn Material>>,
+}
+
+impl SphereSet {
+    pub fn new() -> SphereSet {
+        SphereSet::default()
+    }
+
+    /// A set of all of `spheres`.
+    pub fn from_spheres(spheres: impl IntoIterator<Item=Sphere>) -> SphereSet {
+        // Where each material already is in the palette, by its address.
+        let mut indices: HashMap<*const (), u32> = HashMap::new();
+        let mut set = SphereSet::new();
+        for sphere in spheres {
+            let palette = &mut set.palette;
+            let index = *indices.entry(Arc::as_ptr(&sphere.material) as *const ())
+                                .or_insert_with(|| {
+                                    palette.push(sphere.material.clone());
+                                    palette.len() as u32 - 1
+                                });
+            set.centers.push(sphere.center);
+            set.radii.push(sphere.radius);
+            set.materials.push(index);
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.centers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centers.is_empty()
+    }
+
+    /// The spheres in the set, as `Sphere`s again.
+    pub fn spheres(&self) -> impl Iterator<Item=Sphere> + '_ {
+        (0..self.len()).map(move |i| self.sphere(i))
+    }
+
+    fn sphere(&self, i: usize) -> Sphere {
+        Sphere {
+            center:   self.centers[i],
+            radius:   self.radii[i],
+            material: self.material(i).clone(),
+        }
+    }
+
+    /// Splits the set up into sets of at most `max_len` spheres each, which
+    /// are close together, for a `Bvh` to sort out.
+    pub fn split(&self, max_len: usize) -> Vec<SphereSet> {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        let mut groups = vec![];
+        self.split_into(&mut order, max_len.max(1), &mut groups);
+        groups.into_iter()
+              .map(|group| SphereSet::from_spheres(group.into_iter().map(|i| self.sphere(i))))
+              .collect()
+    }
+
+    /// Splits `order` in half along the axis its centers spread out over the
+    /// most, like the BVH does, until every part fits in `max_len`.
+    fn split_into(&self, order: &mut [usize], max_len: usize, groups: &mut Vec<Vec<usize>>) {
+        if order.len() <= max_len {
+            if !order.is_empty() {
+                groups.push(order.to_vec());
+            }
+            return;
+        }
+        let center = |i: usize| self.centers[i];
+        let (min, max) = order.iter().fold((center(order[0]), center(order[0])),
+                                           |(min, max), &i| (min.min(&center(i)),
+                                                             max.max(&center(i))));
+        let axis = (max - min).argmax_component();
+        order.sort_by(|&a, &b| {
+            center(a)[axis].partial_cmp(&center(b)[axis]).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let (lower, upper) = order.split_at_mut(order.len() / 2);
+        self.split_into(lower, max_len, groups);
+        self.split_into(upper, max_len, groups);
+    }
+
+    /// The closest sphere that `ray` hits between `t_min` and `t_max`, and
+    /// where along it.
+    fn closest(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<(usize, Float)> {
+        crate::stats::count(|c| c.sphere_tests += self.len() as u64);
+        let mut closest = None;
+        let mut t_max = t_max;
+        for (i, (&center, &radius)) in self.centers.iter().zip(self.radii.iter()).enumerate() {
+            if let Some(t) = sphere_hit_t(center, radius, ray, t_min, t_max) {
+                t_max = t;
+                closest = Some(i);
+            }
+        }
+        closest.map(|i| (i, t_max))
+    }
+
+    /// Like `closest()`, for each ray of `rays`, between its own lane of
+    /// `t_min` and `t_max`. Each sphere's center and radius is loaded once for
+    /// all four rays.
+    pub fn closest_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes)
+        -> [Option<(usize, Float)>; 4]
+    {
+        let active = rays.active.iter().filter(|&&on| on).count() as u64;
+        crate::stats::count(|c| c.sphere_tests += active * self.len() as u64);
+        let [dx, dy, dz] = rays.dir;
+        let dir_length_sq = dx * dx + dy * dy + dz * dz;
+        let mut closest = [None; 4];
+        let mut t_max = t_max;
+        for (i, (&center, &radius)) in self.centers.iter().zip(self.radii.iter()).enumerate() {
+            let (hit, t) = sphere_hit_t4(center, radius, rays, dir_length_sq, t_min, t_max);
+            t_max = Lanes::select(hit, t, t_max);
+            for (lane, &hit) in hit.iter().enumerate() {
+                if hit {
+                    closest[lane] = Some(i);
+                }
+            }
+        }
+        rays.map(|lane, _ray| closest[lane].map(|i| (i, t_max.0[lane])))
+    }
+
+    fn material(&self, i: usize) -> &Arc<dyn Material> {
+        &self.palette[self.materials[i] as usize]
+    }
+}
+
+impl Hitable for SphereSet {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, _rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let (i, t) = self.closest(ray, t_min, t_max)?;
+        Some(sphere_record(self.centers[i], self.radii[i], self.material(i), ray, t))
+    }
+
+    fn hit_packet(&self, rays: &RayPacket4, t_min: Lanes, t_max: Lanes, _rngs: &mut LaneRngs)
+        -> [Option<HitRecord>; 4]
+    {
+        let closest = self.closest_packet(rays, t_min, t_max);
+        rays.map(|lane, ray| {
+            let (i, t) = closest[lane]?;
+            Some(sphere_record(self.centers[i], self.radii[i], self.material(i), ray, t))
+        })
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore) -> bool {
+        crate::stats::count(|c| c.sphere_tests += self.len() as u64);
+        // Any opaque sphere in the way is enough. Cutouts need the closest hit
+        // to roll the dice on, one after another.
+        let mut cutouts = false;
+        for (i, (&center, &radius)) in self.centers.iter().zip(self.radii.iter()).enumerate() {
+            if sphere_hit_t(center, radius, ray, t_min, t_max).is_some() {
+                if self.material(i).is_opaque() {
+                    return true;
+                }
+                cutouts = true;
+            }
+        }
+        cutouts && hit_any_through_cutouts(self, ray, t_min, t_max, rng)
+    }
+
+    // Nothing in the set moves, so we ignore the times.
+    fn bounding_box(&self, _t0: Float, _t1: Float) -> Option<Aabb> {
+        if self.is_empty() {
+            return None;
+        }
+        let bbox = |i: usize| {
+            let r = Float3::xxx(self.radii[i]).abs();
+            Aabb {
+                min: self.centers[i] - r,
+                max: self.centers[i] + r,
+            }
+        };
+        Some((1..self.len()).fold(bbox(0), |acc, i| Aabb::surrounding(&acc, &bbox(i))))
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        for material in self.palette.iter_mut() {
+            *material = f(material);
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::hitable::HitableList;
+    use crate::material::{
+        Lambertian,
+        Metal,
+    };
+
+    /// `n` spheres in a few materials, some of which overlap.
+    fn random_spheres(n: usize) -> Vec<Sphere> {
+        let materials: Vec<Arc<dyn Material>> = vec![
+            Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+            Arc::new(Metal { albedo: Float3::xxx(0.8), fuzz: 0.0 }),
+            Arc::new(Lambertian { albedo: Float3::xyz(0.9, 0.1, 0.1) }),
+        ];
+        (0..n)
+            .map(|i| Sphere {
+                center: 10.0 * Float3::xyz(random_sfloat(),
+                                           random_sfloat(),
+                                           random_sfloat()),
+                radius: 0.1 + random_float(),
+                material: materials[i % materials.len()].clone(),
+            })
+            .collect()
+    }
+
+    fn random_ray() -> Ray {
+        Ray {
+            origin: 15.0 * Float3::xyz(random_sfloat(), random_sfloat(), random_sfloat()),
+            dir:    random_unit_vector(),
+            t:      0.0,
+        }
+    }
+
+    fn assert_same_hit(ours: Option<HitRecord>, theirs: Option<HitRecord>, ray: &Ray) {
+        match (ours, theirs) {
+            (None, None) => {},
+            (Some(ours), Some(theirs)) => {
+                assert_eq!(ours.t, theirs.t, "{:?}", ray);
+                assert_eq!(ours.p, theirs.p, "{:?}", ray);
+                assert_eq!(ours.normal, theirs.normal, "{:?}", ray);
+                assert_eq!((ours.u, ours.v), (theirs.u, theirs.v), "{:?}", ray);
+                assert!(Arc::ptr_eq(&ours.material, &theirs.material), "{:?}", ray);
+            },
+            (ours, theirs) => {
+                panic!("{:?}: set hit {:?}, but expected {:?}", ray, ours, theirs);
+            },
+        }
+    }
+
+    #[test]
+    fn check_same_hits_as_a_list() {
+        let mut rng = seeded_rng(0);
+        let spheres = random_spheres(300);
+        let list = HitableList {
+            hitables: spheres.iter()
+                             .map(|s| Box::new(s.clone()) as Box<dyn Hitable>)
+                             .collect(),
+        };
+        let set = SphereSet::from_spheres(spheres);
+        assert_eq!(set.len(), 300);
+        assert_eq!(set.palette.len(), 3);
+        assert_eq!(set.bounding_box(0.0, 0.0), list.bounding_box(0.0, 0.0));
+
+        let bvh = Bvh::new(set.split(8)
+                              .into_iter()
+                              .map(|set| Box::new(set) as Box<dyn Hitable>)
+                              .collect(),
+                           0.0,
+                           0.0);
+        for _ in 0..2000 {
+            let ray = random_ray();
+            let expected = list.hit(&ray, 1e-3, FLOAT_MAX, &mut rng);
+            assert_same_hit(set.hit(&ray, 1e-3, FLOAT_MAX, &mut rng), expected.clone(), &ray);
+            assert_same_hit(bvh.hit(&ray, 1e-3, FLOAT_MAX, &mut rng), expected, &ray);
+            let expected = list.hit_any(&ray, 1e-3, 5.0, &mut rng);
+            assert_eq!(set.hit_any(&ray, 1e-3, 5.0, &mut rng), expected, "{:?}", ray);
+        }
+    }
+
+    #[test]
+    fn check_split() {
+        let set = SphereSet::from_spheres(random_spheres(100));
+        let parts = set.split(16);
+        assert!(parts.iter().all(|part| !part.is_empty() && part.len() <= 16));
+
+        // Every sphere is still there, with its material.
+        let key = |s: Sphere| format!("{:?} {:?} {:p}", s.center, s.radius, s.material);
+        let mut before: Vec<String> = set.spheres().map(key).collect();
+        let mut after: Vec<String> = parts.iter()
+                                          .flat_map(|part| part.spheres())
+                                          .map(key)
+                                          .collect();
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+
+        assert!(SphereSet::new().split(16).is_empty());
+        assert_eq!(SphereSet::new().bounding_box(0.0, 0.0), None);
+    }
+}
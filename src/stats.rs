@@ -0,0 +1,351 @@
+//! Counting rays, for `--verbose`, and everything else we know about a
+//! render, for `--stats-json`.
+//!
+//! Each thread counts into its own `Counts`, which costs about as much as the
+//! check that counting is on. Render threads merge theirs into the totals
+//! after every row, so nothing is shared while tracing.
+
+use std::{
+    cell::Cell,
+    fmt,
+    fs,
+    io,
+    path,
+    sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static LOCAL: Cell<Counts> = Cell::new(Counts::default());
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Counts {
+    /// Rays from the camera: one per sample.
+    pub primary_rays:   u64,
+    /// Rays that a material scattered, after the primary ray.
+    pub scattered_rays: u64,
+    /// Rays sent toward a light, to check that nothing is in the way.
+    pub shadow_rays:    u64,
+    /// Paths still going when they hit the max depth.
+    pub capped_paths:   u64,
+    /// Calls to `Sphere::hit`.
+    pub sphere_tests:   u64,
+    /// Samples that were traced for AOVs too. See `--aov-stride`.
+    #[serde(default)]
+    pub aov_samples:    u64,
+}
+
+impl Counts {
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.scattered_rays + self.shadow_rays
+    }
+
+    /// Every scattered ray is one more bounce on some path.
+    pub fn average_bounces(&self) -> f64 {
+        if self.primary_rays == 0 {
+            return 0.0;
+        }
+        self.scattered_rays as f64 / self.primary_rays as f64
+    }
+
+    pub fn add(&mut self, other: &Counts) {
+        self.primary_rays += other.primary_rays;
+        self.scattered_rays += other.scattered_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.capped_paths += other.capped_paths;
+        self.sphere_tests += other.sphere_tests;
+        self.aov_samples += other.aov_samples;
+    }
+
+    /// A report for a render that took `secs`.
+    pub fn report(&self, secs: f64) -> Report {
+        Report {
+            counts: *self,
+            secs,
+        }
+    }
+}
+
+pub struct Report {
+    counts: Counts,
+    secs:   f64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.counts;
+        writeln!(f, "Primary rays:         {}", c.primary_rays)?;
+        writeln!(f, "Scattered rays:       {}", c.scattered_rays)?;
+        writeln!(f, "Shadow rays:          {}", c.shadow_rays)?;
+        writeln!(f, "Average bounce depth: {:.3}", c.average_bounces())?;
+        writeln!(f, "Paths at depth cap:   {}", c.capped_paths)?;
+        writeln!(f, "Ray-sphere tests:     {}", c.sphere_tests)?;
+        if c.aov_samples > 0 {
+            writeln!(f, "Samples with AOVs:    {}", c.aov_samples)?;
+        }
+        if self.secs > 0.0 {
+            write!(f, "Rays/second:          {:.0}", c.total_rays() as f64 / self.secs)
+        } else {
+            write!(f, "Rays/second:          -")
+        }
+    }
+}
+
+/// How long one tile took.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileTime {
+    pub tile_id:   u32,
+    /// Where the tile is in the tile grid.
+    pub x:         u32,
+    pub y:         u32,
+    /// Time spent rendering the tile's rows, added up over every thread
+    /// that worked on it.
+    pub secs:      f64,
+    /// A hash of the tile's pixels. See `determinism::hash_tile()`.
+    #[serde(default)]
+    pub hash:      u64,
+    #[serde(default)]
+    pub pixels:    u32,
+    /// Wall clock time from when the tile's first row was started to when
+    /// its last one was finished.
+    #[serde(default)]
+    pub wall_secs: f64,
+}
+
+/// Everything `--stats-json` writes out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RenderStats {
+    pub width:             u32,
+    pub height:            u32,
+    pub samples_per_pixel: u32,
+    pub tiles_x:           u32,
+    pub tiles_y:           u32,
+    pub threads:           u32,
+    pub seed:              u64,
+    /// Wall clock time, from the first row started to the last one finished.
+    pub render_secs:       f64,
+    /// Only the tiles we rendered: `--checkerboard-tiles` skips some.
+    pub tiles:             Vec<TileTime>,
+    /// These are only counted when we're asked for them, and are zero otherwise.
+    pub counts:            Counts,
+    /// Whether Ctrl+C stopped the render before it finished.
+    pub interrupted:       bool,
+    /// The build that rendered this, as `--version` puts it.
+    #[serde(default)]
+    pub build:             String,
+}
+
+impl RenderStats {
+    /// Adds in another render of the same image, like another layer of a focus stack.
+    pub fn add_layer(&mut self, other: &RenderStats) {
+        self.render_secs += other.render_secs;
+        self.counts.add(&other.counts);
+        for (tile, other) in self.tiles.iter_mut().zip(other.tiles.iter()) {
+            tile.secs += other.secs;
+            tile.wall_secs += other.wall_secs;
+        }
+        self.interrupted |= other.interrupted;
+    }
+
+    /// A table of how long each tile took, for `--tile-times`.
+    pub fn tile_report(&self) -> TileReport<'_> {
+        TileReport {
+            tiles: &self.tiles,
+        }
+    }
+
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+pub struct TileReport<'a> {
+    tiles: &'a [TileTime],
+}
+
+impl<'a> fmt::Display for TileReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Tile  Grid        Pixels  Wall (s)  Busy (s)  Pixels/s")?;
+        for tile in self.tiles {
+            let rate = if tile.wall_secs > 0.0 {
+                format!("{:.0}", tile.pixels as f64 / tile.wall_secs)
+            } else {
+                "-".to_string()
+            };
+            writeln!(f, "{:>4}  {:<10} {:>7}  {:>8.3}  {:>8.3}  {:>8}",
+                     tile.tile_id,
+                     format!("({}, {})", tile.x, tile.y),
+                     tile.pixels,
+                     tile.wall_secs,
+                     tile.secs,
+                     rate)?;
+        }
+        if self.tiles.is_empty() {
+            return write!(f, "No tiles were rendered");
+        }
+        let walls = self.tiles.iter().map(|t| t.wall_secs);
+        let min = walls.clone().fold(std::f64::INFINITY, f64::min);
+        let max = walls.clone().fold(0.0, f64::max);
+        let mean = walls.sum::<f64>() / self.tiles.len() as f64;
+        write!(f, "Wall time: min {:.3}s, max {:.3}s, mean {:.3}s", min, max, mean)?;
+        if min > 0.0 {
+            write!(f, ", the slowest tile took {:.1}x as long as the fastest", max / min)?;
+        }
+        Ok(())
+    }
+}
+
+/// Totals from every thread that has called `flush()`.
+struct Totals {
+    primary_rays:   AtomicU64,
+    scattered_rays: AtomicU64,
+    shadow_rays:    AtomicU64,
+    capped_paths:   AtomicU64,
+    sphere_tests:   AtomicU64,
+    aov_samples:    AtomicU64,
+}
+
+static TOTALS: Totals = Totals {
+    primary_rays:   AtomicU64::new(0),
+    scattered_rays: AtomicU64::new(0),
+    shadow_rays:    AtomicU64::new(0),
+    capped_paths:   AtomicU64::new(0),
+    sphere_tests:   AtomicU64::new(0),
+    aov_samples:    AtomicU64::new(0),
+};
+
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Updates this thread's counts, if counting is on.
+#[inline]
+pub fn count<F: FnOnce(&mut Counts)>(f: F) {
+    if is_enabled() {
+        LOCAL.with(|local| {
+            let mut counts = local.get();
+            f(&mut counts);
+            local.set(counts);
+        });
+    }
+}
+
+/// This thread's counts since the last `flush()` or `take_local()`.
+pub fn take_local() -> Counts {
+    LOCAL.with(|local| local.replace(Counts::default()))
+}
+
+/// Moves this thread's counts into the totals.
+pub fn flush() {
+    let local = take_local();
+    if local == Counts::default() {
+        return;
+    }
+    TOTALS.primary_rays.fetch_add(local.primary_rays, Ordering::Relaxed);
+    TOTALS.scattered_rays.fetch_add(local.scattered_rays, Ordering::Relaxed);
+    TOTALS.shadow_rays.fetch_add(local.shadow_rays, Ordering::Relaxed);
+    TOTALS.capped_paths.fetch_add(local.capped_paths, Ordering::Relaxed);
+    TOTALS.sphere_tests.fetch_add(local.sphere_tests, Ordering::Relaxed);
+    TOTALS.aov_samples.fetch_add(local.aov_samples, Ordering::Relaxed);
+}
+
+/// Everything flushed so far, leaving the totals at zero.
+pub fn take_totals() -> Counts {
+    Counts {
+        primary_rays:   TOTALS.primary_rays.swap(0, Ordering::Relaxed),
+        scattered_rays: TOTALS.scattered_rays.swap(0, Ordering::Relaxed),
+        shadow_rays:    TOTALS.shadow_rays.swap(0, Ordering::Relaxed),
+        capped_paths:   TOTALS.capped_paths.swap(0, Ordering::Relaxed),
+        sphere_tests:   TOTALS.sphere_tests.swap(0, Ordering::Relaxed),
+        aov_samples:    TOTALS.aov_samples.swap(0, Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_counts_stay_on_their_thread() {
+        enable(true);
+        let counts = std::thread::spawn(|| {
+            for _ in 0..10 {
+                count(|c| c.primary_rays += 1);
+            }
+            count(|c| c.scattered_rays += 25);
+            take_local()
+        }).join().unwrap();
+
+        assert_eq!(counts.primary_rays, 10);
+        assert_eq!(counts.scattered_rays, 25);
+        assert_eq!(counts.average_bounces(), 2.5);
+        assert_eq!(counts.total_rays(), 35);
+        assert_eq!(take_local(), Counts::default());
+    }
+
+    #[test]
+    fn check_report() {
+        let counts = Counts {
+            primary_rays:   100,
+            scattered_rays: 150,
+            shadow_rays:    50,
+            capped_paths:   2,
+            sphere_tests:   1234,
+            aov_samples:    0,
+        };
+        let report = counts.report(2.0).to_string();
+        assert!(report.contains("Average bounce depth: 1.500"), "{}", report);
+        assert!(report.contains("Rays/second:          150"), "{}", report);
+        assert!(report.contains("Ray-sphere tests:     1234"), "{}", report);
+    }
+
+    #[test]
+    fn check_tile_report() {
+        let tile = |tile_id, wall_secs| TileTime {
+            tile_id,
+            x:         tile_id % 2,
+            y:         tile_id / 2,
+            secs:      wall_secs,
+            hash:      0,
+            pixels:    100,
+            wall_secs,
+        };
+        let stats = RenderStats {
+            width:             20,
+            height:            10,
+            samples_per_pixel: 1,
+            tiles_x:           2,
+            tiles_y:           2,
+            threads:           1,
+            seed:              0,
+            render_secs:       1.0,
+            tiles:             vec![tile(0, 0.1), tile(1, 0.5), tile(2, 0.2), tile(3, 0.2)],
+            counts:            Counts::default(),
+            interrupted:       false,
+            build:             String::new(),
+        };
+        let report = stats.tile_report().to_string();
+        assert!(report.contains("   1  (1, 0)         100     0.500     0.500       200\n"),
+                "{}", report);
+        assert!(report.contains("min 0.100s, max 0.500s, mean 0.250s"), "{}", report);
+        assert!(report.contains("5.0x as long"), "{}", report);
+    }
+}
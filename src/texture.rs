@@ -0,0 +1,334 @@
+//! Textures, and baking expensive ones down to images.
+//!
+//! Procedural textures can cost more than everything else about shading a
+//! hit. When an object asks for its texture to be baked, we evaluate the
+//! texture once over the object's texture coordinates before rendering, and
+//! look the result up in an `ImageTexture` from then on.
+//!
+//! Textures that depend on the position of the hit (rather than just its
+//! texture coordinates) need to know where each texel lands on the object,
+//! so we can only bake those onto spheres.
+
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::hitable::sphere_point;
+use crate::prelude::*;
+
+pub trait Texture: std::fmt::Debug + Send + Sync {
+    /// Color of the texture at texture coordinates (`u`, `v`), which are on
+    /// the surface at `p`.
+    fn value(&self, u: Float, v: Float, p: &Float3) -> Float3;
+
+    /// Whether `value()` looks at `p`.
+    fn uses_position(&self) -> bool {
+        false
+    }
+}
+
+/// A plain color is a texture that's the same everywhere.
+impl Texture for Float3 {
+    fn value(&self, _u: Float, _v: Float, _p: &Float3) -> Float3 {
+        *self
+    }
+}
+
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn value(&self, u: Float, v: Float, p: &Float3) -> Float3 {
+        (**self).value(u, v, p)
+    }
+
+    fn uses_position(&self) -> bool {
+        (**self).uses_position()
+    }
+}
+
+/// Alternating squares of two colors, `checks` of them along each of u and v.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CheckerTexture {
+    pub even:   Float3,
+    pub odd:    Float3,
+    pub checks: u32,
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: Float, v: Float, _p: &Float3) -> Float3 {
+        let i = (u * self.checks as Float).floor() as i64;
+        let j = (v * self.checks as Float).floor() as i64;
+        if (i + j) & 1 == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+/// Marble-like veins of `color`, made from `octaves` layers of noise.
+/// Every octave adds detail, and costs as much as all of the others combined.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoiseTexture {
+    pub color:   Float3,
+    pub scale:   Float,
+    pub octaves: u32,
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: Float, _v: Float, p: &Float3) -> Float3 {
+        let veins = (self.scale * p.z + 10.0 * turbulence(*p * self.scale, self.octaves)).sin();
+        self.color * 0.5 * (1.0 + veins)
+    }
+
+    fn uses_position(&self) -> bool {
+        true
+    }
+}
+
+/// Smoothly interpolated random values on the integer lattice, in [0, 1].
+fn noise(p: Float3) -> Float {
+    let lattice = |x: i64, y: i64, z: i64| {
+        let hash = splitmix64(splitmix64(splitmix64(x as u64) ^ y as u64) ^ z as u64);
+        (hash >> 11) as Float / (1_u64 << 53) as Float
+    };
+    let smooth = |t: Float| t * t * (3.0 - 2.0 * t);
+
+    let (x, y, z) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (fx, fy, fz) = (smooth(p.x - x), smooth(p.y - y), smooth(p.z - z));
+    let (x, y, z) = (x as i64, y as i64, z as i64);
+
+    let mut sum = 0.0;
+    for &(dx, dy, dz) in [(0, 0, 0), (1, 0, 0), (0, 1, 0), (1, 1, 0),
+                          (0, 0, 1), (1, 0, 1), (0, 1, 1), (1, 1, 1)].iter()
+    {
+        let weight = (if dx == 1 { fx } else { 1.0 - fx }) *
+                     (if dy == 1 { fy } else { 1.0 - fy }) *
+                     (if dz == 1 { fz } else { 1.0 - fz });
+        sum += weight * lattice(x + dx, y + dy, z + dz);
+    }
+    sum
+}
+
+/// Noise summed over `octaves` frequencies, each double the last at half the weight.
+pub fn turbulence(p: Float3, octaves: u32) -> Float {
+    let mut sum = 0.0;
+    let mut p = p;
+    let mut weight = 1.0;
+    for _ in 0..octaves {
+        sum += weight * (2.0 * noise(p) - 1.0).abs();
+        p *= 2.0;
+        weight *= 0.5;
+    }
+    sum
+}
+
+/// A grid of colors, blended between texel centers.
+/// Texel (`x`, `y`) is centered at u = (x + 0.5) / width, v = (y + 0.5) / height.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageTexture {
+    width:  u32,
+    height: u32,
+    texels: Vec<Float3>,
+}
+
+impl ImageTexture {
+    /// A texture from an 8-bit image, with the top row at v = 1.
+    /// Images are gamma corrected the same way we write them, so we undo
+    /// that to get back to linear color.
+    pub fn from_rgb8(img: &image::RgbImage) -> ImageTexture {
+        let (width, height) = img.dimensions();
+        let mut texels = Vec::with_capacity(width as usize * height as usize);
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let [r, g, b] = img.get_pixel(x, y).data;
+                let display = Float3::xyz(r as Float, g as Float, b as Float) / 255.0;
+                texels.push(display * display);
+            }
+        }
+        ImageTexture {
+            width,
+            height,
+            texels,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Float3 {
+        let x = x.max(0).min(self.width as i64 - 1) as usize;
+        let y = y.max(0).min(self.height as i64 - 1) as usize;
+        self.texels[y * self.width as usize + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: Float, v: Float, _p: &Float3) -> Float3 {
+        let x = u * self.width as Float - 0.5;
+        let y = v * self.height as Float - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = Float3::lerp(fx, self.texel(x0, y0), self.texel(x0 + 1, y0));
+        let bottom = Float3::lerp(fx, self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1));
+        Float3::lerp(fy, top, bottom)
+    }
+}
+
+/// How to bake a texture, given where it's bound to an object.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bake {
+    /// Texels along each of u and v.
+    pub resolution: u32,
+}
+
+/// The surface a texture is baked over.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UvDomain {
+    /// A sphere, where every (u, v) has a known position. See `sphere_uv()`.
+    Sphere {
+        center: Float3,
+        radius: Float,
+    },
+    /// Any other surface. We don't know where (u, v) is on these.
+    Surface,
+}
+
+/// Evaluates `texture` once for every texel of an image over `domain`.
+pub fn bake(texture: &dyn Texture, domain: UvDomain, bake: Bake) -> Result<ImageTexture, String> {
+    if bake.resolution == 0 {
+        return Err("Baking needs a resolution of at least 1".into());
+    }
+    if texture.uses_position() && domain == UvDomain::Surface {
+        return Err(format!("{:?} depends on position, so it can only be baked onto a sphere",
+                           texture));
+    }
+
+    let n = bake.resolution;
+    let texels = (0..n * n)
+        .into_par_iter()
+        .map(|i| {
+            let u = ((i % n) as Float + 0.5) / n as Float;
+            let v = ((i / n) as Float + 0.5) / n as Float;
+            let p = match domain {
+                UvDomain::Sphere { center, radius } => center + radius * sphere_point(u, v),
+                // Nothing we bake here looks at this.
+                UvDomain::Surface => Float3::new(),
+            };
+            texture.value(u, v, &p)
+        })
+        .collect();
+
+    Ok(ImageTexture {
+        width:  n,
+        height: n,
+        texels,
+    })
+}
+
+/// The texture to render `domain` with: `texture` itself, or a baked copy of it
+/// if the binding asks for one.
+pub fn bind(texture: Arc<dyn Texture>, domain: UvDomain, bake_with: Option<Bake>)
+    -> Result<Arc<dyn Texture>, String>
+{
+    match bake_with {
+        None => Ok(texture),
+        Some(settings) => Ok(Arc::new(bake(&*texture, domain, settings)?)),
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::hitable::sphere_uv;
+
+    fn checker() -> CheckerTexture {
+        CheckerTexture {
+            even:   Float3::xyz(0.9, 0.9, 0.9),
+            odd:    Float3::xyz(0.2, 0.3, 0.1),
+            checks: 8,
+        }
+    }
+
+    #[test]
+    fn check_sphere_uv_roundtrip() {
+        for j in 1..16 {
+            for i in 0..32 {
+                let (u, v) = ((i as Float + 0.5) / 32., j as Float / 16.);
+                let p = sphere_point(u, v);
+                assert!((p.length() - 1.0).abs() < 1e-6);
+                let (u2, v2) = sphere_uv(&p);
+                assert!((u - u2).abs() < 1e-6 && (v - v2).abs() < 1e-6,
+                        "({}, {}) came back as ({}, {})", u, v, u2, v2);
+            }
+        }
+    }
+
+    #[test]
+    fn check_baked_checker_matches() {
+        let checker = checker();
+        let baked = bake(&checker, UvDomain::Surface, Bake { resolution: 512 }).unwrap();
+        assert_eq!((baked.width(), baked.height()), (512, 512));
+
+        // Texel centers are exact.
+        let u = 100.5 / 512.;
+        let v = 301.5 / 512.;
+        assert_eq!(baked.value(u, v, &Float3::new()), checker.value(u, v, &Float3::new()));
+
+        // Everywhere else only blurs the edges of the checks a little.
+        let n = 1000;
+        let mut error = 0.0;
+        for _ in 0..n {
+            let (u, v) = (random_float(), random_float());
+            let diff = baked.value(u, v, &Float3::new()) - checker.value(u, v, &Float3::new());
+            error += diff.abs().as_slice().iter().sum::<Float>() / 3.0;
+        }
+        assert!(error / (n as Float) < 0.01, "MAE: {}", error / n as Float);
+    }
+
+    #[test]
+    fn check_position_textures_need_spheres() {
+        let marble = NoiseTexture {
+            color:   Float3::xxx(1.0),
+            scale:   4.0,
+            octaves: 4,
+        };
+        let settings = Bake { resolution: 16 };
+        assert!(bake(&marble, UvDomain::Surface, settings).is_err());
+
+        // On a sphere, every texel is the texture at its spot on the sphere.
+        let center = Float3::xyz(1., 2., 3.);
+        let sphere = UvDomain::Sphere { center, radius: 2. };
+        let baked = bake(&marble, sphere, settings).unwrap();
+        let (u, v) = (3.5 / 16., 10.5 / 16.);
+        let p = center + 2. * sphere_point(u, v);
+        assert!((baked.value(u, v, &p) - marble.value(u, v, &p)).length() < 1e-12);
+
+        // And without asking for it, nothing is baked.
+        let texture: Arc<dyn Texture> = Arc::new(marble);
+        assert!(bind(texture.clone(), UvDomain::Surface, None).is_ok());
+        assert!(bind(texture, UvDomain::Surface, Some(settings)).is_err());
+    }
+
+    #[test]
+    fn check_image_texture_orientation() {
+        // Red on top, gray on the bottom.
+        let img = image::RgbImage::from_fn(1, 2, |_, y| {
+            if y == 0 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([128, 128, 128])
+            }
+        });
+        let texture = ImageTexture::from_rgb8(&img);
+        assert_eq!(texture.value(0.5, 0.75, &Float3::new()), Float3::xyz(1., 0., 0.));
+        // Back in linear color, mid gray is about a quarter.
+        let gray = texture.value(0.5, 0.25, &Float3::new());
+        assert!((gray.x - 0.252).abs() < 1e-3, "{:?}", gray);
+    }
+}
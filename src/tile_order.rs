@@ -0,0 +1,184 @@
+//! The order that tiles start rendering in.
+//!
+//! The tiles with the interesting parts of the image are usually in the
+//! middle, so starting there makes snapshots and the preview window useful
+//! much sooner.
+
+use std::str::FromStr;
+
+use crate::math::splitmix64;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TileOrder {
+    /// Left to right, top to bottom.
+    Scanline,
+    /// Outwards from the center of the image.
+    Spiral,
+    /// Shuffled with the render's seed.
+    Random,
+    /// Along a Hilbert curve, so each tile is next to the one before it.
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const ALL: &'static [TileOrder] = &[
+        TileOrder::Scanline,
+        TileOrder::Spiral,
+        TileOrder::Random,
+        TileOrder::Hilbert,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TileOrder::Scanline => "scanline",
+            TileOrder::Spiral   => "spiral",
+            TileOrder::Random   => "random",
+            TileOrder::Hilbert  => "hilbert",
+        }
+    }
+
+    /// Every tile id of a `tiles_x` by `tiles_y` grid, in the order to render them.
+    /// Tile ids count across each row, then down.
+    pub fn tile_ids(self, tiles_x: u32, tiles_y: u32, seed: u64) -> Vec<u32> {
+        let mut ids: Vec<u32> = (0..tiles_x * tiles_y).collect();
+        let coords = |id: u32| (id % tiles_x, id / tiles_x);
+
+        match self {
+            TileOrder::Scanline => {},
+            TileOrder::Spiral => {
+                // Measured in tiles, from the center of the grid.
+                let offset = |id: u32| {
+                    let (x, y) = coords(id);
+                    (x as f64 + 0.5 - tiles_x as f64 / 2.0,
+                     y as f64 + 0.5 - tiles_y as f64 / 2.0)
+                };
+                // Tiles just as far out go around in a circle.
+                let key = |id: u32| {
+                    let (dx, dy) = offset(id);
+                    (dx * dx + dy * dy, dy.atan2(dx))
+                };
+                ids.sort_by(|&a, &b| key(a).partial_cmp(&key(b)).unwrap());
+            },
+            TileOrder::Random => {
+                // Fisher-Yates
+                let mut state = seed;
+                for i in (1..ids.len()).rev() {
+                    state = splitmix64(state);
+                    let j = (state % (i as u64 + 1)) as usize;
+                    ids.swap(i, j);
+                }
+            },
+            TileOrder::Hilbert => {
+                let side = tiles_x.max(tiles_y).next_power_of_two();
+                ids.sort_by_key(|&id| {
+                    let (x, y) = coords(id);
+                    hilbert_index(side, x, y)
+                });
+            },
+        }
+
+        ids
+    }
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TileOrder, String> {
+        TileOrder::ALL
+            .iter()
+            .cloned()
+            .find(|order| order.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = TileOrder::ALL.iter().map(|o| o.name()).collect();
+                format!("Unknown tile order \"{}\". Expected one of: {}",
+                        s, names.join(", "))
+            })
+    }
+}
+
+/// How far along a Hilbert curve filling a `side` by `side` grid (x, y) is.
+/// `side` must be a power of two.
+/// See: https://en.wikipedia.org/wiki/Hilbert_curve
+fn hilbert_index(side: u32, x: u32, y: u32) -> u64 {
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = side as u64 / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant, so that the curve lines up with the last one.
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_every_tile_once() {
+        for &order in TileOrder::ALL {
+            for &(tiles_x, tiles_y) in [(1, 1), (4, 4), (8, 3), (5, 7), (16, 9)].iter() {
+                let mut ids = order.tile_ids(tiles_x, tiles_y, 0x5eed);
+                ids.sort();
+                let expected: Vec<u32> = (0..tiles_x * tiles_y).collect();
+                assert_eq!(ids, expected, "{:?} on {}x{}", order, tiles_x, tiles_y);
+            }
+        }
+    }
+
+    #[test]
+    fn check_spiral_starts_in_the_center() {
+        // 5x3 has a single center tile: (2, 1).
+        let ids = TileOrder::Spiral.tile_ids(5, 3, 0);
+        assert_eq!(ids[0], 1 * 5 + 2);
+        // The corners are last.
+        let corners = [0, 4, 10, 14];
+        for id in ids[ids.len() - 4..].iter() {
+            assert!(corners.contains(id), "{:?}", ids);
+        }
+
+        // 4x4 has four tiles around the center, which all go first.
+        let ids = TileOrder::Spiral.tile_ids(4, 4, 0);
+        let mut center = ids[..4].to_vec();
+        center.sort();
+        assert_eq!(center, [5, 6, 9, 10]);
+    }
+
+    #[test]
+    fn check_hilbert_steps_to_neighbors() {
+        let ids = TileOrder::Hilbert.tile_ids(8, 8, 0);
+        for pair in ids.windows(2) {
+            let (ax, ay) = (pair[0] % 8, pair[0] / 8);
+            let (bx, by) = (pair[1] % 8, pair[1] / 8);
+            let steps = (ax as i32 - bx as i32).abs() + (ay as i32 - by as i32).abs();
+            assert_eq!(steps, 1, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn check_random_follows_the_seed() {
+        let a = TileOrder::Random.tile_ids(8, 8, 1);
+        assert_eq!(a, TileOrder::Random.tile_ids(8, 8, 1));
+        assert!(a != TileOrder::Random.tile_ids(8, 8, 2));
+        assert!(a != TileOrder::Scanline.tile_ids(8, 8, 1));
+    }
+
+    #[test]
+    fn check_names_round_trip() {
+        for &order in TileOrder::ALL {
+            assert_eq!(order.name().parse::<TileOrder>(), Ok(order));
+        }
+        assert!("zigzag".parse::<TileOrder>().is_err());
+    }
+}
@@ -0,0 +1,877 @@
+//! Splitting an image up into tiles, and rendering them.
+//!
+//! Tiles are what `--tile-order` orders, what `--checkerboard-tiles` and
+//! `--tile-range` pick between, and what `--stats-json` times and hashes.
+//! Rows of tiles are the unit of work, though: threads take the next row from
+//! the front of a queue, so that a tile full of glass is shared out between
+//! them instead of left to one thread to finish alone.
+
+use std::{
+    sync::{
+        atomic,
+        Mutex,
+    },
+    time,
+};
+
+use image::{
+    GenericImage,
+    Rgb,
+    RgbImage,
+};
+use rayon::prelude::*;
+
+use crate::aov::{
+    self,
+    PixelAovs,
+};
+use crate::build_info;
+use crate::camera::Camera;
+use crate::checkpoint::Accumulator;
+use crate::determinism;
+use crate::math;
+use crate::output::to_rgb8;
+use crate::prelude::*;
+use crate::render::{
+    linear_image,
+    needs_to_exit,
+    needs_to_stop,
+    render_pixel,
+    Frame,
+    RenderSettings,
+    Scene,
+};
+use crate::stats;
+use crate::tile_order::TileOrder;
+
+/// A subset of our final image.
+/// Tiles do not know about other tiles, but they do know their x offsets.
+#[derive(Clone, Debug)]
+pub struct Tile {
+    // Unique id for each tile
+    pub tile_id: u32,
+    // x coordinate of tile, in the tile grid
+    pub tile_x: u32,
+    // y coordinate of tile, in the tile grid
+    pub tile_y: u32,
+    // x offset into the parent image
+    pub offset_x: u32,
+    // y-offset into the parent image
+    pub offset_y: u32,
+    // Pixel data for the sub image.
+    // This is owned by the tile, and copied out to the parent image later.
+    pub pixels: RgbImage,
+    // Pixel data for each requested AOV, in the same order as `PixelAovs::aovs()`.
+    pub aovs: Vec<RgbImage>,
+    // Rows that haven't been rendered yet. The tile is done at 0.
+    pub rows_left: u32,
+    // Time spent rendering rows of this tile, over every thread.
+    pub busy: time::Duration,
+    // When the first row of this tile was started, and the last finished.
+    pub started: Option<time::Instant>,
+    pub finished: Option<time::Instant>,
+}
+
+pub fn pick_tiling_dimensions(n_tiles: u32, nx: u32, ny: u32) -> (u32, u32) {
+    let aspect: Float = (nx as Float) / (ny as Float);
+
+    // We want to create roughly square tiles, but they need to divide the
+    // image's width exactly.
+    // In the case of a square image (W == H), we could just call `.sqrt()`.
+    // More generally, we need to scale the number of tiles along one side
+    // by the aspect ratio (W/H).
+    // Here's the problem described in formula.
+    //          x := # of tiles along thex axis (width)
+    //          y := # of tiles along they axis (height)
+    //      x     == ASPECT * y;
+    //      x * y == n_tiles;
+    // Since we know `ASPECT` and `n_tiles`, we re-arrange the above as:
+    //      x    = ASPECT * y
+    //      y**2 = n_tiles / ASPECT
+    // This is enough to compute the value and round it to an integer.
+    let raw_y: f64 = (n_tiles as f64 / aspect as f64).sqrt().round();
+
+    // At this point `raw_y` is a float and might not divide the requested
+    // tile count easily. We need to decide wether to opt for more square
+    // tiles by disregarding the requested tile count, or opt for hitting
+    // the tile count but with less square tiles.
+    // We opt for respecting the requested tile count.
+    // We do this by rounding the previous raw_y value to the closest factor
+    // of the tile count.
+    let mut best_factor = 1;                // First factor
+    let mut best_error = n_tiles as f64;  // Worst possible error
+    for factor in math::factors(n_tiles) {
+        // We want to minimize "error". Here, error is defined as the
+        // ratio from our raw, ideal y with the factor in question.
+        // If the factor is *smaller*, we flip the ratio to allow this
+        // process to shorten the height of tiles, if need be.
+        let mut next_error = raw_y / factor as f64;
+        if next_error < 1.0 {
+            next_error = 1.0 / next_error;
+        }
+
+        if next_error < best_error {
+            best_factor = factor;
+            best_error = next_error;
+        }
+    }
+    let y = best_factor;
+    let x = n_tiles / y;
+    assert_eq!(n_tiles as f64 / y as f64, x as f64,
+               "Tile size calculation should be exact, integer math!");
+    (x, y)
+}
+
+/// Aim for this many tiles per thread when picking the tiling ourselves.
+/// Threads that finish early steal the remaining tiles, and smaller tiles
+/// near the end of a render mean nobody waits long on the last one.
+const AUTO_TILES_PER_THREAD: u32 = 4;
+
+/// ... but keep tiles at least this many pixels on a side, or the overhead
+/// of each tile (and its progress bar) starts to add up.
+const MIN_AUTO_TILE_SIZE: u32 = 32;
+
+/// Picks a tile grid for `n_threads` to render an `nx` x `ny` image with.
+/// Since tiles don't have to divide the image evenly, we pick the number of
+/// rows and columns directly, instead of factoring a tile count.
+pub fn auto_tiling_dimensions(n_threads: u32, nx: u32, ny: u32) -> (u32, u32) {
+    let target = (AUTO_TILES_PER_THREAD * n_threads.max(1)) as Float;
+    let aspect = nx as Float / ny as Float;
+
+    // Roughly square tiles, as in `pick_tiling_dimensions()`.
+    let y = (target / aspect).sqrt().round().max(1.0);
+    let x = (target / y).ceil();
+
+    let max_x = (nx / MIN_AUTO_TILE_SIZE).max(1);
+    let max_y = (ny / MIN_AUTO_TILE_SIZE).max(1);
+    ((x as u32).min(max_x), (y as u32).min(max_y))
+}
+
+/// How an image is split up into tiles.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TilingPlan {
+    pub tiles_x:   u32,
+    pub tiles_y:   u32,
+    /// The grid we were asked for, when the image was too small for it.
+    pub requested: Option<(u32, u32)>,
+}
+
+impl TilingPlan {
+    /// Splits an `nx` x `ny` image into `n_tiles`, or picks the tiling for
+    /// `n_threads` ourselves when `n_tiles` is 0.
+    /// Tiny images can't fit as many tiles as are asked for, so we use as
+    /// many as fit without leaving any of them empty.
+    pub fn new(n_tiles: u32, n_threads: u32, nx: u32, ny: u32) -> TilingPlan {
+        let (x, y) = if n_tiles == 0 {
+            auto_tiling_dimensions(n_threads, nx, ny)
+        } else {
+            pick_tiling_dimensions(n_tiles, nx, ny)
+        };
+        let (tiles_x, tiles_y) = (fit_tiles(x, nx), fit_tiles(y, ny));
+        TilingPlan {
+            tiles_x,
+            tiles_y,
+            requested: if (tiles_x, tiles_y) != (x, y) {
+                Some((x, y))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// What to tell the user, if we couldn't tile the image as asked.
+    pub fn warning(&self, nx: u32, ny: u32) -> Option<String> {
+        self.requested.map(|(x, y)| {
+            format!("A {}x{} image is too small for {}x{} tiles. Using {}x{} tiles instead",
+                    nx, ny, x, y, self.tiles_x, self.tiles_y)
+        })
+    }
+}
+
+/// The most tiles (up to `tiles`) that an axis `n` pixels long splits into,
+/// without any of them coming out empty in `tile_span()`.
+pub fn fit_tiles(tiles: u32, n: u32) -> u32 {
+    let tiles = tiles.max(1).min(n.max(1));
+    let size = (n + tiles - 1) / tiles;
+    if size == 0 {
+        // There aren't any pixels to split up.
+        1
+    } else {
+        // Rounding `size` up can leave the last few tiles nothing.
+        (n + size - 1) / size
+    }
+}
+
+/// The pixels that tile `i` (of `tiles`) covers along an axis `n` pixels
+/// long, as (offset, length).
+/// Tiles are rounded up in size so that they cover everything, which leaves
+/// the last one with whatever is left over. When there are more tiles than
+/// pixels, the extra tiles are empty.
+pub fn tile_span(i: u32, tiles: u32, n: u32) -> (u32, u32) {
+    let size = (n + tiles - 1) / tiles;
+    let offset = (i * size).min(n);
+    (offset, size.min(n - offset))
+}
+
+/// Each tile of the image shaded by how long it took, from the fastest to the
+/// slowest. Tiles we didn't render are left black.
+pub fn tile_time_map(render_stats: &stats::RenderStats) -> RgbImage {
+    let stats::RenderStats { width, height, tiles_x, tiles_y, .. } = *render_stats;
+    let walls = render_stats.tiles.iter().map(|t| t.wall_secs);
+    let fastest = walls.clone().fold(std::f64::INFINITY, f64::min);
+    let slowest = walls.fold(0.0, f64::max);
+    let mut map = RgbImage::new(width, height);
+    for tile in render_stats.tiles.iter() {
+        let t = if slowest > fastest {
+            (tile.wall_secs - fastest) / (slowest - fastest)
+        } else {
+            0.0
+        };
+        let color = aov::viridis(t as Float);
+        let (x0, w) = tile_span(tile.x, tiles_x, width);
+        let (y0, h) = tile_span(tile.y, tiles_y, height);
+        for y in y0..y0 + h {
+            for x in x0..x0 + w {
+                map.put_pixel(x, y, color);
+            }
+        }
+    }
+    map
+}
+
+/// Something a `TiledRender` just finished.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TileProgress {
+    /// Pixel (`x`, `y`) of the image has all of its samples.
+    Pixel { x: u32, y: u32, pixel: Rgb<u8> },
+    /// Another row of `tiles[tile]`, `pixels` wide, with `rows_left` to go.
+    Row { tile: usize, pixels: u32, rows_left: u32 },
+}
+
+/// An image split up into tiles, ready to render.
+#[derive(Clone, Debug)]
+pub struct TiledRender {
+    pub plan:  TilingPlan,
+    /// Only the tiles we're rendering, in the order that they start.
+    pub tiles: Vec<Tile>,
+    /// What every pixel renders alongside the image.
+    pub aovs:  PixelAovs,
+}
+
+impl TiledRender {
+    /// The tiles of `plan` for the image that `settings` describes, in
+    /// `order`, leaving out any that `select` turns down. `select` is given
+    /// each tile's id, and its (x, y) in the grid.
+    /// Tiles start from whatever `accum` already has, so that resuming a
+    /// render picks up where it left off.
+    pub fn new(plan:     TilingPlan,
+               settings: &RenderSettings,
+               order:    TileOrder,
+               accum:    &Accumulator,
+               aovs:     PixelAovs,
+               select:   impl Fn(u32, u32, u32) -> bool)
+        -> TiledRender
+    {
+        let (nx, ny) = (settings.width, settings.height);
+        let (tiles_x, tiles_y) = (plan.tiles_x, plan.tiles_y);
+
+        // Each tile represents a subimage, roughly (nx / tiles_x, ny / tiles_y)
+        // pixels. They are combined after ray tracing.
+        let mut tiles: Vec<Tile> = vec![];
+        for tile_id in order.tile_ids(tiles_x, tiles_y, settings.seed) {
+            // Tile coordinates. Must be translated into pixels with tile_n{x,y}.
+            let x = tile_id % tiles_x;
+            let y = tile_id / tiles_x;
+
+            // When we're set to skip tiles, don't even enqueue it.
+            if !select(tile_id, x, y) {
+                continue;
+            }
+
+            let (offset_x, tile_nx) = tile_span(x, tiles_x, nx);
+            let (offset_y, tile_ny) = tile_span(y, tiles_y, ny);
+            if tile_nx == 0 || tile_ny == 0 {
+                // The image is empty. `TilingPlan` never leaves tiles empty
+                // otherwise.
+                continue;
+            }
+
+            // Start from whatever we've already rendered, if we're resuming.
+            let pixels = RgbImage::from_fn(tile_nx, tile_ny, |tile_x, tile_y| {
+                to_rgb8(accum.get(offset_x + tile_x, offset_y + tile_y).average())
+            });
+
+            tiles.push(Tile {
+                tile_id,
+                tile_x: x,
+                tile_y: y,
+                offset_x,
+                offset_y,
+                aovs: vec![RgbImage::new(tile_nx, tile_ny); aovs.aovs().len()],
+                pixels,
+                rows_left: tile_ny,
+                busy: time::Duration::default(),
+                started: None,
+                finished: None,
+            });
+        }
+
+        TiledRender {
+            plan,
+            tiles,
+            aovs,
+        }
+    }
+
+    /// How many pixels the tiles cover between them.
+    pub fn pixels(&self) -> u64 {
+        self.tiles
+            .iter()
+            .map(|t| t.pixels.width() as u64 * t.pixels.height() as u64)
+            .sum()
+    }
+
+    /// Renders every tile, adding samples to `accum` until each of their
+    /// pixels has `samples_per_pixel` of them, and puts them together into
+    /// the image. Pixels of tiles we left out are black.
+    ///
+    /// `on_progress` hears about every pixel and row as it's finished, from
+    /// the thread that rendered it. That thread carries on once it returns,
+    /// so this is also where to wait while the render is paused.
+    pub fn render(self,
+                  scene:             &Scene,
+                  camera:            &Camera,
+                  settings:          &RenderSettings,
+                  accum:             &Accumulator,
+                  samples_per_pixel: u32,
+                  on_progress:       &(dyn Fn(TileProgress) + Sync))
+        -> Frame
+    {
+        let TiledRender { plan, mut tiles, aovs: pixel_aovs } = self;
+        let ns = samples_per_pixel;
+        let (nx, ny) = (settings.width, settings.height);
+        let n_aovs = pixel_aovs.aovs().len();
+        let before_render = time::Instant::now();
+
+        // Renders one row of a tile. Rows are the unit of work, rather than whole
+        // tiles, so that a tile full of glass can be shared between threads
+        // instead of leaving one of them to finish it alone.
+        let render_row = |i: usize, tile: &Mutex<&mut Tile>, tile_y: u32| {
+            let before_row = time::Instant::now();
+            let (offset_x, offset_y, width) = {
+                let tile = tile.lock().unwrap();
+                (tile.offset_x, tile.offset_y, tile.pixels.width())
+            };
+            let mut pixel_aovs = pixel_aovs.clone();
+            let mut row = Vec::with_capacity(width as usize);
+            let mut aov_rows = vec![vec![]; n_aovs];
+
+            for tile_x in 0..width {
+                // Adjust the (x, y) coordinates wrt our tile.
+                let px = tile_x + offset_x;
+                let py = tile_y + offset_y;
+
+                pixel_aovs.clear();
+
+                let mut sum = accum.get(px, py);
+                render_pixel(scene,
+                             camera,
+                             settings,
+                             (px, py),
+                             ns,
+                             &mut sum,
+                             Some(&mut pixel_aovs));
+                accum.set(px, py, sum);
+
+                // Average samples
+                let pixel = to_rgb8(sum.average());
+                row.push(pixel);
+                for (i, aov_row) in aov_rows.iter_mut().enumerate() {
+                    aov_row.push(pixel_aovs.to_rgb8(i));
+                }
+
+                on_progress(TileProgress::Pixel { x: px, y: py, pixel });
+                if needs_to_stop() {
+                    break;
+                }
+            }
+
+            stats::flush();
+
+            // Only hold the tile long enough to copy the row in.
+            let rows_left = {
+                let mut tile = tile.lock().unwrap();
+                for (tile_x, pixel) in row.iter().enumerate() {
+                    tile.pixels.put_pixel(tile_x as u32, tile_y, *pixel);
+                }
+                for (aov, aov_row) in tile.aovs.iter_mut().zip(aov_rows.iter()) {
+                    for (tile_x, pixel) in aov_row.iter().enumerate() {
+                        aov.put_pixel(tile_x as u32, tile_y, *pixel);
+                    }
+                }
+                tile.busy += before_row.elapsed();
+                tile.started = Some(tile.started.map_or(before_row, |started| {
+                    started.min(before_row)
+                }));
+                tile.finished = Some(time::Instant::now());
+                tile.rows_left -= 1;
+                tile.rows_left
+            };
+            on_progress(TileProgress::Row { tile: i, pixels: row.len() as u32, rows_left });
+        };
+
+        // Every thread takes the next row from the front, so that tiles start in
+        // the order we picked for them. Handing rayon the whole list would split
+        // it up between threads and start each of them somewhere in the middle.
+        {
+            let rows: Vec<(usize, u32)> = tiles
+                .iter()
+                .enumerate()
+                .flat_map(|(i, tile)| (0..tile.pixels.height()).map(move |tile_y| (i, tile_y)))
+                .collect();
+            let queue: Vec<Mutex<&mut Tile>> = tiles.iter_mut().map(Mutex::new).collect();
+            let next_row = atomic::AtomicUsize::new(0);
+            (0..rayon::current_num_threads()).into_par_iter().for_each(|_| {
+                while !needs_to_stop() {
+                    let i = next_row.fetch_add(1, atomic::Ordering::SeqCst);
+                    match rows.get(i) {
+                        Some(&(tile, tile_y)) => render_row(tile, &queue[tile], tile_y),
+                        None => break,
+                    }
+                }
+            });
+        }
+        let render_time = before_render.elapsed();
+        let secs = render_time.as_secs() as f64
+                   + render_time.subsec_millis() as f64 / 1e3;
+
+        let linear = linear_image(scene, accum, nx, ny);
+        let render_stats = stats::RenderStats {
+            width:             nx,
+            height:            ny,
+            samples_per_pixel: ns,
+            tiles_x:           plan.tiles_x,
+            tiles_y:           plan.tiles_y,
+            threads:           rayon::current_num_threads() as u32,
+            seed:              settings.seed,
+            render_secs:       secs,
+            tiles:             tiles.iter()
+                                    .map(|tile| stats::TileTime {
+                                        tile_id:   tile.tile_id,
+                                        x:         tile.tile_x,
+                                        y:         tile.tile_y,
+                                        secs:      tile.busy.as_millis() as f64 / 1e3,
+                                        hash:      determinism::hash_tile(&linear, (
+                                            tile.offset_x,
+                                            tile.offset_y,
+                                            tile.pixels.width(),
+                                            tile.pixels.height(),
+                                        )),
+                                        pixels:    tile.pixels.width() * tile.pixels.height(),
+                                        wall_secs: match (tile.started, tile.finished) {
+                                            (Some(started), Some(finished)) => {
+                                                (finished - started).as_secs_f64()
+                                            },
+                                            _ => 0.0,
+                                        },
+                                    })
+                                    .collect(),
+            counts:            stats::take_totals(),
+            interrupted:       needs_to_exit(),
+            build:             build_info::VERSION_LINE.into(),
+        };
+
+        // Combine the tiles into the final image.
+        let mut imgbuf = RgbImage::new(nx, ny);
+        let mut aovs = vec![RgbImage::new(nx, ny); n_aovs];
+        for tile in tiles {
+            let ok = imgbuf.copy_from(&tile.pixels, tile.offset_x, tile.offset_y);
+            assert_eq!(ok, true,
+                      concat!("imgbuf::copy_from() failed. ",
+                              "Is ({}, {}) out of bounds? Bounds are ({}, {})."),
+                      tile.offset_x + tile.pixels.width(),
+                      tile.offset_y + tile.pixels.height(),
+                      imgbuf.width(),
+                      imgbuf.height());
+            for (aov, tile_aov) in aovs.iter_mut().zip(tile.aovs.iter()) {
+                let ok = aov.copy_from(tile_aov, tile.offset_x, tile.offset_y);
+                assert_eq!(ok, true, "AOV copy_from() failed");
+            }
+        }
+
+        let mask = if needs_to_exit() {
+            Some(accum.mask(ns))
+        } else {
+            None
+        };
+
+        Frame {
+            image:  imgbuf,
+            linear,
+            aovs:   pixel_aovs.aovs().iter().cloned().zip(aovs).collect(),
+            mask,
+            stats:  Some(render_stats),
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bvh;
+    use crate::camera::{
+        CameraInfo,
+        Projection,
+    };
+    use crate::checkpoint::PixelSum;
+    use crate::hitable::{
+        Hitable,
+        HitableList,
+        Sphere,
+    };
+    use crate::material::Lambertian;
+    use crate::render::{
+        Background,
+        DEFAULT_MAX_DEPTH,
+    };
+    use crate::render::t::light_box_camera;
+    use crate::scenes::make_small_light_box;
+
+    /// Renders every tile of the image, with `n_tiles` of them in `order`.
+    fn render_tiled(scene:    &Scene,
+                    cam:      &Camera,
+                    settings: &RenderSettings,
+                    n_tiles:  u32,
+                    order:    TileOrder)
+        -> (Frame, Accumulator)
+    {
+        let (nx, ny) = (settings.width, settings.height);
+        let plan = TilingPlan::new(n_tiles, rayon::current_num_threads() as u32, nx, ny);
+        let accum = Accumulator::new(nx, ny);
+        let frame = TiledRender::new(plan, settings, order, &accum, PixelAovs::new(&[]),
+                                     |_, _, _| true)
+            .render(scene, cam, settings, &accum, settings.samples_per_pixel, &|_| ());
+        (frame, accum)
+    }
+
+    /// Counts how many tiles cover each pixel.
+    fn tile_coverage(n_tiles: u32, nx: u32, ny: u32) -> Vec<u32> {
+        let (tiles_x, tiles_y) = pick_tiling_dimensions(n_tiles, nx, ny);
+        assert_eq!(tiles_x * tiles_y, n_tiles);
+
+        let mut coverage = vec![0; (nx * ny) as usize];
+        for y in 0..tiles_y {
+            for x in 0..tiles_x {
+                let (offset_x, width) = tile_span(x, tiles_x, nx);
+                let (offset_y, height) = tile_span(y, tiles_y, ny);
+                for py in offset_y..(offset_y + height) {
+                    for px in offset_x..(offset_x + width) {
+                        coverage[(py * nx + px) as usize] += 1;
+                    }
+                }
+            }
+        }
+        coverage
+    }
+
+    #[test]
+    fn check_uneven_tiles() {
+        // A prime width
+        assert_eq!(tile_coverage(16, 97, 64), vec![1; 97 * 64]);
+        // A prime tile count
+        assert_eq!(tile_coverage(7, 1920, 1080), vec![1; 1920 * 1080]);
+        // More tiles than fit evenly, leaving some empty
+        assert_eq!(tile_coverage(7, 5, 3), vec![1; 5 * 3]);
+        // One pixel per tile
+        assert_eq!(tile_coverage(24, 6, 4), vec![1; 6 * 4]);
+        assert_eq!(tile_span(5, 6, 6), (5, 1));
+
+        // The last tile takes what's left.
+        assert_eq!(tile_span(0, 7, 1920), (0, 275));
+        assert_eq!(tile_span(6, 7, 1920), (1650, 270));
+    }
+
+    #[test]
+    fn check_auto_tiling() {
+        // 1080p on 16 threads gets about 64 tiles, close to square.
+        let (x, y) = auto_tiling_dimensions(16, 1920, 1080);
+        assert_eq!((x, y), (11, 6));
+        let (w, h) = (1920 / x, 1080 / y);
+        assert!(w * 10 < h * 11 && h * 10 < w * 11, "{}x{} tiles", w, h);
+
+        // Small images get fewer, bigger tiles.
+        assert_eq!(auto_tiling_dimensions(16, 64, 64), (2, 2));
+        assert_eq!(auto_tiling_dimensions(16, 40, 300), (1, 9));
+        assert_eq!(auto_tiling_dimensions(16, 1, 1), (1, 1));
+        // One thread still splits the image up a little.
+        assert_eq!(auto_tiling_dimensions(1, 1200, 800), (2, 2));
+
+        // Every pixel is still covered exactly once.
+        let (tiles_x, tiles_y) = auto_tiling_dimensions(16, 1920, 1080);
+        let mut covered = 0;
+        for y in 0..tiles_y {
+            for x in 0..tiles_x {
+                covered += tile_span(x, tiles_x, 1920).1 * tile_span(y, tiles_y, 1080).1;
+            }
+        }
+        assert_eq!(covered, 1920 * 1080);
+    }
+
+    #[test]
+    fn check_tiling_plans_fit_tiny_images() {
+        for nx in 1..=8 {
+            for ny in 1..=8 {
+                for &n_tiles in [0, 1, 2, 7, 16, 64, 97, 1000, 4096].iter() {
+                    let plan = TilingPlan::new(n_tiles, 16, nx, ny);
+                    let context = format!("{} tiles on {}x{}: {:?}", n_tiles, nx, ny, plan);
+                    assert!(1 <= plan.tiles_x && plan.tiles_x <= nx, "{}", context);
+                    assert!(1 <= plan.tiles_y && plan.tiles_y <= ny, "{}", context);
+                    assert_eq!(plan.warning(nx, ny).is_some(), plan.requested.is_some());
+                    if n_tiles == 0 {
+                        assert_eq!(plan.requested, None, "{}", context);
+                    }
+
+                    // No empty tiles, and every pixel covered exactly once.
+                    let mut coverage = vec![0; (nx * ny) as usize];
+                    for y in 0..plan.tiles_y {
+                        for x in 0..plan.tiles_x {
+                            let (offset_x, width) = tile_span(x, plan.tiles_x, nx);
+                            let (offset_y, height) = tile_span(y, plan.tiles_y, ny);
+                            assert!(width > 0 && height > 0, "{} ({}, {})", context, x, y);
+                            for py in offset_y..(offset_y + height) {
+                                for px in offset_x..(offset_x + width) {
+                                    coverage[(py * nx + px) as usize] += 1;
+                                }
+                            }
+                        }
+                    }
+                    assert_eq!(coverage, vec![1; (nx * ny) as usize], "{}", context);
+                }
+            }
+        }
+
+        // Grids that fit are left alone.
+        assert_eq!(TilingPlan::new(64, 1, 16, 16),
+                   TilingPlan { tiles_x: 8, tiles_y: 8, requested: None });
+        // One tile per pixel at most.
+        let plan = TilingPlan::new(1000, 1, 4, 2);
+        assert_eq!((plan.tiles_x, plan.tiles_y), (4, 2));
+        assert!(plan.requested.is_some());
+        // 4 tiles on 5 pixels would be 2 pixels each, with nothing for the last.
+        assert_eq!((fit_tiles(4, 5), fit_tiles(5, 5), fit_tiles(9, 0)), (3, 5, 1));
+    }
+
+    #[test]
+    fn check_explicit_tiling() {
+        // These are what we've always picked.
+        assert_eq!(pick_tiling_dimensions(16, 1200, 800), (4, 4));
+        assert_eq!(pick_tiling_dimensions(16, 1920, 1080), (4, 4));
+        assert_eq!(pick_tiling_dimensions(6, 1200, 800), (3, 2));
+        assert_eq!(pick_tiling_dimensions(7, 1200, 800), (7, 1));
+        assert_eq!(pick_tiling_dimensions(1, 1200, 800), (1, 1));
+    }
+
+    #[test]
+    fn check_more_tiles_than_pixels_renders() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             16,
+            height:            16,
+            samples_per_pixel: 1,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        for &tiles in [64, 1000].iter() {
+            let (frame, accum) = render_tiled(&scene, &cam, &settings, tiles, TileOrder::Scanline);
+
+            // Every pixel was rendered by exactly one tile, once.
+            for y in 0..settings.height {
+                for x in 0..settings.width {
+                    assert_eq!(accum.get(x, y).samples, 1, "({}, {}) with {} tiles", x, y, tiles);
+                }
+            }
+            let stats = frame.stats.unwrap();
+            assert!(stats.tiles_x <= 16 && stats.tiles_y <= 16, "{:?}", stats);
+            assert_eq!(stats.tiles.len() as u32, stats.tiles_x * stats.tiles_y);
+        }
+    }
+
+    #[test]
+    fn check_rows_render_like_pixels() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             20,
+            height:            12,
+            samples_per_pixel: 3,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let ns = 3;
+
+        let expected = Accumulator::new(settings.width, settings.height);
+        for py in 0..settings.height {
+            for px in 0..settings.width {
+                let mut sum = PixelSum::default();
+                render_pixel(&scene, &cam, &settings, (px, py), ns, &mut sum, None);
+                expected.set(px, py, sum);
+            }
+        }
+
+        // However the rows are split up and handed out, each pixel comes out the same.
+        for &(tiles, order) in [(1, TileOrder::Scanline),
+                                (6, TileOrder::Spiral),
+                                (4, TileOrder::Hilbert)].iter()
+        {
+            let plan = TilingPlan::new(tiles, 1, settings.width, settings.height);
+            let accum = Accumulator::new(settings.width, settings.height);
+            let render = TiledRender::new(plan, &settings, order, &accum, PixelAovs::new(&[]),
+                                          |_, _, _| true);
+            let (pixels, rows) = (Mutex::new(vec![]), Mutex::new(vec![]));
+            let on_progress = |progress: TileProgress| match progress {
+                TileProgress::Pixel { x, y, pixel } => pixels.lock().unwrap().push((x, y, pixel)),
+                TileProgress::Row { tile, rows_left, .. } => {
+                    rows.lock().unwrap().push((tile, rows_left))
+                },
+            };
+            let n_tiles = render.tiles.len();
+            let frame = render.render(&scene, &cam, &settings, &accum, ns, &on_progress);
+            for py in 0..settings.height {
+                for px in 0..settings.width {
+                    assert_eq!(accum.get(px, py), expected.get(px, py),
+                               "({}, {}) with {:?}", px, py, order);
+                    assert_eq!(*frame.image.get_pixel(px, py),
+                               to_rgb8(expected.get(px, py).average()));
+                }
+            }
+
+            // We heard about every pixel once, as it came out.
+            let mut pixels = pixels.into_inner().unwrap();
+            pixels.sort_by_key(|&(x, y, _)| (y, x));
+            assert_eq!(pixels.len() as u32, settings.width * settings.height);
+            for &(x, y, pixel) in pixels.iter() {
+                assert_eq!(pixel, *frame.image.get_pixel(x, y));
+            }
+            // And every tile counted its rows down to 0.
+            let rows = rows.into_inner().unwrap();
+            assert_eq!(rows.len() as u32, settings.height * plan.tiles_x);
+            for tile in 0..n_tiles {
+                assert_eq!(rows.iter().filter(|&&row| row == (tile, 0)).count(), 1,
+                           "tile {} with {:?}", tile, order);
+            }
+        }
+    }
+
+    #[test]
+    fn check_stats_json() {
+        let cam = light_box_camera();
+        let scene = make_small_light_box();
+        let settings = RenderSettings {
+            width:             20,
+            height:            12,
+            samples_per_pixel: 2,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "small-light-box".into(),
+        };
+        let path = std::env::temp_dir()
+            .join(format!("weekend-raytracing-stats-{}.json", std::process::id()));
+        let (frame, _) = render_tiled(&scene, &cam, &settings, 6, TileOrder::Scanline);
+        frame.stats.unwrap().save(&path).unwrap();
+
+        // Read it back without our types, like a script would.
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(json["width"], 20);
+        assert_eq!(json["height"], 12);
+        assert_eq!(json["samples_per_pixel"], 2);
+        assert_eq!(json["seed"], 0x5eed);
+        assert_eq!(json["threads"], rayon::current_num_threads() as u64);
+        assert_eq!(json["interrupted"], false);
+        assert_eq!(json["build"], build_info::VERSION_LINE);
+        assert!(json["render_secs"].as_f64().unwrap() >= 0.0, "{}", json);
+        for counter in ["primary_rays", "scattered_rays", "shadow_rays"].iter() {
+            assert!(json["counts"][counter].is_u64(), "{} missing from {}", counter, json);
+        }
+
+        let (tiles_x, tiles_y) = pick_tiling_dimensions(6, settings.width, settings.height);
+        assert_eq!(json["tiles_x"], tiles_x);
+        assert_eq!(json["tiles_y"], tiles_y);
+        let tiles = json["tiles"].as_array().unwrap();
+        assert_eq!(tiles.len() as u32, tiles_x * tiles_y);
+        for tile in tiles {
+            assert!(tile["x"].as_u64().unwrap() < tiles_x as u64, "{}", tile);
+            assert!(tile["y"].as_u64().unwrap() < tiles_y as u64, "{}", tile);
+            assert!(tile["secs"].as_f64().unwrap() >= 0.0, "{}", tile);
+        }
+    }
+
+    #[test]
+    fn check_tile_times_find_the_busy_quadrant() {
+        let cam = Camera::new(CameraInfo {
+            lookfrom:   Float3::xyz(0., 0., 10.),
+            lookat:     Float3::xyz(0., 0., 0.),
+            up:         Float3::xyz(0., 1., 0.),
+            projection: Projection::Perspective { vfov: 40. },
+            aspect:     1.,
+            aperature:  0.,
+            focus_dist: 10.,
+            t_start:    0.,
+            t_end:      0.,
+        });
+        // A pile of balls in the top left quarter of the view, and nothing
+        // anywhere else.
+        let hitables: Vec<Box<dyn Hitable>> = (0..64)
+            .map(|i| {
+                Box::new(Sphere {
+                    center:   Float3::xyz(-3.3 + 0.4 * (i % 8) as Float,
+                                          0.3 + 0.4 * (i / 8) as Float,
+                                          -(i % 3) as Float),
+                    radius:   0.25,
+                    material: Arc::new(Lambertian {
+                        albedo: Float3::xxx(0.9),
+                    }),
+                }) as Box<dyn Hitable>
+            })
+            .collect();
+        let scene = Scene {
+            background: Background::Black,
+            ..Scene::new(HitableList {
+                hitables: vec![Box::new(bvh::Bvh::new(hitables, 0., 0.))],
+            })
+        };
+        let settings = RenderSettings {
+            width:             32,
+            height:            32,
+            samples_per_pixel: 32,
+            max_depth:         DEFAULT_MAX_DEPTH,
+            seed:              0x5eed,
+            scene:             "busy-quadrant".into(),
+        };
+        let (frame, _) = render_tiled(&scene, &cam, &settings, 4, TileOrder::Scanline);
+        let stats = frame.stats.unwrap();
+
+        assert_eq!((stats.tiles_x, stats.tiles_y), (2, 2));
+        let slowest = stats.tiles
+                           .iter()
+                           .max_by(|a, b| a.wall_secs.partial_cmp(&b.wall_secs).unwrap())
+                           .unwrap();
+        assert_eq!((slowest.x, slowest.y), (0, 0), "{}", stats.tile_report());
+        for tile in stats.tiles.iter() {
+            assert_eq!(tile.pixels, 16 * 16);
+        }
+
+        // The busy quarter is the hottest in the map.
+        let map = tile_time_map(&stats);
+        assert_eq!(*map.get_pixel(0, 0), aov::viridis(1.0));
+        assert_ne!(*map.get_pixel(31, 31), aov::viridis(1.0));
+    }
+}
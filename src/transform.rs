@@ -0,0 +1,391 @@
+//! Rotations, and moving hitables around with them.
+//!
+//! `Mat3` is a 3x3 matrix, which is all a rotation needs. `Transform` wraps a
+//! hitable to scale, rotate, and move it. Each of `scaled()`, `rotated()`, and
+//! `translated()` happens after everything before it, about the world's
+//! origin, so rotating and then translating spins the object in place and
+//! moves it, while translating and then rotating swings it around the origin.
+//!
+//! An `Instance` is a `Transform` of a shared hitable. Placing one object many
+//! times over, each instance only adds a transform, not another copy of it.
+
+use std::{
+    ops,
+    sync::Arc,
+};
+
+use crate::hitable::{
+    Aabb,
+    Hitable,
+    Span,
+};
+use crate::prelude::*;
+
+/// A 3x3 matrix, by rows. Multiplying one by a column vector transforms it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mat3 {
+    pub rows: [Float3; 3],
+}
+
+impl Mat3 {
+    pub fn identity() -> Mat3 {
+        Mat3::diagonal(1.0)
+    }
+
+    /// `scale` times the identity.
+    pub fn diagonal(scale: Float) -> Mat3 {
+        Mat3 {
+            rows: [
+                Float3::xyz(scale, 0., 0.),
+                Float3::xyz(0., scale, 0.),
+                Float3::xyz(0., 0., scale),
+            ],
+        }
+    }
+
+    /// A rotation by `degrees` around `axis`, counterclockwise when `axis`
+    /// points toward us, like the right-hand rule. `axis` doesn't need to be
+    /// normalized, but it can't be zero.
+    pub fn from_axis_angle(axis: Float3, degrees: Float) -> Mat3 {
+        let Float3 { x, y, z } = axis.unit();
+        let (s, c) = degrees.to_radians().sin_cos();
+        let t = 1.0 - c;
+        Mat3 {
+            rows: [
+                Float3::xyz(c + x * x * t,     x * y * t - z * s, x * z * t + y * s),
+                Float3::xyz(y * x * t + z * s, c + y * y * t,     y * z * t - x * s),
+                Float3::xyz(z * x * t - y * s, z * y * t + x * s, c + z * z * t),
+            ],
+        }
+    }
+
+    /// Turns by `roll` around Z, then `pitch` around X, then `yaw` around Y,
+    /// all in degrees. With Y up and looking down -Z, like the camera, that's
+    /// tilting sideways, then up, then turning left.
+    pub fn from_euler(yaw: Float, pitch: Float, roll: Float) -> Mat3 {
+        Mat3::from_axis_angle(Float3::xyz(0., 1., 0.), yaw)
+            * Mat3::from_axis_angle(Float3::xyz(1., 0., 0.), pitch)
+            * Mat3::from_axis_angle(Float3::xyz(0., 0., 1.), roll)
+    }
+
+    pub fn column(&self, i: usize) -> Float3 {
+        Float3::xyz(self.rows[0][i], self.rows[1][i], self.rows[2][i])
+    }
+
+    pub fn transpose(&self) -> Mat3 {
+        Mat3 { rows: [self.column(0), self.column(1), self.column(2)] }
+    }
+
+    pub fn determinant(&self) -> Float {
+        let [a, b, c] = self.rows;
+        a.dot(&b.cross(&c))
+    }
+
+    /// The matrix that undoes this one, unless it flattens space and there's
+    /// no undoing it. For rotations, this is the transpose.
+    pub fn inverse(&self) -> Option<Mat3> {
+        let det = self.determinant();
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+        // The columns of the inverse are the cross products of the rows.
+        let [a, b, c] = self.rows;
+        let columns = Mat3 { rows: [b.cross(&c) / det, c.cross(&a) / det, a.cross(&b) / det] };
+        Some(columns.transpose())
+    }
+
+    pub fn transform(&self, v: Float3) -> Float3 {
+        Float3::xyz(self.rows[0].dot(&v), self.rows[1].dot(&v), self.rows[2].dot(&v))
+    }
+}
+
+/// `a * b` transforms by `b`, and then by `a`.
+impl ops::Mul<Mat3> for Mat3 {
+    type Output = Mat3;
+    fn mul(self, rhs: Mat3) -> Mat3 {
+        let row = |r: Float3| r.x * rhs.rows[0] + r.y * rhs.rows[1] + r.z * rhs.rows[2];
+        Mat3 { rows: [row(self.rows[0]), row(self.rows[1]), row(self.rows[2])] }
+    }
+}
+
+/// A hitable, scaled, rotated, and moved. See the module docs for the order.
+///
+/// Scaling is the same along every axis, so angles stay the same, and so does
+/// how likely `random_toward()` is to pick each direction: lights still work.
+#[derive(Debug)]
+pub struct Transform<H: Hitable> {
+    pub hitable: H,
+    // Points go to `linear * p + offset`, and come back by `inverse`.
+    linear:  Mat3,
+    inverse: Mat3,
+    offset:  Float3,
+}
+
+/// A placement of a hitable that can be placed many times, like a mesh
+/// that's in the scene a thousand times, but in memory once.
+pub type Instance = Transform<Arc<dyn Hitable>>;
+
+impl<H: Hitable> Transform<H> {
+    /// `hitable`, where it is.
+    pub fn new(hitable: H) -> Transform<H> {
+        Transform {
+            hitable,
+            linear:  Mat3::identity(),
+            inverse: Mat3::identity(),
+            offset:  Float3::new(),
+        }
+    }
+
+    /// Scales everything so far by `scale`, which can't be 0, around the origin.
+    pub fn scaled(self, scale: Float) -> Transform<H> {
+        assert!(scale != 0.0, "Can't scale a hitable down to nothing");
+        self.then(Mat3::diagonal(scale), Mat3::diagonal(1.0 / scale))
+    }
+
+    /// Rotates everything so far by `rotation`, around the origin.
+    /// This has to be a rotation, like one from `Mat3::from_axis_angle()`.
+    pub fn rotated(self, rotation: Mat3) -> Transform<H> {
+        self.then(rotation, rotation.transpose())
+    }
+
+    /// Moves everything so far by `offset`.
+    pub fn translated(mut self, offset: Float3) -> Transform<H> {
+        self.offset += offset;
+        self
+    }
+
+    fn then(self, m: Mat3, inverse: Mat3) -> Transform<H> {
+        Transform {
+            hitable: self.hitable,
+            linear:  m * self.linear,
+            inverse: self.inverse * inverse,
+            offset:  m.transform(self.offset),
+        }
+    }
+
+    /// Where a point in the world is, for the hitable.
+    fn to_local(&self, p: Float3) -> Float3 {
+        self.inverse.transform(p - self.offset)
+    }
+
+    /// `ray`, as the hitable sees it. `t` along it is the same in both.
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.to_local(ray.origin),
+            dir:    self.inverse.transform(ray.dir),
+            t:      ray.t,
+        }
+    }
+
+    /// A hit on the hitable, in the world. Normals go by the inverse
+    /// transpose, which keeps them perpendicular to the surface.
+    fn to_world(&self, ray: &Ray, mut record: HitRecord) -> HitRecord {
+        record.p = ray.at_t(record.t);
+        record.normal = self.inverse.transpose().transform(record.normal).unit();
+        // Tangents lie along the surface, and move with it.
+        record.tangent = self.linear.transform(record.tangent).unit();
+        record
+    }
+}
+
+impl<H: Hitable> Hitable for Transform<H> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        let record = self.hitable.hit(&self.local_ray(ray), t_min, t_max, rng)?;
+        Some(self.to_world(ray, record))
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        let bbox = self.hitable.bounding_box(t0, t1)?;
+        // Around every corner of the hitable's box, moved.
+        let corner = |i: usize| {
+            let pick = |axis: usize| {
+                if i & (1 << axis) == 0 { bbox.min[axis] } else { bbox.max[axis] }
+            };
+            self.linear.transform(Float3::xyz(pick(0), pick(1), pick(2))) + self.offset
+        };
+        let first = corner(0);
+        let mut world = Aabb { min: first, max: first };
+        for i in 1..8 {
+            world.min = world.min.min(&corner(i));
+            world.max = world.max.max(&corner(i));
+        }
+        Some(world)
+    }
+
+    fn pdf_value(&self, origin: &Float3, dir: &Float3) -> Float {
+        self.hitable.pdf_value(&self.to_local(*origin), &self.inverse.transform(*dir))
+    }
+
+    fn random_toward(&self, origin: &Float3, rng: &mut dyn RngCore) -> Float3 {
+        self.linear.transform(self.hitable.random_toward(&self.to_local(*origin), rng))
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.hitable.map_materials(f);
+    }
+
+    fn hit_all(&self, ray: &Ray) -> Vec<Span> {
+        self.hitable
+            .hit_all(&self.local_ray(ray))
+            .into_iter()
+            .map(|span| {
+                Span {
+                    enter: self.to_world(ray, span.enter),
+                    exit:  self.to_world(ray, span.exit),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::bvh::Bvh;
+    use crate::hitable::Sphere;
+    use crate::material::Lambertian;
+    use crate::rect::Cuboid;
+
+    fn near(a: Float3, b: Float3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    fn near_mat(a: &Mat3, b: &Mat3) -> bool {
+        (0..3).all(|i| near(a.rows[i], b.rows[i]))
+    }
+
+    const X: Float3 = Float3 { x: 1., y: 0., z: 0. };
+    const Y: Float3 = Float3 { x: 0., y: 1., z: 0. };
+    const Z: Float3 = Float3 { x: 0., y: 0., z: 1. };
+
+    #[test]
+    fn check_quarter_turns() {
+        // Each axis turns the next one into the one after, and that into
+        // the negative of the next.
+        for &(axis, a, b) in [(X, Y, Z), (Y, Z, X), (Z, X, Y)].iter() {
+            let m = Mat3::from_axis_angle(axis, 90.);
+            assert!(near(m.transform(a), b), "{:?}: {:?}", axis, m.transform(a));
+            assert!(near(m.transform(b), -a), "{:?}: {:?}", axis, m.transform(b));
+            assert!(near(m.transform(axis), axis));
+        }
+
+        // Euler angles go around Y, X, and Z, one at a time.
+        assert!(near_mat(&Mat3::from_euler(90., 0., 0.), &Mat3::from_axis_angle(Y, 90.)));
+        assert!(near_mat(&Mat3::from_euler(0., 90., 0.), &Mat3::from_axis_angle(X, 90.)));
+        assert!(near_mat(&Mat3::from_euler(0., 0., 90.), &Mat3::from_axis_angle(Z, 90.)));
+        // Roll happens first: Y rolls over to -X, which pitching leaves be,
+        // and yawing turns to Z.
+        assert!(near(Mat3::from_euler(90., 90., 90.).transform(Y), Z));
+    }
+
+    #[test]
+    fn check_inverses() {
+        seed_thread_rng(0x5eed);
+        let random = || Float3::xyz(random_sfloat(), random_sfloat(), random_sfloat()) * 10;
+        for _ in 0..100 {
+            let m = Mat3::from_axis_angle(random(), 360. * random_float())
+                * Mat3::from_euler(random_sfloat() * 180., random_sfloat() * 90., 0.);
+            assert!(near_mat(&(m * m.inverse().unwrap()), &Mat3::identity()));
+            assert!(near_mat(&m.inverse().unwrap(), &m.transpose()));
+            assert!((m.determinant() - 1.0).abs() < 1e-5);
+
+            let sphere = Sphere {
+                center:   Float3::new(),
+                radius:   1.,
+                material: Arc::new(Lambertian::new(Float3::xxx(0.5))),
+            };
+            let transform = Transform::new(sphere).scaled(0.5 + random_float())
+                                                  .rotated(m)
+                                                  .translated(random());
+            let p = random();
+            let there = transform.linear.transform(p) + transform.offset;
+            assert!(near(transform.to_local(there), p));
+        }
+        let flat = Mat3 { rows: [X, Y, X + Y] };
+        assert_eq!(flat.inverse(), None);
+    }
+
+    #[test]
+    fn check_order_matters() {
+        let mut rng = seeded_rng(0);
+        // A long box along X, which a quarter turn around Z stands on end.
+        let long_box = || Cuboid {
+            min:      Float3::xyz(-2., -1., -1.),
+            max:      Float3::xyz(2., 1., 1.),
+            material: Arc::new(Lambertian::new(Float3::xxx(0.5))),
+        };
+        let turn = Mat3::from_axis_angle(Z, 90.);
+        let offset = Float3::xyz(5., 0., 0.);
+
+        // Turned in place, and then moved along X.
+        let turned_then_moved = Transform::new(long_box()).rotated(turn).translated(offset);
+        let bbox = turned_then_moved.bounding_box(0., 0.).unwrap();
+        assert!(near(bbox.min, Float3::xyz(4., -2., -1.)), "{:?}", bbox);
+        assert!(near(bbox.max, Float3::xyz(6., 2., 1.)), "{:?}", bbox);
+
+        // Moved along X, and then swung around to Y.
+        let moved_then_turned = Transform::new(long_box()).translated(offset).rotated(turn);
+        let bbox = moved_then_turned.bounding_box(0., 0.).unwrap();
+        assert!(near(bbox.min, Float3::xyz(-1., 3., -1.)), "{:?}", bbox);
+        assert!(near(bbox.max, Float3::xyz(1., 7., 1.)), "{:?}", bbox);
+
+        // Rays find it where the box says it is, with normals facing out.
+        let ray = Ray { origin: Float3::xyz(0., 0., 0.), dir: Y, t: 0. };
+        let record = moved_then_turned.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+        assert!((record.t - 3.).abs() < 1e-5, "{}", record.t);
+        assert!(near(record.normal, -Y), "{:?}", record.normal);
+        assert!(near(record.p, Float3::xyz(0., 3., 0.)));
+        assert!(turned_then_moved.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).is_none());
+
+        // Scaling keeps normals normal.
+        let big = Transform::new(long_box()).scaled(3.).translated(Float3::xyz(0., 0., -10.));
+        let ray = Ray { origin: Float3::new(), dir: -Z, t: 0. };
+        let record = big.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+        assert!((record.t - 7.).abs() < 1e-5, "{}", record.t);
+        assert!(near(record.normal, Z), "{:?}", record.normal);
+    }
+
+    #[test]
+    fn check_instances_share_their_object() {
+        let mut rng = seeded_rng(0);
+        seed_thread_rng(0x5eed);
+        // A clump of spheres around the origin, with one right at it.
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Float3::xxx(0.5)));
+        let sphere = |center| -> Box<dyn Hitable> {
+            Box::new(Sphere { center, radius: 0.25, material: material.clone() })
+        };
+        let mut spheres: Vec<Box<dyn Hitable>> = (0..100)
+            .map(|_| sphere(Float3::xyz(random_sfloat(), random_sfloat(), random_sfloat())))
+            .collect();
+        spheres.push(sphere(Float3::new()));
+        let clump: Arc<dyn Hitable> = Arc::new(Bvh::new(spheres, 0., 0.));
+
+        // A row of a thousand of them along X, each turned a little more.
+        let instances: Vec<Box<dyn Hitable>> = (0..1000)
+            .map(|i| -> Box<dyn Hitable> {
+                Box::new(Instance::new(clump.clone())
+                             .rotated(Mat3::from_axis_angle(Y, i as Float))
+                             .translated(10. * i as Float * X))
+            })
+            .collect();
+        let row = Bvh::new(instances, 0., 0.);
+        assert_eq!(Arc::strong_count(&clump), 1001);
+
+        // Straight down through the middle of any of them is straight down
+        // through the middle of the clump, however it's turned.
+        let down = Ray { origin: 10. * Y, dir: -Y, t: 0. };
+        let expected = clump.hit(&down, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+        for &i in [0, 1, 500, 999].iter() {
+            let offset = 10. * i as Float * X;
+            let ray = Ray { origin: offset + down.origin, ..down };
+            let record = row.hit(&ray, 1e-3, FLOAT_MAX, &mut rng).unwrap();
+            assert!((record.t - expected.t).abs() < 1e-5, "{}: {} vs {}", i, record.t, expected.t);
+            assert!(near(record.p, offset + expected.p), "{}: {:?}", i, record.p);
+        }
+        // And there's nothing in between them.
+        let between = Ray { origin: 5. * X + down.origin, ..down };
+        assert!(row.hit(&between, 1e-3, FLOAT_MAX, &mut rng).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+//! Smoke, fog, and other things that rays go into rather than bounce off of.
+//!
+//! A ray through a medium travels some random distance into it before it
+//! scatters, and the denser the medium, the shorter that distance tends to be.
+//! Rays that make it all the way through carry on as if nothing was there.
+//! Media hit with a phase function for a material, usually `Isotropic`.
+//!
+//! `ConstantMedium` is as dense everywhere, so the distance has a closed form.
+//! `NoiseVolume` isn't, so we find the distance by delta tracking (also known
+//! as Woodcock tracking): take steps as if the whole volume were as dense as
+//! its densest point, and at each one, stop with probability local / max.
+//! The steps that don't stop are "null" collisions, which change nothing.
+//! See: Physically Based Rendering (3rd edition), section 15.2.2
+
+use std::sync::Arc;
+
+use crate::hitable::{
+    Aabb,
+    Hitable,
+};
+use crate::prelude::*;
+use crate::texture::turbulence;
+
+/// How many null collisions a ray can go through before we give up and let
+/// it pass. Only very thin parts of very dense volumes get anywhere near this.
+pub const MAX_DELTA_STEPS: u32 = 1024;
+
+/// A distance to travel through a medium of `density` before colliding, with
+/// a random number from `rng`.
+fn collision_distance(density: Float, rng: &mut dyn RngCore) -> Float {
+    // 1 - u is in (0, 1], so the log is finite.
+    -(1.0 - random_float_with(rng)).ln() / density
+}
+
+/// The record for a collision inside a medium, at `t` along `ray`.
+fn collision(ray: &Ray, t: Float, phase: &Arc<dyn Material>) -> HitRecord {
+    HitRecord {
+        t,
+        p:        ray.at_t(t),
+        // Media don't have surfaces, so any normal will do.
+        normal:   Float3::xyz(1., 0., 0.),
+        u:        0.,
+        v:        0.,
+        tangent:  Float3::xyz(0., 1., 0.),
+        material: phase.clone(),
+    }
+}
+
+/// A medium of the same `density` everywhere inside of `boundary`.
+/// `boundary` has to be convex, like a sphere, so that rays enter it once.
+#[derive(Clone, Debug)]
+pub struct ConstantMedium<H: Hitable> {
+    pub boundary: H,
+    pub density:  Float,
+    pub phase:    Arc<dyn Material>,
+}
+
+impl<H: Hitable> Hitable for ConstantMedium<H> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        // Find where the ray is inside, even if it started there.
+        let far = FLOAT_MAX;
+        let enter = self.boundary.hit(ray, -far, far, rng)?;
+        let exit = self.boundary.hit(ray, enter.t + 1.0e-4, far, rng)?;
+        let t_enter = enter.t.max(t_min);
+        let t_exit = exit.t.min(t_max);
+        if t_enter >= t_exit {
+            return None;
+        }
+
+        let length = ray.dir.length();
+        let distance = collision_distance(self.density, rng);
+        if distance >= (t_exit - t_enter) * length {
+            return None;
+        }
+        Some(collision(ray, t_enter + distance / length, &self.phase))
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float) -> Option<Aabb> {
+        self.boundary.bounding_box(t0, t1)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.phase = f(&self.phase);
+    }
+}
+
+/// Smoke inside of `bounds`, whose density comes from turbulent noise.
+///
+/// The density ranges from 0 up to `density`. `scale` is how many lumps of
+/// noise there are per unit, and `octaves` adds finer wisps on top of those,
+/// like `NoiseTexture`. It takes at least one octave to have any smoke.
+#[derive(Clone, Debug)]
+pub struct NoiseVolume {
+    pub bounds:  Aabb,
+    pub density: Float,
+    pub scale:   Float,
+    pub octaves: u32,
+    pub phase:   Arc<dyn Material>,
+}
+
+impl NoiseVolume {
+    /// How dense the smoke is at `p`, which should be inside `bounds`.
+    pub fn density_at(&self, p: &Float3) -> Float {
+        if self.octaves == 0 {
+            return 0.0;
+        }
+        // Each octave of turbulence adds at most its weight: 1, 1/2, 1/4, ...
+        let most = 2.0 - 2.0 * (0.5 as Float).powi(self.octaves as i32 - 1);
+        let field = turbulence(*p * self.scale, self.octaves) / most;
+        self.density * field.min(1.0)
+    }
+}
+
+impl Hitable for NoiseVolume {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float, rng: &mut dyn RngCore)
+        -> Option<HitRecord>
+    {
+        // `density` bounds the field everywhere, so it's our majorant.
+        let majorant = self.density;
+        if majorant <= 0.0 {
+            return None;
+        }
+        let (t_enter, t_exit) = self.bounds.interval(ray, t_min, t_max)?;
+
+        let length = ray.dir.length();
+        let mut t = t_enter;
+        for _ in 0..MAX_DELTA_STEPS {
+            t += collision_distance(majorant, rng) / length;
+            if t >= t_exit {
+                return None;
+            }
+            // Real collisions happen as often as the local density allows.
+            let p = ray.at_t(t);
+            if random_float_with(rng) * majorant < self.density_at(&p) {
+                return Some(collision(ray, t, &self.phase));
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self, _t0: Float, _t1: Float) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn map_materials(&mut self, f: &mut dyn FnMut(&Arc<dyn Material>) -> Arc<dyn Material>) {
+        self.phase = f(&self.phase);
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use crate::hitable::Sphere;
+    use crate::material::Isotropic;
+
+    const RAYS: usize = 20_000;
+
+    fn phase() -> Arc<dyn Material> {
+        Arc::new(Isotropic { albedo: Float3::xxx(0.5) })
+    }
+
+    fn noise(density: Float, scale: Float) -> NoiseVolume {
+        NoiseVolume {
+            bounds: Aabb {
+                min: Float3::xxx(-1.),
+                max: Float3::xxx(1.),
+            },
+            density,
+            scale,
+            octaves: 4,
+            phase: phase(),
+        }
+    }
+
+    /// The fraction of rays that make it through `medium` along the x axis,
+    /// through 2 units of it.
+    fn transmittance(medium: &dyn Hitable, rng: &mut dyn RngCore) -> Float {
+        let ray = Ray {
+            origin: Float3::xyz(-3., 0., 0.),
+            dir:    Float3::xyz(1., 0., 0.),
+            t:      0.,
+        };
+        let through = (0..RAYS)
+            .filter(|_| medium.hit(&ray, 1.0e-3, FLOAT_MAX, rng).is_none())
+            .count();
+        through as Float / RAYS as Float
+    }
+
+    #[test]
+    fn check_constant_noise_matches_constant_medium() {
+        let mut rng = seeded_rng(1);
+        // Without any scale, the noise is the same everywhere, but less dense
+        // than the majorant, so plenty of collisions are null.
+        let volume = noise(2.0, 0.);
+        let density = volume.density_at(&Float3::new());
+        assert!(0.1 < density && density < 1.9, "{}", density);
+
+        let medium = ConstantMedium {
+            boundary: Sphere {
+                center:   Float3::new(),
+                radius:   1.,
+                material: phase(),
+            },
+            density,
+            phase: phase(),
+        };
+        let expected = (-2.0 * density).exp();
+        // Four standard deviations of a binomial.
+        let tolerance = 4.0 * (expected * (1.0 - expected) / RAYS as Float).sqrt();
+        for &(name, medium) in [("noise", &volume as &dyn Hitable),
+                                ("constant", &medium as &dyn Hitable)].iter()
+        {
+            let measured = transmittance(medium, &mut rng);
+            assert!((measured - expected).abs() < tolerance,
+                    "{}: {} vs {}", name, measured, expected);
+        }
+    }
+
+    #[test]
+    fn check_transmittance_falls_with_density() {
+        let mut rng = seeded_rng(2);
+        let measured: Vec<Float> = [0.0, 0.5, 1.0, 2.0, 4.0]
+            .iter()
+            .map(|&density| transmittance(&noise(density, 1.5), &mut rng))
+            .collect();
+        assert_eq!(measured[0], 1.0);
+        for pair in measured.windows(2) {
+            assert!(pair[1] < pair[0], "{:?}", measured);
+        }
+    }
+
+    #[test]
+    fn check_collisions_are_inside() {
+        let mut rng = seeded_rng(3);
+        let volume = noise(8.0, 1.5);
+        // From inside, rays can collide right away, but never behind `t_min`.
+        let ray = Ray {
+            origin: Float3::new(),
+            dir:    Float3::xyz(0.3, -1., 0.5),
+            t:      0.,
+        };
+        let mut hits = 0;
+        for _ in 0..1000 {
+            if let Some(record) = volume.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng) {
+                assert!(record.t > 1.0e-3, "{}", record.t);
+                let p = record.p;
+                assert!(p.x.abs() <= 1. && p.y.abs() <= 1. && p.z.abs() <= 1., "{:?}", p);
+                hits += 1;
+            }
+        }
+        assert!(hits > 500, "{}", hits);
+
+        // And nothing is there at all when it's empty.
+        assert!(noise(0.0, 1.5).hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng).is_none());
+        let none = NoiseVolume { octaves: 0, ..noise(8.0, 1.5) };
+        assert!(none.hit(&ray, 1.0e-3, FLOAT_MAX, &mut rng).is_none());
+    }
+}
@@ -0,0 +1,117 @@
+//! Noticing when a file changes, for `--watch`.
+//!
+//! We poll the file's modification time and size, which works everywhere and
+//! is cheap next to a render. Editors often save in a few steps, or write a
+//! new file and rename it over the old one, so a change only counts once the
+//! file has stopped changing for a moment. A file that's briefly missing
+//! hasn't changed yet.
+
+use std::{
+    fs,
+    path,
+    thread,
+    time,
+};
+
+/// How often we look at the file.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// How long the file has to stay the same after changing, before we believe
+/// that it's done changing.
+const SETTLE_TIME: time::Duration = time::Duration::from_millis(250);
+
+/// What we can tell about the file without reading it.
+type Stamp = Option<(time::SystemTime, u64)>;
+
+fn stamp(path: &path::Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Watches one file for changes.
+pub struct Watcher {
+    path: path::PathBuf,
+    last: Stamp,
+}
+
+impl Watcher {
+    /// Starts watching `path`. Only changes from now on count.
+    pub fn new(path: &path::Path) -> Watcher {
+        Watcher {
+            path: path.to_owned(),
+            last: stamp(path),
+        }
+    }
+
+    /// Waits for the file to change and settle down. Returns false instead if
+    /// `stop()` returns true first.
+    pub fn wait(&mut self, stop: impl Fn() -> bool) -> bool {
+        let mut changed: Option<(Stamp, time::Instant)> = None;
+        while !stop() {
+            let now = stamp(&self.path);
+            match changed {
+                _ if now.is_none() => {},
+                Some((seen, since)) if seen == now && since.elapsed() >= SETTLE_TIME => {
+                    self.last = now;
+                    return true;
+                },
+                Some((seen, _)) if seen == now => {},
+                _ if now != self.last => changed = Some((now, time::Instant::now())),
+                _ => {},
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn test_file(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("weekend-raytracing-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.ron");
+        fs::write(&path, "()").unwrap();
+        path
+    }
+
+    #[test]
+    fn check_changes_are_seen() {
+        let path = test_file("watch-changes");
+        let mut watcher = Watcher::new(&path);
+
+        // A few saves in a row only count once.
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                for text in ["(", "(version", "(version: 1)"].iter() {
+                    thread::sleep(time::Duration::from_millis(50));
+                    fs::write(&path, text).unwrap();
+                }
+            })
+        };
+        let start = time::Instant::now();
+        assert!(watcher.wait(|| start.elapsed() > time::Duration::from_secs(10)));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "(version: 1)");
+        writer.join().unwrap();
+
+        // Nothing has changed since.
+        let start = time::Instant::now();
+        assert!(!watcher.wait(|| start.elapsed() > time::Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn check_stop_stops_waiting() {
+        let path = test_file("watch-stop");
+        let mut watcher = Watcher::new(&path);
+        assert!(!watcher.wait(|| true));
+
+        // Or a missing file, which we keep waiting for.
+        fs::remove_file(&path).unwrap();
+        let start = time::Instant::now();
+        assert!(!watcher.wait(|| start.elapsed() > time::Duration::from_millis(400)));
+    }
+}
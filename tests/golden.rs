@@ -0,0 +1,223 @@
+//! Small renders, checked against reference images in tests/golden/.
+//!
+//! Every sample is seeded, so these come out the same every time, and any
+//! change to what the renderer draws shows up here: intersections, sampling,
+//! materials, the BVH. Pixels can be off by a little, for platforms whose
+//! math rounds differently, but not by more.
+//!
+//! When a render doesn't match, it's written to target/golden/, next to an
+//! image of where it differs. If the change was on purpose, regenerate the
+//! references with:
+//!
+//! ```text
+//! UPDATE_GOLDEN=1 cargo test --test golden
+//! UPDATE_GOLDEN=1 cargo test --test golden --features float32
+//! ```
+//!
+//! f32 draws different random numbers than f64, so its samples land elsewhere
+//! and it has references of its own, in tests/golden/float32/.
+
+use std::{
+    env,
+    fs,
+    path,
+    sync::Arc,
+};
+
+use rand::prelude::*;
+
+use one_weekend::bvh::Bvh;
+use one_weekend::camera::{
+    Camera,
+    CameraInfo,
+    Projection,
+};
+use one_weekend::hitable::{
+    Hitable,
+    HitableList,
+    Sphere,
+};
+use one_weekend::material::{
+    Dielectric,
+    Lambertian,
+    Material,
+    Metal,
+};
+use one_weekend::prelude::*;
+use one_weekend::render::{
+    self,
+    RenderSettings,
+    Scene,
+    DEFAULT_MAX_DEPTH,
+};
+use one_weekend::scenes::{
+    make_cornell_box,
+    make_green_scene,
+    make_small_light_box,
+    SceneKind,
+};
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+
+/// How far any channel of any pixel can be from the reference, out of 255.
+const TOLERANCE: u8 = 3;
+
+fn settings(scene: &str) -> RenderSettings {
+    RenderSettings {
+        width:             WIDTH,
+        height:            HEIGHT,
+        samples_per_pixel: 4,
+        max_depth:         DEFAULT_MAX_DEPTH,
+        seed:              0x5eed,
+        scene:             scene.into(),
+    }
+}
+
+fn camera(lookfrom: Float3, lookat: Float3, vfov: Float) -> Camera {
+    Camera::new(CameraInfo {
+        lookfrom,
+        lookat,
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov },
+        aspect:     WIDTH as Float / HEIGHT as Float,
+        aperature:  0.,
+        focus_dist: 1.,
+        t_start:    0.,
+        t_end:      0.,
+    })
+}
+
+/// How a render differs from its reference.
+struct Diff {
+    /// The biggest difference in any channel.
+    max:    u8,
+    /// The average difference, over every channel.
+    mean:   f64,
+    /// How many pixels have a channel off by more than `TOLERANCE`.
+    pixels: usize,
+}
+
+fn diff(a: &image::RgbImage, b: &image::RgbImage) -> Diff {
+    let (mut max, mut total, mut pixels) = (0, 0_u64, 0);
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let mut off = false;
+        for (ca, cb) in pa.data.iter().zip(pb.data.iter()) {
+            let d = (*ca as i32 - *cb as i32).abs() as u8;
+            max = max.max(d);
+            total += d as u64;
+            off |= d > TOLERANCE;
+        }
+        pixels += off as usize;
+    }
+    let channels = 3 * a.width() as u64 * a.height() as u64;
+    Diff {
+        max,
+        mean: total as f64 / channels as f64,
+        pixels,
+    }
+}
+
+/// Renders `scene` and compares it against tests/golden/`name`.png.
+fn check_golden(name: &str, scene: &Scene, cam: &Camera) {
+    let rendered = render::render(scene, cam, &settings(name), &|_| {}).to_rgb8();
+
+    let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut reference_dir = root.join("tests").join("golden");
+    if cfg!(feature = "float32") {
+        reference_dir = reference_dir.join("float32");
+    }
+    let reference_path = reference_dir.join(format!("{}.png", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        rendered.save(&reference_path).unwrap();
+        eprintln!("Updated {}", reference_path.display());
+        return;
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(img) => img.to_rgb(),
+        Err(e) => {
+            panic!("Unable to read {}: {}\nRun with UPDATE_GOLDEN=1 to create it.",
+                   reference_path.display(), e);
+        },
+    };
+    assert_eq!(reference.dimensions(), rendered.dimensions(), "{}", name);
+
+    let diff = diff(&rendered, &reference);
+    if diff.pixels == 0 {
+        return;
+    }
+
+    // Save what we drew, and where it's off, to look at.
+    let out_dir = root.join("target").join("golden");
+    fs::create_dir_all(&out_dir).unwrap();
+    let out_path = out_dir.join(format!("{}.png", name));
+    rendered.save(&out_path).unwrap();
+    let diff_path = out_dir.join(format!("{}-diff.png", name));
+    image::RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let (a, b) = (rendered.get_pixel(x, y).data, reference.get_pixel(x, y).data);
+        // Brighter where it's further off, so that small differences show up.
+        let channel = |i: usize| (a[i] as i32 - b[i] as i32).abs().saturating_mul(8).min(255);
+        image::Rgb([channel(0) as u8, channel(1) as u8, channel(2) as u8])
+    }).save(&diff_path).unwrap();
+
+    panic!("{} doesn't match its reference: {} of {} pixels are off by more than {}, \
+            by up to {}, and {:.3} on average.\n\
+            Rendered: {}\n\
+            Difference: {}\n\
+            If this change is on purpose, rerun with UPDATE_GOLDEN=1.",
+           name, diff.pixels, WIDTH * HEIGHT, TOLERANCE, diff.max, diff.mean,
+           out_path.display(), diff_path.display());
+}
+
+#[test]
+fn check_golden_green() {
+    let scene = Scene::new(make_green_scene());
+    let cam = camera(Float3::xyz(0., 0., 0.), Float3::xyz(0., 0., -1.), 90.);
+    check_golden("green", &scene, &cam);
+}
+
+#[test]
+fn check_golden_light_box() {
+    let scene = make_small_light_box();
+    let cam = camera(Float3::xyz(278., 278., -800.), Float3::xyz(278., 278., 0.), 40.);
+    check_golden("light-box", &scene, &cam);
+}
+
+/// Lights, flipped normals, and turned boxes, all together.
+#[test]
+fn check_golden_cornell() {
+    let scene = make_cornell_box();
+    let view = SceneKind::Cornell.view();
+    let cam = camera(view.lookfrom, view.lookat, view.vfov);
+    check_golden("cornell", &scene, &cam);
+}
+
+/// Spheres of every kind of material, scattered around a BVH.
+#[test]
+fn check_golden_bvh() {
+    let mut rng = SmallRng::from_seed([7; 16]);
+    let mut hitables: Vec<Box<dyn Hitable>> = vec![Box::new(Sphere {
+        center:   Float3::xyz(0., -1000., 0.),
+        radius:   1000.,
+        material: Arc::new(Lambertian { albedo: Float3::xxx(0.5) }),
+    })];
+    for _ in 0..40 {
+        let color = Float3::xyz(rng.gen(), rng.gen(), rng.gen());
+        let material: Arc<dyn Material> = match rng.gen_range(0, 3) {
+            0 => Arc::new(Lambertian { albedo: color }),
+            1 => Arc::new(Metal { albedo: color, fuzz: 0.3 * rng.gen::<Float>() }),
+            _ => Arc::new(Dielectric::new(1.5)),
+        };
+        let radius = 0.2 + 0.3 * rng.gen::<Float>();
+        hitables.push(Box::new(Sphere {
+            center: Float3::xyz(8. * rng.gen::<Float>() - 4., radius, -6. * rng.gen::<Float>()),
+            radius,
+            material,
+        }));
+    }
+    let scene = Scene::new(HitableList { hitables: vec![Box::new(Bvh::new(hitables, 0., 0.))] });
+    let cam = camera(Float3::xyz(0., 2., 5.), Float3::xyz(0., 0.5, -3.), 50.);
+    check_golden("bvh", &scene, &cam);
+}
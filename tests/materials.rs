@@ -0,0 +1,124 @@
+//! Materials from outside of the library, loaded from scene files.
+
+use std::sync::{
+    atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+    Arc,
+};
+
+use one_weekend::camera::{
+    Camera,
+    CameraInfo,
+    Projection,
+};
+use one_weekend::material::Material;
+use one_weekend::prelude::*;
+use one_weekend::render::{
+    self,
+    RenderSettings,
+    DEFAULT_MAX_DEPTH,
+};
+use one_weekend::scene_file::{
+    self,
+    MaterialRegistry,
+    Object,
+    SCHEMA,
+};
+
+/// How many times any `Counting` material scattered a ray.
+static SCATTERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Reflects straight back where rays came from, and counts them.
+#[derive(Debug)]
+struct Counting {
+    albedo: Float3,
+}
+
+impl Material for Counting {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord, _rng: &mut dyn RngCore) -> Scatter {
+        SCATTERS.fetch_add(1, Ordering::SeqCst);
+        Scatter::Bounce(ScatterRecord {
+            attenuation: self.albedo,
+            scattered:   Ray {
+                origin: record.p,
+                dir:    -ray_in.dir,
+                t:      ray_in.t,
+            },
+        })
+    }
+}
+
+fn counting(object: &Object) -> Result<Arc<dyn Material>, String> {
+    Ok(Arc::new(Counting { albedo: object.vector("albedo")? }))
+}
+
+fn registry() -> MaterialRegistry {
+    let mut materials = MaterialRegistry::default();
+    materials.register_material("Counting", &["albedo"], counting);
+    materials
+}
+
+fn scene_text(material: &str) -> String {
+    format!("(
+                 version: {},
+                 objects: [
+                     Sphere(center: (0, 0, 0), radius: 1, material: {}),
+                 ],
+             )",
+            SCHEMA.current, material)
+}
+
+#[test]
+fn check_custom_material_renders() {
+    let text = scene_text("Counting(albedo: (0.5, 0.5, 0.5))");
+    let scene = scene_file::load_with(&text, &SCHEMA, &registry()).unwrap();
+
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(0., 0., 4.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 40. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 4.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    let settings = RenderSettings {
+        width:             8,
+        height:            8,
+        samples_per_pixel: 2,
+        max_depth:         DEFAULT_MAX_DEPTH,
+        seed:              0x5eed,
+        scene:             "counting".into(),
+    };
+    let before = SCATTERS.load(Ordering::SeqCst);
+    let img = render::render(&scene, &cam, &settings, &|_| {});
+    assert!(SCATTERS.load(Ordering::SeqCst) > before);
+
+    // Rays that hit the ball go right back out into the sky, at half strength.
+    let (center, corner) = (img.get_pixel(4, 4), img.get_pixel(0, 0));
+    assert!(center.length() > 0.0 && center.length() < corner.length(),
+            "{:?} vs {:?}", center, corner);
+}
+
+#[test]
+fn check_unknown_materials_list_registered_ones() {
+    let text = scene_text("Plasma(glow: 1)");
+    let msg = scene_file::load_with(&text, &SCHEMA, &registry()).err().unwrap();
+    assert!(msg.ends_with("unknown material `Plasma`. Expected one of: \
+                           Lambertian, Metal, GgxMetal, Dielectric, DiffuseLight, Isotropic, \
+                           OrenNayar, Counting"),
+            "{}", msg);
+
+    // Built-in registries don't know about it.
+    let text = scene_text("Counting(albedo: (0.5, 0.5, 0.5))");
+    assert!(scene_file::load_str(&text, &SCHEMA).is_err());
+
+    // And its fields are checked like any other material's.
+    let text = scene_text("Counting(albedo: (0.5, 0.5, 0.5), glow: 1)");
+    let msg = scene_file::load_with(&text, &SCHEMA, &registry()).err().unwrap();
+    assert!(msg.ends_with("Counting has no field `glow`. Expected one of: albedo"), "{}", msg);
+}
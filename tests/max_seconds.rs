@@ -0,0 +1,66 @@
+//! Rendering for as long as --max-seconds gives us, rather than to a sample
+//! count.
+
+use std::{
+    env,
+    fs,
+    path,
+    process,
+    time,
+};
+
+/// Renders the cover scene into `output`, with `args` on top, and returns
+/// what it printed.
+fn render(output: &path::Path, args: &[&str]) -> String {
+    let result = process::Command::new(env!("CARGO_BIN_EXE_one-weekend"))
+        .args(["-w", "32", "-h", "24", "--seed", "7"])
+        .args(args)
+        .arg("-o").arg(output)
+        .stdin(process::Stdio::null())
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&result.stderr).into_owned();
+    assert!(result.status.success(), "{}", log);
+    log
+}
+
+fn mean(path: &path::Path) -> f64 {
+    let img = image::open(path).unwrap().to_rgb();
+    let total: u64 = img.pixels().flat_map(|p| p.data.iter()).map(|&c| c as u64).sum();
+    total as f64 / (3 * img.width() * img.height()) as f64
+}
+
+#[test]
+fn check_max_seconds() {
+    let dir = env::temp_dir().join(format!("one-weekend-max-seconds-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let budgeted = dir.join("budgeted.png");
+    let stats = dir.join("stats.json");
+
+    // Far more samples than a second leaves time for.
+    let start = time::Instant::now();
+    let log = render(&budgeted, &["-s", "1000000", "--max-seconds", "1",
+                                  "--stats-json", stats.to_str().unwrap()]);
+    // It has to stop for the deadline, but not much after it.
+    assert!(start.elapsed() < time::Duration::from_secs(30), "{}", log);
+    assert!(log.contains("samples per pixel in"), "{}", log);
+
+    let stats: serde_json::Value = serde_json::from_slice(&fs::read(&stats).unwrap()).unwrap();
+    assert!(stats["samples_per_pixel"].as_u64().unwrap() >= 1, "{}", stats);
+    assert!(stats["samples_per_pixel"].as_u64().unwrap() < 1000000, "{}", stats);
+    // Running out of time is how it's meant to finish.
+    assert_eq!(stats["interrupted"], false);
+
+    // Pixels are averages of however many samples they got, so the image is
+    // about as bright as one rendered to a sample count.
+    let counted = dir.join("counted.png");
+    render(&counted, &["-s", "16"]);
+    let (a, b) = (mean(&budgeted), mean(&counted));
+    assert!((a - b).abs() < 0.1 * b, "{} vs {}", a, b);
+
+    // With time to spare, it stops at --samples.
+    let log = render(&budgeted, &["-s", "3", "--max-seconds", "60"]);
+    assert!(log.contains("Rendered 3 samples per pixel"), "{}", log);
+
+    let _ = fs::remove_dir_all(&dir);
+}
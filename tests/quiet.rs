@@ -0,0 +1,51 @@
+//! What the command line prints, and where.
+
+use std::{
+    env,
+    fs,
+    process,
+};
+
+/// Renders a tiny image with `args`, and returns its (stdout, stderr).
+fn render(args: &[&str]) -> (String, String) {
+    let result = process::Command::new(env!("CARGO_BIN_EXE_one-weekend"))
+        .args(["-w", "16", "-h", "12", "-s", "2"])
+        .args(args)
+        .stdin(process::Stdio::null())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&result.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+    assert!(result.status.success(), "{}", stderr);
+    (stdout, stderr)
+}
+
+#[test]
+fn check_quiet() {
+    let dir = env::temp_dir().join(format!("one-weekend-quiet-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("out.png");
+    let output = output.to_str().unwrap();
+
+    // Files we wrote go to stdout, and everything else to stderr.
+    let (stdout, stderr) = render(&["-o", output]);
+    assert_eq!(stdout, format!("Wrote {}\n", output));
+    assert!(stderr.contains("Rendering on"), "{}", stderr);
+    assert!(stderr.contains("Full scene render time"), "{}", stderr);
+
+    // Quiet renders say nothing at all, unless something goes wrong.
+    fs::remove_file(output).unwrap();
+    let (stdout, stderr) = render(&["-q", "-o", output]);
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+    assert!(fs::metadata(output).is_ok());
+
+    let result = process::Command::new(env!("CARGO_BIN_EXE_one-weekend"))
+        .args(["-q", "--aov-stride", "0"])
+        .output()
+        .unwrap();
+    assert!(!result.status.success());
+    assert_eq!(String::from_utf8_lossy(&result.stderr), "error: --aov-stride must be at least 1\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,493 @@
+//! Rendering through the library, like a program embedding it would.
+
+use std::sync::{
+    atomic,
+    Arc,
+    Mutex,
+};
+
+use one_weekend::camera::{
+    Camera,
+    CameraInfo,
+    Projection,
+};
+use one_weekend::bvh::Bvh;
+use one_weekend::checkpoint::PixelSum;
+use one_weekend::float3::{
+    Float,
+    Float3,
+};
+use one_weekend::hitable::{
+    FlipNormals,
+    Hitable,
+    HitableList,
+    Sphere,
+};
+use one_weekend::material::{
+    Cutout,
+    Dielectric,
+    DiffuseLight,
+    Isotropic,
+    Lambertian,
+    NormalMapped,
+};
+use one_weekend::math::{
+    seed_thread_rng,
+    seeded_rng,
+};
+use one_weekend::output::{
+    self,
+    LinearImage,
+};
+use one_weekend::rect::{
+    XyRect,
+    XzRect,
+    YzRect,
+};
+use one_weekend::render::{
+    self,
+    Background,
+    DebugView,
+    Progress,
+    RenderSettings,
+    Scene,
+    DEFAULT_MAX_DEPTH,
+};
+use one_weekend::scenes::{
+    cover_scene_spheres,
+    make_cover_scene,
+    packed_hitables,
+    CoverSceneParams,
+    SceneSeed,
+};
+use one_weekend::texture::{
+    CheckerTexture,
+    Texture,
+};
+use one_weekend::volume::ConstantMedium;
+
+fn camera() -> Camera {
+    Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(0., 0., 4.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 40. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 4.,
+        t_start:    0.,
+        t_end:      0.,
+    })
+}
+
+fn scene() -> Scene {
+    Scene::new(HitableList {
+        hitables: vec![
+            Box::new(Sphere {
+                center:   Float3::new(),
+                radius:   1.,
+                material: Arc::new(Lambertian { albedo: Float3::xyz(0.8, 0.3, 0.3) }),
+            }),
+            Box::new(Sphere {
+                center:   Float3::xyz(0., 3., 0.),
+                radius:   0.5,
+                material: Arc::new(DiffuseLight { emit: Float3::xxx(4.) }),
+            }),
+        ],
+    })
+}
+
+fn settings() -> RenderSettings {
+    RenderSettings {
+        width:             16,
+        height:            16,
+        samples_per_pixel: 8,
+        max_depth:         DEFAULT_MAX_DEPTH,
+        seed:              0x5eed,
+        scene:             "library".into(),
+    }
+}
+
+#[test]
+fn check_render_16x16() {
+    let (scene, cam, settings) = (scene(), camera(), settings());
+    let reports = Mutex::new(vec![]);
+    let img = render::render(&scene, &cam, &settings, &|progress: Progress| {
+        reports.lock().unwrap().push(progress);
+    });
+    assert_eq!((img.width(), img.height()), (16, 16));
+
+    // Every row reported in, and the last report is of all of them.
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by_key(|progress| progress.rows_done);
+    let done: Vec<u32> = reports.iter().map(|progress| progress.rows_done).collect();
+    assert_eq!(done, (1..=16).collect::<Vec<u32>>());
+    assert!(reports.iter().all(|progress| progress.rows == 16));
+
+    // The ball is in the middle, lit and red, and the sky is around it.
+    let center = img.get_pixel(8, 8);
+    assert!(center.x > center.z, "{:?}", center);
+    let corner = img.get_pixel(0, 15);
+    assert!(corner.z > 0.5, "{:?}", corner);
+
+    // It's the same image as rendering each pixel on its own.
+    for y in 0..16 {
+        for x in 0..16 {
+            let mut sum = PixelSum::default();
+            render::render_pixel(&scene, &cam, &settings, (x, y), 8, &mut sum, None);
+            assert_eq!(img.get_pixel(x, y), sum.average(), "({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn check_raw_output_round_trips() {
+    let img = render::render(&scene(), &camera(), &settings(), &|_| ());
+    let path = std::env::temp_dir()
+        .join(format!("weekend-raytracing-raw-{}.bin", std::process::id()));
+    assert_eq!(output::write_raw(&img, &path), Ok(()));
+    let loaded = LinearImage::load_raw(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, Ok(img));
+}
+
+#[test]
+fn check_max_depth() {
+    let (scene, cam) = (scene(), camera());
+    // Without any bounces, nothing lights the ball but itself, and it doesn't
+    // glow.
+    let flat = RenderSettings { max_depth: 0, ..settings() };
+    let img = render::render(&scene, &cam, &flat, &|_| {});
+    assert_eq!(img.get_pixel(8, 8), Float3::new());
+
+    let calls = atomic::AtomicU32::new(0);
+    let deep = render::render(&scene, &cam, &settings(), &|_| {
+        calls.fetch_add(1, atomic::Ordering::SeqCst);
+    });
+    assert_ne!(deep.get_pixel(8, 8), Float3::new());
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 16);
+}
+
+#[test]
+fn check_deep_glass_has_no_color_cast() {
+    // Glass balls inside of glass balls, under the sky. Clear glass doesn't
+    // change the sky's color, which never has more red than green, so
+    // neither does any pixel. Paths that run out of bounces used to come back
+    // magenta, which tinted glass like this.
+    let glass = Arc::new(Dielectric::new(1.5));
+    let hitables = [1.2, 0.9, 0.6, 0.3].iter()
+        .map(|&radius| {
+            Box::new(Sphere { center: Float3::new(), radius, material: glass.clone() })
+                as Box<dyn Hitable>
+        })
+        .collect();
+    let scene = Scene::new(HitableList { hitables });
+    let shallow = RenderSettings { max_depth: 4, ..settings() };
+    let img = render::render(&scene, &camera(), &shallow, &|_| {});
+    for y in 0..16 {
+        for x in 0..16 {
+            let px = img.get_pixel(x, y);
+            assert!(px.x <= px.y + 1e-6, "({}, {}) is {:?}", x, y, px);
+        }
+    }
+}
+
+/// A unit box, open toward the camera, with nothing outside of it, and
+/// optionally a light on the ceiling.
+fn open_box(lit: bool) -> Scene {
+    let white = Arc::new(Lambertian { albedo: Float3::xxx(0.73) });
+    let light = XzRect {
+        a0: 0.35, a1: 0.65,
+        b0: 0.35, b1: 0.65,
+        k:  0.999,
+        material: Arc::new(DiffuseLight { emit: Float3::xxx(15.) }),
+    };
+    let mut hitables: Vec<Box<dyn Hitable>> = vec![
+        Box::new(XzRect { a0: 0., a1: 1., b0: 0., b1: 1., k: 0., material: white.clone() }),
+        Box::new(FlipNormals {
+            hitable: XzRect { a0: 0., a1: 1., b0: 0., b1: 1., k: 1., material: white.clone() },
+        }),
+        Box::new(FlipNormals {
+            hitable: XyRect { a0: 0., a1: 1., b0: 0., b1: 1., k: 1., material: white.clone() },
+        }),
+        Box::new(FlipNormals {
+            hitable: YzRect { a0: 0., a1: 1., b0: 0., b1: 1., k: 1., material: white.clone() },
+        }),
+        Box::new(YzRect { a0: 0., a1: 1., b0: 0., b1: 1., k: 0., material: white }),
+    ];
+    let mut lights = HitableList::default();
+    if lit {
+        hitables.push(Box::new(FlipNormals { hitable: light.clone() }));
+        lights.hitables.push(Box::new(light));
+    }
+    Scene {
+        lights,
+        background: Background::Black,
+        ..Scene::new(HitableList { hitables })
+    }
+}
+
+#[test]
+fn check_black_background() {
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(0.5, 0.5, -1.6),
+        lookat:     Float3::xyz(0.5, 0.5, 0.),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 40. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 1.6,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    assert_eq!("none".parse::<Background>(), Ok(Background::Black));
+
+    // Nothing gives off any light, so there's nothing to see. Paths that
+    // bounce around until the depth limit are too dark to show up.
+    let dark = render::render(&open_box(false), &cam, &settings(), &|_| {});
+    for y in 0..16 {
+        for x in 0..16 {
+            let rgb = dark.get_pixel(x, y);
+            assert!(rgb.as_slice().iter().all(|c| c.is_finite() && c.abs() < 1e-4),
+                    "({}, {}): {:?}", x, y, rgb);
+        }
+    }
+
+    // The corners see past the box, where it's black, but under the light
+    // isn't.
+    let lit = render::render(&open_box(true), &cam, &settings(), &|_| {});
+    for &(x, y) in [(0, 0), (15, 0), (0, 15), (15, 15)].iter() {
+        assert_eq!(lit.get_pixel(x, y), Float3::new(), "({}, {})", x, y);
+    }
+    let floor = lit.get_pixel(8, 13);
+    assert!(floor.as_slice().iter().all(|&c| c > 0.1), "{:?}", floor);
+}
+
+#[test]
+fn check_debug_views() {
+    let cam = camera();
+    let view = |debug| {
+        let scene = Scene {
+            debug:       Some(debug),
+            depth_range: (0., 6.),
+            ..scene()
+        };
+        render::render(&scene, &cam, &settings(), &|_| {})
+    };
+    // What the image shows: the square root of what we rendered.
+    let shown = |img: &LinearImage, x, y| img.get_pixel(x, y).sqrt();
+    let near = |a: Float3, b: Float3| (a - b).length() < 0.05;
+
+    // The middle of the image looks straight at the front of the ball, which
+    // faces the camera, 3 away.
+    let normals = view(DebugView::Normals);
+    let center = shown(&normals, 8, 8);
+    assert!(near(center, Float3::xyz(0.5, 0.5, 1.0)), "{:?}", center);
+    let depth = shown(&view(DebugView::Depth), 8, 8);
+    assert!(near(depth, Float3::xxx(0.5)), "{:?}", depth);
+    // Which is a color, and is shown like one.
+    let albedo = view(DebugView::Albedo).get_pixel(8, 8);
+    assert!(near(albedo, Float3::xyz(0.8, 0.3, 0.3)), "{:?}", albedo);
+
+    // The corners miss everything, and are gray.
+    assert_eq!(shown(&normals, 0, 15), Float3::xxx(0.5));
+    assert_eq!("uv".parse::<DebugView>(), Ok(DebugView::Uv));
+}
+
+/// Glass, fuzzy metal, and a lens, which all draw random numbers.
+fn cover_scene_and_camera() -> (Scene, Camera) {
+    let scene = Scene::new(make_cover_scene(&SceneSeed::default(),
+                                             &CoverSceneParams::default()));
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(13., 2., 3.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 20. },
+        aspect:     1.,
+        aperature:  0.1,
+        focus_dist: 10.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    (scene, cam)
+}
+
+/// Every sample draws its random numbers from a generator seeded just for it,
+/// so it doesn't matter which thread takes it, or what that thread did before.
+#[test]
+fn check_same_image_on_any_number_of_threads() {
+    let (scene, cam) = cover_scene_and_camera();
+    let render_on = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| render::render(&scene, &cam, &settings(), &|_| {}))
+    };
+    let one = render_on(1);
+    for &threads in [2, 5].iter() {
+        let many = render_on(threads);
+        for y in 0..16 {
+            for x in 0..16 {
+                let (a, b) = (one.get_pixel(x, y), many.get_pixel(x, y));
+                let bits = |rgb: Float3| [rgb.x.to_bits(), rgb.y.to_bits(), rgb.z.to_bits()];
+                assert_eq!(bits(a), bits(b), "({}, {}) on {} threads", x, y, threads);
+            }
+        }
+    }
+}
+
+/// The lens, the materials, light sampling, volumes and cutouts all draw from
+/// the generator they're handed, so the same seed traces exactly the same
+/// paths, whatever the thread's own generator is up to.
+#[test]
+fn check_paths_only_draw_from_their_generator() {
+    let (cover, cam) = cover_scene_and_camera();
+    // Fog, with a ball full of holes between it and the camera.
+    let fog = Scene {
+        world:      HitableList {
+            hitables: vec![
+                Box::new(ConstantMedium {
+                    boundary: Sphere {
+                        center:   Float3::new(),
+                        radius:   4.,
+                        material: Arc::new(Lambertian::new(Float3::xxx(0.5))),
+                    },
+                    density:  0.3,
+                    phase:    Arc::new(Isotropic { albedo: Float3::xxx(0.8) }),
+                }),
+                Box::new(Sphere {
+                    center:   Float3::xyz(6.5, 1., 1.5),
+                    radius:   1.,
+                    material: Arc::new(Cutout {
+                        material: Lambertian::new(Float3::xxx(0.5)),
+                        alpha:    Arc::new(Float3::xxx(0.5)),
+                    }),
+                }),
+            ],
+        },
+        background: Background::Sky,
+        ..Scene::default()
+    };
+    let trace = |scene: &Scene, seed: u64, thread_seed: u64| {
+        seed_thread_rng(thread_seed);
+        let mut rng = seeded_rng(seed);
+        (0..64)
+            .map(|i| {
+                let (s, t) = ((i % 8) as Float / 8., (i / 8) as Float / 8.);
+                let ray = cam.get_ray(s, t, &mut rng);
+                let rgb = render::color(&ray, scene, DEFAULT_MAX_DEPTH, &mut rng);
+                [rgb.x.to_bits(), rgb.y.to_bits(), rgb.z.to_bits()]
+            })
+            .collect::<Vec<_>>()
+    };
+    for scene in [&cover, &fog].iter() {
+        let first = trace(scene, 7, 1);
+        assert_eq!(first, trace(scene, 7, 2));
+        assert_ne!(first, trace(scene, 8, 1));
+    }
+}
+
+/// Camera rays traced four at a time find the same things as ever, and the
+/// paths after them draw the same random numbers, so the image is the same.
+#[test]
+fn check_packets_match_pixels() {
+    // Sphere sets, which test all four rays at once, among glass and metal
+    // that draw random numbers after the first hit.
+    let spheres = cover_scene_spheres(&SceneSeed::default(), &CoverSceneParams::default());
+    let scene = Scene::new(HitableList {
+        hitables: vec![Box::new(Bvh::new(packed_hitables(&spheres).hitables, 0., 0.))],
+    });
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(13., 2., 3.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 20. },
+        aspect:     15. / 13.,
+        aperature:  0.1,
+        focus_dist: 10.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    // Odd sizes, so that blocks hang off of the right and bottom edges.
+    let settings = RenderSettings { width: 15, height: 13, samples_per_pixel: 4, ..settings() };
+    let calls = atomic::AtomicU32::new(0);
+    let img = render::render_packets(&scene, &cam, &settings, &|_| {
+        calls.fetch_add(1, atomic::Ordering::SeqCst);
+    });
+    assert_eq!((img.width(), img.height()), (15, 13));
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 13);
+
+    let bits = |rgb: Float3| [rgb.x.to_bits(), rgb.y.to_bits(), rgb.z.to_bits()];
+    for y in 0..13 {
+        for x in 0..15 {
+            let mut sum = PixelSum::default();
+            render::render_pixel(&scene, &cam, &settings, (x, y), 4, &mut sum, None);
+            assert_eq!(bits(img.get_pixel(x, y)), bits(sum.average()), "({}, {})", x, y);
+        }
+    }
+}
+
+/// A flat panel filling the view, with `normal_map` on it, under the sky.
+fn mapped_panel(normal_map: impl Texture + 'static) -> Scene {
+    Scene::new(HitableList {
+        hitables: vec![
+            Box::new(XyRect {
+                a0:       -1.,
+                a1:       1.,
+                b0:       -1.,
+                b1:       1.,
+                k:        0.,
+                material: Arc::new(NormalMapped {
+                    material:   Lambertian { albedo: Float3::xxx(0.7) },
+                    normal_map: Arc::new(normal_map),
+                }),
+            }),
+        ],
+    })
+}
+
+#[test]
+fn check_normal_maps_shade_flat_panels() {
+    let cam = Camera::new(CameraInfo {
+        lookfrom:   Float3::xyz(0., 0., 3.),
+        lookat:     Float3::new(),
+        up:         Float3::xyz(0., 1., 0.),
+        projection: Projection::Perspective { vfov: 30. },
+        aspect:     1.,
+        aperature:  0.,
+        focus_dist: 3.,
+        t_start:    0.,
+        t_end:      0.,
+    });
+    let settings = RenderSettings { samples_per_pixel: 32, ..settings() };
+
+    // How much redder the bottom left and top right quarters of the panel
+    // are than the other two, leaving out the middle where they meet.
+    let contrast = |scene: &Scene| {
+        let img = render::render(scene, &cam, &settings, &|_| {});
+        let (mut even, mut odd) = (0.0, 0.0);
+        for y in (0..7).chain(9..16) {
+            for x in (0..7).chain(9..16) {
+                let red = img.get_pixel(x, y).x;
+                if (x < 8) == (y >= 8) {
+                    even += red;
+                } else {
+                    odd += red;
+                }
+            }
+        }
+        (even - odd) / 49.
+    };
+
+    // Quarters whose normals tip down see more of the white horizon, and
+    // those tipped up more of the blue sky, so they're clearly different.
+    let bumps = mapped_panel(CheckerTexture {
+        even:   Float3::xyz(0.5, 0.15, 0.6),
+        odd:    Float3::xyz(0.5, 0.85, 0.6),
+        checks: 2,
+    });
+    let bumpy = contrast(&bumps);
+    assert!(bumpy > 0.1, "{}", bumpy);
+
+    // A flat map is just a flat panel.
+    let flat = contrast(&mapped_panel(Float3::xyz(0.5, 0.5, 1.)));
+    assert!(flat.abs() < 0.03, "{}", flat);
+}
@@ -0,0 +1,94 @@
+//! Snapshotting and stopping the renderer with signals, like a batch
+//! scheduler would.
+#![cfg(unix)]
+
+use std::{
+    env,
+    fs,
+    io::{
+        BufRead,
+        BufReader,
+    },
+    process,
+    sync::mpsc,
+    thread,
+    time,
+};
+
+/// Waits up to `secs` seconds for `done()`.
+fn wait_for(secs: u64, mut done: impl FnMut() -> bool) -> bool {
+    let start = time::Instant::now();
+    while start.elapsed() < time::Duration::from_secs(secs) {
+        if done() {
+            return true;
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    false
+}
+
+fn send(child: &process::Child, signal: libc::c_int) {
+    // Safety: This only sends a signal to the child we started.
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, signal) }, 0);
+}
+
+#[test]
+fn check_snapshot_then_stop() {
+    let dir = env::temp_dir().join(format!("one-weekend-signals-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("out.png");
+    let other = dir.join("out.ppm");
+    let partial = dir.join("out.partial.png");
+    let mask = dir.join("out.mask.png");
+
+    // Far more samples than we'll wait for.
+    let mut child = process::Command::new(env!("CARGO_BIN_EXE_one-weekend"))
+        .args(["-w", "32", "-h", "24", "-s", "100000", "--grace-seconds", "30"])
+        .arg("-o").arg(&output)
+        .arg("-o").arg(&other)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (lines, stderr) = mpsc::channel();
+    let reader = BufReader::new(child.stderr.take().unwrap());
+    thread::spawn(move || {
+        for line in reader.lines() {
+            if lines.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Signals are caught by the time we start rendering.
+    loop {
+        let line = stderr.recv_timeout(time::Duration::from_secs(60))
+                         .expect("The render never started");
+        if line.starts_with("Rendering on") {
+            break;
+        }
+    }
+
+    send(&child, libc::SIGUSR1);
+    assert!(wait_for(30, || partial.exists()), "SIGUSR1 didn't write a snapshot");
+    assert_eq!(child.try_wait().unwrap(), None, "SIGUSR1 shouldn't stop the render");
+
+    send(&child, libc::SIGTERM);
+    let mut status = None;
+    assert!(wait_for(60, || {
+        status = child.try_wait().unwrap();
+        status.is_some()
+    }), "SIGTERM didn't stop the render");
+    let log: Vec<String> = stderr.try_iter().collect();
+    assert!(status.unwrap().success(), "{:#?}", log);
+
+    // Everything was written, since there was plenty of time to.
+    for written in [&output, &other, &mask].iter() {
+        assert!(written.exists(), "{} is missing: {:#?}", written.display(), log);
+    }
+    // And the snapshot was replaced by the real thing.
+    assert!(!partial.exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}